@@ -6,6 +6,63 @@ use crate::pristine::*;
 use crate::vector2::Vector2;
 use crate::vertex_buffer::VertexBuffer;
 
+/// The contents of all the vertices of a graph, read with one call
+/// to [`ChangeStore::get_contents_batch`] per change, instead of one
+/// call to [`ChangeStore::get_contents`] per vertex.
+struct GraphContents {
+    buf: Vec<u8>,
+    /// Offset in `buf` of the contents of each vertex, indexed like
+    /// `graph.lines`.
+    offsets: Vec<usize>,
+}
+
+impl GraphContents {
+    fn read<T: GraphTxnT, P: ChangeStore>(
+        changes: &P,
+        txn: &ArcTxn<T>,
+        graph: &Graph,
+    ) -> Result<Self, FileError<P::Error, T>> {
+        let now = std::time::Instant::now();
+        let mut order: Vec<usize> = (0..graph.lines.len()).collect();
+        order.sort_by_key(|&i| (graph.lines[i].vertex.change, graph.lines[i].vertex.start));
+        let mut contents = GraphContents {
+            buf: Vec::with_capacity(graph.total_bytes),
+            offsets: vec![0; graph.lines.len()],
+        };
+        let mut keys = Vec::new();
+        let mut i = 0;
+        while i < order.len() {
+            let change = graph.lines[order[i]].vertex.change;
+            let mut offset = contents.buf.len();
+            keys.clear();
+            while i < order.len() && graph.lines[order[i]].vertex.change == change {
+                let v = graph.lines[order[i]].vertex;
+                contents.offsets[order[i]] = offset;
+                if v.end > v.start {
+                    offset += v.end - v.start
+                }
+                keys.push(v);
+                i += 1
+            }
+            changes
+                .get_contents_batch(
+                    |p| txn.read().get_external(&p).unwrap().map(|x| x.into()),
+                    &keys,
+                    &mut contents.buf,
+                )
+                .map_err(FileError::Changestore)?;
+            debug_assert_eq!(offset, contents.buf.len());
+        }
+        crate::TIMERS.lock().unwrap().alive_contents += now.elapsed();
+        Ok(contents)
+    }
+
+    fn get(&self, v: VertexId, buf: &mut [u8]) {
+        let start = self.offsets[v.0];
+        buf.copy_from_slice(&self.buf[start..start + buf.len()])
+    }
+}
+
 #[derive(Debug)]
 struct ConflictStackElt {
     conflict: Vec<Path>,
@@ -20,6 +77,7 @@ fn output_conflict<T: ChannelTxnT, B: VertexBuffer, P: ChangeStore>(
     channel: &ChannelRef<T>,
     line_buf: &mut B,
     graph: &Graph,
+    contents: &GraphContents,
     sccs: &Vector2<VertexId>,
     conflict: Path,
 ) -> Result<(), FileError<P::Error, T>> {
@@ -90,8 +148,8 @@ fn output_conflict<T: ChannelTxnT, B: VertexBuffer, P: ChangeStore>(
             while elt.idx < elt.conflict[elt.side].path.len() {
                 match elt.conflict[elt.side].path[elt.idx] {
                     PathElement::Scc { scc } => {
-                        output_scc(
-                            changes,
+                        output_scc::<_, _, P>(
+                            contents,
                             txn,
                             graph,
                             &sccs[scc],
@@ -192,7 +250,7 @@ impl PathElement {
 }
 
 fn output_scc<T: GraphTxnT, B: VertexBuffer, P: ChangeStore>(
-    changes: &P,
+    contents: &GraphContents,
     txn: &ArcTxn<T>,
     graph: &Graph,
     scc: &[VertexId],
@@ -221,24 +279,12 @@ fn output_scc<T: GraphTxnT, B: VertexBuffer, P: ChangeStore>(
         crate::TIMERS.lock().unwrap().alive_write += now.elapsed();
 
         let vertex = graph[v].vertex;
-
-        let get_contents = |buf: &mut [u8]| {
-            let now = std::time::Instant::now();
-            let result = changes
-                .get_contents(
-                    |p| txn.read().get_external(&p).unwrap().map(|x| x.into()),
-                    vertex,
-                    buf,
-                )
-                .map(|_| ())
-                .map_err(FileError::Changestore);
-            crate::TIMERS.lock().unwrap().alive_contents += now.elapsed();
-            result
-        };
-
         let now = std::time::Instant::now();
         debug!("outputting {:?}", vertex);
-        vbuf.output_line(vertex, get_contents)?;
+        vbuf.output_line::<FileError<P::Error, T>, _>(vertex, |buf| {
+            contents.get(v, buf);
+            Ok(())
+        })?;
         crate::TIMERS.lock().unwrap().alive_write += now.elapsed();
     }
     let now = std::time::Instant::now();
@@ -269,9 +315,19 @@ pub fn output_graph<T: ChannelTxnT, B: VertexBuffer, P: ChangeStore>(
         graph.collect_forward_edges(&*txn, txn.graph(&*channel), &scc, &forward_scc, forward)?;
     }
     crate::TIMERS.lock().unwrap().alive_graph += now0.elapsed();
+    let contents = GraphContents::read(changes, txn, graph)?;
     let now1 = std::time::Instant::now();
     debug!("conflict_tree = {:?}", conflict_tree);
-    output_conflict(changes, txn, channel, line_buf, graph, &scc, conflict_tree)?;
+    output_conflict(
+        changes,
+        txn,
+        channel,
+        line_buf,
+        graph,
+        &contents,
+        &scc,
+        conflict_tree,
+    )?;
     crate::TIMERS.lock().unwrap().alive_output += now1.elapsed();
     Ok(())
 }
@@ -2,12 +2,51 @@ use super::{AliveVertex, Flags, Graph, VertexId};
 use crate::pristine::*;
 use crate::HashMap;
 use std::collections::hash_map::Entry;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Budget of [`retrieve_limited`]. The default has no limit.
+#[derive(Debug, Clone, Default)]
+pub struct RetrieveLimits {
+    /// Maximal number of alive vertices.
+    pub max_vertices: Option<usize>,
+    /// Maximal total size of the alive vertices, in bytes.
+    pub max_bytes: Option<usize>,
+    /// Retrieval stops as soon as this is set to `true`, from another
+    /// thread for instance.
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+#[derive(Debug, Error)]
+pub enum RetrieveError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(#[from] TxnErr<T>),
+    #[error("Graph too large: more than {vertices} vertices or {bytes} bytes")]
+    TooLarge { vertices: usize, bytes: usize },
+    #[error("Retrieval cancelled")]
+    Cancelled,
+}
 
 pub fn retrieve<T: GraphTxnT>(
     txn: &T,
     channel: &T::Graph,
     pos0: Position<ChangeId>,
 ) -> Result<Graph, TxnErr<T::GraphError>> {
+    match retrieve_limited(txn, channel, pos0, &RetrieveLimits::default()) {
+        Ok(graph) => Ok(graph),
+        Err(RetrieveError::Txn(e)) => Err(e),
+        Err(e) => unreachable!("{}", e),
+    }
+}
+
+/// Same as [`retrieve`], but stops with an error as soon as the
+/// graph exceeds the budget of `limits`, or when it is cancelled.
+pub fn retrieve_limited<T: GraphTxnT>(
+    txn: &T,
+    channel: &T::Graph,
+    pos0: Position<ChangeId>,
+    limits: &RetrieveLimits,
+) -> Result<Graph, RetrieveError<T::GraphError>> {
     let now = std::time::Instant::now();
     let mut graph = Graph {
         lines: Vec::new(),
@@ -32,6 +71,11 @@ pub fn retrieve<T: GraphTxnT>(
     let mut stack = vec![VertexId(1)];
     while let Some(vid) = stack.pop() {
         debug!("vid {:?}", vid);
+        if let Some(ref cancel) = limits.cancel {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(RetrieveError::Cancelled);
+            }
+        }
         graph[vid].children = graph.children.len();
         for e in crate::pristine::iter_adjacent(
             txn,
@@ -48,6 +92,7 @@ pub fn retrieve<T: GraphTxnT>(
                         ent.insert(n);
                         graph.total_bytes += alive.vertex.len();
                         graph.lines.push(alive);
+                        limits.check(&graph)?;
                         stack.push(n);
                         n
                     } else {
@@ -68,6 +113,21 @@ pub fn retrieve<T: GraphTxnT>(
     Ok(graph)
 }
 
+impl RetrieveLimits {
+    fn check<E: std::error::Error>(&self, graph: &Graph) -> Result<(), RetrieveError<E>> {
+        let too_many = self.max_vertices.map(|m| graph.lines.len() > m);
+        let too_big = self.max_bytes.map(|m| graph.total_bytes > m);
+        if too_many.unwrap_or(false) || too_big.unwrap_or(false) {
+            Err(RetrieveError::TooLarge {
+                vertices: self.max_vertices.unwrap_or(usize::MAX),
+                bytes: self.max_bytes.unwrap_or(usize::MAX),
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
 fn new_vertex<T: GraphTxnT>(
     txn: &T,
     graph: &T::Graph,
@@ -8,12 +8,17 @@ use crate::{HashMap, HashSet};
 use thiserror::Error;
 pub(crate) mod edge;
 pub(crate) use edge::*;
+pub(crate) mod spill;
+mod validate;
+pub use validate::check_alive_change;
 mod vertex;
 pub(crate) use vertex::*;
 
 pub enum ApplyError<ChangestoreError: std::error::Error, T: GraphTxnT + TreeTxnT> {
     Changestore(ChangestoreError),
     LocalChange(LocalApplyError<T>),
+    /// The contents of a record couldn't be turned into a change.
+    Contents(crate::record::ContentsError),
 }
 
 impl<C: std::error::Error, T: GraphTxnT + TreeTxnT> std::fmt::Debug for ApplyError<C, T> {
@@ -21,6 +26,7 @@ impl<C: std::error::Error, T: GraphTxnT + TreeTxnT> std::fmt::Debug for ApplyErr
         match self {
             ApplyError::Changestore(e) => std::fmt::Debug::fmt(e, fmt),
             ApplyError::LocalChange(e) => std::fmt::Debug::fmt(e, fmt),
+            ApplyError::Contents(e) => std::fmt::Debug::fmt(e, fmt),
         }
     }
 }
@@ -30,6 +36,7 @@ impl<C: std::error::Error, T: GraphTxnT + TreeTxnT> std::fmt::Display for ApplyE
         match self {
             ApplyError::Changestore(e) => std::fmt::Display::fmt(e, fmt),
             ApplyError::LocalChange(e) => std::fmt::Display::fmt(e, fmt),
+            ApplyError::Contents(e) => std::fmt::Display::fmt(e, fmt),
         }
     }
 }
@@ -38,12 +45,27 @@ impl<C: std::error::Error, T: GraphTxnT + TreeTxnT> std::error::Error for ApplyE
 
 #[derive(Error)]
 pub enum LocalApplyError<T: GraphTxnT + TreeTxnT> {
-    DependencyMissing { hash: crate::pristine::Hash },
-    ChangeAlreadyOnChannel { hash: crate::pristine::Hash },
+    DependencyMissing {
+        hash: crate::pristine::Hash,
+    },
+    ChangeAlreadyOnChannel {
+        hash: crate::pristine::Hash,
+    },
     Txn(#[from] TxnErr<T::GraphError>),
     Tree(#[from] TreeErr<T::TreeError>),
-    Block { block: Position<ChangeId> },
+    Block {
+        block: Position<ChangeId>,
+    },
     InvalidChange,
+    Io(#[from] std::io::Error),
+    /// The graph around the change just applied breaks the invariants
+    /// of [`check_alive`](crate::pristine::check_alive). This is only
+    /// checked with the `validate` feature.
+    InvalidGraph {
+        hash: crate::pristine::Hash,
+        alive: Vec<Vertex<ChangeId>>,
+        reachable_pseudo: Vec<Vertex<ChangeId>>,
+    },
 }
 
 impl<T: GraphTxnT + TreeTxnT> std::fmt::Debug for LocalApplyError<T> {
@@ -59,6 +81,16 @@ impl<T: GraphTxnT + TreeTxnT> std::fmt::Debug for LocalApplyError<T> {
             LocalApplyError::Tree(e) => std::fmt::Debug::fmt(e, fmt),
             LocalApplyError::Block { block } => write!(fmt, "Block error: {:?}", block),
             LocalApplyError::InvalidChange => write!(fmt, "Invalid change"),
+            LocalApplyError::Io(e) => std::fmt::Debug::fmt(e, fmt),
+            LocalApplyError::InvalidGraph {
+                hash,
+                alive,
+                reachable_pseudo,
+            } => write!(
+                fmt,
+                "Invalid graph after applying {:?}: alive but unreachable {:?}, reachable only through pseudo-edges {:?}",
+                hash, alive, reachable_pseudo
+            ),
         }
     }
 }
@@ -76,6 +108,10 @@ impl<T: GraphTxnT + TreeTxnT> std::fmt::Display for LocalApplyError<T> {
             LocalApplyError::Tree(e) => std::fmt::Display::fmt(e, fmt),
             LocalApplyError::Block { block } => write!(fmt, "Block error: {:?}", block),
             LocalApplyError::InvalidChange => write!(fmt, "Invalid change"),
+            LocalApplyError::Io(e) => std::fmt::Display::fmt(e, fmt),
+            LocalApplyError::InvalidGraph { hash, .. } => {
+                write!(fmt, "Invalid graph after applying {}", hash.to_base32())
+            }
         }
     }
 }
@@ -88,6 +124,19 @@ impl<C: std::error::Error, T: GraphTxnT + TreeTxnT> From<crate::pristine::TxnErr
     }
 }
 
+impl<C: std::error::Error, T: GraphTxnT + TreeTxnT>
+    From<crate::record::IntoChangeError<T::GraphError>> for ApplyError<C, T>
+{
+    fn from(err: crate::record::IntoChangeError<T::GraphError>) -> Self {
+        match err {
+            crate::record::IntoChangeError::Txn(e) => {
+                ApplyError::LocalChange(LocalApplyError::Txn(e))
+            }
+            crate::record::IntoChangeError::Contents(e) => ApplyError::Contents(e),
+        }
+    }
+}
+
 impl<C: std::error::Error, T: GraphTxnT + TreeTxnT> From<crate::pristine::TreeErr<T::TreeError>>
     for ApplyError<C, T>
 {
@@ -102,6 +151,7 @@ impl<T: GraphTxnT + TreeTxnT> LocalApplyError<T> {
             MissingError::Txn(e) => LocalApplyError::Txn(TxnErr(e)),
             MissingError::Block(e) => e.into(),
             MissingError::Inconsistent(_) => LocalApplyError::InvalidChange,
+            MissingError::Io(e) => LocalApplyError::Io(e),
         }
     }
 }
@@ -252,6 +302,49 @@ pub fn apply_change_rec_ws<T: TxnT + MutTxnT, P: ChangeStore>(
     Ok(())
 }
 
+/// Same as [apply_change_ws], but applying a change that is already
+/// on the channel does nothing, and returns the position of the
+/// change in the channel and the state after it instead of
+/// [`LocalApplyError::ChangeAlreadyOnChannel`], so that interrupted
+/// operations can be retried.
+pub fn apply_change_idempotent_ws<T: MutTxnT, P: ChangeStore>(
+    changes: &P,
+    txn: &mut T,
+    channel: &mut T::Channel,
+    hash: &Hash,
+    workspace: &mut Workspace,
+) -> Result<(u64, Merkle), ApplyError<P::Error, T>> {
+    if let Some(applied) = applied_state(txn, channel, hash)? {
+        debug!("already applied: {:?}", applied);
+        return Ok(applied);
+    }
+    apply_change_ws(changes, txn, channel, hash, workspace)
+}
+
+/// If change `hash` is on `channel`, its position in the channel and
+/// the state of the channel right after it.
+fn applied_state<T: ChannelTxnT>(
+    txn: &T,
+    channel: &T::Channel,
+    hash: &Hash,
+) -> Result<Option<(u64, Merkle)>, TxnErr<T::GraphError>> {
+    let internal = if let Some(&internal) = txn.get_internal(&hash.into())? {
+        internal
+    } else {
+        return Ok(None);
+    };
+    let n = if let Some(&n) = txn.get_changeset(txn.changes(channel), &internal)? {
+        n
+    } else {
+        return Ok(None);
+    };
+    if let Some(p) = txn.get_revchangeset(txn.rev_changes(channel), &n)? {
+        Ok(Some((n.into(), p.b.into())))
+    } else {
+        Ok(None)
+    }
+}
+
 /// Same as [apply_change_ws], but allocates its own workspace.
 pub fn apply_change<T: MutTxnT, P: ChangeStore>(
     changes: &P,
@@ -315,26 +408,45 @@ fn apply_change_to_channel<T: ChannelMutTxnT + TreeTxnT, F: FnMut(&Hash) -> bool
             return Err(LocalApplyError::ChangeAlreadyOnChannel { hash: *hash });
         };
     debug!("apply change to channel");
+    apply_change_to_graph(
+        txn,
+        T::graph_mut(channel),
+        changes,
+        change_id,
+        hash,
+        change,
+        ws,
+    )?;
+    Ok((n, merkle))
+}
+
+/// Add the vertices and edges of `change` to `graph`, and repair the
+/// graph around them. The change must already be in the log of the
+/// channel of `graph`.
+pub(crate) fn apply_change_to_graph<T: GraphMutTxnT + TreeTxnT, F: FnMut(&Hash) -> bool>(
+    txn: &mut T,
+    graph: &mut T::Graph,
+    changes: &mut F,
+    change_id: ChangeId,
+    hash: &Hash,
+    change: &Change,
+    ws: &mut Workspace,
+) -> Result<(), LocalApplyError<T>> {
+    debug!("apply_change_to_graph {:?} {:?}", change_id, hash);
     let now = std::time::Instant::now();
     for change_ in change.changes.iter() {
         debug!("Applying {:?} (1)", change_);
         for change_ in change_.iter() {
             match *change_ {
-                Atom::NewVertex(ref n) => put_newvertex(
-                    txn,
-                    T::graph_mut(channel),
-                    changes,
-                    change,
-                    ws,
-                    change_id,
-                    n,
-                )?,
+                Atom::NewVertex(ref n) => {
+                    put_newvertex(txn, graph, changes, change, ws, change_id, n)?
+                }
                 Atom::EdgeMap(ref n) => {
                     for edge in n.edges.iter() {
                         if !edge.flag.contains(EdgeFlags::DELETED) {
                             put_newedge(
                                 txn,
-                                T::graph_mut(channel),
+                                graph,
                                 ws,
                                 change_id,
                                 n.inode,
@@ -356,7 +468,7 @@ fn apply_change_to_channel<T: ChannelMutTxnT + TreeTxnT, F: FnMut(&Hash) -> bool
                     if edge.flag.contains(EdgeFlags::DELETED) {
                         put_newedge(
                             txn,
-                            T::graph_mut(channel),
+                            graph,
                             ws,
                             change_id,
                             n.inode,
@@ -371,22 +483,31 @@ fn apply_change_to_channel<T: ChannelMutTxnT + TreeTxnT, F: FnMut(&Hash) -> bool
     }
     crate::TIMERS.lock().unwrap().apply += now.elapsed();
 
-    clean_obsolete_pseudo_edges(txn, T::graph_mut(channel), ws, change_id)?;
+    clean_obsolete_pseudo_edges(txn, graph, ws, change_id)?;
 
     info!("repairing missing contexts");
-    repair_missing_contexts(txn, T::graph_mut(channel), ws, change_id, change)?;
-    detect_folder_conflict_resolutions(
-        txn,
-        T::graph_mut(channel),
-        &mut ws.missing_context,
-        change_id,
-        change,
-    )
-    .map_err(LocalApplyError::from_missing)?;
+    repair_missing_contexts(txn, graph, ws, change_id, change)?;
+    detect_folder_conflict_resolutions(txn, graph, change_id, change)
+        .map_err(LocalApplyError::from_missing)?;
+
+    repair_cyclic_paths(txn, graph, ws)?;
 
-    repair_cyclic_paths(txn, T::graph_mut(channel), ws)?;
+    #[cfg(feature = "validate")]
+    {
+        let (alive, reachable_pseudo) = check_alive_change(txn, graph, change_id, change)
+            .map_err(LocalApplyError::from_missing)?;
+        if !alive.is_empty() || !reachable_pseudo.is_empty() {
+            let mut alive: Vec<_> = alive.into_iter().map(|(v, _)| v).collect();
+            alive.sort();
+            return Err(LocalApplyError::InvalidGraph {
+                hash: *hash,
+                alive,
+                reachable_pseudo: reachable_pseudo.into_iter().map(|(v, _)| v).collect(),
+            });
+        }
+    }
     info!("done applying change");
-    Ok((n, merkle))
+    Ok(())
 }
 
 /// Apply a change created locally: serialize it, compute its hash, and
@@ -498,7 +619,7 @@ fn update_inode<T: ChannelTxnT + TreeMutTxnT>(
 pub struct Workspace {
     parents: HashSet<Vertex<ChangeId>>,
     children: HashSet<Vertex<ChangeId>>,
-    pseudo: Vec<(Vertex<ChangeId>, SerializedEdge, Position<Option<Hash>>)>,
+    pseudo: spill::SpillVec<(Vertex<ChangeId>, SerializedEdge, Position<Option<Hash>>)>,
     deleted_by: HashSet<ChangeId>,
     up_context: Vec<Vertex<ChangeId>>,
     down_context: Vec<Vertex<ChangeId>>,
@@ -513,7 +634,33 @@ impl Workspace {
     pub fn new() -> Self {
         Self::default()
     }
-    fn clear(&mut self) {
+
+    /// A workspace trying to use at most approximately `max_memory`
+    /// bytes: past that limit, the pseudo-edges to clean up and the
+    /// pseudo-edges to missing children are written to a temporary
+    /// file (with the `tempfile` feature), and the caches used to
+    /// repair missing contexts are dropped.
+    pub fn with_max_memory(max_memory: usize) -> Self {
+        let mut ws = Self::default();
+        ws.pseudo.set_max_len(Some(
+            max_memory
+                / 4
+                / std::mem::size_of::<(Vertex<ChangeId>, SerializedEdge, Position<Option<Hash>>)>(),
+        ));
+        ws.missing_context.pseudo.set_max_len(Some(
+            max_memory / 4 / std::mem::size_of::<(Vertex<ChangeId>, SerializedEdge)>(),
+        ));
+        ws.missing_context.max_memory = Some(max_memory / 4);
+        ws
+    }
+
+    /// Number of pseudo-edges written to a temporary file by this
+    /// workspace.
+    #[cfg(test)]
+    pub(crate) fn spilled(&self) -> usize {
+        self.pseudo.total_spilled() + self.missing_context.pseudo.total_spilled()
+    }
+    pub(crate) fn clear(&mut self) {
         self.children.clear();
         self.parents.clear();
         self.pseudo.clear();
@@ -549,7 +696,8 @@ pub(crate) fn clean_obsolete_pseudo_edges<T: GraphMutTxnT + TreeTxnT>(
 ) -> Result<(), LocalApplyError<T>> {
     let mut alive_folder = std::mem::replace(&mut ws.alive_folder, HashMap::new());
     let mut folder_stack = std::mem::replace(&mut ws.folder_stack, Vec::new());
-    for (next_vertex, p, inode) in ws.pseudo.drain(..) {
+    for x in ws.pseudo.drain()? {
+        let (next_vertex, p, inode) = x?;
         let (a, b) = if p.flag().is_parent() {
             if let Ok(&dest) = txn.find_block_end(channel, p.dest()) {
                 (dest, next_vertex)
@@ -704,6 +852,194 @@ fn folder_has_alive_descendants<T: GraphMutTxnT + TreeTxnT>(
     Ok(*alive.get(&b).unwrap_or(&false))
 }
 
+/// Statistics of a compaction of the pseudo-edges of a channel (see
+/// [`MutTxnTExt::compact_pseudo_edges`](crate::MutTxnTExt::compact_pseudo_edges)).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PseudoEdgeStats {
+    /// Number of files and directories whose graphs were visited.
+    pub files: usize,
+    /// Number of pseudo-edges before the compaction.
+    pub before: usize,
+    /// Number of pseudo-edges between alive vertices that were
+    /// implied by other alive paths, and deleted.
+    pub redundant: usize,
+    /// Number of pseudo-edges with a dead end, deleted as after
+    /// applying a change.
+    pub obsolete: usize,
+    /// Number of pseudo-edges after the compaction, including those
+    /// added while repairing the contexts of obsolete ones.
+    pub after: usize,
+}
+
+/// Maximal number of vertices visited when looking for another path
+/// between the ends of a pseudo-edge.
+const MAX_PATH_SEARCH: usize = 10_000;
+
+pub(crate) fn compact_pseudo_edges<T: GraphMutTxnT + TreeTxnT>(
+    txn: &mut T,
+    channel: &mut T::Graph,
+    ws: &mut Workspace,
+) -> Result<PseudoEdgeStats, LocalApplyError<T>> {
+    let (files, pseudo) = collect_all_pseudo_edges(txn, channel)?;
+    let mut stats = PseudoEdgeStats {
+        files,
+        before: pseudo.len(),
+        ..PseudoEdgeStats::default()
+    };
+    let mut visited = HashSet::default();
+    let mut stack = Vec::new();
+    for (a, e, inode) in pseudo {
+        let b = if let Ok(&b) = txn.find_block(channel, e.dest()) {
+            b
+        } else {
+            continue;
+        };
+        // Previous deletions and repairs may have removed this edge.
+        if !has_pseudo_edge(txn, channel, a, &e)? {
+            continue;
+        }
+        if is_alive(txn, channel, &a)? && is_alive(txn, channel, &b)? {
+            if e.flag().is_folder()
+                || !implied_by_alive_path(txn, channel, a, b, &e, &mut visited, &mut stack)?
+            {
+                continue;
+            }
+            debug!("redundant pseudo-edge {:?} {:?} {:?}", a, b, e);
+            del_graph_with_rev(txn, channel, e.flag(), a, b, e.introduced_by())?;
+            stats.redundant += 1;
+        } else {
+            let inode = Position {
+                change: txn.get_external(&inode.change)?.map(|h| h.into()),
+                pos: inode.pos,
+            };
+            // The caches of the workspace are stale after the
+            // previous deletions.
+            ws.clear();
+            ws.pseudo.push((a, e, inode))?;
+            clean_obsolete_pseudo_edges(txn, channel, ws, e.introduced_by())?;
+            if !has_pseudo_edge(txn, channel, a, &e)? {
+                stats.obsolete += 1
+            }
+        }
+    }
+    ws.clear();
+    stats.after = collect_all_pseudo_edges(txn, channel)?.1.len();
+    Ok(stats)
+}
+
+/// A pseudo-edge from a vertex, with the inode of its file.
+type InodePseudoEdge = (Vertex<ChangeId>, SerializedEdge, Position<ChangeId>);
+
+/// All the pseudo-edges reachable from the root of `channel`, with
+/// the inode of the file or directory they belong to, along with the
+/// number of inodes.
+fn collect_all_pseudo_edges<T: GraphTxnT>(
+    txn: &T,
+    channel: &T::Graph,
+) -> Result<(usize, Vec<InodePseudoEdge>), TxnErr<T::GraphError>> {
+    let mut files = 0;
+    let mut pseudo = Vec::new();
+    let mut visited = HashSet::default();
+    let mut stack = vec![(Vertex::ROOT, Position::ROOT)];
+    while let Some((v, inode)) = stack.pop() {
+        if !visited.insert(v) {
+            continue;
+        }
+        for e in iter_adjacent(
+            txn,
+            channel,
+            v,
+            EdgeFlags::empty(),
+            EdgeFlags::all() - EdgeFlags::DELETED - EdgeFlags::PARENT,
+        )? {
+            let e = e?;
+            if e.flag().contains(EdgeFlags::PSEUDO) {
+                pseudo.push((v, *e, inode))
+            }
+            let w = if let Ok(&w) = txn.find_block(channel, e.dest()) {
+                w
+            } else {
+                continue;
+            };
+            if visited.contains(&w) {
+                continue;
+            }
+            // Inodes are the empty vertices below file names.
+            if e.flag().is_folder() && w.is_empty() && !v.is_empty() {
+                files += 1;
+                stack.push((w, w.start_pos()))
+            } else {
+                stack.push((w, inode))
+            }
+        }
+    }
+    Ok((files, pseudo))
+}
+
+fn has_pseudo_edge<T: GraphTxnT>(
+    txn: &T,
+    channel: &T::Graph,
+    a: Vertex<ChangeId>,
+    e: &SerializedEdge,
+) -> Result<bool, TxnErr<T::GraphError>> {
+    for f in iter_adjacent(txn, channel, a, e.flag(), e.flag())? {
+        if f? == e {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Whether `b` can be reached from `a` through alive vertices and
+/// non-deleted edges of the same file, other than the pseudo-edge
+/// `edge` from `a` to `b`. This gives up after visiting
+/// [`MAX_PATH_SEARCH`] vertices.
+fn implied_by_alive_path<T: GraphTxnT>(
+    txn: &T,
+    channel: &T::Graph,
+    a: Vertex<ChangeId>,
+    b: Vertex<ChangeId>,
+    edge: &SerializedEdge,
+    visited: &mut HashSet<Vertex<ChangeId>>,
+    stack: &mut Vec<Vertex<ChangeId>>,
+) -> Result<bool, TxnErr<T::GraphError>> {
+    visited.clear();
+    stack.clear();
+    stack.push(a);
+    while let Some(v) = stack.pop() {
+        if !visited.insert(v) {
+            continue;
+        }
+        if visited.len() > MAX_PATH_SEARCH {
+            return Ok(false);
+        }
+        for e in iter_adjacent(
+            txn,
+            channel,
+            v,
+            EdgeFlags::empty(),
+            EdgeFlags::all() - EdgeFlags::DELETED - EdgeFlags::PARENT,
+        )? {
+            let e = e?;
+            if e.flag().is_folder() || (v == a && e == edge) {
+                continue;
+            }
+            let w = if let Ok(&w) = txn.find_block(channel, e.dest()) {
+                w
+            } else {
+                continue;
+            };
+            if w == b {
+                return Ok(true);
+            }
+            if !visited.contains(&w) && is_alive(txn, channel, &w)? {
+                stack.push(w)
+            }
+        }
+    }
+    Ok(false)
+}
+
 fn repair_missing_contexts<T: GraphMutTxnT + TreeTxnT>(
     txn: &mut T,
     channel: &mut T::Graph,
@@ -715,6 +1051,7 @@ fn repair_missing_contexts<T: GraphMutTxnT + TreeTxnT>(
     crate::missing_context::repair_parents_of_deleted(txn, channel, &mut ws.missing_context)
         .map_err(LocalApplyError::from_missing)?;
     for atom in change.changes.iter().flat_map(|r| r.iter()) {
+        ws.missing_context.trim_caches();
         match atom {
             Atom::NewVertex(ref n) if !n.flag.is_folder() => {
                 let vertex = Vertex {
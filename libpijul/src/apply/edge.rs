@@ -254,7 +254,7 @@ fn collect_pseudo_edges<T: GraphMutTxnT + TreeTxnT>(
             }
         }
         if e.flag().contains(EdgeFlags::PSEUDO) {
-            apply.pseudo.push((v, *e, inode));
+            apply.pseudo.push((v, *e, inode))?;
         }
     }
     Ok(())
@@ -295,7 +295,14 @@ fn reconnect_pseudo_edges<T: GraphMutTxnT + TreeTxnT>(
         for &c in ws.children.iter() {
             if p != c {
                 debug_assert!(is_alive(txn, channel, &c).unwrap());
-                put_graph_with_rev(txn, channel, EdgeFlags::PSEUDO, p, c, ChangeId::ROOT)?;
+                put_graph_with_rev_if_absent(
+                    txn,
+                    channel,
+                    EdgeFlags::PSEUDO,
+                    p,
+                    c,
+                    ChangeId::ROOT,
+                )?;
             }
         }
     }
@@ -333,7 +340,7 @@ where
                 continue;
             }
             if v.introduced_by().is_root() {
-                ws.pseudo.push((dest_vertex, *v));
+                ws.pseudo.push((dest_vertex, *v))?;
                 continue;
             }
             if v.flag().contains(EdgeFlags::PARENT) {
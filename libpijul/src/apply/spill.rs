@@ -0,0 +1,178 @@
+//! A vector that writes its elements to a temporary file when it
+//! grows beyond a given length, to bound the memory used when
+//! applying very large changes. Errors reading or writing the
+//! temporary file are returned to the caller.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+pub(crate) struct SpillVec<T> {
+    mem: Vec<T>,
+    /// Maximum number of elements kept in memory. `None` means that
+    /// this vector never spills.
+    max_len: Option<usize>,
+    #[cfg(feature = "tempfile")]
+    file: Option<std::fs::File>,
+    /// Number of elements currently in `file`, all older than the
+    /// elements of `mem`.
+    spilled: usize,
+    /// Number of elements written to `file` since this vector was
+    /// created.
+    total_spilled: usize,
+}
+
+impl<T> Default for SpillVec<T> {
+    fn default() -> Self {
+        SpillVec {
+            mem: Vec::new(),
+            max_len: None,
+            #[cfg(feature = "tempfile")]
+            file: None,
+            spilled: 0,
+            total_spilled: 0,
+        }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> SpillVec<T> {
+    pub(crate) fn set_max_len(&mut self, max_len: Option<usize>) {
+        self.max_len = max_len.map(|m| m.max(1))
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.mem.is_empty() && self.spilled == 0
+    }
+
+    pub(crate) fn push(&mut self, x: T) -> Result<(), std::io::Error> {
+        if let Some(max_len) = self.max_len {
+            if self.mem.len() >= max_len {
+                self.spill()?
+            }
+        }
+        self.mem.push(x);
+        Ok(())
+    }
+
+    /// Number of elements written to the temporary file since this
+    /// vector was created.
+    #[cfg(test)]
+    pub(crate) fn total_spilled(&self) -> usize {
+        self.total_spilled
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.mem.clear();
+        self.reset_file();
+    }
+
+    /// Remove all the elements, in insertion order. The elements
+    /// are removed even if the iterator isn't consumed entirely.
+    pub(crate) fn drain(&mut self) -> Result<Drain<T>, std::io::Error> {
+        Ok(Drain {
+            reader: self.reader()?,
+            read: 0,
+            mem: None,
+            v: self,
+        })
+    }
+
+    #[cfg(feature = "tempfile")]
+    fn spill(&mut self) -> Result<(), std::io::Error> {
+        use std::io::{Seek, Write};
+        if self.file.is_none() {
+            self.file = Some(tempfile::tempfile()?)
+        }
+        let f = self.file.as_mut().unwrap();
+        // Append after the elements already spilled, even if the file
+        // was read in the meantime.
+        f.seek(std::io::SeekFrom::End(0))?;
+        let mut w = std::io::BufWriter::new(f);
+        for x in self.mem.iter() {
+            bincode::serialize_into(&mut w, x).map_err(bincode_error)?
+        }
+        w.flush()?;
+        debug!("spilled {:?} elements", self.mem.len());
+        self.spilled += self.mem.len();
+        self.total_spilled += self.mem.len();
+        self.mem.clear();
+        Ok(())
+    }
+
+    #[cfg(not(feature = "tempfile"))]
+    fn spill(&mut self) -> Result<(), std::io::Error> {
+        self.max_len = None;
+        Ok(())
+    }
+
+    #[cfg(feature = "tempfile")]
+    fn reader(&mut self) -> Result<Option<std::io::BufReader<std::fs::File>>, std::io::Error> {
+        use std::io::Seek;
+        if self.spilled == 0 {
+            return Ok(None);
+        }
+        let f = self.file.as_mut().unwrap();
+        f.seek(std::io::SeekFrom::Start(0))?;
+        Ok(Some(std::io::BufReader::new(f.try_clone()?)))
+    }
+
+    #[cfg(not(feature = "tempfile"))]
+    fn reader(&mut self) -> Result<Option<std::io::Empty>, std::io::Error> {
+        Ok(None)
+    }
+
+    #[cfg(feature = "tempfile")]
+    fn reset_file(&mut self) {
+        use std::io::Seek;
+        self.spilled = 0;
+        if let Some(ref mut f) = self.file {
+            if f.set_len(0).is_err() || f.seek(std::io::SeekFrom::Start(0)).is_err() {
+                self.file = None
+            }
+        }
+    }
+
+    #[cfg(not(feature = "tempfile"))]
+    fn reset_file(&mut self) {
+        self.spilled = 0;
+    }
+}
+
+pub(crate) struct Drain<'a, T: Serialize + DeserializeOwned> {
+    v: &'a mut SpillVec<T>,
+    #[cfg(feature = "tempfile")]
+    reader: Option<std::io::BufReader<std::fs::File>>,
+    #[cfg(not(feature = "tempfile"))]
+    reader: Option<std::io::Empty>,
+    read: usize,
+    mem: Option<std::vec::IntoIter<T>>,
+}
+
+impl<'a, T: Serialize + DeserializeOwned> Iterator for Drain<'a, T> {
+    type Item = Result<T, std::io::Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.read < self.v.spilled {
+            if let Some(ref mut r) = self.reader {
+                self.read += 1;
+                return Some(bincode::deserialize_from(r).map_err(bincode_error));
+            }
+        }
+        let v = &mut self.v;
+        self.mem
+            .get_or_insert_with(|| std::mem::take(&mut v.mem).into_iter())
+            .next()
+            .map(Ok)
+    }
+}
+
+impl<'a, T: Serialize + DeserializeOwned> Drop for Drain<'a, T> {
+    fn drop(&mut self) {
+        self.v.clear()
+    }
+}
+
+fn bincode_error(e: bincode::Error) -> std::io::Error {
+    match *e {
+        bincode::ErrorKind::Io(e) => e,
+        e => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+    }
+}
@@ -0,0 +1,193 @@
+//! An incremental version of [`check_alive`](crate::pristine::check_alive),
+//! checking only the part of the graph touched by a change that was
+//! just applied. With the `validate` feature, this runs after every
+//! application.
+
+use crate::change::{Atom, Change};
+use crate::missing_context::MissingError;
+use crate::pristine::*;
+use crate::{HashMap, HashSet};
+
+/// Check the invariants checked by [`check_alive`] on the vertices
+/// touched by change `change_id` (the vertices it introduces, and the
+/// endpoints of the edges it adds or deletes) and on their neighbours,
+/// instead of on the whole graph. The results are in the same format
+/// as the results of [`check_alive`]: the alive vertices that are not
+/// reachable from the root (along with the vertex of the file they
+/// belong to, if any), and the vertices that only have pseudo-edges
+/// as alive parents.
+pub fn check_alive_change<T: GraphTxnT>(
+    txn: &T,
+    channel: &T::Graph,
+    change_id: ChangeId,
+    change: &Change,
+) -> Result<
+    (
+        HashMap<Vertex<ChangeId>, Option<Vertex<ChangeId>>>,
+        Vec<(Vertex<ChangeId>, Option<Vertex<ChangeId>>)>,
+    ),
+    MissingError<T::GraphError>,
+> {
+    let mut seeds = HashSet::default();
+    for hunk in change.changes.iter() {
+        for atom in hunk.iter() {
+            match atom {
+                Atom::NewVertex(n) => {
+                    range(txn, channel, change_id, n.start, n.end, &mut seeds)?;
+                    for up in n.up_context.iter() {
+                        block_end(txn, channel, change_id, up, &mut seeds)?
+                    }
+                    for down in n.down_context.iter() {
+                        block(txn, channel, change_id, down, &mut seeds)?
+                    }
+                }
+                Atom::EdgeMap(e) => {
+                    for edge in e.edges.iter() {
+                        block_end(txn, channel, change_id, &edge.from, &mut seeds)?;
+                        let to = internal_vertex(txn, &edge.to, change_id)?;
+                        range(txn, channel, to.change, to.start, to.end, &mut seeds)?;
+                    }
+                }
+            }
+        }
+    }
+
+    // Check the seeds and their neighbours.
+    let mut region = seeds.clone();
+    for &v in seeds.iter() {
+        for e in iter_adj_all(txn, channel, v)? {
+            let e = e?;
+            let w = if e.flag().contains(EdgeFlags::PARENT) {
+                txn.find_block_end(channel, e.dest())
+            } else {
+                txn.find_block(channel, e.dest())
+            };
+            if let Ok(w) = w {
+                region.insert(*w);
+            }
+        }
+    }
+
+    let mut reachable = HashSet::default();
+    reachable.insert(Vertex::ROOT);
+    let mut unreachable = HashSet::default();
+    let mut alive_unreachable = HashMap::default();
+    let mut reachable_pseudo = Vec::new();
+    let mut stack = Vec::new();
+    let mut visited = HashSet::default();
+    for &k in region.iter() {
+        if k.is_root() {
+            continue;
+        }
+        let mut alive = false;
+        let mut has_pseudo_parents = false;
+        let mut has_regular_parents = false;
+        for e in iter_adj_all(txn, channel, k)? {
+            let e = e?;
+            let f = e.flag();
+            if !f.contains(EdgeFlags::PARENT) || f.contains(EdgeFlags::DELETED) {
+                continue;
+            }
+            if !f.contains(EdgeFlags::FOLDER) {
+                if f.contains(EdgeFlags::PSEUDO) {
+                    has_pseudo_parents = true
+                } else {
+                    has_regular_parents = true
+                }
+            }
+            if f.contains(EdgeFlags::BLOCK) || k.is_empty() {
+                alive = true
+            }
+        }
+        if has_pseudo_parents && !has_regular_parents {
+            reachable_pseudo.push((k, find_file(txn, channel, k, &mut stack, &mut visited)?))
+        }
+        if alive && !is_reachable(txn, channel, k, &mut reachable, &mut unreachable)? {
+            let file = find_file(txn, channel, k, &mut stack, &mut visited)?;
+            alive_unreachable.insert(k, file);
+        }
+    }
+    Ok((alive_unreachable, reachable_pseudo))
+}
+
+/// Whether `k` is reachable from the root, following the alive edges
+/// backwards. `reachable` and `unreachable` cache the results across
+/// calls.
+fn is_reachable<T: GraphTxnT>(
+    txn: &T,
+    channel: &T::Graph,
+    k: Vertex<ChangeId>,
+    reachable: &mut HashSet<Vertex<ChangeId>>,
+    unreachable: &mut HashSet<Vertex<ChangeId>>,
+) -> Result<bool, BlockError<T::GraphError>> {
+    let mut visited = HashSet::default();
+    let mut stack = vec![k];
+    let mut found = false;
+    while let Some(v) = stack.pop() {
+        if reachable.contains(&v) {
+            found = true;
+            break;
+        }
+        if unreachable.contains(&v) || !visited.insert(v) {
+            continue;
+        }
+        for e in iter_adjacent(txn, channel, v, EdgeFlags::PARENT, EdgeFlags::all())? {
+            let e = e?;
+            if e.flag().contains(EdgeFlags::PARENT) && !e.flag().contains(EdgeFlags::DELETED) {
+                stack.push(*txn.find_block_end(channel, e.dest())?);
+            }
+        }
+    }
+    // If `k` is reachable, the vertices visited so far aren't
+    // necessarily, so only the negative result is cached for them.
+    if found {
+        reachable.insert(k);
+    } else {
+        unreachable.extend(visited);
+    }
+    Ok(found)
+}
+
+/// Insert the vertices covering `[start, end[` of `change` in `seeds`.
+fn range<T: GraphTxnT>(
+    txn: &T,
+    channel: &T::Graph,
+    change: ChangeId,
+    start: ChangePosition,
+    end: ChangePosition,
+    seeds: &mut HashSet<Vertex<ChangeId>>,
+) -> Result<(), BlockError<T::GraphError>> {
+    let mut pos = start;
+    loop {
+        let v = *txn.find_block(channel, Position { change, pos })?;
+        seeds.insert(v);
+        if v.end >= end || v.end <= pos {
+            return Ok(());
+        }
+        pos = v.end
+    }
+}
+
+fn block<T: GraphTxnT>(
+    txn: &T,
+    channel: &T::Graph,
+    change_id: ChangeId,
+    pos: &Position<Option<Hash>>,
+    seeds: &mut HashSet<Vertex<ChangeId>>,
+) -> Result<(), MissingError<T::GraphError>> {
+    let pos = internal_pos(txn, pos, change_id)?;
+    seeds.insert(*txn.find_block(channel, pos)?);
+    Ok(())
+}
+
+fn block_end<T: GraphTxnT>(
+    txn: &T,
+    channel: &T::Graph,
+    change_id: ChangeId,
+    pos: &Position<Option<Hash>>,
+    seeds: &mut HashSet<Vertex<ChangeId>>,
+) -> Result<(), MissingError<T::GraphError>> {
+    let pos = internal_pos(txn, pos, change_id)?;
+    seeds.insert(*txn.find_block_end(channel, pos)?);
+    Ok(())
+}
@@ -2,7 +2,7 @@ use crate::HashSet;
 use std::collections::BTreeSet;
 
 use crate::pristine::*;
-use crate::text_encoding::Encoding;
+pub use crate::text_encoding::Encoding;
 use chrono::{DateTime, Utc};
 
 #[cfg(feature = "zstd")]
@@ -14,15 +14,29 @@ mod parse;
 mod printable;
 #[cfg(feature = "text-changes")]
 mod text_changes;
+#[cfg(feature = "text-changes")]
+mod text_header;
 pub use parse::*; // for testing
 pub use printable::*; // for testing
-pub use text_changes::{TextDeError, TextSerError, WriteChangeLine};
+pub use text_changes::{
+    get_change_contents, write_hunks, TextDeError, TextSerError, WriteChangeLine,
+};
+pub use text_header::{HeaderError, TextHeader};
 
 mod change_file;
 pub use change_file::*;
 
 mod noenc;
 
+mod contents_index;
+pub use contents_index::{ContentsChunk, ContentsIndex, CHUNK_SIZE};
+
+mod portable;
+pub use portable::*;
+
+mod search;
+pub use search::{search_change, LineMatch};
+
 #[derive(Debug, Error)]
 pub enum ChangeError {
     #[error("Version mismatch: got {}", got)]
@@ -36,6 +50,7 @@ pub enum ChangeError {
     },
     #[error(transparent)]
     Bincode(#[from] bincode::Error),
+    #[cfg(feature = "zstd")]
     #[error(transparent)]
     Zstd(#[from] zstd_seekable::Error),
     #[error(transparent)]
@@ -44,6 +59,13 @@ pub enum ChangeError {
     TomlSer(#[from] toml::ser::Error),
     #[error(transparent)]
     Json(#[from] serde_json::Error),
+    #[error("Unsupported portable change schema: {}", got)]
+    PortableSchemaMismatch { got: u64 },
+    #[cfg(feature = "cbor")]
+    #[error("CBOR error: {0}")]
+    Cbor(String),
+    #[error("Invalid index of the contents section")]
+    InvalidContentsIndex,
     #[error("Missing contents for change {:?}", hash)]
     MissingContents { hash: crate::pristine::Hash },
     #[error("Change hash mismatch, claimed {:?}, computed {:?}", claimed, computed)]
@@ -60,6 +82,8 @@ pub enum ChangeError {
         claimed: crate::pristine::Hash,
         computed: crate::pristine::Hash,
     },
+    #[error(transparent)]
+    FileMetadata(#[from] crate::changestore::FileMetadataError),
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -139,6 +163,115 @@ impl Default for ChangeHeader {
     }
 }
 
+/// A trailer of a change description, such as `Signed-off-by: Name
+/// <email>` or `Fixes: #12`. Trailers are the lines of the last
+/// paragraph of the description, if all of them are of the form
+/// `Key: value`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Trailer {
+    pub key: String,
+    pub value: String,
+}
+
+impl Trailer {
+    pub fn new(key: &str, value: &str) -> Self {
+        Trailer {
+            key: key.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    /// Parse a line of the form `Key: value`, where the key is made
+    /// of ASCII letters, digits and dashes.
+    pub fn parse(line: &str) -> Option<Self> {
+        let i = line.find(':')?;
+        let (key, value) = (&line[..i], line[i + 1..].trim());
+        let valid_key = key.starts_with(|c: char| c.is_ascii_alphabetic())
+            && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+        if valid_key && !value.is_empty() {
+            Some(Trailer::new(key, value))
+        } else {
+            None
+        }
+    }
+}
+
+impl std::fmt::Display for Trailer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.key, self.value)
+    }
+}
+
+/// Split `description` into its body and its trailers.
+fn split_trailers(description: &str) -> (&str, Vec<Trailer>) {
+    let trimmed = description.trim_end();
+    let start = trimmed.rfind("\n\n").map(|i| i + 2).unwrap_or(0);
+    let mut trailers = Vec::new();
+    for line in trimmed[start..].lines() {
+        if let Some(t) = Trailer::parse(line) {
+            trailers.push(t)
+        } else {
+            return (description, Vec::new());
+        }
+    }
+    if trailers.is_empty() {
+        (description, trailers)
+    } else {
+        (trimmed[..start].trim_end(), trailers)
+    }
+}
+
+impl<A> ChangeHeader_<A> {
+    /// The trailers at the end of the description, in order.
+    pub fn trailers(&self) -> Vec<Trailer> {
+        if let Some(ref d) = self.description {
+            split_trailers(d).1
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// The values of the trailers whose key is `key`, ignoring case,
+    /// for example the values of `Signed-off-by`.
+    pub fn trailer_values(&self, key: &str) -> Vec<String> {
+        self.trailers()
+            .into_iter()
+            .filter(|t| t.key.eq_ignore_ascii_case(key))
+            .map(|t| t.value)
+            .collect()
+    }
+
+    /// The description without its trailers, or `None` if the
+    /// description is only made of trailers.
+    pub fn description_body(&self) -> Option<&str> {
+        let d = self.description.as_deref()?;
+        let body = split_trailers(d).0;
+        if body.is_empty() {
+            None
+        } else {
+            Some(body)
+        }
+    }
+
+    /// Append a trailer to the description, unless the exact same
+    /// trailer is already there.
+    pub fn add_trailer(&mut self, trailer: Trailer) {
+        if self.trailers().contains(&trailer) {
+            return;
+        }
+        let has_trailers = !self.trailers().is_empty();
+        let d = self.description.get_or_insert_with(String::new);
+        let len = d.trim_end().len();
+        d.truncate(len);
+        if has_trailers {
+            d.push('\n')
+        } else if !d.is_empty() {
+            d.push_str("\n\n")
+        }
+        d.push_str(&trailer.to_string())
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct LocalChange<Hunk, Author> {
     pub offsets: Offsets,
@@ -165,9 +298,79 @@ impl std::ops::DerefMut for LocalChange<Hunk<Option<Hash>, Local>, Author> {
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Author(pub std::collections::BTreeMap<String, String>);
 
+/// The authors of individual hunks of a change, as indices into the
+/// authors of its header. Hunks without an entry are by all the
+/// authors of the header. This is stored in the `metadata` field of
+/// changes, see [`Hashed::hunk_authors`].
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct HunkAuthors {
+    /// Map from the index of a hunk to the indices of its authors.
+    pub hunk_authors: std::collections::BTreeMap<usize, Vec<usize>>,
+}
+
+impl HunkAuthors {
+    pub fn is_empty(&self) -> bool {
+        self.hunk_authors.is_empty()
+    }
+
+    /// Attribute hunk `hunk` to the authors at indices `authors`.
+    pub fn set(&mut self, hunk: usize, authors: Vec<usize>) {
+        self.hunk_authors.insert(hunk, authors);
+    }
+
+    /// The authors of hunk `hunk`, if it has authors of its own.
+    pub fn get(&self, hunk: usize) -> Option<&[usize]> {
+        self.hunk_authors.get(&hunk).map(|a| a.as_slice())
+    }
+}
+
+/// Display names replacing the names or keys of authors when showing
+/// changes, similar to Git's mailmap: the stored changes are never
+/// modified. The entries are indexed by public key (in base 58) or by
+/// author name, and serialize as a plain map so that they can be
+/// shared (for example by a server).
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct AuthorMap(pub std::collections::BTreeMap<String, String>);
+
+impl AuthorMap {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The display name for the author with this key or name.
+    pub fn get(&self, key_or_name: &str) -> Option<&str> {
+        self.0.get(key_or_name).map(|s| s.as_str())
+    }
+
+    /// The display name of `author`: the entry of its key if there is
+    /// one, else the entry of its name.
+    pub fn display_name(&self, author: &Author) -> Option<&str> {
+        author
+            .0
+            .get("key")
+            .and_then(|k| self.get(k))
+            .or_else(|| author.0.get("name").and_then(|n| self.get(n)))
+    }
+
+    /// Add the entries of `other` for the authors not in this map
+    /// yet, so that local entries take precedence over shared ones.
+    pub fn merge(&mut self, other: &AuthorMap) {
+        for (k, v) in other.0.iter() {
+            self.0.entry(k.clone()).or_insert_with(|| v.clone());
+        }
+    }
+}
+
 // Beware of changes in the version, tags also use that.
 pub const VERSION: u64 = 6;
 pub const VERSION_NOENC: u64 = 4;
+/// Version of the change files where the contents are indexed by
+/// chunks (see [`ContentsIndex`]). This only changes the layout of
+/// the file: the hashed part is the same as in [`VERSION`], so that
+/// changes can be rewritten in this format without changing their
+/// hash.
+pub const CONTENTS_INDEX_VERSION: u64 = 7;
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Hashed<Hunk, Author> {
@@ -190,6 +393,75 @@ pub struct Hashed<Hunk, Author> {
 
 pub type Change = LocalChange<Hunk<Option<Hash>, Local>, Author>;
 
+impl<Hunk> Hashed<Hunk, Author> {
+    /// The per-hunk authors of this change, or `None` if all hunks
+    /// are by all the authors of the header. Author indices out of
+    /// the bounds of the header are ignored.
+    pub fn hunk_authors(&self) -> Option<HunkAuthors> {
+        if self.metadata.is_empty() {
+            return None;
+        }
+        // Other applications may use `metadata` for other things.
+        let mut a: HunkAuthors = serde_json::from_slice(&self.metadata).ok()?;
+        let n = self.header.authors.len();
+        for authors in a.hunk_authors.values_mut() {
+            authors.retain(|&i| i < n)
+        }
+        Some(a)
+    }
+
+    /// Set the per-hunk authors of this change. This changes the
+    /// hash of the change.
+    pub fn set_hunk_authors(&mut self, authors: &HunkAuthors) {
+        let mut m = self.change_metadata();
+        m.hunk_authors = authors.hunk_authors.clone();
+        self.set_change_metadata(&m)
+    }
+
+    /// The fields of the text header of this change that are not
+    /// part of [`ChangeHeader`], such as fields added by tools
+    /// preparing changes.
+    pub fn header_fields(&self) -> std::collections::BTreeMap<String, serde_json::Value> {
+        self.change_metadata().header_fields
+    }
+
+    /// Set the extra fields of the header of this change (see
+    /// [`Hashed::header_fields`]). This changes the hash of the
+    /// change.
+    pub fn set_header_fields(
+        &mut self,
+        fields: std::collections::BTreeMap<String, serde_json::Value>,
+    ) {
+        let mut m = self.change_metadata();
+        m.header_fields = fields;
+        self.set_change_metadata(&m)
+    }
+
+    /// The metadata of this change, or the default metadata if it is
+    /// empty or was written by another application.
+    fn change_metadata(&self) -> ChangeMetadata {
+        serde_json::from_slice(&self.metadata).unwrap_or_default()
+    }
+
+    fn set_change_metadata(&mut self, m: &ChangeMetadata) {
+        self.metadata = if m.hunk_authors.is_empty() && m.header_fields.is_empty() {
+            Vec::new()
+        } else {
+            serde_json::to_vec(m).unwrap()
+        }
+    }
+}
+
+/// The contents of the `metadata` field of changes written by
+/// Pijul. Without header fields, this is the same as [`HunkAuthors`].
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ChangeMetadata {
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    hunk_authors: std::collections::BTreeMap<usize, Vec<usize>>,
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    header_fields: std::collections::BTreeMap<String, serde_json::Value>,
+}
+
 pub fn dependencies<
     'a,
     Local: 'a,
@@ -706,6 +978,23 @@ pub struct Local {
     pub line: usize,
 }
 
+/// The positions in the working copy attached to hunks.
+pub trait LocalPath {
+    fn path(&self) -> &str;
+}
+
+impl LocalPath for Local {
+    fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl LocalPath for LocalByte {
+    fn path(&self) -> &str {
+        &self.path
+    }
+}
+
 pub type Hunk<Hash, Local> = BaseHunk<Atom<Hash>, Local>;
 
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
@@ -810,6 +1099,23 @@ impl<Context, Local> Hunk<Context, Local> {
             extra2: None,
         }
     }
+
+    /// The short code of this hunk, as shown by `pijul diff --short`,
+    /// or `None` for the hunks that don't touch any file.
+    pub fn short_code(&self) -> Option<&'static str> {
+        Some(match self {
+            Hunk::FileMove { .. } => "MV",
+            Hunk::FileDel { .. } => "D",
+            Hunk::FileUndel { .. } => "UD",
+            Hunk::FileAdd { .. } => "A",
+            Hunk::Edit { .. } => "M",
+            Hunk::Replacement { .. } => "R",
+            Hunk::SolveNameConflict { .. } | Hunk::SolveOrderConflict { .. } => "SC",
+            Hunk::UnsolveNameConflict { .. } | Hunk::UnsolveOrderConflict { .. } => "UC",
+            Hunk::ResurrectZombies { .. } => "RZ",
+            Hunk::AddRoot { .. } | Hunk::DelRoot { .. } => return None,
+        })
+    }
 }
 
 impl<Context, Local> Iterator for HunkIter<Hunk<Context, Local>, Atom<Context>> {
@@ -1133,6 +1439,12 @@ impl<H> Hunk<H, Local> {
         }
     }
 
+    pub fn line(&self) -> Option<usize> {
+        self.local().map(|x| x.line)
+    }
+}
+
+impl<H, L: LocalPath> Hunk<H, L> {
     pub fn path(&self) -> &str {
         match self {
             Hunk::FileMove { ref path, .. }
@@ -1145,14 +1457,10 @@ impl<H> Hunk<H, Local> {
             | Hunk::Replacement { ref local, .. }
             | Hunk::SolveOrderConflict { ref local, .. }
             | Hunk::UnsolveOrderConflict { ref local, .. }
-            | Hunk::ResurrectZombies { ref local, .. } => &local.path,
+            | Hunk::ResurrectZombies { ref local, .. } => local.path(),
             Hunk::AddRoot { .. } | Hunk::DelRoot { .. } => "/",
         }
     }
-
-    pub fn line(&self) -> Option<usize> {
-        self.local().map(|x| x.line)
-    }
 }
 
 impl<A, Local> BaseHunk<A, Local> {
@@ -1299,6 +1607,20 @@ pub struct Offsets {
     pub total: u64,
 }
 
+impl Offsets {
+    /// Check that the file is in a version of the format this
+    /// library can read.
+    pub fn check_version(&self) -> Result<(), ChangeError> {
+        if self.version != CONTENTS_INDEX_VERSION
+            && self.version != VERSION
+            && self.version != VERSION_NOENC
+        {
+            return Err(ChangeError::VersionMismatch { got: self.version });
+        }
+        Ok(())
+    }
+}
+
 impl LocalChange<Hunk<Option<Hash>, Local>, Author> {
     #[cfg(feature = "zstd")]
     pub const OFFSETS_SIZE: u64 = 56;
@@ -1359,21 +1681,85 @@ const LEVEL: usize = 10;
 const FRAME_SIZE: usize = 256;
 #[cfg(feature = "zstd")]
 fn compress(input: &[u8], w: &mut Vec<u8>) -> Result<(), ChangeError> {
-    let mut cstream = zstd_seekable::SeekableCStream::new(LEVEL, FRAME_SIZE).unwrap();
-    let mut output = [0; 4096];
-    let mut input_pos = 0;
-    while input_pos < input.len() {
-        let (out_pos, inp_pos) = cstream.compress(&mut output, &input[input_pos..])?;
-        w.write_all(&output[..out_pos])?;
-        input_pos += inp_pos;
-    }
-    while let Ok(n) = cstream.end_stream(&mut output) {
-        if n == 0 {
-            break;
+    compress_frames(input, FRAME_SIZE, w)
+}
+
+#[cfg(feature = "zstd")]
+fn compress_frames(input: &[u8], frame_size: usize, w: &mut Vec<u8>) -> Result<(), ChangeError> {
+    let mut c = Compressor::new(frame_size);
+    c.write(input, w)?;
+    c.finish(w)
+}
+
+/// A seekable zstd stream, fed with consecutive pieces of its input.
+#[cfg(feature = "zstd")]
+struct Compressor {
+    cstream: zstd_seekable::SeekableCStream,
+    output: [u8; 4096],
+}
+
+#[cfg(feature = "zstd")]
+impl Compressor {
+    fn new(frame_size: usize) -> Self {
+        Compressor {
+            cstream: zstd_seekable::SeekableCStream::new(LEVEL, frame_size).unwrap(),
+            output: [0; 4096],
+        }
+    }
+
+    fn write(&mut self, input: &[u8], w: &mut Vec<u8>) -> Result<(), ChangeError> {
+        let mut input_pos = 0;
+        while input_pos < input.len() {
+            let (out_pos, inp_pos) = self
+                .cstream
+                .compress(&mut self.output, &input[input_pos..])?;
+            w.write_all(&self.output[..out_pos])?;
+            input_pos += inp_pos;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self, w: &mut Vec<u8>) -> Result<(), ChangeError> {
+        while let Ok(n) = self.cstream.end_stream(&mut self.output) {
+            if n == 0 {
+                break;
+            }
+            w.write_all(&self.output[..n])?;
+        }
+        Ok(())
+    }
+}
+
+/// The compressor of the contents section, in either format.
+#[cfg(feature = "zstd")]
+enum ContentsWriter {
+    Frames(Compressor),
+    Indexed(contents_index::IndexWriter),
+}
+
+#[cfg(feature = "zstd")]
+impl ContentsWriter {
+    fn new(version: u64) -> Self {
+        if version == CONTENTS_INDEX_VERSION {
+            ContentsWriter::Indexed(contents_index::IndexWriter::new())
+        } else {
+            ContentsWriter::Frames(Compressor::new(FRAME_SIZE))
+        }
+    }
+
+    fn write(&mut self, input: &[u8], w: &mut Vec<u8>) -> Result<(), ChangeError> {
+        match self {
+            ContentsWriter::Frames(c) => c.write(input, w),
+            ContentsWriter::Indexed(c) => c.write(input),
+        }
+    }
+
+    fn finish(self, w: &mut Vec<u8>) -> Result<(), ChangeError> {
+        match self {
+            ContentsWriter::Frames(c) => c.finish(w),
+            ContentsWriter::Indexed(c) => c.finish(w),
         }
-        w.write_all(&output[..n])?;
     }
-    Ok(())
 }
 
 impl Change {
@@ -1384,9 +1770,7 @@ impl Change {
         let mut off = [0u8; Self::OFFSETS_SIZE as usize];
         r.read_exact(&mut off)?;
         let off: Offsets = bincode::deserialize(&off)?;
-        if off.version != VERSION && off.version != VERSION_NOENC {
-            return Err(ChangeError::VersionMismatch { got: off.version });
-        }
+        off.check_version()?;
         r.seek(std::io::SeekFrom::Start(pos))?;
         Ok(off.contents_off)
     }
@@ -1399,9 +1783,76 @@ impl Change {
         W: Write,
         E: From<ChangeError>,
         F: FnOnce(&mut Self, &Hash) -> Result<(), E>,
+    >(
+        &mut self,
+        w: W,
+        f: F,
+    ) -> Result<Hash, E> {
+        self.serialize_version(w, VERSION, None, f)
+    }
+
+    /// Same as [`serialize`](Self::serialize), but in the
+    /// [`CONTENTS_INDEX_VERSION`] format, which versions of Pijul
+    /// older than this one can't read.
+    #[cfg(feature = "zstd")]
+    pub fn serialize_indexed<
+        W: Write,
+        E: From<ChangeError>,
+        F: FnOnce(&mut Self, &Hash) -> Result<(), E>,
+    >(
+        &mut self,
+        w: W,
+        f: F,
+    ) -> Result<Hash, E> {
+        self.serialize_version(w, CONTENTS_INDEX_VERSION, None, f)
+    }
+
+    /// Same as [`serialize`](Self::serialize), for a change whose
+    /// contents are in `contents` rather than in `self.contents`
+    /// (which is ignored), for example a change made by
+    /// [`Recorded::into_change`](crate::record::Recorded::into_change).
+    /// The contents are compressed from `contents`, one chunk at a
+    /// time, without loading them in memory.
+    #[cfg(feature = "zstd")]
+    pub fn serialize_with_contents<
+        W: Write,
+        E: From<ChangeError>,
+        F: FnOnce(&mut Self, &Hash) -> Result<(), E>,
+    >(
+        &mut self,
+        w: W,
+        contents: &mut crate::record::ContentArena,
+        f: F,
+    ) -> Result<Hash, E> {
+        self.serialize_version(w, VERSION, Some(contents), f)
+    }
+
+    /// Same as [`serialize_with_contents`](Self::serialize_with_contents),
+    /// in the [`CONTENTS_INDEX_VERSION`] format.
+    #[cfg(feature = "zstd")]
+    pub fn serialize_indexed_with_contents<
+        W: Write,
+        E: From<ChangeError>,
+        F: FnOnce(&mut Self, &Hash) -> Result<(), E>,
+    >(
+        &mut self,
+        w: W,
+        contents: &mut crate::record::ContentArena,
+        f: F,
+    ) -> Result<Hash, E> {
+        self.serialize_version(w, CONTENTS_INDEX_VERSION, Some(contents), f)
+    }
+
+    #[cfg(feature = "zstd")]
+    fn serialize_version<
+        W: Write,
+        E: From<ChangeError>,
+        F: FnOnce(&mut Self, &Hash) -> Result<(), E>,
     >(
         &mut self,
         mut w: W,
+        version: u64,
+        contents: Option<&mut crate::record::ContentArena>,
         f: F,
     ) -> Result<Hash, E> {
         // Hashed part.
@@ -1436,20 +1887,36 @@ impl Change {
         let contents_off = unhashed_off + unhashed_comp.len() as u64;
         let mut contents_comp = Vec::new();
         let now = std::time::Instant::now();
-        compress(&self.contents, &mut contents_comp)?;
+        let mut cw = ContentsWriter::new(version);
+        let contents_len = if let Some(contents) = contents {
+            let mut result = Ok(());
+            contents
+                .for_each_chunk(|chunk| {
+                    if result.is_ok() {
+                        result = cw.write(chunk, &mut contents_comp)
+                    }
+                })
+                .map_err(ChangeError::from)?;
+            result?;
+            contents.len()
+        } else {
+            cw.write(&self.contents, &mut contents_comp)?;
+            self.contents.len()
+        };
+        cw.finish(&mut contents_comp)?;
         debug!(
             "compressed {:?} bytes of contents in {:?}",
-            self.contents.len(),
+            contents_len,
             now.elapsed()
         );
 
         let offsets = Offsets {
-            version: VERSION,
+            version,
             hashed_len: hashed.len() as u64,
             unhashed_off,
             unhashed_len: unhashed.len() as u64,
             contents_off,
-            contents_len: self.contents.len() as u64,
+            contents_len: contents_len as u64,
             total: contents_off + contents_comp.len() as u64,
         };
 
@@ -1466,11 +1933,7 @@ impl Change {
     #[cfg(feature = "zstd")]
     pub fn check_from_buffer(buf: &[u8], hash: &Hash) -> Result<(), ChangeError> {
         let offsets: Offsets = bincode::deserialize_from(&buf[..Self::OFFSETS_SIZE as usize])?;
-        if offsets.version != VERSION && offsets.version != VERSION_NOENC {
-            return Err(ChangeError::VersionMismatch {
-                got: offsets.version,
-            });
-        }
+        offsets.check_version()?;
 
         debug!("check_from_buffer, offsets = {:?}", offsets);
         let mut s = zstd_seekable::Seekable::init_buf(
@@ -1492,18 +1955,25 @@ impl Change {
             .into());
         }
 
-        let hashed: Hashed<Hunk<Option<Hash>, Local>, Author> = if offsets.version == VERSION {
+        let hashed: Hashed<Hunk<Option<Hash>, Local>, Author> = if offsets.version != VERSION_NOENC
+        {
             bincode::deserialize(&buf_)?
         } else {
             let h: Hashed<noenc::Hunk<Option<Hash>, Local>, noenc::Author> =
                 bincode::deserialize(&buf_)?;
             h.into()
         };
-        buf_.clear();
-        buf_.resize(offsets.contents_len as usize, 0);
-        let mut s = zstd_seekable::Seekable::init_buf(&buf[offsets.contents_off as usize..])?;
-        buf_.resize(offsets.contents_len as usize, 0);
-        s.decompress(&mut buf_[..], 0)?;
+        if offsets.version == CONTENTS_INDEX_VERSION {
+            buf_ = contents_index::read_indexed(
+                &buf[offsets.contents_off as usize..],
+                offsets.contents_len,
+            )?;
+        } else {
+            buf_.clear();
+            buf_.resize(offsets.contents_len as usize, 0);
+            let mut s = zstd_seekable::Seekable::init_buf(&buf[offsets.contents_off as usize..])?;
+            s.decompress(&mut buf_[..], 0)?;
+        }
         let mut hasher = Hasher::default();
         trace!("contents = {:?}", buf_);
         hasher.update(&buf_);
@@ -1535,12 +2005,9 @@ impl Change {
         let mut buf = vec![0u8; Self::OFFSETS_SIZE as usize];
         r.read_exact(&mut buf)?;
         let offsets: Offsets = bincode::deserialize(&buf)?;
+        offsets.check_version()?;
         if offsets.version == VERSION_NOENC {
             return Self::deserialize_noenc(offsets, r, hash);
-        } else if offsets.version != VERSION {
-            return Err(ChangeError::VersionMismatch {
-                got: offsets.version,
-            });
         }
         debug!("offsets = {:?}", offsets);
         buf.clear();
@@ -1580,13 +2047,15 @@ impl Change {
 
         buf.clear();
         buf.resize((offsets.total - offsets.contents_off) as usize, 0);
-        let contents = if r.read_exact(&mut buf).is_ok() {
+        let contents = if r.read_exact(&mut buf).is_err() {
+            Vec::new()
+        } else if offsets.version == CONTENTS_INDEX_VERSION {
+            contents_index::read_indexed(&buf, offsets.contents_len)?
+        } else {
             let mut s = zstd_seekable::Seekable::init_buf(&buf[..])?;
             let mut contents = vec![0u8; offsets.contents_len as usize];
             s.decompress(&mut contents[..], 0)?;
             contents
-        } else {
-            Vec::new()
         };
         debug!("contents = {:?}", contents);
 
@@ -1,17 +1,28 @@
+use super::contents_index::IndexedContents;
 use super::*;
 
 /// An open, seekable change file.
 #[cfg(feature = "zstd")]
 pub struct ChangeFile {
-    s: Option<zstd_seekable::Seekable<'static, OffFile>>,
+    s: Option<Contents>,
     hashed: Hashed<Hunk<Option<Hash>, Local>, Author>,
     hash: Hash,
     unhashed: Option<toml::Value>,
 }
 
+/// The contents section, depending on the version of the file.
+#[cfg(feature = "zstd")]
+enum Contents {
+    Seekable(zstd_seekable::Seekable<'static, OffFile>),
+    Indexed(IndexedContents),
+}
+
+/// A section of a file, starting at offset `start`, and ending at
+/// offset `end`, or at the end of the file if `end` is `None`.
 struct OffFile {
     f: std::fs::File,
     start: u64,
+    end: Option<u64>,
 }
 
 unsafe impl Send for OffFile {}
@@ -27,6 +38,13 @@ impl std::io::Seek for OffFile {
         use std::io::SeekFrom;
         let from = match from {
             SeekFrom::Start(s) => SeekFrom::Start(s + self.start),
+            SeekFrom::End(e) => {
+                if let Some(end) = self.end {
+                    SeekFrom::Start((end as i64 + e) as u64)
+                } else {
+                    SeekFrom::End(e)
+                }
+            }
             c => c,
         };
         self.f.seek(from)
@@ -43,17 +61,14 @@ impl ChangeFile {
         buf.resize(Change::OFFSETS_SIZE as usize, 0);
         r.read_exact(&mut buf)?;
         let offsets: Offsets = bincode::deserialize(&buf)?;
-        if offsets.version != VERSION && offsets.version != VERSION_NOENC {
-            return Err(ChangeError::VersionMismatch {
-                got: offsets.version,
-            });
-        }
+        offsets.check_version()?;
 
         buf.clear();
         buf.resize((offsets.unhashed_off - Change::OFFSETS_SIZE) as usize, 0);
         r.read_exact(&mut buf)?;
         let mut buf2 = vec![0u8; offsets.hashed_len as usize];
-        let hashed: Hashed<Hunk<Option<Hash>, Local>, Author> = if offsets.version == VERSION {
+        let hashed: Hashed<Hunk<Option<Hash>, Local>, Author> = if offsets.version != VERSION_NOENC
+        {
             let mut s = zstd_seekable::Seekable::init_buf(&buf)?;
             s.decompress(&mut buf2, 0)?;
             trace!("deserialize current version {:?}", buf2.len());
@@ -83,11 +98,20 @@ impl ChangeFile {
         let m = r.metadata()?;
         let s = if offsets.contents_off >= m.len() {
             None
+        } else if offsets.version == CONTENTS_INDEX_VERSION {
+            Some(Contents::Indexed(IndexedContents::open(
+                r,
+                offsets.contents_off,
+                offsets.contents_len,
+            )?))
         } else {
-            Some(zstd_seekable::Seekable::init(Box::new(OffFile {
-                f: r,
-                start: offsets.contents_off,
-            }))?)
+            Some(Contents::Seekable(zstd_seekable::Seekable::init(
+                Box::new(OffFile {
+                    f: r,
+                    start: offsets.contents_off,
+                    end: None,
+                }),
+            )?))
         };
         Ok(ChangeFile {
             s,
@@ -106,10 +130,10 @@ impl ChangeFile {
     /// section are not checked.
     pub fn read_contents(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, ChangeError> {
         trace!("read_contents {:?} {:?}", offset, buf.len());
-        if let Some(ref mut s) = self.s {
-            Ok(s.decompress(buf, offset)?)
-        } else {
-            Err(ChangeError::MissingContents { hash: self.hash })
+        match self.s {
+            Some(Contents::Seekable(ref mut s)) => Ok(s.decompress(buf, offset)?),
+            Some(Contents::Indexed(ref mut s)) => s.read(offset, buf),
+            None => Err(ChangeError::MissingContents { hash: self.hash }),
         }
     }
 
@@ -121,3 +145,159 @@ impl ChangeFile {
         &self.unhashed
     }
 }
+
+/// Size of the pieces in which [`HunkReader`] decompresses the
+/// hashed section of a change.
+#[cfg(feature = "zstd")]
+const HUNK_READER_BUF_SIZE: usize = 1 << 16;
+
+/// The hunks of a change file, read and decompressed one by one from
+/// the disk instead of loading the whole change in memory.
+///
+/// The hash of the change is only known once all the hunks have been
+/// read: if it doesn't match, the last item is a
+/// [`ChangeError::ChangeHashMismatch`].
+#[cfg(feature = "zstd")]
+pub struct HunkReader {
+    hunks: Hunks,
+    hash: Hash,
+}
+
+#[cfg(feature = "zstd")]
+enum Hunks {
+    Stream {
+        r: HashedReader,
+        remaining: u64,
+    },
+    /// Files in older versions of the format are loaded in memory
+    /// and converted.
+    Loaded(std::vec::IntoIter<Hunk<Option<Hash>, Local>>),
+    Done,
+}
+
+/// The fields of [`Hashed`] before the hunks.
+#[cfg(feature = "zstd")]
+#[derive(Deserialize)]
+struct HashedPrefix {
+    _version: u64,
+    _header: ChangeHeader_<Author>,
+    _dependencies: Vec<Hash>,
+    _extra_known: Vec<Hash>,
+    _metadata: Vec<u8>,
+}
+
+/// The decompressed hashed section of a change, hashed along the way.
+#[cfg(feature = "zstd")]
+struct HashedReader {
+    s: zstd_seekable::Seekable<'static, OffFile>,
+    buf: Vec<u8>,
+    pos: usize,
+    /// Offset of the end of `buf` in the decompressed section.
+    off: u64,
+    len: u64,
+    hasher: Hasher,
+}
+
+#[cfg(feature = "zstd")]
+impl std::io::Read for HashedReader {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize, std::io::Error> {
+        if self.pos >= self.buf.len() {
+            let n = (self.len - self.off).min(HUNK_READER_BUF_SIZE as u64) as usize;
+            if n == 0 {
+                return Ok(0);
+            }
+            self.buf.resize(n, 0);
+            self.s
+                .decompress(&mut self.buf, self.off)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+            self.hasher.update(&self.buf);
+            self.off += n as u64;
+            self.pos = 0;
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl HunkReader {
+    /// Start reading the hunks of change `hash`, from the file at
+    /// `path`.
+    pub fn open(hash: Hash, path: &str) -> Result<Self, ChangeError> {
+        use std::io::Read;
+        let mut f = std::fs::File::open(path).map_err(|err| ChangeError::IoHash { err, hash })?;
+        let mut buf = vec![0u8; Change::OFFSETS_SIZE as usize];
+        f.read_exact(&mut buf)?;
+        let offsets: Offsets = bincode::deserialize(&buf)?;
+        offsets.check_version()?;
+        if offsets.version == VERSION_NOENC {
+            let change = Change::deserialize(path, Some(&hash))?;
+            return Ok(HunkReader {
+                hunks: Hunks::Loaded(change.hashed.changes.into_iter()),
+                hash,
+            });
+        }
+        let s = zstd_seekable::Seekable::init(Box::new(OffFile {
+            f,
+            start: Change::OFFSETS_SIZE,
+            end: Some(offsets.unhashed_off),
+        }))?;
+        let mut r = HashedReader {
+            s,
+            buf: Vec::new(),
+            pos: 0,
+            off: 0,
+            len: offsets.hashed_len,
+            hasher: Hasher::default(),
+        };
+        let _: HashedPrefix = bincode::deserialize_from(&mut r)?;
+        let remaining: u64 = bincode::deserialize_from(&mut r)?;
+        Ok(HunkReader {
+            hunks: Hunks::Stream { r, remaining },
+            hash,
+        })
+    }
+
+    /// Read the end of the hashed section, and check the hash.
+    fn finish(r: &mut HashedReader, hash: &Hash) -> Result<(), ChangeError> {
+        let _: Hash = bincode::deserialize_from(&mut *r)?;
+        std::io::copy(r, &mut std::io::sink())?;
+        let computed = r.hasher.finish();
+        if &computed != hash {
+            return Err(ChangeError::ChangeHashMismatch {
+                claimed: *hash,
+                computed,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Iterator for HunkReader {
+    type Item = Result<Hunk<Option<Hash>, Local>, ChangeError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.hunks {
+            Hunks::Stream {
+                ref mut r,
+                ref mut remaining,
+            } => {
+                if *remaining > 0 {
+                    *remaining -= 1;
+                    let hunk = bincode::deserialize_from(&mut *r).map_err(From::from);
+                    if hunk.is_err() {
+                        self.hunks = Hunks::Done
+                    }
+                    return Some(hunk);
+                }
+                let result = Self::finish(r, &self.hash);
+                self.hunks = Hunks::Done;
+                result.err().map(Err)
+            }
+            Hunks::Loaded(ref mut it) => it.next().map(Ok),
+            Hunks::Done => None,
+        }
+    }
+}
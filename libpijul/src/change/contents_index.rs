@@ -0,0 +1,224 @@
+//! The contents section of the changes in version
+//! [`CONTENTS_INDEX_VERSION`] of the format. The contents are cut
+//! into chunks of [`CHUNK_SIZE`] bytes, compressed independently
+//! (unless compressing a chunk doesn't make it smaller), and preceded
+//! by a table of the chunks. Reading the contents of a vertex then
+//! only reads and decompresses the chunks covering that vertex.
+
+use super::*;
+#[cfg(feature = "zstd")]
+use std::io::{Read, Seek, SeekFrom};
+
+/// Size of the uncompressed chunks (except the last one, which may be
+/// shorter).
+pub const CHUNK_SIZE: u64 = 4096;
+
+/// The table of chunks at the beginning of the contents section.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct ContentsIndex {
+    pub chunk_size: u64,
+    pub chunks: Vec<ContentsChunk>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct ContentsChunk {
+    /// Offset of the chunk, counted from the end of the index.
+    pub off: u64,
+    /// Length of the chunk in the file.
+    pub len: u64,
+    /// Whether the chunk is compressed, or stored as is.
+    pub compressed: bool,
+}
+
+impl ContentsIndex {
+    /// Uncompressed length of chunk `i`, where `len` is the total
+    /// length of the contents.
+    fn chunk_len(&self, i: usize, len: u64) -> usize {
+        (len - i as u64 * self.chunk_size).min(self.chunk_size) as usize
+    }
+
+    fn check(&self, len: u64) -> Result<(), ChangeError> {
+        if self.chunk_size == 0
+            || self.chunks.len() as u64 != (len + self.chunk_size - 1) / self.chunk_size
+        {
+            return Err(ChangeError::InvalidContentsIndex);
+        }
+        Ok(())
+    }
+}
+
+/// Writer of the index and the chunks of the contents, fed with
+/// consecutive pieces of the contents, of any size.
+#[cfg(feature = "zstd")]
+pub(super) struct IndexWriter {
+    index: ContentsIndex,
+    /// The compressed chunks.
+    data: Vec<u8>,
+    /// The start of the current chunk, shorter than `CHUNK_SIZE`.
+    pending: Vec<u8>,
+    comp: Vec<u8>,
+}
+
+#[cfg(feature = "zstd")]
+impl IndexWriter {
+    pub(super) fn new() -> Self {
+        IndexWriter {
+            index: ContentsIndex {
+                chunk_size: CHUNK_SIZE,
+                chunks: Vec::new(),
+            },
+            data: Vec::new(),
+            pending: Vec::with_capacity(CHUNK_SIZE as usize),
+            comp: Vec::new(),
+        }
+    }
+
+    pub(super) fn write(&mut self, mut input: &[u8]) -> Result<(), ChangeError> {
+        while !input.is_empty() {
+            let n = (CHUNK_SIZE as usize - self.pending.len()).min(input.len());
+            self.pending.extend_from_slice(&input[..n]);
+            input = &input[n..];
+            if self.pending.len() == CHUNK_SIZE as usize {
+                self.write_chunk()?
+            }
+        }
+        Ok(())
+    }
+
+    fn write_chunk(&mut self) -> Result<(), ChangeError> {
+        self.comp.clear();
+        compress_frames(&self.pending, CHUNK_SIZE as usize, &mut self.comp)?;
+        let off = self.data.len() as u64;
+        let compressed = self.comp.len() < self.pending.len();
+        if compressed {
+            self.data.extend_from_slice(&self.comp)
+        } else {
+            self.data.extend_from_slice(&self.pending)
+        }
+        self.index.chunks.push(ContentsChunk {
+            off,
+            len: self.data.len() as u64 - off,
+            compressed,
+        });
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Write the index and the chunks to `w`.
+    pub(super) fn finish(mut self, w: &mut Vec<u8>) -> Result<(), ChangeError> {
+        if !self.pending.is_empty() {
+            self.write_chunk()?
+        }
+        bincode::serialize_into(&mut *w, &self.index)?;
+        w.extend_from_slice(&self.data);
+        Ok(())
+    }
+}
+
+/// Decode a whole contents section, of `len` bytes once decoded.
+#[cfg(feature = "zstd")]
+pub(super) fn read_indexed(mut buf: &[u8], len: u64) -> Result<Vec<u8>, ChangeError> {
+    let index: ContentsIndex = bincode::deserialize_from(&mut buf)?;
+    index.check(len)?;
+    let mut contents = Vec::with_capacity(len as usize);
+    for (i, c) in index.chunks.iter().enumerate() {
+        let data = buf
+            .get(c.off as usize..(c.off + c.len) as usize)
+            .ok_or(ChangeError::InvalidContentsIndex)?;
+        decode_chunk(c, data, index.chunk_len(i, len), &mut contents)?
+    }
+    Ok(contents)
+}
+
+#[cfg(feature = "zstd")]
+fn decode_chunk(
+    c: &ContentsChunk,
+    data: &[u8],
+    len: usize,
+    out: &mut Vec<u8>,
+) -> Result<(), ChangeError> {
+    if c.compressed {
+        let start = out.len();
+        out.resize(start + len, 0);
+        let mut s = zstd_seekable::Seekable::init_buf(data)?;
+        s.decompress(&mut out[start..], 0)?;
+    } else if data.len() == len {
+        out.extend_from_slice(data)
+    } else {
+        return Err(ChangeError::InvalidContentsIndex);
+    }
+    Ok(())
+}
+
+/// The contents section of an open change file, keeping the last
+/// chunk read in memory, since consecutive reads are often in the
+/// same chunk.
+#[cfg(feature = "zstd")]
+pub(super) struct IndexedContents {
+    f: std::fs::File,
+    /// Offset of the end of the index in the file.
+    start: u64,
+    index: ContentsIndex,
+    len: u64,
+    current: Option<(usize, Vec<u8>)>,
+}
+
+#[cfg(feature = "zstd")]
+impl IndexedContents {
+    /// Read the index of the contents section starting at offset
+    /// `contents_off` of `f`, of `len` bytes once decoded.
+    pub(super) fn open(
+        mut f: std::fs::File,
+        contents_off: u64,
+        len: u64,
+    ) -> Result<Self, ChangeError> {
+        f.seek(SeekFrom::Start(contents_off))?;
+        let index: ContentsIndex = bincode::deserialize_from(std::io::BufReader::new(&mut f))?;
+        index.check(len)?;
+        let start = contents_off + bincode::serialized_size(&index)?;
+        Ok(IndexedContents {
+            f,
+            start,
+            index,
+            len,
+            current: None,
+        })
+    }
+
+    /// Read the contents starting at `offset` into `buf`, and return
+    /// the number of bytes read.
+    pub(super) fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, ChangeError> {
+        let mut n = 0;
+        while n < buf.len() {
+            let pos = offset + n as u64;
+            let i = (pos / self.index.chunk_size) as usize;
+            if i >= self.index.chunks.len() {
+                break;
+            }
+            let chunk = self.chunk(i)?;
+            let start = (pos % self.index.chunk_size) as usize;
+            let m = (chunk.len() - start).min(buf.len() - n);
+            buf[n..n + m].copy_from_slice(&chunk[start..start + m]);
+            n += m
+        }
+        Ok(n)
+    }
+
+    fn chunk(&mut self, i: usize) -> Result<&[u8], ChangeError> {
+        if self.current.as_ref().map(|c| c.0) != Some(i) {
+            let c = self.index.chunks[i];
+            let mut data = vec![0; c.len as usize];
+            self.f.seek(SeekFrom::Start(self.start + c.off))?;
+            self.f.read_exact(&mut data)?;
+            let mut out = if let Some((_, mut out)) = self.current.take() {
+                out.clear();
+                out
+            } else {
+                Vec::new()
+            };
+            decode_chunk(&c, &data, self.index.chunk_len(i, self.len), &mut out)?;
+            self.current = Some((i, out))
+        }
+        Ok(&self.current.as_ref().unwrap().1)
+    }
+}
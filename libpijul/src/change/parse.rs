@@ -587,10 +587,10 @@ fn parse_edges(input: &str) -> IResult<&str, Vec<PrintableEdge>> {
     )(input)
 }
 
-pub fn parse_header(input: &str) -> IResult<&str, Result<ChangeHeader, toml::de::Error>> {
+pub fn parse_header(input: &str) -> IResult<&str, Result<TextHeader, HeaderError>> {
     map(
         alt((take_until("# Dependencies"), take_until("# Hunks"))),
-        |s| toml::de::from_str(s),
+        TextHeader::parse,
     )(input)
 }
 
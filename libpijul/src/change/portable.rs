@@ -0,0 +1,314 @@
+//! A stable, documented serialization of changes in JSON (and in
+//! CBOR with the `cbor` feature), meant for external tools that
+//! want to render or construct changes without depending on the
+//! internal binary format.
+//!
+//! A portable change (schema version 1) has the following fields:
+//!
+//! - `schema`: the version of this schema, currently 1.
+//! - `version`: the version of the change format the change was
+//!   made for (see [`VERSION`]).
+//! - `hash` (optional): the hash of the change, in base32. When
+//!   present, it is checked when reading the change back.
+//! - `header`: message, description, timestamp (RFC 3339) and
+//!   authors (as maps of strings).
+//! - `dependencies` and `extra_known`: lists of base32 hashes.
+//! - `metadata`: application-specific bytes, in base64.
+//! - `hunks`: the hunks of the change, following the structure of
+//!   [`BaseHunk`], where all change hashes are written in base32
+//!   (and `null` for the change itself).
+//! - `contents_hash`: the hash of the contents, in base32.
+//! - `contents`: the contents in base64, or `null` if they were
+//!   detached, in which case they must be supplied separately when
+//!   reading the change back.
+//! - `unhashed` (optional): the unhashed JSON part of the change
+//!   (signatures for instance).
+//!
+//! New fields may be added without changing the schema version, and
+//! readers should ignore unknown fields.
+
+use super::*;
+use crate::pristine::Base32;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::io::Write;
+
+/// The current version of the portable schema.
+pub const PORTABLE_SCHEMA_VERSION: u64 = 1;
+
+/// A change hash, serialized in base32.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Base32Hash(pub Hash);
+
+impl Serialize for Base32Hash {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&self.0.to_base32())
+    }
+}
+
+impl<'de> Deserialize<'de> for Base32Hash {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        if let Some(h) = Hash::from_base32(s.as_bytes()) {
+            Ok(Base32Hash(h))
+        } else {
+            Err(serde::de::Error::custom(format!("Invalid hash: {:?}", s)))
+        }
+    }
+}
+
+/// The portable representation of a change. See the module
+/// documentation for a description of the fields.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortableChange {
+    pub schema: u64,
+    pub version: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<Base32Hash>,
+    pub header: ChangeHeader,
+    pub dependencies: Vec<Base32Hash>,
+    pub extra_known: Vec<Base32Hash>,
+    #[serde(with = "base64")]
+    pub metadata: Vec<u8>,
+    pub hunks: Vec<Hunk<Option<Base32Hash>, Local>>,
+    pub contents_hash: Base32Hash,
+    #[serde(default, with = "base64_opt")]
+    pub contents: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unhashed: Option<serde_json::Value>,
+}
+
+mod base64 {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(b: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&data_encoding::BASE64.encode(b))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(d)?;
+        data_encoding::BASE64
+            .decode(s.as_bytes())
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+mod base64_opt {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(b: &Option<Vec<u8>>, s: S) -> Result<S::Ok, S::Error> {
+        if let Some(b) = b {
+            super::base64::serialize(b, s)
+        } else {
+            s.serialize_none()
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<u8>>, D::Error> {
+        if let Some(s) = Option::<String>::deserialize(d)? {
+            Ok(Some(
+                data_encoding::BASE64
+                    .decode(s.as_bytes())
+                    .map_err(serde::de::Error::custom)?,
+            ))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+fn map_position<A, B, F: FnMut(A) -> B>(p: Position<A>, f: &mut F) -> Position<B> {
+    Position {
+        change: f(p.change),
+        pos: p.pos,
+    }
+}
+
+fn map_atom<A, B, F: FnMut(A) -> B>(atom: Atom<A>, f: &mut F) -> Atom<B> {
+    match atom {
+        Atom::NewVertex(n) => Atom::NewVertex(NewVertex {
+            up_context: n
+                .up_context
+                .into_iter()
+                .map(|p| map_position(p, f))
+                .collect(),
+            down_context: n
+                .down_context
+                .into_iter()
+                .map(|p| map_position(p, f))
+                .collect(),
+            flag: n.flag,
+            start: n.start,
+            end: n.end,
+            inode: map_position(n.inode, f),
+        }),
+        Atom::EdgeMap(e) => Atom::EdgeMap(EdgeMap {
+            edges: e
+                .edges
+                .into_iter()
+                .map(|e| NewEdge {
+                    previous: e.previous,
+                    flag: e.flag,
+                    from: map_position(e.from, f),
+                    to: Vertex {
+                        change: f(e.to.change),
+                        start: e.to.start,
+                        end: e.to.end,
+                    },
+                    introduced_by: f(e.introduced_by),
+                })
+                .collect(),
+            inode: map_position(e.inode, f),
+        }),
+    }
+}
+
+impl Change {
+    /// The portable representation of this change, optionally
+    /// including its hash. If `detach_contents` is `true`, the
+    /// contents are left out.
+    pub fn to_portable(&self, hash: Option<Hash>, detach_contents: bool) -> PortableChange {
+        PortableChange {
+            schema: PORTABLE_SCHEMA_VERSION,
+            version: self.version,
+            hash: hash.map(Base32Hash),
+            header: self.header.clone(),
+            dependencies: self.dependencies.iter().map(|h| Base32Hash(*h)).collect(),
+            extra_known: self.extra_known.iter().map(|h| Base32Hash(*h)).collect(),
+            metadata: self.metadata.clone(),
+            hunks: self
+                .changes
+                .iter()
+                .map(|hunk| {
+                    hunk.clone()
+                        .atom_map(
+                            |a| {
+                                Ok::<_, std::convert::Infallible>(map_atom(a, &mut |h| {
+                                    h.map(Base32Hash)
+                                }))
+                            },
+                            |l| l,
+                        )
+                        .unwrap()
+                })
+                .collect(),
+            contents_hash: Base32Hash(self.contents_hash),
+            contents: if detach_contents {
+                None
+            } else {
+                Some(self.contents.clone())
+            },
+            unhashed: self.unhashed.clone(),
+        }
+    }
+
+    /// Build a change from its portable representation. `contents`
+    /// is used if the contents were detached. The hash of the
+    /// contents is always checked, and so is the hash of the change
+    /// if `p` has one.
+    pub fn from_portable(
+        p: PortableChange,
+        contents: Option<Vec<u8>>,
+    ) -> Result<Self, ChangeError> {
+        if p.schema > PORTABLE_SCHEMA_VERSION {
+            return Err(ChangeError::PortableSchemaMismatch { got: p.schema });
+        }
+        if p.version != VERSION && p.version != VERSION_NOENC {
+            return Err(ChangeError::VersionMismatch { got: p.version });
+        }
+        let contents = if let Some(c) = p.contents.or(contents) {
+            c
+        } else {
+            return Err(ChangeError::MissingContents {
+                hash: p.hash.unwrap_or(p.contents_hash).0,
+            });
+        };
+        let mut hasher = Hasher::default();
+        hasher.update(&contents);
+        let computed = hasher.finish();
+        if computed != p.contents_hash.0 {
+            return Err(ChangeError::ContentsHashMismatch {
+                claimed: p.contents_hash.0,
+                computed,
+            });
+        }
+        let change = LocalChange {
+            offsets: Offsets::default(),
+            hashed: Hashed {
+                version: p.version,
+                header: p.header,
+                dependencies: p.dependencies.into_iter().map(|h| h.0).collect(),
+                extra_known: p.extra_known.into_iter().map(|h| h.0).collect(),
+                metadata: p.metadata,
+                changes: p
+                    .hunks
+                    .into_iter()
+                    .map(|hunk| {
+                        hunk.atom_map(
+                            |a| {
+                                Ok::<_, std::convert::Infallible>(map_atom(a, &mut |h| {
+                                    h.map(|h: Base32Hash| h.0)
+                                }))
+                            },
+                            |l| l,
+                        )
+                        .unwrap()
+                    })
+                    .collect(),
+                contents_hash: p.contents_hash.0,
+            },
+            unhashed: p.unhashed,
+            contents,
+        };
+        if let Some(claimed) = p.hash {
+            let computed = change.hash()?;
+            if computed != claimed.0 {
+                return Err(ChangeError::ChangeHashMismatch {
+                    claimed: claimed.0,
+                    computed,
+                });
+            }
+        }
+        Ok(change)
+    }
+
+    /// Write this change in the portable JSON format.
+    pub fn to_json<W: Write>(
+        &self,
+        hash: Option<Hash>,
+        detach_contents: bool,
+        w: W,
+    ) -> Result<(), ChangeError> {
+        serde_json::to_writer_pretty(w, &self.to_portable(hash, detach_contents))?;
+        Ok(())
+    }
+
+    /// Read a change in the portable JSON format.
+    pub fn from_json<R: std::io::Read>(
+        r: R,
+        contents: Option<Vec<u8>>,
+    ) -> Result<Self, ChangeError> {
+        Self::from_portable(serde_json::from_reader(r)?, contents)
+    }
+
+    /// Write this change in the portable CBOR format.
+    #[cfg(feature = "cbor")]
+    pub fn to_cbor<W: Write>(
+        &self,
+        hash: Option<Hash>,
+        detach_contents: bool,
+        w: W,
+    ) -> Result<(), ChangeError> {
+        ciborium::ser::into_writer(&self.to_portable(hash, detach_contents), w)
+            .map_err(|e| ChangeError::Cbor(e.to_string()))
+    }
+
+    /// Read a change in the portable CBOR format.
+    #[cfg(feature = "cbor")]
+    pub fn from_cbor<R: std::io::Read>(
+        r: R,
+        contents: Option<Vec<u8>>,
+    ) -> Result<Self, ChangeError> {
+        let p = ciborium::de::from_reader(r).map_err(|e| ChangeError::Cbor(e.to_string()))?;
+        Self::from_portable(p, contents)
+    }
+}
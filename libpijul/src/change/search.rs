@@ -0,0 +1,141 @@
+//! Searching the lines added and deleted by the hunks of a change,
+//! reading them from the change store one hunk at a time rather than
+//! loading the whole change.
+
+use super::*;
+use crate::changestore::ChangeStore;
+use crate::HashSet;
+
+/// A line added or deleted by a hunk of a change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineMatch {
+    /// Index of the hunk in the change.
+    pub hunk: usize,
+    /// Path of the file edited by the hunk, when the change was
+    /// recorded.
+    pub path: String,
+    /// Whether the line was added (rather than deleted).
+    pub added: bool,
+    /// The line, without its end-of-line character.
+    pub line: String,
+}
+
+/// The lines added or deleted by change `hash` for which `is_match`
+/// returns `true`, in the order of the hunks. If `inodes` is given,
+/// only the hunks editing one of these files are searched. Lines that
+/// aren't valid UTF-8 are skipped.
+pub fn search_change<C: ChangeStore, F: FnMut(&str) -> bool>(
+    changes: &C,
+    hash: &Hash,
+    inodes: Option<&HashSet<Position<Hash>>>,
+    mut is_match: F,
+) -> Result<Vec<LineMatch>, C::Error> {
+    let mut result = Vec::new();
+    let mut buf = Vec::new();
+    let mut deleted = HashSet::default();
+    for (n, hunk) in changes.iter_hunks(hash)?.enumerate() {
+        let hunk = hunk?;
+        let atoms = match hunk {
+            Hunk::Edit { ref change, .. } | Hunk::ResurrectZombies { ref change, .. } => {
+                vec![change]
+            }
+            Hunk::Replacement {
+                ref change,
+                ref replacement,
+                ..
+            } => vec![change, replacement],
+            Hunk::FileAdd {
+                contents: Some(ref contents),
+                ..
+            }
+            | Hunk::FileDel {
+                contents: Some(ref contents),
+                ..
+            }
+            | Hunk::FileUndel {
+                contents: Some(ref contents),
+                ..
+            } => vec![contents],
+            _ => continue,
+        };
+        for atom in atoms {
+            if let Some(inodes) = inodes {
+                let inode = atom.inode();
+                let inode = Position {
+                    change: inode.change.unwrap_or(*hash),
+                    pos: inode.pos,
+                };
+                if !inodes.contains(&inode) {
+                    continue;
+                }
+            }
+            let mut push = |buf: &[u8], added: bool| {
+                for line in buf.split(|c| *c == b'\n') {
+                    if let Ok(line) = std::str::from_utf8(line) {
+                        if is_match(line) {
+                            result.push(LineMatch {
+                                hunk: n,
+                                path: hunk.path().to_string(),
+                                added,
+                                line: line.to_string(),
+                            })
+                        }
+                    }
+                }
+            };
+            match atom {
+                Atom::NewVertex(v) => {
+                    let vertex = Vertex {
+                        change: None,
+                        start: v.start,
+                        end: v.end,
+                    };
+                    if read_contents(changes, hash, vertex, &mut buf)? {
+                        push(&buf, true)
+                    }
+                }
+                Atom::EdgeMap(e) => {
+                    deleted.clear();
+                    for edge in e.edges.iter() {
+                        if edge.flag.contains(EdgeFlags::DELETED)
+                            && !edge.previous.contains(EdgeFlags::DELETED)
+                            && deleted.insert(edge.to)
+                        {
+                            if read_contents(changes, hash, edge.to, &mut buf)? {
+                                push(&buf, false)
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Read the contents of `vertex` (a vertex of change `hash` if its
+/// change is `None`) into `buf`, without its final newline. Returns
+/// `false` for empty vertices, such as inodes.
+fn read_contents<C: ChangeStore>(
+    changes: &C,
+    hash: &Hash,
+    vertex: Vertex<Option<Hash>>,
+    buf: &mut Vec<u8>,
+) -> Result<bool, C::Error> {
+    if vertex.end <= vertex.start {
+        return Ok(false);
+    }
+    buf.clear();
+    buf.resize(vertex.end.us() - vertex.start.us(), 0);
+    changes.get_contents_ext(
+        Vertex {
+            change: Some(vertex.change.unwrap_or(*hash)),
+            ..vertex
+        },
+        buf,
+    )?;
+    if buf.last() == Some(&b'\n') {
+        buf.pop();
+    }
+    Ok(true)
+}
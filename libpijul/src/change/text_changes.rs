@@ -14,6 +14,8 @@ pub enum TextDeError {
     #[error(transparent)]
     TomlDe(#[from] toml::de::Error),
     #[error(transparent)]
+    Header(#[from] HeaderError),
+    #[error(transparent)]
     Nom(#[from] nom::Err<nom::error::Error<String>>),
     #[error("Missing dependency [{0}]")]
     MissingChange(usize),
@@ -105,8 +107,11 @@ impl LocalChange<Hunk<Option<Hash>, Local>, Author> {
         }
 
         if write_header {
-            let s = toml::ser::to_string_pretty(&self.header)?;
-            writeln!(w, "{}", s)?;
+            let header = TextHeader {
+                header: self.header.clone(),
+                fields: self.header_fields(),
+            };
+            writeln!(w, "{}", header.to_toml()?)?;
         }
         let mut hashes = HashMap::default();
         let mut i = 2;
@@ -156,6 +161,74 @@ impl LocalChange<Hunk<Option<Hash>, Local>, Author> {
     }
 }
 
+/// Write some of the hunks of change `hash`, each with its number in
+/// the change, preceded by the list of the changes they reference.
+///
+/// Unlike [`LocalChange::write`], this doesn't need the change in
+/// memory: only the contents introduced by `hunks` are read from
+/// `changes`.
+pub fn write_hunks<W: WriteChangeLine, C: ChangeStore>(
+    changes: &C,
+    hash: &Hash,
+    hunks: &[(usize, Hunk<Option<Hash>, Local>)],
+    mut w: W,
+) -> Result<(), TextSerError<C::Error>> {
+    let mut hashes = HashMap::default();
+    let mut i = 2;
+    hunks_deps(hunks.iter().map(|(_, h)| h), |change| {
+        if let Entry::Vacant(e) = hashes.entry(change) {
+            if i == 2 {
+                w.write_all(Change::DEPS_LINE.as_bytes())?;
+            }
+            e.insert(i);
+            writeln!(w, "[{}]+{}", i, change.to_base32())?;
+            i += 1;
+        }
+        Ok(())
+    })?;
+    if hunks.is_empty() {
+        return Ok(());
+    }
+    // The hunks index the contents of the change by their absolute
+    // positions, but only the ranges they introduce are read.
+    let mut contents = Vec::new();
+    for (_, hunk) in hunks.iter() {
+        let mut range: Option<(ChangePosition, ChangePosition)> = None;
+        for atom in hunk.iter() {
+            if let Atom::NewVertex(ref n) = atom {
+                range = Some(match range {
+                    Some((start, end)) => (start.min(n.start), end.max(n.end)),
+                    None => (n.start, n.end),
+                })
+            }
+        }
+        if let Some((start, end)) = range {
+            if contents.len() < end.us() {
+                contents.resize(end.us(), 0)
+            }
+            changes
+                .get_contents_ext(
+                    Vertex {
+                        change: Some(*hash),
+                        start,
+                        end,
+                    },
+                    &mut contents[start.us()..end.us()],
+                )
+                .map_err(TextSerError::C)?;
+        }
+    }
+    if !hashes.is_empty() {
+        w.write_all(b"\n")?
+    }
+    w.write_all(Change::HUNKS_LINE.as_bytes())?;
+    for (n, hunk) in hunks.iter() {
+        write!(w, "\n{}. ", n + 1)?;
+        hunk.write(changes, &hashes, &contents, &mut w)?
+    }
+    Ok(())
+}
+
 impl Change {
     pub fn read_and_deps<
         R: BufRead,
@@ -194,7 +267,7 @@ impl Change {
 
         // parse header
         let (i, m_header) = parse_header(i).map_err(|e| e.to_owned())?;
-        let header = m_header?;
+        let TextHeader { header, fields } = m_header?;
 
         // parse dependencies
         let (i, deps) = parse_dependencies(i).map_err(|e| e.to_owned())?;
@@ -202,7 +275,9 @@ impl Change {
         // parse hunks
         let (_, hunks) = parse_hunks(i).map_err(|e| e.to_owned())?;
 
-        Change::update(header, deps, hunks, updatables)
+        let (mut change, extra_dependencies) = Change::update(header, deps, hunks, updatables)?;
+        change.hashed.set_header_fields(fields);
+        Ok((change, extra_dependencies))
     }
 
     fn update(
@@ -365,7 +440,10 @@ impl Hunk<Option<Hash>, Local> {
                         basename: name,
                         metadata,
                         ..
-                    } = FileMetadata::read(&change_contents[add.start.0.into()..add.end.0.into()]);
+                    } = FileMetadata::try_read(
+                        &change_contents[add.start.0.into()..add.end.0.into()],
+                    )
+                    .map_err(ChangeError::from)?;
                     PrintableHunk::FileMoveV {
                         path: path.to_string(),
                         name: name.to_string(),
@@ -449,7 +527,10 @@ impl Hunk<Option<Hash>, Local> {
                             basename: name,
                             metadata: perms,
                             ..
-                        } = FileMetadata::read(&change_contents[n.start.0.into()..n.end.0.into()]);
+                        } = FileMetadata::try_read(
+                            &change_contents[n.start.0.into()..n.end.0.into()],
+                        )
+                        .map_err(ChangeError::from)?;
                         (name, perms)
                     };
 
@@ -1123,7 +1204,8 @@ pub fn get_deleted_names<C: ChangeStore>(
                 .get_contents_ext(d.to, &mut tmp)
                 .map_err(TextSerError::C)?;
             if !tmp.is_empty() {
-                let FileMetadata { basename: name, .. } = FileMetadata::read(&tmp);
+                let FileMetadata { basename: name, .. } =
+                    FileMetadata::try_read(&tmp).map_err(ChangeError::from)?;
                 res.push(name.to_string());
             }
         }
@@ -1155,47 +1237,60 @@ pub fn to_printable_pos_vec(
 impl LocalChange<Hunk<Option<Hash>, Local>, Author> {
     pub fn write_all_deps<F: FnMut(Hash) -> Result<(), ChangeError>>(
         &self,
-        mut f: F,
+        f: F,
     ) -> Result<(), ChangeError> {
-        for c in self.changes.iter() {
-            for c in c.iter() {
-                match *c {
-                    Atom::NewVertex(ref n) => {
-                        for change in n
-                            .up_context
-                            .iter()
-                            .chain(n.down_context.iter())
-                            .map(|c| c.change)
-                            .chain(std::iter::once(n.inode.change))
-                        {
-                            if let Some(change) = change {
-                                if let Hash::None = change {
-                                    continue;
-                                }
-                                f(change)?
+        hunks_deps(self.changes.iter(), f)
+    }
+}
+
+/// Call `f` on every change referenced by `hunks`, possibly several
+/// times.
+fn hunks_deps<
+    'a,
+    I: Iterator<Item = &'a Hunk<Option<Hash>, Local>>,
+    F: FnMut(Hash) -> Result<(), ChangeError>,
+>(
+    hunks: I,
+    mut f: F,
+) -> Result<(), ChangeError> {
+    for c in hunks {
+        for c in c.iter() {
+            match *c {
+                Atom::NewVertex(ref n) => {
+                    for change in n
+                        .up_context
+                        .iter()
+                        .chain(n.down_context.iter())
+                        .map(|c| c.change)
+                        .chain(std::iter::once(n.inode.change))
+                    {
+                        if let Some(change) = change {
+                            if let Hash::None = change {
+                                continue;
                             }
+                            f(change)?
                         }
                     }
-                    Atom::EdgeMap(ref e) => {
-                        for edge in e.edges.iter() {
-                            for change in &[
-                                edge.from.change,
-                                edge.to.change,
-                                edge.introduced_by,
-                                e.inode.change,
-                            ] {
-                                if let Some(change) = *change {
-                                    if let Hash::None = change {
-                                        continue;
-                                    }
-                                    f(change)?
+                }
+                Atom::EdgeMap(ref e) => {
+                    for edge in e.edges.iter() {
+                        for change in &[
+                            edge.from.change,
+                            edge.to.change,
+                            edge.introduced_by,
+                            e.inode.change,
+                        ] {
+                            if let Some(change) = *change {
+                                if let Hash::None = change {
+                                    continue;
                                 }
+                                f(change)?
                             }
                         }
                     }
                 }
             }
         }
-        Ok(())
     }
+    Ok(())
 }
@@ -363,7 +363,10 @@ impl Hunk<Option<Hash>, Local> {
                         basename: name,
                         metadata: perms,
                         ..
-                    } = FileMetadata::read(&change_contents[add.start.0.into()..add.end.0.into()]);
+                    } = FileMetadata::try_read(
+                        &change_contents[add.start.0.into()..add.end.0.into()],
+                    )
+                    .map_err(ChangeError::from)?;
                     write!(
                         w,
                         "Moved: {} {} {}",
@@ -452,7 +455,8 @@ impl Hunk<Option<Hash>, Local> {
                         basename: name,
                         metadata: perms,
                         ..
-                    } = FileMetadata::read(&change_contents[n.start.0.into()..n.end.0.into()]);
+                    } = FileMetadata::try_read(&change_contents[n.start.0.into()..n.end.0.into()])
+                        .map_err(ChangeError::from)?;
                     let parent = if let Some(p) = crate::path::parent(&path) {
                         if p.is_empty() {
                             "/"
@@ -1157,7 +1161,7 @@ impl Hunk<Option<Hash>, Local> {
                 }
                 _ => {
                     unimplemented!()
-                },
+                }
             }
         }
     }
@@ -1420,7 +1424,8 @@ pub fn write_deleted_names<W: std::io::Write, C: ChangeStore>(
                 .get_contents_ext(d.to, &mut buf)
                 .map_err(TextSerError::C)?;
             if !buf.is_empty() {
-                let FileMetadata { basename: name, .. } = FileMetadata::read(&buf);
+                let FileMetadata { basename: name, .. } =
+                    FileMetadata::try_read(&buf).map_err(ChangeError::from)?;
                 write!(w, "{}{:?}", if is_first { "" } else { ", " }, name)?;
                 is_first = false;
             }
@@ -0,0 +1,182 @@
+//! The header of the text format of changes, as edited by users when
+//! recording. This is a TOML document, in which comments are allowed
+//! and the fields that are not part of [`ChangeHeader`] are kept, so
+//! that tools can add their own fields to the changes they prepare.
+//! These fields are stored in the metadata of changes (see
+//! [`Hashed::header_fields`]).
+
+use std::collections::BTreeMap;
+
+use super::*;
+
+#[derive(Debug, Error)]
+pub enum HeaderError {
+    #[error(transparent)]
+    Toml(#[from] toml::de::Error),
+    #[error("Missing header field `{0}`")]
+    Missing(&'static str),
+    #[error("Invalid header field `{field}`: expected {expected}")]
+    Invalid {
+        field: String,
+        expected: &'static str,
+    },
+}
+
+/// A change header read from text, along with its fields that are
+/// not part of [`ChangeHeader`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextHeader {
+    pub header: ChangeHeader,
+    pub fields: BTreeMap<String, serde_json::Value>,
+}
+
+impl TextHeader {
+    /// Parse and validate the header of a change.
+    pub fn parse(s: &str) -> Result<Self, HeaderError> {
+        let mut table: toml::value::Table = toml::de::from_str(s)?;
+        let message = match table.remove("message") {
+            Some(toml::Value::String(m)) => m,
+            Some(_) => return Err(invalid("message", "a string")),
+            None => return Err(HeaderError::Missing("message")),
+        };
+        let description = match table.remove("description") {
+            Some(toml::Value::String(d)) => Some(d),
+            Some(_) => return Err(invalid("description", "a string")),
+            None => None,
+        };
+        let timestamp = match table.remove("timestamp") {
+            Some(toml::Value::String(t)) => parse_timestamp(&t)?,
+            Some(toml::Value::Datetime(t)) => parse_timestamp(&t.to_string())?,
+            Some(_) => return Err(invalid("timestamp", "a date")),
+            None => return Err(HeaderError::Missing("timestamp")),
+        };
+        let authors = match table.remove("authors") {
+            Some(toml::Value::Array(a)) => {
+                let mut authors = Vec::with_capacity(a.len());
+                for author in a {
+                    authors.push(parse_author(author)?)
+                }
+                authors
+            }
+            Some(_) => return Err(invalid("authors", "an array of tables")),
+            None => return Err(HeaderError::Missing("authors")),
+        };
+        Ok(TextHeader {
+            header: ChangeHeader {
+                message,
+                description,
+                timestamp,
+                authors,
+            },
+            fields: table
+                .into_iter()
+                .map(|(k, v)| (k, toml_to_json(v)))
+                .collect(),
+        })
+    }
+
+    /// This header in TOML. The extra fields that are not tables are
+    /// written first, since they would otherwise end up in the last
+    /// table of the header (the authors).
+    pub fn to_toml(&self) -> Result<String, toml::ser::Error> {
+        let mut values = toml::value::Table::new();
+        let mut tables = toml::value::Table::new();
+        for (k, v) in self.fields.iter() {
+            if let Some(v) = json_to_toml(v) {
+                let is_table = match v {
+                    toml::Value::Table(_) => true,
+                    toml::Value::Array(ref a) => a.iter().any(|v| v.is_table()),
+                    _ => false,
+                };
+                if is_table {
+                    tables.insert(k.clone(), v);
+                } else {
+                    values.insert(k.clone(), v);
+                }
+            }
+        }
+        let mut s = String::new();
+        if !values.is_empty() {
+            s.push_str(&toml::ser::to_string_pretty(&values)?);
+        }
+        s.push_str(&toml::ser::to_string_pretty(&self.header)?);
+        if !tables.is_empty() {
+            s.push('\n');
+            s.push_str(&toml::ser::to_string_pretty(&tables)?);
+        }
+        Ok(s)
+    }
+}
+
+fn invalid(field: &str, expected: &'static str) -> HeaderError {
+    HeaderError::Invalid {
+        field: field.to_string(),
+        expected,
+    }
+}
+
+fn parse_timestamp(t: &str) -> Result<DateTime<Utc>, HeaderError> {
+    if let Ok(t) = t.parse::<DateTime<chrono::FixedOffset>>() {
+        Ok(t.with_timezone(&Utc))
+    } else {
+        Err(invalid("timestamp", "an RFC 3339 date with a time zone"))
+    }
+}
+
+fn parse_author(author: toml::Value) -> Result<Author, HeaderError> {
+    let table = if let toml::Value::Table(t) = author {
+        t
+    } else {
+        return Err(invalid("authors", "an array of tables"));
+    };
+    let mut a = BTreeMap::new();
+    for (k, v) in table {
+        if let toml::Value::String(v) = v {
+            a.insert(k, v);
+        } else {
+            return Err(invalid(&format!("authors.{}", k), "a string"));
+        }
+    }
+    Ok(Author(a))
+}
+
+fn toml_to_json(v: toml::Value) -> serde_json::Value {
+    use serde_json::Value;
+    match v {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::Number(i.into()),
+        toml::Value::Float(f) => serde_json::Number::from_f64(f)
+            .map(Value::Number)
+            .unwrap_or(Value::Null),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(d) => Value::String(d.to_string()),
+        toml::Value::Array(a) => Value::Array(a.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(t) => {
+            Value::Object(t.into_iter().map(|(k, v)| (k, toml_to_json(v))).collect())
+        }
+    }
+}
+
+/// Convert a JSON value to TOML, or `None` for `null`, which has no
+/// TOML equivalent.
+fn json_to_toml(v: &serde_json::Value) -> Option<toml::Value> {
+    use serde_json::Value;
+    Some(match v {
+        Value::Null => return None,
+        Value::Bool(b) => toml::Value::Boolean(*b),
+        Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                toml::Value::Integer(i)
+            } else {
+                toml::Value::Float(n.as_f64()?)
+            }
+        }
+        Value::String(s) => toml::Value::String(s.clone()),
+        Value::Array(a) => toml::Value::Array(a.iter().filter_map(json_to_toml).collect()),
+        Value::Object(o) => toml::Value::Table(
+            o.iter()
+                .filter_map(|(k, v)| Some((k.clone(), json_to_toml(v)?)))
+                .collect(),
+        ),
+    })
+}
@@ -8,6 +8,7 @@ use std::path::{Path, PathBuf};
 pub struct FileSystem {
     change_cache: RefCell<lru_cache::LruCache<ChangeId, ChangeFile>>,
     changes_dir: PathBuf,
+    index_contents: bool,
 }
 
 impl Clone for FileSystem {
@@ -16,6 +17,7 @@ impl Clone for FileSystem {
         FileSystem {
             changes_dir: self.changes_dir.clone(),
             change_cache: RefCell::new(lru_cache::LruCache::new(len)),
+            index_contents: self.index_contents,
         }
     }
 }
@@ -87,9 +89,18 @@ impl FileSystem {
         FileSystem {
             changes_dir,
             change_cache: RefCell::new(lru_cache::LruCache::new(cap)),
+            index_contents: false,
         }
     }
 
+    /// Save new changes in the
+    /// [`CONTENTS_INDEX_VERSION`](crate::change::CONTENTS_INDEX_VERSION)
+    /// format rather than in the default one, which older versions
+    /// of Pijul can read.
+    pub fn set_index_contents(&mut self, index_contents: bool) {
+        self.index_contents = index_contents
+    }
+
     fn load<F: Fn(ChangeId) -> Option<Hash>>(
         &self,
         hash: F,
@@ -139,6 +150,82 @@ impl FileSystem {
         }
         Ok(())
     }
+
+    /// Rewrite change `hash` in the
+    /// [`CONTENTS_INDEX_VERSION`](crate::change::CONTENTS_INDEX_VERSION)
+    /// format, without changing its hash. Returns `false` if the
+    /// change doesn't need to be rewritten, or can't be: changes
+    /// already in that format, changes without their contents, and
+    /// changes in the older
+    /// [`VERSION_NOENC`](crate::change::VERSION_NOENC) format, whose
+    /// hash would change.
+    pub fn reindex(&self, hash: &Hash) -> Result<bool, Error> {
+        use std::io::{Read, Write};
+        let file_name = self.filename(hash);
+        let mut f = std::fs::File::open(&file_name)?;
+        let mut buf = [0u8; Change::OFFSETS_SIZE as usize];
+        f.read_exact(&mut buf)?;
+        let offsets: crate::change::Offsets =
+            bincode::deserialize(&buf).map_err(crate::change::ChangeError::from)?;
+        if offsets.version != crate::change::VERSION || f.metadata()?.len() <= offsets.contents_off
+        {
+            return Ok(false);
+        }
+        let mut change = Change::deserialize(file_name.to_str().unwrap(), Some(hash))?;
+        let mut tmp = tempfile::NamedTempFile::new_in(&self.changes_dir)?;
+        {
+            let mut w = std::io::BufWriter::new(&mut tmp);
+            change.serialize_indexed(&mut w, |_, h| {
+                if h == hash {
+                    Ok(())
+                } else {
+                    Err(crate::change::ChangeError::ChangeHashMismatch {
+                        claimed: *hash,
+                        computed: *h,
+                    })
+                }
+            })?;
+            w.flush()?;
+        }
+        tmp.persist(&file_name)?;
+        // The cache is indexed by change ids, which we don't know here.
+        self.change_cache.borrow_mut().clear();
+        Ok(true)
+    }
+
+    /// Save `p`, with its contents taken from `contents` if given.
+    fn save_change_<
+        E: From<Error> + From<ChangeError>,
+        F: FnOnce(&mut Change, &Hash) -> Result<(), E>,
+    >(
+        &self,
+        p: &mut Change,
+        contents: Option<&mut crate::record::ContentArena>,
+        ff: F,
+    ) -> Result<Hash, E> {
+        let mut f = match tempfile::NamedTempFile::new_in(&self.changes_dir) {
+            Ok(f) => f,
+            Err(e) => return Err(E::from(Error::from(e))),
+        };
+        let hash = {
+            let w = std::io::BufWriter::new(&mut f);
+            match (contents, self.index_contents) {
+                (Some(c), true) => p.serialize_indexed_with_contents(w, c, ff)?,
+                (Some(c), false) => p.serialize_with_contents(w, c, ff)?,
+                (None, true) => p.serialize_indexed(w, ff)?,
+                (None, false) => p.serialize(w, ff)?,
+            }
+        };
+        let file_name = self.filename(&hash);
+        if let Err(e) = std::fs::create_dir_all(file_name.parent().unwrap()) {
+            return Err(E::from(Error::from(e)));
+        }
+        debug!("file_name = {:?}", file_name);
+        if let Err(e) = f.persist(file_name) {
+            return Err(E::from(Error::from(e)));
+        }
+        Ok(hash)
+    }
 }
 
 impl ChangeStore for FileSystem {
@@ -187,6 +274,40 @@ impl ChangeStore for FileSystem {
         debug!("get_contents {:?}", n);
         Ok(n)
     }
+    fn get_contents_batch<F: Fn(ChangeId) -> Option<Hash>>(
+        &self,
+        hash: F,
+        keys: &[Vertex<ChangeId>],
+        buf: &mut Vec<u8>,
+    ) -> Result<(), Self::Error> {
+        let change = if let Some(k) = keys.iter().find(|k| k.end > k.start && !k.is_root()) {
+            k.change
+        } else {
+            return Ok(());
+        };
+        debug!("get_contents_batch {:?} {:?}", change, keys.len());
+        let mut cache = self.load(hash, change)?;
+        let p = cache.get_mut(&change).unwrap();
+        let mut i = 0;
+        while i < keys.len() {
+            // Read consecutive vertices at once.
+            let start = keys[i].start;
+            let mut end = keys[i].end;
+            let mut j = i + 1;
+            while j < keys.len() && keys[j].start == end {
+                end = keys[j].end;
+                j += 1
+            }
+            if end > start {
+                debug_assert!(keys[i..j].iter().all(|k| k.change == change));
+                let len = buf.len();
+                buf.resize(len + (end - start), 0);
+                p.read_contents(start.into(), &mut buf[len..])?;
+            }
+            i = j
+        }
+        Ok(())
+    }
     fn get_contents_ext(
         &self,
         key: Vertex<Option<Hash>>,
@@ -229,23 +350,18 @@ impl ChangeStore for FileSystem {
         p: &mut Change,
         ff: F,
     ) -> Result<Hash, E> {
-        let mut f = match tempfile::NamedTempFile::new_in(&self.changes_dir) {
-            Ok(f) => f,
-            Err(e) => return Err(E::from(Error::from(e))),
-        };
-        let hash = {
-            let w = std::io::BufWriter::new(&mut f);
-            p.serialize(w, ff)?
-        };
-        let file_name = self.filename(&hash);
-        if let Err(e) = std::fs::create_dir_all(file_name.parent().unwrap()) {
-            return Err(E::from(Error::from(e)));
-        }
-        debug!("file_name = {:?}", file_name);
-        if let Err(e) = f.persist(file_name) {
-            return Err(E::from(Error::from(e)));
-        }
-        Ok(hash)
+        self.save_change_(p, None, ff)
+    }
+    fn save_change_with_contents<
+        E: From<Self::Error> + From<ChangeError>,
+        F: FnOnce(&mut Change, &Hash) -> Result<(), E>,
+    >(
+        &self,
+        p: &mut Change,
+        contents: &mut crate::record::ContentArena,
+        ff: F,
+    ) -> Result<Hash, E> {
+        self.save_change_(p, Some(contents), ff)
     }
     fn del_change(&self, hash: &Hash) -> Result<bool, Self::Error> {
         let file_name = self.filename(hash);
@@ -260,4 +376,87 @@ impl ChangeStore for FileSystem {
         debug!("file_name = {:?}", file_name);
         Ok(Change::deserialize(&file_name, Some(h))?)
     }
+    fn iter_hunks<'a>(&'a self, hash: &Hash) -> Result<HunksIter<'a, Self::Error>, Self::Error> {
+        let file_name = self.filename(hash);
+        let r = crate::change::HunkReader::open(*hash, file_name.to_str().unwrap())?;
+        Ok(Box::new(r.map(|h| h.map_err(From::from))))
+    }
+    fn iter_hashes(
+        &self,
+    ) -> Result<Box<dyn Iterator<Item = Result<StoreEntry, Self::Error>> + '_>, Self::Error> {
+        Ok(Box::new(Entries {
+            dirs: std::fs::read_dir(&self.changes_dir)?,
+            current: None,
+        }))
+    }
+}
+
+/// The files of a change store, two levels deep: the first two
+/// characters of the base32 hash, and then the rest of the hash with
+/// the `change` or `tag` extension.
+struct Entries {
+    dirs: std::fs::ReadDir,
+    current: Option<(String, std::fs::ReadDir)>,
+}
+
+impl Entries {
+    fn entry(prefix: &str, e: &std::fs::DirEntry) -> Result<Option<StoreEntry>, Error> {
+        let path = e.path();
+        let stem = if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+            stem
+        } else {
+            return Ok(None);
+        };
+        let h = format!("{}{}", prefix, stem);
+        let hash = match path.extension().and_then(|e| e.to_str()) {
+            Some("change") => Hash::from_base32(h.as_bytes()).map(StoredHash::Change),
+            Some("tag") => Merkle::from_base32(h.as_bytes()).map(StoredHash::Tag),
+            _ => None,
+        };
+        if let Some(hash) = hash {
+            Ok(Some(StoreEntry {
+                hash,
+                size: e.metadata()?.len(),
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl Iterator for Entries {
+    type Item = Result<StoreEntry, Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((ref prefix, ref mut files)) = self.current {
+                match files.next() {
+                    Some(Ok(e)) => match Self::entry(prefix, &e) {
+                        Ok(Some(e)) => return Some(Ok(e)),
+                        Ok(None) => continue,
+                        Err(e) => return Some(Err(e)),
+                    },
+                    Some(Err(e)) => return Some(Err(e.into())),
+                    None => self.current = None,
+                }
+            }
+            // Move on to the next directory, skipping the temporary
+            // files at the root of the store.
+            let dir = match self.dirs.next()? {
+                Ok(dir) => dir,
+                Err(e) => return Some(Err(e.into())),
+            };
+            let prefix = if let Some(p) = dir.file_name().to_str() {
+                p.to_string()
+            } else {
+                continue;
+            };
+            if prefix.len() != 2 || !dir.path().is_dir() {
+                continue;
+            }
+            match std::fs::read_dir(dir.path()) {
+                Ok(files) => self.current = Some((prefix, files)),
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
 }
@@ -0,0 +1,295 @@
+use super::*;
+use crate::change::{Change, ChangeHeader};
+use crate::pristine::{ChangeId, Hash, Merkle, Vertex};
+use crate::HashSet;
+
+/// A change store reading from `top`, and then from `bottom` for the
+/// changes and tags not found in `top`. New changes are always saved
+/// to `top`, and `bottom` is never modified, so several stores can
+/// share the same `bottom`. Without a `bottom`, this is just `top`.
+#[derive(Clone)]
+pub struct Layered<A, B> {
+    pub top: A,
+    pub bottom: Option<B>,
+}
+
+impl<A, B> Layered<A, B> {
+    pub fn new(top: A, bottom: Option<B>) -> Self {
+        Layered { top, bottom }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Error<A: std::error::Error + 'static, B: std::error::Error + 'static> {
+    #[error(transparent)]
+    Top(A),
+    #[error(transparent)]
+    Bottom(B),
+    #[error(transparent)]
+    Utf8(#[from] std::str::Utf8Error),
+    #[error(transparent)]
+    Change(#[from] ChangeError),
+}
+
+#[cfg(any(feature = "ondisk-repos", feature = "no-mmap"))]
+impl Layered<super::filesystem::FileSystem, super::filesystem::FileSystem> {
+    /// The file of change `hash`: the one in `bottom` if only
+    /// `bottom` has it, else the one in `top`.
+    pub fn filename(&self, hash: &Hash) -> std::path::PathBuf {
+        self.path(|s| s.filename(hash))
+    }
+
+    /// The file of the tag of state `state`: the one in `bottom` if
+    /// only `bottom` has it, else the one in `top`.
+    pub fn tag_filename(&self, state: &Merkle) -> std::path::PathBuf {
+        self.path(|s| s.tag_filename(state))
+    }
+
+    fn path<F: Fn(&super::filesystem::FileSystem) -> std::path::PathBuf>(
+        &self,
+        f: F,
+    ) -> std::path::PathBuf {
+        let top = f(&self.top);
+        if std::fs::metadata(&top).is_err() {
+            if let Some(ref b) = self.bottom {
+                let bottom = f(b);
+                if std::fs::metadata(&bottom).is_ok() {
+                    return bottom;
+                }
+            }
+        }
+        top
+    }
+}
+
+impl<A: ChangeStore, B: ChangeStore> Layered<A, B> {
+    /// Call `top`, and `bottom` if `top` fails. The error of `top` is
+    /// returned if both fail.
+    fn get<T, FA, FB>(&self, top: FA, bottom: FB) -> Result<T, Error<A::Error, B::Error>>
+    where
+        FA: FnOnce(&A) -> Result<T, A::Error>,
+        FB: FnOnce(&B) -> Result<T, B::Error>,
+    {
+        match top(&self.top) {
+            Ok(x) => Ok(x),
+            Err(e) => {
+                if let Some(ref b) = self.bottom {
+                    if let Ok(x) = bottom(b) {
+                        return Ok(x);
+                    }
+                }
+                Err(Error::Top(e))
+            }
+        }
+    }
+}
+
+impl<A: ChangeStore, B: ChangeStore> ChangeStore for Layered<A, B> {
+    type Error = Error<A::Error, B::Error>;
+    fn has_contents(&self, hash: Hash, change_id: Option<ChangeId>) -> bool {
+        self.top.has_contents(hash, change_id)
+            || self
+                .bottom
+                .as_ref()
+                .map(|b| b.has_contents(hash, change_id))
+                .unwrap_or(false)
+    }
+    fn get_contents<F: Fn(ChangeId) -> Option<Hash>>(
+        &self,
+        hash: F,
+        key: Vertex<ChangeId>,
+        buf: &mut [u8],
+    ) -> Result<usize, Self::Error> {
+        match self.top.get_contents(&hash, key, buf) {
+            Ok(n) => Ok(n),
+            Err(e) => {
+                if let Some(ref b) = self.bottom {
+                    if let Ok(n) = b.get_contents(&hash, key, buf) {
+                        return Ok(n);
+                    }
+                }
+                Err(Error::Top(e))
+            }
+        }
+    }
+    fn get_contents_batch<F: Fn(ChangeId) -> Option<Hash>>(
+        &self,
+        hash: F,
+        keys: &[Vertex<ChangeId>],
+        buf: &mut Vec<u8>,
+    ) -> Result<(), Self::Error> {
+        let len = buf.len();
+        match self.top.get_contents_batch(&hash, keys, buf) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                if let Some(ref b) = self.bottom {
+                    buf.truncate(len);
+                    if b.get_contents_batch(&hash, keys, buf).is_ok() {
+                        return Ok(());
+                    }
+                }
+                Err(Error::Top(e))
+            }
+        }
+    }
+    fn get_header(&self, h: &Hash) -> Result<ChangeHeader, Self::Error> {
+        self.get(|a| a.get_header(h), |b| b.get_header(h))
+    }
+    fn get_tag_header(&self, h: &Merkle) -> Result<ChangeHeader, Self::Error> {
+        self.get(|a| a.get_tag_header(h), |b| b.get_tag_header(h))
+    }
+    fn get_contents_ext(
+        &self,
+        key: Vertex<Option<Hash>>,
+        buf: &mut [u8],
+    ) -> Result<usize, Self::Error> {
+        match self.top.get_contents_ext(key, buf) {
+            Ok(n) => Ok(n),
+            Err(e) => {
+                if let Some(ref b) = self.bottom {
+                    if let Ok(n) = b.get_contents_ext(key, buf) {
+                        return Ok(n);
+                    }
+                }
+                Err(Error::Top(e))
+            }
+        }
+    }
+    fn get_dependencies(&self, hash: &Hash) -> Result<Vec<Hash>, Self::Error> {
+        self.get(|a| a.get_dependencies(hash), |b| b.get_dependencies(hash))
+    }
+    fn get_extra_known(&self, hash: &Hash) -> Result<Vec<Hash>, Self::Error> {
+        self.get(|a| a.get_extra_known(hash), |b| b.get_extra_known(hash))
+    }
+    fn get_changes(
+        &self,
+        hash: &Hash,
+    ) -> Result<Vec<crate::change::Hunk<Option<Hash>, crate::change::Local>>, Self::Error> {
+        self.get(|a| a.get_changes(hash), |b| b.get_changes(hash))
+    }
+    fn iter_hunks<'a>(&'a self, hash: &Hash) -> Result<HunksIter<'a, Self::Error>, Self::Error> {
+        match self.top.iter_hunks(hash) {
+            Ok(it) => Ok(Box::new(it.map(|h| h.map_err(Error::Top)))),
+            Err(e) => {
+                if let Some(ref b) = self.bottom {
+                    if let Ok(it) = b.iter_hunks(hash) {
+                        return Ok(Box::new(it.map(|h| h.map_err(Error::Bottom))));
+                    }
+                }
+                Err(Error::Top(e))
+            }
+        }
+    }
+    fn knows(&self, hash0: &Hash, hash1: &Hash) -> Result<bool, Self::Error> {
+        self.get(|a| a.knows(hash0, hash1), |b| b.knows(hash0, hash1))
+    }
+    fn has_edge(
+        &self,
+        change: Hash,
+        from: Position<Option<Hash>>,
+        to: Position<Option<Hash>>,
+        flags: crate::pristine::EdgeFlags,
+    ) -> Result<bool, Self::Error> {
+        self.get(
+            |a| a.has_edge(change, from, to, flags),
+            |b| b.has_edge(change, from, to, flags),
+        )
+    }
+    fn change_deletes_position<F: Fn(ChangeId) -> Option<Hash>>(
+        &self,
+        hash: F,
+        change: ChangeId,
+        pos: Position<Option<Hash>>,
+    ) -> Result<Vec<Hash>, Self::Error> {
+        self.get(
+            |a| a.change_deletes_position(&hash, change, pos),
+            |b| b.change_deletes_position(&hash, change, pos),
+        )
+    }
+    fn save_change<
+        E: From<Self::Error> + From<ChangeError>,
+        F: FnOnce(&mut Change, &Hash) -> Result<(), E>,
+    >(
+        &self,
+        p: &mut Change,
+        f: F,
+    ) -> Result<Hash, E> {
+        // `top` can't return errors of type `E`, so the error of `f`
+        // is kept aside, and replaced by a placeholder for `top`.
+        let mut hook_error = None;
+        let result = self.top.save_change(p, |p, h| {
+            f(p, h).map_err(|e| {
+                hook_error = Some(e);
+                A::Error::from(ChangeError::from(std::io::Error::new(
+                    std::io::ErrorKind::Interrupted,
+                    "change not saved",
+                )))
+            })
+        });
+        match result {
+            Ok(h) => Ok(h),
+            Err(e) => Err(hook_error.unwrap_or_else(|| Error::Top(e).into())),
+        }
+    }
+    fn save_change_with_contents<
+        E: From<Self::Error> + From<ChangeError>,
+        F: FnOnce(&mut Change, &Hash) -> Result<(), E>,
+    >(
+        &self,
+        p: &mut Change,
+        contents: &mut crate::record::ContentArena,
+        f: F,
+    ) -> Result<Hash, E> {
+        // Same as in `save_change`.
+        let mut hook_error = None;
+        let result = self.top.save_change_with_contents(p, contents, |p, h| {
+            f(p, h).map_err(|e| {
+                hook_error = Some(e);
+                A::Error::from(ChangeError::from(std::io::Error::new(
+                    std::io::ErrorKind::Interrupted,
+                    "change not saved",
+                )))
+            })
+        });
+        match result {
+            Ok(h) => Ok(h),
+            Err(e) => Err(hook_error.unwrap_or_else(|| Error::Top(e).into())),
+        }
+    }
+    fn del_change(&self, h: &Hash) -> Result<bool, Self::Error> {
+        self.top.del_change(h).map_err(Error::Top)
+    }
+    fn get_change(&self, h: &Hash) -> Result<Change, Self::Error> {
+        self.get(|a| a.get_change(h), |b| b.get_change(h))
+    }
+    /// The entries of `top`, followed by the entries of `bottom` that
+    /// are not in `top`.
+    fn iter_hashes(
+        &self,
+    ) -> Result<Box<dyn Iterator<Item = Result<StoreEntry, Self::Error>> + '_>, Self::Error> {
+        let mut entries = Vec::new();
+        let mut seen = HashSet::default();
+        for e in self.top.iter_hashes().map_err(Error::Top)? {
+            let e = e.map_err(Error::Top)?;
+            seen.insert(e.hash);
+            entries.push(Ok(e))
+        }
+        let bottom = if let Some(ref b) = self.bottom {
+            Some(b.iter_hashes().map_err(Error::Bottom)?)
+        } else {
+            None
+        };
+        Ok(Box::new(
+            entries.into_iter().chain(
+                bottom
+                    .into_iter()
+                    .flatten()
+                    .map(|e| e.map_err(Error::Bottom))
+                    .filter(move |e| match e {
+                        Ok(e) => !seen.contains(&e.hash),
+                        Err(_) => true,
+                    }),
+            ),
+        ))
+    }
+}
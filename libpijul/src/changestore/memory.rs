@@ -35,8 +35,11 @@ impl ChangeStore for Memory {
     type Error = Error;
     fn has_contents(&self, hash: Hash, _: Option<ChangeId>) -> bool {
         let changes = self.changes.read().unwrap();
-        let p = changes.get(&hash).unwrap();
-        !p.contents.is_empty()
+        if let Some(p) = changes.get(&hash) {
+            !p.contents.is_empty()
+        } else {
+            false
+        }
     }
 
     fn get_tag_header(&self, h: &crate::Merkle) -> Result<ChangeHeader, Self::Error> {
@@ -55,7 +58,8 @@ impl ChangeStore for Memory {
         }
         assert_eq!(buf.len(), key.end - key.start);
         let changes = self.changes.read().unwrap();
-        let p = changes.get(&hash(key.change).unwrap()).unwrap();
+        let h = hash(key.change).unwrap();
+        let p = changes.get(&h).ok_or(Error::ChangeNotFound { hash: h })?;
         let start = key.start.us();
         let end = key.end.us();
         buf.clone_from_slice(&p.contents[start..end]);
@@ -72,7 +76,9 @@ impl ChangeStore for Memory {
             }
             assert_eq!(key.end.us() - key.start.us(), buf.len());
             let changes = self.changes.read().unwrap();
-            let p = changes.get(&change).unwrap();
+            let p = changes
+                .get(&change)
+                .ok_or(Error::ChangeNotFound { hash: change })?;
             let start = key.start.us();
             let end = key.end.us();
             buf.clone_from_slice(&p.contents[start..end]);
@@ -88,7 +94,8 @@ impl ChangeStore for Memory {
         pos: Position<Option<Hash>>,
     ) -> Result<Vec<Hash>, Self::Error> {
         let changes = self.changes.read().unwrap();
-        let change = changes.get(&hash(change).unwrap()).unwrap();
+        let h = hash(change).unwrap();
+        let change = changes.get(&h).ok_or(Error::ChangeNotFound { hash: h })?;
         let mut v = Vec::new();
         for c in change.changes.iter() {
             for c in c.iter() {
@@ -123,4 +130,24 @@ impl ChangeStore for Memory {
             Err(Error::ChangeNotFound { hash: *h })
         }
     }
+    /// The sizes are the sizes of the uncompressed serialized
+    /// changes and tag headers.
+    fn iter_hashes(
+        &self,
+    ) -> Result<Box<dyn Iterator<Item = Result<StoreEntry, Self::Error>> + '_>, Self::Error> {
+        let mut entries = Vec::new();
+        for (h, p) in self.changes.read().unwrap().iter() {
+            entries.push(Ok(StoreEntry {
+                hash: StoredHash::Change(*h),
+                size: bincode::serialized_size(&p.hashed)? + p.contents.len() as u64,
+            }))
+        }
+        for (h, header) in self.tags.read().unwrap().iter() {
+            entries.push(Ok(StoreEntry {
+                hash: StoredHash::Tag(*h),
+                size: bincode::serialized_size(header)?,
+            }))
+        }
+        Ok(Box::new(entries.into_iter()))
+    }
 }
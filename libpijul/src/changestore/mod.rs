@@ -2,21 +2,31 @@
 //! changes are normally stored on disk, there are situations (such as
 //! an embedded Pijul) where one might want changes in-memory, in a
 //! database, or something else.
-use crate::pristine::{ChangeId, Hash, InodeMetadata, Position, Vertex};
+use crate::pristine::{ChangeId, Hash, InodeMetadata, Merkle, Position, Vertex};
 use crate::{
     change::{Change, ChangeError, ChangeHeader},
     text_encoding::Encoding,
 };
 
-#[cfg(feature = "ondisk-repos")]
+#[cfg(any(feature = "ondisk-repos", feature = "no-mmap"))]
 /// If this crate is compiled with the `ondisk-repos` feature (the
-/// default), this module stores changes on the file system, under
-/// `.pijul/changes`.
+/// default) or the `no-mmap` feature, this module stores changes on
+/// the file system, under `.pijul/changes`.
 pub mod filesystem;
 
 /// A change store entirely in memory.
 pub mod memory;
 
+/// A change store reading from another store for the changes it
+/// doesn't have, for example to share the changes of several
+/// repositories on the same machine.
+pub mod layered;
+
+/// The hunks of a change, as returned by [`ChangeStore::iter_hunks`].
+pub type HunksIter<'a, E> = Box<
+    dyn Iterator<Item = Result<crate::change::Hunk<Option<Hash>, crate::change::Local>, E>> + 'a,
+>;
+
 /// A trait for storing changes and reading from them.
 pub trait ChangeStore {
     type Error: std::error::Error
@@ -33,6 +43,26 @@ pub trait ChangeStore {
         key: Vertex<ChangeId>,
         buf: &mut [u8],
     ) -> Result<usize, Self::Error>;
+    /// Append the contents of `keys` to `buf`, in order. All the keys
+    /// must be vertices of the same change, sorted by their start
+    /// position, so that implementations can read them in a single
+    /// pass over the change.
+    fn get_contents_batch<F: Fn(ChangeId) -> Option<Hash>>(
+        &self,
+        hash: F,
+        keys: &[Vertex<ChangeId>],
+        buf: &mut Vec<u8>,
+    ) -> Result<(), Self::Error> {
+        for key in keys.iter() {
+            if key.end <= key.start {
+                continue;
+            }
+            let len = buf.len();
+            buf.resize(len + (key.end - key.start), 0);
+            self.get_contents(&hash, *key, &mut buf[len..])?;
+        }
+        Ok(())
+    }
     fn get_header(&self, h: &Hash) -> Result<ChangeHeader, Self::Error> {
         Ok(self.get_change(h)?.hashed.header)
     }
@@ -54,6 +84,14 @@ pub trait ChangeStore {
     ) -> Result<Vec<crate::change::Hunk<Option<Hash>, crate::change::Local>>, Self::Error> {
         Ok(self.get_change(hash)?.hashed.changes)
     }
+    /// Iterate over the hunks of a change. Unlike
+    /// [`ChangeStore::get_changes`], stores reading the changes from
+    /// the disk read the hunks one at a time, so that tools going
+    /// through many changes don't need to hold whole changes in
+    /// memory.
+    fn iter_hunks<'a>(&'a self, hash: &Hash) -> Result<HunksIter<'a, Self::Error>, Self::Error> {
+        Ok(Box::new(self.get_changes(hash)?.into_iter().map(Ok)))
+    }
     fn knows(&self, hash0: &Hash, hash1: &Hash) -> Result<bool, Self::Error> {
         debug!("knows: {:?} {:?}", hash0, hash1);
         Ok(self.get_change(hash0)?.knows(hash1))
@@ -82,6 +120,25 @@ pub trait ChangeStore {
         p: &mut Change,
         f: F,
     ) -> Result<Hash, E>;
+    /// Same as [`save_change`](Self::save_change), for a change whose
+    /// contents are in `contents` rather than in `p.contents`, as
+    /// returned by [`Recorded::into_change`](crate::record::Recorded::into_change).
+    /// Stores writing changes to disk don't load the contents in
+    /// memory. The default implementation moves them to `p.contents`.
+    fn save_change_with_contents<
+        E: From<Self::Error> + From<ChangeError>,
+        F: FnOnce(&mut Change, &Hash) -> Result<(), E>,
+    >(
+        &self,
+        p: &mut Change,
+        contents: &mut crate::record::ContentArena,
+        f: F,
+    ) -> Result<Hash, E> {
+        p.contents = std::mem::take(contents)
+            .into_vec()
+            .map_err(ChangeError::from)?;
+        self.save_change(p, f)
+    }
     fn del_change(&self, h: &Hash) -> Result<bool, Self::Error>;
     fn get_change(&self, h: &Hash) -> Result<Change, Self::Error>;
     fn get_file_meta<'a, F: Fn(ChangeId) -> Option<Hash>>(
@@ -91,10 +148,60 @@ pub trait ChangeStore {
         buf: &'a mut [u8],
     ) -> Result<FileMetadata<'a>, Self::Error> {
         self.get_contents(hash, vertex, buf)?;
-        Ok(FileMetadata::read(buf))
+        Ok(FileMetadata::try_read(buf).map_err(crate::change::ChangeError::from)?)
+    }
+    /// Iterate over all the changes and tags in this store, in no
+    /// particular order.
+    fn iter_hashes(
+        &self,
+    ) -> Result<Box<dyn Iterator<Item = Result<StoreEntry, Self::Error>> + '_>, Self::Error>;
+    /// Count the changes and tags in this store, and their total
+    /// sizes.
+    fn stats(&self) -> Result<StoreStats, Self::Error> {
+        let mut stats = StoreStats::default();
+        for e in self.iter_hashes()? {
+            let e = e?;
+            match e.hash {
+                StoredHash::Change(_) => {
+                    stats.changes += 1;
+                    stats.changes_size += e.size
+                }
+                StoredHash::Tag(_) => {
+                    stats.tags += 1;
+                    stats.tags_size += e.size
+                }
+            }
+        }
+        Ok(stats)
     }
 }
 
+/// The hash of an entry of a change store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StoredHash {
+    Change(Hash),
+    Tag(Merkle),
+}
+
+/// An entry of a change store, as returned by
+/// [`ChangeStore::iter_hashes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoreEntry {
+    pub hash: StoredHash,
+    /// Size of the entry in the store, in bytes.
+    pub size: u64,
+}
+
+/// Statistics about a change store, as returned by
+/// [`ChangeStore::stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    pub changes: usize,
+    pub changes_size: u64,
+    pub tags: usize,
+    pub tags_size: u64,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct FileMetadata<'a> {
     pub metadata: InodeMetadata,
@@ -102,19 +209,39 @@ pub struct FileMetadata<'a> {
     pub encoding: Option<Encoding>,
 }
 
+#[derive(Debug, Error)]
+pub enum FileMetadataError {
+    #[error("File metadata too short")]
+    TooShort,
+    #[error("Invalid UTF-8 in file name: {0}")]
+    Utf8(#[from] std::str::Utf8Error),
+}
+
 impl<'a> FileMetadata<'a> {
+    /// Read the metadata of a file, panicking if `buf` is not valid
+    /// (see [`FileMetadata::try_read`]).
     pub fn read(buf: &'a [u8]) -> FileMetadata<'a> {
-        // FIXME use ? by adding the From trait somehow
+        match Self::try_read(buf) {
+            Ok(m) => m,
+            Err(e) => panic!("{}: {:?}", e, buf),
+        }
+    }
+
+    /// Read the metadata of a file, either in the current format or
+    /// in the format without an encoding.
+    pub fn try_read(buf: &'a [u8]) -> Result<FileMetadata<'a>, FileMetadataError> {
         trace!("filemetadata read: {:?}", buf);
         if let Ok(m) = bincode::deserialize(buf) {
-            m
+            Ok(m)
+        } else if buf.len() < 2 {
+            Err(FileMetadataError::TooShort)
         } else {
             let (a, b) = buf.split_at(2);
-            FileMetadata {
+            Ok(FileMetadata {
                 metadata: InodeMetadata::from_basename(a),
-                basename: std::str::from_utf8(b).unwrap(),
+                basename: std::str::from_utf8(b)?,
                 encoding: None,
-            }
+            })
         }
     }
 
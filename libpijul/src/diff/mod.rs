@@ -3,6 +3,7 @@ use crate::changestore::*;
 use crate::pristine::*;
 use crate::record::Recorded;
 use crate::text_encoding::Encoding;
+use std::sync::Arc;
 
 mod bin;
 
@@ -17,6 +18,112 @@ lazy_static! {
     pub static ref DEFAULT_SEPARATOR: regex::bytes::Regex = regex::bytes::Regex::new("\n").unwrap();
 }
 
+/// Separators used instead of the default one to split files into
+/// lines before diffing them, for the files whose path matches some
+/// pattern.
+#[derive(Clone, Default)]
+pub struct Separators {
+    rules: Vec<(Arc<dyn Fn(&str) -> bool + Send + Sync>, regex::bytes::Regex)>,
+}
+
+#[derive(Debug, Error)]
+pub enum SeparatorError {
+    #[error(transparent)]
+    Regex(#[from] regex::Error),
+    #[error("Separator {:?} matches the empty string", separator)]
+    MatchesEmpty { separator: String },
+}
+
+impl Separators {
+    /// Split the files whose path satisfies `matches` with
+    /// `separator`, unless a previous rule applies to them. Separators
+    /// matching the empty string are rejected, since they would split
+    /// files everywhere.
+    pub fn push<F: Fn(&str) -> bool + Send + Sync + 'static>(
+        &mut self,
+        matches: F,
+        separator: &str,
+    ) -> Result<(), SeparatorError> {
+        let re = regex::bytes::Regex::new(separator)?;
+        if re.is_match(b"") {
+            return Err(SeparatorError::MatchesEmpty {
+                separator: separator.to_string(),
+            });
+        }
+        self.rules.push((Arc::new(matches), re));
+        Ok(())
+    }
+
+    /// The separator for `path`, if there is a rule for it.
+    pub fn get(&self, path: &str) -> Option<&regex::bytes::Regex> {
+        self.rules
+            .iter()
+            .find(|(matches, _)| matches(path))
+            .map(|(_, re)| re)
+    }
+}
+
+/// Whether a file is diffed and recorded as text or as binary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileClass {
+    Binary,
+    Text,
+}
+
+/// Classes of the files whose path matches some pattern, overriding
+/// the detection of their encoding, which sometimes takes binary
+/// files for text (resulting in huge line diffs), or the opposite.
+#[derive(Clone, Default)]
+pub struct FileClasses {
+    rules: Vec<(Arc<dyn Fn(&str) -> bool + Send + Sync>, FileClass)>,
+}
+
+impl FileClasses {
+    /// Record the files whose path satisfies `matches` as `class`,
+    /// unless a previous rule applies to them.
+    pub fn push<F: Fn(&str) -> bool + Send + Sync + 'static>(
+        &mut self,
+        matches: F,
+        class: FileClass,
+    ) {
+        self.rules.push((Arc::new(matches), class))
+    }
+
+    /// The class of `path`, if there is a rule for it.
+    pub fn get(&self, path: &str) -> Option<FileClass> {
+        self.rules
+            .iter()
+            .find(|(matches, _)| matches(path))
+            .map(|(_, class)| *class)
+    }
+
+    /// The encoding with which `path` is recorded, where `detected`
+    /// is the encoding detected from `contents` (`None` for binary
+    /// files). Binary files forced to text are recorded as UTF-8 if
+    /// they are valid UTF-8, and as binary otherwise, since their
+    /// other bytes wouldn't survive the text format of changes.
+    pub(crate) fn encoding(
+        &self,
+        path: &str,
+        detected: Option<Encoding>,
+        contents: &[u8],
+    ) -> Option<Encoding> {
+        match self.get(path) {
+            Some(FileClass::Binary) => None,
+            Some(FileClass::Text) if detected.is_none() => {
+                if std::str::from_utf8(contents).is_ok() {
+                    Some(Encoding(encoding_rs::UTF_8))
+                } else {
+                    info!("not recording {:?} as text, it isn't valid UTF-8", path);
+                    None
+                }
+            }
+            _ => detected,
+        }
+    }
+}
+
 #[derive(Hash, Clone, Copy)]
 struct Line<'a> {
     l: &'a [u8],
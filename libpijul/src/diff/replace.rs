@@ -65,7 +65,7 @@ impl Recorded {
 
         let mut contents = self.contents.lock();
         for &line in &lines_b[from_new..(from_new + len)] {
-            contents.extend(line.l);
+            contents.extend_from_slice(line.l);
         }
         let end = contents.len();
         if start >= end {
@@ -111,6 +111,16 @@ fn closest_in_repo_ancestor<'a, T: TreeTxnT>(
 ) -> Result<(Inode, std::iter::Peekable<crate::path::Components<'a>>), TreeErr<T::TreeError>> {
     let mut components = crate::path::components(path).peekable();
     let mut fileid = OwnedPathId::inode(Inode::ROOT);
+    // Start from the deepest ancestor already in the path cache, if
+    // any.
+    let mut prefix = Vec::new();
+    if let Some(cache) = txn.path_cache() {
+        let (inode, n) = cache.lock().longest_prefix(crate::path::components(path));
+        trace!("cached prefix {:?} {:?}", inode, n);
+        fileid.parent_inode = inode;
+        prefix.extend((&mut components).take(n).map(|c| (c, None)));
+    }
+    let cached = prefix.len();
     while let Some(c) = components.peek() {
         trace!("component {:?}", c);
         fileid.basename.clone_from_str(c);
@@ -134,11 +144,16 @@ fn closest_in_repo_ancestor<'a, T: TreeTxnT>(
             break;
         }
         if found {
-            components.next();
+            prefix.extend(components.next().map(|c| (c, Some(fileid.parent_inode))));
         } else {
             break;
         }
     }
+    if prefix.len() > cached {
+        if let Some(cache) = txn.path_cache() {
+            cache.lock().insert(prefix)
+        }
+    }
     Ok((fileid.parent_inode, components))
 }
 
@@ -249,6 +264,13 @@ pub fn make_new_child<T: TreeMutTxnT>(
     }
 }
 
+/// Check that `path` can be added to a repository (see
+/// [`crate::path::validate`]), returning the escaped path and the
+/// reason if it can't.
+pub(crate) fn check_path(path: &str) -> Result<(), String> {
+    crate::path::validate(path).map_err(|e| format!("{} ({})", crate::path::escape(path), e))
+}
+
 pub fn add_inode<T: TreeMutTxnT>(
     txn: &mut T,
     inode: Option<Inode>,
@@ -257,6 +279,7 @@ pub fn add_inode<T: TreeMutTxnT>(
     salt: u64,
 ) -> Result<Inode, FsError<T>> {
     debug!("add_inode");
+    check_path(path).map_err(FsError::InvalidPath)?;
     if let Some(parent) = crate::path::parent(path) {
         let (current_inode, unrecorded_path) = closest_in_repo_ancestor(txn, parent)?;
         let mut current_inode = current_inode;
@@ -297,6 +320,8 @@ pub fn move_file_by_inode<T: TreeMutTxnT>(
     salt: u64,
 ) -> Result<(), FsError<T>> {
     debug!("inode = {:?}", inode);
+    // Check before removing the inode from its current place.
+    check_path(destination).map_err(FsError::InvalidPath)?;
     let fileref = if let Some(inode) = txn.get_revtree(&inode, None)? {
         inode.to_owned()
     } else {
@@ -901,7 +926,10 @@ pub(crate) fn follow_oldest_path<T: ChannelTxnT, C: ChangeStore>(
                     )
                     .map_err(FsErrorC::Changestore)?;
                 debug!("{:?}", std::str::from_utf8(&name_buf));
-                let FileMetadata { basename, .. } = FileMetadata::read(&name_buf);
+                let FileMetadata { basename, .. } =
+                    FileMetadata::try_read(&name_buf).map_err(|e| {
+                        FsErrorC::Changestore(crate::change::ChangeError::from(e).into())
+                    })?;
                 if basename == c {
                     let age = txn
                         .get_changeset(txn.changes(&channel), &name.dest().change)
@@ -934,115 +962,271 @@ pub(crate) fn follow_oldest_path<T: ChannelTxnT, C: ChangeStore>(
     Ok((current, ambiguous))
 }
 
+/// The inodes of the files named `path` by the changes of `channel`,
+/// oldest first, according to the path index. This includes the
+/// files that were moved away from `path` or deleted, but not the
+/// descendants of directories named `path`. If the index has no entry
+/// for `path`, for instance because it was created after the changes
+/// naming `path` were applied, this falls back to the oldest file
+/// currently at `path`.
+pub(crate) fn path_inodes<
+    T: ChannelTxnT + DepsTxnT<DepsError = <T as GraphTxnT>::GraphError>,
+    C: ChangeStore,
+>(
+    changes: &C,
+    txn: &T,
+    channel: &T::Channel,
+    path: &str,
+) -> Result<Vec<Position<ChangeId>>, FsErrorC<C::Error, T>> {
+    let path = crate::path::components(path).collect::<Vec<_>>().join("/");
+    let mut named = Vec::new();
+    for (change, inode) in txn.get_path_inodes(&path)? {
+        if let Some(&t) = txn.get_changeset(txn.changes(channel), &change)? {
+            named.push((t, inode))
+        }
+    }
+    if named.is_empty() {
+        let (inode, _) = follow_oldest_path(changes, txn, channel, &path)?;
+        return Ok(vec![inode]);
+    }
+    named.sort();
+    let mut seen = HashSet::new();
+    Ok(named
+        .into_iter()
+        .filter(|(_, inode)| seen.insert(*inode))
+        .map(|(_, inode)| inode)
+        .collect())
+}
+
 pub fn find_path<T: ChannelTxnT, C: ChangeStore>(
     changes: &C,
     txn: &T,
     channel: &T::Channel,
     youngest: bool,
-    mut v: Position<ChangeId>,
+    v: Position<ChangeId>,
 ) -> Result<Option<(String, bool)>, crate::output::FileError<C::Error, T>> {
-    debug!("oldest_path = {:?}", v);
-    let mut path = Vec::new();
-    let mut name_buf = Vec::new();
-    let mut seen = HashSet::new();
-    let flag0 = EdgeFlags::FOLDER | EdgeFlags::PARENT;
-    let flag1 = EdgeFlags::all();
-    let mut all_alive = true;
-    'outer: while !v.change.is_root() {
-        debug!("path = {:?}", path);
-        let mut next_v = None;
-        let mut alive = false;
-        let inode_vertex = match txn.find_block_end(txn.graph(channel), v) {
-            Ok(block) => block,
-            Err(BlockError::Block { block, .. }) => {
-                debug!("block not found {:?}", block);
-                assert!(path.is_empty());
-                return Ok(None);
-            }
-            Err(BlockError::Txn(t)) => return Err(crate::output::FileError::Txn(TxnErr(t))),
-        };
-        debug!("inode_vertex = {:?}", inode_vertex);
-        if *inode_vertex != v.inode_vertex() {
-            info!(
-                "find_path: {:?} != {:?}, this may be due to a corrupt change",
-                inode_vertex,
-                v.inode_vertex()
-            );
-            return Ok(None);
+    PathResolver::new(youngest).find_path(changes, txn, channel, v)
+}
+
+/// Resolves the paths of many positions of the same channel, for
+/// instance all the files touched by a list of changes. The paths of
+/// the directories met along the way are remembered, so the graph is
+/// walked only once per directory.
+///
+/// The paths are cached, so a resolver must only be used with a
+/// single transaction and channel, and not across changes to them.
+pub struct PathResolver {
+    youngest: bool,
+    cache: crate::HashMap<Position<ChangeId>, Option<(String, bool)>>,
+}
+
+impl PathResolver {
+    /// A resolver picking the youngest names of files if `youngest`
+    /// is true, and the oldest names else (see [`find_path`]).
+    pub fn new(youngest: bool) -> Self {
+        PathResolver {
+            youngest,
+            cache: crate::HashMap::default(),
         }
-        for name in iter_adjacent(txn, txn.graph(channel), v.inode_vertex(), flag0, flag1)? {
-            let name = name?;
-            if name.dest().is_root() {
-                break 'outer;
+    }
+
+    /// The path of `v`, and whether all its components are alive.
+    pub fn find_path<T: ChannelTxnT, C: ChangeStore>(
+        &mut self,
+        changes: &C,
+        txn: &T,
+        channel: &T::Channel,
+        mut v: Position<ChangeId>,
+    ) -> Result<Option<(String, bool)>, crate::output::FileError<C::Error, T>> {
+        debug!("find_path = {:?}", v);
+        let mut stack = Vec::new();
+        let mut seen = HashSet::new();
+        let mut name_buf = Vec::new();
+        let mut result = loop {
+            if v.change.is_root() {
+                break Some((String::new(), true));
             }
-            if !name.flag().contains(EdgeFlags::PARENT) {
-                continue;
+            if let Some(r) = self.cache.get(&v) {
+                break r.clone();
             }
-
-            debug!("oldest_path, name = {:?}", name);
-            let age = txn
-                .get_changeset(txn.changes(&channel), &name.dest().change)?
-                .unwrap();
-
-            let name_dest = txn.find_block_end(txn.graph(channel), name.dest()).unwrap();
-            debug!("name_dest = {:?}", name_dest);
-            let mut next = None;
-            for e in iter_adjacent(txn, txn.graph(channel), *name_dest, flag0, flag1)? {
-                let e = e?;
-                if e.flag().contains(EdgeFlags::PARENT | EdgeFlags::FOLDER) {
-                    next = Some(e);
-                    break;
+            match path_step(
+                changes,
+                txn,
+                channel,
+                self.youngest,
+                v,
+                &mut seen,
+                &mut name_buf,
+            )? {
+                PathStep::Root => break Some((String::new(), true)),
+                PathStep::NotFound => break None,
+                PathStep::Parent {
+                    basename,
+                    all_alive,
+                    next,
+                } => {
+                    stack.push((v, basename, all_alive));
+                    v = next
                 }
             }
-            debug!("next = {:?}", next);
-            if let Some(next) = next {
-                debug!("oldest_path, next = {:?}", next);
-                if !next.flag().contains(EdgeFlags::DELETED) {
-                    alive = true;
-                } else if alive {
-                    break;
-                } else {
-                    all_alive = false
-                }
-                if let Some((_, p_age, _)) = next_v {
-                    if (age > p_age) ^ youngest {
-                        debug!("youngest");
-                        continue;
+        };
+        while let Some((v, basename, alive)) = stack.pop() {
+            result = result.map(|(mut path, all_alive)| {
+                if let Some(basename) = basename {
+                    if !path.is_empty() {
+                        path.push('/')
                     }
+                    path.push_str(&basename)
                 }
-                if seen.get(&next.dest()).is_some() {
-                    debug!("seen");
-                    continue;
-                }
-                next_v = Some((name_dest, age, next.dest()));
-            }
+                (path, all_alive && alive)
+            });
+            self.cache.insert(v, result.clone());
         }
-        let (name, _, next) = next_v.unwrap();
-        seen.insert(next);
-        if name.start == name.end {
-            // Non-zero root vertex
-            assert!(next.change.is_root());
-            break;
+        Ok(result)
+    }
+
+    /// The paths of all the positions of `positions`, in the same
+    /// order.
+    pub fn find_paths<
+        T: ChannelTxnT,
+        C: ChangeStore,
+        I: IntoIterator<Item = Position<ChangeId>>,
+    >(
+        &mut self,
+        changes: &C,
+        txn: &T,
+        channel: &T::Channel,
+        positions: I,
+    ) -> Result<Vec<Option<(String, bool)>>, crate::output::FileError<C::Error, T>> {
+        positions
+            .into_iter()
+            .map(|v| self.find_path(changes, txn, channel, v))
+            .collect()
+    }
+}
+
+enum PathStep {
+    /// The parent of the current vertex is the root.
+    Root,
+    /// The current vertex isn't in the graph.
+    NotFound,
+    /// The current vertex is named `basename` in `next`. `basename`
+    /// is `None` if that name is deleted.
+    Parent {
+        basename: Option<String>,
+        all_alive: bool,
+        next: Position<ChangeId>,
+    },
+}
+
+/// Find the parent of `v`, and its name in that parent.
+fn path_step<T: ChannelTxnT, C: ChangeStore>(
+    changes: &C,
+    txn: &T,
+    channel: &T::Channel,
+    youngest: bool,
+    v: Position<ChangeId>,
+    seen: &mut HashSet<Position<ChangeId>>,
+    name_buf: &mut Vec<u8>,
+) -> Result<PathStep, crate::output::FileError<C::Error, T>> {
+    let flag0 = EdgeFlags::FOLDER | EdgeFlags::PARENT;
+    let flag1 = EdgeFlags::all();
+    let mut all_alive = true;
+    let mut next_v = None;
+    let mut alive = false;
+    let inode_vertex = match txn.find_block_end(txn.graph(channel), v) {
+        Ok(block) => block,
+        Err(BlockError::Block { block, .. }) => {
+            debug!("block not found {:?}", block);
+            return Ok(PathStep::NotFound);
+        }
+        Err(BlockError::Txn(t)) => return Err(crate::output::FileError::Txn(TxnErr(t))),
+    };
+    debug!("inode_vertex = {:?}", inode_vertex);
+    if *inode_vertex != v.inode_vertex() {
+        info!(
+            "find_path: {:?} != {:?}, this may be due to a corrupt change",
+            inode_vertex,
+            v.inode_vertex()
+        );
+        return Ok(PathStep::NotFound);
+    }
+    for name in iter_adjacent(txn, txn.graph(channel), v.inode_vertex(), flag0, flag1)? {
+        let name = name?;
+        if name.dest().is_root() {
+            return Ok(PathStep::Root);
+        }
+        if !name.flag().contains(EdgeFlags::PARENT) {
+            continue;
         }
-        if alive {
-            name_buf.resize(name.end - name.start, 0);
-            debug!("getting contents {:?}", name);
 
-            let FileMetadata { basename, .. } = changes
-                .get_file_meta(
-                    |p| txn.get_external(&p).unwrap().map(From::from),
-                    *name,
-                    &mut name_buf,
-                )
-                .map_err(crate::output::FileError::Changestore)?;
+        debug!("oldest_path, name = {:?}", name);
+        let age = txn
+            .get_changeset(txn.changes(&channel), &name.dest().change)?
+            .unwrap();
 
-            path.push(basename.to_string());
+        let name_dest = txn.find_block_end(txn.graph(channel), name.dest()).unwrap();
+        debug!("name_dest = {:?}", name_dest);
+        let mut next = None;
+        for e in iter_adjacent(txn, txn.graph(channel), *name_dest, flag0, flag1)? {
+            let e = e?;
+            if e.flag().contains(EdgeFlags::PARENT | EdgeFlags::FOLDER) {
+                next = Some(e);
+                break;
+            }
         }
         debug!("next = {:?}", next);
-        v = next;
+        if let Some(next) = next {
+            debug!("oldest_path, next = {:?}", next);
+            if !next.flag().contains(EdgeFlags::DELETED) {
+                alive = true;
+            } else if alive {
+                break;
+            } else {
+                all_alive = false
+            }
+            if let Some((_, p_age, _)) = next_v {
+                if (age > p_age) ^ youngest {
+                    debug!("youngest");
+                    continue;
+                }
+            }
+            if seen.get(&next.dest()).is_some() {
+                debug!("seen");
+                continue;
+            }
+            next_v = Some((name_dest, age, next.dest()));
+        }
+    }
+    let (name, _, next) = next_v.unwrap();
+    seen.insert(next);
+    if name.start == name.end {
+        // Non-zero root vertex
+        assert!(next.change.is_root());
+        return Ok(PathStep::Root);
     }
-    path.reverse();
-    Ok(Some((path.join("/"), all_alive)))
+    let basename = if alive {
+        name_buf.resize(name.end - name.start, 0);
+        debug!("getting contents {:?}", name);
+
+        let FileMetadata { basename, .. } = changes
+            .get_file_meta(
+                |p| txn.get_external(&p).unwrap().map(From::from),
+                *name,
+                name_buf,
+            )
+            .map_err(crate::output::FileError::Changestore)?;
+
+        Some(basename.to_string())
+    } else {
+        None
+    };
+    debug!("next = {:?}", next);
+    Ok(PathStep::Parent {
+        basename,
+        all_alive,
+        next,
+    })
 }
 
 pub fn get_latest_touch<'a, T: ChannelTxnT + DepsTxnT<DepsError = <T as GraphTxnT>::GraphError>>(
@@ -1088,3 +1272,155 @@ pub fn get_latest_touch<'a, T: ChannelTxnT + DepsTxnT<DepsError = <T as GraphTxn
     }
     Ok((latest_change.into(), id))
 }
+
+/// A difference between the file trees of two channels, as returned
+/// by [`diff_trees`](fn.diff_trees.html).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeDiff {
+    Added {
+        path: String,
+        is_dir: bool,
+    },
+    Removed {
+        path: String,
+        is_dir: bool,
+    },
+    Moved {
+        from: String,
+        to: String,
+        modified: bool,
+    },
+    Modified {
+        path: String,
+    },
+}
+
+impl TreeDiff {
+    /// The path of the file in the second channel, or in the first
+    /// one if it was removed.
+    pub fn path(&self) -> &str {
+        match self {
+            TreeDiff::Added { path, .. }
+            | TreeDiff::Removed { path, .. }
+            | TreeDiff::Modified { path } => path,
+            TreeDiff::Moved { to, .. } => to,
+        }
+    }
+}
+
+/// The paths of all the files of `graph` (in the first name order
+/// of conflicting names), indexed by the position of their inode,
+/// along with whether they are directories.
+fn graph_tree<T: GraphTxnT, P: ChangeStore>(
+    txn: &T,
+    changes: &P,
+    graph: &T::Graph,
+) -> Result<crate::HashMap<Position<ChangeId>, (String, bool)>, TxnErr<T::GraphError>> {
+    let mut files = crate::HashMap::default();
+    let mut stack = vec![(Position::ROOT, String::new())];
+    while let Some((pos, path)) = stack.pop() {
+        for x in iter_graph_children(txn, changes, graph, pos).map_err(TxnErr)? {
+            let (child, _, meta, name) = x.map_err(TxnErr)?;
+            if files.contains_key(&child) {
+                continue;
+            }
+            let mut child_path = path.clone();
+            crate::path::push(&mut child_path, &name);
+            if meta.is_dir() {
+                stack.push((child, child_path.clone()))
+            }
+            files.insert(child, (child_path, meta.is_dir()));
+        }
+    }
+    Ok(files)
+}
+
+/// Is `pos` touched by a change that is in only one of `a` and `b`?
+fn touched_by_one<T: ChannelTxnT + DepsTxnT<DepsError = <T as GraphTxnT>::GraphError>>(
+    txn: &T,
+    a: &T::Channel,
+    b: &T::Channel,
+    pos: &Position<ChangeId>,
+) -> Result<bool, TxnErr<T::GraphError>> {
+    for x in txn.iter_touched(pos)? {
+        let (p, change) = x?;
+        if p > pos {
+            break;
+        } else if p < pos {
+            continue;
+        }
+        let in_a = txn.get_changeset(txn.changes(a), change)?.is_some();
+        let in_b = txn.get_changeset(txn.changes(b), change)?.is_some();
+        if in_a != in_b {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// The alive vertices of file `pos` in `graph`, sorted.
+fn alive_vertices<T: GraphTxnT>(
+    txn: &T,
+    graph: &T::Graph,
+    pos: Position<ChangeId>,
+) -> Result<Vec<Vertex<ChangeId>>, TxnErr<T::GraphError>> {
+    let g = crate::alive::retrieve(txn, graph, pos)?;
+    let mut v: Vec<_> = g
+        .lines
+        .iter()
+        .map(|l| l.vertex)
+        .filter(|v| v.start < v.end)
+        .collect();
+    v.sort();
+    Ok(v)
+}
+
+/// Compare the file trees of channels `a` and `b`, without
+/// outputting any file. Files are identified by their inode, so
+/// moves are detected exactly. A file present in both channels is
+/// modified if its alive lines differ, which is only checked for the
+/// files touched by changes in only one of the channels.
+///
+/// The result is sorted by path.
+pub fn diff_trees<T, P>(
+    txn: &T,
+    changes: &P,
+    a: &T::Channel,
+    b: &T::Channel,
+) -> Result<Vec<TreeDiff>, TxnErr<T::GraphError>>
+where
+    T: ChannelTxnT + DepsTxnT<DepsError = <T as GraphTxnT>::GraphError>,
+    P: ChangeStore,
+{
+    let tree_a = graph_tree(txn, changes, txn.graph(a))?;
+    let mut tree_b = graph_tree(txn, changes, txn.graph(b))?;
+    let mut result = Vec::new();
+    for (pos, (path_a, is_dir)) in tree_a {
+        let (path_b, _) = if let Some(x) = tree_b.remove(&pos) {
+            x
+        } else {
+            result.push(TreeDiff::Removed {
+                path: path_a,
+                is_dir,
+            });
+            continue;
+        };
+        let modified = !is_dir
+            && touched_by_one(txn, a, b, &pos)?
+            && alive_vertices(txn, txn.graph(a), pos)? != alive_vertices(txn, txn.graph(b), pos)?;
+        if path_a != path_b {
+            result.push(TreeDiff::Moved {
+                from: path_a,
+                to: path_b,
+                modified,
+            })
+        } else if modified {
+            result.push(TreeDiff::Modified { path: path_b })
+        }
+    }
+    for (_, (path, is_dir)) in tree_b {
+        result.push(TreeDiff::Added { path, is_dir })
+    }
+    result.sort_by(|x, y| x.path().cmp(y.path()));
+    Ok(result)
+}
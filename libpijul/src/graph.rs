@@ -0,0 +1,283 @@
+//! Queries on the graph of a channel, for tools that need to analyse
+//! a repository beyond what the working copy shows, and the
+//! operations on vertices needed by tools that edit it at a finer
+//! granularity than lines.
+//!
+//! All the functions of this module take the graph of a channel,
+//! obtained with [`ChannelTxnT::graph`](crate::pristine::ChannelTxnT::graph)
+//! (or [`ChannelMutTxnT::graph_mut`](crate::pristine::ChannelMutTxnT::graph_mut)
+//! to split vertices). Vertices are blocks of bytes introduced by a
+//! change, and edges are labelled by [`EdgeFlags`]. Every edge is
+//! stored twice, once in each direction, the reverse one having the
+//! `PARENT` flag.
+//!
+//! A vertex can be split into two vertices linked by a `BLOCK` edge
+//! without changing the contents of the files, which is how changes
+//! insert or delete bytes in the middle of a vertex. [`next_block`]
+//! and [`previous_block`] go from one part of a split vertex to the
+//! next.
+
+pub use crate::pristine::EdgeFlags;
+use crate::pristine::{
+    iter_adjacent, iter_alive_children, iter_deleted_parents, AdjacentIterator, BlockError,
+    ChangeId, ChangePosition, GraphMutTxnT, GraphTxnT, Position, SerializedEdge, TxnErr, Vertex,
+};
+
+/// The status of a vertex in a graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VertexStatus {
+    /// The vertex is alive, i.e. has at least one alive parent.
+    Alive,
+    /// The vertex is alive but also deleted by at least one change,
+    /// which happens in conflicts between a deletion and an edit.
+    Zombie,
+    /// The vertex is deleted.
+    Deleted,
+}
+
+/// Iterate over the edges starting from `vertex` whose flags are
+/// between `min_flag` and `max_flag` (both included), seen as
+/// integers. Edges are sorted by flags.
+pub fn adjacent<'txn, T: GraphTxnT>(
+    txn: &'txn T,
+    graph: &'txn T::Graph,
+    vertex: Vertex<ChangeId>,
+    min_flag: EdgeFlags,
+    max_flag: EdgeFlags,
+) -> Result<AdjacentIterator<'txn, T>, TxnErr<T::GraphError>> {
+    iter_adjacent(txn, graph, vertex, min_flag, max_flag)
+}
+
+/// Iterate over the edges from `vertex` to its alive children
+/// (including pseudo-edges and folder edges).
+pub fn alive_children<'txn, T: GraphTxnT>(
+    txn: &'txn T,
+    graph: &'txn T::Graph,
+    vertex: Vertex<ChangeId>,
+) -> Result<AdjacentIterator<'txn, T>, TxnErr<T::GraphError>> {
+    iter_alive_children(txn, graph, vertex)
+}
+
+/// Iterate over the edges from `vertex` to all its children, alive
+/// or deleted.
+pub fn children<'txn, T: GraphTxnT>(
+    txn: &'txn T,
+    graph: &'txn T::Graph,
+    vertex: Vertex<ChangeId>,
+) -> Result<
+    impl Iterator<Item = Result<&'txn SerializedEdge, TxnErr<T::GraphError>>> + 'txn,
+    TxnErr<T::GraphError>,
+> {
+    Ok(
+        iter_adjacent(txn, graph, vertex, EdgeFlags::empty(), EdgeFlags::all()).filter(|e| {
+            if let Ok(e) = e {
+                !e.flag().contains(EdgeFlags::PARENT)
+            } else {
+                true
+            }
+        }),
+    )
+}
+
+/// Iterate over the edges from `vertex` to all its parents, alive or
+/// deleted. The edges returned have the `PARENT` flag.
+pub fn parents<'txn, T: GraphTxnT>(
+    txn: &'txn T,
+    graph: &'txn T::Graph,
+    vertex: Vertex<ChangeId>,
+) -> Result<
+    impl Iterator<Item = Result<&'txn SerializedEdge, TxnErr<T::GraphError>>> + 'txn,
+    TxnErr<T::GraphError>,
+> {
+    Ok(
+        iter_adjacent(txn, graph, vertex, EdgeFlags::PARENT, EdgeFlags::all()).filter(|e| {
+            if let Ok(e) = e {
+                e.flag().contains(EdgeFlags::PARENT)
+            } else {
+                true
+            }
+        }),
+    )
+}
+
+/// Iterate over the edges from `vertex` to the parents that deleted
+/// it. The edges returned have the `PARENT` and `DELETED` flags.
+pub fn deleted_parents<'txn, T: GraphTxnT>(
+    txn: &'txn T,
+    graph: &'txn T::Graph,
+    vertex: Vertex<ChangeId>,
+) -> Result<AdjacentIterator<'txn, T>, TxnErr<T::GraphError>> {
+    iter_deleted_parents(txn, graph, vertex)
+}
+
+/// Is `vertex` alive, i.e. does it have an alive parent? Zombies are
+/// alive.
+pub fn is_alive<T: GraphTxnT>(
+    txn: &T,
+    graph: &T::Graph,
+    vertex: &Vertex<ChangeId>,
+) -> Result<bool, TxnErr<T::GraphError>> {
+    crate::pristine::is_alive(txn, graph, vertex)
+}
+
+/// Is `vertex` both alive and deleted?
+pub fn is_zombie<T: GraphTxnT>(
+    txn: &T,
+    graph: &T::Graph,
+    vertex: &Vertex<ChangeId>,
+) -> Result<bool, TxnErr<T::GraphError>> {
+    Ok(status(txn, graph, vertex)? == VertexStatus::Zombie)
+}
+
+/// Is `vertex` deleted (and not alive)?
+pub fn is_deleted<T: GraphTxnT>(
+    txn: &T,
+    graph: &T::Graph,
+    vertex: &Vertex<ChangeId>,
+) -> Result<bool, TxnErr<T::GraphError>> {
+    Ok(status(txn, graph, vertex)? == VertexStatus::Deleted)
+}
+
+/// The status of `vertex` in `graph`.
+pub fn status<T: GraphTxnT>(
+    txn: &T,
+    graph: &T::Graph,
+    vertex: &Vertex<ChangeId>,
+) -> Result<VertexStatus, TxnErr<T::GraphError>> {
+    if !crate::pristine::is_alive(txn, graph, vertex)? {
+        Ok(VertexStatus::Deleted)
+    } else if let Some(e) = iter_deleted_parents(txn, graph, *vertex)?.next() {
+        e?;
+        Ok(VertexStatus::Zombie)
+    } else {
+        Ok(VertexStatus::Alive)
+    }
+}
+
+/// Find the vertex of `graph` that contains position `pos`.
+pub fn find_block<T: GraphTxnT>(
+    txn: &T,
+    graph: &T::Graph,
+    pos: Position<ChangeId>,
+) -> Result<Vertex<ChangeId>, BlockError<T::GraphError>> {
+    txn.find_block(graph, pos).map(|v| *v)
+}
+
+/// Find the vertex of `graph` that ends at position `pos`.
+pub fn find_block_end<T: GraphTxnT>(
+    txn: &T,
+    graph: &T::Graph,
+    pos: Position<ChangeId>,
+) -> Result<Vertex<ChangeId>, BlockError<T::GraphError>> {
+    txn.find_block_end(graph, pos).map(|v| *v)
+}
+
+/// The vertex following `vertex` in the contents of its change, if
+/// they are linked by a `BLOCK` edge, as the two parts of a split
+/// vertex are.
+pub fn next_block<T: GraphTxnT>(
+    txn: &T,
+    graph: &T::Graph,
+    vertex: Vertex<ChangeId>,
+) -> Result<Option<Vertex<ChangeId>>, BlockError<T::GraphError>> {
+    for e in children(txn, graph, vertex)? {
+        let e = e?;
+        if e.flag().contains(EdgeFlags::BLOCK) && e.dest() == vertex.end_pos() {
+            return Ok(Some(*txn.find_block(graph, e.dest())?));
+        }
+    }
+    Ok(None)
+}
+
+/// The vertex preceding `vertex` in the contents of its change, if
+/// they are linked by a `BLOCK` edge.
+pub fn previous_block<T: GraphTxnT>(
+    txn: &T,
+    graph: &T::Graph,
+    vertex: Vertex<ChangeId>,
+) -> Result<Option<Vertex<ChangeId>>, BlockError<T::GraphError>> {
+    for e in parents(txn, graph, vertex)? {
+        let e = e?;
+        if e.flag().contains(EdgeFlags::BLOCK) && e.dest() == vertex.start_pos() {
+            return Ok(Some(*txn.find_block_end(graph, e.dest())?));
+        }
+    }
+    Ok(None)
+}
+
+#[derive(Debug, Error)]
+pub enum SplitError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error("Vertex not found: {:?}", vertex)]
+    VertexNotFound { vertex: Vertex<ChangeId> },
+    #[error("No vertex contains position {:?}", pos)]
+    PositionNotFound { pos: Position<ChangeId> },
+    #[error("Cannot split vertex {:?} at {:?}", vertex, pos)]
+    InvalidPosition {
+        vertex: Vertex<ChangeId>,
+        pos: ChangePosition,
+    },
+}
+
+impl<T: std::error::Error + 'static> From<TxnErr<T>> for SplitError<T> {
+    fn from(e: TxnErr<T>) -> Self {
+        SplitError::Txn(e.0)
+    }
+}
+
+impl<T: std::error::Error + 'static> From<BlockError<T>> for SplitError<T> {
+    fn from(e: BlockError<T>) -> Self {
+        match e {
+            BlockError::Txn(e) => SplitError::Txn(e),
+            BlockError::Block { block } => SplitError::PositionNotFound { pos: block },
+        }
+    }
+}
+
+/// Split `vertex` into `[vertex.start, pos[` and `[pos, vertex.end[`,
+/// linked by a `BLOCK` edge, and return these two vertices. The
+/// parents of `vertex` become the parents of the first one, and its
+/// children the children of the second one.
+///
+/// Unlike [`GraphMutTxnT::split_block`], which panics on invalid
+/// arguments, this checks that `vertex` is a vertex of `graph` and
+/// that `pos` is strictly inside it.
+pub fn split_vertex<T: GraphMutTxnT>(
+    txn: &mut T,
+    graph: &mut T::Graph,
+    vertex: Vertex<ChangeId>,
+    pos: ChangePosition,
+) -> Result<(Vertex<ChangeId>, Vertex<ChangeId>), SplitError<T::GraphError>> {
+    if vertex.change.is_root() || pos <= vertex.start || pos >= vertex.end {
+        return Err(SplitError::InvalidPosition { vertex, pos });
+    }
+    match txn.find_block(graph, vertex.start_pos()) {
+        Ok(v) if *v == vertex => {}
+        Ok(_) | Err(BlockError::Block { .. }) => return Err(SplitError::VertexNotFound { vertex }),
+        Err(BlockError::Txn(e)) => return Err(SplitError::Txn(e)),
+    }
+    let mut buf = Vec::new();
+    txn.split_block(graph, &vertex, pos, &mut buf)?;
+    Ok((
+        Vertex { end: pos, ..vertex },
+        Vertex {
+            start: pos,
+            ..vertex
+        },
+    ))
+}
+
+/// Make `pos` the start of a vertex, splitting the vertex that
+/// contains it if needed, and return the vertex starting at `pos`.
+pub fn split_at<T: GraphMutTxnT>(
+    txn: &mut T,
+    graph: &mut T::Graph,
+    pos: Position<ChangeId>,
+) -> Result<Vertex<ChangeId>, SplitError<T::GraphError>> {
+    let vertex = *txn.find_block(graph, pos)?;
+    if vertex.start == pos.pos {
+        return Ok(vertex);
+    }
+    Ok(split_vertex(txn, graph, vertex, pos.pos)?.1)
+}
@@ -296,6 +296,32 @@ impl Signature {
     }
 }
 
+/// Verify a signature returned by [`SKey::sign_raw`], such as the
+/// signatures of changes, against the Ed25519 key encoded in base 58
+/// as `key` (the format of the `key` field of [`PublicKey`] and of
+/// change authors).
+pub fn verify_raw(key: &str, h: &[u8], signature: &str) -> Result<(), KeyError> {
+    let mut k = [0; 32];
+    bs58::decode(key.as_bytes()).into(&mut k)?;
+    let k = ed25519_dalek::PublicKey::from_bytes(&k)?;
+    let mut sig = [0; 64];
+    bs58::decode(signature.as_bytes()).into(&mut sig)?;
+    let sig = ed25519_dalek::Signature::from_bytes(&sig)?;
+    k.verify_strict(h, &sig)?;
+    Ok(())
+}
+
+#[test]
+fn verify_raw_test() {
+    let sk = SKey::generate(None);
+    let pk = sk.public_key();
+    let signature = sk.sign_raw(b"blabla").unwrap();
+    verify_raw(&pk.key, b"blabla", &signature).unwrap();
+    assert!(verify_raw(&pk.key, b"blablo", &signature).is_err());
+    let other = SKey::generate(None).public_key();
+    assert!(verify_raw(&other.key, b"blabla", &signature).is_err());
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 #[repr(u8)]
 pub enum Algorithm {
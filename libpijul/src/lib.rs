@@ -21,11 +21,14 @@ pub mod changestore;
 mod diff;
 pub mod find_alive;
 pub mod fs;
+pub mod graph;
 mod missing_context;
 pub mod output;
 pub mod path;
+mod preview;
 pub mod pristine;
 pub mod record;
+mod rollback;
 pub mod small_string;
 mod text_encoding;
 mod unrecord;
@@ -34,6 +37,7 @@ pub mod vertex_buffer;
 pub mod working_copy;
 
 pub mod key;
+#[cfg(feature = "zstd")]
 pub mod tag;
 
 mod chardetng;
@@ -64,17 +68,24 @@ pub enum RemoteError {
 }
 
 pub use crate::apply::Workspace as ApplyWorkspace;
-pub use crate::apply::{apply_change_arc, ApplyError, LocalApplyError};
-pub use crate::diff::DEFAULT_SEPARATOR;
+pub use crate::apply::{
+    apply_change_arc, check_alive_change, ApplyError, LocalApplyError, PseudoEdgeStats,
+};
+pub use crate::diff::{FileClass, FileClasses, SeparatorError, Separators, DEFAULT_SEPARATOR};
 pub use crate::fs::{FsError, WorkingCopyIterator};
 pub use crate::output::{Archive, Conflict};
+pub use crate::preview::{preview_conflicts, PreviewError};
 pub use crate::pristine::{
-    ArcTxn, Base32, ChangeId, ChannelMutTxnT, ChannelRef, ChannelTxnT, DepsTxnT, EdgeFlags,
-    GraphTxnT, Hash, Inode, Merkle, MutTxnT, OwnedPathId, RemoteRef, TreeTxnT, TxnT, Vertex,
+    lock_channels, ArcTxn, Base32, ChangeId, ChannelGuards, ChannelMutTxnT, ChannelRef,
+    ChannelTxnT, CommitError, DepsTxnT, EdgeFlags, GraphTxnT, Hash, Inode, Merkle, MutTxnT,
+    OwnedPathId, RemoteRef, TreeTxnT, TxnT, Vertex,
 };
 pub use crate::record::Builder as RecordBuilder;
-pub use crate::record::{Algorithm, InodeUpdate};
-pub use crate::unrecord::UnrecordError;
+pub use crate::record::{record_all_with_header, Algorithm, InodeUpdate};
+pub use crate::rollback::{invert_change, InvertError};
+pub use crate::unrecord::{
+    Blocker, Dependent, PartialUnrecord, RemoteUnrecord, UnrecordError, UnrecordOrderError,
+};
 
 // Making hashmaps deterministic (for testing)
 #[cfg(feature = "deterministic_hash")]
@@ -89,15 +100,31 @@ impl MutTxnTExt for pristine::sanakirja::MutTxn<()> {}
 impl TxnTExt for pristine::sanakirja::MutTxn<()> {}
 impl TxnTExt for pristine::sanakirja::Txn {}
 
-pub fn commit<T: pristine::MutTxnT>(
+/// Commit `txn`, failing if other references to it exist, or if its
+/// lock is poisoned.
+pub fn try_commit<T: pristine::MutTxnT>(
     txn: std::sync::Arc<std::sync::RwLock<T>>,
-) -> Result<(), T::GraphError> {
-    let txn = if let Ok(txn) = std::sync::Arc::try_unwrap(txn) {
-        txn.into_inner().unwrap()
-    } else {
-        unreachable!()
+) -> Result<(), pristine::CommitError<T::GraphError>> {
+    let txn = match std::sync::Arc::try_unwrap(txn) {
+        Ok(txn) => txn
+            .into_inner()
+            .map_err(|_| pristine::CommitError::Poisoned)?,
+        Err(txn) => {
+            return Err(pristine::CommitError::References {
+                strong_count: std::sync::Arc::strong_count(&txn),
+            })
+        }
     };
-    txn.commit()
+    txn.commit().map_err(pristine::CommitError::Txn)
+}
+
+/// Commit `txn`, returning an error instead of panicking if it is
+/// still referenced elsewhere.
+#[deprecated(note = "use `try_commit` instead")]
+pub fn commit<T: pristine::MutTxnT>(
+    txn: std::sync::Arc<std::sync::RwLock<T>>,
+) -> Result<(), pristine::CommitError<T::GraphError>> {
+    try_commit(txn)
 }
 
 pub trait MutTxnTExt: pristine::MutTxnT {
@@ -181,43 +208,86 @@ pub trait MutTxnTExt: pristine::MutTxnT {
         crate::apply::apply_local_change(self, channel, change, hash, inode_updates)
     }
 
+    /// Build a change from `recorded` and save it to `changestore`,
+    /// without applying it to any channel. The returned change and
+    /// inode updates can then be applied with
+    /// [`apply_local_change`](MutTxnTExt::apply_local_change). The
+    /// contents of the returned change are not loaded, see
+    /// [`Recorded::into_change`](record::Recorded::into_change).
+    fn save_recorded<C: changestore::ChangeStore>(
+        &self,
+        recorded: record::Recorded,
+        changestore: &C,
+    ) -> Result<
+        (pristine::Hash, change::Change, HashMap<usize, InodeUpdate>),
+        crate::apply::ApplyError<C::Error, Self>,
+    > {
+        let (mut change, mut contents, updates) =
+            recorded.into_change_no_deps(self, change::ChangeHeader::default())?;
+        let hash = changestore
+            .save_change_with_contents(&mut change, &mut contents, |_, _| Ok(()))
+            .map_err(apply::ApplyError::Changestore)?;
+        Ok((hash, change, updates))
+    }
+
+    /// Save `recorded` as a change with a default header and no
+    /// dependencies, and apply it to `channel`. See
+    /// [`apply_recorded_with_header`](MutTxnTExt::apply_recorded_with_header)
+    /// to set the header and dependencies.
     fn apply_recorded<C: changestore::ChangeStore>(
         &mut self,
         channel: &mut pristine::ChannelRef<Self>,
         recorded: record::Recorded,
         changestore: &C,
     ) -> Result<pristine::Hash, crate::apply::ApplyError<C::Error, Self>> {
-        let contents_hash = {
-            let mut hasher = pristine::Hasher::default();
-            hasher.update(&recorded.contents.lock()[..]);
-            hasher.finish()
-        };
-        let mut change = change::LocalChange {
-            offsets: change::Offsets::default(),
-            hashed: change::Hashed {
-                version: change::VERSION,
-                contents_hash,
-                changes: recorded
-                    .actions
-                    .into_iter()
-                    .map(|rec| rec.globalize(self).unwrap())
-                    .collect(),
-                metadata: Vec::new(),
-                dependencies: Vec::new(),
-                extra_known: Vec::new(),
-                header: change::ChangeHeader::default(),
-            },
-            unhashed: None,
-            contents: std::sync::Arc::try_unwrap(recorded.contents)
-                .unwrap()
-                .into_inner(),
-        };
+        let (hash, change, updates) = self.save_recorded(recorded, changestore)?;
+        apply::apply_local_change(self, channel, &change, &hash, &updates)
+            .map_err(ApplyError::LocalChange)?;
+        Ok(hash)
+    }
+
+    /// Build a change with header `header` from `recorded`, save it
+    /// to `changestore` and apply it to `channel`. The dependencies
+    /// are `dependencies` if given, or else computed from the changes
+    /// of `channel` touched by `recorded`. Returns the hash of the
+    /// new change and the new state of `channel`.
+    fn apply_recorded_with_header<C: changestore::ChangeStore>(
+        &mut self,
+        channel: &mut pristine::ChannelRef<Self>,
+        recorded: record::Recorded,
+        header: change::ChangeHeader,
+        dependencies: Option<Vec<pristine::Hash>>,
+        changestore: &C,
+    ) -> Result<(pristine::Hash, pristine::Merkle), crate::apply::ApplyError<C::Error, Self>> {
+        let (mut change, mut contents, updates) = recorded.into_change_no_deps(self, header)?;
+        if let Some(deps) = dependencies {
+            change.hashed.dependencies = deps
+        } else {
+            let (deps, extra_known) =
+                change::dependencies(self, &*channel.read(), change.hashed.changes.iter())
+                    .map_err(|e| ApplyError::LocalChange(LocalApplyError::Txn(e)))?;
+            change.hashed.dependencies = deps;
+            change.hashed.extra_known = extra_known;
+        }
         let hash = changestore
-            .save_change(&mut change, |_, _| Ok(()))
+            .save_change_with_contents(&mut change, &mut contents, |_, _| Ok(()))
             .map_err(apply::ApplyError::Changestore)?;
-        apply::apply_local_change(self, channel, &change, &hash, &recorded.updatables)
+        let (_, state) = apply::apply_local_change(self, channel, &change, &hash, &updates)
             .map_err(ApplyError::LocalChange)?;
-        Ok(hash)
+        Ok((hash, state))
+    }
+
+    /// Delete the pseudo-edges of `channel` that are implied by other
+    /// paths between alive vertices, and clean up the obsolete ones
+    /// as after applying a change. This doesn't change the contents
+    /// of the channel, but speeds up the traversals of its graph.
+    fn compact_pseudo_edges(
+        &mut self,
+        channel: &pristine::ChannelRef<Self>,
+    ) -> Result<PseudoEdgeStats, LocalApplyError<Self>> {
+        let mut channel = channel.write();
+        let mut ws = ApplyWorkspace::new();
+        crate::apply::compact_pseudo_edges(self, Self::graph_mut(&mut *channel), &mut ws)
     }
 
     fn unrecord<C: changestore::ChangeStore>(
@@ -230,6 +300,54 @@ pub trait MutTxnTExt: pristine::MutTxnT {
         unrecord::unrecord(self, channel, changes, hash, salt)
     }
 
+    /// Unrecord a batch of changes, given in any order, checking
+    /// first that no change outside the batch depends on them. See
+    /// [`TxnTExt::unrecord_order`].
+    fn unrecord_all<C: changestore::ChangeStore>(
+        &mut self,
+        changes: &C,
+        channel: &pristine::ChannelRef<Self>,
+        hashes: &[pristine::Hash],
+        salt: u64,
+    ) -> Result<Vec<pristine::Hash>, unrecord::UnrecordError<C::Error, Self>> {
+        unrecord::unrecord_all(self, channel, changes, hashes, salt)
+    }
+
+    /// Unrecord the hunks of change `hash` touching `path` (or a path
+    /// below it), keeping its other hunks in a new change and
+    /// rewriting the changes of `channel` depending on it. Fails if
+    /// the other hunks or the dependent changes use these hunks.
+    /// `sign` is called on each new change before it is saved.
+    fn unrecord_path<
+        C: changestore::ChangeStore,
+        S: FnMut(&mut change::Change, &pristine::Hash),
+    >(
+        &mut self,
+        changes: &C,
+        channel: &pristine::ChannelRef<Self>,
+        hash: &pristine::Hash,
+        path: &str,
+        salt: u64,
+        sign: S,
+    ) -> Result<PartialUnrecord, unrecord::UnrecordError<C::Error, Self>> {
+        unrecord::unrecord_path(self, channel, changes, hash, path, salt, sign)
+    }
+
+    /// Unrecord all the changes applied to `channel` at timestamp
+    /// `to_timestamp` or later, rebuilding the graph from the
+    /// remaining changes when that is faster than unrecording the
+    /// changes one by one. Returns the unrecorded changes, latest
+    /// first. The working copy must be output again afterwards.
+    fn truncate<C: changestore::ChangeStore>(
+        &mut self,
+        changes: &C,
+        channel: &pristine::ChannelRef<Self>,
+        to_timestamp: u64,
+        salt: u64,
+    ) -> Result<Vec<pristine::Hash>, unrecord::UnrecordError<C::Error, Self>> {
+        unrecord::truncate_channel(self, channel, changes, to_timestamp, salt)
+    }
+
     /// Register a file in the working copy, where the file is given by
     /// its path from the root of the repository, where the components of
     /// the path are separated by `/` (example path: `a/b/c`).
@@ -241,6 +359,11 @@ pub trait MutTxnTExt: pristine::MutTxnT {
     /// given by its path from the root of the repository, where the
     /// components of the path are separated by `/` (example path:
     /// `a/b/c`).
+    ///
+    /// Directories are tracked like files, even when they are empty:
+    /// recording an added directory records it, and outputting it
+    /// creates it even if it has no children. Like files, directories
+    /// that were not added are not recorded.
     fn add_dir(&mut self, path: &str, salt: u64) -> Result<Inode, fs::FsError<Self>> {
         fs::add_inode(self, None, path, true, salt)
     }
@@ -286,6 +409,17 @@ pub trait TxnTExt: pristine::TxnT {
         fs::iter_graph_children(self, changes, &self.graph(channel), key)
     }
 
+    /// Compare the file trees of channels `a` and `b`, see
+    /// [`fs::diff_trees`](fs/fn.diff_trees.html).
+    fn diff_trees<P: changestore::ChangeStore>(
+        &self,
+        changes: &P,
+        a: &pristine::ChannelRef<Self>,
+        b: &pristine::ChannelRef<Self>,
+    ) -> Result<Vec<fs::TreeDiff>, pristine::TxnErr<Self::GraphError>> {
+        fs::diff_trees(self, changes, &*a.read(), &*b.read())
+    }
+
     fn has_change(
         &self,
         channel: &pristine::ChannelRef<Self>,
@@ -312,6 +446,37 @@ pub trait TxnTExt: pristine::TxnT {
         pristine::current_state(self, channel).map_err(|e| e.0)
     }
 
+    /// Classify a change that was unrecorded from a remote channel,
+    /// relative to the local `channel`.
+    fn classify_remote_unrecord(
+        &self,
+        channel: &Self::Channel,
+        hash: &pristine::Hash,
+    ) -> Result<RemoteUnrecord, Self::GraphError> {
+        unrecord::classify_remote_unrecord(self, channel, hash).map_err(|e| e.0)
+    }
+
+    /// An order in which a batch of changes of `channel` can be
+    /// unrecorded (latest first), or the changes outside the batch
+    /// that prevent it.
+    fn unrecord_order(
+        &self,
+        channel: &Self::Channel,
+        hashes: &[pristine::Hash],
+    ) -> Result<Vec<pristine::Hash>, UnrecordOrderError<Self::GraphError>> {
+        unrecord::unrecord_order(self, channel, hashes)
+    }
+
+    /// The changes of `channel` that have to be unrecorded along with
+    /// `hashes`, including `hashes` (latest first).
+    fn unrecord_closure(
+        &self,
+        channel: &Self::Channel,
+        hashes: &[pristine::Hash],
+    ) -> Result<Vec<Dependent>, UnrecordOrderError<Self::GraphError>> {
+        unrecord::unrecord_closure(self, channel, hashes)
+    }
+
     fn log<'channel, 'txn>(
         &'txn self,
         channel: &'channel Self::Channel,
@@ -406,6 +571,60 @@ pub trait TxnTExt: pristine::TxnT {
         }
     }
 
+    /// The changes to unrecord and to apply to go from state `from`
+    /// of `channel` to its state `to`. `Merkle::zero()` is the state
+    /// of the empty channel. Since the states of a channel follow its
+    /// log, at most one of the two lists is non-empty: if `to` comes
+    /// after `from`, only changes need to be applied, else they need
+    /// to be unrecorded. Returns `None` if one of the states isn't a
+    /// state of `channel`.
+    fn changes_between(
+        &self,
+        channel: &Self::Channel,
+        from: &pristine::Merkle,
+        to: &pristine::Merkle,
+    ) -> Result<Option<ChangesBetween>, LogError<Self::GraphError>> {
+        // Position of a state in the log, `None` being before the
+        // first change.
+        let position = |m: &pristine::Merkle| {
+            if *m == pristine::Merkle::zero() {
+                return Ok(Some(None));
+            }
+            let n = self
+                .channel_has_state(self.states(channel), &(*m).into())
+                .map_err(|e| LogError::Txn(e.0))?;
+            Ok(n.map(|n| Some(n.into())))
+        };
+        let (from, to) = match (position(from)?, position(to)?) {
+            (Some(from), Some(to)) => (from, to),
+            _ => return Ok(None),
+        };
+        let (start, end) = if from <= to { (from, to) } else { (to, from) };
+        let mut hashes = Vec::new();
+        for x in self
+            .log(channel, start.map(|n| n + 1).unwrap_or(0))
+            .map_err(LogError::Txn)?
+        {
+            let (n, (h, _)) = x?;
+            if Some(n) > end {
+                break;
+            }
+            hashes.push(h.into())
+        }
+        Ok(Some(if from <= to {
+            ChangesBetween {
+                unrecord: Vec::new(),
+                apply: hashes,
+            }
+        } else {
+            hashes.reverse();
+            ChangesBetween {
+                unrecord: hashes,
+                apply: Vec::new(),
+            }
+        }))
+    }
+
     fn touched_files(&self, h: &pristine::Hash) -> Result<Option<Touched<Self>>, Self::DepsError> {
         if let Some(id) = pristine::GraphTxnT::get_internal(self, &h.into()).map_err(|e| e.0)? {
             Ok(Some(Touched {
@@ -453,6 +672,15 @@ pub trait TxnTExt: pristine::TxnT {
         fs::follow_oldest_path(changes, self, &channel.read(), path)
     }
 
+    fn path_inodes<C: changestore::ChangeStore>(
+        &self,
+        changes: &C,
+        channel: &pristine::ChannelRef<Self>,
+        path: &str,
+    ) -> Result<Vec<pristine::Position<pristine::ChangeId>>, fs::FsErrorC<C::Error, Self>> {
+        fs::path_inodes(changes, self, &channel.read(), path)
+    }
+
     fn iter_adjacent<'txn>(
         &'txn self,
         graph: &'txn Self::Channel,
@@ -562,6 +790,31 @@ impl<T: MutTxnT> ArcTxn<T> {
     }
 }
 
+/// The result of [`TxnTExt::changes_between`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ChangesBetween {
+    /// The changes to unrecord, latest first.
+    pub unrecord: Vec<pristine::Hash>,
+    /// The changes to apply, in the order of the log.
+    pub apply: Vec<pristine::Hash>,
+}
+
+/// An error of [`Log`] and [`RevLog`].
+#[derive(Debug, Error)]
+pub enum LogError<E: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(E),
+    /// The change at `timestamp` in the log of the channel has no
+    /// hash, which means that the pristine is corrupt.
+    #[error(
+        "Corrupt pristine: the change at timestamp {timestamp} of the log ({change:?}) has no hash"
+    )]
+    UnknownChange {
+        timestamp: u64,
+        change: pristine::ChangeId,
+    },
+}
+
 pub struct Log<'txn, T: pristine::ChannelTxnT> {
     txn: &'txn T,
     iter: pristine::Cursor<
@@ -582,20 +835,25 @@ impl<'txn, T: pristine::ChannelTxnT> Iterator for Log<'txn, T> {
                 &'txn pristine::SerializedMerkle,
             ),
         ),
-        T::GraphError,
+        LogError<T::GraphError>,
     >;
     fn next(&mut self) -> Option<Self::Item> {
         match self.iter.next() {
             Some(Ok((n, p))) => {
                 let ext = match self.txn.get_external(&p.a) {
-                    Err(pristine::TxnErr(e)) => return Some(Err(e)),
+                    Err(pristine::TxnErr(e)) => return Some(Err(LogError::Txn(e))),
                     Ok(Some(ext)) => ext,
-                    Ok(None) => panic!("Unknown change {:?}", p),
+                    Ok(None) => {
+                        return Some(Err(LogError::UnknownChange {
+                            timestamp: u64::from_le(n.0),
+                            change: p.a,
+                        }))
+                    }
                 };
                 Some(Ok((u64::from_le(n.0), (ext, &p.b))))
             }
             None => None,
-            Some(Err(e)) => Some(Err(e.0)),
+            Some(Err(e)) => Some(Err(LogError::Txn(e.0))),
         }
     }
 }
@@ -620,17 +878,20 @@ impl<'txn, T: pristine::ChannelTxnT> Iterator for RevLog<'txn, T> {
                 &'txn pristine::SerializedMerkle,
             ),
         ),
-        T::GraphError,
+        LogError<T::GraphError>,
     >;
     fn next(&mut self) -> Option<Self::Item> {
         match self.iter.next() {
             Some(Ok((n, p))) => match self.txn.get_external(&p.a.into()) {
                 Ok(Some(ext)) => Some(Ok((u64::from_le(n.0), (ext, &p.b)))),
-                Err(e) => Some(Err(e.0)),
-                Ok(None) => panic!("Unknown change {:?}", p),
+                Err(e) => Some(Err(LogError::Txn(e.0))),
+                Ok(None) => Some(Err(LogError::UnknownChange {
+                    timestamp: u64::from_le(n.0),
+                    change: p.a,
+                })),
             },
             None => None,
-            Some(Err(e)) => Some(Err(e.0)),
+            Some(Err(e)) => Some(Err(LogError::Txn(e.0))),
         }
     }
 }
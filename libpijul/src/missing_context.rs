@@ -13,6 +13,8 @@ pub enum MissingError<TxnError: std::error::Error + 'static> {
     Block(#[from] BlockError<TxnError>),
     #[error(transparent)]
     Inconsistent(#[from] InconsistentChange<TxnError>),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
 }
 
 impl<T: std::error::Error + 'static> std::convert::From<TxnErr<T>> for MissingError<T> {
@@ -121,7 +123,7 @@ fn repair_regular_up<T: GraphMutTxnT>(
             );
             continue;
         }
-        put_graph_with_rev(txn, channel, flag, ancestor, d, ChangeId::ROOT)?;
+        put_graph_with_rev_if_absent(txn, channel, flag, ancestor, d, ChangeId::ROOT)?;
     }
     Ok(())
 }
@@ -161,7 +163,14 @@ pub(crate) fn repair_missing_down_context<
                     continue;
                 }
                 debug!("repair_missing_down {:?} {:?}", d, desc);
-                put_graph_with_rev(txn, channel, EdgeFlags::PSEUDO, d, desc, ChangeId::ROOT)?;
+                put_graph_with_rev_if_absent(
+                    txn,
+                    channel,
+                    EdgeFlags::PSEUDO,
+                    d,
+                    desc,
+                    ChangeId::ROOT,
+                )?;
             }
         }
     } else {
@@ -260,7 +269,6 @@ where
 pub(crate) fn detect_folder_conflict_resolutions<T: GraphMutTxnT>(
     txn: &mut T,
     channel: &mut T::Graph,
-    ws: &mut Workspace,
     change_id: ChangeId,
     change: &Change,
 ) -> Result<(), MissingError<T::GraphError>> {
@@ -271,7 +279,7 @@ pub(crate) fn detect_folder_conflict_resolutions<T: GraphMutTxnT>(
                     if !edge.flag.contains(EdgeFlags::DELETED) {
                         continue;
                     }
-                    detect_folder_conflict_resolution(txn, channel, ws, change_id, &n.inode, edge)?
+                    detect_folder_conflict_resolution(txn, channel, change_id, &n.inode, edge)?
                 }
             }
         }
@@ -282,7 +290,6 @@ pub(crate) fn detect_folder_conflict_resolutions<T: GraphMutTxnT>(
 fn detect_folder_conflict_resolution<T: GraphMutTxnT>(
     txn: &mut T,
     channel: &mut T::Graph,
-    ws: &mut Workspace,
     change_id: ChangeId,
     inode: &Position<Option<Hash>>,
     e: &NewEdge<Option<Hash>>,
@@ -296,7 +303,7 @@ fn detect_folder_conflict_resolution<T: GraphMutTxnT>(
     } else {
         internal_pos(txn, &inode, change_id)?
     }];
-    let len = ws.pseudo.len();
+    let mut pseudo = Vec::new();
     while let Some(pos) = stack.pop() {
         let dest_vertex = if let Ok(&dest_vertex) = txn.find_block_end(&channel, pos) {
             if !dest_vertex.is_empty() {
@@ -330,22 +337,21 @@ fn detect_folder_conflict_resolution<T: GraphMutTxnT>(
         let f = EdgeFlags::FOLDER | EdgeFlags::PARENT | EdgeFlags::PSEUDO;
         for e in iter_adjacent(txn, channel, dest_vertex, f, f)? {
             let e = e?;
-            ws.pseudo.push((dest_vertex, *e));
+            pseudo.push((dest_vertex, *e));
             let gr = *txn.find_block_end(&channel, e.dest()).unwrap();
             for e in iter_adjacent(txn, channel, gr, f, f)? {
                 let e = e?;
-                ws.pseudo.push((gr, *e));
+                pseudo.push((gr, *e));
                 stack.push(e.dest())
             }
         }
     }
-    // Finally, we were only squatting the `.pseudo` field of the
-    // workspace, since that field is normally used for missing
-    // children, and pseudo-edges from that field are treated in a
-    // special way (by `delete_pseudo_edges` in this module).
-    //
-    // Therefore, we need to delete our folder-pseudo-edges now.
-    for (v, e) in ws.pseudo.drain(len..) {
+    // Finally, delete the folder-pseudo-edges now. These aren't
+    // stored in the `.pseudo` field of the workspace, since that
+    // field is used for missing children, and pseudo-edges from that
+    // field are treated in a special way (by `delete_pseudo_edges` in
+    // this module).
+    for (v, e) in pseudo {
         let p = *txn.find_block_end(channel, e.dest())?;
         del_graph_with_rev(
             txn,
@@ -369,7 +375,7 @@ pub struct Workspace {
     )>,
     unknown: Vec<SerializedEdge>,
     pub(crate) parents: HashSet<SerializedEdge>,
-    pub(crate) pseudo: Vec<(Vertex<ChangeId>, SerializedEdge)>,
+    pub(crate) pseudo: crate::apply::spill::SpillVec<(Vertex<ChangeId>, SerializedEdge)>,
     repaired: HashSet<Vertex<ChangeId>>,
     pub(crate) graphs: Graphs,
     pub(crate) covered_parents: HashSet<(Vertex<ChangeId>, Vertex<ChangeId>)>,
@@ -377,6 +383,8 @@ pub struct Workspace {
     alive_down_cache: HashMap<Vertex<ChangeId>, Option<HashSet<Vertex<ChangeId>>>>,
     alive_up_cache:
         HashMap<Vertex<ChangeId>, (Option<HashSet<Vertex<ChangeId>>>, HashSet<Vertex<ChangeId>>)>,
+    /// Approximate memory budget of the caches above.
+    pub(crate) max_memory: Option<usize>,
 }
 
 #[derive(Debug, Default)]
@@ -425,6 +433,22 @@ impl Workspace {
         self.alive_up_cache.clear();
         self.alive_down_cache.clear();
     }
+    /// Drop the caches if they are likely to exceed
+    /// `self.max_memory`. This only counts the cache entries, not the
+    /// size of the sets they contain.
+    pub(crate) fn trim_caches(&mut self) {
+        if let Some(max) = self.max_memory {
+            let entry = std::mem::size_of::<Vertex<ChangeId>>()
+                + std::mem::size_of::<(Option<HashSet<Vertex<ChangeId>>>, HashSet<Vertex<ChangeId>>)>(
+                );
+            let len = self.alive_up_cache.len() + self.alive_down_cache.len();
+            if len * entry > max {
+                debug!("trimming caches: {:?} entries", len);
+                self.alive_up_cache.clear();
+                self.alive_down_cache.clear();
+            }
+        }
+    }
     pub fn assert_empty(&self) {
         assert!(self.unknown.is_empty());
         assert!(self.unknown_parents.is_empty());
@@ -459,7 +483,7 @@ where
             continue;
         }
         if v.introduced_by().is_root() {
-            ws.pseudo.push((dest_vertex, *v));
+            ws.pseudo.push((dest_vertex, *v))?;
             continue;
         }
         let mut not_del_by_change = true;
@@ -544,7 +568,8 @@ pub(crate) fn delete_pseudo_edges<T: GraphMutTxnT>(
     if ws.pseudo.is_empty() {
         debug!("no pseudo edges")
     }
-    for (dest_vertex, mut e) in ws.pseudo.drain(..) {
+    for x in ws.pseudo.drain()? {
+        let (dest_vertex, mut e) = x?;
         debug!("repair_context_deleted, deleting {:?} {:?}", dest_vertex, e);
         if !is_alive(txn, channel, &dest_vertex)? && !ws.repaired.contains(&dest_vertex) {
             if e.flag().contains(EdgeFlags::PARENT) {
@@ -10,6 +10,12 @@ pub trait Archive {
     fn create_file(&mut self, path: &str, mtime: u64, perm: u16) -> Self::File;
     fn create_dir(&mut self, path: &str, mtime: u64, permissions: u16) -> Result<(), Self::Error>;
     fn close_file(&mut self, f: Self::File) -> Result<(), Self::Error>;
+    /// Whether `path` (relative to the root of the repository) goes
+    /// into the archive. Excluded directories are skipped along with
+    /// everything they contain.
+    fn include(&self, _path: &str, _is_dir: bool) -> bool {
+        true
+    }
 }
 
 #[cfg(feature = "tarball")]
@@ -18,6 +24,9 @@ pub struct Tarball<W: std::io::Write> {
     pub prefix: Option<String>,
     pub buffer: Vec<u8>,
     pub umask: u16,
+    /// Called on the path of each file and directory, see
+    /// `Archive::include`.
+    pub filter: Option<Box<dyn Fn(&str, bool) -> bool + Send + Sync>>,
 }
 
 #[cfg(feature = "tarball")]
@@ -47,8 +56,19 @@ impl<W: std::io::Write> Tarball<W> {
             buffer: Vec::new(),
             prefix,
             umask,
+            filter: None,
         }
     }
+
+    /// Only archive the paths for which `filter(path, is_dir)` is
+    /// true.
+    pub fn with_filter<F: Fn(&str, bool) -> bool + Send + Sync + 'static>(
+        mut self,
+        filter: F,
+    ) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
 }
 
 #[cfg(feature = "tarball")]
@@ -93,6 +113,14 @@ impl<W: std::io::Write> Archive for Tarball<W> {
         self.buffer = file.buf;
         Ok(())
     }
+
+    fn include(&self, path: &str, is_dir: bool) -> bool {
+        if let Some(ref filter) = self.filter {
+            filter(path, is_dir)
+        } else {
+            true
+        }
+    }
 }
 
 #[derive(Error)]
@@ -151,6 +179,26 @@ pub(crate) fn archive<
     channel: &ChannelRef<T>,
     prefix: &mut I,
     arch: &mut A,
+) -> Result<Vec<Conflict>, ArchiveError<P::Error, T, A::Error>> {
+    archive_with_sides(changes, txn, channel, prefix, arch, None)
+}
+
+/// Like [`archive`], and also record in `sides` the changes on the
+/// sides of the conflicts in files, by index of the conflict in the
+/// result.
+pub(crate) fn archive_with_sides<
+    'a,
+    T: ChannelTxnT + TreeTxnT + DepsTxnT<DepsError = <T as GraphTxnT>::GraphError>,
+    P: ChangeStore,
+    I: Iterator<Item = &'a str>,
+    A: Archive,
+>(
+    changes: &P,
+    txn: &ArcTxn<T>,
+    channel: &ChannelRef<T>,
+    prefix: &mut I,
+    arch: &mut A,
+    mut sides: Option<&mut HashMap<usize, Vec<Hash>>>,
 ) -> Result<Vec<Conflict>, ArchiveError<P::Error, T, A::Error>> {
     let mut conflicts = Vec::new();
     let mut files = HashMap::default();
@@ -229,6 +277,10 @@ pub(crate) fn archive<
                 name_entry.insert((name_key, output_item.path.clone()));
 
                 let path = std::mem::replace(&mut output_item.path, String::new());
+                if !arch.include(&path, output_item.meta.is_dir()) {
+                    debug!("excluded: {:?}", path);
+                    continue;
+                }
                 let (_, latest_touch) =
                     crate::fs::get_latest_touch(&*txn_, &channel_, &output_item.pos)?;
                 let latest_touch = {
@@ -266,9 +318,10 @@ pub(crate) fn archive<
                     {
                         let mut f = crate::vertex_buffer::ConflictsWriter::new(
                             &mut f,
-                            &output_item.path,
+                            &path,
                             &mut conflicts,
                         );
+                        f.sides = sides.as_deref_mut();
                         std::mem::drop(channel_);
                         std::mem::drop(txn_);
                         crate::alive::output_graph(
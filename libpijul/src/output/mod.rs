@@ -16,6 +16,19 @@ pub enum OutputError<
 > {
     WorkingCopy(W),
     Pristine(#[from] PristineOutputError<ChangestoreError, T>),
+    /// Some files could not be written to the working copy, or
+    /// exceeded the limits of
+    /// [`output_repository_limited`]. The other files were output.
+    Write(Vec<WriteFailure>),
+    /// Retrieving the graph of a file was cancelled.
+    Retrieve(crate::alive::RetrieveError<T::GraphError>),
+}
+
+/// A file that could not be written to the working copy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteFailure {
+    pub path: String,
+    pub error: String,
 }
 
 impl<C: std::error::Error, T: GraphTxnT + TreeTxnT, W: std::error::Error + Send> std::fmt::Debug
@@ -25,6 +38,8 @@ impl<C: std::error::Error, T: GraphTxnT + TreeTxnT, W: std::error::Error + Send>
         match self {
             OutputError::WorkingCopy(e) => std::fmt::Debug::fmt(e, fmt),
             OutputError::Pristine(e) => std::fmt::Debug::fmt(e, fmt),
+            OutputError::Write(e) => std::fmt::Debug::fmt(e, fmt),
+            OutputError::Retrieve(e) => std::fmt::Debug::fmt(e, fmt),
         }
     }
 }
@@ -36,6 +51,14 @@ impl<C: std::error::Error, T: GraphTxnT + TreeTxnT, W: std::error::Error + Send>
         match self {
             OutputError::WorkingCopy(e) => std::fmt::Display::fmt(e, fmt),
             OutputError::Pristine(e) => std::fmt::Display::fmt(e, fmt),
+            OutputError::Write(e) => {
+                write!(fmt, "Could not write {} file(s):", e.len())?;
+                for f in e.iter() {
+                    write!(fmt, "\n  {}: {}", f.path, f.error)?;
+                }
+                Ok(())
+            }
+            OutputError::Retrieve(e) => std::fmt::Display::fmt(e, fmt),
         }
     }
 }
@@ -327,3 +350,16 @@ pub fn output_file<
     crate::alive::output_graph(changes, txn, channel, out, &mut graph, &mut forward)?;
     Ok(())
 }
+
+/// Output the file at `v0` to memory, along with the vertex (and
+/// hence the change) each range of its bytes comes from.
+pub fn output_file_annotated<T: TreeTxnT + ChannelTxnT, C: crate::changestore::ChangeStore>(
+    changes: &C,
+    txn: &ArcTxn<T>,
+    channel: &ChannelRef<T>,
+    v0: Position<ChangeId>,
+) -> Result<(Vec<u8>, Vec<crate::vertex_buffer::Annotation>), FileError<C::Error, T>> {
+    let mut out = crate::vertex_buffer::Annotator::new();
+    output_file(changes, txn, channel, v0, &mut out)?;
+    Ok(out.into_parts())
+}
@@ -1,8 +1,8 @@
 //! Output the pristine to the working copy, synchronising file
 //! changes (file additions, deletions and renames) in the process.
-use super::{collect_children, OutputError, OutputItem, PristineOutputError};
-use crate::alive::retrieve;
+use super::{collect_children, OutputError, OutputItem, PristineOutputError, WriteFailure};
 use crate::alive::Redundant;
+use crate::alive::{retrieve_limited, RetrieveError, RetrieveLimits};
 use crate::changestore::ChangeStore;
 use crate::fs::{create_new_inode, inode_filename};
 use crate::pristine::*;
@@ -61,6 +61,44 @@ pub fn output_repository_no_pending<
     n_workers: usize,
     salt: u64,
 ) -> Result<BTreeSet<Conflict>, OutputError<P::Error, T, R::Error>>
+where
+    T::Channel: Send + Sync + 'static,
+{
+    output_repository_limited(
+        repo,
+        changes,
+        txn,
+        channel,
+        prefix,
+        output_name_conflicts,
+        if_modified_since,
+        n_workers,
+        salt,
+        &RetrieveLimits::default(),
+    )
+}
+
+/// Same as [`output_repository_no_pending`], but retrieving the graph
+/// of each file within `limits`. Files exceeding them are not written
+/// and are reported in [`OutputError::Write`] once the other files
+/// are output, and cancelling `limits` stops the output with
+/// [`RetrieveError::Cancelled`].
+pub fn output_repository_limited<
+    T: ChannelMutTxnT + TreeMutTxnT<TreeError = T::GraphError> + Send + Sync + 'static,
+    R: WorkingCopy + Send + Clone + Sync + 'static,
+    P: ChangeStore + Send + Clone + 'static,
+>(
+    repo: &R,
+    changes: &P,
+    txn: &ArcTxn<T>,
+    channel: &ChannelRef<T>,
+    prefix: &str,
+    output_name_conflicts: bool,
+    if_modified_since: Option<std::time::SystemTime>,
+    n_workers: usize,
+    salt: u64,
+    limits: &RetrieveLimits,
+) -> Result<BTreeSet<Conflict>, OutputError<P::Error, T, R::Error>>
 where
     T::Channel: Send + Sync + 'static,
 {
@@ -76,6 +114,7 @@ where
         if_modified_since,
         n_workers,
         salt,
+        limits,
     )?;
     del_redundant(txn.clone(), channel.clone(), &f)?;
     Ok(c)
@@ -116,10 +155,19 @@ where
         if_modified_since,
         n_workers,
         salt,
+        &RetrieveLimits::default(),
     )?;
     Ok(c)
 }
 
+/// A file to output: its item, inode, path, temporary path, and
+/// whether it (or one of its ancestors) was just moved.
+type Work = (OutputItem, Inode, String, Option<String>, bool);
+
+/// What each output thread returns: the conflicts it found, the
+/// redundant edges, and the files it couldn't write.
+type LoopOutput = (Vec<Conflict>, Vec<Redundant>, Vec<WriteFailure>);
+
 fn output_loop<
     T: TreeMutTxnT + ChannelTxnT + GraphTxnT,
     R: WorkingCopy + Clone + 'static,
@@ -129,21 +177,23 @@ fn output_loop<
     changes: &P,
     txn: ArcTxn<T>,
     channel: ChannelRef<T>,
-    work: Arc<crossbeam_deque::Injector<(OutputItem, Inode, String, Option<String>)>>,
+    work: Arc<crossbeam_deque::Injector<Work>>,
     stop: Arc<std::sync::atomic::AtomicBool>,
     t: usize,
-) -> Result<(Vec<Conflict>, Vec<Redundant>), OutputError<P::Error, T, R::Error>> {
+    limits: &RetrieveLimits,
+) -> Result<LoopOutput, OutputError<P::Error, T, R::Error>> {
     use crossbeam_deque::*;
     // let backoff = crossbeam_utils::Backoff::new();
     // let w: Worker<(OutputItem, String)> = Worker::new_fifo();
     let mut conflicts = Vec::new();
+    let mut failures = Vec::new();
     let mut forward = Vec::new();
     loop {
         match work.steal() {
-            Steal::Success((item, inode, path, tmp)) => {
+            Steal::Success((item, inode, path, tmp, moved)) => {
                 info!("Outputting {:?} (tmp {:?}), on thread {}", path, tmp, t);
                 let path = tmp.as_deref().unwrap_or(&path);
-                output_item::<_, _, R>(
+                let result = output_item::<_, _, R>(
                     txn.clone(),
                     channel.clone(),
                     changes,
@@ -152,11 +202,34 @@ fn output_loop<
                     &repo,
                     inode,
                     path,
+                    moved,
                     &mut forward,
-                )?;
-                debug!("setting permissions for {:?}", path);
-                repo.set_permissions(path, item.meta.permissions())
-                    .map_err(OutputError::WorkingCopy)?;
+                    limits,
+                )
+                .and_then(|_| {
+                    debug!("setting permissions for {:?}", path);
+                    repo.set_permissions(path, item.meta.permissions())
+                        .map_err(OutputError::WorkingCopy)
+                });
+                // Failing to write a file doesn't stop the output of
+                // the other files, but the whole output fails at the
+                // end.
+                let error = match result {
+                    Ok(()) => None,
+                    Err(OutputError::WorkingCopy(e)) => Some(e.to_string()),
+                    Err(OutputError::Pristine(PristineOutputError::Io(e))) => Some(e.to_string()),
+                    Err(OutputError::Retrieve(e @ RetrieveError::TooLarge { .. })) => {
+                        Some(e.to_string())
+                    }
+                    Err(e) => return Err(e),
+                };
+                if let Some(error) = error {
+                    error!("while writing {:?}: {}", path, error);
+                    failures.push(WriteFailure {
+                        path: path.to_string(),
+                        error,
+                    })
+                }
                 debug!("output {:?}", path);
             }
             Steal::Retry => {}
@@ -167,7 +240,7 @@ fn output_loop<
             }
         }
     }
-    Ok((conflicts, forward))
+    Ok((conflicts, forward, failures))
 }
 
 fn output_repository<
@@ -187,6 +260,7 @@ fn output_repository<
     if_modified_after: Option<std::time::SystemTime>,
     n_workers: usize,
     salt: u64,
+    limits: &RetrieveLimits,
 ) -> Result<(BTreeSet<Conflict>, Vec<Redundant>), OutputError<P::Error, T, R::Error>>
 where
     T::Channel: Send + Sync + 'static,
@@ -201,8 +275,9 @@ where
         let txn = txn.clone();
         let channel = channel.clone();
         let changes = changes.clone();
+        let limits = limits.clone();
         threads.push(std::thread::spawn(move || {
-            output_loop(&repo, &changes, txn, channel, work, stop, t + 1)
+            output_loop(&repo, &changes, txn, channel, work, stop, t + 1, &limits)
         }))
     }
 
@@ -253,26 +328,34 @@ where
         std::mem::swap(&mut files, &mut next_files);
     }
     stop.store(true, std::sync::atomic::Ordering::Relaxed);
-    let o = output_loop(repo, changes, txn, channel, work, stop, 0);
+    let o = output_loop(repo, changes, txn, channel, work, stop, 0, limits);
+    let mut failures = Vec::new();
     for t in threads {
-        let (a, b) = t.join().unwrap()?;
+        let (a, b, c) = t.join().unwrap()?;
         for x in a.into_iter() {
             state.conflicts.insert(x);
         }
         for x in b.into_iter() {
             state.redundant.push(x);
         }
+        failures.extend(c);
     }
-    let (a, b) = o?;
+    let (a, b, c) = o?;
     for x in a.into_iter() {
         state.conflicts.insert(x);
     }
     for x in b.into_iter() {
         state.redundant.push(x);
     }
+    failures.extend(c);
     for (a, b) in state.actual_moves.iter() {
         repo.rename(a, b).map_err(OutputError::WorkingCopy)?
     }
+    repo.sync().map_err(OutputError::WorkingCopy)?;
+    if !failures.is_empty() {
+        failures.sort_by(|a, b| a.path.cmp(&b.path));
+        return Err(OutputError::Write(failures));
+    }
     Ok((state.conflicts, state.redundant))
 }
 
@@ -299,7 +382,7 @@ struct OutputState<'a> {
     output_name_conflicts: bool,
     done_vertices: HashMap<Position<ChangeId>, (Vertex<ChangeId>, String)>,
     conflicts: BTreeSet<Conflict>,
-    work: Arc<crossbeam_deque::Injector<(OutputItem, Inode, String, Option<String>)>>,
+    work: Arc<crossbeam_deque::Injector<Work>>,
     done_inodes: HashSet<Inode>,
     salt: u64,
     if_modified_after: Option<std::time::SystemTime>,
@@ -468,8 +551,11 @@ impl<'a> OutputState<'a> {
                 )?;
             } else {
                 if needs_output(repo, self.if_modified_after, &path) {
+                    // `tmp` is only set if this file or one of its
+                    // ancestors has just been moved.
+                    let moved = tmp.is_some();
                     self.work
-                        .push((output_item.clone(), inode, path.clone(), tmp.clone()));
+                        .push((output_item.clone(), inode, path.clone(), tmp.clone(), moved));
                 } else {
                     debug!("Not outputting {:?}", path)
                 }
@@ -635,22 +721,51 @@ fn output_item<T: ChannelTxnT + TreeTxnT, P: ChangeStore, W: WorkingCopy>(
     repo: &W,
     inode: Inode,
     path: &str,
+    moved: bool,
     forward: &mut Vec<Redundant>,
+    limits: &RetrieveLimits,
 ) -> Result<(), OutputError<P::Error, T, W::Error>> {
     let mut l = {
         let txn = txn.read();
         let channel = channel.read();
-        retrieve(&*txn, txn.graph(&*channel), output_item.pos)?
+        match retrieve_limited(&*txn, txn.graph(&*channel), output_item.pos, limits) {
+            Ok(l) => l,
+            Err(RetrieveError::Txn(e)) => return Err(PristineOutputError::Channel(e).into()),
+            Err(e) => return Err(OutputError::Retrieve(e)),
+        }
     };
+    if moved {
+        // The file has just been renamed to `path`: only rewrite it
+        // if its contents changed too, so that moving a file (or one
+        // of its ancestors) keeps it untouched. Its unrecorded
+        // changes are only kept if they were applied to the channel
+        // (as a pending change) before the changes moving it, in
+        // which case the contents written here merge both sides.
+        let mut f = vertex_buffer::ConflictsWriter::new(Vec::new(), path, conflicts);
+        alive::output_graph(changes, &txn, &channel, &mut f, &mut l, forward)
+            .map_err(PristineOutputError::from)?;
+        let new = f.w;
+        let mut current = Vec::new();
+        if repo.read_file(path, &mut current).is_ok() && current == new {
+            debug!("{:?} moved, contents unchanged", path);
+            return Ok(());
+        }
+        use std::io::Write;
+        let mut w = repo
+            .write_file(&path, inode)
+            .map_err(OutputError::WorkingCopy)?;
+        w.write_all(&new)
+            .map_err(|e| OutputError::Pristine(PristineOutputError::Io(e)))?;
+        return repo.finish_file(&path, w).map_err(OutputError::WorkingCopy);
+    }
     let w = repo
         .write_file(&path, inode)
         .map_err(OutputError::WorkingCopy)?;
     let mut f = vertex_buffer::ConflictsWriter::new(w, &path, conflicts);
     alive::output_graph(changes, &txn, &channel, &mut f, &mut l, forward)
         .map_err(PristineOutputError::from)?;
-    use std::io::Write;
-    f.w.flush().unwrap_or(());
-    Ok(())
+    repo.finish_file(&path, f.w)
+        .map_err(OutputError::WorkingCopy)
 }
 
 fn del_redundant<T: ChannelMutTxnT + GraphMutTxnT>(
@@ -147,3 +147,85 @@ pub fn pop(path: &mut String) {
         path.clear()
     }
 }
+
+/// Check that `path` can be added to a repository. Control
+/// characters, including newlines, are not allowed in paths, since
+/// they would break the text format of changes and the commands
+/// listing paths. Returns the reason why `path` is invalid.
+pub fn validate(path: &str) -> Result<(), &'static str> {
+    if path.chars().any(|c| c.is_control()) {
+        Err("control characters are not allowed in paths")
+    } else {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_validate() {
+    assert!(validate("a/b c/d.txt").is_ok());
+    assert!(validate("a/é/%").is_ok());
+    assert!(validate("a/b\nc").is_err());
+    assert!(validate("a\t").is_err());
+    assert!(validate("\u{7f}").is_err());
+}
+
+/// Percent-escape `%` and the control characters of `path` (as
+/// their UTF-8 bytes), so that it fits on a single line when
+/// displayed.
+pub fn escape(path: &str) -> std::borrow::Cow<str> {
+    if !path.chars().any(|c| c == '%' || c.is_control()) {
+        return std::borrow::Cow::Borrowed(path);
+    }
+    let mut s = String::with_capacity(path.len() + 8);
+    escape_into(&mut s, path);
+    std::borrow::Cow::Owned(s)
+}
+
+fn escape_into(s: &mut String, path: &str) {
+    use std::fmt::Write;
+    for c in path.chars() {
+        if c == '%' || c.is_control() {
+            let mut b = [0; 4];
+            for b in c.encode_utf8(&mut b).bytes() {
+                write!(s, "%{:02X}", b).unwrap()
+            }
+        } else {
+            s.push(c)
+        }
+    }
+}
+
+/// Like [`escape`], but for a path that might not be valid UTF-8,
+/// such as a file name read from the file system: the invalid
+/// bytes are percent-escaped too.
+pub fn escape_bytes(mut path: &[u8]) -> String {
+    use std::fmt::Write;
+    let mut s = String::with_capacity(path.len());
+    loop {
+        match std::str::from_utf8(path) {
+            Ok(p) => {
+                escape_into(&mut s, p);
+                return s;
+            }
+            Err(e) => {
+                let (valid, rest) = path.split_at(e.valid_up_to());
+                escape_into(&mut s, std::str::from_utf8(valid).unwrap());
+                let (invalid, rest) = rest.split_at(e.error_len().unwrap_or(rest.len()));
+                for b in invalid {
+                    write!(s, "%{:02X}", b).unwrap()
+                }
+                path = rest
+            }
+        }
+    }
+}
+
+#[test]
+fn test_escape() {
+    assert_eq!(escape("a/b.txt"), "a/b.txt");
+    assert_eq!(escape("a\nb%"), "a%0Ab%25");
+    assert_eq!(escape("\u{85}é"), "%C2%85é");
+    assert_eq!(escape_bytes(b"a/b"), "a/b");
+    assert_eq!(escape_bytes(b"a\xffb\n"), "a%FFb%0A");
+    assert_eq!(escape_bytes(b"\xe9t\xc3"), "%E9t%C3");
+}
@@ -0,0 +1,285 @@
+//! Previewing the conflicts that applying changes to a channel would
+//! create, without applying them to that channel.
+
+use std::collections::BTreeSet;
+
+use crate::apply::ApplyError;
+use crate::changestore::ChangeStore;
+use crate::output::{archive_with_sides, Archive, ArchiveError, Conflict};
+use crate::pristine::sanakirja::{MutTxn, Pristine, SanakirjaError};
+use crate::pristine::*;
+use crate::{HashMap, HashSet};
+
+/// The in-memory transaction previews are computed in.
+type Fork = MutTxn<()>;
+
+pub enum PreviewError<C: std::error::Error + 'static, T: GraphTxnT> {
+    Txn(TxnErr<T::GraphError>),
+    Fork(ForkError<SanakirjaError>),
+    Apply(ApplyError<C, Fork>),
+    Output(ArchiveError<C, Fork, std::io::Error>),
+}
+
+impl<C: std::error::Error, T: GraphTxnT> std::fmt::Debug for PreviewError<C, T> {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PreviewError::Txn(e) => std::fmt::Debug::fmt(e, fmt),
+            PreviewError::Fork(e) => std::fmt::Debug::fmt(e, fmt),
+            PreviewError::Apply(e) => std::fmt::Debug::fmt(e, fmt),
+            PreviewError::Output(e) => std::fmt::Debug::fmt(e, fmt),
+        }
+    }
+}
+
+impl<C: std::error::Error, T: GraphTxnT> std::fmt::Display for PreviewError<C, T> {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PreviewError::Txn(e) => std::fmt::Display::fmt(e, fmt),
+            PreviewError::Fork(e) => std::fmt::Display::fmt(e, fmt),
+            PreviewError::Apply(e) => std::fmt::Display::fmt(e, fmt),
+            PreviewError::Output(e) => std::fmt::Display::fmt(e, fmt),
+        }
+    }
+}
+
+impl<C: std::error::Error, T: GraphTxnT> std::error::Error for PreviewError<C, T> {}
+
+fn fork_err<C: std::error::Error, T: GraphTxnT>(e: SanakirjaError) -> PreviewError<C, T> {
+    PreviewError::Fork(ForkError::Txn(e))
+}
+
+/// The conflicts that applying `hashes` (and their missing
+/// dependencies) to `channel` would create, excluding the ones
+/// already in `channel`.
+///
+/// `channel` is copied to an in-memory pristine, where the changes
+/// are applied, so neither `txn` nor the working copy are modified.
+/// Only the files touched by the new changes (and their parent
+/// directories) are output, before and after applying the changes.
+///
+/// Conflicts in files are compared by the changes on their sides
+/// rather than by line, so that a conflict already in `channel` and
+/// only moved by the new changes is not reported.
+pub fn preview_conflicts<T, P>(
+    txn: &ArcTxn<T>,
+    channel: &ChannelRef<T>,
+    changes: &P,
+    hashes: &[Hash],
+) -> Result<BTreeSet<Conflict>, PreviewError<P::Error, T>>
+where
+    T: ChannelTxnT + GraphIter + DepsTxnT<DepsError = <T as GraphTxnT>::GraphError>,
+    P: ChangeStore,
+{
+    let pristine = Pristine::new_anon().map_err(fork_err)?;
+    let fork = pristine.arc_txn_begin().map_err(fork_err)?;
+    let before = fork
+        .write()
+        .open_or_create_channel("before")
+        .map_err(fork_err)?;
+    copy_channel(
+        &*txn.read(),
+        &*channel.read(),
+        &mut *fork.write(),
+        &mut *before.write(),
+    )?;
+    let after = fork
+        .write()
+        .fork(&before, "after")
+        .map_err(PreviewError::Fork)?;
+    let n = fork.read().apply_counter(&*after.read());
+    for h in hashes {
+        crate::apply::apply_change_rec(changes, &mut *fork.write(), &mut *after.write(), h, false)
+            .map_err(PreviewError::Apply)?;
+    }
+
+    let paths = touched_paths(changes, &*fork.read(), &*after.read(), n)?;
+    let mut arch = Touched { paths: &paths };
+    let mut sides_before = HashMap::default();
+    let conflicts_before = archive_with_sides(
+        changes,
+        &fork,
+        &before,
+        &mut std::iter::empty(),
+        &mut arch,
+        Some(&mut sides_before),
+    )
+    .map_err(PreviewError::Output)?;
+    let mut sides_after = HashMap::default();
+    let conflicts_after = archive_with_sides(
+        changes,
+        &fork,
+        &after,
+        &mut std::iter::empty(),
+        &mut arch,
+        Some(&mut sides_after),
+    )
+    .map_err(PreviewError::Output)?;
+
+    let mut known: HashMap<ConflictId, usize> = HashMap::default();
+    for (i, c) in conflicts_before.iter().enumerate() {
+        *known.entry(conflict_id(c, &sides_before, i)).or_default() += 1
+    }
+    let mut is_new = Vec::with_capacity(conflicts_after.len());
+    for (i, c) in conflicts_after.iter().enumerate() {
+        is_new.push(match known.get_mut(&conflict_id(c, &sides_after, i)) {
+            Some(n) if *n > 0 => {
+                *n -= 1;
+                false
+            }
+            _ => true,
+        })
+    }
+    Ok(conflicts_after
+        .into_iter()
+        .zip(is_new)
+        .filter(|(_, is_new)| *is_new)
+        .map(|(c, _)| c)
+        .collect())
+}
+
+/// Copy `channel` to `fork_channel`, along with the headers,
+/// dependencies and touched files of its changes, under the same
+/// internal identifiers, so that changes apply to the copy as they
+/// would to `channel`.
+fn copy_channel<T, C>(
+    txn: &T,
+    channel: &T::Channel,
+    fork: &mut Fork,
+    fork_channel: &mut <Fork as ChannelTxnT>::Channel,
+) -> Result<(), PreviewError<C, T>>
+where
+    T: ChannelTxnT + GraphIter + DepsTxnT<DepsError = <T as GraphTxnT>::GraphError>,
+    C: std::error::Error + 'static,
+{
+    for x in changeid_log(txn, channel, L64(0)).map_err(PreviewError::Txn)? {
+        let (_, p) = x.map_err(PreviewError::Txn)?;
+        let id = p.a;
+        let shash = *txn.get_external(&id).map_err(PreviewError::Txn)?.unwrap();
+        fork.put_external(&id, &shash).map_err(|e| fork_err(e.0))?;
+        fork.put_internal(&shash, &id).map_err(|e| fork_err(e.0))?;
+        if let Some(header) = txn.get_cached_header(&id).map_err(PreviewError::Txn)? {
+            fork.put_cached_header(&id, &header)
+                .map_err(|e| fork_err(e.0))?;
+        }
+        for y in txn.iter_dep(&id).map_err(PreviewError::Txn)? {
+            let (a, dep) = y.map_err(PreviewError::Txn)?;
+            if *a < id {
+                continue;
+            } else if *a > id {
+                break;
+            }
+            fork.put_dep(&id, dep).map_err(|e| fork_err(e.0))?;
+            fork.put_revdep(dep, &id).map_err(|e| fork_err(e.0))?;
+        }
+        for y in txn.iter_rev_touched(&id).map_err(PreviewError::Txn)? {
+            let (a, inode) = y.map_err(PreviewError::Txn)?;
+            if *a < id {
+                continue;
+            } else if *a > id {
+                break;
+            }
+            fork.put_touched_files(inode, &id)
+                .map_err(|e| fork_err(e.0))?;
+            fork.put_rev_touched_files(&id, inode)
+                .map_err(|e| fork_err(e.0))?;
+        }
+        let t = fork.apply_counter(fork_channel);
+        fork.put_changes(fork_channel, id, t, &shash.into())
+            .map_err(|e| fork_err(e.0))?;
+    }
+    for x in txn
+        .iter_graph(txn.graph(channel), None)
+        .map_err(PreviewError::Txn)?
+    {
+        let (k, e) = x.map_err(PreviewError::Txn)?;
+        fork.put_graph(<Fork as ChannelMutTxnT>::graph_mut(fork_channel), k, e)
+            .map_err(|e| fork_err(e.0))?;
+    }
+    Ok(())
+}
+
+/// The paths of the files touched by the changes applied to `channel`
+/// from timestamp `from` on.
+fn touched_paths<C: ChangeStore, T: GraphTxnT>(
+    changes: &C,
+    fork: &Fork,
+    channel: &<Fork as ChannelTxnT>::Channel,
+    from: u64,
+) -> Result<HashSet<String>, PreviewError<C::Error, T>> {
+    let mut paths = HashSet::default();
+    let mut resolver = crate::fs::PathResolver::new(false);
+    for x in changeid_log(fork, channel, from.into()).map_err(|e| fork_err(e.0))? {
+        let (_, p) = x.map_err(|e| fork_err(e.0))?;
+        for y in fork.iter_rev_touched(&p.a).map_err(|e| fork_err(e.0))? {
+            let (a, inode) = y.map_err(|e| fork_err(e.0))?;
+            if *a < p.a {
+                continue;
+            } else if *a > p.a {
+                break;
+            }
+            if let Some((path, _)) = resolver
+                .find_path(changes, fork, channel, *inode)
+                .map_err(|e| PreviewError::Output(e.into()))?
+            {
+                paths.insert(path);
+            }
+        }
+    }
+    Ok(paths)
+}
+
+/// An archive discarding everything, and only including the touched
+/// paths and the directories leading to them.
+struct Touched<'a> {
+    paths: &'a HashSet<String>,
+}
+
+impl<'a> Archive for Touched<'a> {
+    type File = std::io::Sink;
+    type Error = std::io::Error;
+    fn create_file(&mut self, _path: &str, _mtime: u64, _perm: u16) -> Self::File {
+        std::io::sink()
+    }
+    fn create_dir(&mut self, _path: &str, _mtime: u64, _perm: u16) -> Result<(), Self::Error> {
+        Ok(())
+    }
+    fn close_file(&mut self, _f: Self::File) -> Result<(), Self::Error> {
+        Ok(())
+    }
+    fn include(&self, path: &str, is_dir: bool) -> bool {
+        self.paths.contains(path)
+            || (is_dir
+                && self.paths.iter().any(|p| {
+                    p.len() > path.len() && p.starts_with(path) && p.as_bytes()[path.len()] == b'/'
+                }))
+    }
+}
+
+/// What identifies a conflict across the two outputs: its kind and
+/// its path and, for conflicts in files, the changes on its sides
+/// instead of its line, which the new changes may shift.
+#[derive(PartialEq, Eq, Hash)]
+enum ConflictId<'a> {
+    Name(&'a str),
+    ZombieFile(&'a str),
+    MultipleNames(Position<ChangeId>),
+    Zombie(&'a str, &'a [Hash]),
+    Cyclic(&'a str, &'a [Hash]),
+    Order(&'a str, &'a [Hash]),
+}
+
+fn conflict_id<'a>(
+    c: &'a Conflict,
+    sides: &'a HashMap<usize, Vec<Hash>>,
+    i: usize,
+) -> ConflictId<'a> {
+    let s = sides.get(&i).map(|s| &s[..]).unwrap_or(&[]);
+    match c {
+        Conflict::Name { path } => ConflictId::Name(path),
+        Conflict::ZombieFile { path } => ConflictId::ZombieFile(path),
+        Conflict::MultipleNames { pos, .. } => ConflictId::MultipleNames(*pos),
+        Conflict::Zombie { path, .. } => ConflictId::Zombie(path, s),
+        Conflict::Cyclic { path, .. } => ConflictId::Cyclic(path, s),
+        Conflict::Order { path, .. } => ConflictId::Order(path, s),
+    }
+}
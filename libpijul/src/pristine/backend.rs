@@ -0,0 +1,91 @@
+//! The primitives of a storage engine for the tables of the pristine.
+//!
+//! Most methods of [`GraphTxnT`](super::GraphTxnT),
+//! [`TreeTxnT`](super::TreeTxnT), [`DepsTxnT`](super::DepsTxnT) and
+//! their mutable counterparts access a single table. A backend
+//! implementing [`Table`] (and [`TableMut`]) on its transactions, for
+//! each type of table it uses, gets these methods from the
+//! `backend_*` macros of `pijul-macros`, the same way the
+//! `sanakirja_*` macros implement them for
+//! [`sanakirja`](super::sanakirja):
+//!
+//! ```ignore
+//! impl DepsTxnT for MyTxn {
+//!     type DepsError = MyError;
+//!     type Dep = MyDb<ChangeId, ChangeId>;
+//!     type DepCursor = MyCursor<ChangeId, ChangeId>;
+//!     backend_table_get!(dep, ChangeId, ChangeId, DepsError);
+//!     backend_cursor_ref!(dep, ChangeId, ChangeId, DepsError);
+//!     // …
+//! }
+//! ```
+//!
+//! The code generated by these macros refers to `Table`, `TableMut`,
+//! `Cursor`, `RevCursor` and `TxnErr`, which must be in scope, and
+//! converts the errors of the backend into the error type of the
+//! trait with `Into`. [`memory`](super::memory) is a reference
+//! backend built this way.
+
+/// Read access to the tables of type `Db` of a transaction. Tables
+/// are sorted by key, then by value, and a key can have several
+/// values.
+pub trait Table<Db> {
+    type Key: ?Sized;
+    type Value: ?Sized;
+    type Cursor;
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// The first entry of `db` not smaller than `(k, v)`, or than the
+    /// first entry of key `k` if `v` is `None`. That entry may have
+    /// a different key.
+    fn get<'txn>(
+        &'txn self,
+        db: &Db,
+        k: &Self::Key,
+        v: Option<&Self::Value>,
+    ) -> Result<Option<(&'txn Self::Key, &'txn Self::Value)>, Self::Error>;
+
+    /// A cursor on `db`, at the first entry not smaller than `pos`
+    /// (as in [`Table::get`]), or at the first entry of `db`.
+    fn cursor(
+        &self,
+        db: &Db,
+        pos: Option<(&Self::Key, Option<&Self::Value>)>,
+    ) -> Result<Self::Cursor, Self::Error>;
+
+    /// A cursor on `db` for iterating backwards, at the last entry not
+    /// greater than `pos` (where all the entries of key `k` are not
+    /// greater than `(k, None)`), or at the last entry of `db`.
+    fn rev_cursor(
+        &self,
+        db: &Db,
+        pos: Option<(&Self::Key, Option<&Self::Value>)>,
+    ) -> Result<Self::Cursor, Self::Error>;
+
+    /// The entry at `cursor`, moving it forward.
+    fn cursor_next<'txn>(
+        &'txn self,
+        cursor: &mut Self::Cursor,
+    ) -> Result<Option<(&'txn Self::Key, &'txn Self::Value)>, Self::Error>;
+
+    /// The entry at `cursor`, moving it backward.
+    fn cursor_prev<'txn>(
+        &'txn self,
+        cursor: &mut Self::Cursor,
+    ) -> Result<Option<(&'txn Self::Key, &'txn Self::Value)>, Self::Error>;
+}
+
+/// Write access to the tables of type `Db` of a transaction.
+pub trait TableMut<Db>: Table<Db> {
+    /// Insert `(k, v)`, returning `false` if it was already there.
+    fn put(&mut self, db: &mut Db, k: &Self::Key, v: &Self::Value) -> Result<bool, Self::Error>;
+
+    /// Delete `(k, v)`, or the first entry of key `k` if `v` is
+    /// `None`, returning `false` if there was no such entry.
+    fn del(
+        &mut self,
+        db: &mut Db,
+        k: &Self::Key,
+        v: Option<&Self::Value>,
+    ) -> Result<bool, Self::Error>;
+}
@@ -0,0 +1,60 @@
+//! A memo of the transitive closures of the `dep` and `revdep`
+//! tables, computed by [`super::DepsTxnT::dep_closure`] and
+//! [`super::DepsTxnT::revdep_closure`], so that commands walking the
+//! dependencies of many changes don't traverse the same parts of the
+//! dependency graph again and again.
+//!
+//! The cache lives in the transaction, and is cleared whenever the
+//! `dep` or `revdep` table is modified.
+
+use super::ChangeId;
+use crate::{HashMap, HashSet};
+
+/// Maximum number of changes in all the cached closures. The cache
+/// is cleared when it grows beyond that.
+const MAX_CHANGES: usize = 1 << 20;
+
+#[derive(Debug, Default)]
+pub struct DepCache {
+    deps: HashMap<ChangeId, HashSet<ChangeId>>,
+    revdeps: HashMap<ChangeId, HashSet<ChangeId>>,
+    len: usize,
+}
+
+impl DepCache {
+    /// The cached closure of `change`, following the `revdep` table
+    /// if `reverse` is true, and the `dep` table else.
+    pub fn get(&self, reverse: bool, change: &ChangeId) -> Option<&HashSet<ChangeId>> {
+        if reverse {
+            self.revdeps.get(change)
+        } else {
+            self.deps.get(change)
+        }
+    }
+
+    pub fn insert(&mut self, reverse: bool, change: ChangeId, closure: HashSet<ChangeId>) {
+        if self.len >= MAX_CHANGES {
+            self.clear()
+        }
+        self.len += closure.len() + 1;
+        if reverse {
+            self.revdeps.insert(change, closure);
+        } else {
+            self.deps.insert(change, closure);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.deps.clear();
+        self.revdeps.clear();
+        self.len = 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
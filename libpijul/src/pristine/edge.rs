@@ -89,7 +89,7 @@ pub struct Edge {
 }
 
 /// The target half of an edge in the repository graph.
-#[derive(Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Hash)]
+#[derive(Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Hash, Serialize, Deserialize)]
 pub struct SerializedEdge([super::L64; 3]);
 
 impl std::fmt::Debug for SerializedEdge {
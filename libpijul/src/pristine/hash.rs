@@ -153,6 +153,33 @@ fn from_to() {
     assert_eq!(Hash::from_base32(&b.as_bytes()), None);
 }
 
+/// For each string of `sorted`, which must be sorted, the length of
+/// its shortest prefix that isn't a prefix of any other string of
+/// `sorted` (or its full length if there is no such prefix).
+pub fn unambiguous_prefix_lens<S: AsRef<str>>(sorted: &[S]) -> Vec<usize> {
+    let common = |a: &str, b: &str| a.bytes().zip(b.bytes()).take_while(|(x, y)| x == y).count();
+    let mut lens = Vec::with_capacity(sorted.len());
+    for (i, s) in sorted.iter().enumerate() {
+        let s = s.as_ref();
+        let mut l = 0;
+        if i > 0 {
+            l = l.max(common(sorted[i - 1].as_ref(), s))
+        }
+        if let Some(next) = sorted.get(i + 1) {
+            l = l.max(common(s, next.as_ref()))
+        }
+        lens.push((l + 1).min(s.len()))
+    }
+    lens
+}
+
+#[test]
+fn prefix_lens() {
+    let sorted = ["AAB", "AAC", "ABC", "B"];
+    assert_eq!(unambiguous_prefix_lens(&sorted), vec![3, 3, 2, 1]);
+    assert_eq!(unambiguous_prefix_lens(&["AB", "ABC"]), vec![2, 3]);
+}
+
 #[derive(Clone, Copy)]
 pub struct SerializedHash {
     pub(crate) t: u8,
@@ -0,0 +1,930 @@
+//! A reference [`backend`](super::backend) keeping its tables in
+//! memory. Its [`Txn`] implements [`GraphTxnT`], [`TreeTxnT`],
+//! [`TreeMutTxnT`], [`DepsTxnT`] and [`DepsMutTxnT`] with the
+//! `backend_*` macros.
+
+use super::backend::{Table, TableMut};
+use super::*;
+use crate::HashMap;
+use std::any::Any;
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+
+/// The keys and values of the tables of this backend, which are
+/// stored as their owned version, so that unsized types such as
+/// [`PathId`] and [`SmallStr`] can be used.
+pub trait Stored: Ord + 'static {
+    type Owned: Ord + Borrow<Self> + Send + Sync + 'static;
+    fn stored(&self) -> Self::Owned;
+}
+
+impl<T: ToOwned + Ord + ?Sized + 'static> Stored for T
+where
+    <T as ToOwned>::Owned: Ord + Send + Sync + 'static,
+{
+    type Owned = <T as ToOwned>::Owned;
+    fn stored(&self) -> Self::Owned {
+        self.to_owned()
+    }
+}
+
+type Entries<K, V> = BTreeMap<<K as Stored>::Owned, BTreeSet<<V as Stored>::Owned>>;
+
+/// The contents of the tables of a transaction. As in Sanakirja,
+/// tables ([`Db`]) are only handles to data owned by the transaction,
+/// so that borrowing the transaction borrows the entries.
+#[derive(Default)]
+pub struct Store {
+    tables: Vec<Box<dyn Any + Send + Sync>>,
+}
+
+/// A table of a [`Store`].
+pub struct Db<K: ?Sized, V: ?Sized> {
+    id: usize,
+    marker: std::marker::PhantomData<fn(&K, &V)>,
+}
+
+impl<K: ?Sized, V: ?Sized> Clone for Db<K, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K: ?Sized, V: ?Sized> Copy for Db<K, V> {}
+
+impl Store {
+    /// Create an empty table.
+    pub fn create<K: Stored + ?Sized, V: Stored + ?Sized>(&mut self) -> Db<K, V> {
+        self.tables.push(Box::new(Entries::<K, V>::new()));
+        Db {
+            id: self.tables.len() - 1,
+            marker: std::marker::PhantomData,
+        }
+    }
+
+    fn entries<K: Stored + ?Sized, V: Stored + ?Sized>(&self, db: &Db<K, V>) -> &Entries<K, V> {
+        self.tables[db.id]
+            .downcast_ref()
+            .expect("Table of another store")
+    }
+
+    fn entries_mut<K: Stored + ?Sized, V: Stored + ?Sized>(
+        &mut self,
+        db: &Db<K, V>,
+    ) -> &mut Entries<K, V> {
+        self.tables[db.id]
+            .downcast_mut()
+            .expect("Table of another store")
+    }
+}
+
+/// The transactions of this backend, which get [`Table`] and
+/// [`TableMut`] for all their tables.
+pub trait MemoryTxn {
+    fn store(&self) -> &Store;
+    fn store_mut(&mut self) -> &mut Store;
+}
+
+/// A cursor of this backend. It only remembers the entry it is at,
+/// so that the table can be modified between two moves.
+pub struct MemoryCursor<K: Stored + ?Sized, V: Stored + ?Sized> {
+    db: Db<K, V>,
+    at: Option<(K::Owned, V::Owned)>,
+}
+
+impl<T, K, V> Table<Db<K, V>> for T
+where
+    T: MemoryTxn,
+    K: Stored + ?Sized,
+    V: Stored + ?Sized,
+{
+    type Key = K;
+    type Value = V;
+    type Cursor = MemoryCursor<K, V>;
+    type Error = std::convert::Infallible;
+
+    fn get<'txn>(
+        &'txn self,
+        db: &Db<K, V>,
+        k: &K,
+        v: Option<&V>,
+    ) -> Result<Option<(&'txn K, &'txn V)>, Self::Error> {
+        Ok(first_from(self.store().entries(db), k, v))
+    }
+
+    fn cursor(
+        &self,
+        db: &Db<K, V>,
+        pos: Option<(&K, Option<&V>)>,
+    ) -> Result<Self::Cursor, Self::Error> {
+        let e = self.store().entries(db);
+        let at: Option<(&K, &V)> = if let Some((k, v)) = pos {
+            first_from(e, k, v)
+        } else {
+            e.iter()
+                .next()
+                .and_then(|(k, vs)| Some((k.borrow(), vs.iter().next()?.borrow())))
+        };
+        Ok(MemoryCursor {
+            db: *db,
+            at: at.map(|(k, v)| (k.stored(), v.stored())),
+        })
+    }
+
+    fn rev_cursor(
+        &self,
+        db: &Db<K, V>,
+        pos: Option<(&K, Option<&V>)>,
+    ) -> Result<Self::Cursor, Self::Error> {
+        let e = self.store().entries(db);
+        let at: Option<(&K, &V)> = if let Some((k, v)) = pos {
+            last_until(e, k, v)
+        } else {
+            e.iter()
+                .next_back()
+                .and_then(|(k, vs)| Some((k.borrow(), vs.iter().next_back()?.borrow())))
+        };
+        Ok(MemoryCursor {
+            db: *db,
+            at: at.map(|(k, v)| (k.stored(), v.stored())),
+        })
+    }
+
+    fn cursor_next<'txn>(
+        &'txn self,
+        cursor: &mut Self::Cursor,
+    ) -> Result<Option<(&'txn K, &'txn V)>, Self::Error> {
+        let e = self.store().entries(&cursor.db);
+        // If the entry of the cursor was deleted, continue from the
+        // next one.
+        let current = if let Some((ref k, ref v)) = cursor.at {
+            first_from::<K, V>(e, k.borrow(), Some(v.borrow()))
+        } else {
+            None
+        };
+        cursor.at = current
+            .and_then(|(k, v)| after(e, k, v))
+            .map(|(k, v)| (k.stored(), v.stored()));
+        Ok(current)
+    }
+
+    fn cursor_prev<'txn>(
+        &'txn self,
+        cursor: &mut Self::Cursor,
+    ) -> Result<Option<(&'txn K, &'txn V)>, Self::Error> {
+        let e = self.store().entries(&cursor.db);
+        let current = if let Some((ref k, ref v)) = cursor.at {
+            last_until::<K, V>(e, k.borrow(), Some(v.borrow()))
+        } else {
+            None
+        };
+        cursor.at = current
+            .and_then(|(k, v)| before(e, k, v))
+            .map(|(k, v)| (k.stored(), v.stored()));
+        Ok(current)
+    }
+}
+
+impl<T, K, V> TableMut<Db<K, V>> for T
+where
+    T: MemoryTxn,
+    K: Stored + ?Sized,
+    V: Stored + ?Sized,
+{
+    fn put(&mut self, db: &mut Db<K, V>, k: &K, v: &V) -> Result<bool, Self::Error> {
+        let e = self.store_mut().entries_mut(db);
+        Ok(e.entry(k.stored()).or_default().insert(v.stored()))
+    }
+
+    fn del(&mut self, db: &mut Db<K, V>, k: &K, v: Option<&V>) -> Result<bool, Self::Error> {
+        let e = self.store_mut().entries_mut(db);
+        let vs = if let Some(vs) = e.get_mut(k) {
+            vs
+        } else {
+            return Ok(false);
+        };
+        let deleted = if let Some(v) = v {
+            vs.remove(v)
+        } else if let Some(first) = vs.iter().next().map(|v| Borrow::<V>::borrow(v).stored()) {
+            vs.remove(&first)
+        } else {
+            false
+        };
+        // Keys have at least one value, which the lookups rely on.
+        if vs.is_empty() {
+            e.remove(k);
+        }
+        Ok(deleted)
+    }
+}
+
+/// The first entry not smaller than `(k, v)`.
+fn first_from<'a, K: Stored + ?Sized, V: Stored + ?Sized>(
+    e: &'a Entries<K, V>,
+    k: &K,
+    v: Option<&V>,
+) -> Option<(&'a K, &'a V)> {
+    let mut keys = e.range::<K, _>((Bound::Included(k), Bound::Unbounded));
+    let (k_, vs) = keys.next()?;
+    if let (true, Some(v)) = (Borrow::<K>::borrow(k_) == k, v) {
+        if let Some(v_) = vs
+            .range::<V, _>((Bound::Included(v), Bound::Unbounded))
+            .next()
+        {
+            return Some((k_.borrow(), v_.borrow()));
+        }
+        let (k_, vs) = keys.next()?;
+        return Some((k_.borrow(), vs.iter().next()?.borrow()));
+    }
+    Some((k_.borrow(), vs.iter().next()?.borrow()))
+}
+
+/// The last entry not greater than `(k, v)`.
+fn last_until<'a, K: Stored + ?Sized, V: Stored + ?Sized>(
+    e: &'a Entries<K, V>,
+    k: &K,
+    v: Option<&V>,
+) -> Option<(&'a K, &'a V)> {
+    let mut keys = e
+        .range::<K, _>((Bound::Unbounded, Bound::Included(k)))
+        .rev();
+    let (k_, vs) = keys.next()?;
+    if let (true, Some(v)) = (Borrow::<K>::borrow(k_) == k, v) {
+        if let Some(v_) = vs
+            .range::<V, _>((Bound::Unbounded, Bound::Included(v)))
+            .next_back()
+        {
+            return Some((k_.borrow(), v_.borrow()));
+        }
+        let (k_, vs) = keys.next()?;
+        return Some((k_.borrow(), vs.iter().next_back()?.borrow()));
+    }
+    Some((k_.borrow(), vs.iter().next_back()?.borrow()))
+}
+
+/// The entry following `(k, v)`.
+fn after<'a, K: Stored + ?Sized, V: Stored + ?Sized>(
+    e: &'a Entries<K, V>,
+    k: &K,
+    v: &V,
+) -> Option<(&'a K, &'a V)> {
+    if let Some((k_, vs)) = e.get_key_value(k) {
+        if let Some(v_) = vs
+            .range::<V, _>((Bound::Excluded(v), Bound::Unbounded))
+            .next()
+        {
+            return Some((k_.borrow(), v_.borrow()));
+        }
+    }
+    let (k_, vs) = e
+        .range::<K, _>((Bound::Excluded(k), Bound::Unbounded))
+        .next()?;
+    Some((k_.borrow(), vs.iter().next()?.borrow()))
+}
+
+/// The entry preceding `(k, v)`.
+fn before<'a, K: Stored + ?Sized, V: Stored + ?Sized>(
+    e: &'a Entries<K, V>,
+    k: &K,
+    v: &V,
+) -> Option<(&'a K, &'a V)> {
+    if let Some((k_, vs)) = e.get_key_value(k) {
+        if let Some(v_) = vs
+            .range::<V, _>((Bound::Unbounded, Bound::Excluded(v)))
+            .next_back()
+        {
+            return Some((k_.borrow(), v_.borrow()));
+        }
+    }
+    let (k_, vs) = e
+        .range::<K, _>((Bound::Unbounded, Bound::Excluded(k)))
+        .next_back()?;
+    Some((k_.borrow(), vs.iter().next_back()?.borrow()))
+}
+
+/// A transaction with the tables of [`GraphTxnT`], [`TreeTxnT`] and
+/// [`DepsTxnT`]. Graphs are created with [`Txn::create_graph`].
+pub struct Txn {
+    store: Store,
+    internal: Db<SerializedHash, ChangeId>,
+    external: Db<ChangeId, SerializedHash>,
+    tree: Db<PathId, Inode>,
+    revtree: Db<Inode, PathId>,
+    inodes: Db<Inode, Position<ChangeId>>,
+    revinodes: Db<Position<ChangeId>, Inode>,
+    partials: Db<SmallStr, Position<ChangeId>>,
+    dep: Db<ChangeId, ChangeId>,
+    revdep: Db<ChangeId, ChangeId>,
+    touched_files: Db<Position<ChangeId>, ChangeId>,
+    rev_touched_files: Db<ChangeId, Position<ChangeId>>,
+    paths: Db<String, (ChangeId, Position<ChangeId>)>,
+    headers: HashMap<ChangeId, CachedHeader>,
+}
+
+impl Txn {
+    pub fn new() -> Self {
+        let mut store = Store::default();
+        Txn {
+            internal: store.create(),
+            external: store.create(),
+            tree: store.create(),
+            revtree: store.create(),
+            inodes: store.create(),
+            revinodes: store.create(),
+            partials: store.create(),
+            dep: store.create(),
+            revdep: store.create(),
+            touched_files: store.create(),
+            rev_touched_files: store.create(),
+            paths: store.create(),
+            headers: HashMap::default(),
+            store,
+        }
+    }
+
+    /// Create an empty graph.
+    pub fn create_graph(&mut self) -> Db<Vertex<ChangeId>, SerializedEdge> {
+        self.store.create()
+    }
+
+    /// Register the internal identifier `id` of change `hash`.
+    pub fn register_change(
+        &mut self,
+        id: &ChangeId,
+        hash: &SerializedHash,
+    ) -> Result<(), TxnErr<std::convert::Infallible>> {
+        let (mut internal, mut external) = (self.internal, self.external);
+        self.put(&mut internal, hash, id).map_err(TxnErr)?;
+        self.put(&mut external, id, hash).map_err(TxnErr)?;
+        Ok(())
+    }
+}
+
+impl Default for Txn {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryTxn for Txn {
+    fn store(&self) -> &Store {
+        &self.store
+    }
+    fn store_mut(&mut self) -> &mut Store {
+        &mut self.store
+    }
+}
+
+/// The cursor of [`GraphTxnT::init_adj`].
+pub struct Adj {
+    cursor: MemoryCursor<Vertex<ChangeId>, SerializedEdge>,
+    key: Vertex<ChangeId>,
+    min_flag: EdgeFlags,
+    max_flag: EdgeFlags,
+}
+
+impl GraphTxnT for Txn {
+    type GraphError = std::convert::Infallible;
+    type Graph = Db<Vertex<ChangeId>, SerializedEdge>;
+    backend_get!(graph, Vertex<ChangeId>, SerializedEdge, GraphError);
+
+    fn get_external(
+        &self,
+        p: &ChangeId,
+    ) -> Result<Option<&SerializedHash>, TxnErr<Self::GraphError>> {
+        if p.is_root() {
+            return Ok(Some(&HASH_NONE));
+        }
+        match self.get(&self.external, p, None).map_err(TxnErr)? {
+            Some((k, v)) if k == p => Ok(Some(v)),
+            _ => Ok(None),
+        }
+    }
+
+    fn get_internal(
+        &self,
+        p: &SerializedHash,
+    ) -> Result<Option<&ChangeId>, TxnErr<Self::GraphError>> {
+        if p.t == HashAlgorithm::None as u8 {
+            return Ok(Some(&ChangeId::ROOT));
+        }
+        match self.get(&self.internal, p, None).map_err(TxnErr)? {
+            Some((k, v)) if k == p => Ok(Some(v)),
+            _ => Ok(None),
+        }
+    }
+
+    type Adj = Adj;
+
+    fn init_adj(
+        &self,
+        g: &Self::Graph,
+        key: Vertex<ChangeId>,
+        dest: Position<ChangeId>,
+        min_flag: EdgeFlags,
+        max_flag: EdgeFlags,
+    ) -> Result<Self::Adj, TxnErr<Self::GraphError>> {
+        let edge = SerializedEdge::new(min_flag, dest.change, dest.pos, ChangeId::ROOT);
+        let cursor = self.cursor(g, Some((&key, Some(&edge)))).map_err(TxnErr)?;
+        Ok(Adj {
+            cursor,
+            key,
+            min_flag,
+            max_flag,
+        })
+    }
+
+    fn next_adj<'a>(
+        &'a self,
+        _: &Self::Graph,
+        a: &mut Self::Adj,
+    ) -> Option<Result<&'a SerializedEdge, TxnErr<Self::GraphError>>> {
+        loop {
+            match <Self as Table<Self::Graph>>::cursor_next(self, &mut a.cursor) {
+                Ok(Some((v, e))) => {
+                    if *v == a.key {
+                        if e.flag() >= a.min_flag {
+                            if e.flag() <= a.max_flag {
+                                return Some(Ok(e));
+                            } else {
+                                return None;
+                            }
+                        }
+                    } else if *v > a.key {
+                        return None;
+                    }
+                }
+                Ok(None) => return None,
+                Err(e) => return Some(Err(TxnErr(e))),
+            }
+        }
+    }
+
+    fn find_block(
+        &self,
+        graph: &Self::Graph,
+        p: Position<ChangeId>,
+    ) -> Result<&Vertex<ChangeId>, BlockError<Self::GraphError>> {
+        if p.change.is_root() {
+            return Ok(&Vertex::ROOT);
+        }
+        let e = self.store.entries(graph);
+        let key = Vertex {
+            change: p.change,
+            start: p.pos,
+            end: p.pos,
+        };
+        // A vertex starting at `p`, possibly empty.
+        if let Some((k, _)) = e
+            .range::<Vertex<ChangeId>, _>((Bound::Included(&key), Bound::Unbounded))
+            .next()
+        {
+            if k.change == p.change && k.start == p.pos {
+                return Ok(k);
+            }
+        }
+        // Else, the last vertex starting before `p`.
+        if let Some((k, _)) = e
+            .range::<Vertex<ChangeId>, _>((Bound::Unbounded, Bound::Excluded(&key)))
+            .next_back()
+        {
+            if k.change == p.change && k.start <= p.pos && p.pos < k.end {
+                return Ok(k);
+            }
+        }
+        Err(BlockError::Block { block: p })
+    }
+
+    fn find_block_end(
+        &self,
+        graph: &Self::Graph,
+        p: Position<ChangeId>,
+    ) -> Result<&Vertex<ChangeId>, BlockError<Self::GraphError>> {
+        if p.change.is_root() {
+            return Ok(&Vertex::ROOT);
+        }
+        let e = self.store.entries(graph);
+        let key = Vertex {
+            change: p.change,
+            start: p.pos,
+            end: p.pos,
+        };
+        // An empty vertex at `p`.
+        if let Some((k, _)) = e.get_key_value(&key) {
+            return Ok(k);
+        }
+        // Else, the last vertex starting before `p`.
+        if let Some((k, _)) = e
+            .range::<Vertex<ChangeId>, _>((Bound::Unbounded, Bound::Excluded(&key)))
+            .next_back()
+        {
+            if k.change == p.change && k.start < p.pos && p.pos <= k.end {
+                return Ok(k);
+            }
+        }
+        Err(BlockError::Block { block: p })
+    }
+}
+
+impl TreeTxnT for Txn {
+    type TreeError = std::convert::Infallible;
+    type Inodes = Db<Inode, Position<ChangeId>>;
+    type Revinodes = Db<Position<ChangeId>, Inode>;
+    backend_table_get!(inodes, Inode, Position<ChangeId>, TreeError, TreeErr);
+    backend_table_get!(revinodes, Position<ChangeId>, Inode, TreeError, TreeErr);
+    type InodesCursor = MemoryCursor<Inode, Position<ChangeId>>;
+    backend_cursor!(inodes, Inode, Position<ChangeId>, TreeError, TreeErr);
+    type RevinodesCursor = MemoryCursor<Position<ChangeId>, Inode>;
+    backend_cursor!(revinodes, Position<ChangeId>, Inode, TreeError, TreeErr);
+
+    type Tree = Db<PathId, Inode>;
+    backend_table_get!(tree, PathId, Inode, TreeError, TreeErr);
+    type TreeCursor = MemoryCursor<PathId, Inode>;
+    backend_iter!(tree, PathId, Inode, TreeError, TreeErr);
+
+    type Revtree = Db<Inode, PathId>;
+    backend_table_get!(revtree, Inode, PathId, TreeError, TreeErr);
+    type RevtreeCursor = MemoryCursor<Inode, PathId>;
+    backend_iter!(revtree, Inode, PathId, TreeError, TreeErr);
+
+    type Partials = Db<SmallStr, Position<ChangeId>>;
+    type PartialsCursor = MemoryCursor<SmallStr, Position<ChangeId>>;
+    backend_cursor!(partials, SmallStr, Position<ChangeId>, TreeError, TreeErr);
+
+    fn iter_inodes(
+        &self,
+    ) -> Result<
+        Cursor<Self, &Self, Self::InodesCursor, Inode, Position<ChangeId>>,
+        TreeErr<Self::TreeError>,
+    > {
+        self.cursor_inodes(&self.inodes, None)
+    }
+
+    fn iter_revinodes(
+        &self,
+    ) -> Result<
+        Cursor<Self, &Self, Self::RevinodesCursor, Position<ChangeId>, Inode>,
+        TreeErr<Self::TreeError>,
+    > {
+        self.cursor_revinodes(&self.revinodes, None)
+    }
+
+    fn iter_partials<'txn>(
+        &'txn self,
+        k: &str,
+    ) -> Result<
+        Cursor<Self, &'txn Self, Self::PartialsCursor, SmallStr, Position<ChangeId>>,
+        TreeErr<Self::TreeError>,
+    > {
+        let k0 = SmallString::from_str(k);
+        self.cursor_partials(&self.partials, Some((&k0, None)))
+    }
+}
+
+impl TreeMutTxnT for Txn {
+    backend_put_del!(inodes, Inode, Position<ChangeId>, TreeError, TreeErr);
+    backend_put_del!(revinodes, Position<ChangeId>, Inode, TreeError, TreeErr);
+    backend_put_del!(tree, PathId, Inode, TreeError, TreeErr);
+    backend_put_del!(revtree, Inode, PathId, TreeError, TreeErr);
+
+    fn put_partials(
+        &mut self,
+        k: &str,
+        e: Position<ChangeId>,
+    ) -> Result<bool, TreeErr<Self::TreeError>> {
+        let k = SmallString::from_str(k);
+        let mut partials = self.partials;
+        self.put(&mut partials, &*k, &e).map_err(TreeErr)
+    }
+
+    fn del_partials(
+        &mut self,
+        k: &str,
+        e: Option<Position<ChangeId>>,
+    ) -> Result<bool, TreeErr<Self::TreeError>> {
+        let k = SmallString::from_str(k);
+        let mut partials = self.partials;
+        self.del(&mut partials, &*k, e.as_ref()).map_err(TreeErr)
+    }
+}
+
+impl DepsTxnT for Txn {
+    type DepsError = std::convert::Infallible;
+    type Dep = Db<ChangeId, ChangeId>;
+    type Revdep = Db<ChangeId, ChangeId>;
+
+    backend_table_get!(dep, ChangeId, ChangeId, DepsError);
+    backend_table_get!(revdep, ChangeId, ChangeId, DepsError);
+    type DepCursor = MemoryCursor<ChangeId, ChangeId>;
+    backend_cursor_ref!(dep, ChangeId, ChangeId, DepsError);
+
+    fn iter_revdep(
+        &self,
+        p: &ChangeId,
+    ) -> Result<Cursor<Self, &Self, Self::DepCursor, ChangeId, ChangeId>, TxnErr<Self::DepsError>>
+    {
+        self.cursor_dep(&self.revdep, Some((p, None)))
+    }
+
+    fn iter_dep(
+        &self,
+        p: &ChangeId,
+    ) -> Result<Cursor<Self, &Self, Self::DepCursor, ChangeId, ChangeId>, TxnErr<Self::DepsError>>
+    {
+        self.cursor_dep(&self.dep, Some((p, None)))
+    }
+
+    fn iter_dep_ref<RT: std::ops::Deref<Target = Self> + Clone>(
+        txn: RT,
+        p: &ChangeId,
+    ) -> Result<Cursor<Self, RT, Self::DepCursor, ChangeId, ChangeId>, TxnErr<Self::DepsError>>
+    {
+        Self::cursor_dep_ref(txn.clone(), &txn.dep, Some((p, None)))
+    }
+
+    fn iter_touched(
+        &self,
+        p: &Position<ChangeId>,
+    ) -> Result<
+        Cursor<Self, &Self, Self::Touched_filesCursor, Position<ChangeId>, ChangeId>,
+        TxnErr<Self::DepsError>,
+    > {
+        self.cursor_touched_files(&self.touched_files, Some((p, None)))
+    }
+
+    fn iter_rev_touched(
+        &self,
+        p: &ChangeId,
+    ) -> Result<
+        Cursor<Self, &Self, Self::Rev_touched_filesCursor, ChangeId, Position<ChangeId>>,
+        TxnErr<Self::DepsError>,
+    > {
+        self.cursor_rev_touched_files(&self.rev_touched_files, Some((p, None)))
+    }
+
+    type Touched_files = Db<Position<ChangeId>, ChangeId>;
+    type Rev_touched_files = Db<ChangeId, Position<ChangeId>>;
+    backend_table_get!(touched_files, Position<ChangeId>, ChangeId, DepsError);
+    backend_table_get!(rev_touched_files, ChangeId, Position<ChangeId>, DepsError);
+    type Touched_filesCursor = MemoryCursor<Position<ChangeId>, ChangeId>;
+    backend_iter!(touched_files, Position<ChangeId>, ChangeId, DepsError);
+    type Rev_touched_filesCursor = MemoryCursor<ChangeId, Position<ChangeId>>;
+    backend_iter!(rev_touched_files, ChangeId, Position<ChangeId>, DepsError);
+
+    fn get_cached_header(
+        &self,
+        change: &ChangeId,
+    ) -> Result<Option<CachedHeader>, TxnErr<Self::DepsError>> {
+        Ok(self.headers.get(change).cloned())
+    }
+
+    fn get_path_inodes(
+        &self,
+        path: &str,
+    ) -> Result<Vec<(ChangeId, Position<ChangeId>)>, TxnErr<Self::DepsError>> {
+        Ok(self
+            .store
+            .entries(&self.paths)
+            .get(path)
+            .map(|vs| vs.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+}
+
+impl DepsMutTxnT for Txn {
+    backend_put_del!(dep, ChangeId, ChangeId, DepsError);
+    backend_put_del!(revdep, ChangeId, ChangeId, DepsError);
+    backend_put_del!(touched_files, Position<ChangeId>, ChangeId, DepsError);
+    backend_put_del!(rev_touched_files, ChangeId, Position<ChangeId>, DepsError);
+
+    fn put_cached_header(
+        &mut self,
+        change: &ChangeId,
+        header: &CachedHeader,
+    ) -> Result<(), TxnErr<Self::DepsError>> {
+        self.headers.insert(*change, header.clone());
+        Ok(())
+    }
+
+    fn del_cached_header(&mut self, change: &ChangeId) -> Result<(), TxnErr<Self::DepsError>> {
+        self.headers.remove(change);
+        Ok(())
+    }
+
+    fn put_path_inode(
+        &mut self,
+        path: &str,
+        change: &ChangeId,
+        inode: &Position<ChangeId>,
+    ) -> Result<(), TxnErr<Self::DepsError>> {
+        self.store
+            .entries_mut(&self.paths)
+            .entry(path.to_string())
+            .or_default()
+            .insert((*change, *inode));
+        Ok(())
+    }
+
+    fn del_path_inode(
+        &mut self,
+        path: &str,
+        change: &ChangeId,
+        inode: &Position<ChangeId>,
+    ) -> Result<(), TxnErr<Self::DepsError>> {
+        let e = self.store.entries_mut(&self.paths);
+        if let Some(vs) = e.get_mut(path) {
+            vs.remove(&(*change, *inode));
+            if vs.is_empty() {
+                e.remove(path);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u64) -> ChangeId {
+        ChangeId(L64(n))
+    }
+
+    fn pos(change: u64, n: u64) -> Position<ChangeId> {
+        Position {
+            change: id(change),
+            pos: ChangePosition(L64(n)),
+        }
+    }
+
+    #[test]
+    fn cursors() {
+        let mut txn = Txn::new();
+        let mut db = txn.dep;
+        for (k, v) in [(1, 2), (1, 3), (2, 1), (4, 0)].iter() {
+            assert!(txn.put(&mut db, &id(*k), &id(*v)).unwrap());
+        }
+        assert!(!txn.put(&mut db, &id(1), &id(2)).unwrap());
+
+        assert_eq!(
+            txn.get(&db, &id(1), Some(&id(3))).unwrap(),
+            Some((&id(1), &id(3)))
+        );
+        assert_eq!(
+            txn.get(&db, &id(1), Some(&id(4))).unwrap(),
+            Some((&id(2), &id(1)))
+        );
+        assert_eq!(txn.get(&db, &id(3), None).unwrap(), Some((&id(4), &id(0))));
+
+        let mut c = txn.cursor(&db, Some((&id(1), Some(&id(3))))).unwrap();
+        let mut fwd = Vec::new();
+        while let Some((k, v)) = txn.cursor_next(&mut c).unwrap() {
+            fwd.push((*k, *v))
+        }
+        assert_eq!(fwd, vec![(id(1), id(3)), (id(2), id(1)), (id(4), id(0))]);
+
+        let mut c = txn.rev_cursor(&db, Some((&id(3), None))).unwrap();
+        let mut bwd = Vec::new();
+        while let Some((k, v)) = txn.cursor_prev(&mut c).unwrap() {
+            bwd.push((*k, *v))
+        }
+        assert_eq!(bwd, vec![(id(2), id(1)), (id(1), id(3)), (id(1), id(2))]);
+
+        assert!(txn.del(&mut db, &id(1), None).unwrap());
+        assert!(!txn.del(&mut db, &id(1), Some(&id(2))).unwrap());
+        assert!(txn.del(&mut db, &id(1), Some(&id(3))).unwrap());
+        assert_eq!(txn.get(&db, &id(1), None).unwrap(), Some((&id(2), &id(1))));
+    }
+
+    #[test]
+    fn deps() {
+        let mut txn = Txn::new();
+        txn.put_dep(&id(2), &id(1)).unwrap();
+        txn.put_revdep(&id(1), &id(2)).unwrap();
+        txn.put_dep(&id(3), &id(2)).unwrap();
+        txn.put_revdep(&id(2), &id(3)).unwrap();
+        assert_eq!(txn.get_dep(&id(3), None).unwrap(), Some(&id(2)));
+        assert_eq!(txn.get_dep(&id(1), None).unwrap(), None);
+
+        // Iterators start at the key, and continue past it.
+        let deps: Vec<_> = txn
+            .iter_dep(&id(2))
+            .unwrap()
+            .map(|x| {
+                let (k, v) = x.unwrap();
+                (*k, *v)
+            })
+            .collect();
+        assert_eq!(deps, vec![(id(2), id(1)), (id(3), id(2))]);
+        assert_eq!(
+            txn.dep_closure(&id(3)).unwrap(),
+            vec![id(1), id(2)].into_iter().collect()
+        );
+        assert_eq!(
+            txn.revdep_closure(&id(1)).unwrap(),
+            vec![id(2), id(3)].into_iter().collect()
+        );
+
+        txn.del_dep(&id(3), Some(&id(2))).unwrap();
+        assert!(txn.dep_closure(&id(3)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn graph() {
+        let mut txn = Txn::new();
+        let hash: SerializedHash = Hash::Blake3([1; 32]).into();
+        txn.register_change(&id(1), &hash).unwrap();
+        assert_eq!(txn.get_internal(&hash).unwrap(), Some(&id(1)));
+        assert_eq!(txn.get_external(&id(1)).unwrap(), Some(&hash));
+        assert_eq!(txn.get_external(&id(2)).unwrap(), None);
+
+        let mut g = txn.create_graph();
+        let a = Vertex {
+            change: id(1),
+            start: pos(1, 0).pos,
+            end: pos(1, 4).pos,
+        };
+        let b = Vertex {
+            change: id(1),
+            start: pos(1, 4).pos,
+            end: pos(1, 8).pos,
+        };
+        let e = SerializedEdge::new(EdgeFlags::BLOCK, id(1), pos(1, 4).pos, id(1));
+        let f = SerializedEdge::new(
+            EdgeFlags::BLOCK | EdgeFlags::PARENT,
+            id(1),
+            pos(1, 0).pos,
+            id(1),
+        );
+        txn.put(&mut g, &a, &e).unwrap();
+        txn.put(&mut g, &b, &f).unwrap();
+
+        assert_eq!(txn.get_graph(&g, &a, None).unwrap(), Some(&e));
+        assert_eq!(txn.find_block(&g, pos(1, 2)).unwrap(), &a);
+        assert_eq!(txn.find_block(&g, pos(1, 4)).unwrap(), &b);
+        assert_eq!(txn.find_block_end(&g, pos(1, 4)).unwrap(), &a);
+        assert!(txn.find_block(&g, pos(1, 8)).is_err());
+        assert!(txn.find_block(&g, pos(2, 0)).is_err());
+
+        let mut adj = txn
+            .init_adj(&g, b, Position::ROOT, EdgeFlags::PARENT, EdgeFlags::all())
+            .unwrap();
+        assert_eq!(txn.next_adj(&g, &mut adj).unwrap().unwrap(), &f);
+        assert!(txn.next_adj(&g, &mut adj).is_none());
+        let mut adj = txn
+            .init_adj(&g, a, Position::ROOT, EdgeFlags::PARENT, EdgeFlags::all())
+            .unwrap();
+        assert!(txn.next_adj(&g, &mut adj).is_none());
+    }
+
+    #[test]
+    fn tree() {
+        let mut txn = Txn::new();
+        let (dir, file) = (Inode(L64(1)), Inode(L64(2)));
+        let dir_name = OwnedPathId {
+            parent_inode: Inode::ROOT,
+            basename: SmallString::from_str("dir"),
+        };
+        let file_name = OwnedPathId {
+            parent_inode: dir,
+            basename: SmallString::from_str("file"),
+        };
+        txn.put_tree(&dir_name, &dir).unwrap();
+        txn.put_revtree(&dir, &dir_name).unwrap();
+        txn.put_tree(&file_name, &file).unwrap();
+        txn.put_revtree(&file, &file_name).unwrap();
+        txn.put_inodes(&file, &pos(1, 0)).unwrap();
+        txn.put_revinodes(&pos(1, 0), &file).unwrap();
+
+        assert_eq!(txn.get_tree(&dir_name, None).unwrap(), Some(&dir));
+        assert_eq!(
+            txn.get_revtree(&file, None).unwrap().map(|p| p.to_owned()),
+            Some(file_name.clone())
+        );
+        let children: Vec<_> = txn
+            .iter_tree(&OwnedPathId::inode(dir), None)
+            .unwrap()
+            .map(|x| {
+                let (k, v) = x.unwrap();
+                (k.to_owned(), *v)
+            })
+            .collect();
+        assert_eq!(children, vec![(file_name.clone(), file)]);
+        assert_eq!(txn.get_revinodes(&pos(1, 0), None).unwrap(), Some(&file));
+        assert_eq!(txn.iter_inodes().unwrap().count(), 1);
+
+        txn.put_partials("dir", pos(1, 0)).unwrap();
+        let partials: Vec<_> = txn
+            .iter_partials("dir")
+            .unwrap()
+            .map(|x| *x.unwrap().1)
+            .collect();
+        assert_eq!(partials, vec![pos(1, 0)]);
+        assert!(txn.del_partials("dir", None).unwrap());
+        assert_eq!(txn.iter_partials("dir").unwrap().count(), 0);
+
+        assert!(txn.del_tree(&file_name, Some(&file)).unwrap());
+        assert_eq!(txn.get_tree(&file_name, None).unwrap(), None);
+    }
+}
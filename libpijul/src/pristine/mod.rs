@@ -21,6 +21,10 @@ mod path_id;
 pub use path_id::*;
 mod merkle;
 pub use merkle::*;
+mod dep_cache;
+pub use dep_cache::*;
+mod path_cache;
+pub use path_cache::*;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct L64(pub u64);
@@ -135,6 +139,59 @@ pub struct SerializedChannel {
     id: RemoteId,
 }
 
+/// Optional metadata attached to a channel, stored alongside the
+/// channels table. This is meant for humans (and discovery UIs), and
+/// is never used by the algorithms.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Public key of the creator of this channel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+    /// Creation time, in seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<u64>,
+}
+
+impl ChannelMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.description.is_none() && self.created_by.is_none() && self.created.is_none()
+    }
+}
+
+/// A summary of the header of a change, cached in the pristine when
+/// the change is applied, so that the log can be filtered without
+/// loading every change. The field names are short to keep as much
+/// of the message as possible in the cache.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedHeader {
+    /// The public keys of the authors, or their names for authors
+    /// without a key.
+    #[serde(rename = "a", default)]
+    pub authors: Vec<String>,
+    /// Seconds since the Unix epoch.
+    #[serde(rename = "t", default)]
+    pub timestamp: i64,
+    /// The first line of the message, possibly truncated.
+    #[serde(rename = "m", default)]
+    pub message: String,
+}
+
+impl CachedHeader {
+    pub fn new(header: &ChangeHeader) -> Self {
+        CachedHeader {
+            authors: header
+                .authors
+                .iter()
+                .filter_map(|a| a.0.get("key").or_else(|| a.0.get("name")).cloned())
+                .collect(),
+            timestamp: header.timestamp.timestamp(),
+            message: header.message.lines().next().unwrap_or("").to_string(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
 #[repr(C)]
 pub struct Pair<A, B> {
@@ -147,6 +204,8 @@ pub trait Base32: Sized {
     fn from_base32(b: &[u8]) -> Option<Self>;
 }
 
+pub mod backend;
+pub mod memory;
 pub mod sanakirja;
 
 pub type ApplyTimestamp = u64;
@@ -178,11 +237,15 @@ impl<T> Clone for ArcTxn<T> {
 }
 
 impl<T: MutTxnT> ArcTxn<T> {
-    pub fn commit(self) -> Result<(), T::GraphError> {
-        if let Ok(txn) = Arc::try_unwrap(self.0) {
-            txn.into_inner().commit()
-        } else {
-            panic!("Tried to commit an ArcTxn without dropping its references")
+    /// Commit this transaction. This fails with
+    /// [`CommitError::References`] if other clones of this `ArcTxn`
+    /// are still alive, in which case nothing is committed.
+    pub fn commit(self) -> Result<(), CommitError<T::GraphError>> {
+        match Arc::try_unwrap(self.0) {
+            Ok(txn) => txn.into_inner().commit().map_err(CommitError::Txn),
+            Err(txn) => Err(CommitError::References {
+                strong_count: Arc::strong_count(&txn),
+            }),
         }
     }
 }
@@ -213,6 +276,61 @@ impl<T: ChannelTxnT> Clone for ChannelRef<T> {
     }
 }
 
+/// Write locks on several channels, obtained with [`lock_channels`].
+/// Indexing this returns the channel at the same position in the
+/// slice passed to [`lock_channels`].
+pub struct ChannelGuards<'a, T: ChannelTxnT> {
+    guards: Vec<parking_lot::RwLockWriteGuard<'a, T::Channel>>,
+    index: Vec<usize>,
+}
+
+/// Lock all the channels in `channels` for writing, in a canonical
+/// order, so that two threads locking overlapping sets of channels
+/// can't deadlock. A channel may appear several times in `channels`,
+/// in which case it is locked only once.
+///
+/// This should be used whenever more than one channel needs to be
+/// locked at the same time.
+pub fn lock_channels<T: ChannelTxnT>(channels: &[ChannelRef<T>]) -> ChannelGuards<T> {
+    let mut order: Vec<usize> = (0..channels.len()).collect();
+    order.sort_by_key(|&i| Arc::as_ptr(&channels[i].r));
+    let mut guards = Vec::with_capacity(channels.len());
+    let mut index = vec![0; channels.len()];
+    let mut last = None;
+    for i in order {
+        let p = Arc::as_ptr(&channels[i].r);
+        if last != Some(p) {
+            guards.push(channels[i].r.write());
+            last = Some(p)
+        }
+        index[i] = guards.len() - 1;
+    }
+    ChannelGuards { guards, index }
+}
+
+impl<'a, T: ChannelTxnT> ChannelGuards<'a, T> {
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+}
+
+impl<'a, T: ChannelTxnT> std::ops::Index<usize> for ChannelGuards<'a, T> {
+    type Output = T::Channel;
+    fn index(&self, i: usize) -> &Self::Output {
+        &self.guards[self.index[i]]
+    }
+}
+
+impl<'a, T: ChannelTxnT> std::ops::IndexMut<usize> for ChannelGuards<'a, T> {
+    fn index_mut(&mut self, i: usize) -> &mut Self::Output {
+        &mut self.guards[self.index[i]]
+    }
+}
+
 impl<T: TxnT> RemoteRef<T> {
     pub fn id(&self) -> &RemoteId {
         &self.id
@@ -320,6 +438,34 @@ pub enum ForkError<T: std::error::Error + 'static> {
     Txn(T),
 }
 
+#[derive(Debug, Error)]
+pub enum RemotePathsError<T: std::error::Error + 'static> {
+    #[error("Path too long: {0}")]
+    TooLong(String),
+    #[error(transparent)]
+    Txn(T),
+}
+
+#[derive(Debug, Error)]
+pub enum CommitError<T: std::error::Error + 'static> {
+    /// The transaction is still referenced elsewhere.
+    /// `strong_count` includes the reference being committed.
+    #[error("Cannot commit a transaction with other references ({strong_count} references)")]
+    References { strong_count: usize },
+    #[error("Transaction lock poisoned")]
+    Poisoned,
+    #[error(transparent)]
+    Txn(T),
+}
+
+#[derive(Debug, Error)]
+pub enum ChannelMetadataError<T: std::error::Error + 'static> {
+    #[error("Channel metadata too long: {0} bytes")]
+    TooLong(usize),
+    #[error(transparent)]
+    Txn(T),
+}
+
 #[derive(Debug, Error)]
 #[error(transparent)]
 pub struct TxnErr<E: std::error::Error + std::fmt::Debug + 'static>(pub E);
@@ -595,6 +741,92 @@ pub trait DepsTxnT: Sized {
     table_get!(rev_touched_files, ChangeId, Position<ChangeId>, DepsError);
     iter!(touched_files, Position<ChangeId>, ChangeId, DepsError);
     iter!(rev_touched_files, ChangeId, Position<ChangeId>, DepsError);
+
+    /// The cached summary of the header of `change`. Changes applied
+    /// before the cache existed, and changes whose header doesn't fit
+    /// in it, have no entry.
+    fn get_cached_header(
+        &self,
+        change: &ChangeId,
+    ) -> Result<Option<CachedHeader>, TxnErr<Self::DepsError>>;
+
+    /// The files named `path` by a change, as pairs of that change
+    /// and the position of the inode of the file, in all channels.
+    /// Changes applied before this index existed have no entry, and
+    /// neither have paths too long to be indexed.
+    fn get_path_inodes(
+        &self,
+        path: &str,
+    ) -> Result<Vec<(ChangeId, Position<ChangeId>)>, TxnErr<Self::DepsError>>;
+
+    /// The memo of the closures computed by [`DepsTxnT::dep_closure`]
+    /// and [`DepsTxnT::revdep_closure`], if this backend keeps one.
+    /// Implementations returning a cache must clear it whenever the
+    /// `dep` or `revdep` table is modified.
+    fn dep_cache(&self) -> Option<&Mutex<DepCache>> {
+        None
+    }
+
+    /// All the changes `change` depends on, directly or indirectly
+    /// (not including `change` itself).
+    fn dep_closure(&self, change: &ChangeId) -> Result<HashSet<ChangeId>, TxnErr<Self::DepsError>> {
+        closure(self, change, false)
+    }
+
+    /// All the changes depending on `change`, directly or indirectly
+    /// (not including `change` itself), in all channels.
+    fn revdep_closure(
+        &self,
+        change: &ChangeId,
+    ) -> Result<HashSet<ChangeId>, TxnErr<Self::DepsError>> {
+        closure(self, change, true)
+    }
+}
+
+/// Transitive closure of `change` in the `revdep` table if `reverse`
+/// is true, and in the `dep` table else. The closures found in the
+/// memo of `txn` are not traversed again.
+fn closure<T: DepsTxnT>(
+    txn: &T,
+    change: &ChangeId,
+    reverse: bool,
+) -> Result<HashSet<ChangeId>, TxnErr<T::DepsError>> {
+    let cached = |id: &ChangeId| {
+        txn.dep_cache()
+            .and_then(|c| c.lock().get(reverse, id).cloned())
+    };
+    if let Some(c) = cached(change) {
+        return Ok(c);
+    }
+    let mut result = HashSet::default();
+    let mut stack = vec![*change];
+    while let Some(id) = stack.pop() {
+        let it = if reverse {
+            txn.iter_revdep(&id)?
+        } else {
+            txn.iter_dep(&id)?
+        };
+        for x in it {
+            let (p, d) = x?;
+            if *p < id {
+                continue;
+            } else if *p > id {
+                break;
+            }
+            if !result.insert(*d) {
+                continue;
+            }
+            if let Some(c) = cached(d) {
+                result.extend(c)
+            } else {
+                stack.push(*d)
+            }
+        }
+    }
+    if let Some(c) = txn.dep_cache() {
+        c.lock().insert(reverse, *change, result.clone())
+    }
+    Ok(result)
 }
 
 #[derive(Debug, Error)]
@@ -642,6 +874,13 @@ pub trait TreeTxnT: Sized {
         Cursor<Self, &'txn Self, Self::PartialsCursor, SmallStr, Position<ChangeId>>,
         TreeErr<Self::TreeError>,
     >;
+
+    /// The cache of paths of the `tree` table, if this backend keeps
+    /// one. Implementations returning a cache must clear it whenever
+    /// the `tree` table is modified.
+    fn path_cache(&self) -> Option<&Mutex<PathCache>> {
+        None
+    }
 }
 
 /// The trait of immutable transactions.
@@ -671,6 +910,14 @@ pub trait TxnT:
         prefix: &str,
     ) -> Result<Hash, HashPrefixError<Self::GraphError>>;
 
+    /// The length of the shortest prefix of the base32 hashes of the
+    /// changes of `channel` identifying each of them among all the
+    /// changes of this pristine, so that
+    /// [`TxnT::hash_from_prefix`] doesn't find them ambiguous.
+    /// Implementations may cache the result until `channel` or the
+    /// set of changes of the pristine is modified.
+    fn hash_prefix_len(&self, channel: &Self::Channel) -> Result<usize, TxnErr<Self::GraphError>>;
+
     fn load_channel(
         &self,
         name: &str,
@@ -693,6 +940,34 @@ pub trait TxnT:
         start: &RemoteId,
     ) -> Result<RemotesIterator<'txn, Self>, TxnErr<Self::GraphError>>;
 
+    /// The metadata attached to channel `name`, if any.
+    fn channel_metadata(
+        &self,
+        name: &str,
+    ) -> Result<Option<ChannelMetadata>, TxnErr<Self::GraphError>>;
+
+    /// The paths the cached changelist of remote `id` is restricted
+    /// to, sorted, or an empty list if that cache is not restricted.
+    fn remote_paths(&self, id: &RemoteId) -> Result<Vec<String>, TxnErr<Self::GraphError>>;
+
+    /// The last time the cached changelist of remote `id` was opened
+    /// for writing, in seconds since the Unix epoch, or `None` if it
+    /// wasn't since this time is recorded.
+    fn remote_last_used(&self, id: &RemoteId) -> Result<Option<u64>, TxnErr<Self::GraphError>>;
+
+    /// The validator of the cached changelist of remote `id`, if any,
+    /// such as an HTTP ETag, used to avoid downloading an unchanged
+    /// changelist again.
+    fn remote_validator(&self, id: &RemoteId) -> Result<Option<String>, TxnErr<Self::GraphError>>;
+
+    /// The capabilities of remote `id`, as last stored with
+    /// [`MutTxnT::set_remote_capabilities`]. Their format is up to
+    /// the caller.
+    fn remote_capabilities(
+        &self,
+        id: &RemoteId,
+    ) -> Result<Option<String>, TxnErr<Self::GraphError>>;
+
     table!(remotes);
     cursor!(remotes, RemoteId, SerializedRemote);
     table!(remote);
@@ -1091,6 +1366,115 @@ pub fn debug_tree_print<T: TreeTxnT>(txn: &T) {
     }
 }
 
+/// The size of the cached changelist of a remote.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RemoteCacheSize {
+    /// Number of changes in the changelist.
+    pub entries: u64,
+    /// Number of tagged states.
+    pub tags: u64,
+}
+
+pub fn remote_cache_size<T: TxnT>(
+    txn: &T,
+    remote: &RemoteRef<T>,
+) -> Result<RemoteCacheSize, TxnErr<T::GraphError>> {
+    let remote = remote.lock();
+    let mut size = RemoteCacheSize::default();
+    for x in txn.iter_remote(&remote.remote, 0)? {
+        x?;
+        size.entries += 1
+    }
+    for x in txn.iter_tags(&remote.tags, 0)? {
+        x?;
+        size.tags += 1
+    }
+    Ok(size)
+}
+
+/// Bounds on the cached changelists of remotes, enforced by
+/// [`expire_remote_caches`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RemoteCachePolicy {
+    /// Maximal number of changes in the changelist of a remote.
+    pub max_entries: Option<u64>,
+    /// Maximal time since the last use of a remote, in seconds.
+    pub max_age: Option<u64>,
+}
+
+/// Why the cache of a remote was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheExpiry {
+    /// The changelist had more entries than the policy allows.
+    TooLarge,
+    /// The remote wasn't used for longer than the policy allows.
+    Unused,
+}
+
+/// A cached remote, as reported by [`expire_remote_caches`].
+#[derive(Debug, Clone)]
+pub struct RemoteCache {
+    pub id: RemoteId,
+    pub path: String,
+    pub size: RemoteCacheSize,
+    /// The last use of the remote, in seconds since the Unix epoch.
+    pub last_used: u64,
+    /// Whether (and why) the cache was dropped.
+    pub expired: Option<CacheExpiry>,
+}
+
+/// Drop the caches of the remotes exceeding `policy` at time `now`
+/// (in seconds since the Unix epoch), or only report them if
+/// `dry_run` is true, and return all the caches. Caches are dropped
+/// entirely, since a truncated changelist would make the remote look
+/// like it had unrecorded changes, and are downloaded again by the
+/// next command using the remote. Remotes last used before this time
+/// was recorded are considered used at `now`.
+pub fn expire_remote_caches<T: MutTxnT>(
+    txn: &mut T,
+    policy: &RemoteCachePolicy,
+    now: u64,
+    dry_run: bool,
+) -> Result<Vec<RemoteCache>, TxnErr<T::GraphError>> {
+    let mut caches = Vec::new();
+    for r in txn.iter_remotes(&RemoteId::nil())? {
+        let r = r?;
+        let size = remote_cache_size(txn, &r)?;
+        let last_used = txn.remote_last_used(r.id())?;
+        let path = r.lock().path.as_str().to_string();
+        caches.push((*r.id(), path, size, last_used))
+    }
+    let mut result = Vec::with_capacity(caches.len());
+    for (id, path, size, last_used) in caches {
+        let last_used = if let Some(t) = last_used {
+            t
+        } else {
+            if !dry_run {
+                txn.touch_remote(&id, Some(now))?;
+            }
+            now
+        };
+        let expired = if policy.max_entries.map(|m| size.entries > m) == Some(true) {
+            Some(CacheExpiry::TooLarge)
+        } else if policy.max_age.map(|m| now.saturating_sub(last_used) > m) == Some(true) {
+            Some(CacheExpiry::Unused)
+        } else {
+            None
+        };
+        if expired.is_some() && !dry_run {
+            txn.drop_named_remote(id).map_err(TxnErr)?;
+        }
+        result.push(RemoteCache {
+            id,
+            path,
+            size,
+            last_used,
+            expired,
+        })
+    }
+    Ok(result)
+}
+
 // #[cfg(debug_assertions)]
 pub fn debug_remotes<T: TxnT>(txn: &T) {
     for t in txn.iter_remotes(&RemoteId([0; 16])).unwrap() {
@@ -1197,6 +1581,24 @@ pub fn debug<W: Write, T: GraphIter>(
     Ok(at_least_one)
 }
 
+/// Check that all the changes in the log of `channel` are known to
+/// the pristine, i.e. have an external hash. Returns the timestamps
+/// and internal identifiers of the changes that don't, which make the
+/// log iterators fail with [`crate::LogError::UnknownChange`].
+pub fn check_log<T: ChannelTxnT>(
+    txn: &T,
+    channel: &T::Channel,
+) -> Result<Vec<(u64, ChangeId)>, TxnErr<T::GraphError>> {
+    let mut unknown = Vec::new();
+    for x in changeid_log(txn, channel, L64(0))? {
+        let (&t, p) = x?;
+        if txn.get_external(&p.a)?.is_none() {
+            unknown.push((t.into(), p.a))
+        }
+    }
+    Ok(unknown)
+}
+
 pub fn check_alive<T: ChannelTxnT + GraphIter>(
     txn: &T,
     channel: &T::Graph,
@@ -1279,7 +1681,7 @@ pub fn check_alive<T: ChannelTxnT + GraphIter>(
     (alive_unreachable, reachable_pseudo)
 }
 
-fn find_file<T: GraphTxnT>(
+pub(crate) fn find_file<T: GraphTxnT>(
     txn: &T,
     channel: &T::Graph,
     k: Vertex<ChangeId>,
@@ -1744,6 +2146,31 @@ pub trait GraphMutTxnT: GraphTxnT {
         v: &SerializedEdge,
     ) -> Result<bool, TxnErr<Self::GraphError>>;
 
+    /// Insert `v` at `k`, unless an edge from `k` with the same flag
+    /// and destination as `v` is already in the graph, whatever change
+    /// introduced it. Returns `true` if and only if `v` was inserted.
+    fn put_graph_if_absent(
+        &mut self,
+        graph: &mut Self::Graph,
+        k: &Vertex<ChangeId>,
+        v: &SerializedEdge,
+    ) -> Result<bool, TxnErr<Self::GraphError>> {
+        let present = {
+            let mut adj = self.init_adj(graph, *k, v.dest(), v.flag(), v.flag())?;
+            if let Some(e) = self.next_adj(graph, &mut adj) {
+                let e = e?;
+                e.flag() == v.flag() && e.dest() == v.dest()
+            } else {
+                false
+            }
+        };
+        if present {
+            Ok(false)
+        } else {
+            self.put_graph(graph, k, v)
+        }
+    }
+
     /// Delete a key and a value from a graph. Returns `true` if and only if `(k, v)` was in the graph.
     fn del_graph(
         &mut self,
@@ -1755,7 +2182,10 @@ pub trait GraphMutTxnT: GraphTxnT {
     fn debug(&mut self, channel: &mut Self::Graph, extra: &str);
 
     /// Split a key `[a, b[` at position `pos`, yielding two keys `[a,
-    /// pos[` and `[pos, b[` linked by an edge.
+    /// pos[` and `[pos, b[` linked by an edge. This panics if `pos` is
+    /// not strictly between `a` and `b`, see
+    /// [`graph::split_vertex`](crate::graph::split_vertex) for a
+    /// checked version.
     fn split_block(
         &mut self,
         graph: &mut Self::Graph,
@@ -1800,6 +2230,19 @@ pub trait ChannelMutTxnT: ChannelTxnT + GraphMutTxnT {
         channel: &mut Self::Tags,
         n: u64,
     ) -> Result<(), TxnErr<Self::GraphError>>;
+
+    /// Remove the changes applied to `channel` at timestamp `t` or
+    /// later from its change tables and tags, and replace its graph
+    /// by an empty one. Returns the removed changes, latest first.
+    ///
+    /// This leaves the graph empty: it is only the first step of
+    /// [`crate::MutTxnTExt::truncate`], which then rebuilds
+    /// the graph from the remaining changes.
+    fn truncate_channel(
+        &mut self,
+        channel: &mut Self::Channel,
+        t: ApplyTimestamp,
+    ) -> Result<Vec<(ApplyTimestamp, ChangeId)>, TxnErr<Self::GraphError>>;
 }
 
 pub trait DepsMutTxnT: DepsTxnT {
@@ -1807,6 +2250,32 @@ pub trait DepsMutTxnT: DepsTxnT {
     put_del!(revdep, ChangeId, ChangeId, DepsError);
     put_del!(touched_files, Position<ChangeId>, ChangeId, DepsError);
     put_del!(rev_touched_files, ChangeId, Position<ChangeId>, DepsError);
+
+    /// Cache a summary of the header of `change`, truncating its
+    /// message if needed.
+    fn put_cached_header(
+        &mut self,
+        change: &ChangeId,
+        header: &CachedHeader,
+    ) -> Result<(), TxnErr<Self::DepsError>>;
+
+    fn del_cached_header(&mut self, change: &ChangeId) -> Result<(), TxnErr<Self::DepsError>>;
+
+    /// Record that `change` names `path` the file whose inode is
+    /// `inode`.
+    fn put_path_inode(
+        &mut self,
+        path: &str,
+        change: &ChangeId,
+        inode: &Position<ChangeId>,
+    ) -> Result<(), TxnErr<Self::DepsError>>;
+
+    fn del_path_inode(
+        &mut self,
+        path: &str,
+        change: &ChangeId,
+        inode: &Position<ChangeId>,
+    ) -> Result<(), TxnErr<Self::DepsError>>;
 }
 
 pub trait TreeMutTxnT: TreeTxnT {
@@ -1861,6 +2330,22 @@ pub trait MutTxnT:
 
     fn drop_channel(&mut self, name: &str) -> Result<bool, Self::GraphError>;
 
+    /// Replace the metadata attached to channel `name`. Empty metadata
+    /// deletes the entry.
+    fn set_channel_metadata(
+        &mut self,
+        name: &str,
+        meta: &ChannelMetadata,
+    ) -> Result<(), ChannelMetadataError<Self::GraphError>>;
+
+    /// Replace the paths the cached changelist of remote `id` is
+    /// restricted to. An empty list means no restriction.
+    fn set_remote_paths(
+        &mut self,
+        id: &RemoteId,
+        paths: &[String],
+    ) -> Result<(), RemotePathsError<Self::GraphError>>;
+
     /// Commit this transaction.
     fn commit(self) -> Result<(), Self::GraphError>;
 
@@ -1883,6 +2368,33 @@ pub trait MutTxnT:
         k: u64,
     ) -> Result<bool, TxnErr<Self::GraphError>>;
 
+    /// Set the last time remote `id` was used to `t` (in seconds
+    /// since the Unix epoch), or to the current time if `t` is
+    /// `None`. [`MutTxnT::open_or_create_remote`] does this.
+    fn touch_remote(
+        &mut self,
+        id: &RemoteId,
+        t: Option<u64>,
+    ) -> Result<(), TxnErr<Self::GraphError>>;
+
+    /// Replace the validator of the cached changelist of remote `id`
+    /// (see [`TxnT::remote_validator`]). `None`, as well as validators
+    /// longer than 255 bytes, delete it.
+    fn set_remote_validator(
+        &mut self,
+        id: &RemoteId,
+        validator: Option<&str>,
+    ) -> Result<(), TxnErr<Self::GraphError>>;
+
+    /// Replace the capabilities of remote `id` (see
+    /// [`TxnT::remote_capabilities`]). `None`, as well as strings
+    /// longer than 255 bytes, delete them.
+    fn set_remote_capabilities(
+        &mut self,
+        id: &RemoteId,
+        capabilities: Option<&str>,
+    ) -> Result<(), TxnErr<Self::GraphError>>;
+
     fn drop_remote(&mut self, remote: RemoteRef<Self>) -> Result<bool, Self::GraphError>;
 
     fn drop_named_remote(&mut self, id: RemoteId) -> Result<bool, Self::GraphError>;
@@ -1991,6 +2503,34 @@ pub(crate) fn put_graph_with_rev<T: GraphMutTxnT>(
     Ok(a && b)
 }
 
+/// Same as [`put_graph_with_rev`], but each direction of the edge is
+/// only inserted if no edge with the same flag already links `k0`
+/// and `k1` in that direction. This completes edges of which only one
+/// direction was inserted, instead of panicking. Returns `true` if
+/// and only if both directions were inserted.
+pub(crate) fn put_graph_with_rev_if_absent<T: GraphMutTxnT>(
+    txn: &mut T,
+    graph: &mut T::Graph,
+    flag: EdgeFlags,
+    k0: Vertex<ChangeId>,
+    k1: Vertex<ChangeId>,
+    introduced_by: ChangeId,
+) -> Result<bool, TxnErr<T::GraphError>> {
+    debug_assert!(!flag.contains(EdgeFlags::PARENT));
+    debug!("put_graph_with_rev_if_absent {:?} {:?} {:?}", k0, k1, flag);
+    let a = txn.put_graph_if_absent(
+        graph,
+        &k0,
+        &SerializedEdge::new(flag, k1.change, k1.start, introduced_by),
+    )?;
+    let b = txn.put_graph_if_absent(
+        graph,
+        &k1,
+        &SerializedEdge::new(flag | EdgeFlags::PARENT, k0.change, k0.end, introduced_by),
+    )?;
+    Ok(a && b)
+}
+
 pub(crate) fn register_change<
     T: GraphMutTxnT + DepsMutTxnT<DepsError = <T as GraphTxnT>::GraphError>,
 >(
@@ -2003,6 +2543,7 @@ pub(crate) fn register_change<
     let shash = hash.into();
     txn.put_external(internal, &shash)?;
     txn.put_internal(&shash, internal)?;
+    txn.put_cached_header(internal, &CachedHeader::new(&change.header))?;
     for dep in change.dependencies.iter() {
         debug!("dep = {:?}", dep);
         let dep_internal = *txn.get_internal(&dep.into())?.unwrap();
@@ -2048,9 +2589,72 @@ pub(crate) fn register_change<
             txn.put_rev_touched_files(internal, &inode)?;
         }
     }
+    for (path, inode) in file_names(txn, internal, change)? {
+        txn.put_path_inode(path, internal, &inode)?;
+    }
     Ok(())
 }
 
+/// The names given to files by `change`, along with the inodes of
+/// these files, for the path index: the names of added files, of
+/// deleted files, and of moved files before they were moved. The
+/// other names of a file are either its current name, or the name it
+/// had before another move or deletion.
+pub(crate) fn file_names<'a, T: GraphTxnT>(
+    txn: &T,
+    internal: &ChangeId,
+    change: &'a Change,
+) -> Result<Vec<(&'a str, Position<ChangeId>)>, TxnErr<T::GraphError>> {
+    let mut names = Vec::new();
+    for hunk in change.changes.iter() {
+        let (path, inode) = match *hunk {
+            Hunk::FileAdd {
+                add_inode: Atom::NewVertex(ref n),
+                ref path,
+                ..
+            } => {
+                let inode = Position {
+                    change: None,
+                    pos: n.start,
+                };
+                (path, inode)
+            }
+            Hunk::FileMove {
+                add: Atom::NewVertex(ref n),
+                ref path,
+                ..
+            } => {
+                if let Some(&inode) = n.down_context.first() {
+                    (path, inode)
+                } else {
+                    continue;
+                }
+            }
+            Hunk::FileDel {
+                ref del, ref path, ..
+            } => (path, del.inode()),
+            _ => continue,
+        };
+        let change = if let Some(c) = inode.change {
+            if let Some(&c) = txn.get_internal(&c.into())? {
+                c
+            } else {
+                continue;
+            }
+        } else {
+            *internal
+        };
+        names.push((
+            path.as_str(),
+            Position {
+                change,
+                pos: inode.pos,
+            },
+        ))
+    }
+    Ok(names)
+}
+
 fn first_state_after<T: ChannelTxnT>(
     txn: &T,
     c: &T::Channel,
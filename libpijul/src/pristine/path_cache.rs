@@ -0,0 +1,78 @@
+//! A prefix tree of the paths of the `tree` table, caching the inode
+//! of each path found by the functions of [`crate::fs`], so that
+//! looking up many deep paths in the same transaction doesn't query
+//! the `tree` table once per component of each path.
+//!
+//! The cache lives in the transaction, and is cleared whenever the
+//! `tree` table is modified.
+
+use super::Inode;
+use crate::HashMap;
+
+/// Maximum number of paths in the cache. The cache is cleared when
+/// it grows beyond that.
+const MAX_PATHS: usize = 1 << 16;
+
+#[derive(Debug, Default)]
+pub struct PathCache {
+    root: Node,
+    len: usize,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    inode: Option<Inode>,
+    children: HashMap<String, Node>,
+}
+
+impl PathCache {
+    /// Find the longest prefix of `components` in the cache, and
+    /// return its inode and number of components. If no prefix is
+    /// cached, this is `(Inode::ROOT, 0)`.
+    pub fn longest_prefix<'a, I: Iterator<Item = &'a str>>(&self, components: I) -> (Inode, usize) {
+        let mut node = &self.root;
+        let mut result = (Inode::ROOT, 0);
+        for (n, c) in components.enumerate() {
+            if let Some(next) = node.children.get(c) {
+                node = next;
+                if let Some(inode) = node.inode {
+                    result = (inode, n + 1)
+                }
+            } else {
+                break;
+            }
+        }
+        result
+    }
+
+    /// Record the inodes of a path, given as a sequence of
+    /// components, each with its inode (or `None` if that ancestor
+    /// is already in the cache).
+    pub fn insert<'a, I: IntoIterator<Item = (&'a str, Option<Inode>)>>(&mut self, path: I) {
+        if self.len >= MAX_PATHS {
+            self.clear()
+        }
+        let mut node = &mut self.root;
+        for (c, inode) in path {
+            node = node.children.entry(c.to_string()).or_default();
+            if let Some(inode) = inode {
+                if node.inode.replace(inode).is_none() {
+                    self.len += 1
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.root = Node::default();
+        self.len = 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
@@ -45,6 +45,22 @@ impl PathId {
     }
 }
 
+impl std::borrow::Borrow<PathId> for OwnedPathId {
+    fn borrow(&self) -> &PathId {
+        self
+    }
+}
+
+impl ToOwned for PathId {
+    type Owned = OwnedPathId;
+    fn to_owned(&self) -> OwnedPathId {
+        OwnedPathId {
+            parent_inode: self.parent_inode,
+            basename: SmallString::from_str(self.basename.as_str()),
+        }
+    }
+}
+
 impl std::ops::Deref for OwnedPathId {
     type Target = PathId;
     fn deref(&self) -> &Self::Target {
@@ -3,6 +3,7 @@ use crate::HashMap;
 use ::sanakirja::*;
 use parking_lot::Mutex;
 use std::collections::hash_map::Entry;
+#[cfg(feature = "mmap")]
 use std::path::Path;
 use std::sync::Arc;
 
@@ -68,6 +69,10 @@ impl std::convert::From<TreeErr<::sanakirja::Error>> for TreeErr<SanakirjaError>
     }
 }
 
+/// Pristines stored in a file, which Sanakirja memory-maps. Without
+/// the `mmap` feature, only the anonymous pristines below are
+/// available, and existing pristines can't be read.
+#[cfg(feature = "mmap")]
 impl Pristine {
     pub fn new<P: AsRef<Path>>(name: P) -> Result<Self, SanakirjaError> {
         Self::new_with_size(name, 1 << 20)
@@ -97,6 +102,246 @@ impl Pristine {
             env: Arc::new(::sanakirja::Env::new_nolock(name, size, 2)?),
         })
     }
+
+    /// Copy the live contents of this pristine, stored in the file at
+    /// `path`, to a fresh file, and replace `path` with that file if
+    /// it is smaller. Sanakirja never shrinks its files, so this is
+    /// the only way to give back the pages freed by unrecording
+    /// changes or deleting channels.
+    ///
+    /// A mutable transaction is held during the whole copy, so that
+    /// nothing else can modify the pristine. This pristine still
+    /// refers to the old file afterwards, and shouldn't be used
+    /// anymore.
+    pub fn compact<P: AsRef<Path>>(&self, path: P) -> Result<CompactStats, SanakirjaError> {
+        let io = |e| SanakirjaError::Sanakirja(::sanakirja::Error::IO(e));
+        let path = path.as_ref();
+        let size_before = std::fs::metadata(path).map_err(io)?.len();
+        let tmp = path.with_extension("compact");
+        match std::fs::remove_file(&tmp) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(io(e)),
+            _ => {}
+        }
+        let from = self.mut_txn_begin()?;
+        from.copy_into(&tmp)?;
+        let size_after = std::fs::metadata(&tmp).map_err(io)?.len();
+        let swapped = size_after < size_before;
+        if swapped {
+            std::fs::rename(&tmp, path).map_err(io)?;
+            // Make the rename durable.
+            #[cfg(not(windows))]
+            if let Some(dir) = path.parent() {
+                std::fs::File::open(dir)
+                    .and_then(|d| d.sync_all())
+                    .map_err(io)?;
+            }
+        } else {
+            std::fs::remove_file(&tmp).map_err(io)?;
+        }
+        debug!("compacted {:?}: {} -> {}", path, size_before, size_after);
+        Ok(CompactStats {
+            size_before,
+            size_after,
+            swapped,
+        })
+    }
+}
+
+/// The result of [`Pristine::compact`].
+#[cfg(feature = "mmap")]
+#[derive(Debug, Clone, Copy)]
+pub struct CompactStats {
+    /// Size of the file before compaction, in bytes.
+    pub size_before: u64,
+    /// Size of the compacted file, in bytes.
+    pub size_after: u64,
+    /// Whether the compacted file replaced the original one, which
+    /// only happens if it is smaller.
+    pub swapped: bool,
+}
+
+#[cfg(feature = "mmap")]
+impl CompactStats {
+    /// The number of pages by which the file shrank. This isn't the
+    /// number of free pages of the original file, which Sanakirja
+    /// doesn't report, since both files can also have space allocated
+    /// in advance.
+    pub fn reclaimed_pages(&self) -> u64 {
+        self.size_before.saturating_sub(self.size_after) / PAGE_SIZE
+    }
+}
+
+/// Size of the pages of Sanakirja.
+const PAGE_SIZE: u64 = 4096;
+
+/// Split the JSON metadata of a channel into values of the
+/// `channel_meta` table, each prefixed with its index in two hex
+/// digits so that they are sorted. Metadata written by older versions
+/// is a single unprefixed value, starting with `{`.
+fn metadata_chunks(json: &str) -> Vec<SmallString> {
+    const CHUNK_LEN: usize = crate::small_string::MAX_LENGTH - 2;
+    let mut chunks = Vec::new();
+    let mut rest = json;
+    while !rest.is_empty() {
+        let mut len = CHUNK_LEN.min(rest.len());
+        while !rest.is_char_boundary(len) {
+            len -= 1
+        }
+        let (chunk, r) = rest.split_at(len);
+        chunks.push(SmallString::from_str(&format!(
+            "{:02x}{}",
+            chunks.len() & 0xff,
+            chunk
+        )));
+        rest = r
+    }
+    chunks
+}
+
+/// Copy all the entries of `db`, in transaction `from`, to `db2` in
+/// transaction `to`.
+#[cfg(feature = "mmap")]
+fn copy_entries<T, K, V, P>(
+    from: &T,
+    to: &mut ::sanakirja::MutTxn<Arc<::sanakirja::Env>, ()>,
+    db: &btree::Db_<K, V, P>,
+    db2: &mut btree::Db_<K, V, P>,
+) -> Result<(), SanakirjaError>
+where
+    T: ::sanakirja::LoadPage<Error = ::sanakirja::Error>,
+    K: Storable + ?Sized,
+    V: Storable + ?Sized,
+    P: btree::BTreeMutPage<K, V>,
+{
+    for x in btree::iter(from, db, None)? {
+        let (k, v) = x?;
+        btree::put(to, db2, k, v)?;
+    }
+    Ok(())
+}
+
+/// Copy `db`, in transaction `from`, to a new table of `to`.
+#[cfg(feature = "mmap")]
+fn copy_db<T, K, V, P>(
+    from: &T,
+    to: &mut ::sanakirja::MutTxn<Arc<::sanakirja::Env>, ()>,
+    db: &btree::Db_<K, V, P>,
+) -> Result<btree::Db_<K, V, P>, SanakirjaError>
+where
+    T: ::sanakirja::LoadPage<Error = ::sanakirja::Error>,
+    K: Storable + ?Sized,
+    V: Storable + ?Sized,
+    P: btree::BTreeMutPage<K, V>,
+{
+    let mut db2 = btree::create_db_(to)?;
+    copy_entries(from, to, db, &mut db2)?;
+    Ok(db2)
+}
+
+#[cfg(feature = "mmap")]
+impl<T: ::sanakirja::LoadPage<Error = ::sanakirja::Error> + ::sanakirja::RootPage> GenericTxn<T> {
+    /// Copy the contents of this transaction to a new pristine at
+    /// `path`, which must not exist. Only the live pages are copied.
+    pub fn copy_into<P: AsRef<Path>>(&self, path: P) -> Result<(), SanakirjaError> {
+        let dest = Pristine::new(path)?;
+        let mut to = dest.mut_txn_begin()?;
+        self.copy_to(&mut to)?;
+        to.commit()
+    }
+
+    /// Copy all the tables of this transaction to `to`, which must
+    /// be a transaction on an empty pristine.
+    fn copy_to(&self, to: &mut MutTxn<()>) -> Result<(), SanakirjaError> {
+        let from = &self.txn;
+        copy_entries(from, &mut to.txn, &self.internal, &mut to.internal)?;
+        copy_entries(from, &mut to.txn, &self.external, &mut to.external)?;
+        copy_entries(from, &mut to.txn, &self.inodes, &mut to.inodes)?;
+        copy_entries(from, &mut to.txn, &self.revinodes, &mut to.revinodes)?;
+        copy_entries(from, &mut to.txn, &self.tree, &mut to.tree)?;
+        copy_entries(from, &mut to.txn, &self.revtree, &mut to.revtree)?;
+        copy_entries(from, &mut to.txn, &self.revdep, &mut to.revdep)?;
+        copy_entries(from, &mut to.txn, &self.dep, &mut to.dep)?;
+        copy_entries(
+            from,
+            &mut to.txn,
+            &self.touched_files,
+            &mut to.touched_files,
+        )?;
+        copy_entries(
+            from,
+            &mut to.txn,
+            &self.rev_touched_files,
+            &mut to.rev_touched_files,
+        )?;
+        copy_entries(from, &mut to.txn, &self.partials, &mut to.partials)?;
+        if let (Some(db), Some(db2)) = (&self.channel_meta, &mut to.channel_meta) {
+            copy_entries(from, &mut to.txn, db, db2)?;
+        }
+        if let (Some(db), Some(db2)) = (&self.remote_paths, &mut to.remote_paths) {
+            copy_entries(from, &mut to.txn, db, db2)?;
+        }
+        if let (Some(db), Some(db2)) = (&self.headers, &mut to.headers) {
+            copy_entries(from, &mut to.txn, db, db2)?;
+        }
+        if let (Some(db), Some(db2)) = (&self.paths, &mut to.paths) {
+            copy_entries(from, &mut to.txn, db, db2)?;
+        }
+        if let (Some(db), Some(db2)) = (&self.remote_access, &mut to.remote_access) {
+            copy_entries(from, &mut to.txn, db, db2)?;
+        }
+        if let (Some(db), Some(db2)) = (&self.remote_validators, &mut to.remote_validators) {
+            copy_entries(from, &mut to.txn, db, db2)?;
+        }
+        if let (Some(db), Some(db2)) = (&self.remote_capabilities, &mut to.remote_capabilities) {
+            copy_entries(from, &mut to.txn, db, db2)?;
+        }
+        // Channels and remotes point to their own tables, which are
+        // copied one by one.
+        for x in btree::iter(from, &self.channels, None)? {
+            let (name, c) = x?;
+            let graph: Db<Vertex<ChangeId>, SerializedEdge> = Db::from_page(c.graph.into());
+            let changes: Db<ChangeId, L64> = Db::from_page(c.changes.into());
+            let revchanges: UDb<L64, Pair<ChangeId, SerializedMerkle>> =
+                UDb::from_page(c.revchanges.into());
+            let states: UDb<SerializedMerkle, L64> = UDb::from_page(c.states.into());
+            let tags: Db<L64, Pair<SerializedMerkle, SerializedMerkle>> =
+                Db::from_page(c.tags.into());
+            let sc = SerializedChannel {
+                graph: copy_db(from, &mut to.txn, &graph)?.db.into(),
+                changes: copy_db(from, &mut to.txn, &changes)?.db.into(),
+                revchanges: copy_db(from, &mut to.txn, &revchanges)?.db.into(),
+                states: copy_db(from, &mut to.txn, &states)?.db.into(),
+                tags: copy_db(from, &mut to.txn, &tags)?.db.into(),
+                apply_counter: c.apply_counter,
+                last_modified: c.last_modified,
+                id: c.id,
+            };
+            btree::put(&mut to.txn, &mut to.channels, name, &sc)?;
+        }
+        for x in btree::iter(from, &self.remotes, None)? {
+            let (id, r) = x?;
+            let remote: UDb<L64, Pair<SerializedHash, SerializedMerkle>> =
+                UDb::from_page(r.remote.into());
+            let rev: UDb<SerializedHash, L64> = UDb::from_page(r.rev.into());
+            let states: UDb<SerializedMerkle, L64> = UDb::from_page(r.states.into());
+            let tags: Db<L64, Pair<SerializedMerkle, SerializedMerkle>> =
+                Db::from_page(r.tags.into());
+            let rr = OwnedSerializedRemote {
+                _remote: copy_db(from, &mut to.txn, &remote)?.db.into(),
+                _rev: copy_db(from, &mut to.txn, &rev)?.db.into(),
+                _states: copy_db(from, &mut to.txn, &states)?.db.into(),
+                _id_rev: r.id_rev,
+                _tags: copy_db(from, &mut to.txn, &tags)?.db.into(),
+                _path: r.path.to_owned(),
+            };
+            btree::put(&mut to.txn, &mut to.remotes, id, &rr)?;
+        }
+        to.cur_channel = Some(self.current_channel()?.to_string());
+        Ok(())
+    }
+}
+
+impl Pristine {
     pub fn new_anon() -> Result<Self, SanakirjaError> {
         Self::new_anon_with_size(1 << 20)
     }
@@ -124,6 +369,13 @@ pub enum Root {
     RevTouchedFiles,
     Partials,
     Remotes,
+    ChannelMeta,
+    RemotePaths,
+    Headers,
+    Paths,
+    RemoteAccess,
+    RemoteValidators,
+    RemoteCapabilities,
 }
 
 const VERSION: L64 = L64(1u64.to_le());
@@ -149,8 +401,20 @@ impl Pristine {
                 partials: txn.root_db(Root::Partials as usize)?,
                 dep: txn.root_db(Root::Dep as usize)?,
                 remotes: txn.root_db(Root::Remotes as usize)?,
+                // Added after the other tables, this may be missing
+                // from older pristines.
+                channel_meta: txn.root_db(Root::ChannelMeta as usize),
+                remote_paths: txn.root_db(Root::RemotePaths as usize),
+                headers: txn.root_db(Root::Headers as usize),
+                paths: txn.root_db(Root::Paths as usize),
+                remote_access: txn.root_db(Root::RemoteAccess as usize),
+                remote_validators: txn.root_db(Root::RemoteValidators as usize),
+                remote_capabilities: txn.root_db(Root::RemoteCapabilities as usize),
                 open_channels: Mutex::new(HashMap::default()),
                 open_remotes: Mutex::new(HashMap::default()),
+                path_cache: Mutex::new(PathCache::default()),
+                dep_cache: Mutex::new(DepCache::default()),
+                prefix_cache: Mutex::new(HashMap::default()),
                 txn,
                 counter: 0,
                 cur_channel: None,
@@ -242,8 +506,50 @@ impl Pristine {
             } else {
                 btree::create_db_(&mut txn)?
             },
+            channel_meta: Some(if let Some(db) = txn.root_db(Root::ChannelMeta as usize) {
+                db
+            } else {
+                btree::create_db_(&mut txn)?
+            }),
+            remote_paths: Some(if let Some(db) = txn.root_db(Root::RemotePaths as usize) {
+                db
+            } else {
+                btree::create_db_(&mut txn)?
+            }),
+            headers: Some(if let Some(db) = txn.root_db(Root::Headers as usize) {
+                db
+            } else {
+                btree::create_db_(&mut txn)?
+            }),
+            paths: Some(if let Some(db) = txn.root_db(Root::Paths as usize) {
+                db
+            } else {
+                btree::create_db_(&mut txn)?
+            }),
+            remote_access: Some(if let Some(db) = txn.root_db(Root::RemoteAccess as usize) {
+                db
+            } else {
+                btree::create_db_(&mut txn)?
+            }),
+            remote_validators: Some(
+                if let Some(db) = txn.root_db(Root::RemoteValidators as usize) {
+                    db
+                } else {
+                    btree::create_db_(&mut txn)?
+                },
+            ),
+            remote_capabilities: Some(
+                if let Some(db) = txn.root_db(Root::RemoteCapabilities as usize) {
+                    db
+                } else {
+                    btree::create_db_(&mut txn)?
+                },
+            ),
             open_channels: Mutex::new(HashMap::default()),
             open_remotes: Mutex::new(HashMap::default()),
+            path_cache: Mutex::new(PathCache::default()),
+            dep_cache: Mutex::new(DepCache::default()),
+            prefix_cache: Mutex::new(HashMap::default()),
             txn,
             counter: 0,
             cur_channel: None,
@@ -285,9 +591,25 @@ pub struct GenericTxn<T: ::sanakirja::LoadPage<Error = ::sanakirja::Error> + ::s
     partials: UDb<SmallStr, Position<ChangeId>>,
     channels: UDb<SmallStr, SerializedChannel>,
     remotes: UDb<RemoteId, SerializedRemote>,
+    channel_meta: Option<UDb<SmallStr, SmallStr>>,
+    remote_paths: Option<UDb<RemoteId, SmallStr>>,
+    headers: Option<UDb<ChangeId, SmallStr>>,
+    paths: Option<UDb<SmallStr, Pair<ChangeId, Position<ChangeId>>>>,
+    /// The last time each remote was opened for writing, in seconds.
+    remote_access: Option<UDb<RemoteId, L64>>,
+    /// The validator of the cached changelist of each remote.
+    remote_validators: Option<UDb<RemoteId, SmallStr>>,
+    /// The capabilities of each remote, as last advertised by its
+    /// server.
+    remote_capabilities: Option<UDb<RemoteId, SmallStr>>,
 
     pub(crate) open_channels: Mutex<HashMap<SmallString, ChannelRef<Self>>>,
     open_remotes: Mutex<HashMap<RemoteId, RemoteRef<Self>>>,
+    path_cache: Mutex<PathCache>,
+    dep_cache: Mutex<DepCache>,
+    /// The results of `hash_prefix_len`, with the apply counters of
+    /// the channels they were computed for.
+    prefix_cache: Mutex<HashMap<String, (u64, usize)>>,
     counter: usize,
     cur_channel: Option<String>,
 }
@@ -350,6 +672,34 @@ impl Txn {
             debug!("check: tags 0x{:x}", tags.db);
             tags.add_refs(&self.txn, refs).unwrap();
         }
+        if let Some(ref channel_meta) = self.channel_meta {
+            debug!("check: channel_meta 0x{:x}", channel_meta.db);
+            channel_meta.add_refs(&self.txn, refs).unwrap();
+        }
+        if let Some(ref remote_paths) = self.remote_paths {
+            debug!("check: remote_paths 0x{:x}", remote_paths.db);
+            remote_paths.add_refs(&self.txn, refs).unwrap();
+        }
+        if let Some(ref headers) = self.headers {
+            debug!("check: headers 0x{:x}", headers.db);
+            headers.add_refs(&self.txn, refs).unwrap();
+        }
+        if let Some(ref paths) = self.paths {
+            debug!("check: paths 0x{:x}", paths.db);
+            paths.add_refs(&self.txn, refs).unwrap();
+        }
+        if let Some(ref remote_access) = self.remote_access {
+            debug!("check: remote_access 0x{:x}", remote_access.db);
+            remote_access.add_refs(&self.txn, refs).unwrap();
+        }
+        if let Some(ref remote_validators) = self.remote_validators {
+            debug!("check: remote_validators 0x{:x}", remote_validators.db);
+            remote_validators.add_refs(&self.txn, refs).unwrap();
+        }
+        if let Some(ref remote_capabilities) = self.remote_capabilities {
+            debug!("check: remote_capabilities 0x{:x}", remote_capabilities.db);
+            remote_capabilities.add_refs(&self.txn, refs).unwrap();
+        }
         debug!("check: remotes 0x{:x}", self.remotes.db);
         self.remotes.add_refs(&self.txn, refs).unwrap();
         for x in btree::iter(&self.txn, &self.remotes, None).unwrap() {
@@ -376,6 +726,135 @@ impl Txn {
     }
 }
 
+/// The size of a table of the pristine, as returned by
+/// [`GenericTxn::table_stats`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TableStats {
+    /// The name of the table.
+    pub table: &'static str,
+    /// The channel owning this table, for the tables of channels.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    /// The remote owning this table, for the tables of remotes: its
+    /// first known path, or its identifier if it has none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote: Option<String>,
+    /// Number of entries in the table.
+    pub entries: u64,
+    /// Number of pages of the B-tree.
+    pub pages: u64,
+}
+
+impl TableStats {
+    /// The approximate size of the table on disk, in bytes.
+    pub fn bytes(&self) -> u64 {
+        self.pages * PAGE_SIZE
+    }
+}
+
+impl<T: ::sanakirja::LoadPage<Error = ::sanakirja::Error> + ::sanakirja::RootPage> GenericTxn<T> {
+    /// Count the entries and pages of each table of the pristine,
+    /// including the tables of each channel and remote. Pages shared
+    /// between tables (for example between forked channels) are
+    /// counted in each of them.
+    pub fn table_stats(&self) -> Result<Vec<TableStats>, TxnErr<SanakirjaError>> {
+        use ::sanakirja::debug::Check;
+        let mut stats = Vec::new();
+        macro_rules! stats {
+            ($txn: expr, $table: expr, $db: expr, $channel: expr, $remote: expr) => {{
+                let mut refs = std::collections::BTreeMap::new();
+                $db.add_refs($txn, &mut refs)?;
+                let mut entries = 0;
+                for x in btree::iter($txn, $db, None)? {
+                    x?;
+                    entries += 1
+                }
+                stats.push(TableStats {
+                    table: $table,
+                    channel: $channel,
+                    remote: $remote,
+                    entries,
+                    pages: refs.len() as u64,
+                })
+            }};
+            ($txn: expr, $table: expr, $db: expr) => {
+                stats!($txn, $table, $db, None, None)
+            };
+        }
+        let txn = &self.txn;
+        stats!(txn, "internal", &self.internal);
+        stats!(txn, "external", &self.external);
+        stats!(txn, "inodes", &self.inodes);
+        stats!(txn, "revinodes", &self.revinodes);
+        stats!(txn, "tree", &self.tree);
+        stats!(txn, "revtree", &self.revtree);
+        stats!(txn, "dep", &self.dep);
+        stats!(txn, "revdep", &self.revdep);
+        stats!(txn, "touched_files", &self.touched_files);
+        stats!(txn, "rev_touched_files", &self.rev_touched_files);
+        stats!(txn, "partials", &self.partials);
+        stats!(txn, "channels", &self.channels);
+        stats!(txn, "remotes", &self.remotes);
+        if let Some(ref channel_meta) = self.channel_meta {
+            stats!(txn, "channel_meta", channel_meta);
+        }
+        if let Some(ref remote_paths) = self.remote_paths {
+            stats!(txn, "remote_paths", remote_paths);
+        }
+        if let Some(ref headers) = self.headers {
+            stats!(txn, "headers", headers);
+        }
+        if let Some(ref paths) = self.paths {
+            stats!(txn, "paths", paths);
+        }
+        if let Some(ref remote_access) = self.remote_access {
+            stats!(txn, "remote_access", remote_access);
+        }
+        if let Some(ref remote_validators) = self.remote_validators {
+            stats!(txn, "remote_validators", remote_validators);
+        }
+        if let Some(ref remote_capabilities) = self.remote_capabilities {
+            stats!(txn, "remote_capabilities", remote_capabilities);
+        }
+        for x in btree::iter(txn, &self.channels, None)? {
+            let (name, tup) = x?;
+            let name = Some(name.as_str().to_string());
+            let graph: Db<Vertex<ChangeId>, SerializedEdge> = Db::from_page(tup.graph.into());
+            let changes: Db<ChangeId, L64> = Db::from_page(tup.changes.into());
+            let revchanges: UDb<L64, Pair<ChangeId, SerializedMerkle>> =
+                UDb::from_page(tup.revchanges.into());
+            let states: UDb<SerializedMerkle, L64> = UDb::from_page(tup.states.into());
+            let tags: Db<L64, Pair<SerializedMerkle, SerializedMerkle>> =
+                Db::from_page(tup.tags.into());
+            stats!(txn, "graph", &graph, name.clone(), None);
+            stats!(txn, "changes", &changes, name.clone(), None);
+            stats!(txn, "revchanges", &revchanges, name.clone(), None);
+            stats!(txn, "states", &states, name.clone(), None);
+            stats!(txn, "tags", &tags, name, None);
+        }
+        for x in btree::iter(txn, &self.remotes, None)? {
+            let (id, tup) = x?;
+            let name = Some(
+                self.remote_paths(id)?
+                    .into_iter()
+                    .next()
+                    .unwrap_or_else(|| id.to_string()),
+            );
+            let remote: UDb<L64, Pair<SerializedHash, SerializedMerkle>> =
+                UDb::from_page(tup.remote.into());
+            let rev: UDb<SerializedHash, L64> = UDb::from_page(tup.rev.into());
+            let states: UDb<SerializedMerkle, L64> = UDb::from_page(tup.states.into());
+            let tags: Db<L64, Pair<SerializedMerkle, SerializedMerkle>> =
+                Db::from_page(tup.tags.into());
+            stats!(txn, "remote", &remote, None, name.clone());
+            stats!(txn, "rev", &rev, None, name.clone());
+            stats!(txn, "states", &states, None, name.clone());
+            stats!(txn, "tags", &tags, None, name);
+        }
+        Ok(stats)
+    }
+}
+
 impl<T: ::sanakirja::LoadPage<Error = ::sanakirja::Error> + ::sanakirja::RootPage> GraphTxnT
     for GenericTxn<T>
 {
@@ -1073,6 +1552,56 @@ impl<T: ::sanakirja::LoadPage<Error = ::sanakirja::Error> + ::sanakirja::RootPag
         P<ChangeId, Position<ChangeId>>,
     >;
     sanakirja_iter!(rev_touched_files, ChangeId, Position<ChangeId>);
+
+    fn get_cached_header(
+        &self,
+        change: &ChangeId,
+    ) -> Result<Option<CachedHeader>, TxnErr<Self::DepsError>> {
+        let db = if let Some(ref db) = self.headers {
+            db
+        } else {
+            return Ok(None);
+        };
+        match btree::get(&self.txn, db, change, None)? {
+            Some((change_, h)) if change_ == change => {
+                if let Ok(h) = serde_json::from_str(h.as_str()) {
+                    Ok(Some(h))
+                } else {
+                    Err(TxnErr(SanakirjaError::PristineCorrupt))
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn get_path_inodes(
+        &self,
+        path: &str,
+    ) -> Result<Vec<(ChangeId, Position<ChangeId>)>, TxnErr<Self::DepsError>> {
+        let db = if let Some(ref db) = self.paths {
+            db
+        } else {
+            return Ok(Vec::new());
+        };
+        if path.len() > crate::small_string::MAX_LENGTH {
+            return Ok(Vec::new());
+        }
+        let k = SmallString::from_str(path);
+        let mut result = Vec::new();
+        for x in btree::iter(&self.txn, db, Some((&k, None)))? {
+            let (k_, v) = x?;
+            if k_.as_str() != path {
+                break;
+            }
+            result.push((v.a, v.b))
+        }
+        Ok(result)
+    }
+
+    fn dep_cache(&self) -> Option<&Mutex<DepCache>> {
+        Some(&self.dep_cache)
+    }
+
     fn iter_revdep(
         &self,
         k: &ChangeId,
@@ -1177,6 +1706,10 @@ impl<T: ::sanakirja::LoadPage<Error = ::sanakirja::Error> + ::sanakirja::RootPag
         let k0 = SmallString::from_str(k);
         self.cursor_partials(&self.partials, Some((&k0, None)))
     }
+
+    fn path_cache(&self) -> Option<&Mutex<PathCache>> {
+        Some(&self.path_cache)
+    }
 }
 
 impl<T: ::sanakirja::LoadPage<Error = ::sanakirja::Error> + ::sanakirja::RootPage> GenericTxn<T> {
@@ -1250,6 +1783,36 @@ impl<T: ::sanakirja::LoadPage<Error = ::sanakirja::Error> + ::sanakirja::RootPag
         }
     }
 
+    fn hash_prefix_len(&self, channel: &Self::Channel) -> Result<usize, TxnErr<Self::GraphError>> {
+        let counter = self.apply_counter(channel);
+        if let Some(&(c, len)) = self.prefix_cache.lock().get(self.name(channel)) {
+            if c == counter {
+                return Ok(len);
+            }
+        }
+        let mut hashes = Vec::new();
+        for x in btree::iter(&self.txn, &self.internal, None)? {
+            let (h, id) = x?;
+            let h: Hash = h.into();
+            if let Hash::None = h {
+                continue;
+            }
+            hashes.push((h.to_base32(), *id))
+        }
+        hashes.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        let (b32, ids): (Vec<_>, Vec<_>) = hashes.into_iter().unzip();
+        let mut len = 0;
+        for (l, id) in super::unambiguous_prefix_lens(&b32).into_iter().zip(ids) {
+            if l > len && self.get_changeset(self.changes(channel), &id)?.is_some() {
+                len = l
+            }
+        }
+        self.prefix_cache
+            .lock()
+            .insert(self.name(channel).to_string(), (counter, len));
+        Ok(len)
+    }
+
     fn state_from_prefix(
         &self,
         channel: &Self::States,
@@ -1438,6 +2001,97 @@ impl<T: ::sanakirja::LoadPage<Error = ::sanakirja::Error> + ::sanakirja::RootPag
         Ok(RemotesIterator { cursor, txn: self })
     }
 
+    fn channel_metadata(
+        &self,
+        name: &str,
+    ) -> Result<Option<ChannelMetadata>, TxnErr<Self::GraphError>> {
+        let db = if let Some(ref db) = self.channel_meta {
+            db
+        } else {
+            return Ok(None);
+        };
+        let name = SmallString::from_str(name);
+        let mut json = String::new();
+        for x in btree::iter(&self.txn, db, Some((&name, None)))? {
+            let (name_, chunk) = x?;
+            if name_ != name.as_ref() {
+                break;
+            }
+            let chunk = chunk.as_str();
+            if chunk.starts_with('{') {
+                // Written by a version that didn't split metadata.
+                json.push_str(chunk)
+            } else {
+                json.push_str(chunk.get(2..).unwrap_or(""))
+            }
+        }
+        if json.is_empty() {
+            return Ok(None);
+        }
+        if let Ok(meta) = serde_json::from_str(&json) {
+            Ok(Some(meta))
+        } else {
+            Err(TxnErr(SanakirjaError::PristineCorrupt))
+        }
+    }
+
+    fn remote_paths(&self, id: &RemoteId) -> Result<Vec<String>, TxnErr<Self::GraphError>> {
+        let db = if let Some(ref db) = self.remote_paths {
+            db
+        } else {
+            return Ok(Vec::new());
+        };
+        let mut paths = Vec::new();
+        for x in btree::iter(&self.txn, db, Some((id, None)))? {
+            let (id_, path) = x?;
+            if id_ > id {
+                break;
+            } else if id_ == id {
+                paths.push(path.as_str().to_string())
+            }
+        }
+        Ok(paths)
+    }
+
+    fn remote_last_used(&self, id: &RemoteId) -> Result<Option<u64>, TxnErr<Self::GraphError>> {
+        let db = if let Some(ref db) = self.remote_access {
+            db
+        } else {
+            return Ok(None);
+        };
+        match btree::get(&self.txn, db, id, None)? {
+            Some((id_, t)) if id_ == id => Ok(Some((*t).into())),
+            _ => Ok(None),
+        }
+    }
+
+    fn remote_validator(&self, id: &RemoteId) -> Result<Option<String>, TxnErr<Self::GraphError>> {
+        let db = if let Some(ref db) = self.remote_validators {
+            db
+        } else {
+            return Ok(None);
+        };
+        match btree::get(&self.txn, db, id, None)? {
+            Some((id_, v)) if id_ == id => Ok(Some(v.as_str().to_string())),
+            _ => Ok(None),
+        }
+    }
+
+    fn remote_capabilities(
+        &self,
+        id: &RemoteId,
+    ) -> Result<Option<String>, TxnErr<Self::GraphError>> {
+        let db = if let Some(ref db) = self.remote_capabilities {
+            db
+        } else {
+            return Ok(None);
+        };
+        match btree::get(&self.txn, db, id, None)? {
+            Some((id_, v)) if id_ == id => Ok(Some(v.as_str().to_string())),
+            _ => Ok(None),
+        }
+    }
+
     type Remote = UDb<L64, Pair<SerializedHash, SerializedMerkle>>;
     type Revremote = UDb<SerializedHash, L64>;
     type Remotestates = UDb<SerializedMerkle, L64>;
@@ -1635,7 +2289,23 @@ impl GraphMutTxnT for MutTxn<()> {
         );
     }
 
-    sanakirja_put_del!(internal, SerializedHash, ChangeId, GraphError);
+    fn put_internal(
+        &mut self,
+        k: &SerializedHash,
+        v: &ChangeId,
+    ) -> Result<bool, TxnErr<Self::GraphError>> {
+        self.prefix_cache.get_mut().clear();
+        Ok(btree::put(&mut self.txn, &mut self.internal, k, v)?)
+    }
+
+    fn del_internal(
+        &mut self,
+        k: &SerializedHash,
+        v: Option<&ChangeId>,
+    ) -> Result<bool, TxnErr<Self::GraphError>> {
+        self.prefix_cache.get_mut().clear();
+        Ok(btree::del(&mut self.txn, &mut self.internal, k, v)?)
+    }
     sanakirja_put_del!(external, ChangeId, SerializedHash, GraphError);
 
     fn split_block(
@@ -1851,6 +2521,35 @@ impl ChannelMutTxnT for MutTxn<()> {
         replay_tags(self, channel, t.into(), &mut Vec::new())?;
         Ok(())
     }
+
+    fn truncate_channel(
+        &mut self,
+        channel: &mut Self::Channel,
+        t: ApplyTimestamp,
+    ) -> Result<Vec<(ApplyTimestamp, ChangeId)>, TxnErr<Self::GraphError>> {
+        let tl: L64 = t.into();
+        let mut removed = Vec::new();
+        for x in btree::rev_iter(&self.txn, &channel.revchanges, None)? {
+            let (t_, p) = x?;
+            if *t_ < tl {
+                break;
+            }
+            removed.push((*t_, p.a, p.b))
+        }
+        // Removing from the end doesn't change the states of the
+        // remaining changes and tags.
+        for (t_, p, m) in removed.iter() {
+            debug!("truncate_channel {:?} {:?}", t_, p);
+            btree::del(&mut self.txn, &mut channel.revchanges, t_, None)?;
+            btree::del(&mut self.txn, &mut channel.states, m, None)?;
+            btree::del(&mut self.txn, &mut channel.changes, p, None)?;
+            btree::del(&mut self.txn, &mut channel.tags, t_, None)?;
+        }
+        let graph = btree::create_db_(&mut self.txn)?;
+        let graph = std::mem::replace(&mut channel.graph, graph);
+        btree::drop(&mut self.txn, graph)?;
+        Ok(removed.into_iter().map(|(t, p, _)| (t.into(), p)).collect())
+    }
 }
 
 fn replay_tags(
@@ -1886,19 +2585,128 @@ fn replay_tags(
 }
 
 impl DepsMutTxnT for MutTxn<()> {
-    sanakirja_put_del!(dep, ChangeId, ChangeId, DepsError);
-    sanakirja_put_del!(revdep, ChangeId, ChangeId, DepsError);
+    fn put_dep(&mut self, k: &ChangeId, v: &ChangeId) -> Result<bool, TxnErr<Self::DepsError>> {
+        self.dep_cache.get_mut().clear();
+        Ok(btree::put(&mut self.txn, &mut self.dep, k, v)?)
+    }
+
+    fn del_dep(
+        &mut self,
+        k: &ChangeId,
+        v: Option<&ChangeId>,
+    ) -> Result<bool, TxnErr<Self::DepsError>> {
+        self.dep_cache.get_mut().clear();
+        Ok(btree::del(&mut self.txn, &mut self.dep, k, v)?)
+    }
+
+    fn put_revdep(&mut self, k: &ChangeId, v: &ChangeId) -> Result<bool, TxnErr<Self::DepsError>> {
+        self.dep_cache.get_mut().clear();
+        Ok(btree::put(&mut self.txn, &mut self.revdep, k, v)?)
+    }
+
+    fn del_revdep(
+        &mut self,
+        k: &ChangeId,
+        v: Option<&ChangeId>,
+    ) -> Result<bool, TxnErr<Self::DepsError>> {
+        self.dep_cache.get_mut().clear();
+        Ok(btree::del(&mut self.txn, &mut self.revdep, k, v)?)
+    }
+
     sanakirja_put_del!(touched_files, Position<ChangeId>, ChangeId, DepsError);
     sanakirja_put_del!(rev_touched_files, ChangeId, Position<ChangeId>, DepsError);
+
+    fn put_cached_header(
+        &mut self,
+        change: &ChangeId,
+        header: &CachedHeader,
+    ) -> Result<(), TxnErr<Self::DepsError>> {
+        self.del_cached_header(change)?;
+        let mut header = header.clone();
+        let mut len = header.message.len().min(crate::small_string::MAX_LENGTH);
+        while !header.message.is_char_boundary(len) {
+            len -= 1
+        }
+        header.message.truncate(len);
+        let mut s = serde_json::to_string(&header).unwrap();
+        // Shorten the message until the header fits, one character
+        // at a time since escaping makes the JSON length hard to
+        // predict.
+        while s.len() > crate::small_string::MAX_LENGTH {
+            if header.message.pop().is_none() {
+                debug!("header of {:?} too long to be cached", change);
+                return Ok(());
+            }
+            s = serde_json::to_string(&header).unwrap();
+        }
+        let s = SmallString::from_str(&s);
+        btree::put(&mut self.txn, self.headers.as_mut().unwrap(), change, &s)?;
+        Ok(())
+    }
+
+    fn del_cached_header(&mut self, change: &ChangeId) -> Result<(), TxnErr<Self::DepsError>> {
+        btree::del(&mut self.txn, self.headers.as_mut().unwrap(), change, None)?;
+        Ok(())
+    }
+
+    fn put_path_inode(
+        &mut self,
+        path: &str,
+        change: &ChangeId,
+        inode: &Position<ChangeId>,
+    ) -> Result<(), TxnErr<Self::DepsError>> {
+        if path.len() > crate::small_string::MAX_LENGTH {
+            debug!("path {:?} too long to be indexed", path);
+            return Ok(());
+        }
+        let k = SmallString::from_str(path);
+        let v = Pair {
+            a: *change,
+            b: *inode,
+        };
+        btree::put(&mut self.txn, self.paths.as_mut().unwrap(), &k, &v)?;
+        Ok(())
+    }
+
+    fn del_path_inode(
+        &mut self,
+        path: &str,
+        change: &ChangeId,
+        inode: &Position<ChangeId>,
+    ) -> Result<(), TxnErr<Self::DepsError>> {
+        if path.len() > crate::small_string::MAX_LENGTH {
+            return Ok(());
+        }
+        let k = SmallString::from_str(path);
+        let v = Pair {
+            a: *change,
+            b: *inode,
+        };
+        btree::del(&mut self.txn, self.paths.as_mut().unwrap(), &k, Some(&v))?;
+        Ok(())
+    }
 }
 
 impl TreeMutTxnT for MutTxn<()> {
     sanakirja_put_del!(inodes, Inode, Position<ChangeId>, TreeError, TreeErr);
     sanakirja_put_del!(revinodes, Position<ChangeId>, Inode, TreeError, TreeErr);
 
-    sanakirja_put_del!(tree, PathId, Inode, TreeError, TreeErr);
     sanakirja_put_del!(revtree, Inode, PathId, TreeError, TreeErr);
 
+    fn put_tree(&mut self, k: &PathId, v: &Inode) -> Result<bool, TreeErr<Self::TreeError>> {
+        self.path_cache.get_mut().clear();
+        Ok(btree::put(&mut self.txn, &mut self.tree, k, v).map_err(TreeErr)?)
+    }
+
+    fn del_tree(
+        &mut self,
+        k: &PathId,
+        v: Option<&Inode>,
+    ) -> Result<bool, TreeErr<Self::TreeError>> {
+        self.path_cache.get_mut().clear();
+        Ok(btree::del(&mut self.txn, &mut self.tree, k, v).map_err(TreeErr)?)
+    }
+
     fn put_partials(
         &mut self,
         k: &str,
@@ -2090,6 +2898,9 @@ impl MutTxnT for MutTxn<()> {
                     None,
                 )
                 .map_err(|e| ForkError::Txn(e.into()))?;
+                let old_name = channel.r.read().name.clone();
+                self.move_channel_metadata(&old_name, Some(&name))
+                    .map_err(ForkError::Txn)?;
                 std::mem::drop(
                     self.open_channels
                         .lock()
@@ -2135,6 +2946,7 @@ impl MutTxnT for MutTxn<()> {
             None
         };
         btree::del(&mut self.txn, &mut self.channels, &name, None)?;
+        self.move_channel_metadata(&name, None)?;
         if let Some((a, b, c, d, e)) = channel {
             let mut unused_changes = Vec::new();
             'outer: for x in btree::rev_iter(&self.txn, &c, None)? {
@@ -2167,6 +2979,9 @@ impl MutTxnT for MutTxn<()> {
                 debug!(target: "drop_channel", "actually unused: {:?}", p);
                 unused_changes.push(p.a);
             }
+            if !unused_changes.is_empty() {
+                self.dep_cache.get_mut().clear();
+            }
             let mut deps = Vec::new();
             for ch in unused_changes.iter() {
                 for x in btree::iter(&self.txn, &self.dep, Some((ch, None)))? {
@@ -2193,6 +3008,54 @@ impl MutTxnT for MutTxn<()> {
         }
     }
 
+    fn set_channel_metadata(
+        &mut self,
+        name: &str,
+        meta: &ChannelMetadata,
+    ) -> Result<(), ChannelMetadataError<Self::GraphError>> {
+        let name = SmallString::from_str(name);
+        let meta = if meta.is_empty() {
+            Vec::new()
+        } else {
+            let meta = serde_json::to_string(meta).unwrap();
+            let chunks = metadata_chunks(&meta);
+            if chunks.len() > 0x100 {
+                return Err(ChannelMetadataError::TooLong(meta.len()));
+            }
+            chunks
+        };
+        let db = self.channel_meta.as_mut().unwrap();
+        while btree::del(&mut self.txn, db, &name, None)
+            .map_err(|e| ChannelMetadataError::Txn(e.into()))?
+        {}
+        for chunk in meta.iter() {
+            btree::put(&mut self.txn, db, &name, chunk)
+                .map_err(|e| ChannelMetadataError::Txn(e.into()))?;
+        }
+        Ok(())
+    }
+
+    fn set_remote_paths(
+        &mut self,
+        id: &RemoteId,
+        paths: &[String],
+    ) -> Result<(), RemotePathsError<Self::GraphError>> {
+        if let Some(p) = paths
+            .iter()
+            .find(|p| p.len() > crate::small_string::MAX_LENGTH)
+        {
+            return Err(RemotePathsError::TooLong(p.clone()));
+        }
+        self.del_remote_paths(id)
+            .map_err(|e| RemotePathsError::Txn(e.into()))?;
+        let db = self.remote_paths.as_mut().unwrap();
+        for p in paths {
+            let p = SmallString::from_str(p);
+            btree::put(&mut self.txn, db, id, &p).map_err(|e| RemotePathsError::Txn(e.into()))?;
+        }
+        Ok(())
+    }
+
     fn open_or_create_remote(
         &mut self,
         id: RemoteId,
@@ -2236,21 +3099,80 @@ impl MutTxnT for MutTxn<()> {
         if let Some(commit) = commit {
             self.put_remotes(commit)?;
         }
+        self.touch_remote(&id, None).map_err(|e| e.0)?;
         Ok(self.open_remotes.lock().get(&id).unwrap().clone())
     }
 
+    fn touch_remote(
+        &mut self,
+        id: &RemoteId,
+        t: Option<u64>,
+    ) -> Result<(), TxnErr<Self::GraphError>> {
+        use std::time::SystemTime;
+        let t = if let Some(t) = t {
+            t
+        } else if let Ok(duration) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            duration.as_secs()
+        } else {
+            return Ok(());
+        };
+        let db = self.remote_access.as_mut().unwrap();
+        btree::del(&mut self.txn, db, id, None)?;
+        btree::put(&mut self.txn, db, id, &L64::from(t))?;
+        Ok(())
+    }
+
+    fn set_remote_validator(
+        &mut self,
+        id: &RemoteId,
+        validator: Option<&str>,
+    ) -> Result<(), TxnErr<Self::GraphError>> {
+        let db = self.remote_validators.as_mut().unwrap();
+        btree::del(&mut self.txn, db, id, None)?;
+        if let Some(v) = validator {
+            if v.len() <= crate::small_string::MAX_LENGTH {
+                btree::put(&mut self.txn, db, id, &SmallString::from_str(v))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn set_remote_capabilities(
+        &mut self,
+        id: &RemoteId,
+        capabilities: Option<&str>,
+    ) -> Result<(), TxnErr<Self::GraphError>> {
+        let db = self.remote_capabilities.as_mut().unwrap();
+        btree::del(&mut self.txn, db, id, None)?;
+        if let Some(c) = capabilities {
+            if c.len() <= crate::small_string::MAX_LENGTH {
+                btree::put(&mut self.txn, db, id, &SmallString::from_str(c))?;
+            }
+        }
+        Ok(())
+    }
+
     fn drop_remote(&mut self, remote: RemoteRef<Self>) -> Result<bool, Self::GraphError> {
         let r = self.open_remotes.lock().remove(&remote.id).unwrap();
         std::mem::drop(remote);
-        assert_eq!(Arc::strong_count(&r.db), 1);
-        Ok(btree::del(&mut self.txn, &mut self.remotes, &r.id, None)?)
+        let id = r.id;
+        let r = Arc::try_unwrap(r.db)
+            .unwrap_or_else(|_| panic!("Remote {:?} is still in use", id))
+            .into_inner();
+        self.del_remote_entry(&id, Some(r))
     }
 
     fn drop_named_remote(&mut self, id: RemoteId) -> Result<bool, Self::GraphError> {
-        if let Some(r) = self.open_remotes.lock().remove(&id) {
-            assert_eq!(Arc::strong_count(&r.db), 1);
-        }
-        Ok(btree::del(&mut self.txn, &mut self.remotes, &id, None)?)
+        let r = if let Some(r) = self.open_remotes.lock().remove(&id) {
+            Some(
+                Arc::try_unwrap(r.db)
+                    .unwrap_or_else(|_| panic!("Remote {:?} is still in use", id))
+                    .into_inner(),
+            )
+        } else {
+            None
+        };
+        self.del_remote_entry(&id, r)
     }
 
     fn commit(mut self) -> Result<(), Self::GraphError> {
@@ -2312,6 +3234,32 @@ impl MutTxnT for MutTxn<()> {
         self.txn
             .set_root(Root::RevTouchedFiles as usize, self.rev_touched_files.db);
         self.txn.set_root(Root::Partials as usize, self.partials.db);
+        if let Some(ref channel_meta) = self.channel_meta {
+            self.txn
+                .set_root(Root::ChannelMeta as usize, channel_meta.db);
+        }
+        if let Some(ref remote_paths) = self.remote_paths {
+            self.txn
+                .set_root(Root::RemotePaths as usize, remote_paths.db);
+        }
+        if let Some(ref headers) = self.headers {
+            self.txn.set_root(Root::Headers as usize, headers.db);
+        }
+        if let Some(ref remote_access) = self.remote_access {
+            self.txn
+                .set_root(Root::RemoteAccess as usize, remote_access.db);
+        }
+        if let Some(ref remote_validators) = self.remote_validators {
+            self.txn
+                .set_root(Root::RemoteValidators as usize, remote_validators.db);
+        }
+        if let Some(ref remote_capabilities) = self.remote_capabilities {
+            self.txn
+                .set_root(Root::RemoteCapabilities as usize, remote_capabilities.db);
+        }
+        if let Some(ref paths) = self.paths {
+            self.txn.set_root(Root::Paths as usize, paths.db);
+        }
         self.txn.commit()?;
         Ok(())
     }
@@ -2374,6 +3322,86 @@ impl<T> MutTxn<T> {
         Ok(())
     }
 
+    /// Delete remote `id` and its tables, which are those of `open`
+    /// if the remote was open in this transaction.
+    fn del_remote_entry(
+        &mut self,
+        id: &RemoteId,
+        open: Option<Remote<Self>>,
+    ) -> Result<bool, SanakirjaError> {
+        let tables = if let Some(r) = open {
+            Some((r.remote, r.rev, r.states, r.tags))
+        } else {
+            match btree::get(&self.txn, &self.remotes, id, None)? {
+                Some((id_, r)) if id_ == id => Some((
+                    UDb::from_page(r.remote.into()),
+                    UDb::from_page(r.rev.into()),
+                    UDb::from_page(r.states.into()),
+                    Db::from_page(r.tags.into()),
+                )),
+                _ => None,
+            }
+        };
+        self.del_remote_paths(id)?;
+        if let Some(ref mut db) = self.remote_access {
+            btree::del(&mut self.txn, db, id, None)?;
+        }
+        if let Some(ref mut db) = self.remote_validators {
+            btree::del(&mut self.txn, db, id, None)?;
+        }
+        if let Some(ref mut db) = self.remote_capabilities {
+            btree::del(&mut self.txn, db, id, None)?;
+        }
+        let deleted = btree::del(&mut self.txn, &mut self.remotes, id, None)?;
+        if let Some((a, b, c, d)) = tables {
+            btree::drop(&mut self.txn, a)?;
+            btree::drop(&mut self.txn, b)?;
+            btree::drop(&mut self.txn, c)?;
+            btree::drop(&mut self.txn, d)?;
+        }
+        Ok(deleted)
+    }
+
+    /// Delete the paths the cache of remote `id` is restricted to.
+    fn del_remote_paths(&mut self, id: &RemoteId) -> Result<(), SanakirjaError> {
+        let db = if let Some(ref mut db) = self.remote_paths {
+            db
+        } else {
+            return Ok(());
+        };
+        while btree::del(&mut self.txn, db, id, None)? {}
+        Ok(())
+    }
+
+    /// Move the metadata of channel `from` to channel `to`, or delete
+    /// it if `to` is `None`.
+    fn move_channel_metadata(
+        &mut self,
+        from: &SmallStr,
+        to: Option<&SmallStr>,
+    ) -> Result<(), SanakirjaError> {
+        let db = if let Some(ref mut db) = self.channel_meta {
+            db
+        } else {
+            return Ok(());
+        };
+        let mut meta = Vec::new();
+        for x in btree::iter(&self.txn, db, Some((from, None)))? {
+            let (from_, chunk) = x?;
+            if from_ != from {
+                break;
+            }
+            meta.push(chunk.to_owned())
+        }
+        while btree::del(&mut self.txn, db, from, None)? {}
+        if let Some(to) = to {
+            for chunk in meta.iter() {
+                btree::put(&mut self.txn, db, to, chunk)?;
+            }
+        }
+        Ok(())
+    }
+
     fn commit_channel(&mut self, channel: ChannelRef<Self>) -> Result<(), SanakirjaError> {
         std::mem::drop(self.open_channels.lock().remove(&channel.r.read().name));
         self.put_channel(channel)
@@ -5,14 +5,21 @@ pub use crate::diff::Algorithm;
 use crate::path::{components, Components};
 use crate::pristine::*;
 use crate::small_string::SmallString;
-use crate::working_copy::WorkingCopyRead;
-use crate::{alive::retrieve, text_encoding::Encoding};
+use crate::working_copy::{PathFilter, WorkingCopyRead};
+use crate::alive::{retrieve_limited, RetrieveError, RetrieveLimits};
+use crate::text_encoding::Encoding;
 use crate::{change::*, changestore::FileMetadata};
 use crate::{HashMap, HashSet};
 use parking_lot::Mutex;
 use std::collections::VecDeque;
 use std::sync::Arc;
 
+mod arena;
+pub use arena::ContentArena;
+
+mod summary;
+pub use summary::{ActionKind, ActionSummary};
+
 #[derive(Error)]
 pub enum RecordError<C: std::error::Error + 'static, W: std::error::Error, T: GraphTxnT + TreeTxnT>
 {
@@ -32,6 +39,44 @@ pub enum RecordError<C: std::error::Error + 'static, W: std::error::Error, T: Gr
     PathNotInRepo(String),
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    #[error("Recording cancelled")]
+    Cancelled,
+    #[error(transparent)]
+    Contents(#[from] ContentsError),
+    #[error("Invalid path: {0}")]
+    InvalidPath(String),
+}
+
+/// Errors turning a record into a change, see
+/// [`Recorded::into_change_no_deps`].
+#[derive(Debug, Error)]
+pub enum IntoChangeError<E: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(#[from] TxnErr<E>),
+    #[error(transparent)]
+    Contents(#[from] ContentsError),
+}
+
+/// Errors getting the contents of a record.
+#[derive(Debug, Error)]
+pub enum ContentsError {
+    /// The contents are still shared with another copy of the record
+    /// (for instance with a recording thread that hasn't finished).
+    #[error("The contents of the record are still in use")]
+    Shared,
+    /// The contents written to disk while recording could not be
+    /// read back.
+    #[error(transparent)]
+    Io(std::io::Error),
+}
+
+/// Error returned by [`Recorded::set_action_authors`] when the
+/// record has no action with that number.
+#[derive(Debug, Error)]
+#[error("No action number {action} in this record ({len} actions)")]
+pub struct NoSuchAction {
+    pub action: usize,
+    pub len: usize,
 }
 
 impl<C: std::error::Error, W: std::error::Error, T: GraphTxnT + TreeTxnT> std::fmt::Debug
@@ -47,6 +92,9 @@ impl<C: std::error::Error, W: std::error::Error, T: GraphTxnT + TreeTxnT> std::f
             RecordError::Diff(e) => std::fmt::Debug::fmt(e, fmt),
             RecordError::PathNotInRepo(p) => write!(fmt, "Path not in repository: {}", p),
             RecordError::Io(e) => std::fmt::Debug::fmt(e, fmt),
+            RecordError::Cancelled => write!(fmt, "Recording cancelled"),
+            RecordError::Contents(e) => std::fmt::Debug::fmt(e, fmt),
+            RecordError::InvalidPath(p) => write!(fmt, "Invalid path: {}", p),
         }
     }
 }
@@ -71,7 +119,21 @@ pub struct Builder {
     deleted_vertices: Arc<Mutex<HashSet<Position<ChangeId>>>>,
     pub force_rediff: bool,
     pub ignore_missing: bool,
-    pub contents: Arc<Mutex<Vec<u8>>>,
+    /// Don't diff files larger than this many bytes.
+    pub max_file_size: Option<u64>,
+    /// Separators overriding the one passed to `record` for some
+    /// files.
+    pub separators: diff::Separators,
+    /// Files recorded as binary or as text regardless of their
+    /// detected encoding.
+    pub file_classes: diff::FileClasses,
+    /// Budget for retrieving the current version of each modified
+    /// file. Files exceeding it are not diffed, and cancelling it
+    /// stops the record with [`RecordError::Cancelled`].
+    pub retrieve_limits: RetrieveLimits,
+    /// Paths left out of the record, as if they were not tracked.
+    pub path_filter: Option<PathFilter>,
+    pub contents: Arc<Mutex<ContentArena>>,
     new_root: Arc<Mutex<Option<(Position<Option<ChangeId>>, u64)>>>,
 }
 
@@ -86,7 +148,7 @@ struct Parent {
 /// The result of recording a change:
 pub struct Recorded {
     /// The "byte contents" of the change.
-    pub contents: Arc<Mutex<Vec<u8>>>,
+    pub contents: Arc<Mutex<ContentArena>>,
     /// The current records, to be lated converted into change operations.
     pub actions: Vec<Hunk<Option<ChangeId>, LocalByte>>,
     /// The updates that need to be made to the ~tree~ and ~revtree~
@@ -101,8 +163,19 @@ pub struct Recorded {
     pub oldest_change: std::time::SystemTime,
     /// Redundant edges found during the comparison.
     pub redundant: Vec<crate::alive::Redundant>,
+    /// Modified files that were not diffed because they are larger
+    /// than `Builder::max_file_size`, or because their graph exceeds
+    /// `Builder::retrieve_limits`.
+    pub skipped_large_files: Vec<String>,
+    /// Authors of individual actions, as indices into the authors of
+    /// the header of the change, set with `set_action_authors`.
+    pub hunk_authors: HunkAuthors,
     /// Force a re-diff
     force_rediff: bool,
+    max_file_size: Option<u64>,
+    separators: diff::Separators,
+    file_classes: diff::FileClasses,
+    retrieve_limits: RetrieveLimits,
     deleted_vertices: Arc<Mutex<HashSet<Position<ChangeId>>>>,
     recorded_inodes: Arc<Mutex<HashMap<Inode, Position<Option<ChangeId>>>>>,
     new_root: Arc<Mutex<Option<(Position<Option<ChangeId>>, u64)>>>,
@@ -115,8 +188,13 @@ impl Default for Builder {
             recorded_inodes: Arc::new(Mutex::new(HashMap::default())),
             force_rediff: false,
             ignore_missing: false,
+            max_file_size: None,
+            separators: diff::Separators::default(),
+            file_classes: diff::FileClasses::default(),
+            retrieve_limits: RetrieveLimits::default(),
+            path_filter: None,
             deleted_vertices: Arc::new(Mutex::new(HashSet::default())),
-            contents: Arc::new(Mutex::new(Vec::new())),
+            contents: Arc::new(Mutex::new(ContentArena::new())),
             new_root: Arc::new(Mutex::new(None)),
         }
     }
@@ -128,6 +206,15 @@ impl Builder {
         Self::default()
     }
 
+    /// A builder keeping at most approximately `max_memory` bytes of
+    /// new contents in memory, writing the rest to a temporary file
+    /// (with the `tempfile` feature) until the change is made.
+    pub fn with_max_memory(max_memory: usize) -> Self {
+        let builder = Self::default();
+        builder.contents.lock().set_max_memory(Some(max_memory));
+        builder
+    }
+
     pub fn recorded(&mut self) -> Arc<Mutex<Recorded>> {
         let m = Arc::new(Mutex::new(self.recorded_()));
         self.rec.push(m.clone());
@@ -143,7 +230,13 @@ impl Builder {
             has_binary_files: false,
             oldest_change: std::time::SystemTime::UNIX_EPOCH,
             redundant: Vec::new(),
+            skipped_large_files: Vec::new(),
+            hunk_authors: HunkAuthors::default(),
             force_rediff: self.force_rediff,
+            max_file_size: self.max_file_size,
+            separators: self.separators.clone(),
+            file_classes: self.file_classes.clone(),
+            retrieve_limits: self.retrieve_limits.clone(),
             deleted_vertices: self.deleted_vertices.clone(),
             recorded_inodes: self.recorded_inodes.clone(),
             new_root: self.new_root.clone(),
@@ -172,6 +265,9 @@ impl Builder {
             for (a, b) in rec.updatables {
                 result.updatables.insert(a + off, b);
             }
+            for (a, b) in rec.hunk_authors.hunk_authors {
+                result.hunk_authors.set(a + off, b);
+            }
             result.largest_file = result.largest_file.max(rec.largest_file);
             result.has_binary_files |= rec.has_binary_files;
             if result.oldest_change == std::time::UNIX_EPOCH
@@ -180,7 +276,10 @@ impl Builder {
             {
                 result.oldest_change = rec.oldest_change
             }
-            result.redundant.extend(rec.redundant.into_iter())
+            result.redundant.extend(rec.redundant.into_iter());
+            result
+                .skipped_large_files
+                .extend(rec.skipped_large_files.into_iter())
         }
         debug!(
             "result = {:?}, updatables = {:?}",
@@ -190,6 +289,192 @@ impl Builder {
     }
 }
 
+/// A new line that looks like a conflict marker output by Pijul,
+/// usually left in a file after an incomplete conflict resolution.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConflictMarker {
+    pub path: String,
+    /// Line number in the file, starting from 1.
+    pub line: usize,
+}
+
+/// Does `line` start like a conflict marker, i.e. with a marker
+/// followed by the number of the conflict?
+fn is_conflict_marker(line: &[u8]) -> bool {
+    use crate::vertex_buffer::{END_MARKER, SEPARATOR, START_MARKER};
+    [START_MARKER, SEPARATOR, END_MARKER].iter().any(|m| {
+        line.starts_with(m.as_bytes())
+            && line[m.len()..].starts_with(b" ")
+            && line
+                .get(m.len() + 1)
+                .map(|c| c.is_ascii_digit())
+                .unwrap_or(false)
+    })
+}
+
+impl Recorded {
+    /// Attribute action number `action` of `self.actions` to the
+    /// authors at indices `authors` in the header of the change
+    /// (instead of all of them, which is the default).
+    pub fn set_action_authors(
+        &mut self,
+        action: usize,
+        authors: Vec<usize>,
+    ) -> Result<(), NoSuchAction> {
+        if action >= self.actions.len() {
+            return Err(NoSuchAction {
+                action,
+                len: self.actions.len(),
+            });
+        }
+        self.hunk_authors.set(action, authors);
+        Ok(())
+    }
+
+    /// Find the lines added by this record that look like conflict
+    /// markers, in the order of the actions.
+    pub fn conflict_markers(&self) -> Result<Vec<ConflictMarker>, std::io::Error> {
+        let mut contents = self.contents.lock();
+        let mut markers = Vec::new();
+        for action in self.actions.iter() {
+            let (new_vertex, path, first_line) = match action {
+                Hunk::Edit {
+                    change: Atom::NewVertex(ref n),
+                    ref local,
+                    ..
+                }
+                | Hunk::Replacement {
+                    replacement: Atom::NewVertex(ref n),
+                    ref local,
+                    ..
+                } => (n, &local.path, local.line),
+                Hunk::FileAdd {
+                    contents: Some(Atom::NewVertex(ref n)),
+                    ref path,
+                    ..
+                } => (n, path, 1),
+                _ => continue,
+            };
+            let bytes = contents.read_range(new_vertex.start.us(), new_vertex.end.us())?;
+            for (i, line) in bytes.split(|c| *c == b'\n').enumerate() {
+                if is_conflict_marker(line) {
+                    markers.push(ConflictMarker {
+                        path: path.clone(),
+                        line: first_line + i,
+                    })
+                }
+            }
+        }
+        Ok(markers)
+    }
+
+    /// Turn this record into a change with header `header` and no
+    /// dependencies, without saving it. Also returns the contents of
+    /// the change, which are not loaded in the change (see
+    /// [`Recorded::into_change`]), and the updates to make to the tree
+    /// when applying that change locally.
+    ///
+    /// Fails with [`ContentsError::Shared`] if the contents of this
+    /// record are still shared with another copy of it.
+    pub fn into_change_no_deps<T: GraphTxnT>(
+        self,
+        txn: &T,
+        header: ChangeHeader,
+    ) -> Result<
+        (Change, ContentArena, HashMap<usize, InodeUpdate>),
+        IntoChangeError<T::GraphError>,
+    > {
+        let mut contents = Arc::try_unwrap(self.contents)
+            .map_err(|_| ContentsError::Shared)?
+            .into_inner();
+        let contents_hash = contents.hash().map_err(ContentsError::Io)?;
+        let changes = self
+            .actions
+            .into_iter()
+            .map(|rec| rec.globalize(txn))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(TxnErr)?;
+        let mut change = LocalChange {
+            offsets: Offsets::default(),
+            hashed: Hashed {
+                version: VERSION,
+                contents_hash,
+                changes,
+                metadata: Vec::new(),
+                dependencies: Vec::new(),
+                extra_known: Vec::new(),
+                header,
+            },
+            unhashed: None,
+            contents: Vec::new(),
+        };
+        change.hashed.set_hunk_authors(&self.hunk_authors);
+        Ok((change, contents, self.updatables))
+    }
+}
+
+/// Record all the tracked files under `prefix` into a single change
+/// with header `header`, depending on the changes of `channel`
+/// touched by the record, without saving or applying it. Returns
+/// `None` if there is nothing to record.
+///
+/// This is meant for recording changes without any user interaction,
+/// for example the initial change of a new repository. The result
+/// can be saved with [`ChangeStore::save_change_with_contents`]
+/// (which is where signatures are added) and applied with
+/// [`MutTxnTExt::apply_local_change`](crate::MutTxnTExt::apply_local_change).
+pub fn record_all_with_header<
+    T,
+    W: WorkingCopyRead + Clone + Send + Sync + 'static,
+    C: ChangeStore + Clone + Send + 'static,
+>(
+    txn: &ArcTxn<T>,
+    channel: &ChannelRef<T>,
+    working_copy: &W,
+    changes: &C,
+    prefix: &str,
+    header: ChangeHeader,
+) -> Result<
+    Option<(Change, ContentArena, HashMap<usize, InodeUpdate>)>,
+    RecordError<C::Error, W::Error, T>,
+>
+where
+    T: ChannelMutTxnT
+        + TreeTxnT
+        + DepsTxnT<DepsError = <T as GraphTxnT>::GraphError>
+        + Send
+        + Sync
+        + 'static,
+    T::Channel: Send + Sync,
+    <W as WorkingCopyRead>::Error: 'static,
+{
+    let mut state = Builder::new();
+    state.record(
+        txn.clone(),
+        Algorithm::default(),
+        false,
+        &crate::DEFAULT_SEPARATOR,
+        channel.clone(),
+        working_copy,
+        changes,
+        prefix,
+        1,
+    )?;
+    let mut rec = state.finish();
+    if rec.actions.is_empty() {
+        return Ok(None);
+    }
+    let updates = rec.take_updatables();
+    let txn = txn.read();
+    let (change, contents) = rec
+        .into_change(&*txn, channel, header)
+        .map_err(|e| match e {
+            IntoChangeError::Txn(e) => RecordError::Txn(e),
+            IntoChangeError::Contents(e) => RecordError::Contents(e),
+        })?;
+    Ok(Some((change, contents, updates)))
+}
+
 /// An account of the files that have been added, moved or deleted, as
 /// returned by record, and used by apply (when applying a change
 /// created locally) to update the trees and inodes databases.
@@ -435,6 +720,9 @@ impl Builder {
 
                 vertex.to_option()
             } else {
+                // Paths added to the tree by older versions, or
+                // directly through the API, haven't been checked.
+                crate::fs::check_path(&item.full_path).map_err(RecordError::InvalidPath)?;
                 let rec = self.recorded();
                 debug!("TAKING LOCK {}", line!());
                 let mut rec = rec.lock();
@@ -590,11 +878,15 @@ impl Builder {
                         )
                         .map_err(RecordError::Changestore)?;
                     let mut full_path = full_path.to_string();
-                    let meta = FileMetadata::read(&name);
+                    let meta = FileMetadata::try_read(&name)
+                        .map_err(|e| RecordError::Changestore(ChangeError::from(e).into()))?;
                     if !full_path.is_empty() {
                         full_path.push('/');
                     }
                     full_path.push_str(meta.basename);
+                    if !self.is_included(&full_path, meta.metadata.is_dir()) {
+                        continue;
+                    }
                     // delete recursively.
                     let rec = self.recorded();
                     let mut rec = rec.lock();
@@ -612,6 +904,14 @@ impl Builder {
         Ok(())
     }
 
+    /// Whether `path` passes `self.path_filter`.
+    fn is_included(&self, path: &str, is_dir: bool) -> bool {
+        self.path_filter
+            .as_ref()
+            .map(|f| f(path, is_dir))
+            .unwrap_or(true)
+    }
+
     fn push_children<'a, T: ChannelTxnT + TreeTxnT, W: WorkingCopyRead, C: ChangeStore>(
         &mut self,
         txn: &T,
@@ -658,7 +958,12 @@ impl Builder {
                 full_path.clone() + "/" + &basename
             };
             debug!("fileid_ {:?} child_inode {:?}", fileid_, child_inode);
-            if let Ok(meta) = working_copy.file_metadata(&full_path) {
+            let meta = working_copy.file_metadata(&full_path);
+            let is_dir = meta.as_ref().map(|m| m.is_dir()).unwrap_or(false);
+            if !self.is_included(&full_path, is_dir) {
+                continue;
+            }
+            if let Ok(meta) = meta {
                 debug!("full_path = {:?}, meta = {:?}", full_path, meta);
                 stack.push((
                     RecordItem {
@@ -796,13 +1101,24 @@ impl Recorded {
         // directory" empty vertex.
         let item_v_papa = self.add_root_if_needed(item.v_papa);
 
+        // Read the file before locking the contents, so that other
+        // threads can keep recording in the meantime.
+        let mut file_contents = Vec::new();
+        let file_encoding = if meta.is_file() {
+            let encoding = working_copy.decode_file(&item.full_path, &mut file_contents)?;
+            Some(self.file_classes.encoding(&item.full_path, encoding, &file_contents))
+        } else {
+            None
+        };
+
         let mut contents = self.contents.lock();
         contents.push(0);
         let inode_pos = ChangePosition(contents.len().into());
         contents.push(0);
-        let (contents_, encoding) = if meta.is_file() {
+        let (contents_, encoding) = if let Some(encoding) = file_encoding {
             let start = ChangePosition(contents.len().into());
-            let encoding = working_copy.decode_file(&item.full_path, &mut contents)?;
+            contents.extend_from_slice(&file_contents);
+            std::mem::drop(file_contents);
             self.has_binary_files |= encoding.is_none();
             let end = ChangePosition(contents.len().into());
             self.largest_file = self.largest_file.max(end.0.as_u64() - start.0.as_u64());
@@ -840,7 +1156,9 @@ impl Recorded {
             basename: item.basename.as_str(),
             encoding: encoding.clone(),
         };
-        file_meta.write(&mut contents);
+        let mut meta_buf = Vec::new();
+        file_meta.write(&mut meta_buf);
+        contents.extend_from_slice(&meta_buf);
         let name_end = ChangePosition(contents.len().into());
         contents.push(0);
         self.actions.push(Hunk::FileAdd {
@@ -1016,18 +1334,31 @@ impl Recorded {
                     &working_copy,
                     &item.full_path,
                 )?)
+            && !self.skip_large_file(&working_copy, &item.full_path)
         {
-            let mut ret = {
+            let ret = {
                 let txn = txn.read();
                 let channel = channel.read();
-                retrieve(&*txn, txn.graph(&*channel), vertex)?
+                retrieve_limited(&*txn, txn.graph(&*channel), vertex, &self.retrieve_limits)
+            };
+            let mut ret = match ret {
+                Ok(ret) => ret,
+                Err(RetrieveError::Txn(e)) => return Err(e.into()),
+                Err(RetrieveError::TooLarge { .. }) => {
+                    info!("not diffing {:?}, its graph is too large", item.full_path);
+                    self.skipped_large_files.push(item.full_path.clone());
+                    return Ok(());
+                }
+                Err(RetrieveError::Cancelled) => return Err(RecordError::Cancelled),
             };
             let mut b = Vec::new();
             let encoding = working_copy
                 .decode_file(&item.full_path, &mut b)
                 .map_err(RecordError::WorkingCopy)?;
+            let encoding = self.file_classes.encoding(&item.full_path, encoding, &b);
             debug!("diffing…");
             let len = self.actions.len();
+            let diff_sep = self.separators.get(&item.full_path).unwrap_or(diff_sep).clone();
             self.diff(
                 changes,
                 txn,
@@ -1040,7 +1371,7 @@ impl Recorded {
                 &mut ret,
                 &b,
                 &encoding,
-                diff_sep,
+                &diff_sep,
             )?;
             if self.actions.len() > len {
                 if let Ok(last_modified) = working_copy.modified_time(&item.full_path) {
@@ -1060,6 +1391,21 @@ impl Recorded {
         Ok(())
     }
 
+    /// Whether `path` is larger than `self.max_file_size`, in which
+    /// case it is added to `self.skipped_large_files`.
+    fn skip_large_file<W: WorkingCopyRead>(&mut self, working_copy: &W, path: &str) -> bool {
+        if let Some(max) = self.max_file_size {
+            if let Ok(Some(size)) = working_copy.file_size(path) {
+                if size > max {
+                    info!("not diffing {:?} ({} bytes)", path, size);
+                    self.skipped_large_files.push(path.to_string());
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
     fn record_moved_file<T: ChannelTxnT + TreeTxnT, C: ChangeStore, W: WorkingCopyRead>(
         &mut self,
         changes: &C,
@@ -1074,6 +1420,7 @@ impl Recorded {
         <W as crate::working_copy::WorkingCopyRead>::Error: 'static,
     {
         debug!("record_moved_file {:?} {:?}", item, vertex);
+        crate::fs::check_path(&item.full_path).map_err(RecordError::InvalidPath)?;
         let basename = item.basename.as_str();
         let mut moved = collect_moved_edges::<_, _, W>(
             txn,
@@ -1111,12 +1458,14 @@ impl Recorded {
         let mut contents = self.contents.lock();
         contents.push(0);
         let meta_start = ChangePosition(contents.len().into());
+        let mut meta_buf = Vec::new();
         FileMetadata {
             metadata: item.metadata,
             basename,
             encoding: encoding.clone(),
         }
-        .write(&mut contents);
+        .write(&mut meta_buf);
+        contents.extend_from_slice(&meta_buf);
         let meta_end = ChangePosition(contents.len().into());
         contents.push(0);
         if !moved.edges.is_empty() {
@@ -1158,10 +1507,10 @@ impl Recorded {
                     }),
                     path: item.full_path.clone(),
                 });
-                contents.truncate(meta_start.0.as_usize())
+                contents.truncate(meta_start.0.as_usize())?
             }
         } else {
-            contents.truncate(meta_start.0.as_usize())
+            contents.truncate(meta_start.0.as_usize())?
         }
         Ok(())
     }
@@ -1169,36 +1518,49 @@ impl Recorded {
     pub fn take_updatables(&mut self) -> HashMap<usize, InodeUpdate> {
         std::mem::replace(&mut self.updatables, HashMap::default())
     }
+    /// Turn this record into a change with header `header`,
+    /// depending on the changes of `channel` it touches, without
+    /// saving it.
+    ///
+    /// The contents of the change are returned separately, since they
+    /// may have been written to disk while recording: the
+    /// `contents` field of the change is empty, but its hash is
+    /// computed from the returned contents. Save it with
+    /// [`ChangeStore::save_change_with_contents`], or load the
+    /// contents with [`ContentArena::into_vec`] first.
     pub fn into_change<T: ChannelTxnT + DepsTxnT<DepsError = <T as GraphTxnT>::GraphError>>(
         self,
         txn: &T,
         channel: &ChannelRef<T>,
         header: crate::change::ChangeHeader,
     ) -> Result<
-        crate::change::LocalChange<
-            crate::change::Hunk<Option<Hash>, crate::change::Local>,
-            crate::change::Author,
-        >,
-        TxnErr<T::GraphError>,
+        (
+            crate::change::LocalChange<
+                crate::change::Hunk<Option<Hash>, crate::change::Local>,
+                crate::change::Author,
+            >,
+            ContentArena,
+        ),
+        IntoChangeError<T::GraphError>,
     > {
         let actions = self
             .actions
             .into_iter()
             .map(|rec| rec.globalize(txn).unwrap())
             .collect();
-        let contents = if let Ok(c) = Arc::try_unwrap(self.contents) {
-            c.into_inner()
-        } else {
-            unreachable!()
-        };
-        Ok(crate::change::LocalChange::make_change(
+        let mut contents = Arc::try_unwrap(self.contents)
+            .map_err(|_| ContentsError::Shared)?
+            .into_inner();
+        let mut change = crate::change::LocalChange::make_change(
             txn,
             &channel,
             actions,
-            contents,
+            Vec::new(),
             header,
             Vec::new(),
-        )?)
+        )?;
+        change.hashed.contents_hash = contents.hash().map_err(ContentsError::Io)?;
+        Ok((change, contents))
     }
 }
 
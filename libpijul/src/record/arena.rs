@@ -0,0 +1,232 @@
+//! The contents of a change being recorded, kept in memory up to a
+//! given size and then written to a temporary file (with the
+//! `tempfile` feature), so that recording a very large number of
+//! files doesn't keep all their contents in memory during the
+//! record.
+
+use std::io::Write;
+
+#[cfg(feature = "tempfile")]
+const CHUNK_SIZE: usize = 1 << 16;
+
+/// The bytes of a change being recorded. Hunks refer to ranges of
+/// these bytes by their position, which doesn't change when the
+/// bytes are moved to disk.
+#[derive(Default)]
+pub struct ContentArena {
+    /// The last bytes, not yet written to `file`.
+    mem: Vec<u8>,
+    /// Maximum length of `mem`. `None` means that the contents never
+    /// spill.
+    max_memory: Option<usize>,
+    #[cfg(feature = "tempfile")]
+    file: Option<std::fs::File>,
+    /// Number of bytes in `file`, all before the bytes of `mem`.
+    spilled: usize,
+}
+
+impl ContentArena {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An arena keeping at most approximately `max_memory` bytes in
+    /// memory.
+    pub fn with_max_memory(max_memory: usize) -> Self {
+        let mut arena = Self::default();
+        arena.set_max_memory(Some(max_memory));
+        arena
+    }
+
+    pub fn set_max_memory(&mut self, max_memory: Option<usize>) {
+        self.max_memory = max_memory.map(|m| m.max(1))
+    }
+
+    /// Total number of bytes, including the ones on disk.
+    pub fn len(&self) -> usize {
+        self.spilled + self.mem.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push(&mut self, b: u8) {
+        self.mem.push(b);
+        self.spill_if_needed()
+    }
+
+    pub fn extend_from_slice(&mut self, b: &[u8]) {
+        self.mem.extend_from_slice(b);
+        self.spill_if_needed()
+    }
+
+    /// Number of bytes written to disk.
+    pub fn spilled(&self) -> usize {
+        self.spilled
+    }
+
+    /// Shorten the contents to their first `len` bytes.
+    pub fn truncate(&mut self, len: usize) -> Result<(), std::io::Error> {
+        if len >= self.spilled {
+            self.mem.truncate(len - self.spilled);
+            Ok(())
+        } else {
+            self.mem.clear();
+            self.truncate_file(len)
+        }
+    }
+
+    /// Call `f` on consecutive chunks of the contents, in order.
+    pub fn for_each_chunk<F: FnMut(&[u8])>(&mut self, mut f: F) -> Result<(), std::io::Error> {
+        self.read_spilled(&mut f)?;
+        f(&self.mem);
+        Ok(())
+    }
+
+    /// Write all the contents to `w`.
+    pub fn write_to<W: Write>(&mut self, mut w: W) -> Result<(), std::io::Error> {
+        let mut result = Ok(());
+        self.for_each_chunk(|chunk| {
+            if result.is_ok() {
+                result = w.write_all(chunk)
+            }
+        })?;
+        result
+    }
+
+    /// Hash of the contents, as in the `contents_hash` field of the
+    /// changes.
+    pub fn hash(&mut self) -> Result<crate::pristine::Hash, std::io::Error> {
+        let mut hasher = crate::pristine::Hasher::default();
+        self.for_each_chunk(|chunk| hasher.update(chunk))?;
+        Ok(hasher.finish())
+    }
+
+    /// Copy bytes `start..end` of the contents.
+    pub fn read_range(&mut self, start: usize, end: usize) -> Result<Vec<u8>, std::io::Error> {
+        assert!(start <= end && end <= self.len());
+        let mut v = Vec::with_capacity(end - start);
+        if start < self.spilled {
+            let spilled_end = end.min(self.spilled);
+            v.resize(spilled_end - start, 0);
+            self.read_spilled_range(start, &mut v)?;
+        }
+        if end > self.spilled {
+            let mem_start = start.max(self.spilled) - self.spilled;
+            v.extend_from_slice(&self.mem[mem_start..end - self.spilled]);
+        }
+        Ok(v)
+    }
+
+    /// Move all the contents back to memory, and return them.
+    ///
+    /// Changes (see [`crate::change::LocalChange`]) keep their
+    /// contents in memory, so this loads the bytes written to
+    /// disk. Use [`crate::change::Change::serialize_with_contents`]
+    /// or [`crate::changestore::ChangeStore::save_change_with_contents`]
+    /// to save a change without loading its contents.
+    pub fn into_vec(mut self) -> Result<Vec<u8>, std::io::Error> {
+        if self.spilled == 0 {
+            return Ok(self.mem);
+        }
+        let mut v = Vec::with_capacity(self.len());
+        self.for_each_chunk(|chunk| v.extend_from_slice(chunk))?;
+        Ok(v)
+    }
+
+    fn spill_if_needed(&mut self) {
+        if let Some(max_memory) = self.max_memory {
+            if self.mem.len() > max_memory {
+                self.spill()
+            }
+        }
+    }
+
+    #[cfg(feature = "tempfile")]
+    fn spill(&mut self) {
+        use std::io::{Seek, SeekFrom};
+        if self.file.is_none() {
+            match tempfile::tempfile() {
+                Ok(f) => self.file = Some(f),
+                Err(e) => {
+                    warn!("Could not create a temporary file, not spilling: {:?}", e);
+                    self.max_memory = None;
+                    return;
+                }
+            }
+        }
+        let f = self.file.as_mut().unwrap();
+        let mut written = f.seek(SeekFrom::Start(self.spilled as u64)).map(|_| ());
+        if written.is_ok() {
+            written = f.write_all(&self.mem)
+        }
+        if let Err(e) = written {
+            warn!("Could not spill to disk: {:?}", e);
+            // Bytes `..self.spilled` are still valid in the file,
+            // keep everything else in memory from now on.
+            self.max_memory = None;
+            return;
+        }
+        debug!("spilled {:?} bytes", self.mem.len());
+        self.spilled += self.mem.len();
+        self.mem.clear();
+    }
+
+    #[cfg(not(feature = "tempfile"))]
+    fn spill(&mut self) {
+        self.max_memory = None
+    }
+
+    #[cfg(feature = "tempfile")]
+    fn read_spilled<F: FnMut(&[u8])>(&mut self, f: &mut F) -> Result<(), std::io::Error> {
+        use std::io::{Read, Seek, SeekFrom};
+        if self.spilled == 0 {
+            return Ok(());
+        }
+        let file = self.file.as_mut().unwrap();
+        file.seek(SeekFrom::Start(0))?;
+        let mut buf = vec![0; CHUNK_SIZE.min(self.spilled)];
+        let mut remaining = self.spilled;
+        while remaining > 0 {
+            let n = remaining.min(buf.len());
+            file.read_exact(&mut buf[..n])?;
+            f(&buf[..n]);
+            remaining -= n;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "tempfile"))]
+    fn read_spilled<F: FnMut(&[u8])>(&mut self, _: &mut F) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    #[cfg(feature = "tempfile")]
+    fn read_spilled_range(&mut self, start: usize, buf: &mut [u8]) -> Result<(), std::io::Error> {
+        use std::io::{Read, Seek, SeekFrom};
+        let file = self.file.as_mut().unwrap();
+        file.seek(SeekFrom::Start(start as u64))?;
+        file.read_exact(buf)
+    }
+
+    #[cfg(not(feature = "tempfile"))]
+    fn read_spilled_range(&mut self, _: usize, _: &mut [u8]) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    #[cfg(feature = "tempfile")]
+    fn truncate_file(&mut self, len: usize) -> Result<(), std::io::Error> {
+        if let Some(ref mut f) = self.file {
+            f.set_len(len as u64)?;
+        }
+        self.spilled = len;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "tempfile"))]
+    fn truncate_file(&mut self, len: usize) -> Result<(), std::io::Error> {
+        self.spilled = len;
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+//! A view of the actions of a [`Recorded`] before they are made into
+//! a change, for interfaces previewing what will be recorded, or
+//! letting users select the actions to record.
+
+use super::*;
+
+/// The kind of an action, one for each variant of [`BaseHunk`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ActionKind {
+    FileMove,
+    FileDel,
+    FileUndel,
+    FileAdd,
+    SolveNameConflict,
+    UnsolveNameConflict,
+    Edit,
+    Replacement,
+    SolveOrderConflict,
+    UnsolveOrderConflict,
+    ResurrectZombies,
+    AddRoot,
+    DelRoot,
+}
+
+/// A summary of one of the actions of a [`Recorded`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActionSummary {
+    /// Index of the action in [`Recorded::actions`].
+    pub index: usize,
+    pub kind: ActionKind,
+    /// Path of the file or directory, relative to the root of the
+    /// repository.
+    pub path: String,
+    /// Lines of the working copy added by this action, starting at
+    /// 1. This is an empty range starting at the line of the action
+    /// for actions adding nothing, and an empty range starting at 0
+    /// for actions on file names.
+    pub added_lines: std::ops::Range<usize>,
+    /// Number of bytes of the recorded version deleted by this
+    /// action, including the file contents of deleted files.
+    pub deleted_bytes: usize,
+    /// Encoding of the file, or `None` for binary files.
+    pub encoding: Option<Encoding>,
+}
+
+impl Recorded {
+    /// Summarize the actions of this record, in order.
+    pub fn summaries(&self) -> Result<Vec<ActionSummary>, std::io::Error> {
+        let mut contents = self.contents.lock();
+        self.actions
+            .iter()
+            .enumerate()
+            .map(|(index, action)| summary(&mut contents, index, action))
+            .collect()
+    }
+
+    /// The contents added by action `index`, if any.
+    pub fn added_contents(&self, index: usize) -> Result<Option<Vec<u8>>, std::io::Error> {
+        let n = match self.actions.get(index) {
+            Some(Hunk::Edit {
+                change: Atom::NewVertex(ref n),
+                ..
+            })
+            | Some(Hunk::Replacement {
+                replacement: Atom::NewVertex(ref n),
+                ..
+            })
+            | Some(Hunk::FileAdd {
+                contents: Some(Atom::NewVertex(ref n)),
+                ..
+            }) => n,
+            _ => return Ok(None),
+        };
+        Ok(Some(
+            self.contents.lock().read_range(n.start.us(), n.end.us())?,
+        ))
+    }
+
+    /// Keep only the actions for which `keep` returns `true`, given
+    /// their index and the action, along with their updates and
+    /// authors. The caller is responsible for keeping consistent
+    /// sets of actions, such as the addition of a file along with
+    /// the edits of its contents.
+    pub fn retain_actions<F: FnMut(usize, &Hunk<Option<ChangeId>, LocalByte>) -> bool>(
+        &mut self,
+        mut keep: F,
+    ) {
+        let actions = std::mem::take(&mut self.actions);
+        let mut updatables = HashMap::default();
+        let mut hunk_authors = HunkAuthors::default();
+        for (i, action) in actions.into_iter().enumerate() {
+            if !keep(i, &action) {
+                continue;
+            }
+            let j = self.actions.len();
+            // Updates are indexed by the number of the action, from 1.
+            if let Some(u) = self.updatables.remove(&(i + 1)) {
+                updatables.insert(j + 1, u);
+            }
+            if let Some(a) = self.hunk_authors.get(i) {
+                hunk_authors.set(j, a.to_vec())
+            }
+            self.actions.push(action)
+        }
+        self.updatables = updatables;
+        self.hunk_authors = hunk_authors;
+    }
+}
+
+fn summary(
+    contents: &mut ContentArena,
+    index: usize,
+    action: &Hunk<Option<ChangeId>, LocalByte>,
+) -> Result<ActionSummary, std::io::Error> {
+    let (kind, line, added, deleted, encoding) = match action {
+        Hunk::FileMove { .. } => (ActionKind::FileMove, 0, None, None, None),
+        Hunk::FileDel {
+            contents: ref c,
+            ref encoding,
+            ..
+        } => (ActionKind::FileDel, 0, None, c.as_ref(), encoding.clone()),
+        Hunk::FileUndel { ref encoding, .. } => {
+            (ActionKind::FileUndel, 0, None, None, encoding.clone())
+        }
+        Hunk::FileAdd {
+            contents: ref c,
+            ref encoding,
+            ..
+        } => (ActionKind::FileAdd, 1, c.as_ref(), None, encoding.clone()),
+        Hunk::SolveNameConflict { .. } => (ActionKind::SolveNameConflict, 0, None, None, None),
+        Hunk::UnsolveNameConflict { .. } => (ActionKind::UnsolveNameConflict, 0, None, None, None),
+        Hunk::Edit {
+            ref change,
+            ref local,
+            ref encoding,
+        } => {
+            let (added, deleted) = if let Atom::NewVertex(_) = change {
+                (Some(change), None)
+            } else {
+                (None, Some(change))
+            };
+            (
+                ActionKind::Edit,
+                local.line,
+                added,
+                deleted,
+                encoding.clone(),
+            )
+        }
+        Hunk::Replacement {
+            ref change,
+            ref replacement,
+            ref local,
+            ref encoding,
+        } => (
+            ActionKind::Replacement,
+            local.line,
+            Some(replacement),
+            Some(change),
+            encoding.clone(),
+        ),
+        Hunk::SolveOrderConflict { ref local, .. } => {
+            (ActionKind::SolveOrderConflict, local.line, None, None, None)
+        }
+        Hunk::UnsolveOrderConflict { ref local, .. } => (
+            ActionKind::UnsolveOrderConflict,
+            local.line,
+            None,
+            None,
+            None,
+        ),
+        Hunk::ResurrectZombies {
+            ref local,
+            ref encoding,
+            ..
+        } => (
+            ActionKind::ResurrectZombies,
+            local.line,
+            None,
+            None,
+            encoding.clone(),
+        ),
+        Hunk::AddRoot { .. } => (ActionKind::AddRoot, 0, None, None, None),
+        Hunk::DelRoot { .. } => (ActionKind::DelRoot, 0, None, None, None),
+    };
+    let added_lines = if let Some(Atom::NewVertex(n)) = added {
+        let bytes = contents.read_range(n.start.us(), n.end.us())?;
+        let mut lines = bytes.iter().filter(|&&b| b == b'\n').count();
+        if !bytes.is_empty() && !bytes.ends_with(b"\n") {
+            lines += 1
+        }
+        line..line + lines
+    } else {
+        line..line
+    };
+    let deleted_bytes = if let Some(Atom::EdgeMap(e)) = deleted {
+        e.edges
+            .iter()
+            .filter(|e| e.flag.contains(EdgeFlags::DELETED))
+            .map(|e| e.to.end - e.to.start)
+            .sum()
+    } else {
+        0
+    };
+    Ok(ActionSummary {
+        index,
+        kind,
+        path: action.path().to_string(),
+        added_lines,
+        deleted_bytes,
+        encoding,
+    })
+}
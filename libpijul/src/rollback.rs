@@ -0,0 +1,54 @@
+//! Rolling back a change, i.e. recording a new change that undoes
+//! it, as opposed to unrecording it, which removes it from the
+//! channel along with the changes that depend on it.
+
+use crate::change::{dependencies, Change, ChangeHeader};
+use crate::changestore::ChangeStore;
+use crate::pristine::*;
+
+#[derive(Debug, Error)]
+pub enum InvertError<ChangestoreError: std::error::Error + 'static, T: std::error::Error + 'static>
+{
+    #[error("Changestore error: {0}")]
+    Changestore(ChangestoreError),
+    #[error(transparent)]
+    Txn(#[from] TxnErr<T>),
+    #[error("Change not in channel: {}", hash.to_base32())]
+    ChangeNotInChannel { hash: Hash },
+}
+
+/// Make a change undoing change `hash` of `channel`: the vertices
+/// introduced by `hash` are deleted, and the edges it modified are
+/// restored to their previous state.
+///
+/// The dependencies of the result are computed from the channel, and
+/// include `hash` and the changes whose vertices it touches. The
+/// change still needs to be saved to a change store before being
+/// applied.
+pub fn invert_change<T, C>(
+    txn: &T,
+    channel: &ChannelRef<T>,
+    changes: &C,
+    hash: &Hash,
+    header: ChangeHeader,
+) -> Result<Change, InvertError<C::Error, T::GraphError>>
+where
+    T: ChannelTxnT + DepsTxnT<DepsError = <T as GraphTxnT>::GraphError>,
+    C: ChangeStore,
+{
+    let channel = channel.read();
+    let in_channel = if let Some(id) = txn.get_internal(&hash.into())? {
+        txn.get_changeset(txn.changes(&channel), id)?.is_some()
+    } else {
+        false
+    };
+    if !in_channel {
+        return Err(InvertError::ChangeNotInChannel { hash: *hash });
+    }
+    let change = changes.get_change(hash).map_err(InvertError::Changestore)?;
+    let mut inverse = change.inverse(hash, header, Vec::new());
+    let (deps, extra_known) = dependencies(txn, &channel, inverse.hashed.changes.iter())?;
+    inverse.hashed.dependencies = deps;
+    inverse.hashed.extra_known = extra_known;
+    Ok(inverse)
+}
@@ -64,6 +64,19 @@ impl AsRef<SmallStr> for SmallString {
     }
 }
 
+impl std::borrow::Borrow<SmallStr> for SmallString {
+    fn borrow(&self) -> &SmallStr {
+        self.as_ref()
+    }
+}
+
+impl ToOwned for SmallStr {
+    type Owned = SmallString;
+    fn to_owned(&self) -> SmallString {
+        SmallString::from_str(self.as_str())
+    }
+}
+
 impl AsMut<SmallStr> for SmallString {
     fn as_mut(&mut self) -> &mut SmallStr {
         let len = self.len as usize;
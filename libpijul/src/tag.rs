@@ -144,6 +144,51 @@ pub fn read_short<R: std::io::Read + std::io::Seek>(
     }
 }
 
+/// Check the tag files of `tags` (pairs of a position in a channel
+/// and a state) stored in `changes`, on `n_threads` threads, and
+/// return the ones whose file exists and matches its state. The
+/// files of `changes.top` that don't match their state are deleted.
+#[cfg(any(feature = "ondisk-repos", feature = "no-mmap"))]
+pub fn check_files(
+    changes: &crate::changestore::layered::Layered<
+        crate::changestore::filesystem::FileSystem,
+        crate::changestore::filesystem::FileSystem,
+    >,
+    tags: Vec<(u64, Merkle)>,
+    n_threads: usize,
+) -> Result<Vec<(u64, Merkle)>, TagError> {
+    let n_threads = n_threads.max(1);
+    let chunk_size = (tags.len() + n_threads - 1) / n_threads;
+    let threads: Vec<_> = tags
+        .chunks(chunk_size.max(1))
+        .map(|chunk| {
+            let chunk = chunk.to_vec();
+            let changes = changes.clone();
+            std::thread::spawn(move || {
+                let mut valid = Vec::with_capacity(chunk.len());
+                for (n, m) in chunk {
+                    let tag_path = changes.tag_filename(&m);
+                    match OpenTagFile::open(&tag_path, &m) {
+                        Ok(_) => valid.push((n, m)),
+                        Err(e) => {
+                            warn!("Invalid tag file for {}: {}", m.to_base32(), e);
+                            if tag_path == changes.top.tag_filename(&m) {
+                                std::fs::remove_file(&tag_path).unwrap_or(());
+                            }
+                        }
+                    }
+                }
+                valid
+            })
+        })
+        .collect();
+    let mut valid = Vec::new();
+    for t in threads {
+        valid.extend(t.join().map_err(|_| TagError::Sync)?)
+    }
+    Ok(valid)
+}
+
 pub const VERSION: u64 = 7;
 pub const VERSION_NOENC: u64 = 5;
 
@@ -375,6 +375,59 @@ fn move_file_test() -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+/// Record an empty directory, and check that it is output, and then
+/// deleted, in another repository. Empty directories that were not
+/// added are not recorded.
+#[test]
+fn empty_dir_test() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo_alice = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo_alice.add_file("file", b"a\nb\n".to_vec());
+    repo_alice.add_dir("dir");
+    repo_alice.add_dir("untracked");
+
+    let env_alice = pristine::sanakirja::Pristine::new_anon()?;
+    let txn_alice = env_alice.arc_txn_begin().unwrap();
+    txn_alice.write().add_file("file", 0)?;
+    txn_alice.write().add_dir("dir", 0)?;
+    let channel_alice = txn_alice.write().open_or_create_channel("main")?;
+    let alice0 = record_all(&repo_alice, &changes, &txn_alice, &channel_alice, "")?;
+
+    let repo_bob = working_copy::memory::Memory::new();
+    let env_bob = pristine::sanakirja::Pristine::new_anon()?;
+    let txn_bob = env_bob.arc_txn_begin().unwrap();
+    let channel = txn_bob.write().open_or_create_channel("main")?;
+    apply::apply_change(
+        &changes,
+        &mut *txn_bob.write(),
+        &mut *channel.write(),
+        &alice0,
+    )?;
+    output::output_repository_no_pending(
+        &repo_bob, &changes, &txn_bob, &channel, "", true, None, 1, 0,
+    )?;
+    let mut files = repo_bob.list_files();
+    files.sort();
+    assert_eq!(files, &["dir", "file"]);
+
+    // Deleting the directory from the working copy is recorded too.
+    repo_alice.remove_path("dir", true)?;
+    let alice1 = record_all(&repo_alice, &changes, &txn_alice, &channel_alice, "")?;
+    apply::apply_change(
+        &changes,
+        &mut *txn_bob.write(),
+        &mut *channel.write(),
+        &alice1,
+    )?;
+    output::output_repository_no_pending(
+        &repo_bob, &changes, &txn_bob, &channel, "", true, None, 1, 0,
+    )?;
+    assert_eq!(repo_bob.list_files(), &["file"]);
+    Ok(())
+}
+
 /// Overwrite a file with a move.
 #[test]
 fn move_file_existing_test() -> Result<(), anyhow::Error> {
@@ -696,7 +749,7 @@ fn move_delete_test() -> Result<(), anyhow::Error> {
         &*txn_alice.read(),
         &channel,
         changes_,
-        std::mem::take(&mut rec.contents.lock()),
+        std::mem::take(&mut *rec.contents.lock()).into_vec()?,
         crate::change::ChangeHeader {
             message: "test".to_string(),
             authors: vec![],
@@ -872,3 +925,948 @@ fn add_file2_test() -> Result<(), anyhow::Error> {
     assert_eq!(repo.list_files().len(), 8);
     Ok(())
 }
+
+/// Files larger than `Builder::max_file_size` are not diffed.
+#[test]
+fn max_file_size() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("small", b"a\n".to_vec());
+    repo.add_file("large", b"a\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("small", 0)?;
+    txn.write().add_file("large", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    repo.write_file("small", Inode::ROOT)?.write_all(b"b\n")?;
+    repo.write_file("large", Inode::ROOT)?
+        .write_all(b"a\nb\nc\nd\ne\nf\n")?;
+
+    let mut state = Builder::new();
+    state.max_file_size = Some(4);
+    state.record(
+        txn.clone(),
+        Algorithm::default(),
+        false,
+        &crate::DEFAULT_SEPARATOR,
+        channel.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    )?;
+    let rec = state.finish();
+    assert_eq!(rec.skipped_large_files, vec!["large".to_string()]);
+    assert!(!rec.actions.is_empty());
+    Ok(())
+}
+
+/// Files whose graph exceeds the retrieval budget are neither diffed
+/// nor output, and cancelling the retrieval stops the record or the
+/// output.
+#[test]
+fn retrieve_limits() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("small", b"a\n".to_vec());
+    repo.add_file("large", b"a\nb\nc\nd\ne\nf\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("small", 0)?;
+    txn.write().add_file("large", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    repo.write_file("small", Inode::ROOT)?.write_all(b"b\n")?;
+    repo.write_file("large", Inode::ROOT)?.write_all(b"a\n")?;
+
+    let limits = crate::alive::RetrieveLimits {
+        max_bytes: Some(4),
+        ..Default::default()
+    };
+    let cancelled = crate::alive::RetrieveLimits {
+        cancel: Some(std::sync::Arc::new(std::sync::atomic::AtomicBool::new(
+            true,
+        ))),
+        ..Default::default()
+    };
+    let rec = record_with(&repo, &changes, &txn, &channel, |b| {
+        b.retrieve_limits = limits.clone()
+    })?;
+    assert_eq!(rec.skipped_large_files, vec!["large".to_string()]);
+    assert!(!rec.actions.is_empty());
+
+    assert!(matches!(
+        record_with(&repo, &changes, &txn, &channel, |b| {
+            b.retrieve_limits = cancelled.clone()
+        }),
+        Err(crate::record::RecordError::Cancelled)
+    ));
+
+    let repo2 = working_copy::memory::Memory::new();
+    let output_with = |limits| {
+        output::output_repository_limited(
+            &repo2, &changes, &txn, &channel, "", true, None, 1, 0, limits,
+        )
+    };
+    match output_with(&limits) {
+        Err(output::OutputError::Write(failures)) => {
+            assert_eq!(failures.len(), 1);
+            assert_eq!(failures[0].path, "large");
+        }
+        x => panic!("unexpected result {:?}", x.map(|_| ())),
+    }
+    let mut buf = Vec::new();
+    repo2.read_file("small", &mut buf)?;
+    assert_eq!(buf, b"a\n");
+    assert!(matches!(
+        output_with(&cancelled),
+        Err(output::OutputError::Retrieve(
+            crate::alive::RetrieveError::Cancelled
+        ))
+    ));
+    Ok(())
+}
+
+/// Files can be recorded as binary regardless of their detected
+/// encoding.
+#[test]
+fn forced_binary() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("text", b"a\nb\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("text", 0)?;
+
+    let record = |classes: crate::FileClasses| {
+        record_with(&repo, &changes, &txn, &channel, |b| {
+            b.file_classes = classes
+        })
+    };
+    assert!(!record(crate::FileClasses::default())?.has_binary_files);
+    let mut classes = crate::FileClasses::default();
+    classes.push(|path| path == "text", crate::FileClass::Binary);
+    assert!(record(classes)?.has_binary_files);
+    Ok(())
+}
+
+/// Files forced to text that aren't valid UTF-8 are still recorded
+/// as binary.
+#[test]
+fn forced_text_invalid() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("bin", vec![0xff, 0xfe, 0, 1, b'\n']);
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("bin", 0)?;
+
+    let mut classes = crate::FileClasses::default();
+    classes.push(|path| path == "bin", crate::FileClass::Text);
+    let rec = record_with(&repo, &changes, &txn, &channel, |b| {
+        b.file_classes = classes
+    })?;
+    assert!(rec.has_binary_files);
+    Ok(())
+}
+
+/// Path lookups go through the path cache, which must be invalidated
+/// when the tree changes.
+#[test]
+fn path_cache_test() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let mut txn = env.mut_txn_begin().unwrap();
+    let file = txn.add_file("a/b/c/d", 0)?;
+    txn.add_file("a/b/e", 0)?;
+
+    assert!(txn.is_tracked("a/b/c/d")?);
+    assert!(txn.is_tracked("a/b/e")?);
+    assert!(!txn.is_tracked("a/b/f")?);
+    assert_eq!(crate::fs::find_inode(&txn, "a/b/c/d")?, file);
+    assert!(!txn.path_cache().unwrap().lock().is_empty());
+
+    txn.move_file("a/b/c/d", "a/b/d", 0)?;
+    assert!(txn.path_cache().unwrap().lock().is_empty());
+    assert!(!txn.is_tracked("a/b/c/d")?);
+    assert!(txn.is_tracked("a/b/c")?);
+    assert_eq!(crate::fs::find_inode(&txn, "a/b/d")?, file);
+
+    txn.remove_file("a/b")?;
+    assert!(!txn.is_tracked("a/b/d")?);
+    assert!(!txn.is_tracked("a/b/e")?);
+    assert!(txn.is_tracked("a")?);
+    Ok(())
+}
+
+/// Record an initial change with a header, as `pijul init --template` does.
+#[test]
+fn record_all_with_header_test() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file(".ignore", b".DS_Store\n".to_vec());
+    repo.add_file("src/main.rs", b"fn main() {}\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let mut channel = txn.write().open_or_create_channel("main").unwrap();
+
+    let header = crate::change::ChangeHeader {
+        message: "Initial change".to_string(),
+        ..crate::change::ChangeHeader::default()
+    };
+    txn.write().add_file(".ignore", 0)?;
+    txn.write().add_file("src/main.rs", 0)?;
+    let (mut change, mut contents, updates) =
+        crate::record_all_with_header(&txn, &channel, &repo, &changes, "", header)?.unwrap();
+    assert_eq!(change.header.message, "Initial change");
+    let hash = changes.save_change_with_contents(&mut change, &mut contents, |_, _| {
+        Ok::<_, anyhow::Error>(())
+    })?;
+    txn.write()
+        .apply_local_change(&mut channel, &change, &hash, &updates)?;
+    // Nothing left to record.
+    assert!(crate::record_all_with_header(
+        &txn,
+        &channel,
+        &repo,
+        &changes,
+        "",
+        Default::default()
+    )?
+    .is_none());
+
+    assert!(txn.read().has_change(&channel, &hash)?.is_some());
+    Ok(())
+}
+
+/// Moving a directory renames the files it contains, without
+/// rewriting them.
+#[test]
+fn move_dir_no_rewrite_test() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo_alice = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo_alice.add_file("dir/file", b"a\nb\nc\n".to_vec());
+
+    let env_alice = pristine::sanakirja::Pristine::new_anon()?;
+    let txn_alice = env_alice.arc_txn_begin().unwrap();
+    txn_alice.write().add_file("dir/file", 0)?;
+    let channel_alice = (&mut *txn_alice.write()).open_or_create_channel("main")?;
+    let alice0 = record_all(&repo_alice, &changes, &txn_alice, &channel_alice, "")?;
+
+    txn_alice.write().move_file("dir", "dir2", 0)?;
+    repo_alice.rename("dir", "dir2")?;
+    let alice1 = record_all(&repo_alice, &changes, &txn_alice, &channel_alice, "")?;
+
+    let repo_bob = working_copy::memory::Memory::new();
+    let env_bob = pristine::sanakirja::Pristine::new_anon()?;
+    let txn_bob = env_bob.arc_txn_begin().unwrap();
+    let channel = (&mut *txn_bob.write()).open_or_create_channel("main")?;
+    apply::apply_change(
+        &changes,
+        &mut *txn_bob.write(),
+        &mut *channel.write(),
+        &alice0,
+    )?;
+    output::output_repository_no_pending(
+        &repo_bob, &changes, &txn_bob, &channel, "", true, None, 1, 0,
+    )?;
+    let modified = repo_bob.modified_time("dir/file")?;
+
+    apply::apply_change(
+        &changes,
+        &mut *txn_bob.write(),
+        &mut *channel.write(),
+        &alice1,
+    )?;
+    output::output_repository_no_pending(
+        &repo_bob, &changes, &txn_bob, &channel, "", true, None, 1, 0,
+    )?;
+    let mut files = repo_bob.list_files();
+    files.sort();
+    assert_eq!(files, &["dir2", "dir2/file"]);
+    assert_eq!(repo_bob.modified_time("dir2/file")?, modified);
+    let mut buf = Vec::new();
+    repo_bob.read_file("dir2/file", &mut buf)?;
+    assert_eq!(buf, b"a\nb\nc\n");
+    Ok(())
+}
+
+/// Recording with a small memory limit writes the new contents to a
+/// temporary file, and gives the same contents.
+#[test]
+fn record_max_memory_test() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    for i in 0..20 {
+        let path = format!("dir/file{}", i);
+        repo.add_file(&path, format!("file {}\nline 2\n", i).into_bytes());
+        txn.write().add_file(&path, 0)?;
+    }
+    let channel = txn.write().open_or_create_channel("main")?;
+
+    let mut contents = Vec::new();
+    let mut spilled = Vec::new();
+    for mut state in vec![Builder::new(), Builder::with_max_memory(16)] {
+        state.record(
+            txn.clone(),
+            Algorithm::default(),
+            false,
+            &crate::DEFAULT_SEPARATOR,
+            channel.clone(),
+            &repo,
+            &changes,
+            "",
+            1,
+        )?;
+        let rec = state.finish();
+        spilled.push(rec.contents.lock().spilled());
+        contents.push(std::mem::take(&mut *rec.contents.lock()).into_vec()?);
+    }
+    assert!(contents[0].len() > 16);
+    assert_eq!(contents[0], contents[1]);
+    assert_eq!(spilled[0], 0);
+    #[cfg(feature = "tempfile")]
+    assert!(spilled[1] > 0);
+    Ok(())
+}
+
+/// Serializing a change from contents written to disk while
+/// recording gives the same file as from the contents in memory.
+#[test]
+fn serialize_with_contents_test() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    for i in 0..500 {
+        let path = format!("dir/file{}", i);
+        repo.add_file(&path, format!("file {}\nline 2\n", i).into_bytes());
+        txn.write().add_file(&path, 0)?;
+    }
+    let channel = txn.write().open_or_create_channel("main")?;
+
+    let mut state = Builder::with_max_memory(16);
+    state.record(
+        txn.clone(),
+        Algorithm::default(),
+        false,
+        &crate::DEFAULT_SEPARATOR,
+        channel.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    )?;
+    let rec = state.finish();
+    let (mut change, mut contents) = rec.into_change(
+        &*txn.read(),
+        &channel,
+        crate::change::ChangeHeader::default(),
+    )?;
+    assert!(change.contents.is_empty());
+    assert!(contents.len() > crate::change::CHUNK_SIZE as usize);
+
+    let mut streamed = Vec::new();
+    let h = change.serialize_with_contents(&mut streamed, &mut contents, |_, _| {
+        Ok::<_, anyhow::Error>(())
+    })?;
+    let mut streamed_indexed = Vec::new();
+    let h_indexed =
+        change.serialize_indexed_with_contents(&mut streamed_indexed, &mut contents, |_, _| {
+            Ok::<_, anyhow::Error>(())
+        })?;
+
+    change.contents = contents.into_vec()?;
+    let mut in_memory = Vec::new();
+    assert_eq!(
+        change.serialize(&mut in_memory, |_, _| Ok::<_, anyhow::Error>(()))?,
+        h
+    );
+    let mut in_memory_indexed = Vec::new();
+    assert_eq!(
+        change.serialize_indexed(&mut in_memory_indexed, |_, _| Ok::<_, anyhow::Error>(()))?,
+        h_indexed
+    );
+    assert_eq!(streamed, in_memory);
+    assert_eq!(streamed_indexed, in_memory_indexed);
+    Change::check_from_buffer(&streamed, &h)?;
+    Ok(())
+}
+
+/// Conflict markers in new lines are found, including when the
+/// contents spilled to disk.
+#[test]
+fn record_conflict_markers_test() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\n".to_vec());
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("file", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    repo.write_file("file", Inode::ROOT)?
+        .write_all(b"a\n>>>>>>> 1 [ABCDEFGH]\nb\n======= 1\n<<<<<<< \n")?;
+    for mut state in vec![Builder::new(), Builder::with_max_memory(4)] {
+        state.record(
+            txn.clone(),
+            Algorithm::default(),
+            false,
+            &crate::DEFAULT_SEPARATOR,
+            channel.clone(),
+            &repo,
+            &changes,
+            "",
+            1,
+        )?;
+        let rec = state.finish();
+        let lines: Vec<_> = rec
+            .conflict_markers()?
+            .into_iter()
+            .map(|m| (m.path, m.line))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![("file".to_string(), 2), ("file".to_string(), 4)]
+        );
+    }
+    Ok(())
+}
+
+/// Compare the trees of two channels after adding, moving and
+/// editing files in one of them.
+#[test]
+fn diff_trees_test() -> Result<(), anyhow::Error> {
+    use crate::fs::TreeDiff;
+    use crate::working_copy::WorkingCopy;
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("a", b"a\nb\n".to_vec());
+    repo.add_file("b", b"c\nd\n".to_vec());
+    repo.add_file("c", b"e\nf\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let main = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("a", 0)?;
+    txn.write().add_file("b", 0)?;
+    txn.write().add_file("c", 0)?;
+    record_all(&repo, &changes, &txn, &main, "")?;
+
+    let other = txn.write().fork(&main, "other")?;
+    repo.write_file("a", Inode::ROOT)?.write_all(b"a\nx\nb\n")?;
+    repo.rename("b", "e")?;
+    txn.write().move_file("b", "e", 0)?;
+    repo.add_file("d", b"g\n".to_vec());
+    txn.write().add_file("d", 0)?;
+    record_all(&repo, &changes, &txn, &other, "")?;
+
+    let diff = txn.read().diff_trees(&changes, &main, &other)?;
+    assert_eq!(
+        diff,
+        vec![
+            TreeDiff::Modified {
+                path: "a".to_string()
+            },
+            TreeDiff::Added {
+                path: "d".to_string(),
+                is_dir: false
+            },
+            TreeDiff::Moved {
+                from: "b".to_string(),
+                to: "e".to_string(),
+                modified: false
+            },
+        ]
+    );
+    assert!(txn.read().diff_trees(&changes, &main, &main)?.is_empty());
+    Ok(())
+}
+
+/// Applying a change twice to the same channel is an error, unless
+/// the application is idempotent, in which case it is a no-op.
+#[test]
+fn apply_twice_test() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    let h = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let channel2 = txn.write().open_or_create_channel("main2")?;
+    let first = apply::apply_change_arc(&changes, &txn, &channel2, &h)?;
+    match apply::apply_change_arc(&changes, &txn, &channel2, &h) {
+        Err(ApplyError::LocalChange(LocalApplyError::ChangeAlreadyOnChannel { hash })) => {
+            assert_eq!(hash, h)
+        }
+        x => panic!("unexpected result {:?}", x.map(|_| ())),
+    }
+    let second = apply::apply_change_idempotent_ws(
+        &changes,
+        &mut *txn.write(),
+        &mut *channel2.write(),
+        &h,
+        &mut apply::Workspace::new(),
+    )?;
+    assert_eq!(first, second);
+    assert_eq!(
+        txn.read().current_state(&*channel.read())?,
+        txn.read().current_state(&*channel2.read())?
+    );
+    assert_eq!(txn.read().log(&*channel2.read(), 0)?.count(), 1);
+    Ok(())
+}
+
+#[test]
+fn changes_between_test() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+
+    let mut hashes = Vec::new();
+    let mut states = vec![Merkle::zero()];
+    for (i, name) in ["a", "b", "c"].iter().enumerate() {
+        repo.add_file(name, vec![b'a' + i as u8, b'\n']);
+        txn.write().add_file(name, 0)?;
+        hashes.push(record_all(&repo, &changes, &txn, &channel, "")?);
+        states.push(txn.read().current_state(&*channel.read())?);
+    }
+
+    let txn = txn.read();
+    let channel = channel.read();
+    let between = txn
+        .changes_between(&channel, &states[1], &states[3])?
+        .unwrap();
+    assert!(between.unrecord.is_empty());
+    assert_eq!(between.apply, &hashes[1..]);
+
+    let between = txn
+        .changes_between(&channel, &states[3], &states[1])?
+        .unwrap();
+    assert_eq!(between.unrecord, vec![hashes[2], hashes[1]]);
+    assert!(between.apply.is_empty());
+
+    let between = txn
+        .changes_between(&channel, &states[0], &states[2])?
+        .unwrap();
+    assert_eq!(between.apply, &hashes[..2]);
+
+    assert_eq!(
+        txn.changes_between(&channel, &states[2], &states[2])?,
+        Some(ChangesBetween::default())
+    );
+    // A state that was never reached on this channel.
+    let unknown = states[3].next(&hashes[0]);
+    assert_eq!(txn.changes_between(&channel, &states[1], &unknown)?, None);
+    Ok(())
+}
+
+/// Resolving the paths of all the files touched by a change at once
+/// gives the same paths as resolving them one by one.
+#[test]
+fn path_resolver_test() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let files = ["a/b/x", "a/b/y", "a/z", "c"];
+    for f in files.iter() {
+        repo.add_file(f, b"a\n".to_vec());
+    }
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    for f in files.iter() {
+        txn.write().add_file(f, 0)?;
+    }
+    let h = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let txn = txn.read();
+    let channel = channel.read();
+    let cid = *txn.get_internal(&h.into())?.unwrap();
+    let mut touched = Vec::new();
+    for x in txn.iter_rev_touched_files(&cid, None)? {
+        let (p, pos) = x?;
+        if *p > cid {
+            break;
+        }
+        touched.push(*pos)
+    }
+    let mut resolver = crate::fs::PathResolver::new(false);
+    let paths = resolver.find_paths(&changes, &*txn, &*channel, touched.iter().cloned())?;
+    for (pos, path) in touched.iter().zip(paths.iter()) {
+        assert_eq!(
+            path,
+            &crate::fs::find_path(&changes, &*txn, &*channel, false, *pos)?
+        );
+    }
+    let mut paths: Vec<_> = paths.into_iter().map(|p| p.unwrap().0).collect();
+    paths.sort();
+    paths.dedup();
+    for f in files.iter() {
+        assert!(paths.iter().any(|p| p == f), "{:?} not in {:?}", f, paths);
+    }
+    Ok(())
+}
+
+/// Checking the graph around each new change finds the same problems
+/// as checking the whole graph, i.e. none here.
+#[test]
+fn check_alive_change_test() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("dir/file", b"a\nb\nc\nd\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("dir/file", 0)?;
+    let mut hashes = vec![record_all(&repo, &changes, &txn, &channel, "")?];
+
+    repo.write_file("dir/file", Inode::ROOT)?
+        .write_all(b"a\nx\nc\nd\ny\n")?;
+    hashes.push(record_all(&repo, &changes, &txn, &channel, "")?);
+
+    txn.write().move_file("dir/file", "file2", 0)?;
+    repo.rename("dir/file", "file2")?;
+    hashes.push(record_all(&repo, &changes, &txn, &channel, "")?);
+
+    let txn = txn.read();
+    let channel = channel.read();
+    assert_eq!(check_alive(&*txn, &channel), Default::default());
+    for &h in hashes.iter() {
+        let change_id = *txn.get_internal(&h.into())?.unwrap();
+        let change = changes.get_change(&h)?;
+        let (alive, reachable) = check_alive_change(&*txn, &channel, change_id, &change)?;
+        assert!(alive.is_empty(), "alive: {:?}", alive);
+        assert!(reachable.is_empty(), "reachable: {:?}", reachable);
+    }
+    Ok(())
+}
+
+/// Replace the regular parent edges of the contents of `change` by
+/// pseudo-edges, which `check_alive` reports.
+fn pseudo_parents(
+    txn: &ArcTxn<pristine::sanakirja::MutTxn<()>>,
+    channel: &ChannelRef<pristine::sanakirja::MutTxn<()>>,
+    change: ChangeId,
+) -> Result<(), anyhow::Error> {
+    let mut txn = txn.write();
+    let mut channel = channel.write();
+    let g = pristine::sanakirja::MutTxn::<()>::graph_mut(&mut *channel);
+    let mut edges = Vec::new();
+    for x in txn.iter_graph(g, None)? {
+        let (k, e) = x?;
+        let f = e.flag();
+        if k.change == change
+            && f.contains(EdgeFlags::PARENT | EdgeFlags::BLOCK)
+            && !f.intersects(EdgeFlags::FOLDER | EdgeFlags::DELETED | EdgeFlags::PSEUDO)
+        {
+            edges.push((*k, *e))
+        }
+    }
+    assert!(!edges.is_empty());
+    for (k, e) in edges {
+        let parent = *txn.find_block_end(g, e.dest())?;
+        del_graph_with_rev(&mut *txn, g, e.flag(), k, parent, e.introduced_by())?;
+        let flag = (e.flag() - EdgeFlags::PARENT) | EdgeFlags::PSEUDO;
+        put_graph_with_rev(&mut *txn, g, flag, parent, k, e.introduced_by())?;
+    }
+    Ok(())
+}
+
+/// `check_alive_change` detects vertices touched by a change that
+/// only have pseudo-edges as parents.
+#[test]
+fn check_alive_change_detect() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\nc\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("file", Inode::ROOT)?
+        .write_all(b"a\nx\nb\nc\n")?;
+    let h1 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let c0 = *txn.read().get_internal(&h0.into())?.unwrap();
+    let c1 = *txn.read().get_internal(&h1.into())?.unwrap();
+    pseudo_parents(&txn, &channel, c0)?;
+
+    let txn = txn.read();
+    let channel = channel.read();
+    let change = changes.get_change(&h1)?;
+    let (_, reachable) = check_alive_change(&*txn, &channel, c1, &change)?;
+    assert!(!reachable.is_empty());
+    assert!(reachable.iter().all(|(v, _)| v.change == c0));
+    Ok(())
+}
+
+/// With the `validate` feature, applying a change around a broken
+/// part of the graph returns an error.
+#[cfg(feature = "validate")]
+#[test]
+fn apply_invalid_graph() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\nc\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+    let other = txn.write().fork(&channel, "other")?;
+    repo.write_file("file", Inode::ROOT)?
+        .write_all(b"a\nx\nb\nc\n")?;
+    let h1 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let c0 = *txn.read().get_internal(&h0.into())?.unwrap();
+    pseudo_parents(&txn, &other, c0)?;
+    match apply::apply_change_arc(&changes, &txn, &other, &h1) {
+        Err(ApplyError::LocalChange(LocalApplyError::InvalidGraph {
+            hash,
+            reachable_pseudo,
+            ..
+        })) => {
+            assert_eq!(hash, h1);
+            assert!(!reachable_pseudo.is_empty())
+        }
+        x => panic!("unexpected result {:?}", x.map(|_| ())),
+    }
+    Ok(())
+}
+
+/// Paths with control characters are refused, when adding and when
+/// moving files.
+#[test]
+fn add_invalid_path() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    for path in ["a\nb", "dir/a\rb", "\u{1b}[31m"].iter() {
+        match txn.write().add_file(path, 0) {
+            Err(crate::fs::FsError::InvalidPath(_)) => {}
+            r => panic!("{:?}: {:?}", path, r),
+        }
+    }
+    txn.write().add_file("dir/file 100%", 0)?;
+    match txn.write().move_file("dir/file 100%", "dir/file\n", 0) {
+        Err(crate::fs::FsError::InvalidPath(_)) => {}
+        r => panic!("{:?}", r),
+    }
+    let files: Vec<_> = crate::fs::iter_working_copy(&*txn.read(), Inode::ROOT)
+        .map(|n| n.unwrap().1)
+        .collect();
+    assert_eq!(files, vec!["dir", "dir/file 100%"]);
+    Ok(())
+}
+
+/// Paths that got into the tree without being checked (for instance
+/// by older versions) are refused when recording.
+#[test]
+fn record_invalid_path() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("a\nb", b"a\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    crate::fs::make_new_child(&mut *txn.write(), Inode::ROOT, "a\nb", false, None, 0)?;
+    assert!(matches!(
+        record_with(&repo, &changes, &txn, &channel, |_| ()),
+        Err(crate::record::RecordError::InvalidPath(_))
+    ));
+    Ok(())
+}
+
+/// A change of the log without a hash is reported by the log
+/// iterators and by `check_log`, instead of making them panic.
+#[test]
+fn log_unknown_change() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("file", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("file", Inode::ROOT)?.write_all(b"a\nb\n")?;
+    let h1 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let p0 = *txn.read().get_internal(&h0.into())?.unwrap();
+    txn.write().del_external(&p0, None)?;
+
+    let txn = txn.read();
+    let channel = channel.read();
+    assert_eq!(pristine::check_log(&*txn, &*channel)?, vec![(0, p0)]);
+    let mut log = txn.reverse_log(&*channel, None)?;
+    let (_, (h, _)) = log.next().unwrap()?;
+    assert_eq!(pristine::Hash::from(h), h1);
+    match log.next() {
+        Some(Err(crate::LogError::UnknownChange {
+            timestamp: 0,
+            change,
+        })) => assert_eq!(change, p0),
+        _ => panic!("expected an unknown change"),
+    }
+    assert!(log.next().is_none());
+    Ok(())
+}
+
+/// Paths rejected by the filter of a record are left out of it.
+#[test]
+fn record_path_filter() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("a/x", b"x\n".to_vec());
+    repo.add_file("b/y", b"y\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("a/x", 0)?;
+    txn.write().add_file("b/y", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+
+    let filter: crate::working_copy::PathFilter =
+        std::sync::Arc::new(|path: &str, _| path != "b" && !path.starts_with("b/"));
+    let mut state = Builder::new();
+    state.path_filter = Some(filter.clone());
+    state.record(
+        txn.clone(),
+        Algorithm::default(),
+        false,
+        &crate::DEFAULT_SEPARATOR,
+        channel.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    )?;
+    let rec = state.finish();
+    assert!(!rec.actions.is_empty());
+    assert!(rec.actions.iter().all(|a| a.path().starts_with("a")));
+    Ok(())
+}
+
+/// Moving a directory keeps the unrecorded changes of the files it
+/// contains, merged with the changes to these files, when they are
+/// applied as a pending change before the move, as in `pijul pull`.
+#[test]
+fn move_dir_keep_local_test() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo_alice = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo_alice.add_file("dir/file", b"a\nb\nc\n".to_vec());
+
+    let env_alice = pristine::sanakirja::Pristine::new_anon()?;
+    let txn_alice = env_alice.arc_txn_begin().unwrap();
+    txn_alice.write().add_file("dir/file", 0)?;
+    let channel_alice = (&mut *txn_alice.write()).open_or_create_channel("main")?;
+    let alice0 = record_all(&repo_alice, &changes, &txn_alice, &channel_alice, "")?;
+
+    txn_alice.write().move_file("dir", "dir2", 0)?;
+    repo_alice.rename("dir", "dir2")?;
+    repo_alice.add_file("dir2/file", b"a\nb\nc\nd\n".to_vec());
+    let alice1 = record_all(&repo_alice, &changes, &txn_alice, &channel_alice, "")?;
+
+    let repo_bob = working_copy::memory::Memory::new();
+    let env_bob = pristine::sanakirja::Pristine::new_anon()?;
+    let txn_bob = env_bob.arc_txn_begin().unwrap();
+    let channel = (&mut *txn_bob.write()).open_or_create_channel("main")?;
+    apply::apply_change(
+        &changes,
+        &mut *txn_bob.write(),
+        &mut *channel.write(),
+        &alice0,
+    )?;
+    output::output_repository_no_pending(
+        &repo_bob, &changes, &txn_bob, &channel, "", true, None, 1, 0,
+    )?;
+    // Bob edits the file, and records his edit in a pending change.
+    repo_bob.add_file("dir/file", b"a\nb\nx\nc\n".to_vec());
+    let pending = record_all(&repo_bob, &changes, &txn_bob, &channel, "")?;
+
+    apply::apply_change(
+        &changes,
+        &mut *txn_bob.write(),
+        &mut *channel.write(),
+        &alice1,
+    )?;
+    output::output_repository_no_pending(
+        &repo_bob, &changes, &txn_bob, &channel, "", true, None, 1, 0,
+    )?;
+    crate::unrecord::unrecord(&mut *txn_bob.write(), &channel, &changes, &pending, 0)?;
+    let mut files = repo_bob.list_files();
+    files.sort();
+    assert_eq!(files, &["dir2", "dir2/file"]);
+    let mut buf = Vec::new();
+    repo_bob.read_file("dir2/file", &mut buf)?;
+    assert_eq!(buf, b"a\nb\nx\nc\nd\n");
+    Ok(())
+}
@@ -68,7 +68,7 @@ fn hash_mism() -> Result<(), anyhow::Error> {
         &*txn.read(),
         &channel,
         changes,
-        std::mem::take(&mut *rec.contents.lock()),
+        std::mem::take(&mut *rec.contents.lock()).into_vec()?,
         crate::change::ChangeHeader {
             message: "test".to_string(),
             authors: vec![],
@@ -92,6 +92,424 @@ fn hash_mism() -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+#[test]
+fn portable() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let store = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\nc\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &store, &txn, &channel, "")?;
+    write!(repo.write_file("file", Inode::ROOT)?, "a\nx\nc\n")?;
+    let h = record_all(&repo, &store, &txn, &channel, "")?;
+    let change = store.get_change(&h).unwrap();
+
+    // With the contents.
+    let mut buf = Vec::new();
+    change.to_json(Some(h), false, &mut buf)?;
+    let change_ = Change::from_json(&buf[..], None)?;
+    assert_eq!(change_.hashed, change.hashed);
+    assert_eq!(change_.contents, change.contents);
+
+    // With detached contents.
+    let mut buf = Vec::new();
+    change.to_json(Some(h), true, &mut buf)?;
+    match Change::from_json(&buf[..], None) {
+        Err(ChangeError::MissingContents { .. }) => {}
+        _ => panic!("contents should be missing"),
+    }
+    let change_ = Change::from_json(&buf[..], Some(change.contents.clone()))?;
+    assert_eq!(change_.hashed, change.hashed);
+    match Change::from_json(&buf[..], Some(b"x".to_vec())) {
+        Err(ChangeError::ContentsHashMismatch { .. }) => {}
+        _ => panic!("contents hash should mismatch"),
+    }
+    Ok(())
+}
+
+fn record_edit<T: MutTxnT + Send + Sync + 'static>(
+    repo: &working_copy::memory::Memory,
+    store: &changestore::memory::Memory,
+    txn: &ArcTxn<T>,
+    channel: &ChannelRef<T>,
+) -> Result<Recorded, anyhow::Error>
+where
+    T::Channel: Send + Sync,
+{
+    let mut state = Builder::new();
+    state.record(
+        txn.clone(),
+        Algorithm::Myers,
+        false,
+        &crate::DEFAULT_SEPARATOR,
+        channel.clone(),
+        repo,
+        store,
+        "",
+        1,
+    )?;
+    Ok(state.finish())
+}
+
+/// The header is kept, and the dependencies are computed unless
+/// they are given.
+#[test]
+fn apply_recorded_with_header() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let store = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\nc\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let mut channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    let h0 = record_all(&repo, &store, &txn, &channel, "")?;
+
+    write!(repo.write_file("file", Inode::ROOT)?, "a\nx\nc\n")?;
+    let rec = record_edit(&repo, &store, &txn, &channel)?;
+    let header = ChangeHeader {
+        message: "edit".to_string(),
+        ..ChangeHeader::default()
+    };
+    let (h1, state) =
+        txn.write()
+            .apply_recorded_with_header(&mut channel, rec, header, None, &store)?;
+    let change = store.get_change(&h1)?;
+    assert_eq!(change.header.message, "edit");
+    assert_eq!(change.dependencies, vec![h0]);
+    assert_eq!(txn.read().current_state(&*channel.read())?, state);
+
+    write!(repo.write_file("file", Inode::ROOT)?, "a\ny\nc\n")?;
+    let rec = record_edit(&repo, &store, &txn, &channel)?;
+    let (h2, _) = txn.write().apply_recorded_with_header(
+        &mut channel,
+        rec,
+        ChangeHeader::default(),
+        Some(vec![h0, h1]),
+        &store,
+    )?;
+    assert_eq!(store.get_change(&h2)?.dependencies, vec![h0, h1]);
+    Ok(())
+}
+
+/// Per-hunk authors survive saving and reloading the change.
+#[test]
+fn hunk_authors() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let store = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\n".to_vec());
+    repo.add_file("file2", b"c\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    txn.write().add_file("file2", 0)?;
+
+    let mut state = Builder::new();
+    state.record(
+        txn.clone(),
+        Algorithm::Myers,
+        false,
+        &crate::DEFAULT_SEPARATOR,
+        channel.clone(),
+        &repo,
+        &store,
+        "",
+        1,
+    )?;
+    let mut rec = state.finish();
+    let last = rec.actions.len() - 1;
+    rec.set_action_authors(last, vec![1])?;
+    assert!(rec.set_action_authors(last + 1, vec![0]).is_err());
+    let mut alice = std::collections::BTreeMap::new();
+    alice.insert("name".to_string(), "alice".to_string());
+    let mut bob = std::collections::BTreeMap::new();
+    bob.insert("name".to_string(), "bob".to_string());
+    let header = ChangeHeader {
+        authors: vec![Author(alice), Author(bob)],
+        ..ChangeHeader::default()
+    };
+    let (mut change, mut contents, _) = rec.into_change_no_deps(&*txn.read(), header)?;
+    let h = store.save_change_with_contents(&mut change, &mut contents, |_, _| {
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    let change = store.get_change(&h).unwrap();
+    let hunk_authors = change.hunk_authors().unwrap();
+    assert_eq!(hunk_authors.get(0), None);
+    assert_eq!(hunk_authors.get(last), Some(&[1][..]));
+    Ok(())
+}
+
+/// Recorded actions can be previewed and selected before making a
+/// change.
+#[test]
+fn recorded_summaries() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let store = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &store, &txn, &channel, "")?;
+
+    repo.write_file("file", Inode::ROOT)?
+        .write_all(b"a\nx\ny\n")?;
+    repo.add_file("file2", b"c\n".to_vec());
+    txn.write().add_file("file2", 0)?;
+
+    let mut state = Builder::new();
+    state.record(
+        txn.clone(),
+        Algorithm::Myers,
+        false,
+        &crate::DEFAULT_SEPARATOR,
+        channel.clone(),
+        &repo,
+        &store,
+        "",
+        1,
+    )?;
+    let mut rec = state.finish();
+    let summaries = rec.summaries()?;
+    debug!("{:#?}", summaries);
+    let replacement = summaries
+        .iter()
+        .find(|s| s.kind == ActionKind::Replacement)
+        .unwrap();
+    assert_eq!(replacement.path, "file");
+    assert_eq!(replacement.added_lines, 2..4);
+    assert_eq!(replacement.deleted_bytes, 2);
+    assert_eq!(
+        rec.added_contents(replacement.index)?.as_deref(),
+        Some(&b"x\ny\n"[..])
+    );
+    let add = summaries
+        .iter()
+        .find(|s| s.kind == ActionKind::FileAdd)
+        .unwrap();
+    assert_eq!(add.path, "file2");
+    assert_eq!(add.added_lines, 1..2);
+    assert!(!rec.updatables.is_empty());
+
+    // Only record the edit of `file`.
+    rec.retain_actions(|_, action| action.path() == "file");
+    assert_eq!(rec.actions.len(), 1);
+    assert_eq!(rec.summaries()?[0].kind, ActionKind::Replacement);
+    assert!(rec.updatables.is_empty());
+    Ok(())
+}
+
+/// Comments are allowed in the text header of changes, its fields
+/// are validated, and the fields unknown to Pijul are kept.
+#[test]
+fn text_header_fields() -> Result<(), anyhow::Error> {
+    let text = r#"# Prepared by a tool
+message = "Fix the parser"
+issue = 12
+timestamp = "2022-01-01T00:00:00Z"
+
+[[authors]]
+name = "alice"
+
+[review]
+by = "bob"
+"#;
+    let h = TextHeader::parse(text)?;
+    assert_eq!(h.header.message, "Fix the parser");
+    assert_eq!(h.header.authors.len(), 1);
+    assert_eq!(h.fields.len(), 2);
+    assert_eq!(h.fields.get("issue"), Some(&serde_json::json!(12)));
+    assert_eq!(
+        h.fields.get("review"),
+        Some(&serde_json::json!({ "by": "bob" }))
+    );
+    assert_eq!(TextHeader::parse(&h.to_toml()?)?, h);
+
+    assert!(matches!(
+        TextHeader::parse("timestamp = \"2022-01-01T00:00:00Z\"\nauthors = []\n"),
+        Err(HeaderError::Missing("message"))
+    ));
+    assert!(matches!(
+        TextHeader::parse("message = \"m\"\ntimestamp = \"yesterday\"\nauthors = []\n"),
+        Err(HeaderError::Invalid { .. })
+    ));
+
+    // The fields are stored along with the per-hunk authors.
+    let mut hashed = Hashed::<Hunk<Option<Hash>, Local>, Author> {
+        version: VERSION,
+        header: h.header.clone(),
+        dependencies: Vec::new(),
+        extra_known: Vec::new(),
+        metadata: Vec::new(),
+        changes: Vec::new(),
+        contents_hash: Hash::None,
+    };
+    let mut authors = HunkAuthors::default();
+    authors.set(0, vec![0]);
+    hashed.set_hunk_authors(&authors);
+    hashed.set_header_fields(h.fields.clone());
+    assert_eq!(hashed.header_fields(), h.fields);
+    assert_eq!(hashed.hunk_authors(), Some(authors.clone()));
+    hashed.set_header_fields(Default::default());
+    assert_eq!(hashed.metadata, serde_json::to_vec(&authors)?);
+    Ok(())
+}
+
+#[test]
+fn author_map() {
+    let mut alice = std::collections::BTreeMap::new();
+    alice.insert("name".to_string(), "alice".to_string());
+    alice.insert("key".to_string(), "KEY".to_string());
+    let alice = Author(alice);
+    let mut bob = std::collections::BTreeMap::new();
+    bob.insert("name".to_string(), "bob".to_string());
+    let bob = Author(bob);
+
+    let mut map = AuthorMap::default();
+    map.0.insert("alice".to_string(), "Alice A.".to_string());
+    map.0.insert("bob".to_string(), "Bob".to_string());
+    assert_eq!(map.display_name(&alice), Some("Alice A."));
+    assert_eq!(map.display_name(&bob), Some("Bob"));
+
+    let mut shared = AuthorMap::default();
+    shared.0.insert("KEY".to_string(), "Alice".to_string());
+    shared.0.insert("bob".to_string(), "Robert".to_string());
+    map.merge(&shared);
+    // The key takes precedence over the name, local entries over
+    // shared ones.
+    assert_eq!(map.display_name(&alice), Some("Alice"));
+    assert_eq!(map.display_name(&bob), Some("Bob"));
+}
+
+/// Changes are saved in the default format unless the store is
+/// configured otherwise, and reindexing them cuts their contents in
+/// chunks, which can be read from any offset.
+#[test]
+fn contents_index() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let store = changestore::memory::Memory::new();
+    let mut contents = Vec::new();
+    for i in 0..2000 {
+        writeln!(contents, "line {}", i)?;
+    }
+    repo.add_file("file", contents);
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    let h = record_all(&repo, &store, &txn, &channel, "")?;
+    let change = store.get_change(&h).unwrap();
+    assert!(change.contents.len() as u64 > 2 * CHUNK_SIZE);
+
+    let dir = tempfile::tempdir()?;
+    let fs_store = changestore::filesystem::FileSystem::from_changes(dir.path().to_path_buf(), 10);
+    let mut change_ = change.clone();
+    assert_eq!(
+        fs_store.save_change(&mut change_, |_, _| Ok::<_, anyhow::Error>(()))?,
+        h
+    );
+    assert_eq!(fs_store.get_change(&h)?.offsets.version, VERSION);
+    assert!(fs_store.reindex(&h)?);
+    let change_ = fs_store.get_change(&h)?;
+    assert_eq!(change_.offsets.version, CONTENTS_INDEX_VERSION);
+    assert_eq!(change_.contents, change.contents);
+
+    let path = fs_store.filename(&h);
+    Change::check_from_buffer(&std::fs::read(&path)?, &h)?;
+    let mut f = ChangeFile::open(h, path.to_str().unwrap())?;
+    // Across a chunk boundary.
+    let start = CHUNK_SIZE as usize - 10;
+    let mut buf = vec![0; 100];
+    assert_eq!(f.read_contents(start as u64, &mut buf)?, 100);
+    assert_eq!(&buf[..], &change.contents[start..start + 100]);
+    // At the end of the contents.
+    let end = change.contents.len() - 10;
+    assert_eq!(f.read_contents(end as u64, &mut buf)?, 10);
+    assert_eq!(&buf[..10], &change.contents[end..]);
+
+    // Already indexed.
+    assert!(!fs_store.reindex(&h)?);
+
+    // Saved directly in the indexed format.
+    let dir = tempfile::tempdir()?;
+    let mut fs_store =
+        changestore::filesystem::FileSystem::from_changes(dir.path().to_path_buf(), 10);
+    fs_store.set_index_contents(true);
+    let mut change_ = change.clone();
+    fs_store.save_change(&mut change_, |_, _| Ok::<_, anyhow::Error>(()))?;
+    let change_ = fs_store.get_change(&h)?;
+    assert_eq!(change_.offsets.version, CONTENTS_INDEX_VERSION);
+    assert_eq!(change_.contents, change.contents);
+    Ok(())
+}
+
+/// Reading the hunks of a change one by one from the disk gives the
+/// same hunks as loading the change.
+#[test]
+fn iter_hunks() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let dir = tempfile::tempdir()?;
+    let store = changestore::filesystem::FileSystem::from_changes(dir.path().to_path_buf(), 10);
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    // Enough hunks for the hashed section to be decompressed in
+    // several pieces.
+    for i in 0..1000 {
+        let path = format!("dir{}/file{}", i % 10, i);
+        repo.add_file(&path, format!("contents of file {}\n", i).into_bytes());
+        txn.write().add_file(&path, 0)?;
+    }
+    let h = record_all(&repo, &store, &txn, &channel, "")?;
+
+    let hunks = store.get_changes(&h)?;
+    assert!(hunks.len() >= 1000);
+    let streamed = store.iter_hunks(&h)?.collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(streamed, hunks);
+
+    // The hash is checked after the last hunk.
+    let path = store.filename(&h);
+    let mut r = HunkReader::open(Hash::None, path.to_str().unwrap())?;
+    for _ in 0..hunks.len() {
+        r.next().unwrap()?;
+    }
+    assert!(matches!(
+        r.next(),
+        Some(Err(ChangeError::ChangeHashMismatch { .. }))
+    ));
+    assert!(r.next().is_none());
+
+    // Stores without a streaming implementation load the change.
+    let memory = changestore::memory::Memory::new();
+    let mut change = store.get_change(&h)?;
+    memory.save_change(&mut change, |_, _| Ok::<_, anyhow::Error>(()))?;
+    assert_eq!(
+        memory.iter_hunks(&h)?.collect::<Result<Vec<_>, _>>()?,
+        hunks
+    );
+    Ok(())
+}
+
 #[cfg(feature = "text-changes")]
 #[test]
 #[ignore]
@@ -201,3 +619,168 @@ fn text_test<C: ChangeStore>(c: &C, change0: &Change, h: Hash) {
     }
     assert_eq!(change0, &change1);
 }
+
+#[test]
+fn trailers() {
+    let mut header = ChangeHeader {
+        description: Some("Some details.\n\nNot: a trailer\nbecause of this line\n".to_string()),
+        ..ChangeHeader::default()
+    };
+    assert!(header.trailers().is_empty());
+
+    header.add_trailer(Trailer::new("Signed-off-by", "Alice <alice@example.com>"));
+    header.add_trailer(Trailer::new("Fixes", "#12"));
+    header.add_trailer(Trailer::new("Fixes", "#12"));
+    assert_eq!(
+        header.description.as_deref(),
+        Some(
+            "Some details.\n\nNot: a trailer\nbecause of this line\n\n\
+             Signed-off-by: Alice <alice@example.com>\nFixes: #12"
+        )
+    );
+    assert_eq!(
+        header.trailers(),
+        vec![
+            Trailer::new("Signed-off-by", "Alice <alice@example.com>"),
+            Trailer::new("Fixes", "#12"),
+        ]
+    );
+    assert_eq!(
+        header.trailer_values("signed-off-by"),
+        vec!["Alice <alice@example.com>"]
+    );
+    assert_eq!(
+        header.description_body(),
+        Some("Some details.\n\nNot: a trailer\nbecause of this line")
+    );
+
+    let mut header = ChangeHeader::default();
+    header.add_trailer(Trailer::new("Reviewed-by", "Bob"));
+    assert_eq!(header.description.as_deref(), Some("Reviewed-by: Bob"));
+    assert_eq!(header.description_body(), None);
+}
+
+#[test]
+fn hash_prefix_len() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let store = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    let mut hashes = Vec::new();
+    for i in 0..50 {
+        let path = format!("file{}", i);
+        repo.add_file(&path, format!("contents of file {}\n", i).into_bytes());
+        txn.write().add_file(&path, 0)?;
+        hashes.push(record_all(&repo, &store, &txn, &channel, "")?);
+    }
+
+    let txn = txn.read();
+    let len = txn.hash_prefix_len(&*channel.read())?;
+    assert!(len >= 1);
+    for h in hashes.iter() {
+        let b32 = h.to_base32();
+        assert_eq!(txn.hash_from_prefix(&b32[..len])?.0, *h);
+    }
+    // The result is cached.
+    assert_eq!(txn.hash_prefix_len(&*channel.read())?, len);
+    Ok(())
+}
+
+/// The lines added and deleted by a change are found hunk by hunk,
+/// and can be restricted to some files.
+#[test]
+fn search_change() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let store = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\nc\n".to_vec());
+    repo.add_file("file2", b"b\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    txn.write().add_file("file2", 0)?;
+    let h0 = record_all(&repo, &store, &txn, &channel, "")?;
+
+    write!(repo.write_file("file", Inode::ROOT)?, "a\nbb\nc\n")?;
+    write!(repo.write_file("file2", Inode::ROOT)?, "b\nbbb\n")?;
+    let h1 = record_all(&repo, &store, &txn, &channel, "")?;
+
+    let matches = crate::change::search_change(&store, &h1, None, |l| l.starts_with('b'))?;
+    let mut lines: Vec<_> = matches
+        .iter()
+        .map(|m| (m.path.as_str(), m.added, m.line.as_str()))
+        .collect();
+    lines.sort();
+    assert_eq!(
+        lines,
+        vec![
+            ("file", false, "b"),
+            ("file", true, "bb"),
+            ("file2", true, "bbb")
+        ]
+    );
+
+    // The inode of `file`, as introduced by the first change.
+    let inode = store
+        .get_change(&h0)?
+        .changes
+        .iter()
+        .find_map(|h| match h {
+            Hunk::FileAdd {
+                add_inode, path, ..
+            } if path == "file" => Some(Position {
+                change: h0,
+                pos: add_inode.as_newvertex().start,
+            }),
+            _ => None,
+        })
+        .unwrap();
+    let mut inodes = HashSet::default();
+    inodes.insert(inode);
+    let matches = crate::change::search_change(&store, &h1, Some(&inodes), |_| true)?;
+    assert_eq!(matches.len(), 2);
+    assert!(matches.iter().all(|m| m.path == "file"));
+    Ok(())
+}
+
+/// Order conflicts are shown like name conflicts: solving them is
+/// "SC", unsolving them "UC".
+#[test]
+fn hunk_short_codes() {
+    let atom = || {
+        Atom::EdgeMap(EdgeMap {
+            edges: Vec::new(),
+            inode: Position::ROOT,
+        })
+    };
+    let local = || Local {
+        path: "file".to_string(),
+        line: 1,
+    };
+    let solve: Hunk<ChangeId, Local> = Hunk::SolveOrderConflict {
+        change: atom(),
+        local: local(),
+    };
+    let unsolve: Hunk<ChangeId, Local> = Hunk::UnsolveOrderConflict {
+        change: atom(),
+        local: local(),
+    };
+    let unsolve_name: Hunk<ChangeId, Local> = Hunk::UnsolveNameConflict {
+        name: atom(),
+        path: "file".to_string(),
+    };
+    let root: Hunk<ChangeId, Local> = Hunk::AddRoot {
+        name: atom(),
+        inode: atom(),
+    };
+    assert_eq!(solve.short_code(), Some("SC"));
+    assert_eq!(unsolve.short_code(), Some("UC"));
+    assert_eq!(unsolve_name.short_code(), Some("UC"));
+    assert_eq!(root.short_code(), None);
+}
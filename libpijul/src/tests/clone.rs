@@ -132,3 +132,320 @@ fn clone_prefixes() -> Result<(), anyhow::Error> {
     txn2.open_or_create_channel("main2").unwrap();
     Ok(())
 }
+
+/// Clone with a tiny memory budget, forcing the application
+/// workspace to spill the pseudo-edges introduced by the deletions of
+/// the second change when applying the third one.
+#[test]
+fn clone_max_memory() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let contents: Vec<u8> = (0..200)
+        .flat_map(|i| format!("{}\n", i).into_bytes())
+        .collect();
+    let contents2: Vec<u8> = (0..200)
+        .filter(|i| i % 3 != 0)
+        .flat_map(|i| format!("{}\n", i).into_bytes())
+        .collect();
+    let contents3: Vec<u8> = (0..200)
+        .filter(|i| i % 3 == 2)
+        .flat_map(|i| format!("{}\n", i).into_bytes())
+        .collect();
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", contents);
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("file", Inode::ROOT)?
+        .write_all(&contents2)?;
+    let h1 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("file", Inode::ROOT)?
+        .write_all(&contents3)?;
+    let h2 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let repo2 = working_copy::memory::Memory::new();
+    let channel2 = txn.write().open_or_create_channel("main2")?;
+    let mut ws = ApplyWorkspace::with_max_memory(1);
+    for h in [h0, h1, h2].iter() {
+        apply::apply_change_ws(
+            &changes,
+            &mut *txn.write(),
+            &mut *channel2.write(),
+            h,
+            &mut ws,
+        )
+        .unwrap();
+    }
+    output::output_repository_no_pending(&repo2, &changes, &txn, &channel2, "", true, None, 1, 0)?;
+    let mut file = Vec::new();
+    repo2.read_file("file", &mut file)?;
+    assert_eq!(file, contents3);
+    #[cfg(feature = "tempfile")]
+    assert!(ws.spilled() > 0);
+    Ok(())
+}
+
+#[test]
+fn lock_channels() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\nc\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let main = txn.write().open_or_create_channel("main")?;
+    let other = txn.write().open_or_create_channel("other")?;
+    txn.write().add_file("file", 0)?;
+    let h = record_all(&repo, &changes, &txn, &main, "")?;
+
+    // Locking the same channel twice must not deadlock, and both
+    // positions must refer to the same channel.
+    let channels = [other.clone(), main.clone(), other.clone()];
+    let mut guards = crate::lock_channels(&channels);
+    assert_eq!(guards.len(), 3);
+    apply::apply_change(&changes, &mut *txn.write(), &mut guards[2], &h)?;
+    let txn_ = txn.read();
+    assert_eq!(
+        txn_.current_state(&guards[0])?,
+        txn_.current_state(&guards[1])?
+    );
+    Ok(())
+}
+
+#[test]
+fn commit_with_references() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().open_or_create_channel("main")?;
+    let txn2 = txn.clone();
+    match txn.commit() {
+        Err(CommitError::References { strong_count }) => assert_eq!(strong_count, 2),
+        _ => panic!("Committed a transaction with other references"),
+    }
+    // The other reference can still be committed.
+    txn2.commit()?;
+    let txn = env.txn_begin()?;
+    assert!(txn.load_channel("main")?.is_some());
+    Ok(())
+}
+
+#[test]
+fn remote_paths() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let id = RemoteId::from_bytes(&[1; 16]).unwrap();
+    let other = RemoteId::from_bytes(&[2; 16]).unwrap();
+    {
+        let mut txn = env.mut_txn_begin()?;
+        txn.open_or_create_remote(id, "remote")?;
+        txn.open_or_create_remote(other, "other")?;
+        assert!(txn.remote_paths(&id)?.is_empty());
+        txn.set_remote_paths(&id, &["b".to_string(), "a/c".to_string()])?;
+        txn.set_remote_paths(&other, &["d".to_string()])?;
+        txn.set_remote_paths(&id, &["a/c".to_string(), "b".to_string(), "e".to_string()])?;
+        assert!(txn.set_remote_paths(&id, &["x".repeat(300)]).is_err());
+        txn.commit()?;
+    }
+    let mut txn = env.mut_txn_begin()?;
+    assert_eq!(txn.remote_paths(&id)?, vec!["a/c", "b", "e"]);
+    assert_eq!(txn.remote_paths(&other)?, vec!["d"]);
+    txn.drop_named_remote(id)?;
+    assert!(txn.remote_paths(&id)?.is_empty());
+    assert_eq!(txn.remote_paths(&other)?, vec!["d"]);
+    Ok(())
+}
+
+#[test]
+fn remote_validator() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let id = RemoteId::from_bytes(&[1; 16]).unwrap();
+    let other = RemoteId::from_bytes(&[2; 16]).unwrap();
+    {
+        let mut txn = env.mut_txn_begin()?;
+        txn.open_or_create_remote(id, "remote")?;
+        txn.open_or_create_remote(other, "other")?;
+        assert_eq!(txn.remote_validator(&id)?, None);
+        txn.set_remote_validator(&id, Some("a \"etag\""))?;
+        txn.set_remote_validator(&id, Some("b \"etag2\""))?;
+        txn.set_remote_validator(&other, Some("c \"etag\""))?;
+        txn.commit()?;
+    }
+    let mut txn = env.mut_txn_begin()?;
+    assert_eq!(txn.remote_validator(&id)?.as_deref(), Some("b \"etag2\""));
+    txn.set_remote_validator(&other, Some(&"x".repeat(300)))?;
+    assert_eq!(txn.remote_validator(&other)?, None);
+    txn.drop_named_remote(id)?;
+    assert_eq!(txn.remote_validator(&id)?, None);
+    Ok(())
+}
+
+#[test]
+fn remote_capabilities() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let id = RemoteId::from_bytes(&[1; 16]).unwrap();
+    let other = RemoteId::from_bytes(&[2; 16]).unwrap();
+    {
+        let mut txn = env.mut_txn_begin()?;
+        txn.open_or_create_remote(id, "remote")?;
+        txn.open_or_create_remote(other, "other")?;
+        assert_eq!(txn.remote_capabilities(&id)?, None);
+        txn.set_remote_capabilities(&id, Some("{\"tags\":false}"))?;
+        txn.set_remote_capabilities(&id, Some("{\"tags\":true}"))?;
+        txn.set_remote_capabilities(&other, Some("{}"))?;
+        txn.commit()?;
+    }
+    let mut txn = env.mut_txn_begin()?;
+    assert_eq!(
+        txn.remote_capabilities(&id)?.as_deref(),
+        Some("{\"tags\":true}")
+    );
+    txn.set_remote_capabilities(&other, None)?;
+    assert_eq!(txn.remote_capabilities(&other)?, None);
+    txn.drop_named_remote(id)?;
+    assert_eq!(txn.remote_capabilities(&id)?, None);
+    Ok(())
+}
+
+/// Channel metadata longer than a small string is split, and moved
+/// with the channel.
+#[test]
+fn long_channel_metadata() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let mut txn = env.mut_txn_begin()?;
+    let mut channel = txn.open_or_create_channel("a")?;
+    txn.open_or_create_channel("b")?;
+    let meta = ChannelMetadata {
+        description: Some("é".repeat(400)),
+        ..ChannelMetadata::default()
+    };
+    txn.set_channel_metadata("a", &meta)?;
+    let short = ChannelMetadata {
+        description: Some("short".to_string()),
+        ..ChannelMetadata::default()
+    };
+    txn.set_channel_metadata("b", &short)?;
+    assert_eq!(txn.channel_metadata("a")?, Some(meta.clone()));
+    assert_eq!(txn.channel_metadata("b")?, Some(short.clone()));
+
+    txn.rename_channel(&mut channel, "c")?;
+    assert_eq!(txn.channel_metadata("a")?, None);
+    assert_eq!(txn.channel_metadata("c")?, Some(meta));
+    txn.set_channel_metadata("c", &short)?;
+    assert_eq!(txn.channel_metadata("c")?, Some(short));
+    txn.set_channel_metadata("c", &ChannelMetadata::default())?;
+    assert_eq!(txn.channel_metadata("c")?, None);
+    Ok(())
+}
+
+/// Remote caches are dropped when they are too large or unused for
+/// too long.
+#[test]
+fn remote_cache_expiry() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+    use pristine::{expire_remote_caches, CacheExpiry, RemoteCachePolicy, RemoteId};
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let large = RemoteId::from_bytes(&[1; 16]).unwrap();
+    let old = RemoteId::from_bytes(&[2; 16]).unwrap();
+    let recent = RemoteId::from_bytes(&[3; 16]).unwrap();
+    let day = 86400;
+    let now = 100 * day;
+    {
+        let mut txn = env.mut_txn_begin()?;
+        let mut r = txn.open_or_create_remote(large, "large")?;
+        let mut m = Merkle::zero();
+        for i in 0..3 {
+            let h = Hash::Blake3([i as u8 + 1; 32]);
+            m = m.next(&h);
+            txn.put_remote(&mut r, i, (h, m))?;
+        }
+        txn.touch_remote(&large, Some(now))?;
+        txn.open_or_create_remote(old, "old")?;
+        txn.touch_remote(&old, Some(now - 30 * day))?;
+        txn.open_or_create_remote(recent, "recent")?;
+        txn.touch_remote(&recent, Some(now - day))?;
+        txn.commit()?;
+    }
+    let policy = RemoteCachePolicy {
+        max_entries: Some(2),
+        max_age: Some(7 * day),
+    };
+    let mut txn = env.mut_txn_begin()?;
+    let caches = expire_remote_caches(&mut txn, &policy, now, true)?;
+    let expired: Vec<_> = caches.iter().map(|c| (c.id, c.expired)).collect();
+    assert_eq!(
+        expired,
+        vec![
+            (large, Some(CacheExpiry::TooLarge)),
+            (old, Some(CacheExpiry::Unused)),
+            (recent, None)
+        ]
+    );
+    assert_eq!(caches[0].size.entries, 3);
+    // A dry run doesn't drop anything.
+    assert!(txn.load_remote(&old)?.is_some());
+
+    expire_remote_caches(&mut txn, &policy, now, false)?;
+    assert!(txn.load_remote(&large)?.is_none());
+    assert!(txn.load_remote(&old)?.is_none());
+    assert!(txn.load_remote(&recent)?.is_some());
+    assert_eq!(txn.remote_last_used(&recent)?, Some(now - day));
+    assert_eq!(txn.remote_last_used(&old)?, None);
+    Ok(())
+}
+
+/// A layered change store reads the changes it doesn't have from
+/// another store, and only saves new changes to its top layer.
+#[test]
+fn layered_changestore() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let bottom = changestore::memory::Memory::new();
+    let top = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    let h0 = record_all(&repo, &bottom, &txn, &channel, "")?;
+
+    let changes = changestore::layered::Layered::new(top.clone(), Some(bottom.clone()));
+    repo.write_file("file", Inode::ROOT)?.write_all(b"a\nb\n")?;
+    let h1 = record_all(&repo, &changes, &txn, &channel, "")?;
+    assert!(top.get_change(&h1).is_ok());
+    assert!(bottom.get_change(&h1).is_err());
+    assert_eq!(
+        changes.get_header(&h0)?.message,
+        bottom.get_header(&h0)?.message
+    );
+
+    let repo2 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo2, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    let mut buf = Vec::new();
+    repo2.read_file("file", &mut buf)?;
+    assert_eq!(buf, b"a\nb\n");
+
+    assert_eq!(changes.stats()?.changes, 2);
+    // The bottom layer is never modified.
+    assert!(!changes.del_change(&h0)?);
+    assert!(bottom.get_change(&h0).is_ok());
+    Ok(())
+}
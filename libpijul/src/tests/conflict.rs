@@ -2727,3 +2727,76 @@ fn check_tree_inodes<T: TxnT>(txn: &T, channel: &T::Channel) {
         }
     }
 }
+
+/// Previewing the conflicts of a change, without applying it.
+#[test]
+fn preview_order_conflict() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo_alice = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo_alice.add_file("file", b"a\nb\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel_alice = txn.write().open_or_create_channel("alice")?;
+    txn.write().add_file("file", 0)?;
+    let init_h = record_all(&repo_alice, &changes, &txn, &channel_alice, "")?;
+
+    let repo_bob = working_copy::memory::Memory::new();
+    let channel_bob = txn.write().fork(&channel_alice, "bob")?;
+    output::output_repository_no_pending(
+        &repo_bob,
+        &changes,
+        &txn,
+        &channel_bob,
+        "",
+        true,
+        None,
+        1,
+        0,
+    )?;
+    repo_bob
+        .write_file("file", Inode::ROOT)?
+        .write_all(b"a\nu\nb\n")?;
+    let bob_h = record_all(&repo_bob, &changes, &txn, &channel_bob, "")?;
+
+    repo_alice
+        .write_file("file", Inode::ROOT)?
+        .write_all(b"a\nx\nb\n")?;
+    let alice_h = record_all(&repo_alice, &changes, &txn, &channel_alice, "")?;
+
+    // Nothing new for changes Alice already has.
+    let conflicts = crate::preview_conflicts(&txn, &channel_alice, &changes, &[init_h])?;
+    assert!(conflicts.is_empty());
+
+    let conflicts = crate::preview_conflicts(&txn, &channel_alice, &changes, &[bob_h])?;
+    let conflicts: Vec<_> = conflicts.into_iter().collect();
+    assert_eq!(conflicts.len(), 1);
+    assert!(matches!(conflicts[0], Conflict::Order { ref path, .. } if path == "file"));
+
+    // Alice's channel is unchanged, and no channel was created.
+    {
+        let txn = txn.read();
+        assert!(txn.has_change(&channel_alice, &alice_h)?.is_some());
+        assert!(txn.has_change(&channel_alice, &bob_h)?.is_none());
+        assert_eq!(txn.channels("")?.len(), 2);
+    }
+
+    // Once Alice has Bob's change, a change moving the conflict
+    // down doesn't create a new one.
+    apply::apply_change(
+        &changes,
+        &mut *txn.write(),
+        &mut *channel_alice.write(),
+        &bob_h,
+    )?;
+    repo_bob
+        .write_file("file", Inode::ROOT)?
+        .write_all(b"z\na\nu\nb\n")?;
+    let bob_h2 = record_all(&repo_bob, &changes, &txn, &channel_bob, "")?;
+    let conflicts = crate::preview_conflicts(&txn, &channel_alice, &changes, &[bob_h2])?;
+    assert!(conflicts.is_empty());
+    assert!(txn.read().has_change(&channel_alice, &bob_h2)?.is_none());
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use crate::changestore::FileMetadata;
+use crate::pristine::InodeMetadata;
+use crate::text_encoding::Encoding;
+
+quickcheck! {
+    fn file_metadata_roundtrip(
+        metadata: u16,
+        basename: String,
+        encoding: Option<Encoding>
+    ) -> bool {
+        let meta = FileMetadata {
+            metadata: InodeMetadata(metadata),
+            basename: &basename,
+            encoding: encoding.clone(),
+        };
+        let mut buf = Vec::new();
+        meta.write(&mut buf);
+        let read = FileMetadata::try_read(&buf).unwrap();
+        read.metadata == meta.metadata && read.basename == basename && read.encoding == encoding
+    }
+
+    // Reading arbitrary bytes returns an error instead of panicking.
+    fn file_metadata_read_any(buf: Vec<u8>) -> () {
+        let _ = FileMetadata::try_read(&buf);
+    }
+
+    // Truncated or corrupted metadata never panics either.
+    fn file_metadata_corrupt(basename: String, cut: usize, flip: usize) -> () {
+        let meta = FileMetadata {
+            metadata: InodeMetadata(0o644),
+            basename: &basename,
+            encoding: Some(Encoding(encoding_rs::UTF_8)),
+        };
+        let mut buf = Vec::new();
+        meta.write(&mut buf);
+        let mut corrupt = buf.clone();
+        let i = flip % corrupt.len();
+        corrupt[i] ^= 0xff;
+        let _ = FileMetadata::try_read(&corrupt);
+        let _ = FileMetadata::try_read(&buf[..cut % buf.len()]);
+    }
+}
+
+#[test]
+fn file_metadata_old_format() {
+    let meta = FileMetadata::try_read(b"\x01\xa4file.txt").unwrap();
+    assert_eq!(meta.metadata, InodeMetadata(0x01a4));
+    assert_eq!(meta.basename, "file.txt");
+    assert!(meta.encoding.is_none());
+    assert!(FileMetadata::try_read(b"\x01").is_err());
+    assert!(FileMetadata::try_read(b"\x01\xa4\xff").is_err());
+}
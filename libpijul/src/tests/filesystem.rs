@@ -3,6 +3,7 @@ use std::io::Write;
 
 const MAX_FILES: usize = 10;
 
+#[cfg(feature = "mmap")]
 #[test]
 fn filesystem() -> Result<(), anyhow::Error> {
     env_logger::try_init().unwrap_or(());
@@ -39,6 +40,7 @@ fn filesystem() -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+#[cfg(feature = "mmap")]
 #[test]
 fn symlink() -> Result<(), anyhow::Error> {
     env_logger::try_init().unwrap_or(());
@@ -78,6 +80,7 @@ fn symlink() -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+#[cfg(feature = "mmap")]
 #[test]
 fn record_dead_symlink() -> Result<(), anyhow::Error> {
     env_logger::try_init().unwrap_or(());
@@ -109,6 +112,7 @@ fn record_dead_symlink() -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+#[cfg(feature = "mmap")]
 #[test]
 fn overwrite_dead_symlink() -> Result<(), anyhow::Error> {
     env_logger::try_init().unwrap_or(());
@@ -147,3 +151,240 @@ fn overwrite_dead_symlink() -> Result<(), anyhow::Error> {
     txn.commit().unwrap();
     Ok(())
 }
+
+#[test]
+fn changestore_iter_hashes() -> Result<(), anyhow::Error> {
+    use crate::changestore::StoredHash;
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let f = tempfile::tempdir()?;
+    let changes = changestore::filesystem::FileSystem::from_root(f.path(), MAX_FILES);
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main").unwrap();
+
+    repo.add_file("a", b"a\nb\n".to_vec());
+    txn.write().add_file("a", 0)?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.add_file("b", b"c\n".to_vec());
+    txn.write().add_file("b", 0)?;
+    let h1 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let hashes = changes
+        .iter_hashes()?
+        .map(|e| e.map(|e| e.hash))
+        .collect::<Result<HashSet<_>, _>>()?;
+    let expected: HashSet<_> = vec![StoredHash::Change(h0), StoredHash::Change(h1)]
+        .into_iter()
+        .collect();
+    assert_eq!(hashes, expected);
+
+    let stats = changes.stats()?;
+    assert_eq!(stats.changes, 2);
+    assert_eq!(stats.tags, 0);
+    assert_eq!(
+        stats.changes_size,
+        std::fs::metadata(changes.filename(&h0))?.len()
+            + std::fs::metadata(changes.filename(&h1))?.len()
+    );
+    Ok(())
+}
+
+#[test]
+fn changestore_get_contents_batch() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let f = tempfile::tempdir()?;
+    let changes = changestore::filesystem::FileSystem::from_root(f.path(), MAX_FILES);
+    repo.add_file("file", b"a\nb\nc\nd\ne\nf\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("file", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+    let h = record_all(&repo, &changes, &txn, &channel, "")?;
+    let id = *txn.read().get_internal(&h.into())?.unwrap();
+    let len = changes.get_change(&h)?.contents.len();
+    assert!(len > 8);
+
+    // Two consecutive vertices, a gap, and an empty vertex.
+    let vertex = |start: usize, end: usize| Vertex {
+        change: id,
+        start: ChangePosition(start.into()),
+        end: ChangePosition(end.into()),
+    };
+    let keys = [vertex(0, 2), vertex(2, 5), vertex(7, 7), vertex(7, len)];
+    let mut expected = Vec::new();
+    for k in keys.iter() {
+        let mut buf = vec![0; k.end - k.start];
+        changes.get_contents(|_| Some(h), *k, &mut buf)?;
+        expected.extend(buf);
+    }
+    let mut batch = Vec::new();
+    changes.get_contents_batch(|_| Some(h), &keys, &mut batch)?;
+    assert_eq!(batch, expected);
+    Ok(())
+}
+
+#[test]
+fn atomic_output() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let r = tempfile::tempdir()?;
+    let repo = working_copy::filesystem::FileSystem::from_root(r.path());
+    let f = tempfile::tempdir()?;
+    let changes = changestore::filesystem::FileSystem::from_root(f.path(), MAX_FILES);
+    repo.write_file("dir/file", Inode::ROOT)?
+        .write_all(&b"a\nb\nc\n"[..])?;
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("dir/file", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+    let p = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let r2 = tempfile::tempdir()?;
+    let mut repo2 = working_copy::filesystem::FileSystem::from_root(r2.path());
+    repo2.set_write_options(working_copy::filesystem::WriteOptions {
+        atomic: true,
+        fsync: working_copy::filesystem::FsyncPolicy::Batch,
+    });
+    let env2 = pristine::sanakirja::Pristine::new_anon()?;
+    let txn2 = env2.arc_txn_begin().unwrap();
+    let channel2 = txn2.write().open_or_create_channel("main")?;
+    apply::apply_change_arc(&changes, &txn2, &channel2, &p)?;
+    let conflicts = output::output_repository_no_pending(
+        &repo2, &changes, &txn2, &channel2, "", true, None, 1, 0,
+    )?;
+    assert!(conflicts.is_empty());
+    assert_eq!(std::fs::read(r2.path().join("dir/file"))?, b"a\nb\nc\n");
+    // No temporary file is left behind.
+    let entries: Vec<_> = std::fs::read_dir(r2.path().join("dir"))?
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    assert_eq!(entries, vec![std::ffi::OsString::from("file")]);
+    Ok(())
+}
+
+/// Copying a pristine to a fresh file keeps all its channels.
+#[cfg(feature = "mmap")]
+#[test]
+fn copy_pristine() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\nc\n".to_vec());
+
+    let f = tempfile::tempdir()?;
+    let env = pristine::sanakirja::Pristine::new(f.path().join("pristine"))?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("file", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+    let h = record_all(&repo, &changes, &txn, &channel, "")?;
+    txn.write().fork(&channel, "other")?;
+    txn.write().set_current_channel("other")?;
+    txn.commit()?;
+
+    let txn = env.txn_begin()?;
+    txn.copy_into(f.path().join("copy"))?;
+    let state = txn.current_state(&*txn.load_channel("main")?.unwrap().read())?;
+
+    let env = pristine::sanakirja::Pristine::new(f.path().join("copy"))?;
+    let txn = env.txn_begin()?;
+    assert_eq!(txn.current_channel()?, "other");
+    assert!(txn.is_tracked("file")?);
+    for name in ["main", "other"].iter() {
+        let channel = txn.load_channel(name)?.unwrap();
+        assert!(txn.get_revchanges(&channel, &h)?.is_some());
+        assert_eq!(txn.current_state(&*channel.read())?, state);
+    }
+    Ok(())
+}
+
+/// A file that cannot be written makes the output fail, after the
+/// other files have been written.
+#[test]
+fn output_write_failure() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let r = tempfile::tempdir()?;
+    let repo = working_copy::filesystem::FileSystem::from_root(r.path());
+    let f = tempfile::tempdir()?;
+    let changes = changestore::filesystem::FileSystem::from_root(f.path(), MAX_FILES);
+    repo.write_file("dir/file", Inode::ROOT)?
+        .write_all(&b"a\nb\nc\n"[..])?;
+    repo.write_file("other", Inode::ROOT)?
+        .write_all(&b"d\n"[..])?;
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("dir/file", 0)?;
+    txn.write().add_file("other", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+    let p = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    // A non-empty directory is in the way of `dir/file`.
+    let r2 = tempfile::tempdir()?;
+    std::fs::create_dir_all(r2.path().join("dir/file"))?;
+    std::fs::write(r2.path().join("dir/file/x"), b"x\n")?;
+    let repo2 = working_copy::filesystem::FileSystem::from_root(r2.path());
+    let env2 = pristine::sanakirja::Pristine::new_anon()?;
+    let txn2 = env2.arc_txn_begin().unwrap();
+    let channel2 = txn2.write().open_or_create_channel("main")?;
+    apply::apply_change_arc(&changes, &txn2, &channel2, &p)?;
+    match output::output_repository_no_pending(
+        &repo2, &changes, &txn2, &channel2, "", true, None, 1, 0,
+    ) {
+        Err(output::OutputError::Write(failures)) => {
+            assert_eq!(failures.len(), 1);
+            assert_eq!(failures[0].path, "dir/file");
+        }
+        x => panic!("unexpected result {:?}", x.map(|_| ())),
+    }
+    assert_eq!(std::fs::read(r2.path().join("other"))?, b"d\n");
+    Ok(())
+}
+
+/// What works without memory-mapped files (the `no-mmap` feature): the
+/// working copy and change store on disk, with a pristine in memory.
+#[test]
+fn filesystem_anon_pristine() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let r = tempfile::tempdir()?;
+    let repo = working_copy::filesystem::FileSystem::from_root(r.path());
+    let f = tempfile::tempdir()?;
+    let changes = changestore::filesystem::FileSystem::from_root(f.path(), MAX_FILES);
+    repo.write_file("dir/file", Inode::ROOT)?
+        .write_all(&b"a\nb\nc\n"[..])?;
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("dir/file", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+    let p = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("dir/file", Inode::ROOT)?
+        .write_all(&b"a\nx\nc\n"[..])?;
+    let p2 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    // Changes are read back from the disk by a new store.
+    let changes = changestore::filesystem::FileSystem::from_root(f.path(), MAX_FILES);
+    assert_eq!(changes.get_change(&p2)?.dependencies, vec![p]);
+
+    let r2 = tempfile::tempdir()?;
+    let repo2 = working_copy::filesystem::FileSystem::from_root(r2.path());
+    let env2 = pristine::sanakirja::Pristine::new_anon()?;
+    let txn2 = env2.arc_txn_begin().unwrap();
+    let channel2 = txn2.write().open_or_create_channel("main")?;
+    apply::apply_change_arc(&changes, &txn2, &channel2, &p2)?;
+    let conflicts = output::output_repository_no_pending(
+        &repo2, &changes, &txn2, &channel2, "", true, None, 1, 0,
+    )?;
+    assert!(conflicts.is_empty());
+    assert_eq!(std::fs::read(r2.path().join("dir/file"))?, b"a\nx\nc\n");
+    txn2.commit()?;
+    Ok(())
+}
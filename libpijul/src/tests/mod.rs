@@ -12,12 +12,16 @@ mod clone;
 mod conflict;
 mod diff;
 mod file_conflicts;
+mod file_metadata;
+#[cfg(any(feature = "ondisk-repos", feature = "no-mmap"))]
 mod filesystem;
 mod missing_context;
 mod partial;
 mod performance;
 mod rm_file;
 mod rollback;
+#[cfg(any(feature = "ondisk-repos", feature = "no-mmap"))]
+mod tag;
 mod text;
 mod text_changes;
 mod unrecord;
@@ -59,7 +63,7 @@ where
         &*txn.read(),
         &channel.clone(),
         changes,
-        std::mem::take(&mut *rec.contents.lock()),
+        std::mem::take(&mut *rec.contents.lock()).into_vec()?,
         crate::change::ChangeHeader {
             message: "test".to_string(),
             authors: vec![],
@@ -129,3 +133,32 @@ where
         .unwrap();
     Ok(hash)
 }
+
+/// Record the whole working copy with a `Builder` set up by `setup`,
+/// without saving or applying the change.
+fn record_with<
+    T: MutTxnT + Send + Sync + 'static,
+    R: WorkingCopy + Clone + Send + Sync + 'static,
+    P: ChangeStore + Clone + Send + 'static,
+>(
+    repo: &R,
+    store: &P,
+    txn: &ArcTxn<T>,
+    channel: &ChannelRef<T>,
+    setup: impl FnOnce(&mut Builder),
+) -> Result<crate::record::Recorded, crate::record::RecordError<P::Error, R::Error, T>> {
+    let mut state = Builder::new();
+    setup(&mut state);
+    state.record(
+        txn.clone(),
+        Algorithm::default(),
+        false,
+        &crate::DEFAULT_SEPARATOR,
+        channel.clone(),
+        repo,
+        store,
+        "",
+        1,
+    )?;
+    Ok(state.finish())
+}
@@ -160,3 +160,77 @@ fn linear_context_repair() {
     }
     txn.commit().unwrap();
 }
+
+/// Pseudo-edges implied by newer lines are deleted by the
+/// compaction, which doesn't change the file.
+#[test]
+fn compact_pseudo_edges() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\nc\nd\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    let versions: [&[u8]; 3] = [b"a\nd\n", b"a\nx\nd\n", b"a\nx\ny\nd\n"];
+    for contents in versions.iter() {
+        repo.write_file("file", Inode::ROOT)?.write_all(contents)?;
+        record_all(&repo, &changes, &txn, &channel, "")?;
+    }
+
+    let stats = txn.write().compact_pseudo_edges(&channel)?;
+    debug!("{:?}", stats);
+    // The pseudo-edge from `a` to `d` is implied by `x` and `y`.
+    assert!(stats.redundant > 0);
+    assert!(stats.after < stats.before);
+    // Compacting again has nothing to do.
+    let again = txn.write().compact_pseudo_edges(&channel)?;
+    assert_eq!(again.redundant, 0);
+    assert_eq!(again.before, stats.after);
+
+    let repo2 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo2, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    let mut buf = Vec::new();
+    repo2.read_file("file", &mut buf)?;
+    assert_eq!(buf, b"a\nx\ny\nd\n");
+    Ok(())
+}
+
+#[test]
+fn table_stats() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("file", Inode::ROOT)?
+        .write_all(b"a\nx\nb\n")?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let stats = txn.read().table_stats()?;
+    debug!("{:#?}", stats);
+    let get = |table: &str, channel: Option<&str>| {
+        stats
+            .iter()
+            .find(|s| s.table == table && s.channel.as_deref() == channel)
+            .unwrap()
+    };
+    assert_eq!(get("internal", None).entries, 2);
+    assert_eq!(get("channels", None).entries, 1);
+    assert_eq!(get("changes", Some("main")).entries, 2);
+    let graph = get("graph", Some("main"));
+    assert!(graph.entries > 0);
+    assert!(graph.pages > 0);
+    assert_eq!(graph.bytes(), graph.pages * 4096);
+    Ok(())
+}
@@ -141,3 +141,62 @@ fn rollback_conflict_resolution_swap() -> Result<(), anyhow::Error> {
 
     Ok(())
 }
+
+#[test]
+fn invert_change_test() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+
+    repo.add_file("file", b"a\nb\nc\n".to_vec());
+    txn.write().add_file("file", 0)?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    repo.write_file("file", Inode::ROOT)
+        .unwrap()
+        .write_all(b"a\nx\nc\n")?;
+    let h1 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let mut inv = crate::invert_change(
+        &*txn.read(),
+        &channel,
+        &changes,
+        &h1,
+        crate::change::ChangeHeader {
+            authors: vec![],
+            message: "rollback".to_string(),
+            description: None,
+            timestamp: chrono::Utc::now(),
+        },
+    )?;
+    assert!(inv.dependencies.contains(&h1));
+    let h_inv = changes.save_change(&mut inv, |_, _| Ok::<_, anyhow::Error>(()))?;
+    apply::apply_change_arc(&changes, &txn, &channel, &h_inv)?;
+    output::output_repository_no_pending(&repo, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    let mut buf = Vec::new();
+    repo.read_file("file", &mut buf)?;
+    assert_eq!(std::str::from_utf8(&buf)?, "a\nb\nc\n");
+
+    // Changes that are not in the channel cannot be inverted.
+    let env2 = pristine::sanakirja::Pristine::new_anon()?;
+    let txn2 = env2.arc_txn_begin().unwrap();
+    let channel2 = txn2.write().open_or_create_channel("main")?;
+    assert!(crate::invert_change(
+        &*txn2.read(),
+        &channel2,
+        &changes,
+        &h0,
+        crate::change::ChangeHeader {
+            authors: vec![],
+            message: "rollback".to_string(),
+            description: None,
+            timestamp: chrono::Utc::now(),
+        },
+    )
+    .is_err());
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use super::*;
+
+/// Tag files are only kept if they match their state, and replaying
+/// the log of a channel in another channel (as `pijul tag restore`
+/// does) gives the same tag file.
+#[test]
+fn check_and_restore_tag_files() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\nc\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("file", 0)?;
+    let channel = (&mut *txn.write()).open_or_create_channel("main")?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+    let state0 = txn.read().current_state(&*channel.read())?;
+    repo.add_file("file", b"a\nb\nx\nc\n".to_vec());
+    let h1 = record_all(&repo, &changes, &txn, &channel, "")?;
+    let state1 = txn.read().current_state(&*channel.read())?;
+
+    let dir = tempfile::tempdir()?;
+    let header = change::ChangeHeader::default();
+    let mut tag_path = dir.path().to_path_buf();
+    for state in [state0, state1].iter() {
+        // Both files are written from the current state, so only the
+        // second one is valid.
+        changestore::filesystem::push_tag_filename(&mut tag_path, state);
+        std::fs::create_dir_all(tag_path.parent().unwrap())?;
+        let w = std::fs::File::create(&tag_path)?;
+        assert_eq!(tag::from_channel(&*txn.read(), "main", &header, w)?, state1);
+        changestore::filesystem::pop_filename(&mut tag_path);
+    }
+    let tags = vec![(0, state0), (1, state1), (2, Merkle::zero())];
+
+    // Files read from a referenced store are checked, but never
+    // deleted.
+    let top = tempfile::tempdir()?;
+    let layered = changestore::layered::Layered::new(
+        changestore::filesystem::FileSystem::from_changes(top.path().to_path_buf(), 1),
+        Some(changestore::filesystem::FileSystem::from_changes(
+            dir.path().to_path_buf(),
+            1,
+        )),
+    );
+    assert_eq!(
+        tag::check_files(&layered, tags.clone(), 2)?,
+        vec![(1, state1)]
+    );
+    changestore::filesystem::push_tag_filename(&mut tag_path, &state0);
+    assert!(std::fs::metadata(&tag_path).is_ok());
+    changestore::filesystem::pop_filename(&mut tag_path);
+
+    let store = changestore::layered::Layered::new(layered.bottom.unwrap(), None);
+    assert_eq!(tag::check_files(&store, tags, 2)?, vec![(1, state1)]);
+    changestore::filesystem::push_tag_filename(&mut tag_path, &state0);
+    assert!(std::fs::metadata(&tag_path).is_err());
+    changestore::filesystem::pop_filename(&mut tag_path);
+
+    let tmp = (&mut *txn.write()).open_or_create_channel("tmp")?;
+    for h in [h0, h1].iter() {
+        apply::apply_change(&changes, &mut *txn.write(), &mut *tmp.write(), h)?;
+    }
+    let restored = tag::from_channel(&*txn.read(), "tmp", &header, Vec::new())?;
+    assert_eq!(restored, state1);
+    Ok(())
+}
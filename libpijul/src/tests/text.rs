@@ -1,5 +1,5 @@
 use super::*;
-use crate::working_copy::WorkingCopy;
+use crate::working_copy::{WorkingCopy, WorkingCopyRead};
 
 #[test]
 fn add_non_utf8_file_test() -> Result<(), anyhow::Error> {
@@ -90,3 +90,181 @@ fn change_non_utf8_file_test() -> Result<(), anyhow::Error> {
 
     Ok(())
 }
+
+#[test]
+fn graph_status_test() -> Result<(), anyhow::Error> {
+    use crate::change::{Atom, Hunk};
+    use std::io::Write;
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("file", Inode::ROOT)?.write_all(b"a\n")?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let change0 = changes.get_change(&h0)?;
+    let contents = change0
+        .changes
+        .iter()
+        .find_map(|h| match h {
+            Hunk::FileAdd {
+                contents: Some(Atom::NewVertex(n)),
+                ..
+            } => Some(n.clone()),
+            _ => None,
+        })
+        .unwrap();
+    let txn = txn.read();
+    let channel = channel.read();
+    let g = txn.graph(&*channel);
+    let c0 = *txn.get_internal(&h0.into())?.unwrap();
+
+    // "a\n" is still alive, "b\n" was deleted.
+    let a = graph::find_block(
+        &*txn,
+        g,
+        Position {
+            change: c0,
+            pos: contents.start,
+        },
+    )?;
+    assert_eq!(graph::status(&*txn, g, &a)?, graph::VertexStatus::Alive);
+    assert!(graph::alive_children(&*txn, g, a)?.next().is_some());
+    let b = graph::find_block_end(
+        &*txn,
+        g,
+        Position {
+            change: c0,
+            pos: contents.end,
+        },
+    )?;
+    assert_ne!(a, b);
+    assert_eq!(graph::status(&*txn, g, &b)?, graph::VertexStatus::Deleted);
+    assert!(graph::is_deleted(&*txn, g, &b)?);
+    assert!(!graph::is_zombie(&*txn, g, &b)?);
+    assert!(graph::deleted_parents(&*txn, g, b)?.next().is_some());
+    for e in graph::parents(&*txn, g, b)? {
+        assert!(e?.flag().contains(graph::EdgeFlags::PARENT))
+    }
+    Ok(())
+}
+
+/// Split a vertex with the public API, navigate between its parts,
+/// and check that invalid splits are errors.
+#[test]
+fn graph_split_test() -> Result<(), anyhow::Error> {
+    use crate::change::{Atom, Hunk};
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let change0 = changes.get_change(&h0)?;
+    let contents = change0
+        .changes
+        .iter()
+        .find_map(|h| match h {
+            Hunk::FileAdd {
+                contents: Some(Atom::NewVertex(n)),
+                ..
+            } => Some(n.clone()),
+            _ => None,
+        })
+        .unwrap();
+    let c0 = *txn.read().get_internal(&h0.into())?.unwrap();
+    let pos = Position {
+        change: c0,
+        pos: contents.start + 2,
+    };
+    {
+        let mut txn = txn.write();
+        let mut channel = channel.write();
+        let g = pristine::sanakirja::MutTxn::<()>::graph_mut(&mut *channel);
+        let v = graph::find_block(&*txn, g, pos)?;
+        assert_eq!(graph::next_block(&*txn, g, v)?, None);
+
+        let a = graph::split_at(&mut *txn, g, pos)?;
+        assert_eq!(a.start_pos(), pos);
+        // Splitting again at the same position does nothing.
+        assert_eq!(graph::split_at(&mut *txn, g, pos)?, a);
+        let b = graph::previous_block(&*txn, g, a)?.unwrap();
+        assert_eq!(b.end_pos(), pos);
+        assert_eq!(graph::next_block(&*txn, g, b)?, Some(a));
+        assert!(graph::is_alive(&*txn, g, &a)?);
+        assert!(graph::is_alive(&*txn, g, &b)?);
+
+        // `a` can't be split at its start, and `v` isn't a vertex
+        // anymore.
+        assert!(matches!(
+            graph::split_vertex(&mut *txn, g, a, a.start),
+            Err(graph::SplitError::InvalidPosition { .. })
+        ));
+        assert!(matches!(
+            graph::split_vertex(&mut *txn, g, v, pos.pos + 1),
+            Err(graph::SplitError::VertexNotFound { .. })
+        ));
+    }
+
+    // The file is unchanged.
+    let repo2 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo2, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    let mut buf = Vec::new();
+    repo2.read_file("file", &mut buf)?;
+    assert_eq!(buf, b"a\nb\n");
+    Ok(())
+}
+
+/// Output a file along with the change each of its bytes comes from.
+#[test]
+fn output_file_annotated_test() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    repo.add_file("file", b"a\nb\n".to_vec());
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    let store = changestore::memory::Memory::new();
+    let h0 = record_all(&repo, &store, &txn, &channel, "")?;
+
+    use std::io::Write;
+    repo.write_file("file", Inode::ROOT)
+        .unwrap()
+        .write_all(b"a\nx\nb\n")?;
+    let h1 = record_all(&repo, &store, &txn, &channel, "")?;
+
+    let (pos, _) = txn.read().follow_oldest_path(&store, &channel, "file")?;
+    let (contents, annotations) = output::output_file_annotated(&store, &txn, &channel, pos)?;
+    assert_eq!(contents, b"a\nx\nb\n");
+
+    let id0 = *txn.read().get_internal(&h0.into())?.unwrap();
+    let id1 = *txn.read().get_internal(&h1.into())?.unwrap();
+    let mut covered = 0;
+    for a in annotations.iter() {
+        assert_eq!(a.start, covered);
+        let expected = if &contents[a.start..a.end] == b"x\n" {
+            id1
+        } else {
+            id0
+        };
+        assert_eq!(a.vertex.change, expected);
+        covered = a.end
+    }
+    assert_eq!(covered, contents.len());
+    Ok(())
+}
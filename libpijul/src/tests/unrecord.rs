@@ -825,3 +825,410 @@ fn double_file() -> Result<(), anyhow::Error> {
     assert!(inodes.next().is_none());
     Ok(())
 }
+
+/// Classify changes unrecorded from a "remote" relative to a local
+/// channel.
+#[test]
+fn classify_remote_unrecord() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\nc\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("file", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    repo.write_file("file", Inode::ROOT)?
+        .write_all(b"a\nx\nc\n")?;
+    let h1 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    {
+        let txn = txn.read();
+        let channel = channel.read();
+        match txn.classify_remote_unrecord(&channel, &h0)? {
+            crate::RemoteUnrecord::DependedUpon { dependents, .. } => {
+                assert_eq!(dependents, vec![h1])
+            }
+            c => panic!("unexpected classification {:?}", c),
+        }
+        assert!(matches!(
+            txn.classify_remote_unrecord(&channel, &h1)?,
+            crate::RemoteUnrecord::InChannel { .. }
+        ));
+    }
+
+    crate::unrecord::unrecord(&mut *txn.write(), &channel, &changes, &h1, 0)?;
+    let txn = txn.read();
+    let channel = channel.read();
+    assert_eq!(
+        txn.classify_remote_unrecord(&channel, &h1)?,
+        crate::RemoteUnrecord::NotInChannel
+    );
+    assert!(matches!(
+        txn.classify_remote_unrecord(&channel, &h0)?,
+        crate::RemoteUnrecord::InChannel { .. }
+    ));
+    assert_eq!(
+        txn.classify_remote_unrecord(&channel, &Hash::Blake3([1; 32]))?,
+        crate::RemoteUnrecord::Unknown
+    );
+    Ok(())
+}
+
+/// The headers of applied changes are cached in the pristine, and
+/// removed from the cache when the change is unrecorded.
+#[test]
+fn cached_header() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("file", 0)?;
+    let mut channel = txn.write().open_or_create_channel("main")?;
+
+    let mut author = std::collections::BTreeMap::new();
+    author.insert("key".to_string(), "KEY".to_string());
+    let header = crate::change::ChangeHeader {
+        message: format!("First line\n\n{}", "x".repeat(1000)),
+        authors: vec![crate::change::Author(author)],
+        timestamp: chrono::Utc.timestamp(1_000_000, 0),
+        ..crate::change::ChangeHeader::default()
+    };
+    let (mut change, mut contents, updates) =
+        crate::record_all_with_header(&txn, &channel, &repo, &changes, "", header)?.unwrap();
+    let hash = changes.save_change_with_contents(&mut change, &mut contents, |_, _| {
+        Ok::<_, anyhow::Error>(())
+    })?;
+    txn.write()
+        .apply_local_change(&mut channel, &change, &hash, &updates)?;
+
+    let id = *txn.read().get_internal(&hash.into())?.unwrap();
+    assert_eq!(
+        txn.read().get_cached_header(&id)?,
+        Some(CachedHeader {
+            authors: vec!["KEY".to_string()],
+            timestamp: 1_000_000,
+            message: "First line".to_string(),
+        })
+    );
+
+    // Long messages are truncated to fit in the cache.
+    let long = CachedHeader {
+        message: "é".repeat(1000),
+        ..CachedHeader::default()
+    };
+    txn.write().put_cached_header(&id, &long)?;
+    let cached = txn.read().get_cached_header(&id)?.unwrap();
+    assert!(!cached.message.is_empty());
+    assert!(long.message.starts_with(&cached.message));
+
+    crate::unrecord::unrecord(&mut *txn.write(), &channel, &changes, &hash, 0)?;
+    assert!(txn.read().get_cached_header(&id)?.is_none());
+    Ok(())
+}
+
+/// Files can be found by the names they had in the past, which are
+/// removed from the path index when the changes giving them are
+/// unrecorded.
+#[test]
+fn path_index() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("file", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    let (inode, _) = txn.read().follow_oldest_path(&changes, &channel, "file")?;
+    assert_eq!(
+        txn.read().path_inodes(&changes, &channel, "file")?,
+        vec![inode]
+    );
+
+    txn.write().move_file("file", "file2", 0)?;
+    repo.rename("file", "file2")?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    assert_eq!(
+        txn.read().path_inodes(&changes, &channel, "file")?,
+        vec![inode]
+    );
+    assert_eq!(
+        txn.read().path_inodes(&changes, &channel, "file2")?,
+        vec![inode]
+    );
+
+    repo.remove_path("file2", false)?;
+    let del = record_all(&repo, &changes, &txn, &channel, "")?;
+    assert_eq!(
+        txn.read().path_inodes(&changes, &channel, "file2")?,
+        vec![inode]
+    );
+
+    crate::unrecord::unrecord(&mut *txn.write(), &channel, &changes, &del, 0)?;
+    assert!(txn.read().get_path_inodes("file2")?.is_empty());
+    assert_eq!(txn.read().get_path_inodes("file")?.len(), 2);
+    Ok(())
+}
+
+/// A batch of changes can be unrecorded in any order, and changes
+/// depending on the batch are all reported before anything is
+/// unrecorded.
+#[test]
+fn unrecord_batch() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\nc\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("file", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("file", Inode::ROOT)?
+        .write_all(b"a\nx\nc\n")?;
+    let h1 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("file", Inode::ROOT)?
+        .write_all(b"a\nx\ny\n")?;
+    let h2 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    {
+        let txn = txn.read();
+        let channel = channel.read();
+        match txn.unrecord_order(&channel, &[h0, h1]) {
+            Err(crate::UnrecordOrderError::Blocked { blockers }) => {
+                assert_eq!(
+                    blockers,
+                    vec![crate::Blocker {
+                        change: h1,
+                        dependents: vec![h2]
+                    }]
+                )
+            }
+            r => panic!("unexpected result {:?}", r.map_err(|e| e.to_string())),
+        }
+        assert_eq!(
+            txn.unrecord_order(&channel, &[h0, h2, h1])?,
+            vec![h2, h1, h0]
+        );
+        let closure = txn.unrecord_closure(&channel, &[h1])?;
+        let batch: Vec<_> = closure.iter().map(|d| d.change).collect();
+        assert_eq!(batch, vec![h2, h1]);
+        assert!(closure[0].depends_on.contains(&h1));
+        assert!(closure[1].depends_on.is_empty());
+    }
+
+    let order = txn.write().unrecord_all(&changes, &channel, &[h1, h2], 0)?;
+    assert_eq!(order, vec![h2, h1]);
+    let txn = txn.read();
+    let channel = channel.read();
+    let id0 = *txn.get_internal(&h0.into())?.unwrap();
+    assert!(txn.get_changeset(txn.changes(&channel), &id0)?.is_some());
+    assert!(txn.get_internal(&h1.into())?.is_none());
+    assert!(matches!(
+        txn.unrecord_order(&channel, &[h1]),
+        Err(crate::UnrecordOrderError::ChangeNotInChannel { .. })
+    ));
+    Ok(())
+}
+
+/// The closures of the dependencies are memoized in the transaction,
+/// and forgotten when the dependencies change.
+#[test]
+fn dep_closure() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\nc\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("file", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("file", Inode::ROOT)?
+        .write_all(b"a\nx\nc\n")?;
+    let h1 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("file", Inode::ROOT)?
+        .write_all(b"a\nx\ny\n")?;
+    let h2 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let id = |h: &Hash| *txn.read().get_internal(&h.into()).unwrap().unwrap();
+    let (id0, id1, id2) = (id(&h0), id(&h1), id(&h2));
+    {
+        let txn = txn.read();
+        assert!(txn.dep_closure(&id0)?.is_empty());
+        assert_eq!(txn.dep_closure(&id2)?, [id0, id1].iter().cloned().collect());
+        assert_eq!(
+            txn.revdep_closure(&id0)?,
+            [id1, id2].iter().cloned().collect()
+        );
+        assert!(!txn.dep_cache().unwrap().lock().is_empty());
+        // Computed again from the memo.
+        assert_eq!(txn.dep_closure(&id2)?, [id0, id1].iter().cloned().collect());
+    }
+
+    crate::unrecord::unrecord(&mut *txn.write(), &channel, &changes, &h2, 0)?;
+    let txn = txn.read();
+    assert_eq!(txn.revdep_closure(&id0)?, [id1].iter().cloned().collect());
+    Ok(())
+}
+
+/// Truncating a channel rebuilds the same channel as unrecording its
+/// latest changes one by one.
+#[test]
+fn truncate_channel() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\nc\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("file", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("file", Inode::ROOT)?
+        .write_all(b"a\nx\nc\n")?;
+    let h1 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.add_file("file2", b"d\ne\n".to_vec());
+    txn.write().add_file("file2", 0)?;
+    let h2 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("file", Inode::ROOT)?
+        .write_all(b"a\nx\ny\n")?;
+    let h3 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let channel2 = txn.write().fork(&channel, "main2")?;
+    txn.write()
+        .unrecord_all(&changes, &channel2, &[h2, h3], 0)?;
+    let channel3 = txn.write().fork(&channel, "main3")?;
+    txn.write()
+        .unrecord_all(&changes, &channel3, &[h1, h2, h3], 0)?;
+
+    let t1 = {
+        let txn = txn.read();
+        let id1 = txn.get_internal(&h1.into())?.unwrap();
+        let t = txn.get_changeset(txn.changes(&channel.read()), id1)?;
+        u64::from(*t.unwrap())
+    };
+    let suffix = txn.write().truncate(&changes, &channel, t1, 0)?;
+    assert_eq!(suffix, vec![h3, h2, h1]);
+    {
+        let txn = txn.read();
+        assert_eq!(
+            txn.current_state(&*channel.read())?,
+            txn.current_state(&*channel3.read())?
+        );
+        assert!(txn.get_internal(&h0.into())?.is_some());
+        // Still used in `main2`.
+        assert!(txn.get_internal(&h1.into())?.is_some());
+        assert!(txn.get_internal(&h2.into())?.is_none());
+        // The name given by the forgotten change is forgotten too.
+        assert!(txn.get_path_inodes("file2")?.is_empty());
+        assert_eq!(txn.get_path_inodes("file")?.len(), 1);
+    }
+
+    let conflicts = output::output_repository_no_pending(
+        &repo, &changes, &txn, &channel, "", true, None, 1, 0,
+    )?;
+    if !conflicts.is_empty() {
+        panic!("conflicts = {:#?}", conflicts);
+    }
+    let mut buf = Vec::new();
+    repo.read_file("file", &mut buf)?;
+    assert_eq!(std::str::from_utf8(&buf), Ok("a\nb\nc\n"));
+    Ok(())
+}
+
+/// Unrecording the hunks of a single file from a change replaces that
+/// change, and the changes depending on it, with new ones.
+#[test]
+fn unrecord_path() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("a", b"a\nb\n".to_vec());
+    repo.add_file("b", b"c\nd\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("a", 0)?;
+    txn.write().add_file("b", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("a", Inode::ROOT)?.write_all(b"a\nx\nb\n")?;
+    let h1 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    // A change using the hunks of `b` blocks the unrecord.
+    let channel2 = txn.write().fork(&channel, "main2")?;
+    repo.write_file("b", Inode::ROOT)?.write_all(b"c\ny\nd\n")?;
+    let h2 = record_all(&repo, &changes, &txn, &channel2, "")?;
+    match txn
+        .write()
+        .unrecord_path(&changes, &channel2, &h0, "b", 0, |_, _| {})
+    {
+        Err(UnrecordError::PathDependedUpon { dependents, .. }) => {
+            assert_eq!(dependents, vec![h2])
+        }
+        _ => panic!("expected the unrecord to be blocked"),
+    }
+    assert!(txn
+        .write()
+        .unrecord_path(&changes, &channel, &h0, "c", 0, |_, _| {})
+        .is_err());
+
+    let mut signed = Vec::new();
+    let p = txn
+        .write()
+        .unrecord_path(&changes, &channel, &h0, "b", 0, |c, h| {
+            // The signature of the original change is gone.
+            assert!(c.unhashed.is_none());
+            signed.push(*h)
+        })?;
+    let h0_ = p.replacement.unwrap();
+    assert_eq!(p.rewritten.len(), 1);
+    assert_eq!(p.rewritten[0].0, h1);
+    let h1_ = p.rewritten[0].1;
+    assert_eq!(signed, vec![h0_, h1_]);
+    {
+        let txn = txn.read();
+        for h in [h0, h1] {
+            assert!(txn.has_change(&channel, &h)?.is_none());
+        }
+        for h in [h0_, h1_] {
+            assert!(txn.has_change(&channel, &h)?.is_some());
+        }
+        // Still in `main2`.
+        assert!(txn.has_change(&channel2, &h0)?.is_some());
+    }
+    let c0 = changes.get_change(&h0_)?;
+    assert!(c0.changes.iter().all(|h| h.path() == "a"));
+    assert!(changes.get_change(&h1_)?.dependencies.contains(&h0_));
+
+    let conflicts = output::output_repository_no_pending(
+        &repo, &changes, &txn, &channel, "", true, None, 1, 0,
+    )?;
+    if !conflicts.is_empty() {
+        panic!("conflicts = {:#?}", conflicts);
+    }
+    let mut buf = Vec::new();
+    repo.read_file("a", &mut buf)?;
+    assert_eq!(std::str::from_utf8(&buf), Ok("a\nx\nb\n"));
+    Ok(())
+}
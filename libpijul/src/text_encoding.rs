@@ -17,7 +17,7 @@ impl Encoding {
         self.0.name()
     }
 
-    pub(crate) fn decode<'a>(&self, text: &'a [u8]) -> Cow<'a, str> {
+    pub fn decode<'a>(&self, text: &'a [u8]) -> Cow<'a, str> {
         self.0.decode(&text).0
     }
 
@@ -73,7 +73,11 @@ impl<'de> Deserialize<'de> for Encoding {
             where
                 E: serde::de::Error,
             {
-                Ok(Encoding::for_label(v))
+                if let Some(e) = encoding_rs::Encoding::for_label_no_replacement(v.as_bytes()) {
+                    Ok(Encoding(e))
+                } else {
+                    Err(E::custom(format!("unknown encoding: {:?}", v)))
+                }
             }
         }
 
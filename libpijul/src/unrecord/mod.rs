@@ -6,7 +6,9 @@ use crate::pristine::*;
 
 use std::collections::{HashMap, HashSet};
 
+mod partial;
 mod working_copy;
+pub use partial::{unrecord_path, PartialUnrecord};
 
 #[derive(Error)]
 pub enum UnrecordError<ChangestoreError: std::error::Error + 'static, T: GraphTxnT + TreeTxnT> {
@@ -33,6 +35,21 @@ pub enum UnrecordError<ChangestoreError: std::error::Error + 'static, T: GraphTx
     LocalApply(#[from] crate::apply::LocalApplyError<T>),
     #[error(transparent)]
     Apply(#[from] crate::apply::ApplyError<ChangestoreError, T>),
+    #[error(transparent)]
+    Order(#[from] UnrecordOrderError<T::GraphError>),
+    #[error("Change {} doesn't touch {}", hash.to_base32(), path)]
+    PathNotInChange { hash: Hash, path: String },
+    #[error("Cannot unrecord {} from change {} because {} use it", path, hash.to_base32(), dependents_message(dependents))]
+    PathDependedUpon {
+        hash: Hash,
+        path: String,
+        dependents: Vec<Hash>,
+    },
+}
+
+fn dependents_message(dependents: &[Hash]) -> String {
+    let d: Vec<_> = dependents.iter().map(|d| d.to_base32()).collect();
+    d.join(", ")
 }
 
 impl<C: std::error::Error, T: GraphTxnT + TreeTxnT> std::fmt::Debug for UnrecordError<C, T> {
@@ -58,8 +75,372 @@ impl<C: std::error::Error, T: GraphTxnT + TreeTxnT> std::fmt::Debug for Unrecord
             UnrecordError::Missing(e) => std::fmt::Debug::fmt(e, fmt),
             UnrecordError::LocalApply(e) => std::fmt::Debug::fmt(e, fmt),
             UnrecordError::Apply(e) => std::fmt::Debug::fmt(e, fmt),
+            UnrecordError::Order(e) => std::fmt::Debug::fmt(e, fmt),
+            UnrecordError::PathNotInChange { hash, path } => {
+                write!(fmt, "Change {} doesn't touch {}", hash.to_base32(), path)
+            }
+            UnrecordError::PathDependedUpon {
+                hash,
+                path,
+                dependents,
+            } => write!(
+                fmt,
+                "Cannot unrecord {} from change {} because {} use it",
+                path,
+                hash.to_base32(),
+                dependents_message(dependents)
+            ),
+        }
+    }
+}
+
+/// How a change unrecorded from a remote channel relates to a local
+/// channel, used to decide what to do with it when pushing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RemoteUnrecord {
+    /// The change is unknown to this repository.
+    Unknown,
+    /// The change is known, but isn't in the channel.
+    NotInChannel,
+    /// The change is in the channel at position `n`, and no other
+    /// change of the channel depends on it.
+    InChannel { n: u64 },
+    /// The change is in the channel at position `n`, and
+    /// `dependents` (also in the channel) depend on it.
+    DependedUpon { n: u64, dependents: Vec<Hash> },
+}
+
+/// Classify a change that was unrecorded from a remote, relative to
+/// `channel`.
+pub fn classify_remote_unrecord<T: TxnT>(
+    txn: &T,
+    channel: &T::Channel,
+    hash: &Hash,
+) -> Result<RemoteUnrecord, TxnErr<T::GraphError>> {
+    let change_id = if let Some(&h) = txn.get_internal(&hash.into())? {
+        h
+    } else {
+        return Ok(RemoteUnrecord::Unknown);
+    };
+    let n: u64 = if let Some(&n) = txn.get_changeset(txn.changes(channel), &change_id)? {
+        n.into()
+    } else {
+        return Ok(RemoteUnrecord::NotInChannel);
+    };
+    let mut dependents = Vec::new();
+    for x in txn.iter_revdep(&change_id)? {
+        let (p, d) = x?;
+        if *p < change_id {
+            continue;
+        } else if *p > change_id {
+            break;
+        }
+        if txn.get_changeset(txn.changes(channel), d)?.is_some() {
+            dependents.push(txn.get_external(d)?.unwrap().into())
+        }
+    }
+    if dependents.is_empty() {
+        Ok(RemoteUnrecord::InChannel { n })
+    } else {
+        Ok(RemoteUnrecord::DependedUpon { n, dependents })
+    }
+}
+
+/// A change that can't be unrecorded along with a batch, because
+/// `dependents`, which are in the channel but not in the batch,
+/// depend on it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Blocker {
+    pub change: Hash,
+    pub dependents: Vec<Hash>,
+}
+
+#[derive(Debug, Error)]
+pub enum UnrecordOrderError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(#[from] TxnErr<T>),
+    #[error("Change not in channel: {}", hash.to_base32())]
+    ChangeNotInChannel { hash: Hash },
+    #[error("{}", blockers_message(blockers))]
+    Blocked { blockers: Vec<Blocker> },
+}
+
+fn blockers_message(blockers: &[Blocker]) -> String {
+    let mut s = String::new();
+    for b in blockers {
+        if !s.is_empty() {
+            s.push('\n')
+        }
+        let deps: Vec<_> = b.dependents.iter().map(|d| d.to_base32()).collect();
+        s.push_str(&format!(
+            "Cannot unrecord change {} because {} depend on it",
+            b.change.to_base32(),
+            deps.join(", ")
+        ));
+    }
+    s
+}
+
+/// An order in which the changes of `hashes` can be unrecorded from
+/// `channel` one by one, i.e. where each change comes before the
+/// changes it depends on. Since a change is always applied after its
+/// dependencies, this is the reverse order of application.
+///
+/// All the changes of `channel` depending on the batch are checked
+/// before anything is unrecorded, and if some of them are not in the
+/// batch, all of them are returned in
+/// [`UnrecordOrderError::Blocked`].
+pub fn unrecord_order<T: TxnT>(
+    txn: &T,
+    channel: &T::Channel,
+    hashes: &[Hash],
+) -> Result<Vec<Hash>, UnrecordOrderError<T::GraphError>> {
+    let mut batch = Vec::with_capacity(hashes.len());
+    for hash in hashes {
+        let change_id = txn.get_internal(&hash.into())?;
+        let n = if let Some(change_id) = change_id {
+            txn.get_changeset(txn.changes(channel), change_id)?
+        } else {
+            None
+        };
+        if let (Some(&change_id), Some(&n)) = (change_id, n) {
+            let n: u64 = n.into();
+            batch.push((n, *hash, change_id))
+        } else {
+            return Err(UnrecordOrderError::ChangeNotInChannel { hash: *hash });
+        }
+    }
+    batch.sort_by(|a, b| b.0.cmp(&a.0));
+    batch.dedup_by_key(|x| x.0);
+    let ids: HashSet<ChangeId> = batch.iter().map(|x| x.2).collect();
+    let mut blockers = Vec::new();
+    for (_, hash, change_id) in batch.iter() {
+        let mut dependents = Vec::new();
+        for x in txn.iter_revdep(change_id)? {
+            let (p, d) = x?;
+            if p < change_id {
+                continue;
+            } else if p > change_id {
+                break;
+            }
+            if !ids.contains(d) && txn.get_changeset(txn.changes(channel), d)?.is_some() {
+                dependents.push(txn.get_external(d)?.unwrap().into())
+            }
+        }
+        if !dependents.is_empty() {
+            blockers.push(Blocker {
+                change: *hash,
+                dependents,
+            })
+        }
+    }
+    if blockers.is_empty() {
+        Ok(batch.into_iter().map(|x| x.1).collect())
+    } else {
+        Err(UnrecordOrderError::Blocked { blockers })
+    }
+}
+
+/// A change that has to be unrecorded along with a batch, because it
+/// depends on the changes of that batch in `depends_on`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dependent {
+    pub change: Hash,
+    pub depends_on: Vec<Hash>,
+}
+
+/// The smallest batch containing `hashes` that can be unrecorded
+/// from `channel`, i.e. `hashes` along with all the changes of
+/// `channel` depending on them, transitively. The batch is returned
+/// in an order suitable for [`unrecord_all`] (latest first), and each
+/// change comes with the changes of the batch it depends on directly.
+pub fn unrecord_closure<T: TxnT>(
+    txn: &T,
+    channel: &T::Channel,
+    hashes: &[Hash],
+) -> Result<Vec<Dependent>, UnrecordOrderError<T::GraphError>> {
+    let mut batch: HashMap<ChangeId, (u64, Hash, Vec<Hash>)> = HashMap::default();
+    let mut requested = Vec::with_capacity(hashes.len());
+    for hash in hashes {
+        let change_id = txn.get_internal(&hash.into())?;
+        let n = if let Some(change_id) = change_id {
+            txn.get_changeset(txn.changes(channel), change_id)?
+        } else {
+            None
+        };
+        if let (Some(&change_id), Some(&n)) = (change_id, n) {
+            batch.insert(change_id, (n.into(), *hash, Vec::new()));
+            requested.push(change_id)
+        } else {
+            return Err(UnrecordOrderError::ChangeNotInChannel { hash: *hash });
+        }
+    }
+    // Since a change can only be applied after its dependencies,
+    // the changes of the channel depending on the batch are exactly
+    // the ones of the closures that are in the channel.
+    for change_id in requested {
+        for d in txn.revdep_closure(&change_id)? {
+            if batch.contains_key(&d) {
+                continue;
+            }
+            if let Some(&n) = txn.get_changeset(txn.changes(channel), &d)? {
+                let h: Hash = txn.get_external(&d)?.unwrap().into();
+                batch.insert(d, (n.into(), h, Vec::new()));
+            }
+        }
+    }
+    let ids: Vec<ChangeId> = batch.keys().cloned().collect();
+    for change_id in ids {
+        let mut depends_on = Vec::new();
+        for x in txn.iter_dep(&change_id)? {
+            let (p, d) = x?;
+            if *p < change_id {
+                continue;
+            } else if *p > change_id {
+                break;
+            }
+            if let Some(dep) = batch.get(d) {
+                depends_on.push(dep.1)
+            }
         }
+        batch.get_mut(&change_id).unwrap().2 = depends_on
     }
+    let mut batch: Vec<_> = batch.into_iter().map(|(_, x)| x).collect();
+    batch.sort_by(|a, b| b.0.cmp(&a.0));
+    Ok(batch
+        .into_iter()
+        .map(|(_, change, depends_on)| Dependent { change, depends_on })
+        .collect())
+}
+
+/// Unrecord all the changes of `hashes` from `channel`, in the order
+/// computed by [`unrecord_order`]. Returns that order.
+pub fn unrecord_all<T: MutTxnT, P: ChangeStore>(
+    txn: &mut T,
+    channel: &ChannelRef<T>,
+    changes: &P,
+    hashes: &[Hash],
+    salt: u64,
+) -> Result<Vec<Hash>, UnrecordError<P::Error, T>> {
+    let order = unrecord_order(txn, &*channel.read(), hashes)?;
+    for hash in order.iter() {
+        unrecord(txn, channel, changes, hash, salt)?;
+    }
+    Ok(order)
+}
+
+/// Unrecord all the changes applied to `channel` at timestamp
+/// `to_timestamp` or later, and return them, latest first.
+///
+/// When these changes are at least as many as the remaining ones,
+/// the graph is rebuilt from the remaining changes in one pass,
+/// instead of unrecording the changes one by one and repairing the
+/// graph after each of them, which is much faster for large
+/// rollbacks. Like [`crate::apply::apply_change_ws`], rebuilding
+/// doesn't update the correspondence between the pristine and the
+/// working copy, so the working copy must be output again afterwards.
+pub fn truncate_channel<T: MutTxnT, P: ChangeStore>(
+    txn: &mut T,
+    channel: &ChannelRef<T>,
+    changes: &P,
+    to_timestamp: u64,
+    salt: u64,
+) -> Result<Vec<Hash>, UnrecordError<P::Error, T>> {
+    let mut remaining = Vec::new();
+    let mut suffix = Vec::new();
+    for x in changeid_log(txn, &*channel.read(), 0u64.into())? {
+        let (t, p) = x?;
+        let h: Hash = txn.get_external(&p.a)?.unwrap().into();
+        if u64::from(*t) < to_timestamp {
+            remaining.push((p.a, h))
+        } else {
+            suffix.push(h)
+        }
+    }
+    suffix.reverse();
+    debug!(
+        "truncate_channel: {} remaining, {} to unrecord",
+        remaining.len(),
+        suffix.len()
+    );
+    if suffix.len() < remaining.len().max(1) {
+        for h in suffix.iter() {
+            unrecord(txn, channel, changes, h, salt)?;
+        }
+        return Ok(suffix);
+    }
+
+    let mut unused = Vec::with_capacity(suffix.len());
+    for h in suffix.iter() {
+        let change_id = *txn.get_internal(&h.into())?.unwrap();
+        // The files added by the suffix disappear from the graph, the
+        // other changes to the tree are restored when outputting.
+        let change = changes.get_change(h).map_err(UnrecordError::Changestore)?;
+        for change_ in change.changes.iter().rev().flat_map(|r| r.rev_iter()) {
+            match *change_ {
+                Atom::NewVertex(ref new_vertex)
+                    if new_vertex.flag.contains(EdgeFlags::FOLDER)
+                        && new_vertex.down_context.is_empty() =>
+                {
+                    working_copy::undo_file_addition(txn, change_id, new_vertex)?;
+                }
+                _ => {}
+            }
+        }
+        if unused_in_other_channels(txn, channel, change_id)? {
+            let names: Vec<_> = file_names(txn, &change_id, &change)?
+                .into_iter()
+                .map(|(path, inode)| (path.to_string(), inode))
+                .collect();
+            unused.push((change_id, names))
+        }
+    }
+    let mut channel = channel.write();
+    txn.truncate_channel(&mut channel, to_timestamp)?;
+    let mut ws = apply::Workspace::new();
+    for (change_id, hash) in remaining {
+        let change = changes
+            .get_change(&hash)
+            .map_err(UnrecordError::Changestore)?;
+        ws.clear();
+        apply::apply_change_to_graph(
+            txn,
+            T::graph_mut(&mut channel),
+            &mut |h| changes.knows(h, &hash).unwrap(),
+            change_id,
+            &hash,
+            &change,
+            &mut ws,
+        )?;
+    }
+
+    // Forget the changes used by no other channel, latest first so
+    // that their dependents are forgotten before them.
+    for (change_id, names) in unused {
+        let mut deps = Vec::new();
+        for x in txn.iter_dep(&change_id)? {
+            let (p, d) = x?;
+            if *p < change_id {
+                continue;
+            } else if *p > change_id {
+                break;
+            }
+            deps.push(*d)
+        }
+        assert!(txn.get_revdep(&change_id, None)?.is_none());
+        while txn.del_dep(&change_id, None)? {}
+        for dep in deps {
+            txn.del_revdep(&dep, Some(&change_id))?;
+        }
+        let hash = *txn.get_external(&change_id)?.unwrap();
+        txn.del_external(&change_id, None)?;
+        txn.del_internal(&hash, None)?;
+        txn.del_cached_header(&change_id)?;
+        for (path, inode) in names {
+            txn.del_path_inode(&path, &change_id, &inode)?;
+        }
+    }
+    Ok(suffix)
 }
 
 pub fn unrecord<T: MutTxnT, P: ChangeStore>(
@@ -90,6 +471,10 @@ pub fn unrecord<T: MutTxnT, P: ChangeStore>(
         while txn.del_dep(&change_id, None)? {}
         txn.del_external(&change_id, None)?;
         txn.del_internal(&hash.into(), None)?;
+        txn.del_cached_header(&change_id)?;
+        for (path, inode) in file_names(txn, &change_id, &change)? {
+            txn.del_path_inode(path, &change_id, &inode)?;
+        }
         for dep in change.dependencies.iter() {
             let dep = *txn.get_internal(&dep.into())?.unwrap();
             txn.del_revdep(&dep, Some(&change_id))?;
@@ -0,0 +1,361 @@
+//! Unrecording the hunks of a change that touch a single path.
+//!
+//! The change is replaced by a change with the same header and
+//! contents, but without these hunks. Since the contents don't move,
+//! the positions of the remaining hunks are the same in both changes,
+//! and the changes of the channel depending on the original change
+//! can be rewritten to point to the replacement, as long as they
+//! don't use the hunks being unrecorded.
+use crate::apply;
+use crate::change::*;
+use crate::changestore::*;
+use crate::pristine::*;
+use crate::record::InodeUpdate;
+use crate::{HashMap, HashSet};
+
+use super::UnrecordError;
+
+/// The outcome of [`unrecord_path`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialUnrecord {
+    /// The change replacing the unrecorded one, with its hunks that
+    /// don't touch the path, or `None` if all its hunks did.
+    pub replacement: Option<Hash>,
+    /// The changes of the channel that depended on the unrecorded
+    /// change, with their new hashes, in the order in which they were
+    /// applied again.
+    pub rewritten: Vec<(Hash, Hash)>,
+}
+
+/// Unrecord the hunks of change `hash` that touch `path` (or a path
+/// below it) from `channel`, keeping the other hunks in a replacement
+/// change, and rewriting the changes of `channel` that depend on it.
+///
+/// Fails without touching the channel if `hash` has no such hunks,
+/// or if some of its other hunks, or some of the changes depending
+/// on it, use the hunks being unrecorded.
+///
+/// The new changes are saved without the unhashed part of the
+/// original ones, since their signatures were made over the old
+/// hashes. `sign` is called on each of them, with its new hash, before
+/// it is saved.
+///
+/// Like [`unrecord`](super::unrecord), this doesn't touch the working
+/// copy: files added by the unrecorded hunks are left as if they had
+/// just been added, and need to be recorded again.
+pub fn unrecord_path<T: MutTxnT, P: ChangeStore, S: FnMut(&mut Change, &Hash)>(
+    txn: &mut T,
+    channel: &ChannelRef<T>,
+    changes: &P,
+    hash: &Hash,
+    path: &str,
+    salt: u64,
+    mut sign: S,
+) -> Result<PartialUnrecord, UnrecordError<P::Error, T>> {
+    let change = changes
+        .get_change(hash)
+        .map_err(UnrecordError::Changestore)?;
+    let (removed, kept): (Vec<usize>, Vec<usize>) =
+        (0..change.changes.len()).partition(|&i| touches(&change.changes[i], path));
+    if removed.is_empty() {
+        return Err(UnrecordError::PathNotInChange {
+            hash: *hash,
+            path: path.to_string(),
+        });
+    }
+    let removed = Removed::new(*hash, removed.iter().map(|&i| &change.changes[i]));
+
+    // Check all the changes before unrecording anything.
+    let closure = super::unrecord_closure(txn, &*channel.read(), &[*hash])?;
+    let mut blocked = Vec::new();
+    if kept
+        .iter()
+        .any(|&i| removed.is_used_by(&change.changes[i], hash))
+    {
+        blocked.push(*hash)
+    }
+    // The dependents, in the order in which they were applied.
+    let mut dependents = Vec::new();
+    for d in closure.iter().rev() {
+        if d.change == *hash {
+            continue;
+        }
+        let c = changes
+            .get_change(&d.change)
+            .map_err(UnrecordError::Changestore)?;
+        if c.changes.iter().any(|h| removed.is_used_by(h, &d.change)) {
+            blocked.push(d.change)
+        }
+        dependents.push((d.change, c))
+    }
+    if !blocked.is_empty() {
+        return Err(UnrecordError::PathDependedUpon {
+            hash: *hash,
+            path: path.to_string(),
+            dependents: blocked,
+        });
+    }
+
+    // The inodes of the files added by these changes, in order to
+    // keep them once the changes are applied again.
+    let mut inodes = HashMap::default();
+    for (h, c) in std::iter::once((hash, &change)).chain(dependents.iter().map(|(h, c)| (h, c))) {
+        let change_id = *txn.get_internal(&h.into())?.unwrap();
+        for v in new_inodes(c) {
+            let pos = Position {
+                change: change_id,
+                pos: v,
+            };
+            if let Some(&inode) = txn.get_revinodes(&pos, None)? {
+                inodes.insert((*h, v), inode);
+            }
+        }
+    }
+
+    for d in closure.iter() {
+        super::unrecord(txn, channel, changes, &d.change, salt)?;
+    }
+
+    let mut new_hashes = HashMap::default();
+    let replacement = if kept.is_empty() {
+        None
+    } else {
+        let mut c = replacement_change(&change, &kept, &removed);
+        let h = save(changes, &mut c, &mut sign)?;
+        apply_again(txn, channel, &c, &h, hash, &inodes)?;
+        Some(h)
+    };
+    new_hashes.insert(*hash, replacement);
+    let mut rewritten = Vec::with_capacity(dependents.len());
+    for (old, mut c) in dependents {
+        rewrite_hashes(&mut c, &new_hashes);
+        let h = save(changes, &mut c, &mut sign)?;
+        apply_again(txn, channel, &c, &h, &old, &inodes)?;
+        new_hashes.insert(old, Some(h));
+        rewritten.push((old, h))
+    }
+    Ok(PartialUnrecord {
+        replacement,
+        rewritten,
+    })
+}
+
+/// Save `change`, a new version of a change, without the unhashed
+/// part of the original one, and sign it with `sign`.
+fn save<T: MutTxnT, P: ChangeStore, S: FnMut(&mut Change, &Hash)>(
+    changes: &P,
+    change: &mut Change,
+    sign: &mut S,
+) -> Result<Hash, UnrecordError<P::Error, T>> {
+    change.unhashed = None;
+    changes
+        .save_change(change, |c, h| {
+            sign(c, h);
+            Ok(())
+        })
+        .map_err(UnrecordError::Changestore)
+}
+
+/// Whether `hunk` is about `path`, or about a path below it.
+fn touches(hunk: &Hunk<Option<Hash>, Local>, path: &str) -> bool {
+    let p = hunk.path();
+    path.is_empty()
+        || (p.starts_with(path) && (p.len() == path.len() || p.as_bytes()[path.len()] == b'/'))
+}
+
+/// The positions of the inode vertices created by `change`.
+fn new_inodes(change: &Change) -> impl Iterator<Item = ChangePosition> + '_ {
+    change
+        .changes
+        .iter()
+        .flat_map(|h| h.iter())
+        .filter_map(|atom| match atom {
+            Atom::NewVertex(v) if v.start == v.end => Some(v.start),
+            _ => None,
+        })
+}
+
+/// The vertices and edges introduced by the hunks being unrecorded.
+struct Removed {
+    hash: Hash,
+    vertices: Vec<(ChangePosition, ChangePosition)>,
+    edges: HashSet<(Position<Hash>, Position<Hash>)>,
+}
+
+impl Removed {
+    fn new<'a, I: Iterator<Item = &'a Hunk<Option<Hash>, Local>>>(hash: Hash, hunks: I) -> Self {
+        let mut removed = Removed {
+            hash,
+            vertices: Vec::new(),
+            edges: HashSet::default(),
+        };
+        for atom in hunks.flat_map(|h| h.iter()) {
+            match atom {
+                Atom::NewVertex(v) => removed.vertices.push((v.start, v.end)),
+                Atom::EdgeMap(e) => {
+                    for e in e.edges.iter() {
+                        removed
+                            .edges
+                            .insert((resolve(e.from, &hash), resolve(e.to.start_pos(), &hash)));
+                    }
+                }
+            }
+        }
+        removed
+    }
+
+    /// Whether `p` is in one of the removed vertices. Positions at
+    /// the end of a vertex (`end == true`) are the sources of edges,
+    /// and the other ones their targets.
+    fn contains(&self, p: Position<Hash>, end: bool) -> bool {
+        p.change == self.hash
+            && self.vertices.iter().any(|&(start, stop)| {
+                if start == stop {
+                    p.pos == start
+                } else if end {
+                    start < p.pos && p.pos <= stop
+                } else {
+                    start <= p.pos && p.pos < stop
+                }
+            })
+    }
+
+    /// Whether `hunk`, from change `owner`, uses the removed vertices
+    /// or edges.
+    fn is_used_by(&self, hunk: &Hunk<Option<Hash>, Local>, owner: &Hash) -> bool {
+        hunk.iter().any(|atom| match atom {
+            Atom::NewVertex(v) => {
+                v.up_context
+                    .iter()
+                    .any(|&p| self.contains(resolve(p, owner), true))
+                    || v.down_context
+                        .iter()
+                        .any(|&p| self.contains(resolve(p, owner), false))
+                    || self.contains(resolve(v.inode, owner), false)
+            }
+            Atom::EdgeMap(e) => {
+                self.contains(resolve(e.inode, owner), false)
+                    || e.edges.iter().any(|e| {
+                        let from = resolve(e.from, owner);
+                        let to = resolve(e.to.start_pos(), owner);
+                        self.contains(from, true)
+                            || self.contains(to, false)
+                            || (e.introduced_by.unwrap_or(*owner) == self.hash
+                                && self.edges.contains(&(from, to)))
+                    })
+            }
+        })
+    }
+}
+
+fn resolve(p: Position<Option<Hash>>, owner: &Hash) -> Position<Hash> {
+    Position {
+        change: p.change.unwrap_or(*owner),
+        pos: p.pos,
+    }
+}
+
+/// `change` without the removed hunks, and without their contents.
+fn replacement_change(change: &Change, kept: &[usize], removed: &Removed) -> Change {
+    let mut c = change.clone();
+    c.hashed.changes = kept.iter().map(|&i| change.changes[i].clone()).collect();
+    for &(start, end) in removed.vertices.iter() {
+        for b in &mut c.contents[start.us()..end.us()] {
+            *b = 0
+        }
+    }
+    c.hashed.contents_hash = {
+        let mut hasher = Hasher::default();
+        hasher.update(&c.contents);
+        hasher.finish()
+    };
+    if let Some(authors) = change.hunk_authors() {
+        let mut a = HunkAuthors::default();
+        for (j, &i) in kept.iter().enumerate() {
+            if let Some(authors) = authors.get(i) {
+                a.set(j, authors.to_vec())
+            }
+        }
+        c.hashed.set_hunk_authors(&a)
+    }
+    c
+}
+
+/// Replace the hashes of `change` according to `new_hashes`, where
+/// `None` means that the change is gone.
+fn rewrite_hashes(change: &mut Change, new_hashes: &HashMap<Hash, Option<Hash>>) {
+    let map = |h: Option<Hash>| match h.and_then(|h| new_hashes.get(&h)) {
+        Some(&Some(n)) => Some(n),
+        _ => h,
+    };
+    let hunks = std::mem::take(&mut change.hashed.changes);
+    change.hashed.changes = hunks
+        .into_iter()
+        .map(|h| {
+            h.atom_map(
+                |atom| Ok::<_, std::convert::Infallible>(map_atom(atom, &map)),
+                |l| l,
+            )
+            .unwrap()
+        })
+        .collect();
+    let deps = |hashes: &[Hash]| -> Vec<Hash> {
+        hashes
+            .iter()
+            .filter_map(|h| match new_hashes.get(h) {
+                Some(n) => *n,
+                None => Some(*h),
+            })
+            .collect()
+    };
+    change.hashed.dependencies = deps(&change.hashed.dependencies);
+    change.hashed.extra_known = deps(&change.hashed.extra_known);
+}
+
+fn map_atom<F: Fn(Option<Hash>) -> Option<Hash>>(
+    atom: Atom<Option<Hash>>,
+    f: &F,
+) -> Atom<Option<Hash>> {
+    let pos = |p: Position<Option<Hash>>| Position {
+        change: f(p.change),
+        pos: p.pos,
+    };
+    match atom {
+        Atom::NewVertex(mut v) => {
+            for p in v.up_context.iter_mut().chain(v.down_context.iter_mut()) {
+                *p = pos(*p)
+            }
+            v.inode = pos(v.inode);
+            Atom::NewVertex(v)
+        }
+        Atom::EdgeMap(mut e) => {
+            e.inode = pos(e.inode);
+            for e in e.edges.iter_mut() {
+                e.from = pos(e.from);
+                e.to.change = f(e.to.change);
+                e.introduced_by = f(e.introduced_by);
+            }
+            Atom::EdgeMap(e)
+        }
+    }
+}
+
+/// Apply `change`, the new version of `old`, to `channel`, giving
+/// the files it adds the inodes they had in `old`.
+fn apply_again<T: MutTxnT, C: std::error::Error + 'static>(
+    txn: &mut T,
+    channel: &ChannelRef<T>,
+    change: &Change,
+    hash: &Hash,
+    old: &Hash,
+    inodes: &HashMap<(Hash, ChangePosition), Inode>,
+) -> Result<(), UnrecordError<C, T>> {
+    let mut updates = HashMap::default();
+    for pos in new_inodes(change) {
+        if let Some(&inode) = inodes.get(&(*old, pos)) {
+            updates.insert(updates.len(), InodeUpdate::Add { pos, inode });
+        }
+    }
+    apply::apply_local_change(txn, channel, change, hash, &updates)?;
+    Ok(())
+}
@@ -55,7 +55,11 @@ pub(crate) struct ConflictsWriter<'a, 'b, W: std::io::Write> {
     pub new_line: bool,
     pub path: &'b str,
     pub conflicts: &'a mut Vec<crate::output::Conflict>,
+    /// If set, the hashes of the changes on the sides of each
+    /// conflict, by index of the conflict in `conflicts`.
+    pub sides: Option<&'a mut crate::HashMap<usize, Vec<Hash>>>,
     pub buf: Vec<u8>,
+    open: Vec<usize>,
 }
 
 impl<'a, 'b, W: std::io::Write> ConflictsWriter<'a, 'b, W> {
@@ -66,7 +70,30 @@ impl<'a, 'b, W: std::io::Write> ConflictsWriter<'a, 'b, W> {
             lines: 1,
             path,
             conflicts,
+            sides: None,
             buf: Vec::new(),
+            open: Vec::new(),
+        }
+    }
+
+    fn begin(&mut self, conflict: crate::output::Conflict, side: &[&Hash]) {
+        let n = self.conflicts.len();
+        self.conflicts.push(conflict);
+        self.open.push(n);
+        if let Some(ref mut sides) = self.sides {
+            let mut s: Vec<Hash> = side.iter().map(|h| **h).collect();
+            s.sort();
+            s.dedup();
+            sides.insert(n, s);
+        }
+    }
+
+    fn next_side(&mut self, side: &[&Hash]) {
+        if let (Some(n), Some(sides)) = (self.open.last(), &mut self.sides) {
+            let s = sides.entry(*n).or_default();
+            s.extend(side.iter().map(|h| **h));
+            s.sort();
+            s.dedup();
         }
     }
 }
@@ -129,10 +156,13 @@ impl<'a, 'b, W: std::io::Write> VertexBuffer for ConflictsWriter<'a, 'b, W> {
     }
 
     fn begin_conflict(&mut self, id: usize, side: &[&Hash]) -> Result<(), std::io::Error> {
-        self.conflicts.push(crate::output::Conflict::Order {
-            path: self.path.to_string(),
-            line: self.lines,
-        });
+        self.begin(
+            crate::output::Conflict::Order {
+                path: self.path.to_string(),
+                line: self.lines,
+            },
+            side,
+        );
         self.output_conflict_marker(START_MARKER, id, side)
     }
     fn begin_zombie_conflict(
@@ -140,19 +170,37 @@ impl<'a, 'b, W: std::io::Write> VertexBuffer for ConflictsWriter<'a, 'b, W> {
         id: usize,
         add_del: &[&Hash],
     ) -> Result<(), std::io::Error> {
-        self.conflicts.push(crate::output::Conflict::Zombie {
-            path: self.path.to_string(),
-            line: self.lines,
-        });
+        self.begin(
+            crate::output::Conflict::Zombie {
+                path: self.path.to_string(),
+                line: self.lines,
+            },
+            add_del,
+        );
         self.output_conflict_marker(START_MARKER, id, add_del)
     }
     fn begin_cyclic_conflict(&mut self, id: usize) -> Result<(), std::io::Error> {
-        self.conflicts.push(crate::output::Conflict::Cyclic {
-            path: self.path.to_string(),
-            line: self.lines,
-        });
+        self.begin(
+            crate::output::Conflict::Cyclic {
+                path: self.path.to_string(),
+                line: self.lines,
+            },
+            &[],
+        );
         self.output_conflict_marker(START_MARKER, id, &[])
     }
+    fn conflict_next(&mut self, id: usize, side: &[&Hash]) -> Result<(), std::io::Error> {
+        self.next_side(side);
+        self.output_conflict_marker(SEPARATOR, id, side)
+    }
+    fn end_conflict(&mut self, id: usize) -> Result<(), std::io::Error> {
+        self.open.pop();
+        self.output_conflict_marker(END_MARKER, id, &[])
+    }
+    fn end_cyclic_conflict(&mut self, id: usize) -> Result<(), std::io::Error> {
+        self.open.pop();
+        self.output_conflict_marker(END_MARKER, id, &[])
+    }
 }
 
 pub struct Writer<W: std::io::Write> {
@@ -254,3 +302,96 @@ impl<W: std::io::Write> VertexBuffer for Writer<W> {
         self.output_conflict_marker(START_MARKER, id, &[])
     }
 }
+
+/// A range of bytes of an output file, and the vertex it comes from.
+/// The change that introduced these bytes is `vertex.change`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Annotation {
+    pub start: usize,
+    pub end: usize,
+    pub vertex: Vertex<ChangeId>,
+}
+
+/// A [`VertexBuffer`] writing a file to memory like [`Writer`], and
+/// recording the provenance of the bytes it outputs, for example to
+/// show which change introduced each line. Conflict markers are not
+/// annotated.
+pub struct Annotator {
+    w: Writer<Vec<u8>>,
+    annotations: Vec<Annotation>,
+}
+
+impl Default for Annotator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Annotator {
+    pub fn new() -> Self {
+        Annotator {
+            w: Writer::new(Vec::new()),
+            annotations: Vec::new(),
+        }
+    }
+
+    /// The bytes output so far, and their annotations, sorted by
+    /// position.
+    pub fn into_parts(self) -> (Vec<u8>, Vec<Annotation>) {
+        (self.w.into_inner(), self.annotations)
+    }
+}
+
+impl VertexBuffer for Annotator {
+    fn output_line<E, C>(&mut self, v: Vertex<ChangeId>, c: C) -> Result<(), E>
+    where
+        E: From<std::io::Error>,
+        C: FnOnce(&mut [u8]) -> Result<(), E>,
+    {
+        let start = self.w.len();
+        self.w.output_line(v, c)?;
+        let end = self.w.len();
+        if end > start {
+            self.annotations.push(Annotation {
+                start,
+                end,
+                vertex: v,
+            })
+        }
+        Ok(())
+    }
+
+    fn output_conflict_marker(
+        &mut self,
+        s: &str,
+        id: usize,
+        sides: &[&Hash],
+    ) -> Result<(), std::io::Error> {
+        self.w.output_conflict_marker(s, id, sides)
+    }
+    fn begin_conflict(&mut self, id: usize, side: &[&Hash]) -> Result<(), std::io::Error> {
+        self.w.begin_conflict(id, side)
+    }
+    fn begin_zombie_conflict(
+        &mut self,
+        id: usize,
+        add_del: &[&Hash],
+    ) -> Result<(), std::io::Error> {
+        self.w.begin_zombie_conflict(id, add_del)
+    }
+    fn begin_cyclic_conflict(&mut self, id: usize) -> Result<(), std::io::Error> {
+        self.w.begin_cyclic_conflict(id)
+    }
+    fn conflict_next(&mut self, id: usize, side: &[&Hash]) -> Result<(), std::io::Error> {
+        self.w.conflict_next(id, side)
+    }
+    fn end_conflict(&mut self, id: usize) -> Result<(), std::io::Error> {
+        self.w.end_conflict(id)
+    }
+    fn end_zombie_conflict(&mut self, id: usize) -> Result<(), std::io::Error> {
+        self.w.end_zombie_conflict(id)
+    }
+    fn end_cyclic_conflict(&mut self, id: usize) -> Result<(), std::io::Error> {
+        self.w.end_cyclic_conflict(id)
+    }
+}
@@ -4,10 +4,137 @@ use canonical_path::{CanonicalPath, CanonicalPathBuf};
 use ignore::WalkBuilder;
 use std::borrow::Cow;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct FileSystem {
     root: PathBuf,
+    options: TraversalOptions,
+    write_options: WriteOptions,
+    /// Paths to skip when traversing the working copy, in addition
+    /// to the ignored ones.
+    path_filter: Option<PathFilter>,
+    /// Files written since the last call to `sync`, with
+    /// `FsyncPolicy::Batch`.
+    unsynced: Arc<parking_lot::Mutex<Vec<PathBuf>>>,
+    /// Directories in which files were created or renamed since the
+    /// last call to `sync`, with `FsyncPolicy::Batch`.
+    unsynced_dirs: Arc<parking_lot::Mutex<std::collections::BTreeSet<PathBuf>>>,
+}
+
+/// Options controlling how files are written when outputting to the
+/// working copy.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WriteOptions {
+    /// Write each file to a temporary file in the same directory,
+    /// and rename it once complete, so that an interrupted output
+    /// never leaves a half-written file.
+    pub atomic: bool,
+    /// When to flush the written files to the disk.
+    pub fsync: FsyncPolicy,
+}
+
+/// When to flush the files written by an output to the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncPolicy {
+    /// Leave it to the operating system.
+    Off,
+    /// Flush each file as soon as it is written.
+    File,
+    /// Flush all the files at the end of the output.
+    Batch,
+}
+
+impl Default for FsyncPolicy {
+    fn default() -> Self {
+        FsyncPolicy::Off
+    }
+}
+
+/// A file being written to the working copy.
+pub struct FileWriter {
+    w: std::io::BufWriter<std::fs::File>,
+    /// The temporary file being written, when writing atomically.
+    tmp: Option<PathBuf>,
+}
+
+impl std::io::Write for FileWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        self.w.write(buf)
+    }
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.w.flush()
+    }
+}
+
+impl Drop for FileWriter {
+    fn drop(&mut self) {
+        // The file was never finished, don't leave the temporary
+        // file behind.
+        if let Some(ref tmp) = self.tmp {
+            std::fs::remove_file(tmp).unwrap_or(())
+        }
+    }
+}
+
+/// Flush the entries of directory `dir` (such as the files renamed
+/// into it) to the disk. Directories can't be opened on Windows, where
+/// this isn't needed.
+#[cfg(not(windows))]
+fn sync_dir(dir: &Path) -> Result<(), std::io::Error> {
+    std::fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(windows)]
+fn sync_dir(_: &Path) -> Result<(), std::io::Error> {
+    Ok(())
+}
+
+/// The temporary file used to write `path` atomically, in the same
+/// directory (and hence on the same filesystem).
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    if let Some(n) = path.file_name() {
+        name.push(n)
+    }
+    name.push(".pijul-tmp");
+    path.with_file_name(name)
+}
+
+/// Options controlling how the working copy is traversed when adding
+/// and recording files.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TraversalOptions {
+    /// Don't descend into directories on other filesystems (mount
+    /// points).
+    pub same_file_system: bool,
+    /// Ignore files larger than this many bytes, both when adding
+    /// them and when diffing them.
+    pub max_file_size: Option<u64>,
+    /// What to do with symbolic links.
+    pub symlinks: SymlinkPolicy,
+}
+
+/// What to do with symbolic links when traversing the working copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    /// Record links as the contents of their target, but don't
+    /// descend into linked directories.
+    Contents,
+    /// Like `Contents`, but also descend into linked directories.
+    Follow,
+    /// Skip symbolic links.
+    Skip,
+}
+
+impl Default for SymlinkPolicy {
+    fn default() -> Self {
+        SymlinkPolicy::Contents
+    }
 }
 
 /// Returns whether `path` is a child of `root_` (or `root_` itself).
@@ -93,6 +220,8 @@ pub enum AddError<T: GraphTxnT + TreeTxnT> {
     Io(#[from] std::io::Error),
     #[error(transparent)]
     Fs(#[from] crate::fs::FsError<T>),
+    #[error("Invalid UTF-8 in path: {0}")]
+    InvalidUtf8(String),
 }
 
 impl<T: GraphTxnT + TreeTxnT> std::fmt::Debug for AddError<T> {
@@ -101,6 +230,7 @@ impl<T: GraphTxnT + TreeTxnT> std::fmt::Debug for AddError<T> {
             AddError::Ignore(e) => std::fmt::Debug::fmt(e, fmt),
             AddError::Io(e) => std::fmt::Debug::fmt(e, fmt),
             AddError::Fs(e) => std::fmt::Debug::fmt(e, fmt),
+            AddError::InvalidUtf8(e) => write!(fmt, "Invalid UTF-8 in path: {}", e),
         }
     }
 }
@@ -151,9 +281,54 @@ impl FileSystem {
     pub fn from_root<P: AsRef<Path>>(root: P) -> Self {
         FileSystem {
             root: root.as_ref().to_path_buf(),
+            options: TraversalOptions::default(),
+            write_options: WriteOptions::default(),
+            path_filter: None,
+            unsynced: Arc::new(parking_lot::Mutex::new(Vec::new())),
+            unsynced_dirs: Arc::new(parking_lot::Mutex::new(std::collections::BTreeSet::new())),
         }
     }
 
+    pub fn set_traversal_options(&mut self, options: TraversalOptions) {
+        self.options = options
+    }
+
+    pub fn traversal_options(&self) -> &TraversalOptions {
+        &self.options
+    }
+
+    pub fn set_write_options(&mut self, options: WriteOptions) {
+        self.write_options = options
+    }
+
+    /// Files were created in, renamed into or out of directory `dir`:
+    /// flush it now or at the next `sync`, depending on the fsync
+    /// policy.
+    fn dir_changed(&self, dir: &Path) -> Result<(), std::io::Error> {
+        match self.write_options.fsync {
+            FsyncPolicy::Off => Ok(()),
+            FsyncPolicy::File => sync_dir(dir),
+            FsyncPolicy::Batch => {
+                self.unsynced_dirs.lock().insert(dir.to_path_buf());
+                Ok(())
+            }
+        }
+    }
+
+    pub fn write_options(&self) -> &WriteOptions {
+        &self.write_options
+    }
+
+    /// Skip the paths rejected by `filter` (and their descendants)
+    /// when adding and recording files.
+    pub fn set_path_filter(&mut self, filter: Option<PathFilter>) {
+        self.path_filter = filter
+    }
+
+    pub fn path_filter(&self) -> Option<&PathFilter> {
+        self.path_filter.as_ref()
+    }
+
     pub fn record_prefixes<
         T: crate::MutTxnTExt + crate::TxnTExt + Send + Sync + 'static,
         C: crate::changestore::ChangeStore + Clone + Send + 'static,
@@ -215,11 +390,20 @@ impl FileSystem {
         for p in self.iterate_prefix_rec(repo_path.clone(), full.clone(), force, threads)? {
             let (path, is_dir) = p?;
             info!("Adding {:?}", path);
+            if path.to_str().is_none() {
+                return Err(AddError::InvalidUtf8(escape_path(&path)));
+            }
             use path_slash::PathExt;
             let path_str = path.to_slash_lossy();
             if path_str.is_empty() || path_str == "." {
                 continue;
             }
+            // With a path filter, directories are walked because they
+            // may contain selected files, and are only added as the
+            // parents of these files.
+            if is_dir && self.path_filter.is_some() {
+                continue;
+            }
             match txn.add(&path_str, is_dir, salt) {
                 Ok(_) => {}
                 Err(crate::fs::FsError::AlreadyInRepo(_)) => {}
@@ -255,15 +439,45 @@ impl FileSystem {
                 });
             }
         }
+        let path_filter = self.path_filter.clone();
+        if let Some(ref filter) = path_filter {
+            use path_slash::PathExt;
+            let path = full.as_path().strip_prefix(repo_path.as_path()).unwrap();
+            let path = path.to_slash_lossy();
+            if !path.is_empty() && !filter(&path, meta.is_dir()) {
+                return Ok(Untracked {
+                    join: None,
+                    receiver,
+                });
+            }
+        }
+        let options = self.options.clone();
         let t = std::thread::spawn(move || -> Result<(), std::io::Error> {
             if meta.is_dir() {
                 let mut walk = WalkBuilder::new(&full);
+                let skip_symlinks = options.symlinks == SymlinkPolicy::Skip;
+                let root = repo_path.clone();
                 walk.ignore(!force)
                     .git_ignore(!force)
                     .hidden(false)
-                    .filter_entry(|p| {
+                    .same_file_system(options.same_file_system)
+                    .follow_links(options.symlinks == SymlinkPolicy::Follow)
+                    .max_filesize(options.max_file_size)
+                    .filter_entry(move |p| {
                         debug!("p.file_name = {:?}", p.file_name());
-                        p.file_name() != crate::DOT_DIR
+                        if p.file_name() == crate::DOT_DIR || (skip_symlinks && p.path_is_symlink())
+                        {
+                            return false;
+                        }
+                        if let Some(ref filter) = path_filter {
+                            use path_slash::PathExt;
+                            if let Ok(path) = p.path().strip_prefix(&root) {
+                                let is_dir = p.file_type().map(|t| t.is_dir()).unwrap_or(false);
+                                let path = path.to_slash_lossy();
+                                return path.is_empty() || filter(&path, is_dir);
+                            }
+                        }
+                        true
                     })
                     .threads((threads - 1).max(1));
                 walk.build_parallel().run(|| {
@@ -335,6 +549,12 @@ impl FileSystem {
             }
         }
         debug!("recording from prefix {:?}", prefix);
+        if state.max_file_size.is_none() {
+            state.max_file_size = self.options.max_file_size
+        }
+        if state.path_filter.is_none() {
+            state.path_filter = self.path_filter.clone()
+        }
         state.record(
             txn.clone(),
             crate::Algorithm::default(),
@@ -366,6 +586,9 @@ impl WorkingCopyRead for FileSystem {
         debug!("permissions = {:?}", permissions);
         Ok(InodeMetadata::new(permissions & 0o100, attr.is_dir()))
     }
+    fn file_size(&self, file: &str) -> Result<Option<u64>, Self::Error> {
+        Ok(Some(std::fs::metadata(&self.path(file))?.len()))
+    }
     fn read_file(&self, file: &str, buffer: &mut Vec<u8>) -> Result<(), Self::Error> {
         use std::io::Read;
         debug!("read_file {:?}", file);
@@ -426,6 +649,9 @@ impl WorkingCopy for FileSystem {
             std::fs::create_dir_all(p)?
         }
         std::fs::rename(&former, &new)?;
+        for p in former.parent().into_iter().chain(new.parent()) {
+            self.dir_changed(p)?
+        }
         Ok(())
     }
     #[cfg(not(windows))]
@@ -454,7 +680,7 @@ impl WorkingCopy for FileSystem {
         Ok(())
     }
 
-    type Writer = std::io::BufWriter<std::fs::File>;
+    type Writer = FileWriter;
     fn write_file(&self, file: &str, _: Inode) -> Result<Self::Writer, Self::Error> {
         let path = self.path(file);
         debug!("path = {:?}", path);
@@ -462,10 +688,62 @@ impl WorkingCopy for FileSystem {
             std::fs::create_dir_all(p).unwrap_or(())
         }
         debug!("write_file: dir created");
+        if self.write_options.atomic {
+            let tmp = tmp_path(&path);
+            let w = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+            return Ok(FileWriter { w, tmp: Some(tmp) });
+        }
         std::fs::remove_file(&path).unwrap_or(());
-        let file = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        let w = std::io::BufWriter::new(std::fs::File::create(&path)?);
         debug!("file");
-        Ok(file)
+        Ok(FileWriter { w, tmp: None })
+    }
+
+    fn finish_file(&self, file: &str, mut w: Self::Writer) -> Result<(), Self::Error> {
+        use std::io::Write;
+        w.flush()?;
+        let path = self.path(file);
+        if self.write_options.fsync == FsyncPolicy::File {
+            w.w.get_ref().sync_all()?;
+        }
+        if let Some(tmp) = w.tmp.take() {
+            std::mem::drop(w);
+            if let Err(e) = std::fs::rename(&tmp, &path) {
+                std::fs::remove_file(&tmp).unwrap_or(());
+                return Err(e);
+            }
+        }
+        if let Some(p) = path.parent() {
+            self.dir_changed(p)?
+        }
+        if self.write_options.fsync == FsyncPolicy::Batch {
+            self.unsynced.lock().push(path)
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), Self::Error> {
+        let unsynced = std::mem::replace(&mut *self.unsynced.lock(), Vec::new());
+        debug!("syncing {:?} files", unsynced.len());
+        for path in unsynced {
+            match std::fs::File::open(&path) {
+                Ok(f) => f.sync_all()?,
+                // The file was deleted since it was written.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        // Then the directories, so that the new names of the files
+        // are durable too.
+        let dirs = std::mem::take(&mut *self.unsynced_dirs.lock());
+        for dir in dirs {
+            match sync_dir(&dir) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
     }
 }
 
@@ -478,3 +756,17 @@ fn permissions(attr: &std::fs::Metadata) -> Option<usize> {
 fn permissions(_: &std::fs::Metadata) -> Option<usize> {
     None
 }
+
+/// Display `path` on a single line, percent-escaping `%`, the
+/// control characters and the bytes that are not valid UTF-8 (see
+/// [`crate::path::escape_bytes`]).
+#[cfg(unix)]
+pub fn escape_path(path: &Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    crate::path::escape_bytes(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+pub fn escape_path(path: &Path) -> String {
+    crate::path::escape(&path.to_string_lossy()).into_owned()
+}
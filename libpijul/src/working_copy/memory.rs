@@ -5,6 +5,21 @@ use parking_lot::Mutex;
 use std::sync::Arc;
 use std::time::SystemTime;
 
+/// The current time. `SystemTime::now` panics on
+/// `wasm32-unknown-unknown`, where a counter is used instead, which is
+/// enough to tell which files were modified after a given time.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn now() -> SystemTime {
+    SystemTime::now()
+}
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+fn now() -> SystemTime {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static CLOCK: AtomicU64 = AtomicU64::new(1);
+    SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(CLOCK.fetch_add(1, Ordering::Relaxed))
+}
+
 #[derive(Debug, Clone)]
 pub struct Memory(Arc<Mutex<Memory_>>);
 
@@ -36,7 +51,7 @@ impl Default for Memory {
     fn default() -> Self {
         Memory(Arc::new(Mutex::new(Memory_ {
             files: FileTree::default(),
-            last_modified: SystemTime::now(),
+            last_modified: now(),
         })))
     }
 }
@@ -77,7 +92,7 @@ impl Memory {
 
     pub fn add_file(&self, file: &str, file_contents: Vec<u8>) {
         let file_meta = InodeMetadata::new(0, false);
-        let last = SystemTime::now();
+        let last = now();
         self.add_inode(
             file,
             Inode::File {
@@ -90,7 +105,7 @@ impl Memory {
 
     pub fn add_dir(&self, file: &str) {
         let file_meta = InodeMetadata::new(0o100, true);
-        let last = SystemTime::now();
+        let last = now();
         self.add_inode(
             file,
             Inode::Directory {
@@ -105,7 +120,7 @@ impl Memory {
 
     fn add_inode(&self, file: &str, inode: Inode) {
         let mut m = self.0.lock();
-        let last = SystemTime::now();
+        let last = now();
         m.last_modified = last;
         let mut file_tree = &mut m.files;
         let file = file.split('/').filter(|c| !c.is_empty());
@@ -160,7 +175,7 @@ impl Memory_ {
         debug!("repo = {:?}", self);
         let mut t = Some(&mut self.files);
         let mut it = file.split('/').filter(|c| !c.is_empty()).peekable();
-        self.last_modified = SystemTime::now();
+        self.last_modified = now();
         while let Some(c) = it.next() {
             debug!("c = {:?}", c);
             let inode_ = t.take().unwrap().children.get_mut(c);
@@ -181,7 +196,7 @@ impl Memory_ {
     fn remove_path_(&mut self, path: &str) -> Option<Inode> {
         debug!("remove_path {:?}", path);
         debug!("repo = {:?}", self);
-        self.last_modified = SystemTime::now();
+        self.last_modified = now();
         let mut t = Some(&mut self.files);
         let mut it = path.split('/').filter(|c| !c.is_empty());
         let mut c = it.next().unwrap();
@@ -225,6 +240,16 @@ impl WorkingCopyRead for Memory {
             }),
         }
     }
+    fn file_size(&self, file: &str) -> Result<Option<u64>, Self::Error> {
+        let m = self.0.lock();
+        match m.get_file(file) {
+            Some(Inode::Directory { .. }) => Ok(None),
+            Some(Inode::File { ref contents, .. }) => Ok(Some(contents.lock().len() as u64)),
+            None => Err(Error::NotFound {
+                path: file.to_string(),
+            }),
+        }
+    }
     fn read_file(&self, file: &str, buffer: &mut Vec<u8>) -> Result<(), Self::Error> {
         let m = self.0.lock();
         match m.get_file(file) {
@@ -255,7 +280,7 @@ impl WorkingCopy for Memory {
             m.get_file(file).is_none()
         };
         if not_already_exists {
-            let last = SystemTime::now();
+            let last = now();
             self.add_inode(
                 file,
                 Inode::Directory {
@@ -319,7 +344,7 @@ impl WorkingCopy for Memory {
         }
         std::mem::drop(m);
         let contents = Arc::new(Mutex::new(Vec::new()));
-        let last_modified = SystemTime::now();
+        let last_modified = now();
         self.add_inode(
             file,
             Inode::File {
@@ -3,17 +3,27 @@ use crate::chardetng::EncodingDetector;
 use crate::pristine::{Inode, InodeMetadata};
 use crate::text_encoding::Encoding;
 
-#[cfg(feature = "ondisk-repos")]
+#[cfg(any(feature = "ondisk-repos", feature = "no-mmap"))]
 pub mod filesystem;
-#[cfg(feature = "ondisk-repos")]
+#[cfg(any(feature = "ondisk-repos", feature = "no-mmap"))]
 pub use filesystem::FileSystem;
 
 pub mod memory;
 pub use memory::Memory;
 
+/// A predicate on the paths of a working copy, relative to the root
+/// of the repository, and on whether they are directories. The paths
+/// for which it is `false` are skipped, along with everything they
+/// contain.
+pub type PathFilter = std::sync::Arc<dyn Fn(&str, bool) -> bool + Send + Sync>;
+
 pub trait WorkingCopyRead {
     type Error: std::error::Error + Send;
     fn file_metadata(&self, file: &str) -> Result<InodeMetadata, Self::Error>;
+    /// The size of the file in bytes, if this working copy knows it.
+    fn file_size(&self, _file: &str) -> Result<Option<u64>, Self::Error> {
+        Ok(None)
+    }
     fn read_file(&self, file: &str, buffer: &mut Vec<u8>) -> Result<(), Self::Error>;
     fn modified_time(&self, file: &str) -> Result<std::time::SystemTime, Self::Error>;
     /// Read the file into the buffer
@@ -44,4 +54,15 @@ pub trait WorkingCopy: WorkingCopyRead {
 
     type Writer: std::io::Write;
     fn write_file(&self, file: &str, inode: Inode) -> Result<Self::Writer, Self::Error>;
+    /// Called once all the contents of `file` have been written to
+    /// `w`, the writer returned by `write_file`.
+    fn finish_file(&self, _file: &str, w: Self::Writer) -> Result<(), Self::Error> {
+        std::mem::drop(w);
+        Ok(())
+    }
+    /// Called at the end of an output, to make all the files written
+    /// durable.
+    fn sync(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
 }
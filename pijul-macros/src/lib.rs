@@ -671,3 +671,257 @@ pub fn sanakirja_put_del(input: proc_macro::TokenStream) -> TokenStream {
         }
     })
 }
+
+/// Arguments of the `backend_*` macros: the name of the table, the
+/// types of its keys and values, the associated error type (`error`
+/// by default) and the wrapper of errors (`TxnErr` by default).
+/// `macro_name` is the name of the calling macro, for error messages.
+fn backend_args(
+    input: proc_macro::TokenStream,
+    macro_name: &str,
+    error: proc_macro2::TokenStream,
+) -> (
+    String,
+    proc_macro2::TokenStream,
+    proc_macro2::TokenStream,
+    proc_macro2::TokenStream,
+    proc_macro2::TokenStream,
+) {
+    let input = proc_macro2::TokenStream::from(input);
+    let mut input_iter = input.into_iter();
+    let name = match input_iter.next() {
+        Some(TokenTree::Ident(id)) => id.to_string(),
+        _ => panic!("{}: first argument not an identifier", macro_name),
+    };
+    let key = proc_macro2::TokenStream::from_iter(next(&mut input_iter).into_iter());
+    let value = proc_macro2::TokenStream::from_iter(next(&mut input_iter).into_iter());
+    let error_ = next(&mut input_iter);
+    let error = if error_.is_empty() {
+        error
+    } else {
+        proc_macro2::TokenStream::from_iter(error_.into_iter())
+    };
+    let txnerr = next(&mut input_iter);
+    let txnerr = if txnerr.is_empty() {
+        quote! { TxnErr }
+    } else {
+        proc_macro2::TokenStream::from_iter(txnerr.into_iter())
+    };
+    assert!(
+        input_iter.next().is_none(),
+        "{}: too many arguments",
+        macro_name
+    );
+    (name, key, value, error, txnerr)
+}
+
+/// Implementation of `table_get!` for backends implementing `Table`
+/// (from `libpijul::pristine::backend`), which must be in scope, on
+/// their transactions.
+#[proc_macro]
+pub fn backend_table_get(input: proc_macro::TokenStream) -> TokenStream {
+    let (name, key, value, error, txnerr) =
+        backend_args(input, "backend_table_get", quote! { Error });
+    let name_capital = syn::Ident::new(&name_capital(&name), Span::call_site());
+    let name_get = syn::Ident::new(&format!("get_{}", name), Span::call_site());
+    let name = syn::Ident::new(&name, Span::call_site());
+    proc_macro::TokenStream::from(quote! {
+        fn #name_get <'txn> (&'txn self, key: &#key, value: Option<&#value>) -> Result<Option<&'txn #value>, #txnerr<Self::#error>> {
+            match <Self as Table<Self::#name_capital>>::get(self, &self.#name, key, value) {
+                Ok(Some((k, v))) if k == key => Ok(Some(v)),
+                Ok(_) => Ok(None),
+                Err(e) => Err(#txnerr(e.into())),
+            }
+        }
+    })
+}
+
+/// Implementation of `get!` for backends implementing `Table`.
+#[proc_macro]
+pub fn backend_get(input: proc_macro::TokenStream) -> TokenStream {
+    let (name, key, value, error, txnerr) = backend_args(input, "backend_get", quote! { Error });
+    let name_capital = syn::Ident::new(&name_capital(&name), Span::call_site());
+    let name_get = syn::Ident::new(&format!("get_{}", name), Span::call_site());
+    proc_macro::TokenStream::from(quote! {
+        fn #name_get<'txn>(&'txn self, db: &Self::#name_capital, key: &#key, value: Option<&#value>) -> Result<Option<&'txn #value>, #txnerr<Self::#error>> {
+            match <Self as Table<Self::#name_capital>>::get(self, db, key, value) {
+                Ok(Some((k, v))) if k == key => Ok(Some(v)),
+                Ok(_) => Ok(None),
+                Err(e) => Err(#txnerr(e.into())),
+            }
+        }
+    })
+}
+
+/// Implementation of `cursor!` for backends implementing `Table`.
+#[proc_macro]
+pub fn backend_cursor(input: proc_macro::TokenStream) -> TokenStream {
+    backend_cursor_(input, "backend_cursor", false, false, false)
+}
+
+/// Implementation of `cursor_ref!` for backends implementing `Table`.
+#[proc_macro]
+pub fn backend_cursor_ref(input: proc_macro::TokenStream) -> TokenStream {
+    backend_cursor_(input, "backend_cursor_ref", false, false, true)
+}
+
+/// Implementation of `iter!` for backends implementing `Table`.
+#[proc_macro]
+pub fn backend_iter(input: proc_macro::TokenStream) -> TokenStream {
+    backend_cursor_(input, "backend_iter", false, true, false)
+}
+
+/// Implementation of `rev_cursor!` for backends implementing `Table`.
+#[proc_macro]
+pub fn backend_rev_cursor(input: proc_macro::TokenStream) -> TokenStream {
+    backend_cursor_(input, "backend_rev_cursor", true, false, false)
+}
+
+fn backend_cursor_(
+    input: proc_macro::TokenStream,
+    macro_name: &str,
+    rev: bool,
+    iter: bool,
+    borrow: bool,
+) -> TokenStream {
+    let (name, key, value, error, txnerr) = backend_args(input, macro_name, quote! { GraphError });
+    let cursor_name = syn::Ident::new(
+        &format!("{}Cursor", name_capital(&name),),
+        Span::call_site(),
+    );
+    let name_capital = syn::Ident::new(&name_capital(&name), Span::call_site());
+    let name_next = syn::Ident::new(&format!("cursor_{}_next", name), Span::call_site());
+    let name_prev = syn::Ident::new(&format!("cursor_{}_prev", name), Span::call_site());
+    let name_cursor = syn::Ident::new(
+        &format!("{}cursor_{}", if rev { "rev_" } else { "" }, name),
+        Span::call_site(),
+    );
+    let name_cursor_ref = syn::Ident::new(
+        &format!("{}cursor_{}_ref", if rev { "rev_" } else { "" }, name),
+        Span::call_site(),
+    );
+    let name_iter = syn::Ident::new(&format!("iter_{}", name), Span::call_site());
+    let name = syn::Ident::new(&name, Span::call_site());
+    let table = quote! { <Self as Table<Self::#name_capital>> };
+
+    if rev {
+        return proc_macro::TokenStream::from(quote! {
+            fn #name_cursor<'txn>(
+                &'txn self,
+                db: &Self::#name_capital,
+                pos: Option<(&#key, Option<&#value>)>,
+            ) -> Result<RevCursor<Self, &'txn Self, Self::#cursor_name, #key, #value>, #txnerr<Self::#error>> {
+                let cursor = #table::rev_cursor(self, db, pos).map_err(|e| #txnerr(e.into()))?;
+                Ok(RevCursor {
+                    cursor,
+                    txn: self,
+                    k: std::marker::PhantomData,
+                    v: std::marker::PhantomData,
+                    t: std::marker::PhantomData,
+                })
+            }
+        });
+    }
+
+    let iter = if iter {
+        quote! {
+            fn #name_iter <'txn> (
+                &'txn self,
+                k: &#key,
+                v: Option<&#value>
+            ) -> Result<Cursor<Self, &'txn Self, Self::#cursor_name, #key, #value>, #txnerr<Self::#error>> {
+                self.#name_cursor(&self.#name, Some((k, v)))
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    let borrow = if borrow {
+        quote! {
+            fn #name_cursor_ref <RT: std::ops::Deref<Target = Self>> (
+                txn: RT,
+                db: &Self::#name_capital,
+                pos: Option<(&#key, Option<&#value>)>,
+            ) -> Result<Cursor<Self, RT, Self::#cursor_name, #key, #value>, #txnerr<Self::#error>> {
+                let cursor = #table::cursor(&*txn, db, pos).map_err(|e| #txnerr(e.into()))?;
+                Ok(Cursor {
+                    cursor,
+                    txn,
+                    k: std::marker::PhantomData,
+                    v: std::marker::PhantomData,
+                    t: std::marker::PhantomData,
+                })
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    proc_macro::TokenStream::from(quote! {
+        fn #name_cursor<'txn>(
+            &'txn self,
+            db: &Self::#name_capital,
+            pos: Option<(&#key, Option<&#value>)>,
+        ) -> Result<Cursor<Self, &'txn Self, Self::#cursor_name, #key, #value>, #txnerr<Self::#error>> {
+            let cursor = #table::cursor(self, db, pos).map_err(|e| #txnerr(e.into()))?;
+            Ok(Cursor {
+                cursor,
+                txn: self,
+                k: std::marker::PhantomData,
+                v: std::marker::PhantomData,
+                t: std::marker::PhantomData,
+            })
+        }
+        #borrow
+        fn #name_next <'txn> (
+            &'txn self,
+            cursor: &mut Self::#cursor_name,
+        ) -> Result<Option<(&'txn #key, &'txn #value)>, #txnerr<Self::#error>> {
+            #table::cursor_next(self, cursor).map_err(|e| #txnerr(e.into()))
+        }
+        fn #name_prev <'txn> (
+            &'txn self,
+            cursor: &mut Self::#cursor_name,
+        ) -> Result<Option<(&'txn #key, &'txn #value)>, #txnerr<Self::#error>> {
+            #table::cursor_prev(self, cursor).map_err(|e| #txnerr(e.into()))
+        }
+        #iter
+    })
+}
+
+/// Implementation of `put_del!` for backends implementing `TableMut`
+/// (from `libpijul::pristine::backend`) on their transactions. The
+/// table is a field of the transaction, and is cloned during the
+/// operation, since the transaction is borrowed mutably.
+#[proc_macro]
+pub fn backend_put_del(input: proc_macro::TokenStream) -> TokenStream {
+    let (name, key, value, error, txnerr) =
+        backend_args(input, "backend_put_del", quote! { Error });
+    let name_capital = syn::Ident::new(&name_capital(&name), Span::call_site());
+    let put = syn::Ident::new(&format!("put_{}", name), Span::call_site());
+    let del = syn::Ident::new(&format!("del_{}", name), Span::call_site());
+    let name = syn::Ident::new(&name, Span::call_site());
+    proc_macro::TokenStream::from(quote! {
+        fn #put(
+            &mut self,
+            k: &#key,
+            v: &#value,
+        ) -> Result<bool, #txnerr<Self::#error>> {
+            let mut db = self.#name.clone();
+            let result = <Self as TableMut<Self::#name_capital>>::put(self, &mut db, k, v);
+            self.#name = db;
+            result.map_err(|e| #txnerr(e.into()))
+        }
+        fn #del(
+            &mut self,
+            k: &#key,
+            v: Option<&#value>,
+        ) -> Result<bool, #txnerr<Self::#error>> {
+            let mut db = self.#name.clone();
+            let result = <Self as TableMut<Self::#name_capital>>::del(self, &mut db, k, v);
+            self.#name = db;
+            result.map_err(|e| #txnerr(e.into()))
+        }
+    })
+}
@@ -1,9 +1,15 @@
+use std::io::Write;
 use std::path::PathBuf;
 
 use anyhow::bail;
 use clap::Parser;
 use libpijul::changestore::ChangeStore;
-use libpijul::{DepsTxnT, GraphTxnT, MutTxnTExt, TxnT};
+use libpijul::pristine::sanakirja::MutTxn;
+use libpijul::{ApplyError, ArcTxn, ChannelRef, Conflict, LocalApplyError};
+use libpijul::{
+    Base32, ChannelMutTxnT, ChannelTxnT, DepsTxnT, GraphTxnT, Hash, MutTxnT, MutTxnTExt, TxnT,
+    TxnTExt,
+};
 use libpijul::{HashMap, HashSet};
 use log::*;
 
@@ -21,13 +27,29 @@ pub struct Apply {
     /// Only apply the dependencies of the change, not the change itself. Only applicable for a single change.
     #[clap(long = "deps-only")]
     deps_only: bool,
-    /// The change that need to be applied. If this value is missing, read the change in text format on the standard input.
+    /// Try to use at most approximately this much memory while applying each change, spilling to temporary files past that limit. Accepts suffixes K, M and G.
+    #[clap(long = "max-memory", value_name = "SIZE", parse(try_from_str = parse_size))]
+    max_memory: Option<usize>,
+    /// Exit with code 3 (after applying the changes) if there are conflicts in the working copy.
+    #[clap(long = "fail-on-conflict")]
+    fail_on_conflict: bool,
+    /// Print the conflicts in the working copy to the standard output as a JSON array of objects with fields `path`, `kind` and `line`.
+    #[clap(long = "conflicts-json")]
+    conflicts_json: bool,
+    /// Skip the changes that can't be applied (for example because they are corrupt, or because their dependencies are missing) along with the changes depending on them, apply the others, and report the skipped changes.
+    #[clap(long = "continue-on-error", conflicts_with = "deps-only")]
+    continue_on_error: bool,
+    /// After applying, output only the paths touched by the changes to the working copy (or to the directory set by `apply.working_copy` in the configuration, even in a bare repository or for another channel than the current one). This is the default if `apply.update_working_copy` is set in the configuration.
+    #[clap(long = "update-working-copy")]
+    update_working_copy: bool,
+    /// The change that need to be applied, or a directory written by `pijul unrecord --keep-changes`. If this value is missing, read the change in text format on the standard input.
     change: Vec<String>,
 }
 
 impl Apply {
     pub fn run(self) -> Result<(), anyhow::Error> {
-        let repo = Repository::find_root(self.repo_path)?;
+        let mut repo = Repository::find_root(self.repo_path)?;
+        let _lock = repo.lock_working_copy()?;
         let txn = repo.pristine.arc_txn_begin()?;
         let cur = txn
             .read()
@@ -40,13 +62,44 @@ impl Apply {
             cur.as_str()
         };
         let is_current_channel = channel_name == cur;
+        let update_working_copy = self.update_working_copy || repo.config.apply.update_working_copy;
+        let attached = if update_working_copy {
+            repo.config.apply.working_copy.as_ref().map(|path| {
+                let mut w =
+                    libpijul::working_copy::filesystem::FileSystem::from_root(repo.path.join(path));
+                w.set_traversal_options(repo.config.working_copy.clone());
+                w.set_write_options(repo.config.output.clone());
+                w
+            })
+        } else {
+            None
+        };
+        if update_working_copy && attached.is_none() {
+            if repo.config.bare {
+                bail!("Bare repositories can only update the working copy set by `apply.working_copy` in the configuration")
+            } else if !is_current_channel {
+                bail!(
+                    "Channel {:?} is not the current channel, its working copy can't be updated",
+                    channel_name
+                )
+            }
+        }
         let channel = if let Some(channel) = txn.read().load_channel(&channel_name)? {
             channel
         } else {
             bail!("Channel {:?} not found", channel_name)
         };
         let mut hashes = Vec::new();
+        let mut tags = Vec::new();
+        let mut failures = Vec::new();
         for ch in self.change.iter() {
+            let bundle = std::path::Path::new(ch);
+            if bundle.join(super::unrecord::BUNDLE_MANIFEST).exists() {
+                let (h, t) = super::unrecord::import_bundle(&repo, bundle)?;
+                hashes.extend(h);
+                tags.extend(t);
+                continue;
+            }
             hashes.push(if let Ok(h) = txn.read().hash_from_prefix(ch) {
                 h.0
             } else {
@@ -75,6 +128,14 @@ impl Apply {
                     .save_change(&mut change, |_, _| Ok::<_, anyhow::Error>(()))?,
             )
         }
+        // The unrecorded changes of the working copy are applied as a
+        // pending change, so that the files output below merge them
+        // with the applied changes, even if these changes move them.
+        let pending_hash = if attached.is_none() && is_current_channel {
+            super::pending(txn.clone(), &channel, &mut repo)?
+        } else {
+            None
+        };
         if self.deps_only {
             if hashes.len() > 1 {
                 bail!("--deps-only is only applicable to a single change")
@@ -83,11 +144,41 @@ impl Apply {
             txn.write()
                 .apply_deps_rec(&repo.changes, &mut channel, hashes.last().unwrap())?;
         } else {
-            let mut channel = channel.write();
+            crate::trust::check_changes(&repo.changes, &hashes)?;
             let mut txn = txn.write();
+            let mut ws = if let Some(max_memory) = self.max_memory {
+                libpijul::ApplyWorkspace::with_max_memory(max_memory)
+            } else {
+                libpijul::ApplyWorkspace::new()
+            };
             for hash in hashes.iter() {
-                txn.apply_change_rec(&repo.changes, &mut channel, hash)?
+                if self.continue_on_error {
+                    apply_or_skip(
+                        &repo.changes,
+                        &mut *txn,
+                        &channel,
+                        hash,
+                        &mut ws,
+                        &mut failures,
+                    )?
+                } else {
+                    txn.apply_change_rec_ws(&repo.changes, &mut *channel.write(), hash, &mut ws)?
+                }
             }
+            let mut channel = channel.write();
+            for tag in tags.iter() {
+                if let Some(n) = txn.channel_has_state(txn.states(&*channel), &tag.into())? {
+                    let tags = txn.tags_mut(&mut *channel);
+                    txn.put_tags(tags, n.into(), tag)?;
+                } else {
+                    warn!(
+                        "Not restoring tag {}: channel does not have that state",
+                        tag.to_base32()
+                    )
+                }
+            }
+            let skipped: HashSet<_> = failures.iter().map(|f| f.hash).collect();
+            hashes.retain(|h| !skipped.contains(h));
         }
 
         let mut touched = HashSet::default();
@@ -109,12 +200,16 @@ impl Apply {
         }
         std::mem::drop(txn_);
 
-        if is_current_channel {
+        let mut conflicts = Vec::new();
+        if let Some(ref attached) = attached {
+            conflicts = update_attached(&repo, attached, &txn, &channel)?;
+        } else if is_current_channel && !repo.config.bare {
             let mut touched_files = Vec::with_capacity(touched.len());
             let txn_ = txn.read();
+            let mut paths = libpijul::fs::PathResolver::new(false);
             for i in touched {
                 if let Some((path, _)) =
-                    libpijul::fs::find_path(&repo.changes, &*txn_, &*channel.read(), false, i)?
+                    paths.find_path(&repo.changes, &*txn_, &*channel.read(), i)?
                 {
                     touched_files.push(path)
                 } else {
@@ -130,7 +225,6 @@ impl Apply {
                     i: 0,
                     pre: "Outputting repository".into(),
                 });
-            let mut conflicts = Vec::new();
             for path in touched_files.iter() {
                 conflicts.extend(
                     libpijul::output::output_repository_no_pending(
@@ -141,7 +235,7 @@ impl Apply {
                         &path,
                         true,
                         None,
-                        num_cpus::get(),
+                        crate::jobs::output(),
                         0,
                     )?
                     .into_iter(),
@@ -157,16 +251,284 @@ impl Apply {
                         "",
                         true,
                         None,
-                        num_cpus::get(),
+                        crate::jobs::output(),
                         0,
                     )?
                     .into_iter(),
                 );
             }
             PROGRESS.join();
-            super::print_conflicts(&conflicts)?;
+        }
+        if let Some(h) = pending_hash {
+            txn.write().unrecord(&repo.changes, &channel, &h, 0)?;
+            if cfg!(feature = "keep-changes") {
+                repo.changes.del_change(&h)?;
+            }
         }
         txn.commit()?;
+        let mut retry = "apply".to_string();
+        if let Some(ref c) = self.channel {
+            retry.push_str(&format!(" --channel {}", c))
+        }
+        for f in failures.iter() {
+            retry.push(' ');
+            retry.push_str(&f.hash.to_base32())
+        }
+        print_failures(&failures, &[retry])?;
+        super::report_conflicts(&conflicts, self.conflicts_json, self.fail_on_conflict)?;
+        check_failures(&failures)
+    }
+}
+
+/// The directory of `.pijul` holding the pristine of the working
+/// copy set by `apply.working_copy`.
+const ATTACHED_DIR: &str = "attached";
+
+/// Update the working copy set by `apply.working_copy` to `channel`.
+/// It has its own pristine, so that the tree of the repository's own
+/// working copy is left alone, and so that files moved or deleted by
+/// the changes are moved or deleted there too. The pristine's only
+/// channel is brought to the state of `channel` by unrecording the
+/// changes `channel` doesn't have and applying the others, and only
+/// the paths touched by these changes are output.
+fn update_attached(
+    repo: &Repository,
+    working_copy: &libpijul::working_copy::filesystem::FileSystem,
+    txn: &ArcTxn<MutTxn<()>>,
+    channel: &ChannelRef<MutTxn<()>>,
+) -> Result<Vec<Conflict>, anyhow::Error> {
+    let dir = repo.changes_dir.parent().unwrap().join(ATTACHED_DIR);
+    std::fs::create_dir_all(&dir)?;
+    if let Some(ref path) = repo.config.apply.working_copy {
+        std::fs::create_dir_all(repo.path.join(path))?;
+    }
+    let pristine = libpijul::pristine::sanakirja::Pristine::new(&dir.join("db"))?;
+    let att_txn = pristine.arc_txn_begin()?;
+    let mut att_channel = att_txn
+        .write()
+        .open_or_create_channel(crate::DEFAULT_CHANNEL)?;
+
+    let mut unrecorded = Vec::new();
+    for x in att_txn.read().reverse_log(&*att_channel.read(), None)? {
+        let (_, (h, _)) = x?;
+        let h: Hash = h.into();
+        if txn.read().get_revchanges(channel, &h)?.is_none() {
+            unrecorded.push(h)
+        }
+    }
+    for h in unrecorded.iter() {
+        debug!("unrecording {:?} from the attached working copy", h);
+        att_txn
+            .write()
+            .unrecord(&repo.changes, &mut att_channel, h, 0)?;
+    }
+    let mut applied = Vec::new();
+    for x in txn.read().log(&*channel.read(), 0)? {
+        let (_, (h, _)) = x?;
+        let h: Hash = h.into();
+        if att_txn.read().get_revchanges(&att_channel, &h)?.is_none() {
+            applied.push(h)
+        }
+    }
+    if unrecorded.is_empty() && applied.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut touched = std::collections::HashSet::new();
+    {
+        let mut ws = libpijul::ApplyWorkspace::new();
+        let mut t = att_txn.write();
+        let mut c = att_channel.write();
+        for h in applied.iter() {
+            t.apply_change_rec_ws(&repo.changes, &mut c, h, &mut ws)?;
+        }
+        // The paths touched by unrecorded changes aren't known,
+        // in which case the whole working copy is output.
+        if unrecorded.is_empty() {
+            for h in applied.iter() {
+                let int = t.get_internal(&h.into())?.unwrap();
+                for x in t.iter_rev_touched(int)? {
+                    let (int_, inode) = x?;
+                    if int_ < int {
+                        continue;
+                    } else if int_ > int {
+                        break;
+                    }
+                    touched.insert(*inode);
+                }
+            }
+        }
+    }
+    let conflicts = super::pushpull::output_touched(
+        working_copy,
+        &repo.changes,
+        &att_txn,
+        &att_channel,
+        &touched,
+    )?;
+    att_txn.commit()?;
+    Ok(conflicts)
+}
+
+/// Parse a size in bytes, with an optional K, M or G suffix (powers of 1024).
+pub(crate) fn parse_size(s: &str) -> Result<usize, anyhow::Error> {
+    let s = s.trim();
+    let (n, unit) = match s.char_indices().last() {
+        Some((i, 'k')) | Some((i, 'K')) => (&s[..i], 1 << 10),
+        Some((i, 'm')) | Some((i, 'M')) => (&s[..i], 1 << 20),
+        Some((i, 'g')) | Some((i, 'G')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    let n: usize = n.trim().parse()?;
+    if let Some(n) = n.checked_mul(unit) {
+        Ok(n)
+    } else {
+        bail!("Size too large: {:?}", s)
+    }
+}
+
+/// A change skipped with `--continue-on-error`.
+#[derive(Debug)]
+pub(super) struct Failure {
+    pub hash: Hash,
+    pub reason: FailureReason,
+}
+
+#[derive(Debug)]
+pub(super) enum FailureReason {
+    /// The change (or one of its dependencies) couldn't be loaded
+    /// from the change store, or couldn't be applied to the graph.
+    Error(String),
+    /// The change depends on this skipped change.
+    Dependency(Hash),
+}
+
+/// Apply `hash` and its dependencies, unless it depends on a change
+/// of `failures`. The changes that can't be applied because they (or
+/// their dependencies) can't be loaded or don't apply to the graph
+/// are added to `failures`, and the rest can still be applied.
+///
+/// Applying to the graph can fail after writing part of the change,
+/// so the change is first applied to a fork of `channel`, dropped
+/// afterwards, and only then to `channel` if that worked. This
+/// applies every change twice. Errors of the transaction abort.
+pub(super) fn apply_or_skip(
+    changes: &crate::repository::Changes,
+    txn: &mut MutTxn<()>,
+    channel: &ChannelRef<MutTxn<()>>,
+    hash: &Hash,
+    ws: &mut libpijul::ApplyWorkspace,
+    failures: &mut Vec<Failure>,
+) -> Result<(), anyhow::Error> {
+    if let Some(dep) = skipped_dependency(changes, txn, &*channel.read(), hash, failures)? {
+        debug!("skipping {:?}, depends on {:?}", hash, dep);
+        failures.push(Failure {
+            hash: *hash,
+            reason: FailureReason::Dependency(dep),
+        });
+        return Ok(());
+    }
+    let trial_name = format!("{}-apply-{}", txn.name(&*channel.read()), hash.to_base32());
+    let trial = txn.fork(channel, &trial_name)?;
+    let result = txn.apply_change_rec_ws(changes, &mut *trial.write(), hash, ws);
+    // `drop_channel` needs the only reference to the fork.
+    std::mem::drop(trial);
+    txn.drop_channel(&trial_name)?;
+    match result {
+        Ok(()) => {
+            txn.apply_change_rec_ws(changes, &mut *channel.write(), hash, ws)?;
+            Ok(())
+        }
+        Err(ApplyError::LocalChange(LocalApplyError::Txn(e))) => Err(e.into()),
+        Err(ApplyError::LocalChange(LocalApplyError::Tree(e))) => Err(e.into()),
+        Err(e) => {
+            debug!("skipping {:?}: {}", hash, e);
+            failures.push(Failure {
+                hash: *hash,
+                reason: FailureReason::Error(e.to_string()),
+            });
+            Ok(())
+        }
+    }
+}
+
+/// A change of `failures` among the dependencies of `hash` that are
+/// not on `channel` yet, if any.
+fn skipped_dependency(
+    changes: &crate::repository::Changes,
+    txn: &MutTxn<()>,
+    channel: &libpijul::pristine::sanakirja::Channel,
+    hash: &Hash,
+    failures: &[Failure],
+) -> Result<Option<Hash>, anyhow::Error> {
+    if failures.is_empty() {
+        return Ok(None);
+    }
+    let skipped: HashSet<Hash> = failures.iter().map(|f| f.hash).collect();
+    let mut stack = vec![*hash];
+    let mut visited = HashSet::default();
+    while let Some(h) = stack.pop() {
+        if !visited.insert(h) {
+            continue;
+        }
+        // Changes that can't be loaded are reported when applying.
+        let deps = if let Ok(deps) = changes.get_dependencies(&h) {
+            deps
+        } else {
+            continue;
+        };
+        for dep in deps {
+            if let Hash::None = dep {
+                continue;
+            }
+            if skipped.contains(&dep) {
+                return Ok(Some(dep));
+            }
+            if let Some(int) = txn.get_internal(&dep.into())? {
+                if txn.get_changeset(txn.changes(channel), int)?.is_some() {
+                    continue;
+                }
+            }
+            stack.push(dep)
+        }
+    }
+    Ok(None)
+}
+
+/// Print the changes skipped with `--continue-on-error`, along with
+/// the commands (without the leading `pijul`) applying them once the
+/// problems are fixed.
+pub(super) fn print_failures(failures: &[Failure], retry: &[String]) -> Result<(), std::io::Error> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+    let mut stderr = std::io::stderr();
+    writeln!(stderr, "These changes could not be applied:")?;
+    for f in failures.iter() {
+        match f.reason {
+            FailureReason::Error(ref e) => writeln!(stderr, "  {}: {}", f.hash.to_base32(), e)?,
+            FailureReason::Dependency(ref dep) => writeln!(
+                stderr,
+                "  {}: depends on {}",
+                f.hash.to_base32(),
+                dep.to_base32()
+            )?,
+        }
+    }
+    writeln!(
+        stderr,
+        "The other changes were applied. Once the problems above are fixed, apply the skipped changes with:"
+    )?;
+    for r in retry.iter() {
+        writeln!(stderr, "  pijul {}", r)?;
+    }
+    Ok(())
+}
+
+/// Fail if changes were skipped with `--continue-on-error`.
+pub(super) fn check_failures(failures: &[Failure]) -> Result<(), anyhow::Error> {
+    if failures.is_empty() {
         Ok(())
+    } else {
+        bail!("{} changes could not be applied", failures.len())
     }
 }
@@ -5,6 +5,7 @@ use clap::Parser;
 use libpijul::{Hash, Merkle, TxnT};
 use log::debug;
 
+use crate::filters::FilterOpts;
 use crate::repository::Repository;
 
 #[derive(Parser, Debug)]
@@ -33,6 +34,8 @@ pub struct Archive {
     /// Append this path in front of each path inside the archive
     #[clap(long = "umask")]
     umask: Option<String>,
+    #[clap(flatten)]
+    filters: FilterOpts,
     /// Name of the output file
     #[clap(short = 'o')]
     name: String,
@@ -72,6 +75,8 @@ impl Archive {
             }
         }
 
+        let filter = self.filters.build()?;
+
         if let Some(ref rem) = self.remote {
             debug!("unknown");
             let mut remote = crate::remote::unknown_remote(
@@ -84,6 +89,7 @@ impl Archive {
                 },
                 self.no_cert_check,
                 true,
+                None,
             )
             .await?;
             if let crate::remote::RemoteRepo::LocalChannel(_) = remote {
@@ -92,6 +98,9 @@ impl Archive {
                     path.push(rem);
                 }
             } else {
+                if filter.is_some() {
+                    bail!("--include and --exclude only work with local repositories")
+                }
                 let mut p = std::path::Path::new(&self.name).to_path_buf();
                 if !self.name.ends_with(".tar.gz") {
                     p.set_extension("tar.gz");
@@ -110,6 +119,9 @@ impl Archive {
             }
             let mut f = std::fs::File::create(&p)?;
             let mut tarball = libpijul::output::Tarball::new(&mut f, self.prefix, umask);
+            if let Some(filter) = filter {
+                tarball = tarball.with_filter(move |path, is_dir| filter.matches(path, is_dir))
+            }
             let conflicts = if let Some(state) = state {
                 let txn = repo.pristine.arc_txn_begin()?;
                 let channel = {
@@ -0,0 +1,363 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use clap::Parser;
+use libpijul::changestore::ChangeStore;
+use libpijul::pristine::sanakirja::MutTxn;
+use libpijul::{
+    ArcTxn, Base32, ChannelMutTxnT, ChannelRef, ChannelTxnT, GraphTxnT, Hash, MutTxnT, MutTxnTExt,
+    TxnT, TxnTExt, DOT_DIR,
+};
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::repository::Repository;
+
+/// The file where the state of the bisection is kept, in `.pijul`.
+const BISECT_FILE: &str = "bisect.json";
+/// The scratch channel forked from the bisected channel, which is the
+/// current channel during the bisection.
+const BISECT_CHANNEL: &str = "bisect";
+
+#[derive(Parser, Debug)]
+pub struct Bisect {
+    /// Set the repository where this command should run. Defaults to the first ancestor of the current directory that contains a `.pijul` directory.
+    #[clap(long = "repository")]
+    repo_path: Option<PathBuf>,
+    #[clap(subcommand)]
+    subcmd: SubCommand,
+}
+
+#[derive(Parser, Debug)]
+pub enum SubCommand {
+    /// Start finding the first change of a channel that introduced a
+    /// bug. This forks the channel into a scratch channel called
+    /// `bisect`, and outputs the states to test there. There must not
+    /// be unrecorded changes in the working copy.
+    #[clap(name = "start")]
+    Start {
+        /// Bisect this channel instead of the current channel
+        #[clap(long = "channel")]
+        channel: Option<String>,
+        /// A change after which the channel was good (by default, the
+        /// empty channel is assumed to be good)
+        #[clap(long = "good")]
+        good: Option<String>,
+        /// A change after which the channel was bad (by default, the
+        /// current state of the channel is assumed to be bad)
+        #[clap(long = "bad")]
+        bad: Option<String>,
+    },
+    /// Mark the state in the working copy as good (or the state after
+    /// the given change).
+    #[clap(name = "good")]
+    Good { change: Option<String> },
+    /// Mark the state in the working copy as bad (or the state after
+    /// the given change).
+    #[clap(name = "bad")]
+    Bad { change: Option<String> },
+    /// Run a command in the root of the working copy on each state to
+    /// test, until the first bad change is found. The state is good if
+    /// the command exits with code 0, and bad otherwise.
+    #[clap(name = "run")]
+    Run {
+        command: String,
+        #[clap(last = true)]
+        args: Vec<String>,
+    },
+    /// Stop bisecting: delete the scratch channel, and go back to the
+    /// bisected channel.
+    #[clap(name = "reset")]
+    Reset,
+}
+
+/// The state of a bisection. States of the channel are represented by
+/// the number of changes of the log applied in that state, so that
+/// `good` and `bad` are the longest known good prefix and the
+/// shortest known bad prefix of the log.
+#[derive(Debug, Serialize, Deserialize)]
+struct BisectState {
+    /// The bisected channel.
+    channel: String,
+    /// The log of `channel` when the bisection started, in base32.
+    log: Vec<String>,
+    good: usize,
+    bad: usize,
+    /// The state of the scratch channel.
+    current: usize,
+}
+
+impl Bisect {
+    pub fn run(self) -> Result<(), anyhow::Error> {
+        let mut repo = Repository::find_root(self.repo_path)?;
+        repo.require_working_copy()?;
+        let _lock = repo.lock_working_copy()?;
+        match self.subcmd {
+            SubCommand::Start { channel, good, bad } => start(&mut repo, channel, good, bad),
+            SubCommand::Good { change } => {
+                let mut state = load(&repo)?;
+                let n = state.position(change.as_deref())?;
+                if n >= state.bad {
+                    bail!("This state is after a bad state, it can't be good")
+                }
+                state.good = state.good.max(n);
+                step(&repo, &mut state)?;
+                Ok(())
+            }
+            SubCommand::Bad { change } => {
+                let mut state = load(&repo)?;
+                let n = state.position(change.as_deref())?;
+                if n <= state.good {
+                    bail!("This state is before a good state, it can't be bad")
+                }
+                state.bad = state.bad.min(n);
+                step(&repo, &mut state)?;
+                Ok(())
+            }
+            SubCommand::Run { command, args } => {
+                let mut state = load(&repo)?;
+                loop {
+                    debug!("running {:?} {:?}", command, args);
+                    let status = std::process::Command::new(&command)
+                        .args(&args)
+                        .current_dir(&repo.path)
+                        .status()?;
+                    if status.code().is_none() {
+                        bail!("{:?} was interrupted: {}", command, status)
+                    } else if status.success() {
+                        state.good = state.current
+                    } else {
+                        state.bad = state.current
+                    }
+                    if step(&repo, &mut state)? {
+                        return Ok(());
+                    }
+                }
+            }
+            SubCommand::Reset => {
+                let state = load(&repo)?;
+                let txn = repo.pristine.arc_txn_begin()?;
+                let channel = if let Some(c) = txn.read().load_channel(&state.channel)? {
+                    c
+                } else {
+                    bail!("Channel {:?} not found", state.channel)
+                };
+                txn.write().set_current_channel(&state.channel)?;
+                output(&repo, &txn, &channel)?;
+                txn.write().drop_channel(BISECT_CHANNEL)?;
+                txn.commit()?;
+                std::fs::remove_file(state_path(&repo))?;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn state_path(repo: &Repository) -> PathBuf {
+    repo.path.join(DOT_DIR).join(BISECT_FILE)
+}
+
+fn load(repo: &Repository) -> Result<BisectState, anyhow::Error> {
+    match std::fs::read(state_path(repo)) {
+        Ok(s) => Ok(serde_json::from_slice(&s)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            bail!("No bisection in progress, see `pijul bisect start`")
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl BisectState {
+    fn save(&self, repo: &Repository) -> Result<(), anyhow::Error> {
+        std::fs::write(state_path(repo), serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// The state after `change`, or the current state if `change` is
+    /// `None`.
+    fn position(&self, change: Option<&str>) -> Result<usize, anyhow::Error> {
+        let change = if let Some(change) = change {
+            change
+        } else {
+            return Ok(self.current);
+        };
+        let mut found = None;
+        for (i, h) in self.log.iter().enumerate() {
+            if h.starts_with(change) {
+                if found.is_some() {
+                    bail!("Ambiguous change: {:?}", change)
+                }
+                found = Some(i + 1)
+            }
+        }
+        if let Some(n) = found {
+            Ok(n)
+        } else {
+            bail!("Change {:?} is not in channel {:?}", change, self.channel)
+        }
+    }
+
+    fn hash(&self, n: usize) -> Result<Hash, anyhow::Error> {
+        if let Some(h) = Hash::from_base32(self.log[n].as_bytes()) {
+            Ok(h)
+        } else {
+            bail!("Invalid hash in {}: {:?}", BISECT_FILE, self.log[n])
+        }
+    }
+}
+
+fn start(
+    repo: &mut Repository,
+    channel: Option<String>,
+    good: Option<String>,
+    bad: Option<String>,
+) -> Result<(), anyhow::Error> {
+    if std::fs::metadata(state_path(repo)).is_ok() {
+        bail!("A bisection is already in progress, see `pijul bisect reset`")
+    }
+    let txn = repo.pristine.arc_txn_begin()?;
+    let channel_name = if let Some(c) = channel {
+        c
+    } else {
+        txn.read()
+            .current_channel()
+            .unwrap_or(crate::DEFAULT_CHANNEL)
+            .to_string()
+    };
+    if txn.read().load_channel(BISECT_CHANNEL)?.is_some() {
+        bail!("Channel {:?} already exists", BISECT_CHANNEL)
+    }
+    let channel = if let Some(c) = txn.read().load_channel(&channel_name)? {
+        c
+    } else {
+        bail!("Channel {:?} not found", channel_name)
+    };
+    let current = txn
+        .read()
+        .current_channel()
+        .unwrap_or(crate::DEFAULT_CHANNEL)
+        .to_string();
+    super::tag::try_record(repo, txn.clone(), &current)?;
+
+    let mut log = Vec::new();
+    for x in txn.read().log(&*channel.read(), 0)? {
+        let (_, (h, _)) = x?;
+        let h: Hash = h.into();
+        log.push(h.to_base32())
+    }
+    let mut state = BisectState {
+        channel: channel_name,
+        good: 0,
+        bad: log.len(),
+        current: log.len(),
+        log,
+    };
+    if good.is_some() {
+        state.good = state.position(good.as_deref())?;
+    }
+    if bad.is_some() {
+        state.bad = state.position(bad.as_deref())?;
+    }
+    if state.good >= state.bad {
+        bail!("The good state must be before the bad state")
+    }
+    {
+        let mut txn = txn.write();
+        txn.fork(&channel, BISECT_CHANNEL)?;
+        txn.set_current_channel(BISECT_CHANNEL)?;
+    }
+    txn.commit()?;
+    state.save(repo)?;
+    step(repo, &mut state)?;
+    Ok(())
+}
+
+/// Check out the next state to test, or report the first bad change.
+/// Returns whether the bisection is over.
+fn step(repo: &Repository, state: &mut BisectState) -> Result<bool, anyhow::Error> {
+    let mut stderr = std::io::stderr();
+    let done = state.bad - state.good <= 1;
+    let next = if done {
+        state.bad
+    } else {
+        (state.good + state.bad) / 2
+    };
+    let txn = repo.pristine.arc_txn_begin()?;
+    let channel = if let Some(c) = txn.read().load_channel(BISECT_CHANNEL)? {
+        c
+    } else {
+        bail!("Channel {:?} not found", BISECT_CHANNEL)
+    };
+    if next < state.current {
+        // Going back by many changes is faster by rebuilding the
+        // channel from its first `next` changes.
+        let t = {
+            let txn = txn.read();
+            let h = state.hash(next)?;
+            let id = if let Some(id) = txn.get_internal(&(&h).into())? {
+                *id
+            } else {
+                bail!("Change {} not found", h.to_base32())
+            };
+            let t = txn.get_changeset(txn.changes(&*channel.read()), &id)?;
+            u64::from(*t.unwrap())
+        };
+        txn.write().truncate(&repo.changes, &channel, t, 0)?;
+    } else {
+        let mut channel = channel.write();
+        let mut txn = txn.write();
+        for i in state.current..next {
+            txn.apply_change_rec(&repo.changes, &mut channel, &state.hash(i)?)?;
+        }
+    }
+    output(repo, &txn, &channel)?;
+    txn.commit()?;
+    state.current = next;
+    state.save(repo)?;
+
+    let hash = state.hash(next - 1)?;
+    let header = repo.changes.get_header(&hash)?;
+    if done {
+        writeln!(
+            stderr,
+            "The first bad change is {}\n\n    {}\n\nSee `pijul bisect reset` to go back to channel {:?}",
+            hash.to_base32(),
+            header.message,
+            state.channel
+        )?;
+    } else {
+        let left = state.bad - state.good - 1;
+        writeln!(
+            stderr,
+            "Bisecting: {} change(s) left to test (about {} step(s)), now after change {}\n\n    {}",
+            left,
+            (usize::BITS - left.leading_zeros()),
+            hash.to_base32(),
+            header.message
+        )?;
+    }
+    Ok(done)
+}
+
+/// Output the whole working copy from `channel`.
+fn output(
+    repo: &Repository,
+    txn: &ArcTxn<MutTxn<()>>,
+    channel: &ChannelRef<MutTxn<()>>,
+) -> Result<(), anyhow::Error> {
+    let conflicts = libpijul::output::output_repository_no_pending(
+        &repo.working_copy,
+        &repo.changes,
+        txn,
+        channel,
+        "",
+        true,
+        None,
+        crate::jobs::output(),
+        0,
+    )?;
+    txn.write().touch_channel(&mut *channel.write(), None);
+    super::print_conflicts(&conflicts)?;
+    Ok(())
+}
@@ -1,3 +1,4 @@
+use std::io::Write;
 use std::path::PathBuf;
 
 use clap::Parser;
@@ -11,6 +12,24 @@ pub struct Change {
     /// Use the repository at PATH instead of the current directory
     #[clap(long = "repository", value_name = "PATH")]
     repo_path: Option<PathBuf>,
+    /// Show the change grouped by file, with the added and deleted lines wrapped to the width of the terminal, instead of the text format of changes
+    #[clap(long = "pretty")]
+    pretty: bool,
+    /// Only show the paths of the files touched by the change
+    #[clap(long = "name-only", conflicts_with_all = &["pretty", "files"])]
+    name_only: bool,
+    /// Only show the files touched by the change, with the kinds of operations on them and the number of lines added and deleted
+    #[clap(long = "files", conflicts_with = "pretty")]
+    files: bool,
+    /// Output the change in JSON: its header, dependencies, and hunks grouped by file, with their lines in the old and new versions of the file, their edge flags and the lines they add and delete
+    #[clap(long = "json", conflicts_with_all = &["pretty", "name-only", "files"])]
+    json: bool,
+    /// Fold all files in the pretty view, only showing their paths
+    #[clap(long = "fold", requires = "pretty")]
+    fold: bool,
+    /// Don't fold this file when folding (may be given several times)
+    #[clap(long = "expand", value_name = "PATH", requires = "fold")]
+    expand: Vec<String>,
     /// The hash of the change to show, or an unambiguous prefix thereof
     #[clap(value_name = "HASH")]
     hash: Option<String>,
@@ -43,7 +62,55 @@ impl Change {
             }
         };
         let change = changes.get_change(&hash).unwrap();
+        if self.json {
+            let sections = super::render::sections(&changes, &change)?;
+            let mut stdout = std::io::stdout();
+            serde_json::to_writer_pretty(
+                &mut stdout,
+                &super::render::json(&hash, &change, &sections),
+            )?;
+            writeln!(stdout)?;
+            return Ok(());
+        }
+        if self.name_only || self.files {
+            let mut stdout = std::io::stdout();
+            for s in super::render::sections(&changes, &change)? {
+                if self.name_only {
+                    writeln!(stdout, "{}", libpijul::path::escape(&s.path))?;
+                } else {
+                    writeln!(
+                        stdout,
+                        "{} {} (+{} -{})",
+                        s.status_codes(),
+                        libpijul::path::escape(&s.path),
+                        s.added,
+                        s.deleted
+                    )?;
+                }
+            }
+            return Ok(());
+        }
+        // The width must be read before the pager is started.
+        let width = if atty::is(atty::Stream::Stdout) {
+            terminal_size::terminal_size().map(|(w, _)| w.0 as usize)
+        } else {
+            None
+        };
         let colors = super::diff::is_colored(repo.config.pager.as_ref());
+        if self.pretty {
+            let mut r = super::render::Renderer {
+                w: termcolor::StandardStream::stdout(termcolor::ColorChoice::Auto),
+                colors,
+                width,
+                aliases: repo.config.author_aliases.clone(),
+            };
+            r.header(&hash, &change.header)?;
+            for s in super::render::sections(&changes, &change)? {
+                let folded = self.fold && !self.expand.iter().any(|e| e == &s.path);
+                r.section(&s, folded)?;
+            }
+            return Ok(());
+        }
         change.write(
             &changes,
             Some(hash),
@@ -53,6 +120,23 @@ impl Change {
                 colors,
             },
         )?;
+        if let Some(hunk_authors) = change.hunk_authors() {
+            // Hunks are numbered from 1 in the text above.
+            let mut stdout = std::io::stdout();
+            writeln!(stdout, "\n# Hunk authors\n")?;
+            for (hunk, authors) in hunk_authors.hunk_authors.iter() {
+                let names: Vec<_> = authors
+                    .iter()
+                    .map(|&a| {
+                        super::credit::author_name(
+                            &change.header.authors[a],
+                            &repo.config.author_aliases,
+                        )
+                    })
+                    .collect();
+                writeln!(stdout, "{}. {}", hunk + 1, names.join(", "))?;
+            }
+        }
         Ok(())
     }
 }
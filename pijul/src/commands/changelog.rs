@@ -0,0 +1,126 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use clap::Parser;
+use libpijul::changestore::ChangeStore;
+use libpijul::*;
+
+use crate::repository::Repository;
+
+#[derive(Parser, Debug)]
+pub struct Changelog {
+    /// Set the repository where this command should run. Defaults to the first ancestor of the current directory that contains a `.pijul` directory.
+    #[clap(long = "repository")]
+    repo_path: Option<PathBuf>,
+    /// Use this channel instead of the current channel
+    #[clap(long = "channel")]
+    channel: Option<String>,
+    /// Group the changes by the value of this trailer of their descriptions, for example `Category` for changes ending with `Category: Fixed`
+    #[clap(long = "group-by", value_name = "TRAILER")]
+    group_by: Option<String>,
+    /// The state (usually a tag) the release notes start from, or an unambiguous prefix thereof
+    #[clap(value_name = "FROM")]
+    from: String,
+    /// The state the release notes end at, or an unambiguous prefix thereof. Defaults to the current state of the channel.
+    #[clap(value_name = "TO")]
+    to: Option<String>,
+}
+
+/// The heading of the changes without the trailer given by
+/// `--group-by`.
+const OTHER: &str = "Other changes";
+
+impl Changelog {
+    pub fn run(self) -> Result<(), anyhow::Error> {
+        let repo = Repository::find_root(self.repo_path)?;
+        let txn = repo.pristine.txn_begin()?;
+        let channel_name = if let Some(ref c) = self.channel {
+            c
+        } else {
+            txn.current_channel().unwrap_or(crate::DEFAULT_CHANNEL)
+        };
+        let channel = if let Some(channel) = txn.load_channel(channel_name)? {
+            channel
+        } else {
+            bail!("No such channel: {:?}", channel_name)
+        };
+        let channel = channel.read();
+        let (from, _) = txn.state_from_prefix(txn.states(&*channel), &self.from)?;
+        let to = if let Some(ref to) = self.to {
+            txn.state_from_prefix(txn.states(&*channel), to)?.0
+        } else {
+            txn.current_state(&*channel)?
+        };
+        let between = if let Some(b) = txn.changes_between(&*channel, &from, &to)? {
+            b
+        } else {
+            bail!("States not found in channel {:?}", channel_name)
+        };
+        if !between.unrecord.is_empty() {
+            bail!(
+                "State {} comes after state {} in channel {:?}",
+                from.to_base32(),
+                to.to_base32(),
+                channel_name
+            )
+        }
+
+        // Sections in the order of their first change.
+        let mut sections: Vec<(String, Vec<String>)> = Vec::new();
+        let mut section_index = BTreeMap::new();
+        for hash in between.apply.iter() {
+            let (message, section) = if let Some(ref key) = self.group_by {
+                let header = repo.changes.get_header(hash)?;
+                let section = header.trailer_values(key).into_iter().next();
+                let message = header.message.lines().next().unwrap_or("").to_string();
+                (message, section.unwrap_or_else(|| OTHER.to_string()))
+            } else {
+                (first_line(&repo, &txn, hash)?, String::new())
+            };
+            let i = *section_index.entry(section.clone()).or_insert_with(|| {
+                sections.push((section, Vec::new()));
+                sections.len() - 1
+            });
+            sections[i]
+                .1
+                .push(format!("- {} ({})", message, &hash.to_base32()[..10]))
+        }
+        // Changes without the trailer come last.
+        if let Some(i) = sections.iter().position(|s| s.0 == OTHER) {
+            let other = sections.remove(i);
+            sections.push(other)
+        }
+
+        let mut stdout = std::io::stdout();
+        writeln!(
+            stdout,
+            "## Changes from {} to {}",
+            &from.to_base32()[..10],
+            &to.to_base32()[..10]
+        )?;
+        for (section, items) in sections {
+            writeln!(stdout)?;
+            if self.group_by.is_some() {
+                writeln!(stdout, "### {}\n", section)?;
+            }
+            for item in items {
+                writeln!(stdout, "{}", item)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The first line of the message of `hash`, from the header cache of
+/// the pristine if possible, to avoid reading the change file.
+fn first_line<T: TxnT>(repo: &Repository, txn: &T, hash: &Hash) -> Result<String, anyhow::Error> {
+    if let Some(id) = txn.get_internal(&hash.into())? {
+        if let Some(h) = txn.get_cached_header(id)? {
+            return Ok(h.message);
+        }
+    }
+    let header = repo.changes.get_header(hash)?;
+    Ok(header.message.lines().next().unwrap_or("").to_string())
+}
@@ -1,11 +1,14 @@
 use std::io::Write;
 use std::path::PathBuf;
 
+use crate::config::Direction;
+use crate::remote::RemoteChannel;
 use crate::repository::Repository;
 use anyhow::anyhow;
 use anyhow::bail;
 use clap::Parser;
-use libpijul::{ChannelTxnT, MutTxnT, TxnT};
+use libpijul::pristine::ChannelMetadata;
+use libpijul::{Base32, ChannelTxnT, MutTxnT, TxnT, TxnTExt};
 
 #[derive(Parser, Debug)]
 pub struct Channel {
@@ -18,6 +21,25 @@ pub struct Channel {
 
 #[derive(Parser, Debug)]
 pub enum SubCommand {
+    /// List channels. This is the default when no subcommand is given.
+    #[clap(name = "list")]
+    List {
+        /// Also show the description, creator and creation time of
+        /// each channel, along with its number of changes, last
+        /// modification time and state.
+        #[clap(long = "verbose", short = 'v')]
+        verbose: bool,
+        /// Sort channels by last modification time, most recent first.
+        #[clap(long = "by-activity")]
+        by_activity: bool,
+        /// List the channels of this remote instead of the local
+        /// ones. The remote doesn't send descriptions.
+        #[clap(long = "remote")]
+        remote: Option<String>,
+        /// Do not check certificates (HTTPS remotes only, this option might be dangerous)
+        #[clap(short = 'k', requires = "remote")]
+        no_cert_check: bool,
+    },
     /// Delete a channel.
     /// The channel must not be the current channel.
     #[clap(name = "delete")]
@@ -25,8 +47,9 @@ pub enum SubCommand {
     /// Rename a channel.
     #[clap(name = "rename")]
     Rename { from: String, to: Option<String> },
-    /// Switch to a channel.
-    /// There must not be unrecorded changes in the working copy.
+    /// Switch to a channel, updating only the files that differ
+    /// between the two channels. Unrecorded changes are kept, unless
+    /// they are in one of these files.
     #[clap(name = "switch")]
     Switch {
         to: Option<String>,
@@ -35,27 +58,39 @@ pub enum SubCommand {
     },
     /// Create a new, empty channel.
     #[clap(name = "new")]
-    New { name: String },
+    New {
+        name: String,
+        /// Set the description of the new channel.
+        #[clap(short = 'm', long = "description")]
+        description: Option<String>,
+    },
+    /// Set or edit the description of a channel (the current channel
+    /// if omitted). Without `-m`, the description is edited in `$EDITOR`.
+    #[clap(name = "describe")]
+    Describe {
+        name: Option<String>,
+        /// Use this description instead of starting an editor. An
+        /// empty description removes it.
+        #[clap(short = 'm', long = "message")]
+        description: Option<String>,
+    },
 }
 
 impl Channel {
-    pub fn run(self) -> Result<(), anyhow::Error> {
-        let mut stdout = std::io::stdout();
+    pub async fn run(self) -> Result<(), anyhow::Error> {
         match self.subcmd {
-            None => {
-                let repo = Repository::find_root(self.repo_path)?;
-                let txn = repo.pristine.txn_begin()?;
-                let current = txn.current_channel().ok();
-                for channel in txn.channels("")? {
-                    let channel = channel.read();
-                    let name = txn.name(&*channel);
-                    if current == Some(name) {
-                        writeln!(stdout, "* {}", name)?;
-                    } else {
-                        writeln!(stdout, "  {}", name)?;
-                    }
-                }
-            }
+            None => list(self.repo_path, false, false)?,
+            Some(SubCommand::List {
+                verbose,
+                by_activity,
+                remote: Some(ref remote),
+                no_cert_check,
+            }) => list_remote(self.repo_path, remote, verbose, by_activity, no_cert_check).await?,
+            Some(SubCommand::List {
+                verbose,
+                by_activity,
+                ..
+            }) => list(self.repo_path, verbose, by_activity)?,
             Some(SubCommand::Delete { ref delete }) => {
                 let repo = Repository::find_root(self.repo_path)?;
                 let mut txn = repo.pristine.mut_txn_begin()?;
@@ -98,16 +133,164 @@ impl Channel {
                 txn.set_current_channel(&to)?;
                 txn.commit()?;
             }
-            Some(SubCommand::New { name }) => {
+            Some(SubCommand::New { name, description }) => {
                 let repo = Repository::find_root(self.repo_path)?;
                 let mut txn = repo.pristine.mut_txn_begin()?;
                 if txn.load_channel(&name)?.is_some() {
                     bail!("Channel {} already exists", name)
                 }
                 txn.open_or_create_channel(&name)?;
+                let meta = ChannelMetadata {
+                    description: description.filter(|d| !d.trim().is_empty()),
+                    created_by: public_key(),
+                    created: Some(chrono::Utc::now().timestamp() as u64),
+                };
+                txn.set_channel_metadata(&name, &meta)?;
+                txn.commit()?;
+            }
+            Some(SubCommand::Describe { name, description }) => {
+                let repo = Repository::find_root(self.repo_path)?;
+                let mut txn = repo.pristine.mut_txn_begin()?;
+                let name = if let Some(name) = name {
+                    name
+                } else {
+                    txn.current_channel()
+                        .unwrap_or(crate::DEFAULT_CHANNEL)
+                        .to_string()
+                };
+                if txn.load_channel(&name)?.is_none() {
+                    bail!("No such channel: {:?}", name)
+                }
+                let mut meta = txn.channel_metadata(&name)?.unwrap_or_default();
+                let description = if let Some(description) = description {
+                    description
+                } else {
+                    edit::edit(meta.description.as_deref().unwrap_or(""))?
+                };
+                let description = description.trim();
+                meta.description = if description.is_empty() {
+                    None
+                } else {
+                    Some(description.to_string())
+                };
+                txn.set_channel_metadata(&name, &meta)?;
                 txn.commit()?;
             }
         }
         Ok(())
     }
 }
+
+fn list(repo_path: Option<PathBuf>, verbose: bool, by_activity: bool) -> Result<(), anyhow::Error> {
+    let mut stdout = std::io::stdout();
+    let repo = Repository::find_root(repo_path)?;
+    let txn = repo.pristine.txn_begin()?;
+    let current = txn.current_channel().ok();
+    let mut channels = crate::remote::local::list_channels(&txn)?;
+    sort_channels(&mut channels, by_activity);
+    for c in channels.iter() {
+        if current == Some(c.name.as_str()) {
+            writeln!(stdout, "* {}", c.name)?;
+        } else {
+            writeln!(stdout, "  {}", c.name)?;
+        }
+        if !verbose {
+            continue;
+        }
+        if let Some(meta) = txn.channel_metadata(&c.name)? {
+            if let Some(ref d) = meta.description {
+                for l in d.lines() {
+                    writeln!(stdout, "      {}", l)?;
+                }
+            }
+            if let Some(created) = meta.created {
+                write!(stdout, "      Created: {}", format_date(created))?;
+                if let Some(ref key) = meta.created_by {
+                    write!(stdout, " by {}", key)?;
+                }
+                writeln!(stdout)?;
+            } else if let Some(ref key) = meta.created_by {
+                writeln!(stdout, "      Created by {}", key)?;
+            }
+        }
+        write_activity(&mut stdout, c)?;
+    }
+    Ok(())
+}
+
+/// List the channels of remote `remote_name`, over the `channels`
+/// protocol command for SSH remotes.
+async fn list_remote(
+    repo_path: Option<PathBuf>,
+    remote_name: &str,
+    verbose: bool,
+    by_activity: bool,
+    no_cert_check: bool,
+) -> Result<(), anyhow::Error> {
+    let mut stdout = std::io::stdout();
+    let repo = Repository::find_root(repo_path)?;
+    let txn = repo.pristine.txn_begin()?;
+    let mut remote = repo
+        .remote(
+            Some(&repo.path),
+            remote_name,
+            crate::DEFAULT_CHANNEL,
+            Direction::Pull,
+            no_cert_check,
+            true,
+        )
+        .await?;
+    let channels = remote.channels(&txn).await?;
+    remote.finish().await?;
+    let mut channels = if let Some(channels) = channels {
+        channels
+    } else {
+        bail!("Remote {:?} cannot list its channels", remote_name)
+    };
+    sort_channels(&mut channels, by_activity);
+    for c in channels.iter() {
+        writeln!(stdout, "  {}", c.name)?;
+        if verbose {
+            write_activity(&mut stdout, c)?;
+        }
+    }
+    Ok(())
+}
+
+fn sort_channels(channels: &mut [RemoteChannel], by_activity: bool) {
+    if by_activity {
+        channels.sort_by(|a, b| {
+            b.last_modified
+                .cmp(&a.last_modified)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+    } else {
+        channels.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+}
+
+fn write_activity<W: Write>(mut w: W, c: &RemoteChannel) -> Result<(), std::io::Error> {
+    // `last_modified` is in milliseconds.
+    writeln!(
+        w,
+        "      Apply counter: {}, last modified: {}",
+        c.apply_counter,
+        format_date(c.last_modified / 1000)
+    )?;
+    writeln!(w, "      State: {}", c.state.to_base32())
+}
+
+fn format_date(secs: u64) -> String {
+    chrono::NaiveDateTime::from_timestamp_opt(secs as i64, 0)
+        .map(|d| d.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| secs.to_string())
+}
+
+/// The public key of the current user, if any.
+fn public_key() -> Option<String> {
+    let mut dir = crate::config::global_config_dir()?;
+    dir.push("publickey.json");
+    let f = std::fs::File::open(&dir).ok()?;
+    let k: libpijul::key::PublicKey = serde_json::from_reader(f).ok()?;
+    Some(k.key)
+}
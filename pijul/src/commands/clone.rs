@@ -23,6 +23,17 @@ pub struct Clone {
     /// Do not check certificates (HTTPS remotes only, this option might be dangerous)
     #[clap(short = 'k')]
     no_cert_check: bool,
+    /// Only clone the pristine and the changes, without outputting a
+    /// working copy. Bare repositories can be pushed to and pulled
+    /// from, but not recorded in.
+    #[clap(long = "bare")]
+    bare: bool,
+    /// Read the changes of this local repository instead of copying
+    /// them, to save disk space. Only the changes that repository
+    /// doesn't have are downloaded, and the new repository can't be
+    /// used anymore if it is deleted.
+    #[clap(long = "reference", value_name = "PATH")]
+    reference: Option<PathBuf>,
     /// Clone this remote
     remote: String,
     /// Path where to clone the repository.
@@ -40,6 +51,7 @@ impl Clone {
             &self.channel,
             self.no_cert_check,
             true,
+            None,
         )
         .await?;
 
@@ -80,7 +92,18 @@ impl Clone {
                 .into(),
             _ => self.remote.as_str().into(),
         };
-        let mut repo = Repository::init(Some(path), None, Some(&remote_normalised))?;
+        let mut repo = if let Some(ref reference) = self.reference {
+            Repository::init_with_reference(
+                Some(path),
+                Some(&remote_normalised),
+                self.bare,
+                reference,
+            )?
+        } else if self.bare {
+            Repository::init_bare(Some(path), Some(&remote_normalised))?
+        } else {
+            Repository::init(Some(path), None, Some(&remote_normalised))?
+        };
         let txn = repo.pristine.arc_txn_begin()?;
         let mut channel = txn.write().open_or_create_channel(&self.channel)?;
         if let Some(ref change) = self.change {
@@ -103,18 +126,23 @@ impl Clone {
                 )
                 .await?;
         }
+        if let Some(meta) = remote.get_channel_metadata(&*txn.read()).await? {
+            txn.write().set_channel_metadata(&self.channel, &meta)?;
+        }
 
-        libpijul::output::output_repository_no_pending(
-            &repo.working_copy,
-            &repo.changes,
-            &txn,
-            &channel,
-            "",
-            true,
-            None,
-            num_cpus::get(),
-            self.salt.unwrap_or(0),
-        )?;
+        if !self.bare {
+            libpijul::output::output_repository_no_pending(
+                &repo.working_copy,
+                &repo.changes,
+                &txn,
+                &channel,
+                "",
+                true,
+                None,
+                crate::jobs::output(),
+                self.salt.unwrap_or(0),
+            )?;
+        }
         remote.finish().await?;
         txn.write().set_current_channel(&self.channel)?;
 
@@ -0,0 +1,172 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::bail;
+use clap::Parser;
+
+use crate::repository::*;
+
+#[derive(Parser, Debug)]
+pub struct Config {
+    /// Set the repository where this command should run. Defaults to the first ancestor of the current directory that contains a `.pijul` directory.
+    #[clap(long = "repository")]
+    repo_path: Option<PathBuf>,
+    /// Use the global configuration file instead of the repository's
+    #[clap(long = "global", short = 'g')]
+    global: bool,
+    #[clap(subcommand)]
+    subcmd: SubCommand,
+}
+
+#[derive(Parser, Debug)]
+pub enum SubCommand {
+    /// Print the value of a key. Keys of sections are separated by
+    /// dots, as in `hooks.record` or `author.name`.
+    #[clap(name = "get")]
+    Get { key: String },
+    /// Set the value of a key. The value is read as TOML if possible
+    /// (for example `true`, `10` or `["a", "b"]`), and as a string
+    /// otherwise. The file is only written if the resulting
+    /// configuration is valid.
+    #[clap(name = "set")]
+    Set { key: String, value: String },
+    /// List all the keys set in the configuration file, with their
+    /// values.
+    #[clap(name = "list")]
+    List,
+}
+
+impl Config {
+    pub fn run(self) -> Result<(), anyhow::Error> {
+        let path = if self.global {
+            if let Some(path) = crate::config::global_config_file() {
+                path
+            } else {
+                bail!("Global configuration directory not found")
+            }
+        } else {
+            let repo = Repository::find_root(self.repo_path)?;
+            repo.path.join(libpijul::DOT_DIR).join(CONFIG_FILE)
+        };
+        let mut config = read(&path)?;
+        let mut stdout = std::io::stdout();
+        match self.subcmd {
+            SubCommand::Get { key } => match get(&config, &key) {
+                Some(toml::Value::String(s)) => writeln!(stdout, "{}", s)?,
+                Some(v @ toml::Value::Table(_)) => write!(stdout, "{}", v)?,
+                Some(v) => writeln!(stdout, "{}", v)?,
+                None => bail!("Key {:?} not found in {:?}", key, path),
+            },
+            SubCommand::Set { key, value } => {
+                let value = parse_value(&value);
+                set(&mut config, &key, value)?;
+                let validated = if self.global {
+                    crate::config::validate_global(&config)
+                } else {
+                    crate::config::validate_repository(&config)
+                };
+                if let Err(e) = validated {
+                    bail!(
+                        "Not setting {:?}, the configuration would be invalid: {}",
+                        key,
+                        e
+                    )
+                }
+                write(&path, &config)?;
+            }
+            SubCommand::List => {
+                let mut entries = Vec::new();
+                flatten(&config, String::new(), &mut entries);
+                for (k, v) in entries {
+                    writeln!(stdout, "{} = {}", k, v)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read(path: &Path) -> Result<toml::Value, anyhow::Error> {
+    match std::fs::read_to_string(path) {
+        Ok(s) => match toml::from_str(&s) {
+            Ok(v) => Ok(v),
+            Err(e) => bail!("Could not read configuration file at {:?}: {}", path, e),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Ok(toml::Value::Table(Default::default()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Write `config` to a temporary file next to `path`, and then move
+/// it to `path`, so that the configuration is never half-written.
+fn write(path: &Path, config: &toml::Value) -> Result<(), anyhow::Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    std::fs::write(&tmp, toml::to_string(config)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn get<'a>(config: &'a toml::Value, key: &str) -> Option<&'a toml::Value> {
+    let mut v = config;
+    for k in key.split('.') {
+        v = v.as_table()?.get(k)?
+    }
+    Some(v)
+}
+
+fn set(config: &mut toml::Value, key: &str, value: toml::Value) -> Result<(), anyhow::Error> {
+    let mut v = config;
+    let mut components = key.split('.').peekable();
+    while let Some(k) = components.next() {
+        if k.is_empty() {
+            bail!("Invalid key: {:?}", key)
+        }
+        let table = if let Some(t) = v.as_table_mut() {
+            t
+        } else {
+            bail!("Cannot set {:?}: a parent key is not a section", key)
+        };
+        if components.peek().is_none() {
+            table.insert(k.to_string(), value);
+            return Ok(());
+        }
+        v = table
+            .entry(k.to_string())
+            .or_insert_with(|| toml::Value::Table(Default::default()));
+    }
+    Ok(())
+}
+
+/// Read `value` as a TOML value, or else as a string.
+fn parse_value(value: &str) -> toml::Value {
+    if let Ok(toml::Value::Table(mut t)) = format!("v = {}", value).parse::<toml::Value>() {
+        if let Some(v) = t.remove("v") {
+            return v;
+        }
+    }
+    toml::Value::String(value.to_string())
+}
+
+fn flatten(config: &toml::Value, prefix: String, entries: &mut Vec<(String, toml::Value)>) {
+    if let Some(t) = config.as_table() {
+        for (k, v) in t.iter() {
+            let key = if prefix.is_empty() {
+                k.clone()
+            } else {
+                format!("{}.{}", prefix, k)
+            };
+            if v.is_table() {
+                flatten(v, key, entries)
+            } else {
+                entries.push((key, v.clone()))
+            }
+        }
+    }
+}
@@ -1,15 +1,22 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 
 use anyhow::bail;
 use canonical_path::CanonicalPathBuf;
 use clap::Parser;
+use libpijul::change::{Atom, Author, AuthorMap};
+use libpijul::changestore::ChangeStore;
+use libpijul::pristine::ChangePosition;
 use libpijul::vertex_buffer::VertexBuffer;
 use libpijul::*;
 use log::debug;
 
 use crate::repository::Repository;
 
+/// The number of characters of the hashes shown when no length is
+/// configured.
+const DEFAULT_HASH_LENGTH: usize = 12;
+
 #[derive(Parser, Debug)]
 pub struct Credit {
     /// Set the repository where this command should run. Defaults to the first ancestor of the current directory that contains a `.pijul` directory.
@@ -18,6 +25,11 @@ pub struct Credit {
     /// Use this channel instead of the current channel
     #[clap(long = "channel")]
     channel: Option<String>,
+    /// Show the first N characters of the change hashes, or more if
+    /// needed to keep them unambiguous. Defaults to the `hash_length`
+    /// of the configuration, or to 12
+    #[clap(long = "hash-length", value_name = "N")]
+    hash_length: Option<usize>,
     /// The file to annotate
     file: PathBuf,
 }
@@ -50,6 +62,9 @@ impl Credit {
             let path = root.strip_prefix(&repo_path.as_path())?.to_str().unwrap();
             txn.follow_oldest_path(&repo.changes, &channel, &path)?
         };
+        let hash_len = super::hash_length(&repo, self.hash_length)
+            .unwrap_or(DEFAULT_HASH_LENGTH)
+            .max(txn.hash_prefix_len(&*channel.read())?);
         std::mem::drop(txn);
 
         super::pager(repo.config.pager.as_ref());
@@ -59,7 +74,14 @@ impl Credit {
             &txn_,
             &channel,
             pos,
-            &mut Creditor::new(std::io::stdout(), txn_.clone(), channel.clone()),
+            &mut Creditor::new(
+                std::io::stdout(),
+                txn_.clone(),
+                channel.clone(),
+                repo.changes.clone(),
+                repo.config.author_aliases.clone(),
+                hash_len,
+            ),
         ) {
             Ok(_) => {}
             Err(libpijul::output::FileError::Io(io)) => {
@@ -74,17 +96,31 @@ impl Credit {
     }
 }
 
-pub struct Creditor<W: std::io::Write, T: ChannelTxnT> {
+pub struct Creditor<W: std::io::Write, T: ChannelTxnT, C: ChangeStore> {
     w: W,
     buf: Vec<u8>,
     new_line: bool,
     changes: HashSet<Hash>,
     txn: ArcTxn<T>,
     channel: ChannelRef<T>,
+    store: C,
+    /// For each change already seen, the new vertices of its hunks
+    /// that have authors of their own, with these authors.
+    hunk_authors: HashMap<Hash, Vec<(ChangePosition, ChangePosition, Vec<String>)>>,
+    aliases: AuthorMap,
+    /// The number of characters of the hashes shown.
+    hash_len: usize,
 }
 
-impl<W: std::io::Write, T: ChannelTxnT> Creditor<W, T> {
-    pub fn new(w: W, txn: ArcTxn<T>, channel: ChannelRef<T>) -> Self {
+impl<W: std::io::Write, T: ChannelTxnT, C: ChangeStore> Creditor<W, T, C> {
+    pub fn new(
+        w: W,
+        txn: ArcTxn<T>,
+        channel: ChannelRef<T>,
+        store: C,
+        aliases: AuthorMap,
+        hash_len: usize,
+    ) -> Self {
         Creditor {
             w,
             new_line: true,
@@ -92,11 +128,77 @@ impl<W: std::io::Write, T: ChannelTxnT> Creditor<W, T> {
             txn,
             channel,
             changes: HashSet::new(),
+            store,
+            hunk_authors: HashMap::new(),
+            aliases,
+            hash_len,
         }
     }
+
+    /// The authors of the hunk that introduced `v`, if the change of
+    /// that hunk has per-hunk authors.
+    fn vertex_authors(&mut self, v: Vertex<ChangeId>) -> Option<Vec<String>> {
+        let hash: Hash = self.txn.read().get_external(&v.change).ok()??.into();
+        let store = &self.store;
+        let aliases = &self.aliases;
+        let vertices = self
+            .hunk_authors
+            .entry(hash)
+            .or_insert_with(|| hunk_vertices_authors(store, aliases, &hash));
+        vertices
+            .iter()
+            .find(|(start, end, _)| *start <= v.start && v.end <= *end)
+            .map(|(_, _, authors)| authors.clone())
+    }
 }
 
-impl<W: std::io::Write, T: TxnTExt> VertexBuffer for Creditor<W, T> {
+fn hunk_vertices_authors<C: ChangeStore>(
+    store: &C,
+    aliases: &AuthorMap,
+    hash: &Hash,
+) -> Vec<(ChangePosition, ChangePosition, Vec<String>)> {
+    let mut result = Vec::new();
+    let change = if let Ok(change) = store.get_change(hash) {
+        change
+    } else {
+        return result;
+    };
+    let hunk_authors = if let Some(a) = change.hunk_authors() {
+        a
+    } else {
+        return result;
+    };
+    for (i, hunk) in change.changes.iter().enumerate() {
+        if let Some(authors) = hunk_authors.get(i) {
+            let names: Vec<_> = authors
+                .iter()
+                .map(|&a| author_name(&change.header.authors[a], aliases))
+                .collect();
+            for atom in hunk.iter() {
+                if let Atom::NewVertex(ref n) = atom {
+                    result.push((n.start, n.end, names.clone()))
+                }
+            }
+        }
+    }
+    result
+}
+
+/// The display name of `author` in `aliases` if there is one, else
+/// its name if it has one, else its key.
+pub(crate) fn author_name(author: &Author, aliases: &AuthorMap) -> String {
+    if let Some(s) = aliases.display_name(author) {
+        s.to_string()
+    } else if let Some(s) = author.0.get("name") {
+        s.clone()
+    } else if let Some(k) = author.0.get("key") {
+        k.clone()
+    } else {
+        String::new()
+    }
+}
+
+impl<W: std::io::Write, T: TxnTExt, S: ChangeStore> VertexBuffer for Creditor<W, T, S> {
     fn output_line<E, C: FnOnce(&mut [u8]) -> Result<(), E>>(
         &mut self,
         v: Vertex<ChangeId>,
@@ -136,10 +238,13 @@ impl<W: std::io::Write, T: TxnTExt> VertexBuffer for Creditor<W, T> {
                     self.w,
                     "{}{}",
                     if is_first { "" } else { ", " },
-                    c.split_at(12).0,
+                    super::short_hash(&c, Some(self.hash_len)),
                 )?;
                 is_first = false;
             }
+            if let Some(authors) = self.vertex_authors(v) {
+                write!(self.w, " (by {})", authors.join(", "))?;
+            }
             writeln!(self.w, "\n")?;
         }
         let ends_with_newline = self.buf.ends_with(b"\n");
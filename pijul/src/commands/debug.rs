@@ -1,9 +1,12 @@
+use std::io::Write;
 use std::path::PathBuf;
 
 use crate::repository::Repository;
 use anyhow::bail;
 use clap::Parser;
+use libpijul::pristine::sanakirja::TableStats;
 use libpijul::{TxnT, TxnTExt};
+use serde_derive::Serialize;
 
 #[derive(Parser, Debug)]
 pub struct Debug {
@@ -13,11 +16,38 @@ pub struct Debug {
     channel: Option<String>,
     #[clap(long = "sanakirja-only")]
     sanakirja_only: bool,
+    /// Check the consistency of the channel and report the problems
+    /// found, instead of dumping the pristine
+    #[clap(long = "fsck", conflicts_with_all = &["sanakirja-only", "root"])]
+    fsck: bool,
     root: Option<String>,
+    #[clap(subcommand)]
+    subcmd: Option<SubCommand>,
+}
+
+#[derive(Parser, Debug)]
+pub enum SubCommand {
+    /// Print the number of entries and the approximate size of each
+    /// table of the pristine, including the tables of channels and
+    /// remotes
+    #[clap(name = "txn-stats")]
+    TxnStats {
+        /// Set the repository where this command should run. Defaults to
+        /// the first ancestor of the current directory that contains a
+        /// `.pijul` directory.
+        #[clap(long = "repository")]
+        repo_path: Option<PathBuf>,
+        /// Output the statistics in JSON
+        #[clap(long = "json")]
+        json: bool,
+    },
 }
 
 impl Debug {
     pub fn run(self) -> Result<(), anyhow::Error> {
+        if let Some(SubCommand::TxnStats { repo_path, json }) = self.subcmd {
+            return txn_stats(repo_path.or(self.repo_path), json);
+        }
         let repo = Repository::find_root(self.repo_path)?;
         let txn = repo.pristine.txn_begin()?;
         let channel_name = if let Some(ref c) = self.channel {
@@ -31,6 +61,23 @@ impl Debug {
         } else {
             bail!("No such channel: {:?}", channel_name)
         };
+        if self.fsck {
+            let unknown = libpijul::pristine::check_log(&txn, &channel.read())?;
+            for (timestamp, change) in unknown.iter() {
+                println!(
+                    "Log entry at timestamp {}: change {:?} has no hash",
+                    timestamp, change
+                );
+            }
+            if !unknown.is_empty() {
+                bail!(
+                    "Channel {:?} is corrupt: {} broken log entries",
+                    channel_name,
+                    unknown.len()
+                )
+            }
+            return Ok(());
+        }
         if !self.sanakirja_only {
             libpijul::pristine::debug_inodes(&txn);
             libpijul::pristine::debug_dep(&txn);
@@ -66,3 +113,60 @@ impl Debug {
         Ok(())
     }
 }
+
+#[derive(Serialize)]
+struct JsonStats<'a> {
+    #[serde(flatten)]
+    stats: &'a TableStats,
+    bytes: u64,
+}
+
+fn txn_stats(repo_path: Option<PathBuf>, json: bool) -> Result<(), anyhow::Error> {
+    let repo = Repository::find_root(repo_path)?;
+    let txn = repo.pristine.txn_begin()?;
+    let stats = txn.table_stats()?;
+    let mut stdout = std::io::stdout();
+    if json {
+        let stats: Vec<_> = stats
+            .iter()
+            .map(|stats| JsonStats {
+                stats,
+                bytes: stats.bytes(),
+            })
+            .collect();
+        serde_json::to_writer_pretty(&mut stdout, &stats)?;
+        writeln!(stdout)?;
+        return Ok(());
+    }
+    let owner = |s: &TableStats| match (&s.channel, &s.remote) {
+        (Some(c), _) => format!("channel {}", c),
+        (_, Some(r)) => format!("remote {}", r),
+        _ => String::new(),
+    };
+    let table_width = stats.iter().map(|s| s.table.len()).max().unwrap_or(0);
+    let owner_width = stats.iter().map(|s| owner(s).len()).max().unwrap_or(0);
+    let (mut entries, mut pages) = (0, 0);
+    for s in stats.iter() {
+        writeln!(
+            stdout,
+            "{:<tw$}  {:<ow$}  {:>10} entries  {:>8} pages  {:>10}",
+            s.table,
+            owner(s),
+            s.entries,
+            s.pages,
+            crate::progress::human_bytes(s.bytes() as f64),
+            tw = table_width,
+            ow = owner_width,
+        )?;
+        entries += s.entries;
+        pages += s.pages;
+    }
+    writeln!(
+        stdout,
+        "Total: {} entries in {} tables, {} pages",
+        entries,
+        stats.len(),
+        pages
+    )?;
+    Ok(())
+}
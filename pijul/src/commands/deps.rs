@@ -0,0 +1,193 @@
+use std::collections::{HashSet, VecDeque};
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use clap::Parser;
+use libpijul::changestore::ChangeStore;
+use libpijul::*;
+use serde_derive::Serialize;
+
+use crate::repository::*;
+
+#[derive(Parser, Debug)]
+pub struct Deps {
+    /// Use the repository at PATH instead of the current directory
+    #[clap(long = "repository", value_name = "PATH")]
+    repo_path: Option<PathBuf>,
+    /// Use this channel instead of the current channel
+    #[clap(long = "channel")]
+    channel: Option<String>,
+    /// Show the changes of the channel that depend on this change
+    /// (i.e. what would block unrecording it) instead of its
+    /// dependencies (i.e. what pushing it would push along)
+    #[clap(long = "reverse", short = 'r')]
+    reverse: bool,
+    /// Only follow dependencies up to this depth
+    #[clap(long = "depth", short = 'd')]
+    depth: Option<usize>,
+    /// Output format: "tree" (the default), "json" or "dot" (for Graphviz)
+    #[clap(long = "output-format")]
+    output_format: Option<String>,
+    /// The hash of the change, or an unambiguous prefix thereof
+    #[clap(value_name = "HASH")]
+    hash: String,
+}
+
+/// A change of the dependency graph, with the changes it points to.
+#[derive(Serialize)]
+struct Node {
+    hash: String,
+    message: String,
+    depth: usize,
+    /// Dependencies (or reverse dependencies with `--reverse`), not
+    /// listed if the depth limit is reached.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    edges: Option<Vec<String>>,
+}
+
+impl Deps {
+    pub fn run(self) -> Result<(), anyhow::Error> {
+        let repo = Repository::find_root(self.repo_path.clone())?;
+        let txn = repo.pristine.txn_begin()?;
+        let channel_name = if let Some(ref c) = self.channel {
+            c
+        } else {
+            txn.current_channel().unwrap_or(crate::DEFAULT_CHANNEL)
+        };
+        let channel = if let Some(channel) = txn.load_channel(channel_name)? {
+            channel
+        } else {
+            bail!("No such channel: {:?}", channel_name)
+        };
+        let channel = channel.read();
+        let (_, root) = if let Some(h) = Hash::from_base32(self.hash.as_bytes()) {
+            if let Some(id) = txn.get_internal(&h.into())? {
+                (h, *id)
+            } else {
+                bail!("Change not found: {:?}", self.hash)
+            }
+        } else {
+            txn.hash_from_prefix(&self.hash)?
+        };
+
+        // Breadth-first traversal, so that each change is visited at
+        // its smallest depth.
+        let mut nodes = Vec::new();
+        let mut visited = HashSet::new();
+        let mut queue = VecDeque::new();
+        queue.push_back((root, 0));
+        while let Some((id, depth)) = queue.pop_front() {
+            if !visited.insert(id) {
+                continue;
+            }
+            let hash: Hash = txn.get_external(&id)?.unwrap().into();
+            let message = repo.changes.get_header(&hash)?.message;
+            let edges = if self.depth.map(|d| depth < d).unwrap_or(true) {
+                let mut edges = Vec::new();
+                let it = if self.reverse {
+                    txn.iter_revdep(&id)?
+                } else {
+                    txn.iter_dep(&id)?
+                };
+                for x in it {
+                    let (id0, next) = x?;
+                    if id0 < &id {
+                        continue;
+                    } else if id0 > &id {
+                        break;
+                    }
+                    // Changes depending on this one in other channels
+                    // don't matter here.
+                    if self.reverse && txn.get_changeset(txn.changes(&channel), next)?.is_none() {
+                        continue;
+                    }
+                    edges.push(*next)
+                }
+                for next in edges.iter() {
+                    queue.push_back((*next, depth + 1))
+                }
+                Some(edges)
+            } else {
+                None
+            };
+            nodes.push((id, hash, message, depth, edges))
+        }
+
+        let base32 = |id: &ChangeId| -> Result<String, anyhow::Error> {
+            let h: Hash = txn.get_external(id)?.unwrap().into();
+            Ok(h.to_base32())
+        };
+        let mut stdout = std::io::stdout();
+        match self.output_format.as_ref().map(|s| s.as_str()) {
+            Some(s) if s.eq_ignore_ascii_case("json") => {
+                let mut out = Vec::with_capacity(nodes.len());
+                for (_, hash, message, depth, edges) in nodes {
+                    out.push(Node {
+                        hash: hash.to_base32(),
+                        message,
+                        depth,
+                        edges: if let Some(edges) = edges {
+                            Some(edges.iter().map(&base32).collect::<Result<_, _>>()?)
+                        } else {
+                            None
+                        },
+                    })
+                }
+                serde_json::to_writer_pretty(&mut stdout, &out)?;
+                writeln!(stdout)?;
+            }
+            Some(s) if s.eq_ignore_ascii_case("dot") => {
+                writeln!(stdout, "digraph {{")?;
+                for (_, hash, message, _, edges) in nodes.iter() {
+                    let hash = hash.to_base32();
+                    writeln!(
+                        stdout,
+                        "  {:?} [label={:?}];",
+                        hash,
+                        format!("{}\n{}", &hash[..10], message.lines().next().unwrap_or(""))
+                    )?;
+                    for e in edges.iter().flatten() {
+                        writeln!(stdout, "  {:?} -> {:?};", hash, base32(e)?)?;
+                    }
+                }
+                writeln!(stdout, "}}")?;
+            }
+            Some(s) if !s.eq_ignore_ascii_case("tree") => {
+                bail!("Unknown output format: {:?}", s)
+            }
+            _ => {
+                let nodes: libpijul::HashMap<_, _> = nodes
+                    .into_iter()
+                    .map(|(id, hash, message, _, edges)| (id, (hash, message, edges)))
+                    .collect();
+                let mut shown = HashSet::new();
+                let mut stack = vec![(root, 0)];
+                while let Some((id, indent)) = stack.pop() {
+                    let (hash, message, edges) = nodes.get(&id).unwrap();
+                    write!(
+                        stdout,
+                        "{:indent$}{} {}",
+                        "",
+                        hash.to_base32(),
+                        message.lines().next().unwrap_or(""),
+                        indent = 2 * indent
+                    )?;
+                    if !shown.insert(id) {
+                        // Already shown with its dependencies above.
+                        if edges.as_ref().map(|e| !e.is_empty()).unwrap_or(false) {
+                            write!(stdout, " (...)")?;
+                        }
+                        writeln!(stdout)?;
+                        continue;
+                    }
+                    writeln!(stdout)?;
+                    for e in edges.iter().flatten().rev() {
+                        stack.push((*e, indent + 1))
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -2,12 +2,15 @@ use std::collections::{BTreeMap, BTreeSet};
 use std::io::Write;
 use std::path::PathBuf;
 
+use anyhow::bail;
 use canonical_path::CanonicalPathBuf;
 use clap::Parser;
 use libpijul::change::*;
+use libpijul::working_copy::filesystem::escape_path;
 use libpijul::{MutTxnT, TxnT, TxnTExt};
 use serde_derive::Serialize;
 
+use crate::filters::FilterOpts;
 use crate::repository::*;
 
 #[derive(Parser, Debug)]
@@ -18,7 +21,7 @@ pub struct Diff {
     /// Output the diff in JSON format instead of the default change text format.
     #[clap(long = "json")]
     pub json: bool,
-    /// Compare with this channel.
+    /// Compare the working copy with this channel instead of the current channel, without switching to it.
     #[clap(long = "channel")]
     pub channel: Option<String>,
     /// Add all the changes of this channel as dependencies (except changes implied transitively), instead of the minimal dependencies.
@@ -27,18 +30,27 @@ pub struct Diff {
     /// Show a short version of the diff.
     #[clap(short = 's', long = "short")]
     pub short: bool,
+    /// Only print the paths of the files that changed. With `--channel`, compare the file trees of the current channel and of that channel (instead of the working copy), without outputting any file.
+    #[clap(long = "name-only", conflicts_with_all = &["json", "short"])]
+    pub name_only: bool,
     /// Include the untracked files
     #[clap(short = 'u', long = "untracked")]
     pub untracked: bool,
-    /// Only diff those paths (files or directories). If missing, diff the entire repository.
+    #[clap(flatten)]
+    pub filters: FilterOpts,
+    /// Only diff those paths (files or directories), relative to the current directory. If missing, diff the current directory, which is the entire repository when run from its root.
     pub prefixes: Vec<PathBuf>,
 }
 
 impl Diff {
     pub fn run(mut self) -> Result<(), anyhow::Error> {
-        let repo = Repository::find_root(self.repo_path.clone())?;
+        let mut repo = Repository::find_root(self.repo_path.clone())?;
+        repo.require_working_copy()?;
+        let filter = self.filters.path_filter()?;
+        repo.working_copy.set_path_filter(filter.clone());
         let txn = repo.pristine.arc_txn_begin()?;
         let mut stdout = std::io::stdout();
+        self.prefixes = repo.scoped_prefixes(&self.prefixes)?;
 
         if self.untracked && self.json {
             let txn = txn.read();
@@ -60,9 +72,37 @@ impl Diff {
         } else {
             cur.as_str()
         };
-        let channel = txn.write().open_or_create_channel(&channel)?;
+        if self.name_only && channel != cur {
+            let txn = txn.read();
+            let load = |name: &str| {
+                if let Some(c) = txn.load_channel(name)? {
+                    Ok(c)
+                } else {
+                    bail!("Channel {:?} not found", name)
+                }
+            };
+            let (other, current) = (load(channel)?, load(&cur)?);
+            for d in txn.diff_trees(&repo.changes, &other, &current)? {
+                writeln!(stdout, "{}", libpijul::path::escape(d.path()))?;
+            }
+            return Ok(());
+        }
+        let is_current = channel == cur;
+        let channel = if is_current {
+            txn.write().open_or_create_channel(&channel)?
+        } else if let Some(c) = txn.read().load_channel(channel)? {
+            c
+        } else {
+            bail!("Channel {:?} not found", channel)
+        };
 
         let mut state = libpijul::RecordBuilder::new();
+        state.separators = repo.config.separators()?;
+        state.file_classes = repo.config.file_classes(&[])?;
+        state.path_filter = filter;
+        // The modification times of the files only tell whether they
+        // changed since the last output of the current channel.
+        state.force_rediff = !is_current;
         if self.prefixes.is_empty() {
             state.record(
                 txn.clone(),
@@ -73,10 +113,9 @@ impl Diff {
                 &repo.working_copy,
                 &repo.changes,
                 "",
-                num_cpus::get(),
+                crate::jobs::record(),
             )?
         } else {
-            self.fill_relative_prefixes()?;
             repo.working_copy.record_prefixes(
                 txn.clone(),
                 channel.clone(),
@@ -85,7 +124,7 @@ impl Diff {
                 CanonicalPathBuf::canonicalize(&repo.path)?,
                 &self.prefixes,
                 false,
-                num_cpus::get(),
+                crate::jobs::record(),
                 0,
             )?;
         }
@@ -94,11 +133,11 @@ impl Diff {
             let txn = txn.read();
             if self.short && self.untracked {
                 for path in untracked(&repo, &*txn)? {
-                    writeln!(stdout, "U {}", path.to_str().unwrap())?;
+                    writeln!(stdout, "U {}", escape_path(&path))?;
                 }
             } else if self.untracked {
                 for path in untracked(&repo, &*txn)? {
-                    writeln!(stdout, "{}", path.to_str().unwrap())?;
+                    writeln!(stdout, "{}", escape_path(&path))?;
                 }
             }
             return Ok(());
@@ -163,47 +202,12 @@ impl Diff {
         } else if self.short {
             let mut changes = BTreeMap::new();
             for ch in change.changes.iter() {
-                match ch {
-                    Hunk::FileMove { path, .. } => {
-                        changes.entry(path).or_insert(BTreeSet::new()).insert("MV")
-                    }
-                    Hunk::FileDel { path, .. } => {
-                        changes.entry(path).or_insert(BTreeSet::new()).insert("D")
-                    }
-                    Hunk::FileUndel { path, .. } => {
-                        changes.entry(path).or_insert(BTreeSet::new()).insert("UD")
-                    }
-                    Hunk::FileAdd { path, .. } => {
-                        changes.entry(path).or_insert(BTreeSet::new()).insert("A")
-                    }
-                    Hunk::SolveNameConflict { path, .. } => {
-                        changes.entry(path).or_insert(BTreeSet::new()).insert("SC")
-                    }
-                    Hunk::UnsolveNameConflict { path, .. } => {
-                        changes.entry(path).or_insert(BTreeSet::new()).insert("UC")
-                    }
-                    Hunk::Edit {
-                        local: Local { path, .. },
-                        ..
-                    } => changes.entry(path).or_insert(BTreeSet::new()).insert("M"),
-                    Hunk::Replacement {
-                        local: Local { path, .. },
-                        ..
-                    } => changes.entry(path).or_insert(BTreeSet::new()).insert("R"),
-                    Hunk::SolveOrderConflict {
-                        local: Local { path, .. },
-                        ..
-                    } => changes.entry(path).or_insert(BTreeSet::new()).insert("SC"),
-                    Hunk::UnsolveOrderConflict {
-                        local: Local { path, .. },
-                        ..
-                    } => changes.entry(path).or_insert(BTreeSet::new()).insert("SC"),
-                    Hunk::ResurrectZombies {
-                        local: Local { path, .. },
-                        ..
-                    } => changes.entry(path).or_insert(BTreeSet::new()).insert("RZ"),
-                    Hunk::AddRoot { .. } | Hunk::DelRoot { .. } => true,
-                };
+                if let Some(code) = ch.short_code() {
+                    changes
+                        .entry(ch.path())
+                        .or_insert(BTreeSet::new())
+                        .insert(code);
+                }
             }
             let al = changes
                 .iter()
@@ -222,16 +226,21 @@ impl Diff {
                     is_first = false;
                 }
                 let (sp, _) = spaces.split_at(al - v.len());
-                writeln!(stdout, "{} {}", sp, k)?;
+                writeln!(stdout, "{} {}", sp, libpijul::path::escape(k))?;
             }
             if self.untracked {
                 for path in untracked(&repo, &*txn_)? {
-                    writeln!(stdout, "U {}", path.to_str().unwrap())?;
+                    writeln!(stdout, "U {}", escape_path(&path))?;
                 }
             }
+        } else if self.name_only {
+            let paths: BTreeSet<_> = change.changes.iter().map(|ch| ch.path()).collect();
+            for path in paths {
+                writeln!(stdout, "{}", libpijul::path::escape(path))?;
+            }
         } else if self.untracked {
             for path in untracked(&repo, &*txn_)? {
-                writeln!(stdout, "{}", path.to_str().unwrap())?;
+                writeln!(stdout, "{}", escape_path(&path))?;
             }
         } else {
             match change.write(
@@ -249,7 +258,9 @@ impl Diff {
                 Err(e) => return Err(e.into()),
             }
         }
-        if actions_is_empty && self.prefixes.is_empty() {
+        // Only the current channel is output to the working copy, so
+        // another channel can't be marked as up to date with it.
+        if actions_is_empty && self.prefixes.is_empty() && is_current {
             use libpijul::ChannelMutTxnT;
             txn_.touch_channel(&mut *channel.write(), None);
             std::mem::drop(txn_);
@@ -257,16 +268,6 @@ impl Diff {
         }
         Ok(())
     }
-
-    fn fill_relative_prefixes(&mut self) -> Result<(), anyhow::Error> {
-        let cwd = std::env::current_dir()?;
-        for p in self.prefixes.iter_mut() {
-            if p.is_relative() {
-                *p = cwd.join(&p);
-            }
-        }
-        Ok(())
-    }
 }
 
 #[derive(Debug, Serialize)]
@@ -361,12 +362,18 @@ fn untracked<'a, T: TxnTExt>(
     txn: &'a T,
 ) -> Result<impl Iterator<Item = PathBuf> + 'a, anyhow::Error> {
     let repo_path = CanonicalPathBuf::canonicalize(&repo.path)?;
-    let threads = num_cpus::get();
+    let threads = crate::jobs::record();
+    // As in `pijul add`, the directories walked with a filter are
+    // only there for the files they contain.
+    let filtered = repo.working_copy.path_filter().is_some();
     Ok(repo
         .working_copy
         .iterate_prefix_rec(repo_path.clone(), repo_path.clone(), false, threads)?
         .filter_map(move |x| {
-            let (path, _) = x.unwrap();
+            let (path, is_dir) = x.unwrap();
+            if is_dir && filtered {
+                return None;
+            }
             use path_slash::PathExt;
             let path_str = path.to_slash_lossy();
             if !txn.is_tracked(&path_str).unwrap() {
@@ -0,0 +1,93 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use clap::Parser;
+use libpijul::MutTxnT;
+use log::debug;
+
+use super::pushpull::{all_remote_names, expand_remote_group};
+use crate::config::Direction;
+use crate::repository::Repository;
+
+#[derive(Parser, Debug)]
+pub struct Fetch {
+    /// Set the repository where this command should run. Defaults to the first ancestor of the current directory that contains a `.pijul` directory.
+    #[clap(long = "repository")]
+    repo_path: Option<PathBuf>,
+    /// Do not check certificates (HTTPS remotes only, this option might be dangerous)
+    #[clap(short = 'k')]
+    no_cert_check: bool,
+    /// Fetch the changelist of this remote channel
+    #[clap(long = "from-channel")]
+    from_channel: Option<String>,
+    /// Fetch from these remotes (or from all the remotes of group `@<group>`) instead of all the remotes of the configuration file
+    remotes: Vec<String>,
+}
+
+impl Fetch {
+    pub async fn run(self) -> Result<(), anyhow::Error> {
+        let repo = Repository::find_root(self.repo_path.clone())?;
+        let names = if self.remotes.is_empty() {
+            all_remote_names(&repo)?
+        } else {
+            let mut names = Vec::new();
+            for r in self.remotes.iter() {
+                names.extend(expand_remote_group(&repo, r)?.into_iter())
+            }
+            names
+        };
+        let from_channel = self
+            .from_channel
+            .as_deref()
+            .unwrap_or(crate::DEFAULT_CHANNEL);
+        fetch(&repo, &names, from_channel, self.no_cert_check).await
+    }
+}
+
+/// Refresh the cached changelists of the remotes `names`, without
+/// pulling anything, so that later pulls only need to download the
+/// changes. Failing remotes are reported, but don't prevent the other
+/// ones from being fetched.
+pub(super) async fn fetch(
+    repo: &Repository,
+    names: &[String],
+    from_channel: &str,
+    no_cert_check: bool,
+) -> Result<(), anyhow::Error> {
+    let mut txn = repo.pristine.mut_txn_begin()?;
+    let mut stderr = std::io::stderr();
+    let mut failed = 0;
+    for name in names {
+        debug!("fetching {:?}", name);
+        let result = async {
+            let mut remote = repo
+                .remote(
+                    Some(&repo.path),
+                    name,
+                    from_channel,
+                    Direction::Pull,
+                    no_cert_check,
+                    true,
+                )
+                .await?;
+            let added = remote.refresh_changelist(&mut txn).await?;
+            remote.finish().await?;
+            Ok::<_, anyhow::Error>(added)
+        }
+        .await;
+        match result {
+            Ok(Some(added)) => writeln!(stderr, "Fetched {}: {} new change(s)", name, added)?,
+            Ok(None) => writeln!(stderr, "Nothing to fetch from {}", name)?,
+            Err(e) => {
+                writeln!(stderr, "Error fetching {}: {}", name, e)?;
+                failed += 1
+            }
+        }
+    }
+    txn.commit()?;
+    if failed > 0 {
+        bail!("Could not fetch {} of {} remote(s)", failed, names.len())
+    }
+    Ok(())
+}
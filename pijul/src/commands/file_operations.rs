@@ -6,6 +6,7 @@ use clap::Parser;
 use libpijul::{MutTxnT, MutTxnTExt, TxnTExt};
 use log::{debug, info};
 
+use crate::filters::FilterOpts;
 use crate::repository::Repository;
 
 #[derive(Parser, Debug)]
@@ -22,6 +23,8 @@ pub struct Move {
 impl Move {
     pub fn run(mut self) -> Result<(), anyhow::Error> {
         let repo = Repository::find_root(None)?;
+        repo.require_working_copy()?;
+        let _lock = repo.lock_working_copy()?;
         let to = if let Some(to) = self.paths.pop() {
             to
         } else {
@@ -97,7 +100,7 @@ impl List {
         let mut stdout = std::io::stdout();
         for p in txn.iter_working_copy() {
             let p = p?.1;
-            writeln!(stdout, "{}", p)?;
+            writeln!(stdout, "{}", libpijul::path::escape(&p))?;
         }
         Ok(())
     }
@@ -111,15 +114,21 @@ pub struct Add {
     force: bool,
     #[clap(hide = true, long = "salt")]
     salt: Option<u64>,
+    #[clap(flatten)]
+    filters: FilterOpts,
     /// Paths to add to the internal tree.
     paths: Vec<PathBuf>,
 }
 
 impl Add {
     pub fn run(self) -> Result<(), anyhow::Error> {
-        let repo = Repository::find_root(None)?;
+        let mut repo = Repository::find_root(None)?;
+        repo.require_working_copy()?;
+        let _lock = repo.lock_working_copy()?;
+        let filter = self.filters.path_filter()?;
+        repo.working_copy.set_path_filter(filter.clone());
         let txn = repo.pristine.arc_txn_begin()?;
-        let threads = num_cpus::get();
+        let threads = crate::jobs::record();
         let repo_path = CanonicalPathBuf::canonicalize(&repo.path)?;
         let mut stderr = std::io::stderr();
         for path in self.paths.iter() {
@@ -150,14 +159,24 @@ impl Add {
                     self.salt.unwrap_or(0),
                 )?
             } else {
+                if path.as_path().to_str().is_none() {
+                    writeln!(
+                        stderr,
+                        "Invalid UTF-8 in path: {}",
+                        libpijul::working_copy::filesystem::escape_path(path.as_path())
+                    )?;
+                    continue;
+                }
                 let mut txn = txn.write();
-                let path = if let Ok(path) = path.as_path().strip_prefix(&repo_path.as_path()) {
-                    path
-                } else {
+                let path_str = repo.relative_path(path.as_path())?;
+                if path_str.is_empty() {
                     continue;
-                };
-                use path_slash::PathExt;
-                let path_str = path.to_slash_lossy();
+                }
+                if let Some(ref filter) = filter {
+                    if !filter(&path_str, meta.is_dir()) {
+                        continue;
+                    }
+                }
                 if !txn.is_tracked(&path_str)? {
                     if let Err(e) = txn.add(&path_str, meta.is_dir(), self.salt.unwrap_or(0)) {
                         writeln!(stderr, "{}", e)?;
@@ -179,6 +198,8 @@ pub struct Remove {
 impl Remove {
     pub fn run(self) -> Result<(), anyhow::Error> {
         let repo = Repository::find_root(None)?;
+        repo.require_working_copy()?;
+        let _lock = repo.lock_working_copy()?;
         let mut txn = repo.pristine.mut_txn_begin()?;
         let repo_path = CanonicalPathBuf::canonicalize(&repo.path)?;
         for path in self.paths.iter() {
@@ -46,6 +46,7 @@ impl Git {
         } else {
             Repository::init(self.repo_path.clone(), None, None)?
         };
+        let _lock = repo.lock_working_copy()?;
         let git = git2::Repository::open(&repo.path)?;
         let st = git.statuses(None)?;
         let mut uncommitted = false;
@@ -451,7 +452,7 @@ fn import_commit_parents<T: TxnTExt + MutTxnTExt + GraphIter + Send + Sync + 'st
             "",
             false,
             None,
-            num_cpus::get(),
+            crate::jobs::output(),
             0,
         )?;
         let t = output_time.elapsed();
@@ -739,7 +740,7 @@ fn record_apply<
     debug!("record_apply {:?}", prefixes);
     let record_time = std::time::Instant::now();
     let mut state = libpijul::RecordBuilder::new();
-    let num_cpus = num_cpus::get();
+    let n_workers = crate::jobs::record();
     for p in prefixes.iter() {
         use libpijul::working_copy::filesystem::*;
         match working_copy.record_prefix(
@@ -750,7 +751,7 @@ fn record_apply<
             repo_path.clone(),
             p,
             false,
-            num_cpus,
+            n_workers,
             0,
         ) {
             Ok(_) => {}
@@ -772,7 +773,7 @@ fn record_apply<
             repo_path.clone(),
             Path::new(""),
             false,
-            num_cpus,
+            n_workers,
             0,
         ) {
             Ok(_) => {}
@@ -797,19 +798,25 @@ fn record_apply<
     let n = actions.len();
     let (dependencies, extra_known) =
         libpijul::change::dependencies(&*txn, &channel.read(), actions.iter())?;
+    let mut contents = std::mem::take(&mut *rec.contents.lock());
     let mut change = libpijul::change::LocalChange::make_change(
         &*txn,
         &channel,
         actions,
-        std::mem::replace(&mut *rec.contents.lock(), Vec::new()),
+        Vec::new(),
         header,
         Vec::new(),
     )?;
+    change.contents_hash = contents.hash()?;
     change.dependencies = dependencies;
     change.extra_known = extra_known;
     debug!("saving change");
     let hash = changes
-        .save_change(&mut change, |_, _| Ok::<_, anyhow::Error>(()))
+        .save_change_with_contents(
+            &mut change,
+            &mut contents,
+            |_, _| Ok::<_, anyhow::Error>(()),
+        )
         .unwrap();
     stats.record_time = record_time.elapsed();
     debug!("saved");
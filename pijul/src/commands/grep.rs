@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::bail;
+use clap::Parser;
+use libpijul::change::LineMatch;
+use libpijul::*;
+use log::debug;
+use regex::Regex;
+
+use crate::repository::Repository;
+
+#[derive(Parser, Debug)]
+pub struct Grep {
+    /// Set the repository where this command should run. Defaults to the first ancestor of the current directory that contains a `.pijul` directory.
+    #[clap(long = "repository")]
+    repo_path: Option<PathBuf>,
+    /// Search this channel instead of the current channel (with `--history`)
+    #[clap(long = "channel")]
+    channel: Option<String>,
+    /// Search the lines added and deleted by all the changes of the
+    /// channel, instead of the tracked files of the working copy
+    #[clap(long = "history")]
+    history: bool,
+    /// Regular expression to search for
+    pattern: String,
+    /// Only search in these paths
+    #[clap(last = true)]
+    paths: Vec<String>,
+}
+
+impl Grep {
+    pub fn run(self) -> Result<(), anyhow::Error> {
+        let repo = Repository::find_root(self.repo_path.clone())?;
+        let re = Regex::new(&self.pattern)?;
+        if self.history {
+            self.grep_history(repo, re)
+        } else {
+            self.grep_working_copy(repo, re)
+        }
+    }
+
+    fn grep_working_copy(&self, repo: Repository, re: Regex) -> Result<(), anyhow::Error> {
+        let txn = repo.pristine.txn_begin()?;
+        let mut stdout = std::io::stdout();
+        for p in txn.iter_working_copy() {
+            let (_, path, is_dir) = p?;
+            if is_dir || !self.in_paths(&path) {
+                continue;
+            }
+            let f = if let Ok(f) = std::fs::File::open(repo.path.join(&path)) {
+                std::io::BufReader::new(f)
+            } else {
+                continue;
+            };
+            for (n, line) in f.split(b'\n').enumerate() {
+                let line = line?;
+                // Skip binary lines.
+                if let Ok(line) = std::str::from_utf8(&line) {
+                    if re.is_match(line) {
+                        let path = libpijul::path::escape(&path);
+                        writeln!(stdout, "{}:{}:{}", path, n + 1, line)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn in_paths(&self, path: &str) -> bool {
+        self.paths.is_empty()
+            || self.paths.iter().any(|p| {
+                let p = p.trim_end_matches('/');
+                path == p || (path.starts_with(p) && path.as_bytes().get(p.len()) == Some(&b'/'))
+            })
+    }
+
+    fn grep_history(&self, repo: Repository, re: Regex) -> Result<(), anyhow::Error> {
+        let txn = repo.pristine.txn_begin()?;
+        let channel_name = if let Some(ref c) = self.channel {
+            c
+        } else {
+            txn.current_channel().unwrap_or(crate::DEFAULT_CHANNEL)
+        };
+        let channel = if let Some(channel) = txn.load_channel(channel_name)? {
+            channel
+        } else {
+            bail!("No such channel: {:?}", channel_name)
+        };
+
+        // Positions of the inodes we're interested in, if any,
+        // including the files that were at these paths in the past.
+        let mut inodes = HashSet::default();
+        for path in self.paths.iter() {
+            for p in txn.path_inodes(&repo.changes, &channel, path)? {
+                inodes.insert(p);
+                for q in libpijul::fs::iter_graph_descendants(&txn, &channel.read(), p)? {
+                    inodes.insert(q?);
+                }
+            }
+        }
+
+        let mut hashes = Vec::new();
+        for x in txn.log(&*channel.read(), 0)? {
+            let (_, (h, _)) = x?;
+            if !inodes.is_empty() {
+                let h_int = txn.get_internal(h)?.unwrap();
+                let mut touches = false;
+                for p in inodes.iter() {
+                    if txn.get_touched_files(p, Some(h_int))?.is_some() {
+                        touches = true;
+                        break;
+                    }
+                }
+                if !touches {
+                    continue;
+                }
+            }
+            hashes.push(Hash::from(h));
+        }
+        debug!("grep: {:?} changes", hashes.len());
+
+        // The same inodes, identified by the hash of the change that
+        // introduced them, to select the hunks editing them.
+        let inodes = if inodes.is_empty() {
+            None
+        } else {
+            let mut ext = HashSet::default();
+            for p in inodes.iter() {
+                if let Some(h) = txn.get_external(&p.change)? {
+                    ext.insert(Position {
+                        change: h.into(),
+                        pos: p.pos,
+                    });
+                }
+            }
+            Some(Arc::new(ext))
+        };
+
+        let hashes = Arc::new(hashes);
+        let re = Arc::new(re);
+        let next = Arc::new(AtomicUsize::new(0));
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let jobs = crate::jobs::other();
+        let mut workers = Vec::with_capacity(jobs);
+        for _ in 0..jobs {
+            let hashes = hashes.clone();
+            let re = re.clone();
+            let inodes = inodes.clone();
+            let next = next.clone();
+            let sender = sender.clone();
+            let changes = repo.changes.clone();
+            workers.push(std::thread::spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let hash = if let Some(hash) = hashes.get(i) {
+                    hash
+                } else {
+                    break;
+                };
+                let result =
+                    libpijul::change::search_change(&changes, hash, inodes.as_deref(), |l| {
+                        re.is_match(l)
+                    });
+                if sender.send((i, result)).is_err() {
+                    break;
+                }
+            }));
+        }
+        std::mem::drop(sender);
+
+        // Print the results in log order, as soon as they're available.
+        let mut stdout = std::io::stdout();
+        let mut pending = BTreeMap::new();
+        let mut current = 0;
+        for (i, result) in receiver.iter() {
+            pending.insert(i, result);
+            while let Some(result) = pending.remove(&current) {
+                print_matches(&mut stdout, &hashes[current], &result?)?;
+                current += 1;
+            }
+        }
+        for w in workers {
+            if w.join().is_err() {
+                bail!("A search thread panicked")
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Print the matches of a change, grouped by hunk.
+fn print_matches<W: Write>(mut w: W, hash: &Hash, matches: &[LineMatch]) -> std::io::Result<()> {
+    let hash = hash.to_base32();
+    let mut hunk = None;
+    for m in matches {
+        if hunk != Some(m.hunk) {
+            writeln!(w, "{} {}. {}", hash, m.hunk + 1, m.path)?;
+            hunk = Some(m.hunk)
+        }
+        writeln!(w, "    {} {}", if m.added { '+' } else { '-' }, m.line)?;
+    }
+    Ok(())
+}
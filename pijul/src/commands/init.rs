@@ -1,7 +1,13 @@
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
+use anyhow::bail;
+use chrono::Utc;
 use clap::Parser;
-use libpijul::MutTxnT;
+use libpijul::change::*;
+use libpijul::changestore::ChangeStore;
+use libpijul::{Base32, MutTxnT, MutTxnTExt, TxnTExt};
+use serde_derive::Deserialize;
 
 use crate::repository::*;
 
@@ -15,20 +21,226 @@ pub struct Init {
     /// Example: `pijul init --kind=rust`
     #[clap(long = "kind", short = 'k')]
     kind: Option<String>,
+    /// Initialize the repository from this template directory (defaults
+    /// to `template.init` in the global configuration, if any). A
+    /// template may contain an `ignore` file, a `config` file (copied
+    /// to `.pijul/config`, including its hooks), a `files` directory
+    /// copied into the working copy and recorded as the initial
+    /// change, and a `template.toml` file setting the `message` and
+    /// `description` of that change.
+    #[clap(long = "template")]
+    template: Option<PathBuf>,
     /// Path where the repository should be initalized
     path: Option<PathBuf>,
 }
 
+/// Name of the file setting the header of the initial change, in a
+/// template directory.
+const TEMPLATE_MANIFEST: &str = "template.toml";
+const TEMPLATE_IGNORE: &str = "ignore";
+const TEMPLATE_CONFIG: &str = "config";
+const TEMPLATE_FILES: &str = "files";
+
+#[derive(Debug, Default, Deserialize)]
+struct TemplateHeader {
+    message: Option<String>,
+    description: Option<String>,
+}
+
 impl Init {
     pub fn run(self) -> Result<(), anyhow::Error> {
-        let repo = Repository::init(self.path, self.kind.as_deref(), None)?;
+        let template = if let Some(t) = self.template {
+            Some(t)
+        } else if let Ok((cfg, _)) = crate::config::Global::load() {
+            cfg.template.and_then(|t| t.init)
+        } else {
+            None
+        };
+        if let Some(ref t) = template {
+            if !t.is_dir() {
+                bail!("Template {:?} is not a directory", t)
+            }
+        }
+        // Load the identity before creating anything, so that a
+        // missing key doesn't leave a half-initialized repository.
+        let identity = if let Some(ref t) = template {
+            if t.join(TEMPLATE_FILES).is_dir() || t.join(TEMPLATE_IGNORE).is_file() {
+                Some((initial_header(t)?, super::load_key()?.1))
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        let path = if let Some(path) = self.path {
+            path
+        } else {
+            std::env::current_dir()?
+        };
+        let repo = Repository::init(Some(path), self.kind.as_deref(), None)?;
         let mut txn = repo.pristine.mut_txn_begin()?;
         let channel_name = self
             .channel
             .unwrap_or_else(|| crate::DEFAULT_CHANNEL.to_string());
-        txn.open_or_create_channel(&channel_name)?;
+        let mut channel = txn.open_or_create_channel(&channel_name)?;
         txn.set_current_channel(&channel_name)?;
+
+        let template = if let Some(template) = template {
+            template
+        } else {
+            txn.commit()?;
+            return Ok(());
+        };
+
+        let config = template.join(TEMPLATE_CONFIG);
+        if config.is_file() {
+            std::fs::copy(&config, repo.path.join(libpijul::DOT_DIR).join("config"))?;
+        }
+        let mut added = Vec::new();
+        let ignore = template.join(TEMPLATE_IGNORE);
+        if ignore.is_file() {
+            merge_ignore(&ignore, &repo.path.join(".ignore"))?;
+            added.push(".ignore".to_string());
+        }
+        let files = template.join(TEMPLATE_FILES);
+        if files.is_dir() {
+            copy_template_files(&files, &repo.path, "", &mut added)?;
+        }
+        let (header, key) = if let Some(identity) = identity {
+            identity
+        } else {
+            txn.commit()?;
+            return Ok(());
+        };
+
+        for path in added.iter() {
+            if !txn.is_tracked(path)? {
+                txn.add_file(path, 0)?;
+            }
+        }
+        txn.apply_root_change_if_needed(&repo.changes, &channel, rand::thread_rng())?;
+        let txn = libpijul::ArcTxn::new(txn);
+        let recorded = libpijul::record_all_with_header(
+            &txn,
+            &channel,
+            &repo.working_copy,
+            &repo.changes,
+            "",
+            header,
+        )?;
+        if let Some((mut change, mut contents, updates)) = recorded {
+            let hash = repo.changes.save_change_with_contents(
+                &mut change,
+                &mut contents,
+                |change, hash| {
+                    change.unhashed = Some(serde_json::json!({
+                        "signature": key.sign_raw(&hash.to_bytes()).unwrap(),
+                    }));
+                    Ok::<_, anyhow::Error>(())
+                },
+            )?;
+            txn.write()
+                .apply_local_change(&mut channel, &change, &hash, &updates)?;
+            writeln!(std::io::stdout(), "Hash: {}", hash.to_base32())?;
+        }
         txn.commit()?;
         Ok(())
     }
 }
+
+/// The header of the initial change of a repository created from
+/// `template`, read from its `template.toml`.
+fn initial_header(template: &Path) -> Result<ChangeHeader, anyhow::Error> {
+    let manifest = template.join(TEMPLATE_MANIFEST);
+    let t: TemplateHeader = match std::fs::read_to_string(&manifest) {
+        Ok(s) => toml::from_str(&s)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => TemplateHeader::default(),
+        Err(e) => bail!("Could not read {:?}: {}", manifest, e),
+    };
+    let mut author = std::collections::BTreeMap::new();
+    if let Some(mut dir) = crate::config::global_config_dir() {
+        dir.push("publickey.json");
+        if let Ok(key) = std::fs::File::open(&dir) {
+            let k: libpijul::key::PublicKey = serde_json::from_reader(key)?;
+            author.insert("key".to_string(), k.key);
+        } else {
+            bail!("No identity configured yet. Please use `pijul key` to create one")
+        }
+    }
+    Ok(ChangeHeader {
+        message: t.message.unwrap_or_else(|| "Initial change".to_string()),
+        description: t.description,
+        authors: vec![Author(author)],
+        timestamp: Utc::now(),
+    })
+}
+
+/// Copy the files of `from` into `to`, without replacing existing
+/// files, and push their paths relative to the root of the template
+/// (prefixed by `prefix`) to `added`.
+fn copy_template_files(
+    from: &Path,
+    to: &Path,
+    prefix: &str,
+    added: &mut Vec<String>,
+) -> Result<(), anyhow::Error> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let name = if let Some(name) = entry.file_name().to_str() {
+            name.to_string()
+        } else {
+            bail!("Invalid file name in template: {:?}", entry.path())
+        };
+        let path = if prefix.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        let dest = to.join(&name);
+        if entry.file_type()?.is_dir() {
+            copy_template_files(&entry.path(), &dest, &path, added)?;
+        } else if name == ".ignore" {
+            merge_ignore(&entry.path(), &dest)?;
+            if !added.contains(&path) {
+                added.push(path);
+            }
+        } else if dest.exists() {
+            writeln!(
+                std::io::stderr(),
+                "Not replacing existing file {:?} with the template's",
+                dest
+            )?;
+        } else {
+            std::fs::copy(entry.path(), &dest)?;
+            added.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Append the lines of the ignore file `from` missing from `to`, so
+/// that the default entries written by `pijul init` (or an existing
+/// ignore file) are kept.
+fn merge_ignore(from: &Path, to: &Path) -> Result<(), anyhow::Error> {
+    let template = std::fs::read_to_string(from)?;
+    let existing = match std::fs::read_to_string(to) {
+        Ok(s) => s,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let mut f = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(to)?;
+    if !existing.is_empty() && !existing.ends_with('\n') {
+        writeln!(f)?;
+    }
+    for line in template.lines() {
+        if !line.trim().is_empty() && !existing.lines().any(|l| l == line) {
+            writeln!(f, "{}", line)?;
+        }
+    }
+    Ok(())
+}
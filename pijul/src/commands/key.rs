@@ -31,6 +31,28 @@ pub enum SubCommand {
         no_cert_check: bool,
         remote: String,
     },
+    /// Trust a public key: changes signed by it are accepted when the
+    /// `[trust]` policy of the global configuration is `warn` or
+    /// `reject`. This also signs the key with your own key, and
+    /// prints that signature so that others can import it.
+    Trust {
+        /// Trust level, `full` or `marginal`. Keys signed by fully
+        /// trusted keys are trusted marginally
+        #[clap(long = "level", default_value = "full")]
+        level: crate::trust::TrustLevel,
+        /// Instead of trusting the key directly, import this
+        /// signature of the key, made by the key given with `--by`
+        #[clap(long = "signature", requires = "by")]
+        signature: Option<String>,
+        /// The key that made the signature given with `--signature`
+        #[clap(long = "by", requires = "signature")]
+        by: Option<String>,
+        /// The public key, as in the `key` field of change authors
+        key: String,
+    },
+    /// Revoke the trust in a public key, including the trust coming
+    /// from signatures by other keys
+    Untrust { key: String },
 }
 
 impl Key {
@@ -112,7 +134,8 @@ impl Key {
                     } else {
                         bail!("No such remote: {}", remote)
                     }
-                } else if let Some(mut ssh) = crate::remote::ssh::ssh_remote(&remote, false) {
+                } else if let Some(mut ssh) = crate::remote::ssh::ssh_remote(&remote, false, None) {
+                    crate::offline::check(&remote)?;
                     if let Some(c) = ssh.connect(&remote, crate::DEFAULT_CHANNEL).await? {
                         c
                     } else {
@@ -125,6 +148,40 @@ impl Key {
                 remote.prove(key).await?;
             }
 
+            Some(SubCommand::Trust {
+                level,
+                signature,
+                by,
+                key,
+            }) => {
+                let mut store = crate::trust::TrustStore::load()?;
+                if let (Some(signature), Some(by)) = (signature, by) {
+                    store.add_signature(&key, &by, &signature)?;
+                } else {
+                    if level == crate::trust::TrustLevel::Never {
+                        bail!("Use `pijul key untrust` to stop trusting a key")
+                    }
+                    store.set_level(&key, level);
+                    if let Ok((_, sk)) = super::load_key() {
+                        let by = sk.public_key().key;
+                        let signature = sk.sign_raw(key.as_bytes())?;
+                        store.add_signature(&key, &by, &signature)?;
+                        writeln!(
+                            std::io::stdout(),
+                            "pijul key trust {} --by {} --signature {}",
+                            key,
+                            by,
+                            signature
+                        )?;
+                    }
+                }
+                store.save()?;
+            }
+            Some(SubCommand::Untrust { key }) => {
+                let mut store = crate::trust::TrustStore::load()?;
+                store.set_level(&key, crate::trust::TrustLevel::Never);
+                store.save()?;
+            }
             None => {
                 Self::command().write_long_help(&mut std::io::stdout())?;
             }
@@ -1,15 +1,17 @@
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::convert::TryFrom;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
 use crate::repository::Repository;
 use anyhow::bail;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
 use clap::Parser;
 use libpijul::changestore::*;
 use libpijul::pristine::{
-    sanakirja::Txn, ChannelRef, DepsTxnT, GraphTxnT, TreeErr, TreeTxnT, TxnErr,
+    sanakirja::Txn, CachedHeader, ChannelRef, ChannelTxnT, DepsTxnT, GraphTxnT, Position, TreeErr,
+    TreeTxnT, TxnErr,
 };
 use libpijul::{Base32, TxnT, TxnTExt};
 use log::*;
@@ -29,6 +31,11 @@ pub struct Log {
     /// Only show the change hashes
     #[clap(long = "hash-only")]
     hash_only: bool,
+    /// Show the first N characters of the change hashes, or more
+    /// if needed to keep them unambiguous. Defaults to the
+    /// `hash_length` of the configuration, or to full hashes
+    #[clap(long = "hash-length", value_name = "N")]
+    hash_length: Option<usize>,
     /// Include state identifiers in the output
     #[clap(long = "state")]
     states: bool,
@@ -43,6 +50,33 @@ pub struct Log {
     limit: Option<usize>,
     #[clap(long = "output-format")]
     output_format: Option<String>,
+    /// Include the paths of the files touched by each change
+    #[clap(long = "stat")]
+    stat: bool,
+    /// Include the hunks of each change. When paths are given, only
+    /// the hunks touching these paths are shown
+    #[clap(long = "patch")]
+    patch: bool,
+    /// Refresh the cached changelists of all the remotes of the
+    /// configuration file first, as `pijul fetch` does
+    #[clap(long = "prefetch")]
+    prefetch: bool,
+    /// Only show the changes by this author, given by their public key
+    /// or by a part of their name, login or email
+    #[clap(long = "author")]
+    author: Option<String>,
+    /// Only show the changes whose message matches this regular
+    /// expression. Only the first line of messages is searched
+    #[clap(long = "grep", value_name = "REGEX")]
+    grep: Option<regex::Regex>,
+    /// Only show the changes recorded at or after this date, given
+    /// as `YYYY-MM-DD`, in RFC 3339 format, or in seconds since the
+    /// Unix epoch
+    #[clap(long = "since", value_name = "DATE", parse(try_from_str = parse_date))]
+    since: Option<DateTime<Utc>>,
+    /// Only show the changes recorded at or before this date
+    #[clap(long = "until", value_name = "DATE", parse(try_from_str = parse_date))]
+    until: Option<DateTime<Utc>>,
     /// Filter log output, showing only log entries that touched the specified
     /// files. Accepted as a list of paths relative to your current directory.
     /// Currently, filters can only be applied when logging the channel that's
@@ -77,6 +111,11 @@ impl TryFrom<Log> for LogIterator {
         };
         let limit = cmd.limit.unwrap_or(std::usize::MAX);
         let offset = cmd.offset.unwrap_or(0);
+        let hash_len = if let Some(len) = super::hash_length(&repo, cmd.hash_length) {
+            Some(len.max(txn.hash_prefix_len(&*channel_ref.read())?))
+        } else {
+            None
+        };
 
         let mut id_path = repo.path.join(libpijul::DOT_DIR);
         id_path.push("identities");
@@ -96,6 +135,7 @@ impl TryFrom<Log> for LogIterator {
             channel_ref,
             limit,
             offset,
+            hash_len,
         })
     }
 }
@@ -113,6 +153,17 @@ pub enum Error<E: std::error::Error> {
     #[error(transparent)]
     Fs(#[from] libpijul::FsError<libpijul::pristine::sanakirja::Txn>),
     #[error(transparent)]
+    FsC(
+        #[from]
+        libpijul::fs::FsErrorC<
+            libpijul::changestore::layered::Error<
+                libpijul::changestore::filesystem::Error,
+                libpijul::changestore::filesystem::Error,
+            >,
+            Txn,
+        >,
+    ),
+    #[error(transparent)]
     Io(#[from] std::io::Error),
     #[error("pijul log couldn't assemble file prefix for pattern `{}`: {} was not a file in the repository at {}", pat, canon_path.display(), repo_path.display())]
     FilterPath {
@@ -125,7 +176,34 @@ pub enum Error<E: std::error::Error> {
     #[error(transparent)]
     E(E),
     #[error(transparent)]
-    Filesystem(#[from] libpijul::changestore::filesystem::Error),
+    File(
+        #[from]
+        libpijul::output::FileError<
+            libpijul::changestore::layered::Error<
+                libpijul::changestore::filesystem::Error,
+                libpijul::changestore::filesystem::Error,
+            >,
+            Txn,
+        >,
+    ),
+    #[error(transparent)]
+    Filesystem(
+        #[from]
+        libpijul::changestore::layered::Error<
+            libpijul::changestore::filesystem::Error,
+            libpijul::changestore::filesystem::Error,
+        >,
+    ),
+    #[error(transparent)]
+    Text(
+        #[from]
+        libpijul::change::TextSerError<
+            libpijul::changestore::layered::Error<
+                libpijul::changestore::filesystem::Error,
+                libpijul::changestore::filesystem::Error,
+            >,
+        >,
+    ),
 }
 
 // A lot of error-handling noise here, but since we're dealing with
@@ -133,26 +211,25 @@ pub enum Error<E: std::error::Error> {
 // probably necessary for the feedback to be good.
 fn get_inodes<E: std::error::Error>(
     txn: &Txn,
+    changes: &crate::repository::Changes,
+    channel: &ChannelRef<Txn>,
     repo_path: &Path,
     pats: &[String],
-) -> Result<
-    Vec<(
-        libpijul::Inode,
-        Option<libpijul::pristine::Position<libpijul::ChangeId>>,
-    )>,
-    Error<E>,
-> {
+) -> Result<Vec<Position<libpijul::ChangeId>>, Error<E>> {
     let mut inodes = Vec::new();
     for pat in pats {
-        let canon_path = match Path::new(pat).canonicalize() {
+        let (canon_path, exists) = match Path::new(pat).canonicalize() {
+            // Files that don't exist anymore can still be found by
+            // the names they had in the past.
             Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => {
-                return Err(Error::NotFound(pat.to_string()))
+                (std::env::current_dir()?.join(pat), false)
             }
             Err(e) => return Err(e.into()),
-            Ok(p) => p,
+            Ok(p) => (p, true),
         };
 
         match canon_path.strip_prefix(repo_path).map(|p| p.to_str()) {
+            Err(_) if !exists => return Err(Error::NotFound(pat.to_string())),
             // strip_prefix error is if repo_path is not a prefix of canon_path,
             // which would only happen if they pased in a filter path that's not
             // in the repository.
@@ -166,12 +243,26 @@ fn get_inodes<E: std::error::Error>(
             // PathBuf.to_str() returns none iff the path contains invalid UTF-8.
             Ok(None) => return Err(Error::InvalidUtf8(pat.to_string())),
             Ok(Some(s)) => {
-                let inode = libpijul::fs::find_inode(txn, s)?;
-                let inode_position = txn.get_inodes(&inode, None)?;
-                inodes.push((inode, inode_position.cloned()))
+                if exists {
+                    let inode = libpijul::fs::find_inode(txn, s)?;
+                    if let Some(p) = txn.get_inodes(&inode, None)? {
+                        inodes.push(*p)
+                    }
+                }
+                // The files that were at this path in the past.
+                match txn.path_inodes(changes, channel, s) {
+                    Ok(named) => inodes.extend(named),
+                    Err(libpijul::fs::FsErrorC::NotFound(_)) if exists => {}
+                    Err(libpijul::fs::FsErrorC::NotFound(_)) => {
+                        return Err(Error::NotFound(pat.to_string()))
+                    }
+                    Err(e) => return Err(e.into()),
+                }
             }
         };
     }
+    inodes.sort();
+    inodes.dedup();
     log::debug!("log filters: {:#?}\n", pats);
     Ok(inodes)
 }
@@ -198,6 +289,12 @@ enum LogEntry {
         message: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         description: Option<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        trailers: Vec<libpijul::change::Trailer>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        files: Option<Vec<String>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        patch: Option<String>,
     },
     Hash(libpijul::Hash),
 }
@@ -213,6 +310,9 @@ impl std::fmt::Display for LogEntry {
                 timestamp,
                 message,
                 description,
+                files,
+                patch,
+                ..
             } => {
                 if let Some(ref h) = hash {
                     writeln!(f, "Change {}", h)?;
@@ -244,6 +344,15 @@ impl std::fmt::Display for LogEntry {
                 if let Some(ref description) = description {
                     writeln!(f, "\n    {}\n", description)?;
                 }
+                if let Some(ref files) = files {
+                    for file in files.iter() {
+                        writeln!(f, "    {}", libpijul::path::escape(file))?;
+                    }
+                    writeln!(f)?;
+                }
+                if let Some(ref patch) = patch {
+                    writeln!(f, "{}", patch)?;
+                }
             }
             LogEntry::Hash(h) => {
                 writeln!(f, "{}", h.to_base32())?;
@@ -275,6 +384,9 @@ struct LogIterator {
     channel_ref: ChannelRef<Txn>,
     limit: usize,
     offset: usize,
+    /// The number of characters of the hashes shown, if they are
+    /// shortened.
+    hash_len: Option<usize>,
 }
 
 /// This implementation of Serialize is hand-rolled in order
@@ -323,21 +435,59 @@ impl LogIterator {
         let mut id_path = self.id_path.clone();
         let mut global_id_path = self.global_id_path.clone();
 
-        let inodes = get_inodes(&self.txn, &self.repo.path, &self.cmd.filters)?;
+        // The paths of the touched files are resolved together, so
+        // that each directory is only looked up once.
+        let mut paths = if self.cmd.stat {
+            Some(libpijul::fs::PathResolver::new(false))
+        } else {
+            None
+        };
+
+        let inodes = get_inodes(
+            &self.txn,
+            &self.repo.changes,
+            &self.channel_ref,
+            &self.repo.path,
+            &self.cmd.filters,
+        )?;
+        let patch_inodes = if self.cmd.patch {
+            Some(self.patch_inodes(&inodes)?)
+        } else {
+            None
+        };
         let mut offset = self.offset;
         let mut limit = self.limit;
         for pr in self.txn.reverse_log(&*self.channel_ref.read(), None)? {
-            let (_, (h, mrk)) = pr?;
+            let (_, (h, mrk)) = match pr {
+                Ok(x) => x,
+                Err(libpijul::LogError::UnknownChange { timestamp, change }) => {
+                    // Don't stop at a corrupt entry, the rest of the
+                    // log may still be readable.
+                    eprintln!(
+                        "Broken log entry at timestamp {}: change {:?} has no hash (see `pijul debug --fsck`)",
+                        timestamp, change
+                    );
+                    continue;
+                }
+                Err(libpijul::LogError::Txn(e)) => return Err(e.into()),
+            };
             let cid = self.txn.get_internal(h)?.unwrap();
             let mut is_in_filters = inodes.is_empty();
-            for (_, position) in inodes.iter() {
-                if let Some(position) = position {
-                    is_in_filters = self.txn.get_touched_files(position, Some(cid))? == Some(cid);
-                    if is_in_filters {
-                        break;
-                    }
+            for position in inodes.iter() {
+                is_in_filters = self.txn.get_touched_files(position, Some(cid))? == Some(cid);
+                if is_in_filters {
+                    break;
                 }
             }
+            if is_in_filters {
+                is_in_filters = self.header_matches(
+                    &mut authors,
+                    &mut id_path,
+                    &mut global_id_path,
+                    h.into(),
+                    cid,
+                )?;
+            }
             if is_in_filters {
                 if offset == 0 && limit > 0 {
                     // If there were no path filters applied, OR is this was one of the hashes
@@ -348,6 +498,9 @@ impl LogIterator {
                         &mut global_id_path,
                         h.into(),
                         Some(mrk.into()),
+                        cid,
+                        paths.as_mut(),
+                        patch_inodes.as_ref(),
                     )?;
                     f(entry).map_err(Error::E)?;
                     limit -= 1
@@ -362,6 +515,60 @@ impl LogIterator {
         Ok(())
     }
 
+    /// Whether the header of a change matches the `--author`,
+    /// `--grep`, `--since` and `--until` filters. This uses the
+    /// header cache of the pristine, and only loads the change if it
+    /// was applied before that cache existed.
+    fn header_matches<E: std::error::Error>(
+        &self,
+        author_kvs: &mut HashMap<String, String>,
+        id_path: &mut PathBuf,
+        global_id_path: &mut Option<PathBuf>,
+        h: libpijul::Hash,
+        cid: &libpijul::ChangeId,
+    ) -> Result<bool, Error<E>> {
+        let cmd = &self.cmd;
+        if cmd.author.is_none() && cmd.grep.is_none() && cmd.since.is_none() && cmd.until.is_none()
+        {
+            return Ok(true);
+        }
+        let header = if let Some(header) = self.txn.get_cached_header(cid)? {
+            header
+        } else {
+            CachedHeader::new(&self.repo.changes.get_header(&h.into())?)
+        };
+        if let Some(ref since) = cmd.since {
+            if header.timestamp < since.timestamp() {
+                return Ok(false);
+            }
+        }
+        if let Some(ref until) = cmd.until {
+            if header.timestamp > until.timestamp() {
+                return Ok(false);
+            }
+        }
+        if let Some(ref re) = cmd.grep {
+            if !re.is_match(&header.message) {
+                return Ok(false);
+            }
+        }
+        if let Some(ref author) = cmd.author {
+            for a in header.authors {
+                let alias = self.repo.config.author_aliases.get(&a);
+                if &a == author
+                    || alias
+                        .map(|al| al.contains(author.as_str()))
+                        .unwrap_or(false)
+                    || key_name(author_kvs, id_path, global_id_path, a).contains(author.as_str())
+                {
+                    return Ok(true);
+                }
+            }
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
     /// Create a [`LogEntry`] for a given hash.
     ///
     /// Most of this is just getting the right key information from either the cache
@@ -373,6 +580,9 @@ impl LogIterator {
         global_id_path: &mut Option<PathBuf>,
         h: libpijul::Hash,
         m: Option<libpijul::Merkle>,
+        cid: &libpijul::ChangeId,
+        paths: Option<&mut libpijul::fs::PathResolver>,
+        patch_inodes: Option<&HashSet<Position<libpijul::Hash>>>,
     ) -> Result<LogEntry, Error<E>> {
         if self.cmd.hash_only {
             return Ok(LogEntry::Hash(h));
@@ -382,72 +592,199 @@ impl LogIterator {
             .authors
             .into_iter()
             .map(|mut auth| {
-                let auth = if let Some(k) = auth.0.remove("key") {
-                    match author_kvs.entry(k) {
-                        Entry::Occupied(e) => e.into_mut(),
-                        Entry::Vacant(e) => {
-                            let mut id = None;
-                            id_path.push(e.key());
-                            if let Ok(f) = std::fs::File::open(&id_path) {
-                                if let Ok(id_) = serde_json::from_reader::<_, super::Identity>(f) {
-                                    id = Some(id_)
-                                }
-                            }
-                            id_path.pop();
-                            debug!("{:?} {:?}", global_id_path, id);
-                            if let Some(ref mut global_id_path) = global_id_path {
-                                if id.is_none() {
-                                    global_id_path.push(e.key());
-                                    debug!("{:?}", global_id_path);
-                                    if let Ok(f) = std::fs::File::open(&global_id_path) {
-                                        if let Ok(id_) = serde_json::from_reader(f) {
-                                            id = Some(id_)
-                                        } else {
-                                            debug!("wrong identity for {:?}", e.key());
-                                        }
-                                    }
-                                    global_id_path.pop();
-                                }
-                            }
-
-                            if let Some(id) = id {
-                                if let Some(ref name) = id.name {
-                                    if let Some(ref email) = id.email {
-                                        e.insert(format!("{} ({}) <{}>", name, id.login, email))
-                                    } else {
-                                        e.insert(format!("{} ({})", name, id.login))
-                                    }
-                                } else {
-                                    e.insert(id.login)
-                                }
-                            } else {
-                                let k = e.key().to_string();
-                                e.insert(k)
-                            }
-                        }
-                    }
+                if let Some(a) = self.repo.config.author_aliases.display_name(&auth) {
+                    a.to_owned()
+                } else if let Some(k) = auth.0.remove("key") {
+                    key_name(author_kvs, id_path, global_id_path, k).to_owned()
                 } else {
-                    auth.0.get("name").unwrap()
-                };
-                auth.to_owned()
+                    auth.0.get("name").unwrap().to_owned()
+                }
             })
             .collect();
+        let files = if let Some(paths) = paths {
+            Some(self.touched_paths(paths, cid)?)
+        } else {
+            None
+        };
+        let patch = if let Some(inodes) = patch_inodes {
+            Some(self.patch(&h, inodes)?)
+        } else {
+            None
+        };
         Ok(LogEntry::Full {
-            hash: Some(h.to_base32()),
+            hash: Some(super::short_hash(&h.to_base32(), self.hash_len).to_string()),
             state: m.map(|mm| mm.to_base32()).filter(|_| self.cmd.states),
             authors: Some(authors),
             timestamp: Some(header.timestamp),
             message: Some(header.message.clone()),
+            trailers: header.trailers(),
             description: header.description,
+            files,
+            patch,
         })
     }
+
+    /// The positions of the files and directories given as filters,
+    /// and of everything under these directories.
+    fn patch_inodes<E: std::error::Error>(
+        &self,
+        inodes: &[Position<libpijul::ChangeId>],
+    ) -> Result<HashSet<Position<libpijul::Hash>>, Error<E>> {
+        let channel = self.channel_ref.read();
+        let mut positions = HashSet::new();
+        for position in inodes.iter() {
+            positions.insert(*position);
+            for p in libpijul::fs::iter_graph_descendants(
+                &self.txn,
+                self.txn.graph(&*channel),
+                *position,
+            )? {
+                positions.insert(p?);
+            }
+        }
+        let mut result = HashSet::with_capacity(positions.len());
+        for p in positions {
+            result.insert(Position {
+                change: self.txn.get_external(&p.change)?.unwrap().into(),
+                pos: p.pos,
+            });
+        }
+        Ok(result)
+    }
+
+    /// The hunks of change `h` touching `inodes`, or all its hunks if
+    /// `inodes` is empty, in the text format. The hunks are streamed
+    /// from the change file, and only the contents of the kept hunks
+    /// are read.
+    fn patch<E: std::error::Error>(
+        &self,
+        h: &libpijul::Hash,
+        inodes: &HashSet<Position<libpijul::Hash>>,
+    ) -> Result<String, Error<E>> {
+        let mut hunks = Vec::new();
+        for (n, hunk) in self.repo.changes.iter_hunks(h)?.enumerate() {
+            let hunk = hunk?;
+            if inodes.is_empty() || crate::remote::hunk_touches_inodes(h, &hunk, inodes) {
+                hunks.push((n, hunk))
+            }
+        }
+        let mut patch = Vec::new();
+        libpijul::change::write_hunks(&self.repo.changes, h, &hunks, &mut patch)?;
+        Ok(String::from_utf8_lossy(&patch).into_owned())
+    }
+
+    /// The current paths of the files touched by change `cid`,
+    /// sorted.
+    fn touched_paths<E: std::error::Error>(
+        &self,
+        paths: &mut libpijul::fs::PathResolver,
+        cid: &libpijul::ChangeId,
+    ) -> Result<Vec<String>, Error<E>> {
+        let channel = self.channel_ref.read();
+        let mut touched = BTreeSet::new();
+        for x in self.txn.iter_rev_touched_files(cid, None)? {
+            let (p, pos) = x?;
+            if p > cid {
+                break;
+            } else if p < cid {
+                continue;
+            }
+            if let Some((path, _)) =
+                paths.find_path(&self.repo.changes, &self.txn, &*channel, *pos)?
+            {
+                if !path.is_empty() {
+                    touched.insert(path);
+                }
+            }
+        }
+        Ok(touched.into_iter().collect())
+    }
+}
+
+/// The name of the identity with public key `k`, or `k` itself if
+/// there is no such identity.
+fn key_name<'x>(
+    author_kvs: &'x mut HashMap<String, String>,
+    id_path: &mut PathBuf,
+    global_id_path: &mut Option<PathBuf>,
+    k: String,
+) -> &'x str {
+    match author_kvs.entry(k) {
+        Entry::Occupied(e) => e.into_mut(),
+        Entry::Vacant(e) => {
+            let mut id = None;
+            id_path.push(e.key());
+            if let Ok(f) = std::fs::File::open(&id_path) {
+                if let Ok(id_) = serde_json::from_reader::<_, super::Identity>(f) {
+                    id = Some(id_)
+                }
+            }
+            id_path.pop();
+            debug!("{:?} {:?}", global_id_path, id);
+            if let Some(ref mut global_id_path) = global_id_path {
+                if id.is_none() {
+                    global_id_path.push(e.key());
+                    debug!("{:?}", global_id_path);
+                    if let Ok(f) = std::fs::File::open(&global_id_path) {
+                        if let Ok(id_) = serde_json::from_reader(f) {
+                            id = Some(id_)
+                        } else {
+                            debug!("wrong identity for {:?}", e.key());
+                        }
+                    }
+                    global_id_path.pop();
+                }
+            }
+
+            if let Some(id) = id {
+                if let Some(ref name) = id.name {
+                    if let Some(ref email) = id.email {
+                        e.insert(format!("{} ({}) <{}>", name, id.login, email))
+                    } else {
+                        e.insert(format!("{} ({})", name, id.login))
+                    }
+                } else {
+                    e.insert(id.login)
+                }
+            } else {
+                let k = e.key().to_string();
+                e.insert(k)
+            }
+        }
+    }
+}
+
+/// Parse a date given as `YYYY-MM-DD` (at midnight UTC), in RFC 3339
+/// format, or as a number of seconds since the Unix epoch.
+fn parse_date(s: &str) -> Result<DateTime<Utc>, anyhow::Error> {
+    if let Ok(d) = DateTime::parse_from_rfc3339(s) {
+        Ok(d.with_timezone(&Utc))
+    } else if let Ok(d) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        Ok(DateTime::from_utc(d.and_hms(0, 0, 0), Utc))
+    } else if let Some(d) = s
+        .parse()
+        .ok()
+        .and_then(|t| NaiveDateTime::from_timestamp_opt(t, 0))
+    {
+        Ok(DateTime::from_utc(d, Utc))
+    } else {
+        bail!("Could not parse date {:?}", s)
+    }
 }
 
 impl Log {
     // In order to accommodate both pretty-printing and efficient
     // serialization to a serde target format, this now delegates
     // mostly to [`LogIterator`].
-    pub fn run(self) -> Result<(), anyhow::Error> {
+    pub async fn run(self) -> Result<(), anyhow::Error> {
+        if self.prefetch {
+            let repo = Repository::find_root(self.repo_path.clone())?;
+            let names = super::pushpull::all_remote_names(&repo)?;
+            let fetched = super::fetch::fetch(&repo, &names, crate::DEFAULT_CHANNEL, false).await;
+            if let Err(e) = fetched {
+                writeln!(std::io::stderr(), "Warning: {}", e)?;
+            }
+        }
         let log_iter = LogIterator::try_from(self)?;
         let mut stdout = std::io::stdout();
 
@@ -9,6 +9,11 @@ pub use clone::Clone;
 mod pushpull;
 pub use pushpull::*;
 
+mod fetch;
+pub use fetch::Fetch;
+
+mod remote_status;
+
 mod log;
 pub use self::log::Log;
 
@@ -21,6 +26,14 @@ pub use diff::Diff;
 mod change;
 pub use change::Change;
 
+mod render;
+
+mod deps;
+pub use deps::Deps;
+
+mod changelog;
+pub use changelog::Changelog;
+
 mod protocol;
 pub use protocol::Protocol;
 
@@ -47,6 +60,9 @@ pub use file_operations::*;
 mod apply;
 pub use apply::*;
 
+mod rollback;
+pub use rollback::Rollback;
+
 mod archive;
 pub use archive::*;
 
@@ -59,6 +75,21 @@ pub use tag::*;
 mod key;
 pub use key::*;
 
+mod config;
+pub use config::Config;
+
+mod grep;
+pub use grep::Grep;
+
+mod optimize;
+pub use optimize::Optimize;
+
+mod publish;
+pub use publish::Publish;
+
+mod bisect;
+pub use bisect::Bisect;
+
 // #[cfg(debug_assertions)]
 mod debug;
 // #[cfg(debug_assertions)]
@@ -73,7 +104,12 @@ fn pending<T: libpijul::MutTxnTExt + libpijul::TxnT + Send + Sync + 'static>(
 ) -> Result<Option<libpijul::Hash>, anyhow::Error> {
     use libpijul::changestore::ChangeStore;
 
+    if repo.config.bare {
+        return Ok(None);
+    }
     let mut builder = libpijul::record::Builder::new();
+    builder.separators = repo.config.separators()?;
+    builder.file_classes = repo.config.file_classes(&[])?;
     builder.record(
         txn.clone(),
         libpijul::Algorithm::default(),
@@ -83,7 +119,7 @@ fn pending<T: libpijul::MutTxnTExt + libpijul::TxnT + Send + Sync + 'static>(
         &repo.working_copy,
         &repo.changes,
         "",
-        num_cpus::get(),
+        crate::jobs::record(),
     )?;
     let recorded = builder.finish();
     if recorded.actions.is_empty() {
@@ -95,26 +131,30 @@ fn pending<T: libpijul::MutTxnTExt + libpijul::TxnT + Send + Sync + 'static>(
         .into_iter()
         .map(|rec| rec.globalize(&*txn).unwrap())
         .collect();
-    let contents = if let Ok(c) = std::sync::Arc::try_unwrap(recorded.contents) {
+    let mut contents = if let Ok(c) = std::sync::Arc::try_unwrap(recorded.contents) {
         c.into_inner()
     } else {
         unreachable!()
     };
+    // The contents are saved from the arena, without loading them.
     let mut pending_change = libpijul::change::Change::make_change(
         &*txn,
         channel,
         actions,
-        contents,
+        Vec::new(),
         libpijul::change::ChangeHeader::default(),
         Vec::new(),
     )?;
+    pending_change.contents_hash = contents.hash()?;
     let (dependencies, extra_known) =
         libpijul::change::dependencies(&*txn, &*channel.read(), pending_change.changes.iter())?;
     pending_change.dependencies = dependencies;
     pending_change.extra_known = extra_known;
     let hash = repo
         .changes
-        .save_change(&mut pending_change, |_, _| Ok::<_, anyhow::Error>(()))
+        .save_change_with_contents(&mut pending_change, &mut contents, |_, _| {
+            Ok::<_, anyhow::Error>(())
+        })
         .unwrap();
     txn.apply_local_change(channel, &pending_change, &hash, &recorded.updatables)?;
     Ok(Some(hash))
@@ -173,17 +213,83 @@ fn pager() -> bool {
 
 use crate::remote::CS;
 
-/// Make a "changelist", i.e. a list of patches that can be edited in
-/// a text editor.
-fn make_changelist<S: libpijul::changestore::ChangeStore>(
+/// Number of changes whose headers are loaded at once (in parallel)
+/// when making a changelist, bounding the memory used for very large
+/// changelists.
+const CHANGELIST_BATCH: usize = 256;
+
+/// The part of a changelist describing a single change or state.
+struct ChangelistEntry {
+    dependencies: Vec<libpijul::Hash>,
+    header: libpijul::change::ChangeHeader,
+}
+
+fn load_changelist_entry<S: libpijul::changestore::ChangeStore>(
+    changes: &S,
+    p: &CS,
+) -> Result<ChangelistEntry, anyhow::Error> {
+    Ok(match p {
+        CS::Change(p) => ChangelistEntry {
+            dependencies: changes.get_dependencies(p)?,
+            header: changes.get_header(p)?,
+        },
+        CS::State(p) => ChangelistEntry {
+            dependencies: Vec::new(),
+            header: changes.get_tag_header(p)?,
+        },
+    })
+}
+
+/// Load the entries of `batch`, in order, using at most `n_threads`
+/// threads.
+fn load_changelist_batch<S: libpijul::changestore::ChangeStore + Clone + Send + 'static>(
+    changes: &S,
+    batch: &[CS],
+    n_threads: usize,
+) -> Result<Vec<ChangelistEntry>, anyhow::Error> {
+    let chunk_size = (batch.len() + n_threads - 1) / n_threads;
+    if n_threads <= 1 || chunk_size == 0 {
+        return batch
+            .iter()
+            .map(|p| load_changelist_entry(changes, p))
+            .collect();
+    }
+    let threads: Vec<_> = batch
+        .chunks(chunk_size)
+        .map(|chunk| {
+            let changes = changes.clone();
+            let chunk = chunk.to_vec();
+            std::thread::spawn(move || {
+                chunk
+                    .iter()
+                    .map(|p| load_changelist_entry(&changes, p))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+        })
+        .collect();
+    let mut result = Vec::with_capacity(batch.len());
+    for t in threads {
+        result.extend(t.join().unwrap()?)
+    }
+    Ok(result)
+}
+
+/// Write a "changelist", i.e. a list of patches that can be edited in
+/// a text editor, to `v`. Headers are loaded in parallel, in batches
+/// of [`CHANGELIST_BATCH`], and written as soon as they are loaded.
+/// The hashes are shortened to `hash_len` characters if given.
+fn make_changelist<
+    S: libpijul::changestore::ChangeStore + Clone + Send + 'static,
+    W: std::io::Write,
+>(
     changes: &S,
     pullable: &[CS],
     verb: &str,
-) -> Result<Vec<u8>, anyhow::Error> {
+    hash_len: Option<usize>,
+    mut v: W,
+) -> Result<(), anyhow::Error> {
     use libpijul::Base32;
-    use std::io::Write;
 
-    let mut v = Vec::new();
     // TODO: This message should probably be customizable
     writeln!(
         v,
@@ -191,87 +297,172 @@ fn make_changelist<S: libpijul::changestore::ChangeStore>(
 # valid hash, and no other character (except possibly a newline), will
 # be {}ed.\n",
         verb, verb,
-    )
-    .unwrap();
+    )?;
+    let n_threads = crate::jobs::other().min(16);
     let mut first_p = true;
-    for p in pullable {
-        use ::log::*;
-        debug!("make_changelist {:?}", p);
-        if !first_p {
-            writeln!(v, "").unwrap();
-        }
-        first_p = false;
-        let header = match p {
-            CS::Change(p) => {
-                writeln!(v, "{}\n", p.to_base32()).unwrap();
-                let deps = changes.get_dependencies(&p)?;
-                if !deps.is_empty() {
-                    write!(v, "  Dependencies:").unwrap();
-                    for d in deps {
-                        write!(v, " {}", d.to_base32()).unwrap();
-                    }
-                    writeln!(v).unwrap();
+    for batch in pullable.chunks(CHANGELIST_BATCH) {
+        let entries = load_changelist_batch(changes, batch, n_threads)?;
+        for (p, entry) in batch.iter().zip(entries) {
+            use ::log::*;
+            debug!("make_changelist {:?}", p);
+            if !first_p {
+                writeln!(v, "")?;
+            }
+            first_p = false;
+            match p {
+                CS::Change(p) => writeln!(v, "{}\n", short_hash(&p.to_base32(), hash_len))?,
+                CS::State(p) => writeln!(v, "{}\n", short_hash(&p.to_base32(), hash_len))?,
+            }
+            if !entry.dependencies.is_empty() {
+                write!(v, "  Dependencies:")?;
+                for d in entry.dependencies {
+                    write!(v, " {}", short_hash(&d.to_base32(), hash_len))?;
                 }
-                changes.get_header(&p)?
+                writeln!(v)?;
             }
-            CS::State(p) => {
-                writeln!(v, "{}\n", p.to_base32()).unwrap();
-                changes.get_tag_header(&p)?
+            let header = entry.header;
+            write!(v, "  Author: [")?;
+            let mut first = true;
+            for a in header.authors.iter() {
+                if !first {
+                    write!(v, ", ")?;
+                }
+                first = false;
+                if let Some(s) = a.0.get("name") {
+                    write!(v, "{}", s)?
+                } else if let Some(k) = a.0.get("key") {
+                    write!(v, "{}", k)?
+                }
             }
-        };
-        write!(v, "  Author: [").unwrap();
-        let mut first = true;
-        for a in header.authors.iter() {
-            if !first {
-                write!(v, ", ").unwrap();
+            writeln!(v, "]")?;
+            writeln!(v, "  Date: {}", header.timestamp)?;
+            for t in header.trailers() {
+                writeln!(v, "  {}", t)?;
+            }
+            writeln!(v)?;
+            for l in header.message.lines() {
+                writeln!(v, "    {}", l)?;
             }
-            first = false;
-            if let Some(s) = a.0.get("name") {
-                write!(v, "{}", s).unwrap()
-            } else if let Some(k) = a.0.get("key") {
-                write!(v, "{}", k).unwrap()
+            if let Some(desc) = header.description_body() {
+                writeln!(v)?;
+                for l in desc.lines() {
+                    writeln!(v, "    {}", l)?;
+                }
             }
         }
-        writeln!(v, "]").unwrap();
-        writeln!(v, "  Date: {}\n", header.timestamp).unwrap();
-        for l in header.message.lines() {
-            writeln!(v, "    {}", l).unwrap();
+    }
+    Ok(())
+}
+
+/// Parses a list of hashes from `r`, in a single pass.
+/// Everything that is not a line consisting of a
+/// valid hash of `states`, or of a prefix of exactly one of them, and
+/// nothing else will be ignored. The
+/// selected hashes are returned in the order of `states`, regardless
+/// of the order of the lines and of duplicated lines.
+fn parse_changelist<R: std::io::BufRead>(
+    r: R,
+    states: &[CS],
+) -> Result<Vec<crate::remote::CS>, anyhow::Error> {
+    use libpijul::Base32;
+    let mut selected = std::collections::HashSet::with_capacity(states.len());
+    for l in r.split(b'\n') {
+        let l = l?;
+        let l = if let Some(l) = l.strip_suffix(b"\r") {
+            l
+        } else {
+            &l[..]
+        };
+        // Hashes and states have the same size, only one of them
+        // (if any) will be in `states`.
+        if let Some(h) = libpijul::Hash::from_base32(l) {
+            selected.insert(CS::Change(h));
+        }
+        if let Some(m) = libpijul::Merkle::from_base32(l) {
+            selected.insert(CS::State(m));
         }
-        if let Some(desc) = header.description {
-            writeln!(v).unwrap();
-            for l in desc.lines() {
-                writeln!(v, "    {}", l).unwrap();
+        if !l.is_empty() && l.iter().all(|c| matches!(c, b'A'..=b'Z' | b'2'..=b'7')) {
+            let mut matching = states.iter().filter(|s| {
+                let b32 = match s {
+                    CS::Change(h) => h.to_base32(),
+                    CS::State(m) => m.to_base32(),
+                };
+                b32.as_bytes().starts_with(l)
+            });
+            if let (Some(s), None) = (matching.next(), matching.next()) {
+                selected.insert(*s);
             }
         }
     }
-    Ok(v)
+    Ok(states
+        .iter()
+        .filter(|h| selected.contains(h))
+        .cloned()
+        .collect())
 }
 
-/// Parses a list of hashes from a slice of bytes.
-/// Everything that is not a line consisting of a
-/// valid hash and nothing else will be ignored.
-fn parse_changelist(o: &[u8], states: &[CS]) -> Vec<crate::remote::CS> {
+/// Write the changelist of `shown` to a temporary file, open it in
+/// the user's editor, and parse the result, keeping only the lines
+/// that are in `states`. The hashes are shortened to `hash_length`
+/// characters if given, or more if needed to tell the hashes of
+/// `states` apart.
+fn edit_changelist<S: libpijul::changestore::ChangeStore + Clone + Send + 'static>(
+    changes: &S,
+    shown: &[CS],
+    states: &[CS],
+    verb: &str,
+    hash_length: Option<usize>,
+) -> Result<Vec<CS>, anyhow::Error> {
     use libpijul::Base32;
-    let states: std::collections::HashSet<&CS> = states.iter().collect();
-    if let Ok(o) = std::str::from_utf8(o) {
-        o.lines()
-            .filter_map(|l| {
-                ::log::debug!(
-                    "l = {:?} {:?}",
-                    l,
-                    libpijul::Merkle::from_base32(l.as_bytes())
-                );
-                let h_ = libpijul::Hash::from_base32(l.as_bytes()).map(crate::remote::CS::Change);
-                if let Some(h) = h_ {
-                    if states.contains(&h) {
-                        return h_;
-                    }
-                }
-                libpijul::Merkle::from_base32(&l.as_bytes()[..]).map(crate::remote::CS::State)
+    let hash_len = hash_length.map(|len| {
+        let mut b32: Vec<_> = states
+            .iter()
+            .map(|s| match s {
+                CS::Change(h) => h.to_base32(),
+                CS::State(m) => m.to_base32(),
             })
-            .collect()
-    } else {
-        Vec::new()
+            .collect();
+        b32.sort_unstable();
+        libpijul::pristine::unambiguous_prefix_lens(&b32)
+            .into_iter()
+            .fold(len, std::cmp::max)
+    });
+    let path = std::env::temp_dir().join(format!(
+        "pijul-{}-{}-{}",
+        verb,
+        std::process::id(),
+        rand::random::<u32>()
+    ));
+    let result = (|| -> Result<Vec<CS>, anyhow::Error> {
+        let mut f = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        make_changelist(changes, shown, verb, hash_len, &mut f)?;
+        std::io::Write::flush(&mut f)?;
+        std::mem::drop(f);
+        edit::edit_file(&path)?;
+        parse_changelist(std::io::BufReader::new(std::fs::File::open(&path)?), states)
+    })();
+    std::fs::remove_file(&path).unwrap_or(());
+    result
+}
+
+/// The number of characters of the hashes shown to the user:
+/// `requested` if given, else the `hash_length` of the repository
+/// configuration or of the global configuration. `None` means full
+/// hashes.
+fn hash_length(repo: &crate::repository::Repository, requested: Option<usize>) -> Option<usize> {
+    requested.or(repo.config.hash_length).or_else(|| {
+        crate::config::Global::load()
+            .ok()
+            .and_then(|(global, _)| global.hash_length)
+    })
+}
+
+/// The first `len` characters of the base32 hash `h`, or `h` itself
+/// if `len` is `None`.
+fn short_hash(h: &str, len: Option<usize>) -> &str {
+    match len {
+        Some(len) if len < h.len() => &h[..len],
+        _ => h,
     }
 }
 
@@ -296,15 +487,21 @@ fn load_key() -> Result<(libpijul::key::SecretKey, libpijul::key::SKey), anyhow:
         dir.push("secretkey.json");
         if let Ok(key) = std::fs::File::open(&dir) {
             let k: libpijul::key::SecretKey = serde_json::from_reader(key)?;
-            let pass = if k.encryption.is_some() {
-                Some(rpassword::read_password_from_tty(Some(&format!(
-                    "Password for {:?}: ",
-                    dir
-                )))?)
-            } else {
-                None
-            };
-            let sk = k.load(pass.as_deref())?;
+            if k.encryption.is_none() {
+                let sk = k.load(None)?;
+                return Ok((k, sk));
+            }
+            let config = crate::config::Global::load()
+                .map(|(g, _)| g.key)
+                .unwrap_or_default();
+            if let Some(pass) = crate::keychain::get(&k, &config) {
+                let sk = k.load(Some(&pass))?;
+                return Ok((k, sk));
+            }
+            let pass =
+                rpassword::read_password_from_tty(Some(&format!("Password for {:?}: ", dir)))?;
+            let sk = k.load(Some(&pass))?;
+            crate::keychain::remember(&k, &config, &pass);
             Ok((k, sk))
         } else {
             bail!("Secret key not found, please use `pijul key generate` and try again")
@@ -354,40 +551,102 @@ fn find_hash<B: libpijul::Base32>(
 
 use libpijul::Conflict;
 fn print_conflicts(conflicts: &[Conflict]) -> Result<(), std::io::Error> {
+    print_conflicts_titled("There were conflicts:", conflicts)
+}
+
+fn print_conflicts_titled(title: &str, conflicts: &[Conflict]) -> Result<(), std::io::Error> {
     if conflicts.is_empty() {
         return Ok(());
     }
     let mut w = termcolor::StandardStream::stderr(termcolor::ColorChoice::Auto);
+    use libpijul::path::escape;
     use std::io::Write;
     use termcolor::*;
     w.set_color(ColorSpec::new().set_fg(Some(Color::Red)))?;
-    writeln!(w, "\nThere were conflicts:\n")?;
+    writeln!(w, "\n{}\n", title)?;
     w.set_color(ColorSpec::new().set_fg(None))?;
     for c in conflicts.iter() {
         match c {
-            Conflict::Name { ref path } => writeln!(w, "  - Name conflict on \"{}\"", path)?,
-            Conflict::ZombieFile { ref path } => {
-                writeln!(w, "  - Path deletion conflict \"{}\"", path)?
+            Conflict::Name { path } => writeln!(w, "  - Name conflict on \"{}\"", escape(path))?,
+            Conflict::ZombieFile { path } => {
+                writeln!(w, "  - Path deletion conflict \"{}\"", escape(path))?
             }
-            Conflict::MultipleNames { ref path, .. } => {
-                writeln!(w, "  - File has multiple names: \"{}\"", path)?
+            Conflict::MultipleNames { path, .. } => {
+                writeln!(w, "  - File has multiple names: \"{}\"", escape(path))?
             }
-            Conflict::Zombie { ref path, ref line } => writeln!(
+            Conflict::Zombie { path, line } => writeln!(
                 w,
                 "  - Deletion conflict in \"{}\" starting on line {}",
-                path, line
+                escape(path),
+                line
             )?,
-            Conflict::Cyclic { ref path, ref line } => writeln!(
+            Conflict::Cyclic { path, line } => writeln!(
                 w,
                 "  - Cycle conflict in \"{}\" starting on line {}",
-                path, line
+                escape(path),
+                line
             )?,
-            Conflict::Order { ref path, ref line } => writeln!(
+            Conflict::Order { path, line } => writeln!(
                 w,
                 "  - Order conflict in \"{}\" starting on line {}",
-                path, line
+                escape(path),
+                line
             )?,
         }
     }
     Ok(())
 }
+
+/// The exit code of `pijul apply` and `pijul pull` when given
+/// `--fail-on-conflict`, if there were conflicts.
+pub const CONFLICT_EXIT_CODE: i32 = 3;
+
+/// Returned by the commands given `--fail-on-conflict`, after the
+/// channel and working copy have been updated.
+#[derive(Debug, thiserror::Error)]
+#[error("There were {n} conflict(s)")]
+pub struct ConflictError {
+    pub n: usize,
+}
+
+#[derive(Serialize)]
+struct ConflictSummary<'a> {
+    path: &'a str,
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+}
+
+impl<'a> From<&'a Conflict> for ConflictSummary<'a> {
+    fn from(c: &'a Conflict) -> Self {
+        let (path, kind, line) = match c {
+            Conflict::Name { path } => (path, "name", None),
+            Conflict::ZombieFile { path } => (path, "zombie_file", None),
+            Conflict::MultipleNames { path, .. } => (path, "multiple_names", None),
+            Conflict::Zombie { path, line } => (path, "zombie", Some(*line)),
+            Conflict::Cyclic { path, line } => (path, "cyclic", Some(*line)),
+            Conflict::Order { path, line } => (path, "order", Some(*line)),
+        };
+        ConflictSummary { path, kind, line }
+    }
+}
+
+/// Report the conflicts produced by applying changes: as text on
+/// stderr, or as a JSON array on stdout if `json` is set (even if
+/// there are none). If `fail` is set and there are conflicts, return
+/// a [`ConflictError`].
+fn report_conflicts(conflicts: &[Conflict], json: bool, fail: bool) -> Result<(), anyhow::Error> {
+    use std::io::Write;
+    if json {
+        let summary: Vec<ConflictSummary> = conflicts.iter().map(|c| c.into()).collect();
+        let mut stdout = std::io::stdout();
+        serde_json::to_writer_pretty(&mut stdout, &summary)?;
+        writeln!(stdout)?;
+    } else {
+        print_conflicts(conflicts)?;
+    }
+    if fail && !conflicts.is_empty() {
+        return Err(ConflictError { n: conflicts.len() }.into());
+    }
+    Ok(())
+}
@@ -0,0 +1,249 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use clap::Parser;
+use libpijul::changestore::{ChangeStore, StoredHash};
+use libpijul::{Base32, MutTxnT, MutTxnTExt, TxnT};
+use log::debug;
+
+use crate::repository::{Repository, PRISTINE_DIR};
+
+#[derive(Parser, Debug)]
+pub struct Optimize {
+    #[clap(subcommand)]
+    subcmd: SubCommand,
+}
+
+#[derive(Parser, Debug)]
+pub enum SubCommand {
+    /// Rewrite the change files of the repository
+    #[clap(name = "changes")]
+    Changes {
+        /// Set the repository where this command should run. Defaults to
+        /// the first ancestor of the current directory that contains a
+        /// `.pijul` directory.
+        #[clap(long = "repository")]
+        repo_path: Option<PathBuf>,
+        /// Rewrite the changes in version 7 of the format, where the
+        /// contents are indexed, so that parts of them can be read
+        /// without decompressing everything. This doesn't change the
+        /// hashes of the changes, but versions of Pijul without support
+        /// for this format can't read them anymore.
+        #[clap(long = "reindex")]
+        reindex: bool,
+    },
+    /// Copy the pristine to a fresh file without its free pages, and
+    /// replace it if that makes it smaller. The pristine can't be used
+    /// by other commands in the meantime.
+    #[clap(name = "pristine")]
+    Pristine {
+        /// Set the repository where this command should run. Defaults to
+        /// the first ancestor of the current directory that contains a
+        /// `.pijul` directory.
+        #[clap(long = "repository")]
+        repo_path: Option<PathBuf>,
+    },
+    /// Delete the pseudo-edges of a channel that are implied by other
+    /// paths between alive lines, which accumulate after large merges
+    /// and slow down the traversals of the graph. This doesn't change
+    /// the contents of the channel.
+    #[clap(name = "graph")]
+    Graph {
+        /// Set the repository where this command should run. Defaults to
+        /// the first ancestor of the current directory that contains a
+        /// `.pijul` directory.
+        #[clap(long = "repository")]
+        repo_path: Option<PathBuf>,
+        /// Compact this channel instead of the current channel.
+        #[clap(long = "channel")]
+        channel: Option<String>,
+    },
+    /// List the cached changelists of the remotes with their sizes,
+    /// and drop the ones exceeding the bounds of the `[remote_cache]`
+    /// section of the configuration, or of the options. Dropped caches
+    /// are downloaded again the next time their remote is used.
+    #[clap(name = "remotes")]
+    Remotes {
+        /// Set the repository where this command should run. Defaults to
+        /// the first ancestor of the current directory that contains a
+        /// `.pijul` directory.
+        #[clap(long = "repository")]
+        repo_path: Option<PathBuf>,
+        /// Drop the caches of more than this many changes.
+        #[clap(long = "max-entries", value_name = "N")]
+        max_entries: Option<u64>,
+        /// Drop the caches of the remotes unused for more than this
+        /// many days.
+        #[clap(long = "max-age", value_name = "DAYS")]
+        max_age: Option<u64>,
+        /// Only show the caches that would be dropped.
+        #[clap(long = "dry-run")]
+        dry_run: bool,
+    },
+}
+
+impl Optimize {
+    pub fn run(self) -> Result<(), anyhow::Error> {
+        match self.subcmd {
+            SubCommand::Changes { repo_path, reindex } => {
+                if !reindex {
+                    bail!("Nothing to do, see `pijul optimize changes --help`")
+                }
+                let repo = Repository::find_root(repo_path)?;
+                let store = &repo.changes.top;
+                // Collect the hashes first, since the files are
+                // replaced while reindexing.
+                let mut hashes = Vec::new();
+                for e in store.iter_hashes()? {
+                    if let StoredHash::Change(h) = e?.hash {
+                        hashes.push(h)
+                    }
+                }
+                let mut stderr = std::io::stderr();
+                let mut reindexed = 0;
+                let mut failed = 0;
+                for h in hashes.iter() {
+                    debug!("reindexing {:?}", h);
+                    match store.reindex(h) {
+                        Ok(true) => reindexed += 1,
+                        Ok(false) => {}
+                        Err(e) => {
+                            writeln!(stderr, "Error reindexing {}: {}", h.to_base32(), e)?;
+                            failed += 1
+                        }
+                    }
+                }
+                writeln!(
+                    stderr,
+                    "Reindexed {} of {} change(s)",
+                    reindexed,
+                    hashes.len()
+                )?;
+                if failed > 0 {
+                    bail!("Could not reindex {} change(s)", failed)
+                }
+                Ok(())
+            }
+            SubCommand::Pristine { repo_path } => {
+                let repo = Repository::find_root(repo_path)?;
+                let path = repo
+                    .changes_dir
+                    .parent()
+                    .unwrap()
+                    .join(PRISTINE_DIR)
+                    .join("db");
+                let stats = repo.pristine.compact(&path)?;
+                let mut stderr = std::io::stderr();
+                if stats.swapped {
+                    writeln!(
+                        stderr,
+                        "Compacted the pristine from {} to {} bytes ({} page(s) reclaimed)",
+                        stats.size_before,
+                        stats.size_after,
+                        stats.reclaimed_pages()
+                    )?;
+                } else {
+                    writeln!(
+                        stderr,
+                        "The pristine ({} bytes) could not be made smaller",
+                        stats.size_before
+                    )?;
+                }
+                Ok(())
+            }
+            SubCommand::Graph { repo_path, channel } => {
+                let repo = Repository::find_root(repo_path)?;
+                let mut txn = repo.pristine.mut_txn_begin()?;
+                let channel_name = if let Some(c) = channel {
+                    c
+                } else {
+                    txn.current_channel()
+                        .unwrap_or(crate::DEFAULT_CHANNEL)
+                        .to_string()
+                };
+                let channel = if let Some(channel) = txn.load_channel(&channel_name)? {
+                    channel
+                } else {
+                    bail!("No such channel: {:?}", channel_name)
+                };
+                let stats = txn.compact_pseudo_edges(&channel)?;
+                txn.commit()?;
+                writeln!(
+                    std::io::stderr(),
+                    "Pseudo-edges of channel {} ({} files and directories): {} before, {} after ({} redundant, {} obsolete)",
+                    channel_name,
+                    stats.files,
+                    stats.before,
+                    stats.after,
+                    stats.redundant,
+                    stats.obsolete,
+                )?;
+                Ok(())
+            }
+            SubCommand::Remotes {
+                repo_path,
+                max_entries,
+                max_age,
+                dry_run,
+            } => {
+                let repo = Repository::find_root(repo_path)?;
+                let config = &repo.config.remote_cache;
+                let policy = libpijul::pristine::RemoteCachePolicy {
+                    max_entries: max_entries.or(config.max_entries),
+                    max_age: max_age.or(config.max_age_days).map(|d| d * 86400),
+                };
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::SystemTime::UNIX_EPOCH)?
+                    .as_secs();
+                let mut txn = repo.pristine.mut_txn_begin()?;
+                let caches =
+                    libpijul::pristine::expire_remote_caches(&mut txn, &policy, now, dry_run)?;
+                let mut stdout = std::io::stdout();
+                let mut dropped = 0;
+                let verb = if dry_run { "to drop" } else { "dropped" };
+                for c in caches.iter() {
+                    let last_used = chrono::NaiveDateTime::from_timestamp(c.last_used as i64, 0);
+                    write!(
+                        stdout,
+                        "{}: {}, {} change(s), {} tag(s), last used {}",
+                        c.id,
+                        c.path,
+                        c.size.entries,
+                        c.size.tags,
+                        last_used.format("%Y-%m-%d"),
+                    )?;
+                    match c.expired {
+                        Some(libpijul::pristine::CacheExpiry::TooLarge) => {
+                            writeln!(stdout, " ({}: too large)", verb)?
+                        }
+                        Some(libpijul::pristine::CacheExpiry::Unused) => {
+                            writeln!(stdout, " ({}: unused)", verb)?
+                        }
+                        None => writeln!(stdout)?,
+                    }
+                    if c.expired.is_some() {
+                        dropped += 1
+                    }
+                }
+                if dry_run {
+                    writeln!(
+                        std::io::stderr(),
+                        "Would drop {} of {} remote cache(s)",
+                        dropped,
+                        caches.len()
+                    )?;
+                } else {
+                    txn.commit()?;
+                    writeln!(
+                        std::io::stderr(),
+                        "Dropped {} of {} remote cache(s)",
+                        dropped,
+                        caches.len()
+                    )?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
@@ -25,18 +25,25 @@ pub struct Protocol {
 }
 
 lazy_static! {
-    static ref STATE: Regex = Regex::new(r#"state\s+(\S+)(\s+([0-9]+)?)\s+"#).unwrap();
-    static ref ID: Regex = Regex::new(r#"id\s+(\S+)\s+"#).unwrap();
-    static ref IDENTITIES: Regex = Regex::new(r#"identities(\s+([0-9]+))?\s+"#).unwrap();
-    static ref CHANGELIST: Regex = Regex::new(r#"changelist\s+(\S+)\s+([0-9]+)(.*)\s+"#).unwrap();
+    static ref STATE: Regex = Regex::new(r#"^state\s+(\S+)(\s+([0-9]+))?\s+$"#).unwrap();
+    static ref ID: Regex = Regex::new(r#"^id\s+(\S+)\s+$"#).unwrap();
+    static ref IDENTITIES: Regex =
+        Regex::new(r#"^identities(\s+([0-9]+))?((\s+[^\s]+)*)\s+$"#).unwrap();
+    static ref CHANGELIST: Regex = Regex::new(r#"^changelist\s+(\S+)\s+([0-9]+)(.*)\s+$"#).unwrap();
     static ref CHANGELIST_PATHS: Regex = Regex::new(r#""(((\\")|[^"])+)""#).unwrap();
-    static ref CHANGE: Regex = Regex::new(r#"((change)|(partial))\s+([^ ]*)\s+"#).unwrap();
-    static ref TAG: Regex = Regex::new(r#"^tag\s+(\S+)\s+"#).unwrap();
-    static ref TAGUP: Regex = Regex::new(r#"^tagup\s+(\S+)\s+(\S+)\s+([0-9]+)\s+"#).unwrap();
-    static ref APPLY: Regex = Regex::new(r#"apply\s+(\S+)\s+([^ ]*) ([0-9]+)\s+"#).unwrap();
-    static ref CHANNEL: Regex = Regex::new(r#"channel\s+(\S+)\s+"#).unwrap();
+    static ref CHANGE: Regex = Regex::new(r#"^((change)|(partial))\s+([^ ]*)\s+$"#).unwrap();
+    static ref TAG: Regex = Regex::new(r#"^tag\s+(\S+)\s+$"#).unwrap();
+    static ref TAGUP: Regex = Regex::new(r#"^tagup\s+(\S+)\s+(\S+)\s+([0-9]+)\s+$"#).unwrap();
+    static ref APPLY: Regex = Regex::new(r#"^apply\s+(\S+)\s+([^ ]*) ([0-9]+)\s+$"#).unwrap();
+    static ref STAGE: Regex = Regex::new(r#"^stage\s+(\S+)\s+([^ ]*) ([0-9]+)\s+$"#).unwrap();
+    static ref VALIDATE: Regex = Regex::new(r#"^validate\s+(\S+)\s+$"#).unwrap();
+    static ref CHANNEL: Regex = Regex::new(r#"^channel\s+(\S+)\s+$"#).unwrap();
+    static ref CHANNEL_META: Regex = Regex::new(r#"^channelmeta\s+(\S+)\s+$"#).unwrap();
+    static ref CHANNELS: Regex = Regex::new(r#"^channels\s+$"#).unwrap();
+    static ref CAPABILITIES: Regex = Regex::new(r#"^capabilities\s+$"#).unwrap();
+    static ref CLOSURE: Regex = Regex::new(r#"^closure\s+(\S+)\s+(\S+)\s+(\S+)\s+$"#).unwrap();
     static ref ARCHIVE: Regex =
-        Regex::new(r#"archive\s+(\S+)\s*(( ([^:]+))*)( :(.*))?\n"#).unwrap();
+        Regex::new(r#"^archive\s+(\S+)\s*(( ([^:]+))*)( :(.*))?\n$"#).unwrap();
 }
 
 fn load_channel<T: MutTxnTExt>(txn: &T, name: &str) -> Result<ChannelRef<T>, anyhow::Error> {
@@ -50,8 +57,8 @@ fn load_channel<T: MutTxnTExt>(txn: &T, name: &str) -> Result<ChannelRef<T>, any
 const PARTIAL_CHANGE_SIZE: u64 = 1 << 20;
 
 impl Protocol {
-    pub fn run(self) -> Result<(), anyhow::Error> {
-        let mut repo = Repository::find_root(self.repo_path)?;
+    pub async fn run(self) -> Result<(), anyhow::Error> {
+        let repo = Repository::find_root(self.repo_path)?;
         let txn = repo.pristine.arc_txn_begin()?;
         let mut ws = libpijul::ApplyWorkspace::new();
         let mut buf = String::new();
@@ -61,11 +68,61 @@ impl Protocol {
         let o = std::io::stdout();
         let mut o = BufWriter::new(o.lock());
         let mut applied = HashMap::new();
+        let mut staged: HashMap<String, crate::staging::Staging> = HashMap::new();
 
         debug!("reading");
         while s.read_line(&mut buf)? > 0 {
             debug!("{:?}", buf);
-            if let Some(cap) = ID.captures(&buf) {
+            if CAPABILITIES.is_match(&buf) {
+                // One line of JSON.
+                serde_json::to_writer(&mut o, &crate::remote::Capabilities::current())?;
+                writeln!(o)?;
+                o.flush()?;
+            } else if let Some(cap) = CLOSURE.captures(&buf) {
+                // The changes needed to go from the first state of
+                // the channel to the second one, in the format of
+                // `changelist`, followed by an empty line.
+                let txn = txn.read();
+                let channel = load_channel(&*txn, &cap[1])?;
+                let from = Merkle::from_base32(cap[2].as_bytes());
+                let to = Merkle::from_base32(cap[3].as_bytes());
+                let closure = if let (Some(from), Some(to)) = (from, to) {
+                    crate::remote::local::state_closure(&*txn, &channel, &from, &to)?
+                } else {
+                    None
+                };
+                if let Some(closure) = closure {
+                    for (n, h, m, tag) in closure {
+                        if tag {
+                            writeln!(o, "{}.{}.{}.", n, h.to_base32(), m.to_base32())?;
+                        } else {
+                            writeln!(o, "{}.{}.{}", n, h.to_base32(), m.to_base32())?;
+                        }
+                    }
+                } else {
+                    writeln!(o, "error:Unknown state")?;
+                }
+                writeln!(o)?;
+                o.flush()?;
+            } else if let Some(cap) = CHANNEL_META.captures(&buf) {
+                // One line of JSON, or an empty line if the channel
+                // has no metadata.
+                let txn = txn.read();
+                load_channel(&*txn, &cap[1])?;
+                if let Some(meta) = txn.channel_metadata(&cap[1])? {
+                    serde_json::to_writer(&mut o, &meta)?;
+                }
+                writeln!(o)?;
+                o.flush()?;
+            } else if CHANNELS.is_match(&buf) {
+                // One line per channel, "apply_counter last_modified
+                // state name", followed by an empty line.
+                for c in crate::remote::local::list_channels(&*txn.read())? {
+                    writeln!(o, "{}", c)?;
+                }
+                writeln!(o)?;
+                o.flush()?;
+            } else if let Some(cap) = ID.captures(&buf) {
                 let channel = load_channel(&*txn.read(), &cap[1])?;
                 let c = channel.read();
                 writeln!(o, "{}", c.id)?;
@@ -175,8 +232,7 @@ impl Protocol {
                 o.flush()?;
             } else if let Some(cap) = TAG.captures(&buf) {
                 if let Some(state) = Merkle::from_base32(cap[1].as_bytes()) {
-                    let mut tag_path = repo.changes_dir.clone();
-                    libpijul::changestore::filesystem::push_tag_filename(&mut tag_path, &state);
+                    let tag_path = repo.changes.tag_filename(&state);
                     let mut tag = libpijul::tag::OpenTagFile::open(&tag_path, &state)?;
                     let mut buf = Vec::new();
                     tag.short(&mut buf)?;
@@ -189,11 +245,10 @@ impl Protocol {
                     let channel = load_channel(&*txn.read(), &cap[2])?;
                     let m = libpijul::pristine::current_state(&*txn.read(), &*channel.read())?;
                     if m == state {
-                        let mut tag_path = repo.changes_dir.clone();
-                        libpijul::changestore::filesystem::push_tag_filename(&mut tag_path, &m);
-                        if std::fs::metadata(&tag_path).is_ok() {
+                        if std::fs::metadata(&repo.changes.tag_filename(&m)).is_ok() {
                             bail!("Tag for state {} already exists", m.to_base32());
                         }
+                        let tag_path = repo.changes.top.tag_filename(&m);
 
                         let last_t = if let Some(n) =
                             txn.read().reverse_log(&*channel.read(), None)?.next()
@@ -210,7 +265,11 @@ impl Protocol {
                         let mut buf = vec![0; size];
                         s.read_exact(&mut buf)?;
 
-                        let header = libpijul::tag::read_short(std::io::Cursor::new(&buf[..]), &m)?;
+                        let header = libpijul::tag::read_short(std::io::Cursor::new(&buf[..]), &m)
+                            .map_err(|error| crate::remote::UploadError::Tag {
+                                state: m.to_base32(),
+                                error,
+                            })?;
 
                         let temp_path = tag_path.with_extension("tmp");
 
@@ -233,10 +292,10 @@ impl Protocol {
                     debug!("protocol error: {:?}", buf);
                     bail!("Protocol error")
                 };
-                libpijul::changestore::filesystem::push_filename(&mut repo.changes_dir, &h);
-                debug!("repo = {:?}", repo.changes_dir);
-                let mut f = std::fs::File::open(&repo.changes_dir)?;
-                let size = std::fs::metadata(&repo.changes_dir)?.len();
+                let path = repo.changes.filename(&h);
+                debug!("change = {:?}", path);
+                let mut f = std::fs::File::open(&path)?;
+                let size = std::fs::metadata(&path)?.len();
                 let size = if &cap[1] == "change" || size <= PARTIAL_CHANGE_SIZE {
                     size
                 } else {
@@ -256,29 +315,82 @@ impl Protocol {
                     o.write_all(&buf2[..n])?;
                 }
                 o.flush()?;
-                libpijul::changestore::filesystem::pop_filename(&mut repo.changes_dir);
+            } else if let Some(cap) = STAGE.captures(&buf) {
+                let h = if let Some(h) = Hash::from_base32(cap[2].as_bytes()) {
+                    h
+                } else {
+                    debug!("protocol error {:?}", buf);
+                    bail!("Protocol error");
+                };
+                if !staged.contains_key(&cap[1]) {
+                    let staging = crate::staging::Staging::new(&repo.path.join(DOT_DIR))?;
+                    staged.insert(cap[1].to_string(), staging);
+                }
+                let staging = staged.get_mut(&cap[1]).unwrap();
+                let size: usize = cap[3].parse().unwrap();
+                buf2.resize(size, 0);
+                s.read_exact(&mut buf2)?;
+                if let Err(e) = crate::remote::verify_change(&h, &buf2) {
+                    writeln!(o, "error:{}", e)?;
+                    o.flush()?;
+                } else {
+                    std::fs::write(staging.path(&h), &buf2)?;
+                    staging.push(h);
+                }
+            } else if let Some(cap) = VALIDATE.captures(&buf) {
+                let mut verdicts = Vec::new();
+                if let Some(mut staging) = staged.remove(&cap[1]) {
+                    let channel = load_channel(&*txn.read(), &cap[1])?;
+                    verdicts = staging.validate(
+                        &repo.config.hooks.validate,
+                        &repo.changes_dir,
+                        &repo.changes,
+                        &mut *txn.write(),
+                        &channel,
+                        &mut ws,
+                    )?;
+                    for v in verdicts.iter().filter(|v| v.accepted) {
+                        let h = Hash::from_base32(v.hash.as_bytes()).unwrap();
+                        applied
+                            .entry(cap[1].to_string())
+                            .or_insert_with(|| (channel.clone(), Vec::new()))
+                            .1
+                            .push(h);
+                    }
+                }
+                // One line of JSON.
+                serde_json::to_writer(&mut o, &verdicts)?;
+                writeln!(o)?;
+                o.flush()?;
             } else if let Some(cap) = APPLY.captures(&buf) {
+                if !repo.config.hooks.validate.is_empty() {
+                    bail!("Changes pushed to this repository must be validated, but this version of Pijul can't push them for validation")
+                }
                 let h = if let Some(h) = Hash::from_base32(cap[2].as_bytes()) {
                     h
                 } else {
                     debug!("protocol error {:?}", buf);
                     bail!("Protocol error");
                 };
-                let mut path = repo.changes_dir.clone();
-                libpijul::changestore::filesystem::push_filename(&mut path, &h);
+                let path = repo.changes.top.filename(&h);
                 std::fs::create_dir_all(path.parent().unwrap())?;
                 let size: usize = cap[3].parse().unwrap();
                 buf2.resize(size, 0);
                 s.read_exact(&mut buf2)?;
+                // Check the change before it replaces any file.
+                crate::remote::verify_change(&h, &buf2)?;
                 std::fs::write(&path, &buf2)?;
-                libpijul::change::Change::deserialize(&path.to_string_lossy(), Some(&h))?;
                 let channel = load_channel(&*txn.read(), &cap[1])?;
                 {
                     let mut channel_ = channel.write();
                     txn.write()
                         .apply_change_ws(&repo.changes, &mut channel_, &h, &mut ws)?;
                 }
-                applied.insert(cap[1].to_string(), channel);
+                applied
+                    .entry(cap[1].to_string())
+                    .or_insert_with(|| (channel, Vec::new()))
+                    .1
+                    .push(h);
             } else if let Some(cap) = ARCHIVE.captures(&buf) {
                 let mut w = Vec::new();
                 let mut tarball = libpijul::output::Tarball::new(
@@ -332,6 +444,12 @@ impl Protocol {
                 } else {
                     0
                 };
+                // Only send the identities of these keys, if any are
+                // given.
+                let keys: HashSet<&str> = cap
+                    .get(3)
+                    .map(|k| k.as_str().split_whitespace().collect())
+                    .unwrap_or_default();
                 let mut id_dir = repo.path.clone();
                 id_dir.push(DOT_DIR);
                 id_dir.push("identities");
@@ -341,7 +459,7 @@ impl Protocol {
                     continue;
                 };
                 for id in r {
-                    output_id(id, last_touched, &mut o).unwrap_or(());
+                    output_id(id, last_touched, &keys, &mut o).unwrap_or(());
                 }
                 writeln!(o)?;
                 o.flush()?;
@@ -351,22 +469,39 @@ impl Protocol {
             buf.clear();
         }
         let applied_nonempty = !applied.is_empty();
-        for (_, channel) in applied {
+        for (_, (channel, _)) in applied.iter() {
+            if repo.config.bare {
+                continue;
+            }
+            let _lock = repo.lock_working_copy()?;
             libpijul::output::output_repository_no_pending(
                 &repo.working_copy,
                 &repo.changes,
                 &txn,
-                &channel,
+                channel,
                 "",
                 true,
                 None,
-                num_cpus::get(),
+                crate::jobs::output(),
                 0,
             )?;
         }
+        let mut summaries = Vec::new();
+        if crate::notify::is_configured(&repo) {
+            for (name, (channel, hashes)) in applied {
+                let state = txn.read().current_state(&*channel.read())?;
+                summaries.push(crate::notify::ApplySummary::new(
+                    &repo, &name, state, &hashes,
+                )?)
+            }
+        }
         if applied_nonempty {
             txn.commit()?;
         }
+        // Notify only once the changes are committed.
+        for summary in summaries.iter() {
+            crate::notify::dispatch(&repo, summary).await?
+        }
         Ok(())
     }
 }
@@ -386,6 +521,7 @@ fn get_public_key() -> Result<libpijul::key::PublicKey, anyhow::Error> {
 fn output_id<W: Write>(
     id: Result<std::fs::DirEntry, std::io::Error>,
     last_touched: u64,
+    keys: &HashSet<&str>,
     mut o: W,
 ) -> Result<(), anyhow::Error> {
     let id = id?;
@@ -405,6 +541,9 @@ fn output_id<W: Write>(
             } else {
                 return Ok(());
             };
+            if !keys.is_empty() && !keys.contains(public_key.key.as_str()) {
+                return Ok(());
+            }
             if !done.insert(public_key.key.clone()) {
                 return Ok(());
             }
@@ -426,6 +565,13 @@ fn output_id<W: Write>(
                 debug!("no global config");
             }
         } else {
+            if !keys.is_empty() {
+                // Identities are saved under the name of their key.
+                let name = p.file_name().and_then(|n| n.to_str()).unwrap_or("");
+                if !keys.contains(name) {
+                    return Ok(());
+                }
+            }
             let mut idf = if let Ok(f) = std::fs::File::open(&p) {
                 f
             } else {
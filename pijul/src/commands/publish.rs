@@ -0,0 +1,158 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::bail;
+use clap::Parser;
+use libpijul::changestore::filesystem::{push_filename, push_tag_filename};
+use libpijul::{Base32, ChannelTxnT, Hash, Merkle, TxnT, TxnTExt, DOT_DIR};
+use log::debug;
+
+use crate::remote::static_files::{
+    Manifest, PublishedChannel, CHANGELISTS_DIR, MANIFEST, STATIC_VERSION,
+};
+use crate::remote::CS;
+use crate::repository::{Repository, CHANGES_DIR};
+
+#[derive(Parser, Debug)]
+pub struct Publish {
+    /// Set the repository where this command should run. Defaults to the first ancestor of the current directory that contains a `.pijul` directory.
+    #[clap(long = "repository")]
+    repo_path: Option<PathBuf>,
+    /// Publish only this channel (all channels by default). Can be given several times.
+    #[clap(long = "channel")]
+    channel: Vec<String>,
+    /// Write the repository as static files to this directory, which any HTTP file server can then serve to `pijul clone` and `pijul pull`. Publishing again to the same directory only copies the new files.
+    #[clap(long = "static", value_name = "DIR")]
+    static_dir: PathBuf,
+}
+
+impl Publish {
+    pub fn run(self) -> Result<(), anyhow::Error> {
+        let repo = Repository::find_root(self.repo_path)?;
+        let txn = repo.pristine.txn_begin()?;
+        let channels = if self.channel.is_empty() {
+            txn.channels("")?
+        } else {
+            let mut channels = Vec::new();
+            for c in self.channel.iter() {
+                if let Some(c) = txn.load_channel(c)? {
+                    channels.push(c)
+                } else {
+                    bail!("No such channel: {:?}", c)
+                }
+            }
+            channels
+        };
+
+        let dot_dir = self.static_dir.join(DOT_DIR);
+        let manifest_path = dot_dir.join(MANIFEST);
+        // Keep the channels published before.
+        let mut manifest: Manifest = match std::fs::read(&manifest_path) {
+            Ok(m) => serde_json::from_slice(&m)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Manifest::default(),
+            Err(e) => return Err(e.into()),
+        };
+        if manifest.version > STATIC_VERSION {
+            bail!(
+                "{:?} was published by a newer version of Pijul",
+                self.static_dir
+            )
+        }
+        manifest.version = STATIC_VERSION;
+
+        let mut copied = 0;
+        for channel in channels {
+            let ch = channel.read();
+            let name = txn.name(&*ch).to_string();
+            if name.contains('/') {
+                bail!("Channel {:?} can't be published as static files", name)
+            }
+            debug!("publishing {:?}", name);
+            let mut changelist = Vec::new();
+            let mut len = 0;
+            let mut state = Merkle::zero();
+            for x in txn.log(&*ch, 0)? {
+                let (n, (h, m)) = x?;
+                let h: Hash = h.into();
+                let m: Merkle = m.into();
+                if copy_file(&repo, &dot_dir, &CS::Change(h))? {
+                    copied += 1
+                }
+                if txn.is_tagged(txn.tags(&*ch), n)? {
+                    if copy_file(&repo, &dot_dir, &CS::State(m))? {
+                        copied += 1
+                    }
+                    writeln!(changelist, "{}.{}.{}.", n, h.to_base32(), m.to_base32())?;
+                } else {
+                    writeln!(changelist, "{}.{}.{}", n, h.to_base32(), m.to_base32())?;
+                }
+                len += 1;
+                state = m
+            }
+            write_atomic(&dot_dir.join(CHANGELISTS_DIR).join(&name), &changelist)?;
+            manifest.channels.insert(
+                name.clone(),
+                PublishedChannel {
+                    id: txn.id(&*ch).map(|id| id.to_string()).unwrap_or_default(),
+                    state: state.to_base32(),
+                    len,
+                    metadata: txn.channel_metadata(&name)?,
+                },
+            );
+        }
+        // The manifest is written last, so that clients only see
+        // complete channels.
+        write_atomic(&manifest_path, &serde_json::to_vec_pretty(&manifest)?)?;
+        let mut stderr = std::io::stderr();
+        writeln!(
+            stderr,
+            "Published {} channel(s) to {:?} ({} new file(s))",
+            manifest.channels.len(),
+            self.static_dir,
+            copied
+        )?;
+        Ok(())
+    }
+}
+
+/// Copy the file of `c` to the `changes` directory in `dot_dir`,
+/// unless it is already there. Returns whether it was copied.
+fn copy_file(repo: &Repository, dot_dir: &Path, c: &CS) -> Result<bool, anyhow::Error> {
+    let mut to = dot_dir.join(CHANGES_DIR);
+    let mut from = match c {
+        CS::Change(h) => {
+            push_filename(&mut to, h);
+            repo.changes.top.filename(h)
+        }
+        CS::State(m) => {
+            push_tag_filename(&mut to, m);
+            repo.changes.top.tag_filename(m)
+        }
+    };
+    if std::fs::metadata(&to).is_ok() {
+        return Ok(false);
+    }
+    if std::fs::metadata(&from).is_err() {
+        if let Some(ref b) = repo.changes.bottom {
+            from = match c {
+                CS::Change(h) => b.filename(h),
+                CS::State(m) => b.tag_filename(m),
+            }
+        }
+    }
+    std::fs::create_dir_all(to.parent().unwrap())?;
+    if std::fs::hard_link(&from, &to).is_err() {
+        let tmp = to.with_extension("tmp");
+        std::fs::copy(&from, &tmp)?;
+        std::fs::rename(&tmp, &to)?;
+    }
+    Ok(true)
+}
+
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), anyhow::Error> {
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
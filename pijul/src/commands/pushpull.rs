@@ -2,7 +2,7 @@ use std::collections::{BTreeSet, HashSet};
 use std::io::Write;
 use std::path::PathBuf;
 
-use super::{make_changelist, parse_changelist};
+use super::edit_changelist;
 use anyhow::bail;
 use clap::Parser;
 use lazy_static::lazy_static;
@@ -31,10 +31,37 @@ pub enum SubRemote {
     /// Deletes the remote
     #[clap(name = "delete")]
     Delete { remote: String },
+    /// Show how many changes the current channel has that the remotes
+    /// of the configuration file don't have, and conversely,
+    /// according to their cached changelists
+    #[clap(name = "status")]
+    Status {
+        /// Compare the remotes with this channel instead of the current channel
+        #[clap(long = "channel")]
+        channel: Option<String>,
+        /// Refresh the cached changelists first, as `pijul fetch`
+        /// does, and list the changes of the channel unrecorded by the
+        /// remotes since they were cached
+        #[clap(long = "refresh")]
+        refresh: bool,
+        /// Refresh the changelists of this remote channel
+        #[clap(long = "from-channel")]
+        from_channel: Option<String>,
+        /// Do not check certificates (HTTPS remotes only, this option might be dangerous)
+        #[clap(short = 'k')]
+        no_cert_check: bool,
+        /// Output the status in JSON format
+        #[clap(long = "json")]
+        json: bool,
+        /// Only show these remotes (or the remotes of group
+        /// `@<group>`) instead of all the remotes of the
+        /// configuration file
+        remotes: Vec<String>,
+    },
 }
 
 impl Remote {
-    pub fn run(self) -> Result<(), anyhow::Error> {
+    pub async fn run(self) -> Result<(), anyhow::Error> {
         let repo = Repository::find_root(self.repo_path)?;
         debug!("{:?}", repo.config);
         let mut stdout = std::io::stdout();
@@ -60,6 +87,27 @@ impl Remote {
                     txn.commit()?;
                 }
             }
+            Some(SubRemote::Status {
+                channel,
+                refresh,
+                from_channel,
+                no_cert_check,
+                json,
+                remotes,
+            }) => {
+                super::remote_status::status(
+                    &repo,
+                    &remotes,
+                    super::remote_status::StatusOptions {
+                        channel: channel.as_deref(),
+                        refresh,
+                        from_channel: from_channel.as_deref().unwrap_or(crate::DEFAULT_CHANNEL),
+                        no_cert_check,
+                        json,
+                    },
+                )
+                .await?
+            }
         }
         Ok(())
     }
@@ -74,7 +122,7 @@ pub struct Push {
     #[clap(long = "from-channel")]
     from_channel: Option<String>,
     /// Push all changes
-    #[clap(long = "all", short = 'a', conflicts_with = "changes")]
+    #[clap(long = "all", short = 'a', conflicts_with_all = &["changes", "selected"])]
     all: bool,
     /// Force an update of the local remote cache. May effect some
     /// reporting of unrecords/concurrent changes in the remote.
@@ -88,12 +136,18 @@ pub struct Push {
     path: Vec<String>,
     /// Push to this remote
     to: Option<String>,
+    /// Push only these changes (and their unpushed dependencies). Each of them is a hash prefix, `HASH~deps` for a change and all its dependencies, or `FROM..TO` for the changes between two states of the channel (`TO` defaults to the current state).
+    #[clap(value_name = "CHANGES")]
+    selected: Vec<String>,
     /// Push to this remote channel instead of the remote's default channel
     #[clap(long = "to-channel")]
     to_channel: Option<String>,
-    /// Push only these changes
+    /// Push only these changes, with the same syntax as the positional arguments
     #[clap(last = true)]
     changes: Vec<String>,
+    /// What to do with the changes unrecorded from the remote that are still in the channel: push them again (`push`), unrecord them locally too (`unrecord`) or abort (`abort`). Asked interactively if omitted.
+    #[clap(long = "remote-unrecords", value_name = "ACTION", possible_values = &["push", "unrecord", "abort"])]
+    remote_unrecords: Option<String>,
 }
 
 #[derive(Parser, Debug)]
@@ -101,11 +155,15 @@ pub struct Pull {
     /// Set the repository where this command should run. Defaults to the first ancestor of the current directory that contains a `.pijul` directory.
     #[clap(long = "repository")]
     repo_path: Option<PathBuf>,
-    /// Pull into this channel instead of the current channel
+    /// Pull into this channel instead of the current channel. The
+    /// working copy is left untouched if this is not the current channel.
     #[clap(long = "to-channel")]
     to_channel: Option<String>,
+    /// Create the channel given by `--to-channel` if it doesn't exist
+    #[clap(long = "create-channel", requires = "to-channel")]
+    create_channel: bool,
     /// Pull all changes
-    #[clap(long = "all", short = 'a', conflicts_with = "changes")]
+    #[clap(long = "all", short = 'a', conflicts_with_all = &["changes", "selected"])]
     all: bool,
     /// Force an update of the local remote cache. May effect some
     /// reporting of unrecords/concurrent changes in the remote.
@@ -120,16 +178,71 @@ pub struct Pull {
     /// Only pull to these paths
     #[clap(long = "path")]
     path: Vec<String>,
-    /// Pull from this remote
+    /// Pull from all the remotes of the configuration file
+    #[clap(long = "all-remotes", conflicts_with = "from")]
+    all_remotes: bool,
+    /// Pull from this remote, or from all the remotes of group `@<group>`
     from: Option<String>,
+    /// Pull only these changes. Each of them is a hash prefix, `HASH~deps` for a change and all its dependencies, or `FROM..TO` for the changes between two states of the remote channel (`TO` defaults to its last state).
+    #[clap(value_name = "CHANGES")]
+    selected: Vec<String>,
     /// Pull from this remote channel
     #[clap(long = "from-channel")]
     from_channel: Option<String>,
+    /// Pull the changes needed to reach this state of the remote channel
+    #[clap(long = "state", conflicts_with_all = &["changes", "selected", "path"])]
+    state: Option<String>,
+    /// Only show the conflicts that pulling the selected changes would
+    /// create, without applying them
+    #[clap(long = "preview-conflicts")]
+    preview_conflicts: bool,
+    /// Exit with code 3 (after pulling) if there are conflicts in the working copy.
+    #[clap(long = "fail-on-conflict", conflicts_with = "preview-conflicts")]
+    fail_on_conflict: bool,
+    /// Print the conflicts in the working copy to the standard output as a JSON array of objects with fields `path`, `kind` and `line`.
+    #[clap(long = "conflicts-json", conflicts_with = "preview-conflicts")]
+    conflicts_json: bool,
+    /// Skip the changes that can't be applied (for example because they are corrupt) along with the changes depending on them, pull the others, and report the skipped changes.
+    #[clap(long = "continue-on-error", conflicts_with = "preview-conflicts")]
+    continue_on_error: bool,
     /// Pull changes from the local repository, not necessarily from a channel
     #[clap(last = true)]
     changes: Vec<String>, // For local changes only, can't be symmetric.
 }
 
+/// All the remotes of the configuration file, including the default
+/// remote, sorted by name.
+pub(super) fn all_remote_names(repo: &Repository) -> Result<Vec<String>, anyhow::Error> {
+    let mut names: Vec<String> = repo.config.remotes.keys().cloned().collect();
+    if let Some(ref def) = repo.config.default_remote {
+        if !names.contains(def) {
+            names.push(def.clone())
+        }
+    }
+    if names.is_empty() {
+        bail!("No remotes configured")
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// The members of group `<group>` if `name` is `@<group>`, or just
+/// `name`.
+pub(super) fn expand_remote_group(
+    repo: &Repository,
+    name: &str,
+) -> Result<Vec<String>, anyhow::Error> {
+    if let Some(group) = name.strip_prefix('@') {
+        match repo.config.remote_groups.get(group) {
+            Some(names) if !names.is_empty() => Ok(names.clone()),
+            Some(_) => bail!("Remote group {:?} is empty", group),
+            None => bail!("No such remote group: {:?}", group),
+        }
+    } else {
+        Ok(vec![name.to_string()])
+    }
+}
+
 lazy_static! {
     static ref CHANNEL: Regex = Regex::new(r#"([^:]*)(:(.*))?"#).unwrap();
 }
@@ -169,10 +282,17 @@ impl Push {
         }
     }
 
-    pub async fn run(self) -> Result<(), anyhow::Error> {
+    pub async fn run(mut self) -> Result<(), anyhow::Error> {
         let mut stderr = std::io::stderr();
-        let repo = Repository::find_root(self.repo_path.clone())?;
+        let mut repo = Repository::find_root(self.repo_path.clone())?;
         debug!("{:?}", repo.config);
+        if let Some(to) = self.to.take() {
+            if is_selection(&repo, &to) {
+                self.selected.insert(0, to)
+            } else {
+                self.to = Some(to)
+            }
+        }
         let txn = repo.pristine.arc_txn_begin()?;
         let cur = txn
             .read()
@@ -217,6 +337,11 @@ impl Push {
             .await?;
 
         let mut channel = txn.write().open_or_create_channel(&channel_name)?;
+        self.changes = expand_selection(
+            &*txn.read(),
+            Some(&channel),
+            self.selected.iter().chain(self.changes.iter()),
+        )?;
 
         let PushDelta {
             to_upload,
@@ -235,7 +360,35 @@ impl Push {
             return Ok(());
         }
 
-        notify_remote_unrecords(&repo, remote_unrecs.as_slice());
+        let to_upload = if remote_unrecs.is_empty() {
+            to_upload
+        } else {
+            let action = resolve_remote_unrecords(
+                &repo,
+                &*txn.read(),
+                &*channel.read(),
+                remote_unrecs.as_slice(),
+                self.remote_unrecords.as_deref(),
+            )?;
+            match action {
+                RemoteUnrecordAction::Repush => to_upload,
+                RemoteUnrecordAction::Abort => bail!("Push aborted"),
+                RemoteUnrecordAction::UnrecordLocally => {
+                    let is_current_channel = channel_name == cur.as_str();
+                    unrecord_locally(
+                        &mut repo,
+                        &txn,
+                        &channel,
+                        remote_unrecs.as_slice(),
+                        is_current_channel,
+                    )?;
+                    to_upload
+                        .into_iter()
+                        .filter(|c| remote_unrecs.iter().all(|(_, u)| u != c))
+                        .collect()
+                }
+            }
+        };
         notify_unknown_changes(unknown_changes.as_slice());
 
         let to_upload = if !self.changes.is_empty() {
@@ -281,19 +434,24 @@ impl Push {
                 bail!("Changes not found: {:?}", not_found)
             }
 
-            check_deps(&repo.changes, &to_upload, &u)?;
-            u
+            complete_selection(&*txn, &to_upload, u)?
         } else if self.all {
             to_upload
         } else {
-            let mut o = make_changelist(&repo.changes, &to_upload, "push")?;
+            let mut o = to_upload.clone();
             loop {
-                let d = parse_changelist(&edit::edit_bytes(&o[..])?, &to_upload);
+                let d = edit_changelist(
+                    &repo.changes,
+                    &o,
+                    &to_upload,
+                    "push",
+                    super::hash_length(&repo, None),
+                )?;
                 let comp = complete_deps(&repo.changes, &to_upload, &d)?;
                 if comp.len() == d.len() {
                     break comp;
                 }
-                o = make_changelist(&repo.changes, &comp, "push")?
+                o = comp
             }
         };
         debug!("to_upload = {:?}", to_upload);
@@ -304,6 +462,7 @@ impl Push {
             return Ok(());
         }
 
+        repo.link_from_reference(&to_upload)?;
         remote
             .upload_changes(
                 &mut *txn.write(),
@@ -334,17 +493,36 @@ impl Pull {
         } else {
             None
         };
-        let delta = remote
-            .update_changelist_pushpull(
-                txn,
-                &self.path,
-                channel,
-                force_cache,
-                repo,
-                self.changes.as_slice(),
-                true,
-            )
-            .await?;
+        let local_channel = if let RemoteRepo::LocalChannel(ref c) = remote {
+            Some(c.clone())
+        } else {
+            None
+        };
+        let delta = if let Some(ref state) = self.state {
+            self.state_delta(txn, channel, repo, remote, state, force_cache)
+                .await?
+        } else if self.changes.is_empty() || local_channel.is_some() {
+            // The changes of a local channel are in our pristine.
+            let changes = if let Some(ref c) = local_channel {
+                expand_selection(&*txn, txn.load_channel(c)?.as_ref(), self.changes.iter())?
+            } else {
+                Vec::new()
+            };
+            remote
+                .update_changelist_pushpull(
+                    txn,
+                    &self.path,
+                    channel,
+                    force_cache,
+                    repo,
+                    &changes,
+                    true,
+                )
+                .await?
+        } else {
+            self.selection_delta(txn, channel, repo, remote, force_cache)
+                .await?
+        };
         let to_download = remote
             .pull(
                 repo,
@@ -362,63 +540,257 @@ impl Pull {
         })
     }
 
-    pub async fn run(self) -> Result<(), anyhow::Error> {
+    /// The changes selected on the command line, resolved against the
+    /// changelist of `remote`. The dependencies of the `HASH~deps`
+    /// selections are only known after downloading them, so they are
+    /// downloaded until none is missing.
+    async fn selection_delta(
+        &self,
+        txn: &mut MutTxn<()>,
+        channel: &mut ChannelRef<MutTxn<()>>,
+        repo: &mut Repository,
+        remote: &mut RemoteRepo,
+        force_cache: Option<bool>,
+    ) -> Result<RemoteDelta<MutTxn<()>>, anyhow::Error> {
+        let mut delta = remote
+            .update_changelist_pushpull(txn, &self.path, channel, force_cache, repo, &[], true)
+            .await?;
+        let list = remote_changelist(txn, &delta)?;
+        let (selected, mut frontier) = remote_selection(&list, &self.changes)?;
+        let mut deps = HashSet::new();
+        while !frontier.is_empty() {
+            let hashes: Vec<_> = frontier.iter().map(|h| CS::Change(*h)).collect();
+            remote
+                .pull(repo, txn, channel, &hashes, &HashSet::new(), false)
+                .await?;
+            let mut next = Vec::new();
+            for h in frontier {
+                for d in repo.changes.get_dependencies(&h)? {
+                    if txn.get_revchanges(channel, &d)?.is_none() && deps.insert(d) {
+                        next.push(d)
+                    }
+                }
+            }
+            frontier = next
+        }
+        // The dependencies come first, in the order of the remote.
+        let mut deps: Vec<_> = deps.into_iter().collect();
+        deps.sort_by_key(|d| list.iter().position(|(h, _)| h == d));
+        let mut seen = HashSet::new();
+        let mut to_download = Vec::new();
+        for c in deps.into_iter().map(CS::Change).chain(selected.into_iter()) {
+            if let CS::Change(ref h) = c {
+                if txn.get_revchanges(channel, h)?.is_some() {
+                    continue;
+                }
+            }
+            if seen.insert(c) {
+                to_download.push(c)
+            }
+        }
+        delta.to_download = to_download;
+        Ok(delta)
+    }
+
+    /// The changes needed to reach `state` of the remote channel.
+    /// Smart remotes compute them from the current state of
+    /// `channel`. Else, the changelist is downloaded and cut after
+    /// `state`.
+    async fn state_delta(
+        &self,
+        txn: &mut MutTxn<()>,
+        channel: &mut ChannelRef<MutTxn<()>>,
+        repo: &mut Repository,
+        remote: &mut RemoteRepo,
+        state: &str,
+        force_cache: Option<bool>,
+    ) -> Result<RemoteDelta<MutTxn<()>>, anyhow::Error> {
+        let state = if let Some(state) = Merkle::from_base32(state.as_bytes()) {
+            state
+        } else {
+            bail!("Invalid state: {:?}", state)
+        };
+        let from = txn.current_state(&*channel.read())?;
+        if let Some(to_download) = remote.state_closure(txn, &from, &state).await? {
+            debug!("closure computed by the remote: {:?}", to_download);
+            return Ok(RemoteDelta {
+                inodes: HashSet::new(),
+                to_download,
+                remote_ref: None,
+                ours_ge_dichotomy_set: HashSet::new(),
+                theirs_ge_dichotomy_set: HashSet::new(),
+                theirs_ge_dichotomy: Vec::new(),
+                remote_unrecs: Vec::new(),
+            });
+        }
+        let mut delta = remote
+            .update_changelist_pushpull(txn, &[], channel, force_cache, repo, &[], true)
+            .await?;
+        let mut reachable = HashSet::new();
+        let mut found = false;
+        if let Some(ref r) = delta.remote_ref {
+            for x in txn.iter_remote(&r.lock().remote, 0)? {
+                let (_, p) = x?;
+                reachable.insert(CS::Change(p.a.into()));
+                if p.b == state {
+                    found = true;
+                    break;
+                }
+            }
+        }
+        if !found {
+            bail!("State not found in the remote: {}", state.to_base32())
+        }
+        delta.to_download.retain(|c| reachable.contains(c));
+        Ok(delta)
+    }
+
+    /// The remotes to pull from, in the order in which their
+    /// changelists are merged: all the remotes of the configuration
+    /// file (sorted by name) with `--all-remotes`, the members of a
+    /// group for `@group`, or a single remote.
+    fn remote_names(&self, repo: &Repository) -> Result<Vec<String>, anyhow::Error> {
+        if self.all_remotes {
+            return all_remote_names(repo);
+        }
+        let remote_name = if let Some(ref rem) = self.from {
+            rem
+        } else if let Some(ref def) = repo.config.default_remote {
+            def
+        } else {
+            bail!("Missing remote")
+        };
+        expand_remote_group(repo, remote_name)
+    }
+
+    /// The channel to pull into. The current channel is created if
+    /// it doesn't exist yet (as in a new repository), other channels
+    /// only with `--create-channel`.
+    fn target_channel(
+        &self,
+        txn: &ArcTxn<MutTxn<()>>,
+        channel_name: &str,
+        is_current_channel: bool,
+    ) -> Result<ChannelRef<MutTxn<()>>, anyhow::Error> {
+        if let Some(channel) = txn.read().load_channel(channel_name)? {
+            return Ok(channel);
+        }
+        if is_current_channel || self.create_channel {
+            Ok(txn.write().open_or_create_channel(channel_name)?)
+        } else {
+            bail!(
+                "No such channel: {:?} (use --create-channel to create it)",
+                channel_name
+            )
+        }
+    }
+
+    pub async fn run(mut self) -> Result<(), anyhow::Error> {
         let mut repo = Repository::find_root(self.repo_path.clone())?;
-        let txn = repo.pristine.arc_txn_begin()?;
-        let cur = txn
-            .read()
+        if let Some(from) = self.from.take() {
+            if is_selection(&repo, &from) {
+                self.selected.insert(0, from)
+            } else {
+                self.from = Some(from)
+            }
+        }
+        let cur = repo
+            .pristine
+            .txn_begin()?
             .current_channel()
             .unwrap_or(crate::DEFAULT_CHANNEL)
             .to_string();
         let channel_name = if let Some(ref c) = self.to_channel {
-            c
+            c.clone()
         } else {
-            cur.as_str()
+            cur.clone()
         };
         let is_current_channel = channel_name == cur;
-        let mut channel = txn.write().open_or_create_channel(&channel_name)?;
-        debug!("{:?}", repo.config);
-        let remote_name = if let Some(ref rem) = self.from {
-            rem
-        } else if let Some(ref def) = repo.config.default_remote {
-            def
+        // Pulling into another channel doesn't touch the working copy.
+        let _lock = if is_current_channel {
+            Some(repo.lock_working_copy()?)
         } else {
-            bail!("Missing remote")
+            None
         };
+        let txn = repo.pristine.arc_txn_begin()?;
+        let mut channel = self.target_channel(&txn, &channel_name, is_current_channel)?;
+        debug!("{:?}", repo.config);
+        let remote_names = self.remote_names(&repo)?;
         let from_channel = if let Some(ref c) = self.from_channel {
             c
         } else {
             crate::DEFAULT_CHANNEL
         };
-        let mut remote = repo
-            .remote(
-                Some(&repo.path),
-                &remote_name,
-                from_channel,
-                Direction::Pull,
-                self.no_cert_check,
-                true,
-            )
-            .await?;
-        debug!("downloading");
+        // The selection is resolved against the changelist of each
+        // remote, in `to_download`.
+        let mut changes = std::mem::take(&mut self.selected);
+        changes.append(&mut self.changes);
+        self.changes = changes;
 
-        let RemoteDelta {
-            inodes,
-            remote_ref,
-            mut to_download,
-            remote_unrecs,
-            ..
-        } = self
-            .to_download(&mut *txn.write(), &mut channel, &mut repo, &mut remote)
-            .await?;
+        // Union of the changelists of all remotes, in the order of
+        // `remote_names`. Each change is downloaded from (and
+        // completed by) the first remote that has it.
+        let mut remotes = Vec::with_capacity(remote_names.len());
+        let mut to_download = Vec::new();
+        let mut seen = HashSet::new();
+        let mut inodes = HashSet::new();
+        let mut all_inodes = false;
+        for remote_name in remote_names.iter() {
+            let mut remote = repo
+                .remote(
+                    Some(&repo.path),
+                    &remote_name,
+                    from_channel,
+                    Direction::Pull,
+                    self.no_cert_check,
+                    true,
+                )
+                .await?;
+            debug!("downloading from {:?}", remote_name);
 
-        let hash = super::pending(txn.clone(), &mut channel, &mut repo)?;
+            let RemoteDelta {
+                inodes: remote_inodes,
+                remote_ref,
+                to_download: remote_to_download,
+                remote_unrecs,
+                ..
+            } = self
+                .to_download(&mut *txn.write(), &mut channel, &mut repo, &mut remote)
+                .await?;
 
-        if let Some(ref r) = remote_ref {
-            remote.update_identities(&mut repo, r).await?;
+            if let Some(ref r) = remote_ref {
+                remote
+                    .update_identities(&mut repo, r, &remote_to_download)
+                    .await?;
+            }
+
+            notify_remote_unrecords(&repo, remote_unrecs.as_slice());
+
+            if remote_inodes.is_empty() {
+                all_inodes = true
+            } else {
+                inodes.extend(remote_inodes.into_iter())
+            }
+            let mut own = Vec::new();
+            for h in remote_to_download {
+                if seen.insert(h) {
+                    to_download.push(h);
+                    own.push(h);
+                }
+            }
+            remotes.push((remote, own));
+        }
+        if all_inodes {
+            inodes.clear()
         }
 
-        notify_remote_unrecords(&repo, remote_unrecs.as_slice());
+        // The pending change is only relevant to the channel of the
+        // working copy.
+        let hash = if is_current_channel {
+            super::pending(txn.clone(), &mut channel, &mut repo)?
+        } else {
+            None
+        };
 
         if to_download.is_empty() {
             let mut stderr = std::io::stderr();
@@ -431,17 +803,70 @@ impl Pull {
         }
 
         if !self.all && self.changes.is_empty() {
-            let mut o = make_changelist(&repo.changes, &to_download, "pull")?;
+            let mut o = to_download.clone();
             to_download = loop {
-                let d = parse_changelist(&edit::edit_bytes(&o[..])?, &to_download);
+                let d = edit_changelist(
+                    &repo.changes,
+                    &o,
+                    &to_download,
+                    "pull",
+                    super::hash_length(&repo, None),
+                )?;
                 let comp = complete_deps(&repo.changes, &to_download, &d)?;
                 if comp.len() == d.len() {
                     break comp;
                 }
-                o = make_changelist(&repo.changes, &comp, "pull")?
+                o = comp
             };
         }
 
+        if self.preview_conflicts {
+            let hashes: Vec<_> = to_download
+                .iter()
+                .filter_map(|h| {
+                    if let CS::Change(h) = h {
+                        Some(*h)
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+            let conflicts = libpijul::preview_conflicts(&txn, &channel, &repo.changes, &hashes)?;
+            if conflicts.is_empty() {
+                writeln!(
+                    std::io::stderr(),
+                    "Pulling these changes would not create any conflict"
+                )?;
+            } else {
+                super::print_conflicts_titled(
+                    "Pulling these changes would create conflicts:",
+                    &conflicts.into_iter().collect::<Vec<_>>(),
+                )?;
+            }
+            for (mut remote, _) in remotes {
+                remote.finish().await?;
+            }
+            if let Some(h) = hash {
+                repo.changes.del_change(&h)?;
+            }
+            // `txn` is dropped without being committed, leaving the
+            // channel and the working copy untouched.
+            return Ok(());
+        }
+
+        let hashes: Vec<_> = to_download
+            .iter()
+            .filter_map(|h| {
+                if let CS::Change(h) = h {
+                    Some(*h)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        crate::trust::check_changes(&repo.changes, &hashes)?;
+
+        let mut failures = Vec::new();
         {
             // Now that .pull is always given `false` for `do_apply`...
             let mut ws = libpijul::ApplyWorkspace::new();
@@ -453,14 +878,24 @@ impl Pull {
                 pre: "Applying".into(),
             });
             std::mem::drop(pro);
-            let mut channel = channel.write();
             let mut txn = txn.write();
             for h in to_download.iter() {
                 match h {
+                    CS::Change(h) if self.continue_on_error => {
+                        super::apply::apply_or_skip(
+                            &repo.changes,
+                            &mut *txn,
+                            &channel,
+                            h,
+                            &mut ws,
+                            &mut failures,
+                        )?;
+                    }
                     CS::Change(h) => {
-                        txn.apply_change_rec_ws(&repo.changes, &mut channel, h, &mut ws)?;
+                        txn.apply_change_rec_ws(&repo.changes, &mut *channel.write(), h, &mut ws)?;
                     }
                     CS::State(s) => {
+                        let mut channel = channel.write();
                         if let Some(n) = txn.channel_has_state(&channel.states, &s.into())? {
                             txn.put_tags(&mut channel.tags, n.into(), s)?;
                         } else {
@@ -475,12 +910,41 @@ impl Pull {
                 PROGRESS.borrow_mut().unwrap()[n].incr()
             }
         }
+        if !failures.is_empty() {
+            let skipped: HashSet<_> = failures.iter().map(|f| CS::Change(f.hash)).collect();
+            to_download.retain(|h| !skipped.contains(h));
+        }
+
+        // The command pulling the skipped changes again, from the
+        // remote each of them was to be downloaded from.
+        let mut retry = Vec::new();
+        for (name, (_, own)) in remote_names.iter().zip(remotes.iter()) {
+            let hashes: Vec<_> = failures
+                .iter()
+                .filter(|f| own.contains(&CS::Change(f.hash)))
+                .map(|f| f.hash.to_base32())
+                .collect();
+            if hashes.is_empty() {
+                continue;
+            }
+            let mut r = format!("pull {} --from-channel {}", name, from_channel);
+            if let Some(ref c) = self.to_channel {
+                r.push_str(&format!(" --to-channel {}", c))
+            }
+            r.push_str(" -- ");
+            r.push_str(&hashes.join(" "));
+            retry.push(r)
+        }
 
         debug!("completing changes");
-        remote
-            .complete_changes(&repo, &*txn.read(), &mut channel, &to_download, self.full)
-            .await?;
-        remote.finish().await?;
+        let selected: HashSet<_> = to_download.iter().collect();
+        for (mut remote, own) in remotes {
+            let own: Vec<_> = own.into_iter().filter(|h| selected.contains(h)).collect();
+            remote
+                .complete_changes(&repo, &*txn.read(), &mut channel, &own, self.full)
+                .await?;
+            remote.finish().await?;
+        }
 
         debug!("inodes = {:?}", inodes);
         debug!("to_download: {:?}", to_download.len());
@@ -514,67 +978,82 @@ impl Pull {
             }
         }
         std::mem::drop(txn_);
-        if is_current_channel {
-            let mut touched_paths = BTreeSet::new();
-            {
-                let txn_ = txn.read();
-                for &i in touched.iter() {
-                    if let Some((path, _)) =
-                        libpijul::fs::find_path(&repo.changes, &*txn_, &*channel.read(), false, i)?
-                    {
-                        touched_paths.insert(path);
-                    } else {
-                        touched_paths.clear();
-                        break;
-                    }
-                }
-            }
-            if touched_paths.is_empty() {
-                touched_paths.insert(String::from(""));
-            }
-            let mut last = None;
-            PROGRESS
-                .borrow_mut()
-                .unwrap()
-                .push(crate::progress::Cursor::Spin {
-                    i: 0,
-                    pre: "Outputting repository".into(),
-                });
-            let mut conflicts = Vec::new();
-            for path in touched_paths.iter() {
-                match last {
-                    Some(last_path) if path.starts_with(last_path) => continue,
-                    _ => (),
-                }
-                debug!("path = {:?}", path);
-                conflicts.extend(
-                    libpijul::output::output_repository_no_pending(
-                        &repo.working_copy,
-                        &repo.changes,
-                        &txn,
-                        &channel,
-                        path,
-                        true,
-                        None,
-                        num_cpus::get(),
-                        0,
-                    )?
-                    .into_iter(),
-                );
-                last = Some(path)
-            }
-            PROGRESS.join();
-
-            super::print_conflicts(&conflicts)?;
-        }
+        let conflicts = if is_current_channel && !repo.config.bare {
+            output_touched(&repo.working_copy, &repo.changes, &txn, &channel, &touched)?
+        } else {
+            Vec::new()
+        };
         if let Some(h) = hash {
             txn.write().unrecord(&repo.changes, &mut channel, &h, 0)?;
             repo.changes.del_change(&h)?;
         }
 
         txn.commit()?;
-        Ok(())
+        super::apply::print_failures(&failures, &retry)?;
+        super::report_conflicts(&conflicts, self.conflicts_json, self.fail_on_conflict)?;
+        super::apply::check_failures(&failures)
+    }
+}
+
+/// Output the paths of the working copy containing the vertices of
+/// `touched`, or the whole working copy if one of them is not
+/// alive anymore.
+pub(super) fn output_touched(
+    working_copy: &libpijul::working_copy::filesystem::FileSystem,
+    changes: &crate::repository::Changes,
+    txn: &ArcTxn<MutTxn<()>>,
+    channel: &ChannelRef<MutTxn<()>>,
+    touched: &HashSet<libpijul::pristine::Position<ChangeId>>,
+) -> Result<Vec<Conflict>, anyhow::Error> {
+    let mut touched_paths = BTreeSet::new();
+    {
+        let txn_ = txn.read();
+        let mut paths = libpijul::fs::PathResolver::new(false);
+        for &i in touched.iter() {
+            if let Some((path, _)) = paths.find_path(changes, &*txn_, &*channel.read(), i)? {
+                touched_paths.insert(path);
+            } else {
+                touched_paths.clear();
+                break;
+            }
+        }
+    }
+    if touched_paths.is_empty() {
+        touched_paths.insert(String::from(""));
+    }
+    let mut conflicts = Vec::new();
+    let mut last = None;
+    PROGRESS
+        .borrow_mut()
+        .unwrap()
+        .push(crate::progress::Cursor::Spin {
+            i: 0,
+            pre: "Outputting repository".into(),
+        });
+    for path in touched_paths.iter() {
+        match last {
+            Some(last_path) if path.starts_with(last_path) => continue,
+            _ => (),
+        }
+        debug!("path = {:?}", path);
+        conflicts.extend(
+            libpijul::output::output_repository_no_pending(
+                working_copy,
+                changes,
+                txn,
+                channel,
+                path,
+                true,
+                None,
+                crate::jobs::output(),
+                0,
+            )?
+            .into_iter(),
+        );
+        last = Some(path)
     }
+    PROGRESS.join();
+    Ok(conflicts)
 }
 
 fn complete_deps<C: ChangeStore>(
@@ -613,19 +1092,240 @@ fn complete_deps<C: ChangeStore>(
     Ok(result)
 }
 
-fn check_deps<C: ChangeStore>(c: &C, original: &[CS], now: &[CS]) -> Result<(), anyhow::Error> {
+/// Add to `now` the dependencies of its changes that are in
+/// `original` but not in `now`, placing each of them before the first
+/// change depending on it.
+fn complete_selection<T: TxnT>(
+    txn: &T,
+    original: &[CS],
+    now: Vec<CS>,
+) -> Result<Vec<CS>, anyhow::Error> {
     let original_: HashSet<_> = original.iter().collect();
-    let now_: HashSet<_> = now.iter().collect();
+    let mut now_: HashSet<_> = now.iter().cloned().collect();
+    let mut result = Vec::with_capacity(now.len());
     for n in now {
-        // check that all of `now`'s deps are in now or not in original
-        let n = if let CS::Change(n) = n { n } else { continue };
-        for d in c.get_dependencies(n)? {
-            if original_.get(&CS::Change(d)).is_some() && now_.get(&CS::Change(d)).is_none() {
-                bail!("Missing dependency: {:?}", n)
+        let h = if let CS::Change(h) = n {
+            h
+        } else {
+            result.push(n);
+            continue;
+        };
+        let id = if let Some(&id) = txn.get_internal(&h.into())? {
+            id
+        } else {
+            result.push(n);
+            continue;
+        };
+        // Missing dependencies, in the order of `original`.
+        let mut missing = Vec::new();
+        for d in txn.dep_closure(&id)? {
+            let d = CS::Change(txn.get_external(&d)?.unwrap().into());
+            if original_.contains(&d) && !now_.contains(&d) {
+                missing.push(d)
             }
         }
+        missing.sort_by_key(|d| original.iter().position(|o| o == d));
+        for d in missing {
+            debug!("adding missing dependency {:?} of {:?}", d, h);
+            now_.insert(d);
+            result.push(d)
+        }
+        result.push(n)
     }
-    Ok(())
+    Ok(result)
+}
+
+/// Whether `arg`, given where a remote is expected, is rather the
+/// first change of a selection, as in `pijul push HASH`: it is not a
+/// configured remote nor an existing path, and is a range of base32
+/// prefixes (`FROM..TO` or `FROM..`), a `~deps` selection or a base32
+/// prefix. Remotes such as `me@host:../repo` are not ranges.
+fn is_selection(repo: &Repository, arg: &str) -> bool {
+    if repo.config.remotes.contains_key(arg)
+        || repo.config.default_remote.as_deref() == Some(arg)
+        || std::path::Path::new(arg).exists()
+    {
+        return false;
+    }
+    let is_prefix =
+        |s: &str| !s.is_empty() && s.bytes().all(|b| matches!(b, b'A'..=b'Z' | b'2'..=b'7'));
+    if let Some((from, to)) = arg.split_once("..") {
+        is_prefix(from) && (to.is_empty() || is_prefix(to))
+    } else {
+        is_prefix(arg.strip_suffix("~deps").unwrap_or(arg))
+    }
+}
+
+/// The changelist of the remote of `delta`, as just downloaded: the
+/// cached entries before the dichotomy, followed by the downloaded
+/// ones.
+fn remote_changelist(
+    txn: &MutTxn<()>,
+    delta: &RemoteDelta<MutTxn<()>>,
+) -> Result<Vec<(Hash, Merkle)>, anyhow::Error> {
+    let mut list = Vec::new();
+    if let Some(ref r) = delta.remote_ref {
+        for x in txn.iter_remote(&r.lock().remote, 0)? {
+            let (_, p) = x?;
+            let h = CS::Change(p.a.into());
+            if delta.ours_ge_dichotomy_set.contains(&h)
+                || delta.theirs_ge_dichotomy_set.contains(&h)
+            {
+                continue;
+            }
+            list.push((p.a.into(), p.b.into()))
+        }
+    }
+    list.extend(
+        delta
+            .theirs_ge_dichotomy
+            .iter()
+            .map(|(_, h, m, _)| (*h, *m)),
+    );
+    Ok(list)
+}
+
+/// The position in `list` of the only entry whose key starts with
+/// `prefix`.
+fn position_from_prefix<K: Fn(&(Hash, Merkle)) -> String>(
+    list: &[(Hash, Merkle)],
+    prefix: &str,
+    key: K,
+) -> Result<Option<usize>, anyhow::Error> {
+    let mut found = None;
+    for (i, e) in list.iter().enumerate() {
+        if key(e).starts_with(prefix) {
+            if found.is_some() {
+                bail!("Ambiguous prefix: {:?}", prefix)
+            }
+            found = Some(i)
+        }
+    }
+    Ok(found)
+}
+
+/// Resolve the changes given on the command line against the
+/// changelist of a remote. `FROM..TO` is the changes of the remote
+/// between its states `FROM` and `TO` (its last state if `TO` is
+/// empty), other arguments are state or hash prefixes, with a `~deps`
+/// suffix for the hashes whose dependencies are selected too. Returns
+/// the selection, and the changes whose dependencies are wanted.
+fn remote_selection(
+    list: &[(Hash, Merkle)],
+    selection: &[String],
+) -> Result<(Vec<CS>, Vec<Hash>), anyhow::Error> {
+    let state = |e: &(Hash, Merkle)| e.1.to_base32();
+    let hash = |e: &(Hash, Merkle)| e.0.to_base32();
+    let mut result = Vec::new();
+    let mut with_deps = Vec::new();
+    for s in selection {
+        if let Some((from, to)) = s.split_once("..") {
+            let from = if let Some(i) = position_from_prefix(list, from, state)? {
+                i
+            } else {
+                bail!("State not found in the remote: {:?}", from)
+            };
+            let to = if to.is_empty() {
+                list.len() - 1
+            } else if let Some(i) = position_from_prefix(list, to, state)? {
+                i
+            } else {
+                bail!("State not found in the remote: {:?}", to)
+            };
+            if to < from {
+                bail!(
+                    "State {} comes after state {}",
+                    list[from].1.to_base32(),
+                    list[to].1.to_base32()
+                )
+            }
+            result.extend(list[from + 1..=to].iter().map(|(h, _)| CS::Change(*h)))
+        } else if let Some(prefix) = s.strip_suffix("~deps") {
+            if let Some(i) = position_from_prefix(list, prefix, hash)? {
+                result.push(CS::Change(list[i].0));
+                with_deps.push(list[i].0)
+            } else {
+                bail!("Change not found in the remote: {:?}", prefix)
+            }
+        } else if let Some(i) = position_from_prefix(list, s, state).ok().flatten() {
+            result.push(CS::State(list[i].1))
+        } else if let Some(i) = position_from_prefix(list, s, hash)? {
+            result.push(CS::Change(list[i].0))
+        } else {
+            bail!("Change not found in the remote: {:?}", s)
+        }
+    }
+    Ok((result, with_deps))
+}
+
+/// Expand the changes given on the command line, returning hash (or
+/// state) prefixes. `FROM..TO` is the changes applied to `channel`
+/// between states `FROM` and `TO` (its current state if `TO` is
+/// empty), and `HASH~deps` is a change preceded by all its
+/// dependencies. Other arguments are returned unchanged.
+fn expand_selection<'a, I: Iterator<Item = &'a String>>(
+    txn: &MutTxn<()>,
+    channel: Option<&ChannelRef<MutTxn<()>>>,
+    selection: I,
+) -> Result<Vec<String>, anyhow::Error> {
+    let mut result = Vec::new();
+    let mut seen = HashSet::new();
+    for s in selection {
+        if let Some((from, to)) = s.split_once("..") {
+            let channel = if let Some(channel) = channel {
+                channel.read()
+            } else {
+                bail!(
+                    "Ranges of states are only supported in local channels: {:?}",
+                    s
+                )
+            };
+            let from = txn.state_from_prefix(txn.states(&*channel), from)?.0;
+            let to = if to.is_empty() {
+                txn.current_state(&*channel)?
+            } else {
+                txn.state_from_prefix(txn.states(&*channel), to)?.0
+            };
+            let between = if let Some(b) = txn.changes_between(&*channel, &from, &to)? {
+                b
+            } else {
+                bail!("States not found: {:?}", s)
+            };
+            if !between.unrecord.is_empty() {
+                bail!(
+                    "State {} comes after state {}",
+                    from.to_base32(),
+                    to.to_base32()
+                )
+            }
+            for h in between.apply.iter() {
+                if seen.insert(*h) {
+                    result.push(h.to_base32())
+                }
+            }
+        } else if let Some(prefix) = s.strip_suffix("~deps") {
+            let (hash, id) = txn.hash_from_prefix(prefix)?;
+            // Order the closure by the number of dependencies, so
+            // that each change comes after its own dependencies.
+            let mut deps = Vec::new();
+            for d in txn.dep_closure(&id)? {
+                deps.push((txn.dep_closure(&d)?.len(), d))
+            }
+            deps.sort();
+            for (_, d) in deps {
+                let d: Hash = txn.get_external(&d)?.unwrap().into();
+                if seen.insert(d) {
+                    result.push(d.to_base32())
+                }
+            }
+            if seen.insert(hash) {
+                result.push(hash.to_base32())
+            }
+        } else {
+            result.push(s.clone())
+        }
+    }
+    Ok(result)
 }
 
 fn notify_remote_unrecords(repo: &Repository, remote_unrecs: &[(u64, crate::remote::CS)]) {
@@ -662,6 +1362,158 @@ fn notify_remote_unrecords(repo: &Repository, remote_unrecs: &[(u64, crate::remo
     }
 }
 
+/// What to do with the changes unrecorded from the remote that are
+/// still in the channel being pushed.
+enum RemoteUnrecordAction {
+    Repush,
+    UnrecordLocally,
+    Abort,
+}
+
+/// List the changes unrecorded from the remote, along with how they
+/// relate to the local channel, and ask the user what to do with
+/// them, unless `action` (the `--remote-unrecords` argument) is given.
+fn resolve_remote_unrecords<T: TxnTExt>(
+    repo: &Repository,
+    txn: &T,
+    channel: &T::Channel,
+    remote_unrecs: &[(u64, CS)],
+    action: Option<&str>,
+) -> Result<RemoteUnrecordAction, anyhow::Error> {
+    let mut stderr = std::io::stderr();
+    writeln!(
+        stderr,
+        "The following changes have been unrecorded in the remote:"
+    )?;
+    for (_, cs) in remote_unrecs {
+        let (header, hash) = match cs {
+            CS::Change(hash) => (repo.changes.get_header(hash)?, hash.to_base32()),
+            CS::State(hash) => (repo.changes.get_tag_header(hash)?, hash.to_base32()),
+        };
+        writeln!(stderr)?;
+        writeln!(stderr, "    {}", hash)?;
+        writeln!(stderr, "    {}", header.message)?;
+        writeln!(stderr, "    {}", header.timestamp)?;
+        if let CS::Change(hash) = cs {
+            match txn.classify_remote_unrecord(channel, hash)? {
+                RemoteUnrecord::InChannel { .. } => {}
+                RemoteUnrecord::DependedUpon { dependents, .. } => {
+                    writeln!(stderr, "    Local changes depending on it:")?;
+                    for d in dependents {
+                        writeln!(stderr, "        {}", d.to_base32())?;
+                    }
+                }
+                RemoteUnrecord::NotInChannel | RemoteUnrecord::Unknown => {
+                    writeln!(stderr, "    Not in the local channel")?;
+                }
+            }
+        }
+    }
+    match action {
+        Some("push") => return Ok(RemoteUnrecordAction::Repush),
+        Some("unrecord") => return Ok(RemoteUnrecordAction::UnrecordLocally),
+        Some("abort") => return Ok(RemoteUnrecordAction::Abort),
+        Some(a) => bail!("Unknown action for --remote-unrecords: {:?}", a),
+        None => {}
+    }
+    if !atty::is(atty::Stream::Stdin) {
+        bail!("Not pushing changes unrecorded in the remote, use --remote-unrecords to choose what to do with them")
+    }
+    loop {
+        write!(
+            stderr,
+            "\nPush them again (r), unrecord them locally too (u), or abort (a)? "
+        )?;
+        stderr.flush()?;
+        let mut buffer = String::new();
+        std::io::stdin().read_line(&mut buffer)?;
+        match buffer.trim() {
+            "r" | "R" => return Ok(RemoteUnrecordAction::Repush),
+            "u" | "U" => return Ok(RemoteUnrecordAction::UnrecordLocally),
+            "a" | "A" | "" => return Ok(RemoteUnrecordAction::Abort),
+            _ => {}
+        }
+    }
+}
+
+/// Unrecord the changes unrecorded from the remote from the local
+/// channel too, latest first. Fails if another change of the channel
+/// depends on one of them. If `channel` is the current channel, the
+/// working copy is updated, keeping its unrecorded changes, like
+/// `pijul unrecord --reset` does.
+fn unrecord_locally(
+    repo: &mut Repository,
+    txn: &ArcTxn<MutTxn<()>>,
+    channel: &ChannelRef<MutTxn<()>>,
+    remote_unrecs: &[(u64, CS)],
+    is_current_channel: bool,
+) -> Result<(), anyhow::Error> {
+    let mut changes = Vec::new();
+    {
+        let txn = txn.read();
+        let channel = channel.read();
+        let unrecs: HashSet<_> = remote_unrecs
+            .iter()
+            .filter_map(|(_, cs)| {
+                if let CS::Change(h) = cs {
+                    Some(*h)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        for hash in unrecs.iter() {
+            match txn.classify_remote_unrecord(&channel, hash)? {
+                RemoteUnrecord::InChannel { n } => changes.push((n, *hash)),
+                RemoteUnrecord::DependedUpon { n, dependents } => {
+                    if let Some(d) = dependents.iter().find(|d| !unrecs.contains(*d)) {
+                        bail!(
+                            "Cannot unrecord change {} because {} depends on it",
+                            hash.to_base32(),
+                            d.to_base32()
+                        )
+                    }
+                    changes.push((n, *hash))
+                }
+                RemoteUnrecord::NotInChannel | RemoteUnrecord::Unknown => {}
+            }
+        }
+    }
+    if changes.is_empty() {
+        return Ok(());
+    }
+    let pending_hash = if is_current_channel {
+        super::pending(txn.clone(), channel, repo)?
+    } else {
+        None
+    };
+    changes.sort_by(|a, b| b.0.cmp(&a.0));
+    for (_, hash) in changes {
+        debug!("unrecording {:?}", hash);
+        txn.write().unrecord(&repo.changes, channel, &hash, 0)?;
+    }
+    if is_current_channel && !repo.config.bare {
+        libpijul::output::output_repository_no_pending(
+            &repo.working_copy,
+            &repo.changes,
+            txn,
+            channel,
+            "",
+            true,
+            None,
+            crate::jobs::output(),
+            0,
+        )?;
+    }
+    if let Some(h) = pending_hash {
+        txn.write().unrecord(&repo.changes, channel, &h, 0)?;
+        if cfg!(feature = "keep-changes") {
+            repo.changes.del_change(&h)?;
+        }
+    }
+    Ok(())
+}
+
 fn notify_unknown_changes(unknown_changes: &[crate::remote::CS]) {
     use std::fmt::Write;
     if unknown_changes.is_empty() {
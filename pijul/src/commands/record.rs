@@ -1,5 +1,5 @@
-use std::io::Write;
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::bail;
@@ -14,6 +14,8 @@ use libpijul::{
 use libpijul::{HashMap, HashSet};
 use log::debug;
 
+use crate::config::ConflictMarkers;
+use crate::filters::FilterOpts;
 use crate::repository::*;
 
 #[derive(Parser, Debug)]
@@ -24,9 +26,15 @@ pub struct Record {
     /// Set the change message
     #[clap(short = 'm', long = "message")]
     pub message: Option<String>,
-    /// Set the author field
-    #[clap(long = "author")]
-    pub author: Option<String>,
+    /// Read the change message from this file, or from the standard input if FILE is `-`. The first line is the message, and the following lines the description. Lines starting with `#` are ignored.
+    #[clap(long = "message-file", value_name = "FILE", conflicts_with = "message")]
+    pub message_file: Option<PathBuf>,
+    /// Set the authors of the change instead of the identity created with `pijul key`. AUTHOR is the public key, login or name of an identity known to this repository or to the global configuration, or any other name. Can be given several times.
+    #[clap(long = "author", value_name = "AUTHOR")]
+    pub author: Vec<String>,
+    /// Add an author to the change, in the same format as `--author`. Can be given several times.
+    #[clap(long = "co-author", value_name = "AUTHOR")]
+    pub co_author: Vec<String>,
     /// Record the change in this channel instead of the current channel
     #[clap(long = "channel")]
     pub channel: Option<String>,
@@ -45,7 +53,28 @@ pub struct Record {
     #[clap(long = "amend")]
     #[allow(clippy::option_option)]
     pub amend: Option<Option<String>>,
-    /// Paths in which to record the changes
+    /// Write the change to this file instead of applying it. The
+    /// change is not added to the repository, nor to any channel.
+    #[clap(long = "patch-file", value_name = "PATH", conflicts_with = "amend")]
+    pub patch_file: Option<PathBuf>,
+    /// Keep at most approximately this much of the new contents in memory while recording, spilling to a temporary file past that limit. Accepts suffixes K, M and G.
+    #[clap(long = "max-memory", value_name = "SIZE", parse(try_from_str = super::apply::parse_size))]
+    pub max_memory: Option<usize>,
+    /// Record lines that look like conflict markers even if the `conflict_markers` setting of the repository is "deny"
+    #[clap(long = "allow-conflict-markers")]
+    pub allow_conflict_markers: bool,
+    /// Add a `Signed-off-by` trailer to the description, with the name and email of the author
+    #[clap(short = 's', long = "sign-off")]
+    pub sign_off: bool,
+    /// Add a trailer of the form `Key: value` to the description, such as `Reviewed-by: Alice` or `Fixes: #12`. Can be given several times.
+    #[clap(long = "trailer", value_name = "TRAILER", parse(try_from_str = parse_trailer))]
+    pub trailers: Vec<Trailer>,
+    /// Record the files matching this pattern (in the syntax of `.ignore` files, such as `*.svg`) as binary, whatever their contents look like. Can be given several times.
+    #[clap(long = "binary", value_name = "GLOB")]
+    pub binary: Vec<String>,
+    #[clap(flatten)]
+    pub filters: FilterOpts,
+    /// Paths in which to record the changes, relative to the current directory. If missing, record the changes in the current directory, which is the entire repository when run from its root.
     pub prefixes: Vec<PathBuf>,
 }
 
@@ -58,9 +87,195 @@ pub(crate) fn timestamp_validator(s: &str) -> Result<(), &'static str> {
     Err("Could not parse timestamp")
 }
 
+/// Read a change message and description from `path`, or from the
+/// standard input if `path` is `-`.
+fn read_message_file(path: &Path) -> Result<(String, Option<String>), anyhow::Error> {
+    let mut text = String::new();
+    if path == Path::new("-") {
+        std::io::stdin().read_to_string(&mut text)?;
+    } else if let Err(e) = std::fs::File::open(path).and_then(|mut f| f.read_to_string(&mut text)) {
+        bail!("Could not read message file {:?}: {}", path, e)
+    }
+    let mut lines = text
+        .lines()
+        .filter(|l| !l.starts_with('#'))
+        .skip_while(|l| l.trim().is_empty());
+    let message = if let Some(m) = lines.next() {
+        m.trim().to_string()
+    } else {
+        bail!("No change message in {:?}", path)
+    };
+    let description = lines.collect::<Vec<_>>().join("\n");
+    let description = description.trim();
+    Ok((
+        message,
+        if description.is_empty() {
+            None
+        } else {
+            Some(description.to_string())
+        },
+    ))
+}
+
+/// An author given on the command line.
+struct CliAuthor {
+    author: Author,
+    /// The name (and email, if any) used to sign off, if known.
+    name: Option<String>,
+}
+
+/// The public key of the identity created with `pijul key`, or
+/// `None` if there is no global configuration directory.
+fn own_public_key() -> Result<Option<String>, anyhow::Error> {
+    if let Some(mut dir) = crate::config::global_config_dir() {
+        dir.push("publickey.json");
+        if let Ok(key) = std::fs::File::open(&dir) {
+            let k: libpijul::key::PublicKey = serde_json::from_reader(key)?;
+            Ok(Some(k.key))
+        } else {
+            bail!("No identity configured yet. Please use `pijul key` to create one")
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+/// Resolve the authors given with `--author` or `--co-author`
+/// against the identities of the repository and of the global
+/// configuration.
+fn resolve_authors(repo: &Repository, authors: &[String]) -> Result<Vec<CliAuthor>, anyhow::Error> {
+    if authors.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut dirs = vec![repo.path.join(libpijul::DOT_DIR).join("identities")];
+    if let Some(dir) = crate::config::global_config_dir() {
+        dirs.push(dir.join("identities"))
+    }
+    // All the identities we know of, by public key. Identities of the
+    // repository take precedence over the global ones.
+    let mut identities = std::collections::BTreeMap::new();
+    for dir in dirs.iter() {
+        let entries = if let Ok(entries) = std::fs::read_dir(dir) {
+            entries
+        } else {
+            continue;
+        };
+        for entry in entries {
+            let entry = entry?;
+            let id = std::fs::read(entry.path())
+                .ok()
+                .and_then(|id| serde_json::from_slice::<super::Identity>(&id).ok());
+            if let Some(id) = id {
+                identities.entry(id.public_key.key.clone()).or_insert(id);
+            }
+        }
+    }
+    let own_key = own_public_key().ok().flatten();
+    let mut result = Vec::with_capacity(authors.len());
+    for a in authors {
+        let mut b = std::collections::BTreeMap::new();
+        let name = if let Some(id) = identities.get(a.as_str()) {
+            b.insert("key".to_string(), a.clone());
+            Some(identity_name(id))
+        } else if own_key.as_deref() == Some(a.as_str()) {
+            b.insert("key".to_string(), a.clone());
+            None
+        } else {
+            let matching: Vec<_> = identities
+                .values()
+                .filter(|id| id.login == *a || id.name.as_deref() == Some(a.as_str()))
+                .collect();
+            if matching.len() > 1 {
+                bail!(
+                    "Author {:?} matches several identities, please use one of their keys: {}",
+                    a,
+                    matching
+                        .iter()
+                        .map(|id| id.public_key.key.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            } else if let Some(id) = matching.first() {
+                b.insert("key".to_string(), id.public_key.key.clone());
+                Some(identity_name(id))
+            } else if looks_like_key(a) {
+                bail!(
+                    "Unknown identity {:?}. Identities are downloaded along with the changes they signed, or created with `pijul key`",
+                    a
+                )
+            } else {
+                b.insert("name".to_string(), a.clone());
+                Some(a.clone())
+            }
+        };
+        let author = Author(b);
+        if !result.iter().any(|r: &CliAuthor| r.author == author) {
+            result.push(CliAuthor { author, name })
+        }
+    }
+    Ok(result)
+}
+
+fn identity_name(id: &super::Identity) -> String {
+    let name = id.name.as_ref().unwrap_or(&id.login);
+    if let Some(ref email) = id.email {
+        format!("{} <{}>", name, email)
+    } else {
+        name.clone()
+    }
+}
+
+/// Whether `s` looks like a public key (32 bytes in base58) rather
+/// than like a name.
+fn looks_like_key(s: &str) -> bool {
+    (40..=44).contains(&s.len())
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() && !"0OIl".contains(c))
+}
+
+/// The name (and email, if any) of the author signing off: the first
+/// author given on the command line, or the author of the global
+/// configuration.
+fn sign_off_name(authors: &[CliAuthor]) -> Result<String, anyhow::Error> {
+    if let Some(name) = authors.first().and_then(|a| a.name.as_ref()) {
+        return Ok(name.clone());
+    }
+    if let Ok((global, _)) = crate::config::Global::load() {
+        let author = global.author;
+        let name = author.full_name.unwrap_or(author.name);
+        if !name.is_empty() {
+            return Ok(if let Some(email) = author.email {
+                format!("{} <{}>", name, email)
+            } else {
+                name
+            });
+        }
+    }
+    bail!("No author name configured, cannot sign off. Please set `author.name` in the global configuration, or use --author")
+}
+
+fn parse_trailer(s: &str) -> Result<Trailer, anyhow::Error> {
+    if let Some(t) = Trailer::parse(s) {
+        Ok(t)
+    } else {
+        bail!("Invalid trailer {:?}, expected `Key: value`", s)
+    }
+}
+
 impl Record {
-    pub fn run(self) -> Result<(), anyhow::Error> {
+    pub fn run(mut self) -> Result<(), anyhow::Error> {
         let repo = Repository::find_root(self.repo_path.clone())?;
+        if self.working_copy.is_none() {
+            repo.require_working_copy()?;
+        }
+        let _lock = repo.lock_working_copy()?;
+        let description = if let Some(ref file) = self.message_file {
+            let (message, description) = read_message_file(file)?;
+            self.message = Some(message);
+            description
+        } else {
+            None
+        };
         let mut stdout = std::io::stdout();
         let mut stderr = std::io::stderr();
 
@@ -101,7 +316,9 @@ impl Record {
             extra.push(h)
         }
 
-        let header = if let Some(ref amend) = self.amend {
+        let authors = resolve_authors(&repo, &self.author)?;
+        let co_authors = resolve_authors(&repo, &self.co_author)?;
+        let mut header = if let Some(ref amend) = self.amend {
             let h = if let Some(ref hash) = amend {
                 txn.read().hash_from_prefix(hash)?.0
             } else if let Some(h) = txn.read().reverse_log(&*channel.read(), None)?.next() {
@@ -109,7 +326,7 @@ impl Record {
             } else {
                 return Ok(());
             };
-            let header = if let Some(message) = self.message.clone() {
+            let mut header = if let Some(message) = self.message.clone() {
                 ChangeHeader {
                     message,
                     ..repo.changes.get_header(&h)?
@@ -117,6 +334,9 @@ impl Record {
             } else {
                 repo.changes.get_header(&h)?
             };
+            if !authors.is_empty() {
+                header.authors = authors.iter().map(|a| a.author.clone()).collect();
+            }
 
             txn.write().unrecord(
                 &repo.changes,
@@ -126,8 +346,22 @@ impl Record {
             )?;
             header
         } else {
-            self.header()?
+            self.header(&authors)?
         };
+        for a in co_authors.iter() {
+            if !header.authors.contains(&a.author) {
+                header.authors.push(a.author.clone())
+            }
+        }
+        if description.is_some() {
+            header.description = description
+        }
+        self.add_trailers(&mut header, &authors)?;
+        // Only record the current directory when running in a
+        // subdirectory of the repository.
+        if self.working_copy.is_none() {
+            self.prefixes = repo.scoped_prefixes(&self.prefixes)?;
+        }
         let no_prefixes =
             self.prefixes.is_empty() && !self.ignore_missing && self.working_copy.is_none();
         let (repo_path, working_copy) = if let Some(ref w) = self.working_copy {
@@ -140,10 +374,18 @@ impl Record {
         };
 
         let (_, key) = super::load_key()?;
+        let patch_file = self.patch_file.clone();
 
         txn.write()
             .apply_root_change_if_needed(&repo.changes, &channel, rand::thread_rng())?;
 
+        let conflict_markers = if self.allow_conflict_markers {
+            ConflictMarkers::Allow
+        } else {
+            repo.config.conflict_markers
+        };
+        let separators = repo.config.separators()?;
+        let file_classes = repo.config.file_classes(&self.binary)?;
         let result = self.record(
             txn,
             channel.clone(),
@@ -152,15 +394,42 @@ impl Record {
             repo_path,
             header,
             &extra,
+            conflict_markers,
+            separators,
+            file_classes,
         )?;
         match result {
-            Either::A((txn, mut change, updates, oldest)) => {
-                let hash = repo.changes.save_change(&mut change, |change, hash| {
+            Either::A((_, mut change, contents, _, _)) if patch_file.is_some() => {
+                // Don't commit the transaction: nothing is applied.
+                let patch_file = patch_file.unwrap();
+                let mut f = std::io::BufWriter::new(std::fs::File::create(&patch_file)?);
+                let sign = |change: &mut Change, hash: &libpijul::Hash| {
                     change.unhashed = Some(serde_json::json!({
                         "signature": key.sign_raw(&hash.to_bytes()).unwrap(),
                     }));
                     Ok::<_, anyhow::Error>(())
-                })?;
+                };
+                let hash = if let Some(mut contents) = contents {
+                    change.serialize_with_contents(&mut f, &mut contents, sign)?
+                } else {
+                    change.serialize(&mut f, sign)?
+                };
+                f.flush()?;
+                writeln!(stdout, "Hash: {}", hash.to_base32())?;
+            }
+            Either::A((txn, mut change, contents, updates, oldest)) => {
+                let sign = |change: &mut Change, hash: &libpijul::Hash| {
+                    change.unhashed = Some(serde_json::json!({
+                        "signature": key.sign_raw(&hash.to_bytes()).unwrap(),
+                    }));
+                    Ok::<_, anyhow::Error>(())
+                };
+                let hash = if let Some(mut contents) = contents {
+                    repo.changes
+                        .save_change_with_contents(&mut change, &mut contents, sign)?
+                } else {
+                    repo.changes.save_change(&mut change, sign)?
+                };
 
                 let mut txn_ = txn.write();
                 txn_.apply_local_change(&mut channel, &change, &hash, &updates)?;
@@ -203,22 +472,32 @@ impl Record {
         Ok(())
     }
 
-    fn header(&self) -> Result<ChangeHeader, anyhow::Error> {
+    /// Add the trailers given on the command line to the description.
+    fn add_trailers(
+        &self,
+        header: &mut ChangeHeader,
+        authors: &[CliAuthor],
+    ) -> Result<(), anyhow::Error> {
+        if self.sign_off {
+            header.add_trailer(Trailer::new("Signed-off-by", &sign_off_name(authors)?))
+        }
+        for t in self.trailers.iter() {
+            header.add_trailer(t.clone())
+        }
+        Ok(())
+    }
+
+    fn header(&self, authors: &[CliAuthor]) -> Result<ChangeHeader, anyhow::Error> {
         let config = crate::config::Global::load();
-        let mut authors = Vec::new();
-        let mut b = std::collections::BTreeMap::new();
-        if let Some(ref a) = self.author {
-            b.insert("name".to_string(), a.clone());
-        } else if let Some(mut dir) = crate::config::global_config_dir() {
-            dir.push("publickey.json");
-            if let Ok(key) = std::fs::File::open(&dir) {
-                let k: libpijul::key::PublicKey = serde_json::from_reader(key).unwrap();
-                b.insert("key".to_string(), k.key);
-            } else {
-                bail!("No identity configured yet. Please use `pijul key` to create one")
+        let authors = if !authors.is_empty() {
+            authors.iter().map(|a| a.author.clone()).collect()
+        } else {
+            let mut b = std::collections::BTreeMap::new();
+            if let Some(key) = own_public_key()? {
+                b.insert("key".to_string(), key);
             }
-        }
-        authors.push(Author(b));
+            vec![Author(b)]
+        };
         let templates = config
             .as_ref()
             .ok()
@@ -258,21 +537,11 @@ impl Record {
         Ok(header)
     }
 
-    fn fill_relative_prefixes(&mut self) -> Result<(), anyhow::Error> {
-        let cwd = std::env::current_dir()?;
-        for p in self.prefixes.iter_mut() {
-            if p.is_relative() {
-                *p = cwd.join(&p);
-            }
-        }
-        Ok(())
-    }
-
     fn record<
         T: TxnTExt + MutTxnTExt + Sync + Send + 'static,
         C: ChangeStore + Send + Clone + 'static,
     >(
-        mut self,
+        self,
         txn: ArcTxn<T>,
         channel: ChannelRef<T>,
         working_copy: &libpijul::working_copy::FileSystem,
@@ -280,11 +549,15 @@ impl Record {
         repo_path: CanonicalPathBuf,
         header: ChangeHeader,
         extra_deps: &[libpijul::Hash],
+        conflict_markers: ConflictMarkers,
+        separators: libpijul::Separators,
+        file_classes: libpijul::FileClasses,
     ) -> Result<
         Either<
             (
                 ArcTxn<T>,
                 Change,
+                Option<libpijul::record::ContentArena>,
                 HashMap<usize, libpijul::InodeUpdate>,
                 std::time::SystemTime,
             ),
@@ -292,10 +565,24 @@ impl Record {
         >,
         anyhow::Error,
     > {
-        let mut state = libpijul::RecordBuilder::new();
+        let mut state = if let Some(max_memory) = self.max_memory {
+            libpijul::RecordBuilder::with_max_memory(max_memory)
+        } else {
+            libpijul::RecordBuilder::new()
+        };
         if self.ignore_missing {
             state.ignore_missing = true;
         }
+        state.max_file_size = working_copy.traversal_options().max_file_size;
+        state.separators = separators;
+        state.file_classes = file_classes;
+        let filter = self.filters.path_filter()?;
+        let mut working_copy = working_copy.clone();
+        working_copy.set_path_filter(filter.clone());
+        let working_copy = &working_copy;
+        state.path_filter = filter.clone();
+        // Recording uses a single thread unless told otherwise.
+        let n_workers = crate::jobs::configured(crate::jobs::Operation::Record).unwrap_or(1);
         if self.prefixes.is_empty() {
             if self.ignore_missing {
                 for f in ignore::Walk::new(&repo_path) {
@@ -303,6 +590,12 @@ impl Record {
                     if f.metadata()?.is_file() {
                         let p = CanonicalPath::new(f.path())?;
                         let p = p.as_path().strip_prefix(&repo_path).unwrap();
+                        if let Some(ref filter) = filter {
+                            use path_slash::PathExt;
+                            if !filter(&p.to_slash_lossy(), false) {
+                                continue;
+                            }
+                        }
                         state.record(
                             txn.clone(),
                             libpijul::Algorithm::default(),
@@ -312,7 +605,7 @@ impl Record {
                             working_copy,
                             changes,
                             p.to_str().unwrap(),
-                            1, // num_cpus::get(),
+                            n_workers,
                         )?
                     }
                 }
@@ -326,11 +619,10 @@ impl Record {
                     working_copy,
                     changes,
                     "",
-                    1, // num_cpus::get(),
+                    n_workers,
                 )?
             }
         } else {
-            self.fill_relative_prefixes()?;
             working_copy.record_prefixes(
                 txn.clone(),
                 channel.clone(),
@@ -339,15 +631,42 @@ impl Record {
                 repo_path,
                 &self.prefixes,
                 false,
-                1, // num_cpus::get(),
+                n_workers,
                 self.timestamp.unwrap_or(0) as u64,
             )?;
         }
 
         let mut rec = state.finish();
+        for f in rec.skipped_large_files.iter() {
+            writeln!(
+                std::io::stderr(),
+                "Warning: not recording changes to {:?}, larger than max_file_size",
+                f
+            )?;
+        }
         if rec.actions.is_empty() {
             return Ok(Either::B(txn));
         }
+        if conflict_markers != ConflictMarkers::Allow {
+            let markers = rec.conflict_markers()?;
+            let mut stderr = std::io::stderr();
+            for m in markers.iter() {
+                writeln!(
+                    stderr,
+                    "{}: {}:{}: conflict marker",
+                    if conflict_markers == ConflictMarkers::Deny {
+                        "Error"
+                    } else {
+                        "Warning"
+                    },
+                    m.path,
+                    m.line
+                )?;
+            }
+            if conflict_markers == ConflictMarkers::Deny && !markers.is_empty() {
+                bail!("Not recording unresolved conflicts, use --allow-conflict-markers to record anyway")
+            }
+        }
         debug!("TAKING LOCK {}", line!());
         let txn_ = txn.write();
         let actions = rec
@@ -355,13 +674,16 @@ impl Record {
             .into_iter()
             .map(|rec| rec.globalize(&*txn_).unwrap())
             .collect();
-        let contents = if let Ok(c) = Arc::try_unwrap(rec.contents) {
+        let mut contents = if let Ok(c) = Arc::try_unwrap(rec.contents) {
             c.into_inner()
         } else {
             unreachable!()
         };
+        // With `-a`, the contents are saved straight from the arena,
+        // which may be on disk.
         let mut change =
-            LocalChange::make_change(&*txn_, &channel, actions, contents, header, Vec::new())?;
+            LocalChange::make_change(&*txn_, &channel, actions, Vec::new(), header, Vec::new())?;
+        change.contents_hash = contents.hash()?;
 
         let current: HashSet<_> = change.dependencies.iter().cloned().collect();
         for dep in extra_deps.iter() {
@@ -371,9 +693,11 @@ impl Record {
         }
 
         debug!("has_binary = {:?}", rec.has_binary_files);
-        let mut change = if self.all {
-            change
+        let (mut change, contents) = if self.all {
+            (change, Some(contents))
         } else {
+            // The editor needs all the contents anyway.
+            change.contents = contents.into_vec()?;
             let mut o = Vec::new();
             debug!("write change");
             change.write(changes, None, true, &mut o)?;
@@ -386,17 +710,21 @@ impl Record {
                 } else {
                     edit::edit_bytes(&o[..])?
                 };
-                if bytes.iter().all(|c| (*c as char).is_whitespace()) {
+                if is_empty_template(&bytes) {
                     bail!("Empty change")
                 }
                 let mut change = std::io::BufReader::new(std::io::Cursor::new(&bytes));
-                if let Ok(change) =
-                    Change::read_and_deps(&mut change, &mut rec.updatables, &*txn_, &channel)
-                {
-                    break change;
-                }
-
+                let result =
+                    Change::read_and_deps(&mut change, &mut rec.updatables, &*txn_, &channel);
+                let e = match result {
+                    Ok(change) => break change,
+                    Err(e) => e,
+                };
+                debug!("syntax error: {:?}", e);
                 let mut err = SYNTAX_ERROR.as_bytes().to_vec();
+                for l in e.to_string().lines() {
+                    writeln!(err, "# {}", l)?;
+                }
                 err.append(&mut bytes);
                 with_errors = Some(err)
             };
@@ -407,7 +735,7 @@ impl Record {
                     bail!("Cannot parse change")
                 }
             }
-            change
+            (change, None)
         };
 
         let current: HashSet<_> = change.dependencies.iter().cloned().collect();
@@ -422,7 +750,13 @@ impl Record {
         }
         debug!("saving change");
         std::mem::drop(txn_);
-        Ok(Either::A((txn, change, rec.updatables, rec.oldest_change)))
+        Ok(Either::A((
+            txn,
+            change,
+            contents,
+            rec.updatables,
+            rec.oldest_change,
+        )))
     }
 }
 
@@ -431,6 +765,15 @@ enum Either<A, B> {
     B(B),
 }
 
+/// Whether the edited change contains nothing but blank lines and
+/// comments, which aborts the recording.
+fn is_empty_template(bytes: &[u8]) -> bool {
+    bytes.split(|c| *c == b'\n').all(|l| {
+        let l = std::str::from_utf8(l).unwrap_or("").trim();
+        l.is_empty() || l.starts_with('#')
+    })
+}
+
 const SYNTAX_ERROR: &str = "# Syntax errors, please try again.
 # Alternatively, you may delete the entire file (including this
 # comment) to abort.
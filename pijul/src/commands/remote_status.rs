@@ -0,0 +1,289 @@
+//! `pijul remote status`: how far the remotes of the configuration
+//! file are from a local channel, according to their cached
+//! changelists.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+use anyhow::bail;
+use libpijul::pristine::sanakirja::MutTxn;
+use libpijul::pristine::{RemoteId, RemoteRef};
+use libpijul::*;
+use log::debug;
+use serde_derive::Serialize;
+
+use super::pushpull::{all_remote_names, expand_remote_group};
+use crate::config::Direction;
+use crate::repository::Repository;
+
+/// The divergence between a local channel and one cached changelist
+/// of a remote.
+#[derive(Debug, Serialize)]
+struct RemoteStatus {
+    /// The name of the remote in the configuration file.
+    remote: String,
+    /// The address of the remote.
+    url: String,
+    /// The identifier of the cached remote channel, if there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    /// Number of changes of the local channel that are not in the
+    /// remote.
+    ahead: usize,
+    /// Number of changes of the remote that are not in the local
+    /// channel.
+    behind: usize,
+    /// The paths the cached changelist is restricted to, if any, in
+    /// which case `ahead` also counts the changes outside of these
+    /// paths.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    paths: Vec<String>,
+    /// The changes of the local channel that the remote unrecorded
+    /// since they were last cached. This is only known when
+    /// refreshing the cache.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    unrecorded: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Options of `pijul remote status`.
+pub(super) struct StatusOptions<'a> {
+    pub channel: Option<&'a str>,
+    pub refresh: bool,
+    pub from_channel: &'a str,
+    pub no_cert_check: bool,
+    pub json: bool,
+}
+
+/// Compare the channel of `opts` with the cached changelists of
+/// `remotes`, or of all the remotes of the configuration file if
+/// `remotes` is empty, and print the result.
+pub(super) async fn status(
+    repo: &Repository,
+    remotes: &[String],
+    opts: StatusOptions<'_>,
+) -> Result<(), anyhow::Error> {
+    let names = if remotes.is_empty() {
+        all_remote_names(repo)?
+    } else {
+        let mut names = Vec::new();
+        for r in remotes.iter() {
+            names.extend(expand_remote_group(repo, r)?.into_iter())
+        }
+        names
+    };
+    let mut txn = repo.pristine.mut_txn_begin()?;
+    let channel_name = if let Some(c) = opts.channel {
+        c.to_string()
+    } else {
+        txn.current_channel()
+            .unwrap_or(crate::DEFAULT_CHANNEL)
+            .to_string()
+    };
+    let channel = if let Some(channel) = txn.load_channel(&channel_name)? {
+        channel
+    } else {
+        bail!("No such channel: {:?}", channel_name)
+    };
+
+    let mut statuses = Vec::new();
+    for name in names.iter() {
+        let url = if let Some(r) = repo.config.remotes.get(name) {
+            r.with_dir(Direction::Pull)
+        } else {
+            name
+        };
+        let mut error = None;
+        let mut unrecorded = HashMap::new();
+        if opts.refresh {
+            let before = cached_changes(&txn, url)?;
+            let result = async {
+                let mut remote = repo
+                    .remote(
+                        Some(&repo.path),
+                        name,
+                        opts.from_channel,
+                        Direction::Pull,
+                        opts.no_cert_check,
+                        true,
+                    )
+                    .await?;
+                remote.refresh_changelist(&mut txn).await?;
+                remote.finish().await?;
+                Ok::<_, anyhow::Error>(())
+            }
+            .await;
+            if let Err(e) = result {
+                error = Some(e.to_string())
+            }
+            // The entries removed from a cache by the refresh are the
+            // changes unrecorded by the remote.
+            let after = cached_changes(&txn, url)?;
+            for (id, old) in before {
+                if let Some(new) = after.get(&id) {
+                    let mut u = Vec::new();
+                    for h in old.difference(new) {
+                        if txn.get_revchanges(&channel, h)?.is_some() {
+                            u.push(h.to_base32())
+                        }
+                    }
+                    u.sort();
+                    unrecorded.insert(id, u);
+                }
+            }
+        }
+        let caches = cached_remotes(&txn, url)?;
+        if caches.is_empty() {
+            statuses.push(RemoteStatus {
+                remote: name.clone(),
+                url: url.to_string(),
+                id: None,
+                ahead: 0,
+                behind: 0,
+                paths: Vec::new(),
+                unrecorded: Vec::new(),
+                error: Some(
+                    error.unwrap_or_else(|| "No cached changelist, run `pijul fetch`".to_string()),
+                ),
+            });
+            continue;
+        }
+        for remote in caches {
+            let changes = remote_changes(&txn, &remote)?;
+            let mut behind = 0;
+            for h in changes.iter() {
+                if txn.get_revchanges(&channel, h)?.is_none() {
+                    behind += 1
+                }
+            }
+            let mut ahead = 0;
+            for x in txn.log(&*channel.read(), 0)? {
+                let (_, (h, _)) = x?;
+                let h: Hash = h.into();
+                if !changes.contains(&h) {
+                    ahead += 1
+                }
+            }
+            debug!(
+                "{:?} {:?}: ahead {}, behind {}",
+                name,
+                remote.id(),
+                ahead,
+                behind
+            );
+            statuses.push(RemoteStatus {
+                remote: name.clone(),
+                url: url.to_string(),
+                id: Some(remote.id().to_string()),
+                ahead,
+                behind,
+                paths: txn.remote_paths(remote.id())?,
+                unrecorded: unrecorded.remove(remote.id()).unwrap_or_default(),
+                error: error.clone(),
+            })
+        }
+    }
+    if opts.refresh {
+        txn.commit()?;
+    }
+
+    let mut stdout = std::io::stdout();
+    if opts.json {
+        serde_json::to_writer_pretty(&mut stdout, &statuses)?;
+        writeln!(stdout)?;
+        return Ok(());
+    }
+    let width = statuses
+        .iter()
+        .map(|s| s.remote.len())
+        .max()
+        .unwrap_or(0)
+        .max("REMOTE".len());
+    writeln!(
+        stdout,
+        "{:width$}  {:>6}  {:>6}  {:>10}",
+        "REMOTE",
+        "AHEAD",
+        "BEHIND",
+        "UNRECORDED",
+        width = width
+    )?;
+    for s in statuses.iter() {
+        if let Some(ref e) = s.error {
+            if s.id.is_none() {
+                writeln!(stdout, "{:width$}  {}", s.remote, e, width = width)?;
+                continue;
+            }
+        }
+        write!(
+            stdout,
+            "{:width$}  {:>6}  {:>6}  {:>10}",
+            s.remote,
+            s.ahead,
+            s.behind,
+            s.unrecorded.len(),
+            width = width
+        )?;
+        if !s.paths.is_empty() {
+            write!(stdout, "  (paths: {})", s.paths.join(", "))?;
+        }
+        if let Some(ref e) = s.error {
+            write!(stdout, "  (cached, {})", e)?;
+        }
+        writeln!(stdout)?;
+    }
+    for s in statuses.iter() {
+        if !s.unrecorded.is_empty() {
+            writeln!(
+                stdout,
+                "\nChanges of channel {:?} unrecorded by {}:",
+                channel_name, s.remote
+            )?;
+            for h in s.unrecorded.iter() {
+                writeln!(stdout, "  {}", h)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The cached changelists of the remote at `url`, for all its
+/// channels.
+fn cached_remotes(
+    txn: &MutTxn<()>,
+    url: &str,
+) -> Result<Vec<RemoteRef<MutTxn<()>>>, anyhow::Error> {
+    let mut result = Vec::new();
+    for r in txn.iter_remotes(&RemoteId::nil())? {
+        let r = r?;
+        if r.lock().path.as_str() == url {
+            result.push(r)
+        }
+    }
+    Ok(result)
+}
+
+/// The changes of the cached changelists of the remote at `url`.
+fn cached_changes(
+    txn: &MutTxn<()>,
+    url: &str,
+) -> Result<HashMap<RemoteId, HashSet<Hash>>, anyhow::Error> {
+    let mut result = HashMap::new();
+    for remote in cached_remotes(txn, url)? {
+        result.insert(*remote.id(), remote_changes(txn, &remote)?);
+    }
+    Ok(result)
+}
+
+fn remote_changes(
+    txn: &MutTxn<()>,
+    remote: &RemoteRef<MutTxn<()>>,
+) -> Result<HashSet<Hash>, anyhow::Error> {
+    let mut changes = HashSet::new();
+    for x in txn.iter_remote(&remote.lock().remote, 0)? {
+        let (_, p) = x?;
+        changes.insert(p.a.into());
+    }
+    Ok(changes)
+}
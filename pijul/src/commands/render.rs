@@ -0,0 +1,465 @@
+//! A rendered view of a change, for `pijul change`. Unlike the text
+//! format of changes, which is meant to be read back by Pijul, this
+//! groups the hunks by file, and only shows the lines added and
+//! deleted, wrapped to the width of the terminal, or serialized in
+//! JSON for review tools.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::ops::Range;
+
+use libpijul::change::{get_change_contents, Atom, ChangeHeader, Hunk, Local};
+use libpijul::changestore::ChangeStore;
+use libpijul::{Base32, EdgeFlags, Hash};
+use serde_derive::Serialize;
+use termcolor::{Color, ColorSpec, WriteColor};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineKind {
+    Added,
+    Deleted,
+}
+
+impl LineKind {
+    fn prefix(&self) -> &'static str {
+        match self {
+            LineKind::Added => "+",
+            LineKind::Deleted => "-",
+        }
+    }
+    fn color(&self) -> Color {
+        match self {
+            LineKind::Added => Color::Green,
+            LineKind::Deleted => Color::Red,
+        }
+    }
+}
+
+struct RenderedHunk {
+    /// Number of the hunk in the text format, from 1.
+    index: usize,
+    status: &'static str,
+    title: String,
+    /// Lines of the file before and after the change, from 1, for
+    /// the hunks on the contents of files.
+    old: Option<Range<usize>>,
+    new: Option<Range<usize>>,
+    flags: BTreeSet<&'static str>,
+    lines: Vec<(LineKind, String)>,
+}
+
+/// The hunks of a change touching a single file.
+pub struct FileSection {
+    pub path: String,
+    /// Short codes of the operations on this file, as in `pijul diff
+    /// --short`.
+    pub status: BTreeSet<&'static str>,
+    pub added: usize,
+    pub deleted: usize,
+    hunks: Vec<RenderedHunk>,
+    /// Lines added minus lines deleted by the hunks so far, to
+    /// compute the lines of the next hunks in the old file.
+    offset: isize,
+}
+
+impl FileSection {
+    pub fn status_codes(&self) -> String {
+        let s: Vec<_> = self.status.iter().cloned().collect();
+        s.join(",")
+    }
+}
+
+/// Group the hunks of `change` by file, sorted by path. The root
+/// hunks, which don't touch any file, are skipped.
+pub fn sections<C: ChangeStore>(
+    changes: &C,
+    change: &libpijul::change::Change,
+) -> Result<Vec<FileSection>, anyhow::Error> {
+    let mut sections = BTreeMap::new();
+    for (index, hunk) in change.changes.iter().enumerate() {
+        let status = if let Some(status) = hunk.short_code() {
+            status
+        } else {
+            continue;
+        };
+        let (title, lines) = match hunk {
+            Hunk::FileMove { .. } => ("moved".to_string(), Vec::new()),
+            Hunk::FileDel {
+                contents, encoding, ..
+            } => {
+                let lines = if let Some(c) = contents {
+                    let c = get_change_contents(changes, c, &change.contents)?;
+                    lines(LineKind::Deleted, &c, encoding.as_ref())
+                } else {
+                    Vec::new()
+                };
+                ("deleted".to_string(), lines)
+            }
+            Hunk::FileUndel { .. } => ("undeleted".to_string(), Vec::new()),
+            Hunk::FileAdd {
+                contents, encoding, ..
+            } => {
+                let lines = if let Some(c) = contents {
+                    let c = get_change_contents(changes, c, &change.contents)?;
+                    lines(LineKind::Added, &c, encoding.as_ref())
+                } else {
+                    Vec::new()
+                };
+                ("added".to_string(), lines)
+            }
+            Hunk::SolveNameConflict { .. } => ("solved name conflict".to_string(), Vec::new()),
+            Hunk::UnsolveNameConflict { .. } => ("unsolved name conflict".to_string(), Vec::new()),
+            Hunk::Edit {
+                change: c,
+                local: Local { line, .. },
+                encoding,
+            } => {
+                let kind = match c {
+                    Atom::NewVertex(_) => Some(LineKind::Added),
+                    Atom::EdgeMap(e)
+                        if e.edges
+                            .first()
+                            .map(|e| e.flag.contains(EdgeFlags::DELETED))
+                            .unwrap_or(false) =>
+                    {
+                        Some(LineKind::Deleted)
+                    }
+                    Atom::EdgeMap(_) => None,
+                };
+                let lines = if let Some(kind) = kind {
+                    let c = get_change_contents(changes, c, &change.contents)?;
+                    lines(kind, &c, encoding.as_ref())
+                } else {
+                    Vec::new()
+                };
+                (format!("line {}", line), lines)
+            }
+            Hunk::Replacement {
+                change: c,
+                replacement,
+                local: Local { line, .. },
+                encoding,
+            } => {
+                let del = get_change_contents(changes, c, &change.contents)?;
+                let add = get_change_contents(changes, replacement, &change.contents)?;
+                let mut l = lines(LineKind::Deleted, &del, encoding.as_ref());
+                l.extend(lines(LineKind::Added, &add, encoding.as_ref()));
+                (format!("line {}", line), l)
+            }
+            Hunk::SolveOrderConflict {
+                change: c,
+                local: Local { line, .. },
+            } => {
+                let c = get_change_contents(changes, c, &change.contents)?;
+                let l = lines(LineKind::Added, &c, None);
+                (format!("line {}, solved order conflict", line), l)
+            }
+            Hunk::UnsolveOrderConflict {
+                local: Local { line, .. },
+                ..
+            } => (
+                format!("line {}, unsolved order conflict", line),
+                Vec::new(),
+            ),
+            Hunk::ResurrectZombies {
+                local: Local { line, .. },
+                ..
+            } => (
+                format!("line {}, resurrected zombie lines", line),
+                Vec::new(),
+            ),
+            Hunk::AddRoot { .. } | Hunk::DelRoot { .. } => continue,
+        };
+        let path = hunk.path();
+        let section = sections
+            .entry(path.to_string())
+            .or_insert_with(|| FileSection {
+                path: path.to_string(),
+                status: BTreeSet::new(),
+                added: 0,
+                deleted: 0,
+                hunks: Vec::new(),
+                offset: 0,
+            });
+        section.status.insert(status);
+        let added = lines.iter().filter(|l| l.0 == LineKind::Added).count();
+        let deleted = lines.len() - added;
+        section.added += added;
+        section.deleted += deleted;
+        let (old, new) = match hunk {
+            Hunk::FileAdd { .. } => (None, Some(1..1 + added)),
+            Hunk::FileDel { .. } => (Some(1..1 + deleted), None),
+            Hunk::Edit { local, .. }
+            | Hunk::Replacement { local, .. }
+            | Hunk::SolveOrderConflict { local, .. }
+            | Hunk::UnsolveOrderConflict { local, .. }
+            | Hunk::ResurrectZombies { local, .. } => {
+                let new = local.line;
+                let old = (new as isize - section.offset).max(1) as usize;
+                section.offset += added as isize - deleted as isize;
+                (Some(old..old + deleted), Some(new..new + added))
+            }
+            _ => (None, None),
+        };
+        section.hunks.push(RenderedHunk {
+            index: index + 1,
+            status,
+            title,
+            old,
+            new,
+            flags: hunk.iter().flat_map(atom_flags).collect(),
+            lines,
+        })
+    }
+    Ok(sections.into_iter().map(|(_, s)| s).collect())
+}
+
+/// The names of the flags of the edges of `atom`.
+fn atom_flags(atom: &Atom<Option<Hash>>) -> Vec<&'static str> {
+    const NAMES: [(EdgeFlags, &str); 5] = [
+        (EdgeFlags::BLOCK, "block"),
+        (EdgeFlags::PSEUDO, "pseudo"),
+        (EdgeFlags::FOLDER, "folder"),
+        (EdgeFlags::PARENT, "parent"),
+        (EdgeFlags::DELETED, "deleted"),
+    ];
+    let flags = match atom {
+        Atom::NewVertex(n) => n.flag,
+        Atom::EdgeMap(e) => e.edges.iter().fold(EdgeFlags::empty(), |f, e| f | e.flag),
+    };
+    NAMES
+        .iter()
+        .filter(|(f, _)| flags.contains(*f))
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// A change in JSON, as output by `pijul change --json` and written
+/// in the bundles of `pijul unrecord --keep-changes`.
+#[derive(Debug, Serialize)]
+pub struct JsonChange<'a> {
+    hash: String,
+    header: &'a ChangeHeader,
+    dependencies: Vec<String>,
+    extra_known: Vec<String>,
+    files: Vec<JsonFile<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonFile<'a> {
+    path: &'a str,
+    status: &'a BTreeSet<&'static str>,
+    added: usize,
+    deleted: usize,
+    hunks: Vec<JsonHunk<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonHunk<'a> {
+    index: usize,
+    status: &'static str,
+    title: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old: Option<JsonRange>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new: Option<JsonRange>,
+    flags: &'a BTreeSet<&'static str>,
+    lines: Vec<JsonLine<'a>>,
+}
+
+/// A range of lines, from `start` included to `end` excluded.
+#[derive(Debug, Serialize)]
+struct JsonRange {
+    start: usize,
+    end: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonLine<'a> {
+    kind: &'static str,
+    text: &'a str,
+}
+
+/// The JSON view of `change`, whose sections are `sections`.
+pub fn json<'a>(
+    hash: &Hash,
+    change: &'a libpijul::change::Change,
+    sections: &'a [FileSection],
+) -> JsonChange<'a> {
+    let range = |r: &Option<Range<usize>>| {
+        r.as_ref().map(|r| JsonRange {
+            start: r.start,
+            end: r.end,
+        })
+    };
+    JsonChange {
+        hash: hash.to_base32(),
+        header: &change.header,
+        dependencies: change.dependencies.iter().map(|h| h.to_base32()).collect(),
+        extra_known: change.extra_known.iter().map(|h| h.to_base32()).collect(),
+        files: sections
+            .iter()
+            .map(|s| JsonFile {
+                path: &s.path,
+                status: &s.status,
+                added: s.added,
+                deleted: s.deleted,
+                hunks: s
+                    .hunks
+                    .iter()
+                    .map(|h| JsonHunk {
+                        index: h.index,
+                        status: h.status,
+                        title: &h.title,
+                        old: range(&h.old),
+                        new: range(&h.new),
+                        flags: &h.flags,
+                        lines: h
+                            .lines
+                            .iter()
+                            .map(|(kind, text)| JsonLine {
+                                kind: kind.prefix(),
+                                text,
+                            })
+                            .collect(),
+                    })
+                    .collect(),
+            })
+            .collect(),
+    }
+}
+
+/// Split `contents` into lines. Contents without an encoding are
+/// binary, and shown as a single line with their size.
+fn lines(
+    kind: LineKind,
+    contents: &[u8],
+    encoding: Option<&libpijul::change::Encoding>,
+) -> Vec<(LineKind, String)> {
+    if contents.is_empty() {
+        return Vec::new();
+    }
+    if let Some(encoding) = encoding {
+        encoding
+            .decode(contents)
+            .lines()
+            .map(|l| (kind, l.to_string()))
+            .collect()
+    } else if let Ok(s) = std::str::from_utf8(contents) {
+        s.lines().map(|l| (kind, l.to_string())).collect()
+    } else {
+        vec![(kind, format!("(binary contents, {} bytes)", contents.len()))]
+    }
+}
+
+/// Writes file sections to a terminal.
+pub struct Renderer<W> {
+    pub w: W,
+    pub colors: bool,
+    /// Wrap the lines to this width, if any.
+    pub width: Option<usize>,
+    /// Display names of the authors.
+    pub aliases: libpijul::change::AuthorMap,
+}
+
+impl<W: WriteColor> Renderer<W> {
+    fn set_color(&mut self, spec: &ColorSpec) -> Result<(), std::io::Error> {
+        if self.colors {
+            self.w.set_color(spec)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn reset(&mut self) -> Result<(), std::io::Error> {
+        if self.colors {
+            self.w.reset()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Write the header of a change.
+    pub fn header(
+        &mut self,
+        hash: &Hash,
+        header: &libpijul::change::ChangeHeader,
+    ) -> Result<(), std::io::Error> {
+        use libpijul::Base32;
+        self.set_color(ColorSpec::new().set_fg(Some(Color::Yellow)))?;
+        writeln!(self.w, "Change {}", hash.to_base32())?;
+        self.reset()?;
+        let authors: Vec<_> = header
+            .authors
+            .iter()
+            .map(|a| super::credit::author_name(a, &self.aliases))
+            .collect();
+        writeln!(self.w, "Author: {}", authors.join(", "))?;
+        writeln!(self.w, "Date: {}", header.timestamp.to_rfc2822())?;
+        writeln!(self.w)?;
+        for l in header.message.lines() {
+            writeln!(self.w, "    {}", l)?;
+        }
+        if let Some(ref d) = header.description {
+            writeln!(self.w)?;
+            for l in d.lines() {
+                writeln!(self.w, "    {}", l)?;
+            }
+        }
+        writeln!(self.w)
+    }
+
+    /// Write a section: a folded section only shows its path and the
+    /// number of lines added and deleted.
+    pub fn section(&mut self, section: &FileSection, folded: bool) -> Result<(), std::io::Error> {
+        self.set_color(ColorSpec::new().set_bold(true))?;
+        write!(
+            self.w,
+            "{} {} {}",
+            if folded { "[+]" } else { "[-]" },
+            section.status_codes(),
+            section.path,
+        )?;
+        self.reset()?;
+        writeln!(self.w, " (+{} -{})", section.added, section.deleted)?;
+        if folded {
+            return Ok(());
+        }
+        for hunk in section.hunks.iter() {
+            self.set_color(ColorSpec::new().set_fg(Some(Color::Cyan)))?;
+            writeln!(self.w, "  @ {}", hunk.title)?;
+            self.reset()?;
+            for (kind, line) in hunk.lines.iter() {
+                self.line(*kind, line)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a line, wrapped to the width of the terminal. The
+    /// continuation lines are indented.
+    fn line(&mut self, kind: LineKind, line: &str) -> Result<(), std::io::Error> {
+        self.set_color(ColorSpec::new().set_fg(Some(kind.color())))?;
+        let first = format!("  {} ", kind.prefix());
+        let next = format!("  {}   ", kind.prefix());
+        let chars: Vec<char> = line.chars().collect();
+        let mut start = 0;
+        let mut prefix = &first;
+        loop {
+            let len = if let Some(width) = self.width {
+                width.saturating_sub(prefix.len()).max(1)
+            } else {
+                chars.len()
+            };
+            let end = (start + len).min(chars.len());
+            let chunk: String = chars[start..end].iter().collect();
+            writeln!(self.w, "{}{}", prefix, chunk)?;
+            if end >= chars.len() {
+                break;
+            }
+            start = end;
+            prefix = &next;
+        }
+        self.reset()
+    }
+}
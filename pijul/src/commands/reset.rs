@@ -41,6 +41,12 @@ impl Reset {
     fn reset(self, overwrite_changes: bool) -> Result<(), anyhow::Error> {
         let has_repo_path = self.repo_path.is_some();
         let repo = Repository::find_root(self.repo_path)?;
+        repo.require_working_copy()?;
+        let _lock = if self.dry_run {
+            None
+        } else {
+            Some(repo.lock_working_copy()?)
+        };
         let txn = repo.pristine.arc_txn_begin()?;
 
         let cur = txn
@@ -96,6 +102,9 @@ impl Reset {
             .current_channel()
             .unwrap_or(crate::DEFAULT_CHANNEL)
             .to_string();
+        // Paths with unrecorded changes, which switching channels
+        // keeps unless they differ between the two channels.
+        let mut unrecorded = BTreeSet::new();
         if self.channel.as_deref() == Some(&current_channel) {
             if !overwrite_changes {
                 return Ok(());
@@ -119,13 +128,14 @@ impl Reset {
                     &repo.working_copy,
                     &repo.changes,
                     "",
-                    num_cpus::get(),
+                    crate::jobs::record(),
                 )?;
                 let rec = state.finish();
                 debug!("actions = {:?}", rec.actions);
-                if !rec.actions.is_empty() {
+                if !rec.actions.is_empty() && overwrite_changes {
                     bail!("Cannot change channel, as there are unrecorded changes.")
                 }
+                unrecorded.extend(rec.actions.iter().map(|a| a.path().to_string()));
             }
         }
 
@@ -142,7 +152,7 @@ impl Reset {
                     "",
                     true,
                     Some(last_modified),
-                    num_cpus::get(),
+                    crate::jobs::output(),
                     0,
                 )?;
                 txn.write().touch_channel(&mut *channel.write(), None);
@@ -151,7 +161,8 @@ impl Reset {
             }
             let mut inodes = HashSet::new();
             let mut txn_ = txn.write();
-            if let Some(cur) = txn_.load_channel(&current_channel)? {
+            let cur = txn_.load_channel(&current_channel)?;
+            if let Some(ref cur) = cur {
                 let mut changediff = HashSet::new();
                 let (a, b, s) =
                     libpijul::pristine::last_common_state(&*txn_, &*cur.read(), &*channel.read())?;
@@ -170,9 +181,10 @@ impl Reset {
                 txn_.set_current_channel(c)?
             }
             let mut paths = BTreeSet::new();
+            let mut resolver = libpijul::fs::PathResolver::new(false);
             for pos in inodes.iter() {
                 if let Some((path, _)) =
-                    libpijul::fs::find_path(&repo.changes, &*txn_, &*channel.read(), false, *pos)?
+                    resolver.find_path(&repo.changes, &*txn_, &*channel.read(), *pos)?
                 {
                     paths.insert(path);
                 } else {
@@ -183,6 +195,32 @@ impl Reset {
             if !inodes.is_empty() && paths.is_empty() {
                 paths.insert(String::from(""));
             }
+            if !unrecorded.is_empty() {
+                // The files that differ, with their names in both
+                // channels.
+                let mut differing = paths.clone();
+                if let Some(ref cur) = cur {
+                    let mut resolver = libpijul::fs::PathResolver::new(false);
+                    for pos in inodes.iter() {
+                        if let Some((path, _)) =
+                            resolver.find_path(&repo.changes, &*txn_, &*cur.read(), *pos)?
+                        {
+                            differing.insert(path);
+                        }
+                    }
+                }
+                let overlapping: Vec<_> = unrecorded
+                    .iter()
+                    .filter(|p| differing.iter().any(|d| overlaps(p, d)))
+                    .map(|p| p.as_str())
+                    .collect();
+                if !overlapping.is_empty() {
+                    bail!(
+                        "Cannot change channel, as there are unrecorded changes to files that differ between the two channels: {}",
+                        overlapping.join(", ")
+                    )
+                }
+            }
             let mut last = None;
             PROGRESS
                 .borrow_mut()
@@ -207,7 +245,7 @@ impl Reset {
                         path,
                         true,
                         None,
-                        num_cpus::get(),
+                        crate::jobs::output(),
                         0,
                     )?
                     .into_iter(),
@@ -238,7 +276,7 @@ impl Reset {
                         &path,
                         true,
                         None,
-                        num_cpus::get(),
+                        crate::jobs::output(),
                         0,
                     )?
                     .into_iter(),
@@ -288,6 +326,15 @@ fn changes_after<T: ChannelTxnT + DepsTxnT>(
     Ok(())
 }
 
+/// Whether `a` and `b` are the same path, or one of them is in the
+/// other.
+fn overlaps(a: &str, b: &str) -> bool {
+    let inside = |a: &str, b: &str| {
+        b.is_empty() || (a.starts_with(b) && a.as_bytes().get(b.len()) == Some(&b'/'))
+    };
+    a == b || inside(a, b) || inside(b, a)
+}
+
 fn last_modified<T: ChannelTxnT>(txn: &T, channel: &T::Channel) -> std::time::SystemTime {
     std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(txn.last_modified(channel))
 }
@@ -0,0 +1,117 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use chrono::Utc;
+use clap::Parser;
+use libpijul::change::*;
+use libpijul::changestore::ChangeStore;
+use libpijul::{Base32, MutTxnTExt, TxnT, TxnTExt};
+
+use crate::repository::*;
+
+#[derive(Parser, Debug)]
+pub struct Rollback {
+    /// Set the repository where this command should run. Defaults to the first ancestor of the current directory that contains a `.pijul` directory.
+    #[clap(long = "repository")]
+    repo_path: Option<PathBuf>,
+    /// Roll back the change in this channel instead of the current channel
+    #[clap(long = "channel")]
+    channel: Option<String>,
+    /// Set the message of the new change (defaults to "Rollback of <HASH>")
+    #[clap(short = 'm', long = "message")]
+    message: Option<String>,
+    /// The hash of the change to roll back, or an unambiguous prefix thereof
+    #[clap(value_name = "HASH")]
+    hash: String,
+}
+
+impl Rollback {
+    pub fn run(self) -> Result<(), anyhow::Error> {
+        let mut repo = Repository::find_root(self.repo_path)?;
+        let _lock = repo.lock_working_copy()?;
+        let txn = repo.pristine.arc_txn_begin()?;
+        let cur = txn
+            .read()
+            .current_channel()
+            .unwrap_or(crate::DEFAULT_CHANNEL)
+            .to_string();
+        let channel_name = if let Some(ref c) = self.channel {
+            c
+        } else {
+            cur.as_str()
+        };
+        let is_current_channel = channel_name == cur;
+        let channel = if let Some(channel) = txn.read().load_channel(channel_name)? {
+            channel
+        } else {
+            bail!("Channel {:?} not found", channel_name)
+        };
+        let hash = txn.read().hash_from_prefix(&self.hash)?.0;
+        let header = ChangeHeader {
+            message: self
+                .message
+                .unwrap_or_else(|| format!("Rollback of {}", hash.to_base32())),
+            authors: vec![author()?],
+            description: None,
+            timestamp: Utc::now(),
+        };
+        let (_, key) = super::load_key()?;
+
+        let mut change =
+            libpijul::invert_change(&*txn.read(), &channel, &repo.changes, &hash, header)?;
+        let inverse = repo.changes.save_change(&mut change, |change, hash| {
+            change.unhashed = Some(serde_json::json!({
+                "signature": key.sign_raw(&hash.to_bytes()).unwrap(),
+            }));
+            Ok::<_, anyhow::Error>(())
+        })?;
+
+        // Keep the unrecorded modifications of the working copy.
+        let pending_hash = if is_current_channel {
+            super::pending(txn.clone(), &channel, &mut repo)?
+        } else {
+            None
+        };
+        txn.write()
+            .apply_change(&repo.changes, &mut *channel.write(), &inverse)?;
+        if is_current_channel && !repo.config.bare {
+            let conflicts = libpijul::output::output_repository_no_pending(
+                &repo.working_copy,
+                &repo.changes,
+                &txn,
+                &channel,
+                "",
+                true,
+                None,
+                crate::jobs::output(),
+                0,
+            )?;
+            super::print_conflicts(&conflicts.into_iter().collect::<Vec<_>>())?;
+        }
+        if let Some(h) = pending_hash {
+            txn.write().unrecord(&repo.changes, &channel, &h, 0)?;
+            if cfg!(feature = "keep-changes") {
+                repo.changes.del_change(&h)?;
+            }
+        }
+        txn.commit()?;
+        writeln!(std::io::stdout(), "Hash: {}", inverse.to_base32())?;
+        Ok(())
+    }
+}
+
+/// The author of the new change, from the global public key.
+fn author() -> Result<Author, anyhow::Error> {
+    let mut author = std::collections::BTreeMap::new();
+    if let Some(mut dir) = crate::config::global_config_dir() {
+        dir.push("publickey.json");
+        if let Ok(key) = std::fs::File::open(&dir) {
+            let k: libpijul::key::PublicKey = serde_json::from_reader(key)?;
+            author.insert("key".to_string(), k.key);
+        } else {
+            bail!("No identity configured yet. Please use `pijul key` to create one")
+        }
+    }
+    Ok(Author(author))
+}
@@ -1,13 +1,16 @@
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use crate::commands::record::timestamp_validator;
 use crate::repository::Repository;
 use anyhow::bail;
 use clap::Parser;
-use libpijul::change::ChangeHeader;
-use libpijul::{ArcTxn, Base32, ChannelMutTxnT, ChannelTxnT, MutTxnT, TxnT, TxnTExt};
+use libpijul::change::{AuthorMap, ChangeHeader};
+use libpijul::{
+    ArcTxn, Base32, ChannelMutTxnT, ChannelTxnT, Merkle, MutTxnT, MutTxnTExt, TxnT, TxnTExt,
+};
 use log::*;
+use serde_derive::Serialize;
 
 #[derive(Parser, Debug)]
 pub struct Tag {
@@ -42,6 +45,41 @@ pub enum SubCommand {
         #[clap(long = "timestamp", validator = timestamp_validator)]
         timestamp: Option<i64>,
     },
+    /// List the tags of a channel, latest first.
+    #[clap(name = "list")]
+    List {
+        /// Set the repository where this command should run. Defaults to
+        /// the first ancestor of the current directory that contains a
+        /// `.pijul` directory.
+        #[clap(long = "repository")]
+        repo_path: Option<PathBuf>,
+        /// List the tags of this channel instead of the current channel
+        #[clap(long = "channel")]
+        channel: Option<String>,
+        /// Show the full header of each tag, the number of changes it
+        /// includes, and the channels where it is tagged.
+        #[clap(short = 'v', long = "verbose")]
+        verbose: bool,
+        /// Output the tags in JSON format.
+        #[clap(long = "json")]
+        json: bool,
+    },
+    /// Show the header of a tag, the number of changes it includes,
+    /// and its positions in the channels where it is tagged.
+    #[clap(name = "show")]
+    Show {
+        /// Set the repository where this command should run. Defaults to
+        /// the first ancestor of the current directory that contains a
+        /// `.pijul` directory.
+        #[clap(long = "repository")]
+        repo_path: Option<PathBuf>,
+        /// Output the tag in JSON format.
+        #[clap(long = "json")]
+        json: bool,
+        /// The state of the tag, or an unambiguous prefix thereof.
+        #[clap(value_name = "STATE")]
+        state: String,
+    },
     /// Restore a tag into a new channel.
     #[clap(name = "checkout")]
     Checkout {
@@ -80,6 +118,22 @@ pub enum SubCommand {
         channel: Option<String>,
         tag: String,
     },
+    /// Regenerate the missing tag files of a channel from the changes
+    /// of the channel. This requires all the changes up to the last
+    /// missing tag to be available locally. Tag files that only
+    /// contain a header, as downloaded from a remote, are also
+    /// regenerated in full.
+    #[clap(name = "restore")]
+    Restore {
+        /// Set the repository where this command should run. Defaults to
+        /// the first ancestor of the current directory that contains a
+        /// `.pijul` directory.
+        #[clap(long = "repository")]
+        repo_path: Option<PathBuf>,
+        /// Restore the tags of this channel instead of the current channel
+        #[clap(long = "channel")]
+        channel: Option<String>,
+    },
 }
 
 impl Tag {
@@ -135,19 +189,73 @@ impl Tag {
                 txn.commit()?;
                 writeln!(stdout, "{}", h.to_base32())?;
             }
+            Some(SubCommand::List {
+                repo_path,
+                channel,
+                verbose,
+                json,
+            }) => {
+                let repo = Repository::find_root(repo_path)?;
+                let txn = repo.pristine.txn_begin()?;
+                let channel_name = channel.unwrap_or_else(|| {
+                    txn.current_channel()
+                        .unwrap_or(crate::DEFAULT_CHANNEL)
+                        .to_string()
+                });
+                let channel = if let Some(c) = txn.load_channel(&channel_name)? {
+                    c
+                } else {
+                    bail!("Channel {:?} not found", channel_name)
+                };
+                let mut tags = Vec::new();
+                for t in txn.rev_iter_tags(txn.tags(&*channel.read()), None)? {
+                    let (t, _) = t?;
+                    let (_, m) = txn.get_changes(&channel, (*t).into())?.unwrap();
+                    tags.push(tag_info(&txn, &repo.changes.tag_filename(&m), m)?);
+                }
+                if json {
+                    serde_json::to_writer_pretty(&mut stdout, &tags)?;
+                    writeln!(stdout)?;
+                    return Ok(());
+                }
+                super::pager(repo.config.pager.as_ref());
+                for tag in tags.iter() {
+                    if verbose {
+                        tag.write(&mut stdout, &repo.config.author_aliases)?
+                    } else {
+                        writeln!(
+                            stdout,
+                            "{} {} {}",
+                            tag.state,
+                            tag.header.timestamp.format("%F"),
+                            tag.header.message.lines().next().unwrap_or("")
+                        )?
+                    }
+                }
+            }
+            Some(SubCommand::Show {
+                repo_path,
+                json,
+                state,
+            }) => {
+                let repo = Repository::find_root(repo_path)?;
+                let (h, tag_path) = find_tag(&repo, &state)?;
+                let txn = repo.pristine.txn_begin()?;
+                let tag = tag_info(&txn, &tag_path, h)?;
+                if json {
+                    serde_json::to_writer_pretty(&mut stdout, &tag)?;
+                    writeln!(stdout)?;
+                } else {
+                    tag.write(&mut stdout, &repo.config.author_aliases)?
+                }
+            }
             Some(SubCommand::Checkout {
                 repo_path,
                 mut tag,
                 to_channel,
             }) => {
                 let repo = Repository::find_root(repo_path)?;
-                let mut tag_path = repo.changes_dir.clone();
-                let h = if let Some(h) = libpijul::Merkle::from_base32(tag.as_bytes()) {
-                    libpijul::changestore::filesystem::push_tag_filename(&mut tag_path, &h);
-                    h
-                } else {
-                    super::find_hash(&mut tag_path, &tag)?
-                };
+                let (h, tag_path) = find_tag(&repo, &tag)?;
 
                 let mut txn = repo.pristine.mut_txn_begin()?;
                 tag = h.to_base32();
@@ -166,13 +274,9 @@ impl Tag {
             }
             Some(SubCommand::Reset { repo_path, tag }) => {
                 let repo = Repository::find_root(repo_path)?;
-                let mut tag_path = repo.changes_dir.clone();
-                let h = if let Some(h) = libpijul::Merkle::from_base32(tag.as_bytes()) {
-                    libpijul::changestore::filesystem::push_tag_filename(&mut tag_path, &h);
-                    h
-                } else {
-                    super::find_hash(&mut tag_path, &tag)?
-                };
+                repo.require_working_copy()?;
+                let _lock = repo.lock_working_copy()?;
+                let (h, tag_path) = find_tag(&repo, &tag)?;
 
                 let tag = libpijul::tag::txn::TagTxn::new(&tag_path, &h)?;
                 let txn = libpijul::tag::txn::WithTag {
@@ -190,7 +294,7 @@ impl Tag {
                     "",
                     true,
                     None,
-                    num_cpus::get(),
+                    crate::jobs::output(),
                     0,
                 )?;
                 if let Ok(txn) = std::sync::Arc::try_unwrap(txn.0) {
@@ -204,13 +308,7 @@ impl Tag {
                 tag,
             }) => {
                 let repo = Repository::find_root(repo_path)?;
-                let mut tag_path = repo.changes_dir.clone();
-                let h = if let Some(h) = libpijul::Merkle::from_base32(tag.as_bytes()) {
-                    libpijul::changestore::filesystem::push_tag_filename(&mut tag_path, &h);
-                    h
-                } else {
-                    super::find_hash(&mut tag_path, &tag)?
-                };
+                let (h, _) = find_tag(&repo, &tag)?;
 
                 let mut txn = repo.pristine.mut_txn_begin()?;
                 let channel_name = channel.unwrap_or_else(|| {
@@ -233,6 +331,103 @@ impl Tag {
                 txn.commit()?;
                 writeln!(stdout, "Deleted tag {}", h.to_base32())?;
             }
+            Some(SubCommand::Restore { repo_path, channel }) => {
+                let repo = Repository::find_root(repo_path)?;
+                let mut txn = repo.pristine.mut_txn_begin()?;
+                let channel_name = channel.unwrap_or_else(|| {
+                    txn.current_channel()
+                        .unwrap_or(crate::DEFAULT_CHANNEL)
+                        .to_string()
+                });
+                let channel = if let Some(c) = txn.load_channel(&channel_name)? {
+                    c
+                } else {
+                    bail!("Channel {:?} not found", channel_name)
+                };
+
+                // Find the tags whose file is missing, or only has a
+                // header. Keep the headers of the latter.
+                let mut to_restore = std::collections::BTreeMap::new();
+                for t in txn.iter_tags(txn.tags(&*channel.read()), 0)? {
+                    let (t, _) = t?;
+                    let t: u64 = (*t).into();
+                    let m = if let Some((_, m)) = txn.get_changes(&channel, t)? {
+                        m
+                    } else {
+                        bail!(
+                            "Tag at position {} not found in channel {:?}",
+                            t,
+                            channel_name
+                        )
+                    };
+                    match libpijul::tag::OpenTagFile::open(&repo.changes.tag_filename(&m), &m) {
+                        Ok(f) if f.header.total > f.header.channel => {}
+                        Ok(mut f) => {
+                            to_restore.insert(t, (m, Some(f.header()?)));
+                        }
+                        Err(e) => {
+                            debug!("tag {:?}: {:?}", m, e);
+                            to_restore.insert(t, (m, None));
+                        }
+                    }
+                }
+                let last = if let Some((&last, _)) = to_restore.iter().next_back() {
+                    last
+                } else {
+                    writeln!(stdout, "No tag to restore")?;
+                    return Ok(());
+                };
+
+                let mut log = Vec::new();
+                for x in txn.log(&*channel.read(), 0)? {
+                    let (n, (h, _)) = x?;
+                    if n > last {
+                        break;
+                    }
+                    log.push((n, libpijul::Hash::from(h)))
+                }
+
+                // Replay the log of the channel in a temporary
+                // channel, which is never committed, writing the tag
+                // files along the way.
+                let tmp_name = format!("tag-restore-{}", channel_name);
+                if txn.load_channel(&tmp_name)?.is_some() {
+                    bail!("Channel {:?} already exists", tmp_name)
+                }
+                let tmp = txn.open_or_create_channel(&tmp_name)?;
+                let mut temp_path = repo.changes_dir.clone();
+                temp_path.push("tmp");
+                for (n, h) in log {
+                    if std::fs::metadata(&repo.changes.filename(&h)).is_err() {
+                        bail!("Change {} is missing, cannot restore tags", h.to_base32())
+                    }
+                    txn.apply_change(&repo.changes, &mut *tmp.write(), &h)?;
+                    let (m, header_) = if let Some(x) = to_restore.remove(&n) {
+                        x
+                    } else {
+                        continue;
+                    };
+                    let header_ = if let Some(h) = header_ {
+                        h
+                    } else {
+                        header(None, Some("Restored tag".to_string()), None)?
+                    };
+                    let mut w = std::fs::File::create(&temp_path)?;
+                    let h = libpijul::tag::from_channel(&txn, &tmp_name, &header_, &mut w)?;
+                    if h != m {
+                        std::fs::remove_file(&temp_path)?;
+                        bail!(
+                            "Restored state {} doesn't match tag {}",
+                            h.to_base32(),
+                            m.to_base32()
+                        )
+                    }
+                    let tag_path = repo.changes.top.tag_filename(&m);
+                    std::fs::create_dir_all(tag_path.parent().unwrap())?;
+                    std::fs::rename(&temp_path, &tag_path)?;
+                    writeln!(stdout, "Restored tag {}", m.to_base32())?;
+                }
+            }
             None => {
                 let repo = Repository::find_root(self.repo_path)?;
                 let txn = repo.pristine.txn_begin()?;
@@ -246,20 +441,22 @@ impl Tag {
                 } else {
                     bail!("Channel {:?} not found", channel_name)
                 };
-                let mut tag_path = repo.changes_dir.clone();
                 super::pager(repo.config.pager.as_ref());
                 for t in txn.rev_iter_tags(txn.tags(&*channel.read()), None)? {
                     let (t, _) = t?;
                     let (_, m) = txn.get_changes(&channel, (*t).into())?.unwrap();
-                    libpijul::changestore::filesystem::push_tag_filename(&mut tag_path, &m);
+                    let tag_path = repo.changes.tag_filename(&m);
                     debug!("tag path {:?}", tag_path);
                     let mut f = libpijul::tag::OpenTagFile::open(&tag_path, &m)?;
                     let header = f.header()?;
                     writeln!(stdout, "State {}", m.to_base32())?;
-                    writeln!(stdout, "Author: {:?}", header.authors)?;
+                    writeln!(
+                        stdout,
+                        "Author: {}",
+                        author_names(&header, &repo.config.author_aliases)
+                    )?;
                     writeln!(stdout, "Date: {}", header.timestamp)?;
                     writeln!(stdout, "\n    {}\n", header.message)?;
-                    libpijul::changestore::filesystem::pop_filename(&mut tag_path);
                 }
             }
         }
@@ -267,6 +464,112 @@ impl Tag {
     }
 }
 
+/// A tag, as shown by `pijul tag show` and `pijul tag list`.
+#[derive(Serialize)]
+struct TagInfo {
+    state: String,
+    #[serde(flatten)]
+    header: ChangeHeader,
+    /// The number of changes included in the tag, if it is tagged in
+    /// at least one channel of this repository.
+    changes: Option<usize>,
+    channels: Vec<TagPosition>,
+}
+
+#[derive(Serialize)]
+struct TagPosition {
+    channel: String,
+    position: u64,
+}
+
+/// The state of tag `tag`, given in full or as a prefix, and the path
+/// of its file, which may be in the referenced repository.
+fn find_tag(repo: &Repository, tag: &str) -> Result<(Merkle, PathBuf), anyhow::Error> {
+    let h = if let Some(h) = Merkle::from_base32(tag.as_bytes()) {
+        h
+    } else {
+        match super::find_hash(&mut repo.changes_dir.clone(), tag) {
+            Ok(h) => h,
+            Err(e) => {
+                if let Some(ref reference) = repo.config.reference {
+                    let mut dir = reference.join(libpijul::DOT_DIR);
+                    dir.push(crate::repository::CHANGES_DIR);
+                    super::find_hash(&mut dir, tag)?
+                } else {
+                    return Err(e);
+                }
+            }
+        }
+    };
+    Ok((h, repo.changes.tag_filename(&h)))
+}
+
+fn tag_info<T: TxnT>(txn: &T, tag_path: &Path, m: Merkle) -> Result<TagInfo, anyhow::Error> {
+    let header = libpijul::tag::OpenTagFile::open(tag_path, &m)?.header()?;
+    let mut changes = None;
+    let mut channels = Vec::new();
+    for channel in txn.channels("")? {
+        let channel = channel.read();
+        let n: u64 = if let Some(n) = txn.channel_has_state(txn.states(&*channel), &m.into())? {
+            n.into()
+        } else {
+            continue;
+        };
+        if !txn.is_tagged(txn.tags(&*channel), n)? {
+            continue;
+        }
+        if changes.is_none() {
+            let mut count = 0;
+            for x in txn.log(&*channel, 0)? {
+                if x?.0 > n {
+                    break;
+                }
+                count += 1
+            }
+            changes = Some(count)
+        }
+        channels.push(TagPosition {
+            channel: txn.name(&*channel).to_string(),
+            position: n,
+        })
+    }
+    Ok(TagInfo {
+        state: m.to_base32(),
+        header,
+        changes,
+        channels,
+    })
+}
+
+impl TagInfo {
+    fn write<W: Write>(&self, mut w: W, aliases: &AuthorMap) -> Result<(), anyhow::Error> {
+        writeln!(w, "State {}", self.state)?;
+        writeln!(w, "Author: {}", author_names(&self.header, aliases))?;
+        writeln!(w, "Date: {}", self.header.timestamp)?;
+        if let Some(n) = self.changes {
+            writeln!(w, "Changes: {}", n)?;
+        }
+        for p in self.channels.iter() {
+            writeln!(w, "Channel: {} (position {})", p.channel, p.position)?;
+        }
+        writeln!(w, "\n    {}\n", self.header.message)?;
+        if let Some(ref d) = self.header.description {
+            writeln!(w, "    {}\n", d)?;
+        }
+        Ok(())
+    }
+}
+
+/// The authors of a tag, by their display names.
+fn author_names(header: &ChangeHeader, aliases: &AuthorMap) -> String {
+    let names: Vec<_> = header
+        .authors
+        .iter()
+        .map(|a| super::credit::author_name(a, aliases))
+        .collect();
+    names.join(", ")
+}
+
 fn header(
     author: Option<&str>,
     message: Option<String>,
@@ -310,7 +613,7 @@ fn header(
     }
 }
 
-fn try_record<T: ChannelMutTxnT + TxnT + Send + Sync + 'static>(
+pub(super) fn try_record<T: ChannelMutTxnT + TxnT + Send + Sync + 'static>(
     repo: &mut Repository,
     txn: ArcTxn<T>,
     channel: &str,
@@ -330,7 +633,7 @@ fn try_record<T: ChannelMutTxnT + TxnT + Send + Sync + 'static>(
         &repo.working_copy,
         &repo.changes,
         "",
-        num_cpus::get(),
+        crate::jobs::record(),
     )?;
     let rec = state.finish();
     if !rec.actions.is_empty() {
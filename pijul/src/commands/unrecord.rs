@@ -1,13 +1,17 @@
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
-use super::{make_changelist, parse_changelist};
+use super::edit_changelist;
 use crate::remote::CS;
 use crate::repository::Repository;
 use anyhow::{anyhow, bail};
 use clap::Parser;
 use libpijul::changestore::ChangeStore;
+use libpijul::pristine::sanakirja::MutTxn;
 use libpijul::*;
 use log::debug;
+use serde_derive::{Deserialize, Serialize};
 
 #[derive(Parser, Debug)]
 pub struct Unrecord {
@@ -25,6 +29,20 @@ pub struct Unrecord {
     /// of `unrecord_changes` in your global configuration.
     #[clap(long = "show-changes", value_name = "N", conflicts_with("change-id"))]
     show_changes: Option<usize>,
+    /// Before unrecording, copy the changes (and the tags of the channel that the unrecord removes) to this directory. `pijul apply <DIR>` restores them.
+    #[clap(long = "keep-changes", value_name = "DIR")]
+    keep_changes: Option<PathBuf>,
+    /// Don't unrecord anything, but list all the changes of the channel that would have to be unrecorded along with the given ones (transitively), grouped by file, and the command unrecording all of them.
+    #[clap(long = "explain", requires = "change-id")]
+    explain: bool,
+    /// Only unrecord the parts of the change touching this path (a file or a directory), replacing the change with a new one containing its other parts. The changes depending on it are replaced with new changes too, unless they use the unrecorded parts.
+    #[clap(
+        long = "path",
+        value_name = "PATH",
+        requires = "change-id",
+        conflicts_with_all = &["reset", "explain", "keep-changes"]
+    )]
+    path: Option<PathBuf>,
     /// The hash of a change (unambiguous prefixes are accepted)
     change_id: Vec<String>,
 }
@@ -33,6 +51,7 @@ impl Unrecord {
     pub fn run(self) -> Result<(), anyhow::Error> {
         let mut repo = Repository::find_root(self.repo_path)?;
         debug!("{:?}", repo.config);
+        let _lock = repo.lock_working_copy()?;
         let txn = repo.pristine.arc_txn_begin()?;
         let cur = txn
             .read()
@@ -76,8 +95,10 @@ impl Unrecord {
                 .map(|h| CS::Change((h.unwrap().1).0.into()))
                 .take(number_of_changes)
                 .collect::<Vec<_>>();
-            let o = make_changelist(&repo.changes, &hashes_, "unrecord")?;
-            for h in parse_changelist(&edit::edit_bytes(&o[..])?, &hashes_).iter() {
+            let hash_length = super::hash_length(&repo, None);
+            for h in
+                edit_changelist(&repo.changes, &hashes_, &hashes_, "unrecord", hash_length)?.iter()
+            {
                 if let CS::Change(h) = h {
                     hashes.push((*h, *txn.get_internal(&h.into())?.unwrap()))
                 }
@@ -105,44 +126,59 @@ impl Unrecord {
         }
         debug!("changes: {:?}", changes);
         std::mem::drop(channel_);
+        if let Some(ref path) = self.path {
+            return unrecord_path(&repo, txn, &channel, &changes, path);
+        }
+        if self.explain {
+            let hashes: Vec<_> = changes.iter().map(|c| c.0).collect();
+            return explain(&repo, &*txn.read(), &channel, &hashes);
+        }
         let pending_hash = if self.reset {
             super::pending(txn.clone(), &channel, &mut repo)?
         } else {
             None
         };
-        changes.sort_by(|a, b| b.2.cmp(&a.2));
-        for (hash, change_id, _) in changes {
-            let channel_ = channel.read();
-            let txn_ = txn.read();
-            for p in txn_.iter_revdep(&change_id)? {
-                let (p, d) = p?;
-                if p < &change_id {
-                    continue;
-                } else if p > &change_id {
-                    break;
-                }
-                if txn_.get_changeset(txn_.changes(&channel_), d)?.is_some() {
-                    let dep: Hash = txn_.get_external(d)?.unwrap().into();
-                    if Some(dep) == pending_hash {
-                        bail!(
-                            "Cannot unrecord change {} because unrecorded changes depend on it",
-                            hash.to_base32()
-                        );
-                    } else {
-                        bail!(
-                            "Cannot unrecord change {} because {} depend on it",
-                            hash.to_base32(),
-                            dep.to_base32()
-                        );
-                    }
+        // Check all the dependencies before unrecording anything, so
+        // that the changes can be given in any order.
+        let hashes: Vec<_> = changes.iter().map(|c| c.0).collect();
+        let order = match txn.read().unrecord_order(&*channel.read(), &hashes) {
+            Ok(order) => order,
+            Err(UnrecordOrderError::Blocked { blockers }) => {
+                let mut msg = Vec::new();
+                for b in blockers {
+                    let deps: Vec<_> = b
+                        .dependents
+                        .iter()
+                        .map(|d| {
+                            if Some(*d) == pending_hash {
+                                "unrecorded changes".to_string()
+                            } else {
+                                d.to_base32()
+                            }
+                        })
+                        .collect();
+                    msg.push(format!(
+                        "Cannot unrecord change {} because {} depend on it",
+                        b.change.to_base32(),
+                        deps.join(", ")
+                    ))
                 }
+                bail!(
+                    "{}\nSee `pijul unrecord --explain` for all the changes to unrecord together",
+                    msg.join("\n")
+                )
             }
-            std::mem::drop(channel_);
-            std::mem::drop(txn_);
-            txn.write().unrecord(&repo.changes, &channel, &hash, 0)?;
+            Err(e) => return Err(e.into()),
+        };
+        changes.sort_by(|a, b| b.2.cmp(&a.2));
+        if let Some(ref dir) = self.keep_changes {
+            export_bundle(&repo, &*txn.read(), &*channel.read(), &changes, dir)?;
+        }
+        for hash in order.iter() {
+            txn.write().unrecord(&repo.changes, &channel, hash, 0)?;
         }
 
-        if self.reset && is_current_channel {
+        if self.reset && is_current_channel && !repo.config.bare {
             libpijul::output::output_repository_no_pending(
                 &repo.working_copy,
                 &repo.changes,
@@ -151,7 +187,7 @@ impl Unrecord {
                 "",
                 true,
                 None,
-                num_cpus::get(),
+                crate::jobs::output(),
                 0,
             )?;
         }
@@ -165,3 +201,220 @@ impl Unrecord {
         Ok(())
     }
 }
+
+/// Unrecord the parts of a change touching `path`, and print the new
+/// hashes of the changes replaced in the process.
+fn unrecord_path(
+    repo: &Repository,
+    txn: ArcTxn<MutTxn<()>>,
+    channel: &ChannelRef<MutTxn<()>>,
+    changes: &[(Hash, ChangeId, Option<u64>)],
+    path: &Path,
+) -> Result<(), anyhow::Error> {
+    let hash = if let [(hash, _, _)] = changes {
+        *hash
+    } else {
+        bail!("--path only works with a single change")
+    };
+    let path = repo.relative_path(path)?;
+    if path.is_empty() {
+        bail!("Use `pijul unrecord` without --path to unrecord the whole change")
+    }
+    let (_, key) = super::load_key()?;
+    let public_key = key.public_key().key;
+    let result =
+        txn.write()
+            .unrecord_path(&repo.changes, channel, &hash, &path, 0, |change, hash| {
+                // The changes by other authors can't be signed again, and
+                // are left unsigned.
+                let authored = change
+                    .header
+                    .authors
+                    .iter()
+                    .any(|a| a.0.get("key") == Some(&public_key));
+                if authored {
+                    change.unhashed = Some(serde_json::json!({
+                        "signature": key.sign_raw(&hash.to_bytes()).unwrap(),
+                    }));
+                }
+            })?;
+    txn.commit()?;
+    let mut stdout = std::io::stdout();
+    if let Some(h) = result.replacement {
+        writeln!(stdout, "{} -> {}", hash.to_base32(), h.to_base32())?;
+    } else {
+        writeln!(stdout, "{} unrecorded", hash.to_base32())?;
+    }
+    for (old, new) in result.rewritten.iter() {
+        writeln!(stdout, "{} -> {}", old.to_base32(), new.to_base32())?;
+    }
+    Ok(())
+}
+
+/// Print the changes that have to be unrecorded along with `hashes`,
+/// and the files they touch.
+fn explain<T: TxnT>(
+    repo: &Repository,
+    txn: &T,
+    channel: &ChannelRef<T>,
+    hashes: &[Hash],
+) -> Result<(), anyhow::Error> {
+    let closure = txn.unrecord_closure(&*channel.read(), hashes)?;
+    let mut stdout = std::io::stdout();
+    writeln!(
+        stdout,
+        "Unrecording {} change(s) requires unrecording {} change(s):",
+        hashes.len(),
+        closure.len()
+    )?;
+    let mut paths = libpijul::fs::PathResolver::new(false);
+    let mut files = BTreeMap::new();
+    for d in closure.iter() {
+        if d.depends_on.is_empty() {
+            writeln!(stdout, "  {}", d.change.to_base32())?;
+        } else {
+            let deps: Vec<_> = d.depends_on.iter().map(|h| h.to_base32()).collect();
+            writeln!(
+                stdout,
+                "  {}, depending on {}",
+                d.change.to_base32(),
+                deps.join(", ")
+            )?;
+        }
+        let cid = txn.get_internal(&d.change.into())?.unwrap();
+        for x in txn.iter_rev_touched_files(cid, None)? {
+            let (p, pos) = x?;
+            if p > cid {
+                break;
+            } else if p < cid {
+                continue;
+            }
+            if let Some((path, _)) = paths.find_path(&repo.changes, txn, &*channel.read(), *pos)? {
+                if !path.is_empty() {
+                    files.entry(path).or_insert_with(Vec::new).push(d.change)
+                }
+            }
+        }
+    }
+    if !files.is_empty() {
+        writeln!(stdout, "\nBy file:")?;
+        for (path, hashes) in files {
+            let hashes: Vec<_> = hashes.iter().map(|h| h.to_base32()).collect();
+            let path = libpijul::path::escape(&path);
+            writeln!(stdout, "  {}: {}", path, hashes.join(", "))?;
+        }
+    }
+    let all: Vec<_> = closure.iter().map(|d| d.change.to_base32()).collect();
+    writeln!(stdout, "\nTo unrecord them together:")?;
+    writeln!(stdout, "  pijul unrecord {}", all.join(" "))?;
+    Ok(())
+}
+
+/// The name of the file describing a bundle.
+pub(crate) const BUNDLE_MANIFEST: &str = "bundle.toml";
+
+/// A bundle of unrecorded changes, written by `pijul unrecord
+/// --keep-changes`, along with the change and tag files it lists,
+/// and a JSON view of each change (as output by `pijul change
+/// --json`).
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Bundle {
+    /// The channel the changes were unrecorded from.
+    pub channel: String,
+    /// The changes, in the order in which they were applied to the
+    /// channel.
+    pub changes: Vec<String>,
+    /// The tags removed from the channel by the unrecord.
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+fn export_bundle<T: TxnT>(
+    repo: &Repository,
+    txn: &T,
+    channel: &T::Channel,
+    changes: &[(Hash, ChangeId, Option<u64>)],
+    dir: &Path,
+) -> Result<(), anyhow::Error> {
+    std::fs::create_dir_all(dir)?;
+    let mut bundle = Bundle {
+        channel: txn.name(channel).to_string(),
+        changes: Vec::new(),
+        tags: Vec::new(),
+    };
+    let hashes: Vec<_> = changes.iter().map(|c| CS::Change(c.0)).collect();
+    repo.link_from_reference(&hashes)?;
+    // `changes` is sorted from the latest to the oldest.
+    for (hash, _, _) in changes.iter().rev() {
+        let h = hash.to_base32();
+        std::fs::copy(
+            repo.changes.top.filename(hash),
+            dir.join(format!("{}.change", h)),
+        )?;
+        let change = repo.changes.get_change(hash)?;
+        let sections = super::render::sections(&repo.changes, &change)?;
+        let json = std::fs::File::create(dir.join(format!("{}.json", h)))?;
+        serde_json::to_writer_pretty(json, &super::render::json(hash, &change, &sections))?;
+        bundle.changes.push(h);
+    }
+    // Unrecording a change removes all the tags from its position
+    // onwards.
+    if let Some(n) = changes.iter().filter_map(|c| c.2).min() {
+        for t in txn.iter_tags(txn.tags(channel), n)? {
+            let (_, m) = t?;
+            let m: Merkle = m.a.into();
+            let h = m.to_base32();
+            repo.link_from_reference(&[CS::State(m)])?;
+            let tag = repo.changes.top.tag_filename(&m);
+            if std::fs::metadata(&tag).is_ok() {
+                std::fs::copy(&tag, dir.join(format!("{}.tag", h)))?;
+            }
+            bundle.tags.push(h);
+        }
+    }
+    std::fs::write(dir.join(BUNDLE_MANIFEST), toml::to_string_pretty(&bundle)?)?;
+    debug!("exported {:?} to {:?}", bundle, dir);
+    Ok(())
+}
+
+/// Read a bundle written by `pijul unrecord --keep-changes`, copying
+/// its changes and tags back to the repository, and return the hashes
+/// of the changes (in the order they should be applied) and the tags.
+pub(crate) fn import_bundle(
+    repo: &Repository,
+    dir: &Path,
+) -> Result<(Vec<Hash>, Vec<Merkle>), anyhow::Error> {
+    let bundle: Bundle = toml::from_str(&std::fs::read_to_string(dir.join(BUNDLE_MANIFEST))?)?;
+    let mut hashes = Vec::with_capacity(bundle.changes.len());
+    for h in bundle.changes.iter() {
+        let hash = if let Some(hash) = Hash::from_base32(h.as_bytes()) {
+            hash
+        } else {
+            bail!("Invalid change hash in bundle: {:?}", h)
+        };
+        if !repo.changes.top.has_change(&hash) && !repo.in_reference(&CS::Change(hash)) {
+            let path = dir.join(format!("{}.change", h));
+            let mut change =
+                libpijul::change::Change::deserialize(&path.to_string_lossy(), Some(&hash))?;
+            repo.changes
+                .save_change(&mut change, |_, _| Ok::<_, anyhow::Error>(()))?;
+        }
+        hashes.push(hash)
+    }
+    let mut tags = Vec::with_capacity(bundle.tags.len());
+    for h in bundle.tags.iter() {
+        let m = if let Some(m) = Merkle::from_base32(h.as_bytes()) {
+            m
+        } else {
+            bail!("Invalid tag in bundle: {:?}", h)
+        };
+        let tag = repo.changes.top.tag_filename(&m);
+        let path = dir.join(format!("{}.tag", h));
+        if std::fs::metadata(&tag).is_err() && std::fs::metadata(&path).is_ok() {
+            std::fs::create_dir_all(tag.parent().unwrap())?;
+            std::fs::copy(&path, &tag)?;
+        }
+        tags.push(m)
+    }
+    Ok((hashes, tags))
+}
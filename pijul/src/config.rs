@@ -12,8 +12,88 @@ pub struct Global {
     pub unrecord_changes: Option<usize>,
     pub colors: Option<Choice>,
     pub pager: Option<Choice>,
+    /// Number of characters of the hashes shown by `pijul log`,
+    /// `pijul credit` and in the lists of changes to push, pull or
+    /// unrecord (see [`Config::hash_length`]).
+    pub hash_length: Option<usize>,
+    /// Number of seconds the commands modifying the working copy
+    /// wait for another pijul process to release it (see
+    /// `--lock-timeout`).
+    pub lock_timeout: Option<u64>,
     pub template: Option<Templates>,
     pub ignore_kinds: Option<HashMap<String, Vec<String>>>,
+    #[serde(default)]
+    pub key: KeyConfig,
+    #[serde(default)]
+    pub jobs: JobsConfig,
+    /// Never connect to SSH or HTTP remotes (see `--offline`).
+    #[serde(default)]
+    pub offline: bool,
+    #[serde(default)]
+    pub trust: TrustConfig,
+    #[serde(default)]
+    pub network: NetworkConfig,
+}
+
+/// How the password of the secret key is remembered.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct KeyConfig {
+    /// Store the password in the keychain of the operating system
+    /// (requires the `keychain` feature).
+    #[serde(default)]
+    pub keychain: bool,
+}
+
+/// Number of threads (or concurrent downloads) used by each
+/// operation. The `--jobs` flag overrides all of them.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct JobsConfig {
+    /// Used by operations without a setting of their own.
+    pub default: Option<usize>,
+    pub record: Option<usize>,
+    pub output: Option<usize>,
+    pub download: Option<usize>,
+}
+
+/// Settings of the connections to SSH and HTTP remotes.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    /// Number of seconds between the keepalive messages sent on SSH
+    /// connections to keep them open when idle (0 disables this).
+    pub keepalive: Option<u64>,
+    /// Maximal number of bytes per second downloaded from remotes,
+    /// with an optional K, M or G suffix.
+    pub download_limit: Option<String>,
+    /// Maximal number of bytes per second uploaded to remotes.
+    pub upload_limit: Option<String>,
+    /// Number of seconds to wait for SSH servers to answer requests
+    /// that older servers ignore, such as `capabilities` (defaults to
+    /// 10, 0 waits forever).
+    pub reply_timeout: Option<u64>,
+}
+
+/// What to do with the changes that are not signed by a trusted key
+/// (see `pijul key trust`) when pulling or applying them.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TrustConfig {
+    #[serde(default)]
+    pub policy: TrustPolicy,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TrustPolicy {
+    #[serde(rename = "ignore")]
+    Ignore,
+    #[serde(rename = "warn")]
+    Warn,
+    #[serde(rename = "reject")]
+    Reject,
+}
+
+impl Default for TrustPolicy {
+    fn default() -> Self {
+        TrustPolicy::Ignore
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -38,6 +118,9 @@ pub enum Choice {
 pub struct Templates {
     pub message: Option<PathBuf>,
     pub description: Option<PathBuf>,
+    /// Repository template used by `pijul init` when no `--template`
+    /// is given.
+    pub init: Option<PathBuf>,
 }
 
 pub const GLOBAL_CONFIG_DIR: &str = ".pijulconfig";
@@ -52,47 +135,180 @@ pub fn global_config_dir() -> Option<PathBuf> {
     }
 }
 
+/// The path of the global configuration file: the first existing
+/// file among `<config dir>/pijul/config.toml`,
+/// `$HOME/.config/pijul/config.toml` and `$HOME/.pijulconfig`, or the
+/// first of these paths if none exists.
+pub fn global_config_file() -> Option<PathBuf> {
+    let mut candidates = Vec::new();
+    if let Some(mut dir) = global_config_dir() {
+        dir.push("config.toml");
+        candidates.push(dir)
+    }
+    if let Some(mut dir) = dirs_next::home_dir() {
+        dir.push(".config");
+        dir.push(CONFIG_DIR);
+        dir.push("config.toml");
+        candidates.push(dir)
+    }
+    if let Some(mut dir) = dirs_next::home_dir() {
+        dir.push(GLOBAL_CONFIG_DIR);
+        candidates.push(dir)
+    }
+    if let Some(p) = candidates.iter().find(|p| p.exists()) {
+        return Some(p.clone());
+    }
+    candidates.into_iter().next()
+}
+
 impl Global {
     pub fn load() -> Result<(Global, u64), anyhow::Error> {
-        if let Some(mut dir) = global_config_dir() {
-            dir.push("config.toml");
-            let (s, meta) = std::fs::read(&dir)
-                .and_then(|x| Ok((x, std::fs::metadata(&dir)?)))
-                .or_else(|e| {
-                    // Read from `$HOME/.config/pijul` dir
-                    if let Some(mut dir) = dirs_next::home_dir() {
-                        dir.push(".config");
-                        dir.push(CONFIG_DIR);
-                        dir.push("config.toml");
-                        std::fs::read(&dir).and_then(|x| Ok((x, std::fs::metadata(&dir)?)))
-                    } else {
-                        Err(e.into())
-                    }
-                })
-                .or_else(|e| {
-                    // Read from `$HOME/.pijulconfig`
-                    if let Some(mut dir) = dirs_next::home_dir() {
-                        dir.push(GLOBAL_CONFIG_DIR);
-                        std::fs::read(&dir).and_then(|x| Ok((x, std::fs::metadata(&dir)?)))
-                    } else {
-                        Err(e.into())
-                    }
-                })?;
-            debug!("s = {:?}", s);
-            if let Ok(t) = toml::from_slice(&s) {
+        let path = if let Some(path) = global_config_file() {
+            path
+        } else {
+            bail!("Global configuration file missing")
+        };
+        let s = std::fs::read_to_string(&path)?;
+        let meta = std::fs::metadata(&path)?;
+        debug!("s = {:?}", s);
+        match from_str_warn(&s, &path) {
+            Ok(t) => {
                 let ts = meta
                     .modified()?
                     .duration_since(std::time::SystemTime::UNIX_EPOCH)
                     .unwrap()
                     .as_secs();
                 Ok((t, ts))
+            }
+            Err(e) => bail!("Could not read configuration file at {:?}: {}", path, e),
+        }
+    }
+}
+
+/// Deserialize the configuration in `s`, calling `unknown` with each
+/// key that is not part of the schema, such as `network.foo`.
+fn from_str_unknown<T: serde::de::DeserializeOwned, F: FnMut(String)>(
+    s: &str,
+    mut unknown: F,
+) -> Result<T, toml::de::Error> {
+    let mut d = toml::Deserializer::new(s);
+    let t = serde_ignored::deserialize(&mut d, |path| unknown(key(&path)))?;
+    d.end()?;
+    Ok(t)
+}
+
+fn key(path: &serde_ignored::Path) -> String {
+    use serde_ignored::Path;
+    match *path {
+        Path::Root => String::new(),
+        Path::Seq { parent, index } => format!("{}[{}]", key(parent), index),
+        Path::Map { parent, key: ref k } => {
+            let parent = key(parent);
+            if parent.is_empty() {
+                k.clone()
             } else {
-                bail!("Could not read configuration file at {:?}", dir)
+                format!("{}.{}", parent, k)
             }
-        } else {
-            bail!("Global configuration file missing")
+        }
+        Path::Some { parent }
+        | Path::NewtypeStruct { parent }
+        | Path::NewtypeVariant { parent } => key(parent),
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref WARNED: std::sync::Mutex<std::collections::HashSet<PathBuf>> =
+        Default::default();
+}
+
+/// Deserialize the configuration file at `path`, whose contents are
+/// `s`, with a warning for each unknown key (only the first time this
+/// file is read). Unknown keys are ignored, so that configuration
+/// files remain readable by older and newer versions of Pijul.
+pub fn from_str_warn<T: serde::de::DeserializeOwned>(
+    s: &str,
+    path: &std::path::Path,
+) -> Result<T, toml::de::Error> {
+    let mut unknown = Vec::new();
+    let t = from_str_unknown(s, |k| unknown.push(k))?;
+    if !unknown.is_empty() && WARNED.lock().unwrap().insert(path.to_path_buf()) {
+        let mut stderr = std::io::stderr();
+        for k in unknown.iter() {
+            writeln!(stderr, "Warning: unknown key {} in {:?}", k, path).unwrap_or(());
+        }
+    }
+    Ok(t)
+}
+
+/// Deserialize `value`, failing on unknown keys.
+fn from_value_strict<T: serde::de::DeserializeOwned>(
+    value: &toml::Value,
+) -> Result<T, anyhow::Error> {
+    let mut unknown = Vec::new();
+    // Going through a string gives errors with the key and position.
+    let t = from_str_unknown(&toml::to_string(value)?, |k| unknown.push(k))?;
+    if let Some(k) = unknown.first() {
+        bail!("Unknown key {}", k)
+    }
+    Ok(t)
+}
+
+/// Check that `value` is a valid global configuration, without
+/// unknown keys. Errors name the offending key.
+pub fn validate_global(value: &toml::Value) -> Result<(), anyhow::Error> {
+    let global = from_value_strict::<Global>(value)?;
+    let jobs = [
+        ("jobs.default", global.jobs.default),
+        ("jobs.record", global.jobs.record),
+        ("jobs.output", global.jobs.output),
+        ("jobs.download", global.jobs.download),
+    ];
+    for (key, n) in jobs.iter() {
+        if *n == Some(0) {
+            bail!("Invalid value for {}: at least one job is needed", key)
+        }
+    }
+    let limits = [
+        ("network.download_limit", &global.network.download_limit),
+        ("network.upload_limit", &global.network.upload_limit),
+    ];
+    for (key, limit) in limits.iter() {
+        if let Some(limit) = limit {
+            if let Err(e) = crate::commands::parse_size(limit) {
+                bail!("Invalid value for {}: {}", key, e)
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that `value` is a valid repository configuration, without
+/// unknown keys. Errors name the offending key.
+pub fn validate_repository(value: &toml::Value) -> Result<(), anyhow::Error> {
+    let config = from_value_strict::<Config>(value)?;
+    config.separators()?;
+    config.file_classes(&[])?;
+    let hooks = [
+        ("hooks.record", &config.hooks.record),
+        ("hooks.apply", &config.hooks.apply),
+        ("hooks.validate", &config.hooks.validate),
+    ];
+    for (key, hooks) in hooks.iter() {
+        for hook in hooks.iter() {
+            if let Err(e) = hook.command() {
+                bail!("Invalid hook in {}: {}", key, e)
+            }
+        }
+    }
+    for (group, remotes) in config.remote_groups.iter() {
+        if remotes.is_empty() {
+            bail!(
+                "Invalid value for remote_groups.{}: the group is empty",
+                group
+            )
         }
     }
+    Ok(())
 }
 
 #[derive(Debug, Deserialize, Default)]
@@ -102,11 +318,214 @@ pub struct Config {
     pub extra_dependencies: Vec<String>,
     #[serde(default)]
     pub remotes: HashMap<String, RemoteName>,
+    /// Named groups of remotes, pulled from with `pijul pull @group`.
+    #[serde(default)]
+    pub remote_groups: HashMap<String, Vec<String>>,
+    /// SSH options of the remotes, by remote name (or address), used
+    /// instead of the SSH configuration of the user.
+    #[serde(default)]
+    pub ssh: HashMap<String, SshOptions>,
     #[serde(default)]
     pub hooks: Hooks,
     pub unrecord_changes: Option<usize>,
     pub colors: Option<Choice>,
     pub pager: Option<Choice>,
+    /// Number of characters of the hashes shown by `pijul log`,
+    /// `pijul credit` and in the lists of changes to push, pull or
+    /// unrecord, instead of full hashes. The hashes are made longer
+    /// when needed to keep them unambiguous.
+    pub hash_length: Option<usize>,
+    /// How to traverse the working copy: filesystem boundaries,
+    /// maximum file size and symbolic links.
+    #[serde(default)]
+    pub working_copy: libpijul::working_copy::filesystem::TraversalOptions,
+    /// How files are written to the working copy: atomically or
+    /// not, and when they are flushed to the disk.
+    #[serde(default)]
+    pub output: libpijul::working_copy::filesystem::WriteOptions,
+    /// What `pijul record` does with new lines that look like
+    /// conflict markers.
+    #[serde(default)]
+    pub conflict_markers: ConflictMarkers,
+    /// The repository has no working copy (created with `pijul clone
+    /// --bare`).
+    #[serde(default)]
+    pub bare: bool,
+    /// Another repository on the same machine, whose changes are read
+    /// instead of being copied to this one (set by `pijul clone
+    /// --reference`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<PathBuf>,
+    /// Write new change files in version 7 of the format, where the
+    /// contents are indexed by chunks. Versions of Pijul without
+    /// support for this format can't read these changes, including
+    /// when they download them from this repository.
+    #[serde(default)]
+    pub index_change_contents: bool,
+    /// Separators splitting some files into "lines" when recording
+    /// and diffing them, instead of newlines.
+    #[serde(default)]
+    pub separators: Vec<Separator>,
+    /// Files recorded as binary or as text, regardless of the
+    /// encoding detected from their contents.
+    #[serde(default)]
+    pub file_classes: Vec<FileClassRule>,
+    /// Display names replacing the names or public keys of authors
+    /// in `pijul log`, `pijul credit` and `pijul change`, similar to
+    /// Git's mailmap. The changes themselves are not modified.
+    #[serde(default)]
+    pub author_aliases: libpijul::change::AuthorMap,
+    /// What `pijul apply` does with the working copy.
+    #[serde(default)]
+    pub apply: ApplyConfig,
+    /// Bounds on the cached changelists of the remotes, enforced by
+    /// `pijul optimize remotes`.
+    #[serde(default)]
+    pub remote_cache: RemoteCacheConfig,
+}
+
+/// The `[remote_cache]` section of the repository configuration.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RemoteCacheConfig {
+    /// Maximal number of changes in the cached changelist of a
+    /// remote.
+    pub max_entries: Option<u64>,
+    /// Maximal number of days since a remote was last used.
+    pub max_age_days: Option<u64>,
+}
+
+/// The `[apply]` section of the repository configuration, used for
+/// instance on servers deploying the files of a channel.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ApplyConfig {
+    /// Always update the working copy after applying changes, as with
+    /// `pijul apply --update-working-copy`.
+    #[serde(default)]
+    pub update_working_copy: bool,
+    /// The directory updated instead of the root of the repository,
+    /// which is the only way to update a working copy from a bare
+    /// repository. It is tracked by its own pristine, in
+    /// `.pijul/attached`.
+    pub working_copy: Option<PathBuf>,
+}
+
+/// The `[ssh.<remote>]` sections of the repository configuration, for
+/// instance for deployment machines using a deploy key. These options
+/// override `~/.ssh/config`, but the user and port given in the
+/// address of the remote take precedence.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SshOptions {
+    /// The private key used to authenticate, relative to `~/.ssh` if
+    /// the path is relative. The SSH agent is only used if it has
+    /// this key.
+    pub identity_file: Option<PathBuf>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    /// A host to connect through, as `[user@]host[:port]`. This runs
+    /// `ssh -W <host>:<port> [-p <port>] [user@]host`, and thus needs
+    /// an `ssh` executable.
+    pub proxy_jump: Option<String>,
+    #[serde(default)]
+    pub strict_host_key_checking: HostKeyPolicy,
+}
+
+/// What to do with the unknown host keys of SSH servers. Changed keys
+/// are always refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum HostKeyPolicy {
+    /// Ask whether to learn the key.
+    #[serde(rename = "ask")]
+    Ask,
+    /// Refuse unknown keys.
+    #[serde(rename = "yes")]
+    Yes,
+    /// Learn unknown keys without asking.
+    #[serde(rename = "accept-new")]
+    AcceptNew,
+    /// Accept unknown keys without learning them.
+    #[serde(rename = "no")]
+    No,
+}
+
+impl Default for HostKeyPolicy {
+    fn default() -> Self {
+        HostKeyPolicy::Ask
+    }
+}
+
+/// A record separator (a regular expression) for the files matching
+/// a pattern, in the syntax of `.ignore` files, such as `*.js`. The
+/// first rule matching a file applies.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Separator {
+    pub files: String,
+    pub separator: String,
+}
+
+/// A rule forcing the files matching a pattern, in the syntax of
+/// `.ignore` files, to be recorded as `"binary"` or as `"text"`. The
+/// first rule matching a file applies.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileClassRule {
+    pub files: String,
+    pub class: libpijul::FileClass,
+}
+
+impl Config {
+    /// The separators of the `[[separators]]` sections.
+    pub fn separators(&self) -> Result<libpijul::Separators, anyhow::Error> {
+        let mut separators = libpijul::Separators::default();
+        for s in self.separators.iter() {
+            let mut b = ignore::gitignore::GitignoreBuilder::new("");
+            b.add_line(None, &s.files)?;
+            let files = b.build()?;
+            separators
+                .push(
+                    move |path| files.matched_path_or_any_parents(path, false).is_ignore(),
+                    &s.separator,
+                )
+                .map_err(|e| anyhow::anyhow!("Invalid separator for {:?}: {}", s.files, e))?;
+        }
+        Ok(separators)
+    }
+
+    /// The classes of the `[[file_classes]]` sections, after the
+    /// files matching `binary` (given to `pijul record --binary`).
+    pub fn file_classes(&self, binary: &[String]) -> Result<libpijul::FileClasses, anyhow::Error> {
+        let mut classes = libpijul::FileClasses::default();
+        let rules = binary
+            .iter()
+            .map(|files| (files, libpijul::FileClass::Binary))
+            .chain(self.file_classes.iter().map(|r| (&r.files, r.class)));
+        for (files, class) in rules {
+            let mut b = ignore::gitignore::GitignoreBuilder::new("");
+            b.add_line(None, files)?;
+            let files = b.build()?;
+            classes.push(
+                move |path| files.matched_path_or_any_parents(path, false).is_ignore(),
+                class,
+            );
+        }
+        Ok(classes)
+    }
+}
+
+/// What to do when recording lines that look like conflict markers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum ConflictMarkers {
+    #[serde(rename = "allow")]
+    Allow,
+    #[serde(rename = "warn")]
+    Warn,
+    #[serde(rename = "deny")]
+    Deny,
+}
+
+impl Default for ConflictMarkers {
+    fn default() -> Self {
+        ConflictMarkers::Allow
+    }
 }
 
 #[derive(Debug)]
@@ -183,6 +602,20 @@ pub struct SplitRemote {
 pub struct Hooks {
     #[serde(default)]
     pub record: Vec<HookEntry>,
+    /// Run after changes are applied to a channel of this repository
+    /// by a remote (`pijul push` over SSH), with a JSON summary of
+    /// the applied changes on their standard input. See
+    /// [`crate::notify`].
+    #[serde(default)]
+    pub apply: Vec<HookEntry>,
+    /// URLs to which the same JSON summary is posted.
+    #[serde(default)]
+    pub webhooks: Vec<String>,
+    /// Run on each change pushed to this repository, before it is
+    /// applied. The change is rejected if one of these hooks fails.
+    /// See [`crate::staging`].
+    #[serde(default)]
+    pub validate: Vec<HookEntry>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -195,41 +628,43 @@ struct RawHook {
 }
 
 impl HookEntry {
-    pub fn run(&self) -> Result<(), anyhow::Error> {
-        let (proc, s) = match &self.0 {
+    /// The command of this hook and its name, or `None` if the hook
+    /// is empty.
+    fn command(&self) -> Result<Option<(std::process::Command, String)>, anyhow::Error> {
+        match &self.0 {
             toml::Value::String(ref s) => {
                 if s.is_empty() {
-                    return Ok(());
+                    return Ok(None);
                 }
-                (
-                    if cfg!(target_os = "windows") {
-                        std::process::Command::new("cmd")
-                            .args(&["/C", s])
-                            .output()
-                            .expect("failed to execute process")
-                    } else {
-                        std::process::Command::new(
-                            std::env::var("SHELL").unwrap_or("sh".to_string()),
-                        )
-                        .arg("-c")
-                        .arg(s)
-                        .output()
-                        .expect("failed to execute process")
-                    },
-                    s.clone(),
-                )
+                let cmd = if cfg!(target_os = "windows") {
+                    let mut cmd = std::process::Command::new("cmd");
+                    cmd.args(&["/C", s]);
+                    cmd
+                } else {
+                    let mut cmd = std::process::Command::new(
+                        std::env::var("SHELL").unwrap_or("sh".to_string()),
+                    );
+                    cmd.arg("-c").arg(s);
+                    cmd
+                };
+                Ok(Some((cmd, s.clone())))
             }
             v => {
                 let hook = v.clone().try_into::<RawHook>()?;
-                (
-                    std::process::Command::new(&hook.command)
-                        .args(&hook.args)
-                        .output()
-                        .expect("failed to execute process"),
-                    hook.command,
-                )
+                let mut cmd = std::process::Command::new(&hook.command);
+                cmd.args(&hook.args);
+                Ok(Some((cmd, hook.command)))
             }
+        }
+    }
+
+    pub fn run(&self) -> Result<(), anyhow::Error> {
+        let (mut cmd, s) = if let Some(c) = self.command()? {
+            c
+        } else {
+            return Ok(());
         };
+        let proc = cmd.output().expect("failed to execute process");
         if !proc.status.success() {
             let mut stderr = std::io::stderr();
             writeln!(stderr, "Hook {:?} exited with code {:?}", s, proc.status)?;
@@ -237,6 +672,38 @@ impl HookEntry {
         }
         Ok(())
     }
+
+    /// Run this hook with `input` on its standard input. Unlike
+    /// [`HookEntry::run`], a failure of the hook is returned as an
+    /// error (with the standard error of the hook, if any) instead of
+    /// exiting.
+    pub fn run_with_input(&self, input: &[u8]) -> Result<(), anyhow::Error> {
+        let (mut cmd, s) = if let Some(c) = self.command()? {
+            c
+        } else {
+            return Ok(());
+        };
+        let mut child = cmd
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::piped())
+            .spawn()?;
+        // Hooks are free not to read their input.
+        match child.stdin.take().unwrap().write_all(input) {
+            Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => return Err(e.into()),
+            _ => {}
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            let err = String::from_utf8_lossy(&output.stderr);
+            if err.trim().is_empty() {
+                bail!("Hook {:?} exited with code {:?}", s, output.status)
+            } else {
+                bail!("{}", err.trim())
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
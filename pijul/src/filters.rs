@@ -0,0 +1,90 @@
+//! The `--include` and `--exclude` options of the commands going
+//! through the working copy. They come on top of the ignore files of
+//! the repository: an ignored file stays ignored even if it matches
+//! `--include`.
+
+use std::sync::Arc;
+
+use clap::Parser;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use libpijul::working_copy::PathFilter;
+
+#[derive(Parser, Debug, Default)]
+pub struct FilterOpts {
+    /// Only consider the paths matching this pattern (in the syntax of `.gitignore` files). Can be given several times
+    #[clap(long = "include", value_name = "GLOB")]
+    pub include: Vec<String>,
+    /// Skip the paths matching this pattern (in the syntax of `.gitignore` files), and everything they contain. Can be given several times
+    #[clap(long = "exclude", value_name = "GLOB")]
+    pub exclude: Vec<String>,
+}
+
+impl FilterOpts {
+    /// The matcher for these options, or `None` if there aren't any.
+    pub fn build(&self) -> Result<Option<Filter>, anyhow::Error> {
+        Filter::new(&self.include, &self.exclude)
+    }
+
+    /// Same as [`FilterOpts::build`], in the form expected by
+    /// libpijul.
+    pub fn path_filter(&self) -> Result<Option<PathFilter>, anyhow::Error> {
+        Ok(self.build()?.map(Filter::into_path_filter))
+    }
+}
+
+/// Patterns selecting paths, relative to the root of the repository.
+pub struct Filter {
+    include: Option<Gitignore>,
+    exclude: Gitignore,
+}
+
+impl Filter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Option<Self>, anyhow::Error> {
+        if include.is_empty() && exclude.is_empty() {
+            return Ok(None);
+        }
+        let build = |patterns: &[String]| -> Result<Gitignore, anyhow::Error> {
+            let mut b = GitignoreBuilder::new("");
+            for p in patterns {
+                b.add_line(None, p)?;
+            }
+            Ok(b.build()?)
+        };
+        Ok(Some(Filter {
+            include: if include.is_empty() {
+                None
+            } else {
+                Some(build(include)?)
+            },
+            exclude: build(exclude)?,
+        }))
+    }
+
+    /// Excluded paths never match. Files must also match an include
+    /// pattern if there are any, or be in a directory that does.
+    /// Directories always match unless excluded, since they may
+    /// contain included files: `pijul add` doesn't add the
+    /// directories it walks with a filter, only the parents of the
+    /// files it adds.
+    pub fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self
+            .exclude
+            .matched_path_or_any_parents(path, is_dir)
+            .is_ignore()
+        {
+            return false;
+        }
+        if is_dir {
+            return true;
+        }
+        if let Some(ref include) = self.include {
+            include.matched_path_or_any_parents(path, false).is_ignore()
+        } else {
+            true
+        }
+    }
+
+    pub fn into_path_filter(self) -> PathFilter {
+        Arc::new(move |path, is_dir| self.matches(path, is_dir))
+    }
+}
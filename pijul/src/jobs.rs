@@ -0,0 +1,82 @@
+//! Number of threads (or of concurrent downloads) used by the
+//! different operations, set by the global `--jobs` flag, or else by
+//! the `[jobs]` section of the global configuration.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use lazy_static::lazy_static;
+
+use crate::config::JobsConfig;
+
+/// Number of concurrent downloads when nothing is configured.
+const DEFAULT_DOWNLOADS: usize = 20;
+
+/// The value of `--jobs`, 0 if unset.
+static CLI_JOBS: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref CONFIG: JobsConfig = match crate::config::Global::load() {
+        Ok((global, _)) => global.jobs,
+        Err(_) => JobsConfig::default(),
+    };
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Operation {
+    /// Recording changes and traversing the working copy.
+    Record,
+    /// Outputting channels to the working copy.
+    Output,
+    /// Downloading changes and tags from remotes.
+    Download,
+    /// Anything else done in parallel.
+    Other,
+}
+
+/// Set the number of jobs given on the command line, which overrides
+/// the configuration for all operations.
+pub fn set(jobs: Option<usize>) {
+    CLI_JOBS.store(jobs.unwrap_or(0), Ordering::Relaxed)
+}
+
+/// The number of jobs explicitly set for `op`, if any.
+pub fn configured(op: Operation) -> Option<usize> {
+    let cli = CLI_JOBS.load(Ordering::Relaxed);
+    let n = if cli > 0 {
+        Some(cli)
+    } else {
+        let op_config = match op {
+            Operation::Record => CONFIG.record,
+            Operation::Output => CONFIG.output,
+            Operation::Download => CONFIG.download,
+            Operation::Other => None,
+        };
+        op_config.or(CONFIG.default)
+    };
+    n.map(|n| n.max(1))
+}
+
+/// The number of jobs for `op`, defaulting to the number of CPUs
+/// (or to 20 concurrent downloads).
+pub fn get(op: Operation) -> usize {
+    configured(op).unwrap_or_else(|| match op {
+        Operation::Download => DEFAULT_DOWNLOADS,
+        _ => num_cpus::get(),
+    })
+}
+
+pub fn record() -> usize {
+    get(Operation::Record)
+}
+
+pub fn output() -> usize {
+    get(Operation::Output)
+}
+
+pub fn download() -> usize {
+    get(Operation::Download)
+}
+
+pub fn other() -> usize {
+    get(Operation::Other)
+}
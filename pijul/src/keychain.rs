@@ -0,0 +1,65 @@
+//! Passwords of encrypted secret keys, saved with the `keychain`
+//! feature in the keychain of the operating system (macOS Keychain,
+//! Windows Credential Manager or the Secret Service on Linux), so
+//! that using the same key several times doesn't prompt for its
+//! password every time.
+//!
+//! Passwords are indexed by the encrypted key itself, so that a key
+//! generated or re-encrypted since never gets an outdated password.
+
+use libpijul::key::SecretKey;
+use log::debug;
+
+use crate::config::KeyConfig;
+
+#[cfg(feature = "keychain")]
+const SERVICE: &str = "pijul";
+
+/// Find the password of `key` in the keychain, if enabled.
+pub fn get(key: &SecretKey, config: &KeyConfig) -> Option<String> {
+    if config.keychain {
+        if let Some(pass) = keychain_get(key) {
+            debug!("key password found in the keychain");
+            return Some(pass);
+        }
+    }
+    None
+}
+
+/// Remember the password of `key`, after it was successfully used.
+pub fn remember(key: &SecretKey, config: &KeyConfig, password: &str) {
+    if config.keychain {
+        keychain_set(key, password)
+    }
+}
+
+#[cfg(feature = "keychain")]
+fn keychain_get(key: &SecretKey) -> Option<String> {
+    match keyring::Entry::new(SERVICE, &key.key).get_password() {
+        Ok(pass) => Some(pass),
+        Err(keyring::Error::NoEntry) => None,
+        Err(e) => {
+            log::warn!("Could not read the keychain: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(feature = "keychain")]
+fn keychain_set(key: &SecretKey, password: &str) {
+    if let Err(e) = keyring::Entry::new(SERVICE, &key.key).set_password(password) {
+        log::warn!("Could not save the key password in the keychain: {}", e)
+    }
+}
+
+#[cfg(not(feature = "keychain"))]
+fn keychain_get(_: &SecretKey) -> Option<String> {
+    static WARN: std::sync::Once = std::sync::Once::new();
+    WARN.call_once(|| {
+        log::warn!("`key.keychain` is set, but Pijul was compiled without the `keychain` feature")
+    });
+    None
+}
+
+#[cfg(not(feature = "keychain"))]
+fn keychain_set(_: &SecretKey, _: &str) {}
@@ -0,0 +1,126 @@
+//! An advisory lock on the working copy, taken by the commands
+//! writing to it (`record`, `pull`, `reset`…), so that two Pijul
+//! processes don't interleave their writes. This lock is a file in
+//! the `.pijul` directory, separate from the lock of the pristine:
+//! commands only reading the repository don't take it, and a process
+//! waiting for it doesn't hold the pristine.
+//!
+//! A command finding the lock taken waits for it to be released, for
+//! at most the number of seconds given by the global `--lock-timeout`
+//! flag, or by `lock_timeout` in the global configuration (60 by
+//! default, 0 fails immediately).
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use fs2::FileExt;
+use lazy_static::lazy_static;
+use log::debug;
+use thiserror::Error;
+
+pub const LOCK_FILE: &str = "lock";
+
+const DEFAULT_TIMEOUT: u64 = 60;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The timeout given by `--lock-timeout`, or `u64::MAX` if it wasn't
+/// given.
+static CLI_TIMEOUT: AtomicU64 = AtomicU64::new(u64::MAX);
+
+lazy_static! {
+    static ref CONFIG: Option<u64> = match crate::config::Global::load() {
+        Ok((global, _)) => global.lock_timeout,
+        Err(_) => None,
+    };
+}
+
+#[derive(Debug, Error)]
+#[error("Another pijul operation is in progress{}", .pid.map(|pid| format!(" (pid {})", pid)).unwrap_or_default())]
+pub struct LockedError {
+    /// The process holding the lock, if it could be read from the
+    /// lock file.
+    pub pid: Option<u32>,
+}
+
+/// Set the timeout from the command line. If `timeout` is `None`,
+/// the global configuration decides.
+pub fn set_timeout(timeout: Option<u64>) {
+    if let Some(timeout) = timeout {
+        CLI_TIMEOUT.store(timeout, Ordering::Relaxed)
+    }
+}
+
+fn timeout() -> Duration {
+    let t = CLI_TIMEOUT.load(Ordering::Relaxed);
+    Duration::from_secs(if t != u64::MAX {
+        t
+    } else {
+        CONFIG.unwrap_or(DEFAULT_TIMEOUT)
+    })
+}
+
+/// The lock of a working copy, released when dropped.
+pub struct WorkingCopyLock {
+    file: File,
+}
+
+impl WorkingCopyLock {
+    /// Lock the working copy of the repository whose `.pijul`
+    /// directory is `dot_dir`, waiting for the process holding the
+    /// lock, if any, for at most the configured timeout.
+    pub fn acquire(dot_dir: &Path) -> Result<Self, anyhow::Error> {
+        let path = dot_dir.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)?;
+        let timeout = timeout();
+        let start = Instant::now();
+        let mut waiting = false;
+        loop {
+            match file.try_lock_exclusive() {
+                Ok(()) => break,
+                Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {}
+                Err(e) => return Err(e.into()),
+            }
+            let pid = read_pid(&mut file);
+            debug!("{:?} is locked by {:?}", path, pid);
+            if start.elapsed() >= timeout {
+                return Err(LockedError { pid }.into());
+            }
+            if !waiting {
+                eprintln!("{}, waiting for it to finish", LockedError { pid });
+                waiting = true
+            }
+            std::thread::sleep(POLL_INTERVAL)
+        }
+        // Record our pid, for the error messages of other processes.
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", std::process::id())?;
+        file.flush()?;
+        Ok(WorkingCopyLock { file })
+    }
+}
+
+/// The pid written in a lock file. On platforms where locks are
+/// mandatory, the file can't be read while locked, and this returns
+/// `None`.
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut s = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut s).ok()?;
+    s.trim().parse().ok()
+}
+
+impl Drop for WorkingCopyLock {
+    fn drop(&mut self) {
+        self.file.set_len(0).unwrap_or(());
+        self.file.unlock().unwrap_or(())
+    }
+}
@@ -1,8 +1,17 @@
 mod commands;
 mod config;
+mod filters;
+mod jobs;
+mod keychain;
+mod lock;
+mod notify;
+mod offline;
 mod progress;
 mod remote;
 mod repository;
+mod staging;
+mod transfer;
+mod trust;
 
 use std::ffi::OsString;
 use std::io::Write;
@@ -21,6 +30,21 @@ const PROTOCOL_VERSION: usize = 3;
 #[derive(Parser, Debug)]
 #[clap(version, author, color(ColorChoice::Auto), infer_subcommands = true)]
 pub struct Opts {
+    /// Number of threads (or concurrent downloads) to use, for all
+    /// operations. Defaults to the `[jobs]` section of the global
+    /// configuration, or else to the number of CPUs.
+    #[clap(long = "jobs", short = 'j', global = true)]
+    pub jobs: Option<usize>,
+    /// Never connect to SSH or HTTP remotes, and fail as soon as an
+    /// operation needs one. Also set by `offline = true` in the
+    /// global configuration.
+    #[clap(long = "offline", global = true)]
+    pub offline: bool,
+    /// Number of seconds to wait for another pijul process modifying
+    /// the working copy to finish, before failing. Defaults to
+    /// `lock_timeout` in the global configuration, or else to 60.
+    #[clap(long = "lock-timeout", global = true, value_name = "SECONDS")]
+    pub lock_timeout: Option<u64>,
     #[clap(subcommand)]
     pub subcmd: SubCommand,
 }
@@ -48,9 +72,25 @@ pub enum SubCommand {
     /// Pulls changes from a remote upstream
     Pull(Pull),
 
+    /// Refreshes the cached changelists of remotes without pulling
+    /// anything, so that later pulls are mostly local
+    Fetch(Fetch),
+
+    /// Publishes the channels of the repository as static files, which
+    /// any HTTP file server can serve to `pijul clone` and `pijul pull`
+    Publish(Publish),
+
     /// Shows information about a particular change
     Change(Change),
 
+    /// Shows the dependencies of a change, or with `--reverse` the
+    /// changes depending on it
+    Deps(Deps),
+
+    /// Lists the messages of the changes between two states of a
+    /// channel (usually two tags), as release notes in Markdown
+    Changelog(Changelog),
+
     /// Manages different channels
     Channel(Channel),
 
@@ -112,6 +152,13 @@ pub enum SubCommand {
     /// Applies changes to a channel
     Apply(Apply),
 
+    /// Rolls back a change, by recording and applying a new change
+    /// undoing it.
+    ///
+    /// Unlike `pijul unrecord`, the change stays in the log, and the
+    /// changes depending on it don't need to be removed.
+    Rollback(Rollback),
+
     /// Manages remote repositories
     Remote(Remote),
 
@@ -121,6 +168,10 @@ pub enum SubCommand {
     /// Shows which change last affected each line of the given file(s)
     Credit(Credit),
 
+    /// Searches tracked files, or with `--history` the lines added
+    /// and deleted by all changes of a channel, for a regular expression
+    Grep(Grep),
+
     /// Manage tags (create tags, check out a tag)
     Tag(Tag),
 
@@ -130,6 +181,19 @@ pub enum SubCommand {
     /// can be found in the `Keys` section of the manual.
     Key(Key),
 
+    /// Reads and edits the configuration of the repository, or with
+    /// `--global` the global configuration
+    Config(Config),
+
+    /// Rewrites the files of the repository to make them faster to
+    /// read, for instance with `pijul optimize changes --reindex`
+    Optimize(Optimize),
+
+    /// Finds the first change of a channel that introduced a bug, by
+    /// testing states of the channel in a scratch channel, manually
+    /// or with `pijul bisect run`
+    Bisect(Bisect),
+
     #[clap(external_subcommand)]
     ExternalSubcommand(Vec<OsString>),
 }
@@ -139,9 +203,16 @@ async fn main() {
     setup_panic!();
     env_logger_init();
     let opts = Opts::parse();
+    jobs::set(opts.jobs);
+    offline::set(opts.offline);
+    lock::set_timeout(opts.lock_timeout);
 
     if let Err(e) = run(opts).await {
         log::debug!("{:?}", e);
+        if let Some(e) = e.downcast_ref::<commands::ConflictError>() {
+            writeln!(std::io::stderr(), "Error: {}", e).unwrap_or(());
+            std::process::exit(commands::CONFLICT_EXIT_CODE);
+        }
         match e.downcast::<std::io::Error>() {
             Ok(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
             Ok(e) => writeln!(std::io::stderr(), "Error: {}", e).unwrap_or(()),
@@ -226,16 +297,20 @@ fn report_external_command_error(cmd: &OsString, err: std::io::Error) -> ! {
 
 async fn run(opts: Opts) -> Result<(), anyhow::Error> {
     match opts.subcmd {
-        SubCommand::Log(l) => l.run(),
+        SubCommand::Log(l) => l.run().await,
         SubCommand::Init(init) => init.run(),
         SubCommand::Clone(clone) => clone.run().await,
         SubCommand::Record(record) => record.run(),
         SubCommand::Diff(diff) => diff.run(),
         SubCommand::Push(push) => push.run().await,
         SubCommand::Pull(pull) => pull.run().await,
+        SubCommand::Fetch(fetch) => fetch.run().await,
+        SubCommand::Publish(publish) => publish.run(),
         SubCommand::Change(change) => change.run(),
-        SubCommand::Channel(channel) => channel.run(),
-        SubCommand::Protocol(protocol) => protocol.run(),
+        SubCommand::Deps(deps) => deps.run(),
+        SubCommand::Changelog(c) => c.run(),
+        SubCommand::Channel(channel) => channel.run().await,
+        SubCommand::Protocol(protocol) => protocol.run().await,
         #[cfg(feature = "git")]
         SubCommand::Git(git) => git.run(),
         SubCommand::Move(move_cmd) => move_cmd.run(),
@@ -248,11 +323,16 @@ async fn run(opts: Opts) -> Result<(), anyhow::Error> {
         SubCommand::Fork(fork) => fork.run(),
         SubCommand::Unrecord(unrecord) => unrecord.run(),
         SubCommand::Apply(apply) => apply.run(),
-        SubCommand::Remote(remote) => remote.run(),
+        SubCommand::Rollback(rollback) => rollback.run(),
+        SubCommand::Remote(remote) => remote.run().await,
         SubCommand::Archive(archive) => archive.run().await,
         SubCommand::Credit(credit) => credit.run(),
+        SubCommand::Grep(grep) => grep.run(),
         SubCommand::Tag(tag) => tag.run(),
         SubCommand::Key(key) => key.run().await,
+        SubCommand::Config(config) => config.run(),
+        SubCommand::Optimize(optimize) => optimize.run(),
+        SubCommand::Bisect(bisect) => bisect.run(),
         SubCommand::ExternalSubcommand(command) => Ok(run_external_command(command)?),
     }
 }
@@ -0,0 +1,129 @@
+//! Notifications sent after changes are applied to a repository by a
+//! remote, for server deployments. The hooks and webhooks are set in
+//! the `[hooks]` section of `.pijul/config`:
+//!
+//! ```toml
+//! [hooks]
+//! apply = [ "sendmail maintainers@example.org" ]
+//! webhooks = [ "https://example.org/pijul-hook" ]
+//! ```
+//!
+//! Each `apply` hook gets an [`ApplySummary`] as JSON on its standard
+//! input, and the same JSON is posted to each webhook. Failures are
+//! reported on the standard error, but don't fail the application of
+//! the changes, which is already committed when the notifications are
+//! sent.
+
+use std::collections::BTreeSet;
+
+use libpijul::change::{Author, Change};
+use libpijul::changestore::ChangeStore;
+use libpijul::{Base32, Hash, Merkle};
+use log::{debug, warn};
+use serde_derive::Serialize;
+
+use crate::repository::Repository;
+
+/// What was applied to one channel.
+#[derive(Debug, Serialize)]
+pub struct ApplySummary {
+    pub channel: String,
+    /// The state of the channel after the changes were applied.
+    pub state: String,
+    pub changes: Vec<ChangeSummary>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChangeSummary {
+    pub hash: String,
+    pub message: String,
+    pub authors: Vec<Author>,
+    /// The paths of the files added, deleted, moved or edited by the
+    /// change.
+    pub files: BTreeSet<String>,
+}
+
+impl ChangeSummary {
+    /// The summary of a change loaded in memory.
+    pub fn from_change(hash: &Hash, change: &Change) -> Self {
+        ChangeSummary {
+            hash: hash.to_base32(),
+            message: change.hashed.header.message.clone(),
+            authors: change.hashed.header.authors.clone(),
+            files: change
+                .hashed
+                .changes
+                .iter()
+                .map(|hunk| hunk.path().to_string())
+                .collect(),
+        }
+    }
+}
+
+impl ApplySummary {
+    pub fn new(
+        repo: &Repository,
+        channel: &str,
+        state: Merkle,
+        hashes: &[Hash],
+    ) -> Result<Self, anyhow::Error> {
+        let mut changes = Vec::with_capacity(hashes.len());
+        for h in hashes {
+            let header = repo.changes.get_header(h)?;
+            let mut files = BTreeSet::new();
+            for hunk in repo.changes.iter_hunks(h)? {
+                files.insert(hunk?.path().to_string());
+            }
+            changes.push(ChangeSummary {
+                hash: h.to_base32(),
+                message: header.message,
+                authors: header.authors,
+                files,
+            })
+        }
+        Ok(ApplySummary {
+            channel: channel.to_string(),
+            state: state.to_base32(),
+            changes,
+        })
+    }
+}
+
+/// Whether any notification is configured in `repo`.
+pub fn is_configured(repo: &Repository) -> bool {
+    !repo.config.hooks.apply.is_empty() || !repo.config.hooks.webhooks.is_empty()
+}
+
+/// Send `summary` to the hooks and webhooks of `repo`.
+pub async fn dispatch(repo: &Repository, summary: &ApplySummary) -> Result<(), anyhow::Error> {
+    let json = serde_json::to_vec(summary)?;
+    for hook in repo.config.hooks.apply.iter() {
+        if let Err(e) = hook.run_with_input(&json) {
+            warn!("apply hook failed: {}", e);
+            eprintln!("Apply hook failed: {}", e)
+        }
+    }
+    if repo.config.hooks.webhooks.is_empty() {
+        return Ok(());
+    }
+    if crate::offline::is_offline() {
+        eprintln!("Not calling the webhooks in offline mode");
+        return Ok(());
+    }
+    let client = reqwest::Client::new();
+    for url in repo.config.hooks.webhooks.iter() {
+        debug!("webhook {:?}", url);
+        let res = client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(json.clone())
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+        if let Err(e) = res {
+            warn!("webhook {:?} failed: {}", url, e);
+            eprintln!("Webhook {:?} failed: {}", url, e)
+        }
+    }
+    Ok(())
+}
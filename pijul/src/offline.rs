@@ -0,0 +1,48 @@
+//! Offline mode, set by the global `--offline` flag or by `offline =
+//! true` in the global configuration. In offline mode, Pijul never
+//! connects to SSH or HTTP remotes, and the operations that need one
+//! fail before trying. Local remotes, and the change stores of other
+//! repositories (see `pijul clone --reference`), still work.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use lazy_static::lazy_static;
+use thiserror::Error;
+
+/// Whether `--offline` was given.
+static CLI_OFFLINE: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref CONFIG: bool = match crate::config::Global::load() {
+        Ok((global, _)) => global.offline,
+        Err(_) => false,
+    };
+}
+
+#[derive(Debug, Error)]
+#[error("Cannot connect to {remote:?} in offline mode (set by `--offline` or by `offline = true` in the global configuration)")]
+pub struct OfflineError {
+    pub remote: String,
+}
+
+/// Set offline mode from the command line. If `offline` is false,
+/// the global configuration decides.
+pub fn set(offline: bool) {
+    CLI_OFFLINE.store(offline, Ordering::Relaxed)
+}
+
+pub fn is_offline() -> bool {
+    CLI_OFFLINE.load(Ordering::Relaxed) || *CONFIG
+}
+
+/// Fail if in offline mode. This must be called before connecting
+/// to any network remote.
+pub fn check(remote: &str) -> Result<(), OfflineError> {
+    if is_offline() {
+        Err(OfflineError {
+            remote: remote.to_string(),
+        })
+    } else {
+        Ok(())
+    }
+}
@@ -122,6 +122,12 @@ pub enum Cursor {
         pre: Cow<'static, str>,
         i: usize,
     },
+    /// The amount of data transferred from or to remotes, and the
+    /// transfer rate.
+    Rate {
+        pre: Cow<'static, str>,
+        transfer: &'static crate::transfer::Transfer,
+    },
 }
 
 impl Cursor {
@@ -130,6 +136,7 @@ impl Cursor {
             Cursor::Static { pre } => pre,
             Cursor::Bar { pre, .. } => pre,
             Cursor::Spin { pre, .. } => pre,
+            Cursor::Rate { pre, .. } => pre,
         }
     }
     fn n(&self) -> usize {
@@ -245,10 +252,44 @@ impl Cursor {
                 }
                 Ok(())
             }
+            Cursor::Rate { pre, transfer } => {
+                for _ in 0..npre - pre.chars().count() {
+                    stdout.write_all(b" ")?;
+                }
+                let (bytes, rate) = transfer.rate();
+                let s = format!(
+                    "{} {}, {}/s",
+                    pre,
+                    human_bytes(bytes as f64),
+                    human_bytes(rate)
+                );
+                stdout.write_all(s.as_bytes())?;
+                // Fill the rest of the line with spaces.
+                let len = npre - pre.chars().count() + s.chars().count();
+                for _ in len..w {
+                    stdout.write_all(b" ")?;
+                }
+                Ok(())
+            }
         }
     }
 }
 
+/// `x` bytes, in the largest unit that keeps it above 1.
+pub(crate) fn human_bytes(mut x: f64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut u = 0;
+    while x >= 1024. && u < UNITS.len() - 1 {
+        x /= 1024.;
+        u += 1
+    }
+    if u == 0 {
+        format!("{} {}", x as u64, UNITS[u])
+    } else {
+        format!("{:.1} {}", x, UNITS[u])
+    }
+}
+
 impl InnerCursors {
     pub fn push(&mut self, c: Cursor) -> usize {
         let r = self.cursors.len();
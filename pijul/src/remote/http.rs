@@ -1,26 +1,50 @@
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashSet};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use anyhow::bail;
-use libpijul::pristine::{Base32, Position};
+use libpijul::pristine::{Base32, Position, RemoteId};
 use libpijul::Hash;
 use log::{debug, error, trace};
+use serde_derive::{Deserialize, Serialize};
 
+use crate::remote::static_files::{self, StaticChannel};
 use crate::remote::CS;
 
-const USER_AGENT: &str = concat!("pijul-", clap::crate_version!());
+pub(super) const USER_AGENT: &str = concat!("pijul-", clap::crate_version!());
 
 pub struct Http {
     pub url: url::Url,
     pub channel: String,
     pub client: reqwest::Client,
     pub name: String,
+    /// Directory where the changelists downloaded from this remote
+    /// are cached, if the server sent an ETag for them.
+    pub cache_dir: Option<PathBuf>,
+    /// Capabilities of the server, once known.
+    pub capabilities: Option<crate::remote::Capabilities>,
+    /// The channel, if this repository was published as static files
+    /// by `pijul publish --static`.
+    pub static_channel: Option<StaticChannel>,
+}
+
+/// A changelist downloaded from an HTTP remote, replayed when the
+/// server answers a request for the same URL with `304 Not Modified`.
+/// Its ETag is stored in the remotes table of the pristine, along
+/// with `token`, so that a file and a validator from different
+/// downloads (for example if the transaction wasn't committed) are
+/// never used together.
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedChangelist {
+    token: String,
+    url: String,
+    body: String,
 }
 
 async fn download_change(
     client: reqwest::Client,
     url: url::Url,
+    static_url: Option<url::Url>,
     mut path: PathBuf,
     c: CS,
 ) -> Result<CS, anyhow::Error> {
@@ -62,9 +86,12 @@ async fn download_change(
     });
     let mut done = false;
     while !done {
-        let mut res = if let Ok(res) = client
-            .get(&url)
-            .query(&[(req, &c32)])
+        let get = if let Some(ref u) = static_url {
+            client.get(u.clone())
+        } else {
+            client.get(&url).query(&[(req, &c32)])
+        };
+        let mut res = if let Ok(res) = get
             .header(reqwest::header::USER_AGENT, USER_AGENT)
             .send()
             .await
@@ -98,7 +125,9 @@ async fn download_change(
                     if let Some(ref mut s) = size {
                         *s -= chunk.len();
                     }
+                    let len = chunk.len();
                     send.send(Some(chunk)).await?;
+                    crate::transfer::DOWNLOAD.transferred(len).await;
                 }
                 Ok(None) => match size {
                     Some(0) | None => done = true,
@@ -130,7 +159,57 @@ async fn download_change(
     Ok(c)
 }
 
-const POOL_SIZE: usize = 20;
+/// Cache the changelist `body`, downloaded from `url`, if the server
+/// sent an ETag for it, and return the validator to store in the
+/// remotes table, `"<token> <etag>"`. Servers without ETag support get
+/// no cache. Errors are ignored, since the cache is only an
+/// optimisation.
+fn cache_changelist(
+    path: &Path,
+    url: &url::Url,
+    etag: Option<String>,
+    body: &[u8],
+) -> Option<String> {
+    let (etag, body) = match (etag, std::str::from_utf8(body)) {
+        (Some(etag), Ok(body)) => (etag, body),
+        _ => {
+            std::fs::remove_file(path).unwrap_or(());
+            return None;
+        }
+    };
+    let token = data_encoding::BASE32_NOPAD.encode(&rand::random::<[u8; 10]>());
+    let cached = CachedChangelist {
+        token: token.clone(),
+        url: url.to_string(),
+        body: body.to_string(),
+    };
+    let write = || -> Result<(), anyhow::Error> {
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, serde_json::to_vec(&cached)?)?;
+        Ok(())
+    };
+    if let Err(e) = write() {
+        debug!("could not cache changelist: {:?}", e);
+        return None;
+    }
+    Some(format!("{} {}", token, etag))
+}
+
+/// The ETag and body of the changelist cached in `path`, if it was
+/// downloaded from `url` and matches `validator`.
+fn read_cached_changelist(
+    path: &Path,
+    validator: &str,
+    url: &url::Url,
+) -> Option<(String, String)> {
+    let (token, etag) = validator.split_once(' ')?;
+    let cached: CachedChangelist = serde_json::from_slice(&std::fs::read(path).ok()?).ok()?;
+    if cached.token == token && cached.url == url.as_str() {
+        Some((etag.to_string(), cached.body))
+    } else {
+        None
+    }
+}
 
 impl Http {
     pub async fn download_changes(
@@ -141,15 +220,22 @@ impl Http {
         path: &PathBuf,
         _full: bool,
     ) -> Result<(), anyhow::Error> {
-        let mut pool = <[_; POOL_SIZE]>::default();
+        let pool_size = crate::jobs::download();
+        let mut pool: Vec<Option<_>> = (0..pool_size).map(|_| None).collect();
         let mut cur = 0;
         while let Some(c) = hashes.recv().await {
             debug!("downloading {:?}", c);
+            let static_url = if self.static_channel.is_some() {
+                Some(static_files::change_url(&self.url, &c)?)
+            } else {
+                None
+            };
             let t = std::mem::replace(
                 &mut pool[cur],
                 Some(tokio::spawn(download_change(
                     self.client.clone(),
                     self.url.clone(),
+                    static_url,
                     path.clone(),
                     c,
                 ))),
@@ -165,10 +251,10 @@ impl Http {
                 }
                 debug!("sent");
             }
-            cur = (cur + 1) % POOL_SIZE;
+            cur = (cur + 1) % pool_size;
         }
-        for f in 0..POOL_SIZE {
-            if let Some(t) = pool[(cur + f) % POOL_SIZE].take() {
+        for f in 0..pool_size {
+            if let Some(t) = pool[(cur + f) % pool_size].take() {
                 let c = t.await??;
                 debug!("sending {:?}", c);
                 super::PROGRESS.borrow_mut().unwrap()[pro_n].incr();
@@ -189,6 +275,12 @@ impl Http {
         to_channel: Option<&str>,
         changes: &[CS],
     ) -> Result<(), anyhow::Error> {
+        if self.static_channel.is_some() {
+            bail!(
+                "Cannot push to {}, which is published as static files (see `pijul publish --static`)",
+                self.url
+            )
+        }
         for c in changes {
             let url = {
                 let mut p = self.url.path().to_string();
@@ -210,15 +302,14 @@ impl Http {
                 CS::Change(c) => {
                     libpijul::changestore::filesystem::push_filename(&mut local, &c);
                     let change = std::fs::read(&local)?;
+                    super::verify_change(c, &change)?;
                     base32 = c.to_base32();
                     to_channel.push(("apply", &base32));
                     change
                 }
                 CS::State(c) => {
                     libpijul::changestore::filesystem::push_tag_filename(&mut local, &c);
-                    let mut tag_file = libpijul::tag::OpenTagFile::open(&local, &c)?;
-                    let mut v = Vec::new();
-                    tag_file.short(&mut v)?;
+                    let v = super::verify_tag(&local, c)?;
                     base32 = c.to_base32();
                     to_channel.push(("tagup", &base32));
                     v
@@ -226,6 +317,7 @@ impl Http {
             };
             libpijul::changestore::filesystem::pop_filename(&mut local);
             debug!("url {:?} {:?}", url, to_channel);
+            let len = body.len();
             let resp = self
                 .client
                 .post(url)
@@ -247,11 +339,18 @@ impl Http {
                     }
                 }
             }
+            crate::transfer::UPLOAD.transferred(len).await;
             super::PROGRESS.borrow_mut().unwrap()[pro_n].incr();
         }
         Ok(())
     }
 
+    /// Download the changelist of the remote from position `from`,
+    /// restricted to `paths`. If `cache` is given, it is the id of
+    /// the remote and the validator stored in its remotes table,
+    /// which is sent to the server to avoid downloading an unchanged
+    /// changelist again, and replaced by the validator of the new
+    /// changelist.
     pub async fn download_changelist<
         A,
         F: FnMut(&mut A, u64, Hash, libpijul::Merkle, bool) -> Result<(), anyhow::Error>,
@@ -261,7 +360,17 @@ impl Http {
         a: &mut A,
         from: u64,
         paths: &[String],
+        cache: Option<(&RemoteId, &mut Option<String>)>,
     ) -> Result<HashSet<Position<Hash>>, anyhow::Error> {
+        if let Some(ref s) = self.static_channel {
+            // Static repositories don't support paths, see `capabilities`.
+            for &(n, h, m, tag) in s.log.iter() {
+                if n >= from {
+                    f(a, n, h, m, tag)?
+                }
+            }
+            return Ok(HashSet::new());
+        }
         let url = {
             let mut p = self.url.path().to_string();
             if !p.ends_with("/") {
@@ -277,24 +386,56 @@ impl Http {
         for p in paths.iter() {
             query.push(("path", p));
         }
-        let res = self
+        let url = {
+            let mut u = url;
+            u.query_pairs_mut().extend_pairs(query.iter());
+            u
+        };
+        let (cache_path, validator) = match (cache, &self.cache_dir) {
+            (Some((id, validator)), Some(dir)) => (Some(dir.join(id.to_string())), Some(validator)),
+            _ => (None, None),
+        };
+        let cached = match (&cache_path, &validator) {
+            (Some(path), Some(Some(v))) => read_cached_changelist(path, v, &url),
+            _ => None,
+        };
+        let mut req = self
             .client
-            .get(url)
-            .query(&query)
-            .header(reqwest::header::USER_AGENT, USER_AGENT)
-            .send()
-            .await?;
+            .get(url.clone())
+            .header(reqwest::header::USER_AGENT, USER_AGENT);
+        if let Some((ref etag, _)) = cached {
+            req = req.header(reqwest::header::IF_NONE_MATCH, etag)
+        }
+        let res = req.send().await?;
         let status = res.status();
-        if !status.is_success() {
-            match serde_json::from_slice::<libpijul::RemoteError>(&*res.bytes().await?) {
-                Ok(remote_err) => return Err(remote_err.into()),
-                Err(_) if status.as_u16() == 404 => {
-                    bail!("Repository `{}` not found (404)", self.url)
+        let resp = if status == reqwest::StatusCode::NOT_MODIFIED {
+            if let Some((etag, body)) = cached {
+                debug!("changelist not modified, etag {:?}", etag);
+                body.into_bytes()
+            } else {
+                bail!("Unexpected HTTP response: {}", status)
+            }
+        } else {
+            if !status.is_success() {
+                match serde_json::from_slice::<libpijul::RemoteError>(&*res.bytes().await?) {
+                    Ok(remote_err) => return Err(remote_err.into()),
+                    Err(_) if status.as_u16() == 404 => {
+                        bail!("Repository `{}` not found (404)", self.url)
+                    }
+                    Err(_) => bail!("Http request failed with status code: {}", status),
                 }
-                Err(_) => bail!("Http request failed with status code: {}", status),
             }
-        }
-        let resp = res.bytes().await?;
+            let etag = res
+                .headers()
+                .get(reqwest::header::ETAG)
+                .and_then(|e| e.to_str().ok())
+                .map(|e| e.to_string());
+            let resp = res.bytes().await?.to_vec();
+            if let (Some(path), Some(validator)) = (cache_path, validator) {
+                *validator = cache_changelist(&path, &url, etag, &resp)
+            }
+            resp
+        };
         let mut result = HashSet::new();
         if let Ok(data) = std::str::from_utf8(&resp) {
             for l in data.lines() {
@@ -322,6 +463,9 @@ impl Http {
         mid: Option<u64>,
     ) -> Result<Option<(u64, libpijul::Merkle, libpijul::Merkle)>, anyhow::Error> {
         debug!("get_state {:?}", self.url);
+        if let Some(ref s) = self.static_channel {
+            return Ok(s.get_state(mid));
+        }
         let url = format!("{}/{}", self.url, super::DOT_DIR);
         let q = if let Some(mid) = mid {
             [
@@ -358,8 +502,55 @@ impl Http {
         }
     }
 
+    /// Ask the server for the changes needed to reach state `to` of
+    /// the channel from its state `from`, in the format of the
+    /// changelist. Returns `None` if the server doesn't know one of
+    /// these states.
+    pub async fn state_closure(
+        &self,
+        from: &libpijul::Merkle,
+        to: &libpijul::Merkle,
+    ) -> Result<Option<Vec<(u64, Hash, libpijul::Merkle, bool)>>, anyhow::Error> {
+        debug!("state_closure {:?}", self.url);
+        let url = format!("{}/{}", self.url, super::DOT_DIR);
+        let q = [
+            ("channel", self.channel.clone()),
+            ("closure", from.to_base32()),
+            ("to", to.to_base32()),
+        ];
+        let res = self
+            .client
+            .get(&url)
+            .query(&q)
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            bail!("HTTP error {:?}", res.status())
+        }
+        let resp = res.bytes().await?;
+        let mut result = Vec::new();
+        for l in std::str::from_utf8(&resp)?.lines() {
+            if l.is_empty() {
+                break;
+            }
+            match super::parse_line(l)? {
+                super::ListLine::Change { n, h, m, tag } => result.push((n, h, m, tag)),
+                super::ListLine::Error(e) => {
+                    debug!("closure: {:?}", e);
+                    return Ok(None);
+                }
+                super::ListLine::Position(_) => {}
+            }
+        }
+        Ok(Some(result))
+    }
+
     pub async fn get_id(&self) -> Result<Option<libpijul::pristine::RemoteId>, anyhow::Error> {
         debug!("get_state {:?}", self.url);
+        if let Some(ref s) = self.static_channel {
+            return Ok(s.id);
+        }
         let url = format!("{}/{}", self.url, super::DOT_DIR);
         let q = [("channel", self.channel.clone()), ("id", String::new())];
         let res = self
@@ -377,12 +568,81 @@ impl Http {
         Ok(libpijul::pristine::RemoteId::from_bytes(&resp))
     }
 
+    /// The capabilities of the server, asked only once. Servers that
+    /// don't answer are assumed to support what they supported before
+    /// this request was introduced.
+    pub async fn capabilities(&mut self) -> Result<crate::remote::Capabilities, anyhow::Error> {
+        if let Some(ref c) = self.capabilities {
+            return Ok(c.clone());
+        }
+        if self.static_channel.is_some() {
+            let c = crate::remote::Capabilities {
+                identities: false,
+                archive: false,
+                paths: false,
+                closure: false,
+                ..crate::remote::Capabilities::current()
+            };
+            self.capabilities = Some(c.clone());
+            return Ok(c);
+        }
+        let url = format!("{}/{}", self.url, super::DOT_DIR);
+        let res = self
+            .client
+            .get(&url)
+            .query(&[("capabilities", "")])
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .send()
+            .await?;
+        let c = if res.status().is_success() {
+            serde_json::from_slice(&res.bytes().await?).ok()
+        } else {
+            None
+        };
+        let c = c.unwrap_or_else(crate::remote::Capabilities::legacy);
+        debug!("capabilities = {:?}", c);
+        self.capabilities = Some(c.clone());
+        Ok(c)
+    }
+
+    pub async fn get_channel_metadata(
+        &self,
+    ) -> Result<Option<libpijul::pristine::ChannelMetadata>, anyhow::Error> {
+        if let Some(ref s) = self.static_channel {
+            return Ok(s.metadata.clone());
+        }
+        let url = format!("{}/{}", self.url, super::DOT_DIR);
+        let q = [
+            ("channel", self.channel.clone()),
+            ("channelmeta", String::new()),
+        ];
+        let res = self
+            .client
+            .get(&url)
+            .query(&q)
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Ok(None);
+        }
+        let resp = res.bytes().await?;
+        debug!("resp = {:?}", resp);
+        Ok(serde_json::from_slice(&resp).ok())
+    }
+
     pub async fn archive<W: std::io::Write + Send + 'static>(
         &mut self,
         prefix: Option<String>,
         state: Option<(libpijul::Merkle, &[Hash])>,
         mut w: W,
     ) -> Result<u64, anyhow::Error> {
+        if self.static_channel.is_some() {
+            bail!(
+                "{} is published as static files, and can't make archives",
+                self.url
+            )
+        }
         let url = {
             let mut p = self.url.path().to_string();
             if !p.ends_with("/") {
@@ -432,8 +692,8 @@ impl Http {
 
     pub async fn update_identities(
         &mut self,
-        rev: Option<u64>,
-        mut path: PathBuf,
+        keys: &BTreeSet<String>,
+        path: &Path,
     ) -> Result<u64, anyhow::Error> {
         let url = {
             let mut p = self.url.path().to_string();
@@ -445,20 +705,32 @@ impl Http {
             u.set_path(&p);
             u
         };
-        let res = self
+        let mut query = vec![("identities", "")];
+        query.extend(keys.iter().map(|k| ("key", k.as_str())));
+        let mut req = self
             .client
             .get(url)
-            .query(&[(
-                "identities",
-                if let Some(rev) = rev {
-                    format!("{}", rev)
-                } else {
-                    String::new()
-                },
-            )])
-            .header(reqwest::header::USER_AGENT, USER_AGENT)
-            .send()
-            .await?;
+            .query(&query)
+            .header(reqwest::header::USER_AGENT, USER_AGENT);
+        // If all these identities are already here, only ask for them
+        // if one of them changed since the oldest one was saved.
+        let since = keys
+            .iter()
+            .map(|k| super::identity_last_modified(path, k))
+            .collect::<Option<Vec<_>>>()
+            .and_then(|t| t.into_iter().min())
+            .and_then(|t| chrono::NaiveDateTime::from_timestamp_opt(t as i64, 0));
+        if let Some(since) = since {
+            req = req.header(
+                reqwest::header::IF_MODIFIED_SINCE,
+                since.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+            )
+        }
+        let res = req.send().await?;
+        if res.status() == reqwest::StatusCode::NOT_MODIFIED {
+            debug!("identities not modified");
+            return Ok(0);
+        }
         if !res.status().is_success() {
             bail!("HTTP error {:?}", res.status())
         }
@@ -470,13 +742,9 @@ impl Http {
         }
         let resp: Identities = res.json().await?;
 
-        std::fs::create_dir_all(&path)?;
         for id in resp.id.iter() {
-            path.push(&id.public_key.key);
-            debug!("recv identity: {:?} {:?}", id, path);
-            let mut id_file = std::fs::File::create(&path)?;
-            serde_json::to_writer_pretty(&mut id_file, &id)?;
-            path.pop();
+            debug!("recv identity: {:?}", id);
+            super::save_identity(path, keys, id)?;
         }
         Ok(resp.rev)
     }
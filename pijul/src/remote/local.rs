@@ -1,5 +1,5 @@
-use std::collections::HashSet;
-use std::path::PathBuf;
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::bail;
@@ -14,6 +14,9 @@ pub struct Local {
     pub channel: String,
     pub root: std::path::PathBuf,
     pub changes_dir: std::path::PathBuf,
+    /// The change store of the remote repository, including the
+    /// repository it references, if any.
+    pub changes: crate::repository::Changes,
     pub pristine: Arc<libpijul::pristine::sanakirja::Pristine>,
     pub name: String,
 }
@@ -39,6 +42,48 @@ pub fn get_state<T: TxnTExt>(
     }
 }
 
+/// The channels of the repository, with their activity.
+pub fn list_channels<T: TxnTExt>(txn: &T) -> Result<Vec<super::RemoteChannel>, anyhow::Error> {
+    let mut channels = Vec::new();
+    for c in txn.channels("")? {
+        let c = c.read();
+        channels.push(super::RemoteChannel {
+            name: txn.name(&*c).to_string(),
+            apply_counter: txn.apply_counter(&*c),
+            last_modified: txn.last_modified(&*c),
+            state: txn.current_state(&*c)?,
+        })
+    }
+    Ok(channels)
+}
+
+/// The changes of `channel` needed to reach its state `to` from its
+/// state `from` (`Merkle::zero()` for the empty channel), in the order
+/// of the log, with their position and resulting state, and whether
+/// that state is tagged. Returns `None` if one of these states isn't
+/// a state of `channel`, or if `to` comes before `from` (see
+/// [`TxnTExt::changes_between`]).
+pub fn state_closure<T: TxnTExt>(
+    txn: &T,
+    channel: &libpijul::pristine::ChannelRef<T>,
+    from: &Merkle,
+    to: &Merkle,
+) -> Result<Option<Vec<(u64, Hash, Merkle, bool)>>, anyhow::Error> {
+    let between = txn.changes_between(&*channel.read(), from, to)?;
+    let apply = match between {
+        Some(b) if b.unrecord.is_empty() => b.apply,
+        _ => return Ok(None),
+    };
+    let mut result = Vec::with_capacity(apply.len());
+    for h in apply {
+        let n = txn.get_revchanges(channel, &h)?.unwrap();
+        let (_, m) = txn.get_changes(channel, n)?.unwrap();
+        let tag = txn.is_tagged(txn.tags(&*channel.read()), n)?;
+        result.push((n, h, m, tag))
+    }
+    Ok(Some(result))
+}
+
 impl Local {
     pub fn get_state(
         &mut self,
@@ -49,6 +94,31 @@ impl Local {
         Ok(get_state(&txn, &channel, mid)?)
     }
 
+    pub fn state_closure(
+        &self,
+        from: &Merkle,
+        to: &Merkle,
+    ) -> Result<Option<Vec<(u64, Hash, Merkle, bool)>>, anyhow::Error> {
+        let txn = self.pristine.txn_begin()?;
+        if let Some(channel) = txn.load_channel(&self.channel)? {
+            state_closure(&txn, &channel, from, to)
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn get_channel_metadata(
+        &self,
+    ) -> Result<Option<libpijul::pristine::ChannelMetadata>, anyhow::Error> {
+        let txn = self.pristine.txn_begin()?;
+        Ok(txn.channel_metadata(&self.channel)?)
+    }
+
+    pub fn channels(&self) -> Result<Vec<super::RemoteChannel>, anyhow::Error> {
+        let txn = self.pristine.txn_begin()?;
+        list_channels(&txn)
+    }
+
     pub fn get_id(&self) -> Result<libpijul::pristine::RemoteId, anyhow::Error> {
         let txn = self.pristine.txn_begin()?;
         if let Some(channel) = txn.load_channel(&self.channel)? {
@@ -72,10 +142,7 @@ impl Local {
         from: u64,
         paths: &[String],
     ) -> Result<HashSet<Position<Hash>>, anyhow::Error> {
-        let store = libpijul::changestore::filesystem::FileSystem::from_root(
-            &self.root,
-            crate::repository::max_files(),
-        );
+        let store = self.changes.clone();
         let remote_txn = self.pristine.txn_begin()?;
         let remote_channel = if let Some(channel) = remote_txn.load_channel(&self.channel)? {
             channel
@@ -89,7 +156,10 @@ impl Local {
         let mut paths_ = HashSet::new();
         let mut result = HashSet::new();
         for s in paths {
-            if let Ok((p, _ambiguous)) = remote_txn.follow_oldest_path(&store, &remote_channel, s) {
+            let inodes = remote_txn
+                .path_inodes(&store, &remote_channel, s)
+                .unwrap_or_default();
+            for p in inodes {
                 debug!("p = {:?}", p);
                 result.insert(Position {
                     change: remote_txn.get_external(&p.change)?.unwrap().into(),
@@ -142,52 +212,71 @@ impl Local {
         mut local: PathBuf,
         to_channel: Option<&str>,
         changes: &[CS],
-    ) -> Result<(), anyhow::Error> {
-        let store = libpijul::changestore::filesystem::FileSystem::from_root(
-            &self.root,
-            crate::repository::max_files(),
-        );
+    ) -> Result<Vec<crate::staging::Verdict>, anyhow::Error> {
+        let store = self.changes.clone();
+        let config = crate::repository::load_config(&self.root)?;
         let txn = self.pristine.arc_txn_begin()?;
         let channel = txn
             .write()
             .open_or_create_channel(to_channel.unwrap_or(&self.channel))?;
+        // The changes go through a staging area, where they are
+        // validated before being applied, and the tags are added
+        // afterwards.
+        let mut staging = crate::staging::Staging::new(&self.root.join(DOT_DIR))?;
+        let mut tags = Vec::new();
         for c in changes {
-            match c {
+            let dest = match c {
                 CS::Change(c) => {
                     libpijul::changestore::filesystem::push_filename(&mut local, &c);
-                    libpijul::changestore::filesystem::push_filename(&mut self.changes_dir, &c);
+                    super::verify_change(c, &std::fs::read(&local)?)?;
+                    staging.path(c)
                 }
-                CS::State(c) => {
-                    libpijul::changestore::filesystem::push_tag_filename(&mut local, &c);
-                    libpijul::changestore::filesystem::push_tag_filename(&mut self.changes_dir, &c);
+                CS::State(m) => {
+                    libpijul::changestore::filesystem::push_tag_filename(&mut local, &m);
+                    super::verify_tag(&local, m)?;
+                    tags.push(*c);
+                    self.changes.top.tag_filename(m)
                 }
-            }
-            std::fs::create_dir_all(&self.changes_dir.parent().unwrap())?;
-            debug!("hard link {:?} {:?}", local, self.changes_dir);
-            if std::fs::metadata(&self.changes_dir).is_err() {
-                if std::fs::hard_link(&local, &self.changes_dir).is_err() {
-                    std::fs::copy(&local, &self.changes_dir)?;
+            };
+            std::fs::create_dir_all(dest.parent().unwrap())?;
+            debug!("hard link {:?} {:?}", local, dest);
+            if std::fs::metadata(&dest).is_err() {
+                if std::fs::hard_link(&local, &dest).is_err() {
+                    std::fs::copy(&local, &dest)?;
                 }
             }
             debug!("hard link done");
             libpijul::changestore::filesystem::pop_filename(&mut local);
-            libpijul::changestore::filesystem::pop_filename(&mut self.changes_dir);
+            if let CS::Change(c) = c {
+                staging.push(*c);
+                super::PROGRESS.borrow_mut().unwrap()[pro_n].incr();
+            }
         }
         let repo = libpijul::working_copy::filesystem::FileSystem::from_root(&self.root);
-        upload_changes(pro_n, &store, &mut *txn.write(), &channel, changes)?;
-        libpijul::output::output_repository_no_pending(
-            &repo,
+        let verdicts = staging.validate(
+            &config.hooks.validate,
+            &self.changes_dir,
             &store,
-            &txn,
+            &mut *txn.write(),
             &channel,
-            "",
-            true,
-            None,
-            num_cpus::get(),
-            0,
+            &mut libpijul::ApplyWorkspace::new(),
         )?;
+        upload_changes(pro_n, &store, &mut *txn.write(), &channel, &tags)?;
+        if !config.bare {
+            libpijul::output::output_repository_no_pending(
+                &repo,
+                &store,
+                &txn,
+                &channel,
+                "",
+                true,
+                None,
+                crate::jobs::output(),
+                0,
+            )?;
+        }
         txn.commit()?;
-        Ok(())
+        Ok(verdicts)
     }
 
     pub async fn download_changes(
@@ -198,16 +287,16 @@ impl Local {
         mut path: &mut PathBuf,
     ) -> Result<(), anyhow::Error> {
         while let Some(c) = hashes.recv().await {
-            match c {
+            let remote_path = match c {
                 CS::Change(c) => {
-                    libpijul::changestore::filesystem::push_filename(&mut self.changes_dir, &c);
                     libpijul::changestore::filesystem::push_filename(&mut path, &c);
+                    self.changes.filename(&c)
                 }
                 CS::State(c) => {
-                    libpijul::changestore::filesystem::push_tag_filename(&mut self.changes_dir, &c);
                     libpijul::changestore::filesystem::push_tag_filename(&mut path, &c);
+                    self.changes.tag_filename(&c)
                 }
-            }
+            };
             super::PROGRESS.borrow_mut().unwrap()[pro_n].incr();
             if std::fs::metadata(&path).is_ok() {
                 debug!("metadata {:?} ok", path);
@@ -215,11 +304,10 @@ impl Local {
                 continue;
             }
             std::fs::create_dir_all(&path.parent().unwrap())?;
-            if std::fs::hard_link(&self.changes_dir, &path).is_err() {
-                std::fs::copy(&self.changes_dir, &path)?;
+            if std::fs::hard_link(&remote_path, &path).is_err() {
+                std::fs::copy(&remote_path, &path)?;
             }
             debug!("hard link done");
-            libpijul::changestore::filesystem::pop_filename(&mut self.changes_dir);
             libpijul::changestore::filesystem::pop_filename(&mut path);
             debug!("sent");
             send.send(c).await?;
@@ -229,36 +317,25 @@ impl Local {
 
     pub async fn update_identities(
         &mut self,
-        _rev: Option<u64>,
-        mut path: PathBuf,
+        keys: &BTreeSet<String>,
+        path: &Path,
     ) -> Result<u64, anyhow::Error> {
         let mut other_path = self.root.join(DOT_DIR);
         other_path.push("identities");
-        let r = if let Ok(r) = std::fs::read_dir(&other_path) {
-            r
-        } else {
-            return Ok(0);
-        };
-        std::fs::create_dir_all(&path)?;
-        for id in r {
-            let id = id?;
-            let m = id.metadata()?;
-            let p = id.path();
-            path.push(p.file_name().unwrap());
-            if let Ok(ml) = std::fs::metadata(&path) {
-                if ml.modified()? < m.modified()? {
-                    std::fs::remove_file(&path)?;
-                } else {
-                    path.pop();
-                    continue;
-                }
-            }
-            if std::fs::hard_link(&p, &path).is_err() {
-                std::fs::copy(&p, &path)?;
+        let mut revision = 0;
+        for key in keys {
+            other_path.push(key);
+            let id = std::fs::read(&other_path)
+                .ok()
+                .and_then(|id| serde_json::from_slice::<crate::Identity>(&id).ok());
+            other_path.pop();
+            if let Some(id) = id {
+                debug!("identity {:?}", id);
+                super::save_identity(path, keys, &id)?;
+                revision = revision.max(id.last_modified);
             }
-            path.pop();
         }
-        Ok(0)
+        Ok(revision)
     }
 }
 
@@ -1,4 +1,5 @@
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashSet};
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
@@ -11,6 +12,7 @@ use libpijul::pristine::{
 use libpijul::DOT_DIR;
 use libpijul::{ChannelTxnT, DepsTxnT, GraphTxnT, MutTxnTExt, TxnTExt};
 use log::{debug, info};
+use serde_derive::{Deserialize, Serialize};
 
 use crate::config::*;
 use crate::repository::*;
@@ -24,6 +26,8 @@ use local::*;
 pub mod http;
 use http::*;
 
+pub mod static_files;
+
 use crate::progress::PROGRESS;
 
 pub enum RemoteRepo {
@@ -34,6 +38,122 @@ pub enum RemoteRepo {
     None,
 }
 
+/// The features supported by a remote, as advertised by its server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// Protocol version of the server.
+    pub version: usize,
+    #[serde(default)]
+    pub tags: bool,
+    #[serde(default)]
+    pub identities: bool,
+    /// Identities restricted to some keys, given after the revision
+    /// in the `identities` command.
+    #[serde(default)]
+    pub identity_keys: bool,
+    #[serde(default)]
+    pub archive: bool,
+    /// Changelists restricted to some paths.
+    #[serde(default)]
+    pub paths: bool,
+    /// Changes needed to reach a state, computed by the server.
+    #[serde(default)]
+    pub closure: bool,
+    #[serde(default)]
+    pub channel_metadata: bool,
+    /// Two-phase pushes: pushed changes are validated by the server
+    /// before being applied (see [`crate::staging`]).
+    #[serde(default)]
+    pub validate: bool,
+    /// Listing the channels of the remote, with their activity.
+    #[serde(default)]
+    pub channels: bool,
+}
+
+impl Capabilities {
+    /// The capabilities of this version of Pijul.
+    pub fn current() -> Self {
+        Capabilities {
+            version: crate::PROTOCOL_VERSION,
+            tags: true,
+            identities: true,
+            identity_keys: true,
+            archive: true,
+            paths: true,
+            closure: true,
+            channel_metadata: true,
+            validate: true,
+            channels: true,
+        }
+    }
+
+    /// What servers that don't advertise their capabilities are
+    /// assumed to support: everything they supported before the
+    /// `capabilities` command was introduced.
+    pub fn legacy() -> Self {
+        Capabilities {
+            identity_keys: false,
+            closure: false,
+            channel_metadata: false,
+            validate: false,
+            channels: false,
+            ..Capabilities::current()
+        }
+    }
+}
+
+/// How long the capabilities of a remote are remembered in the
+/// pristine before being asked again, in seconds, so that upgrades
+/// and downgrades of its server are eventually noticed.
+pub const CAPABILITIES_MAX_AGE: u64 = 24 * 3600;
+
+/// Capabilities of a remote, as stored in the pristine along with the
+/// time at which they were asked.
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredCapabilities {
+    time: u64,
+    capabilities: Capabilities,
+}
+
+/// A channel of a remote, along with its activity, as sent by the
+/// `channels` protocol command.
+#[derive(Debug, Clone)]
+pub struct RemoteChannel {
+    pub name: String,
+    pub apply_counter: u64,
+    /// Last modification time, in milliseconds since the Unix epoch.
+    pub last_modified: u64,
+    pub state: Merkle,
+}
+
+impl RemoteChannel {
+    /// Parse a line of the answer to the `channels` protocol command.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut s = line.splitn(4, ' ');
+        Some(RemoteChannel {
+            apply_counter: s.next()?.parse().ok()?,
+            last_modified: s.next()?.parse().ok()?,
+            state: Merkle::from_base32(s.next()?.as_bytes())?,
+            name: s.next()?.to_string(),
+        })
+    }
+}
+
+/// The format of a line of the answer to the `channels` protocol
+/// command, without its final newline.
+impl std::fmt::Display for RemoteChannel {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            fmt,
+            "{} {} {} {}",
+            self.apply_counter,
+            self.last_modified,
+            self.state.to_base32(),
+            self.name
+        )
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum CS {
     Change(Hash),
@@ -41,6 +161,45 @@ pub enum CS {
 }
 
 impl Repository {
+    /// Whether `h` is in the repository referenced by this one (see
+    /// `pijul clone --reference`), and doesn't need to be downloaded.
+    pub fn in_reference(&self, h: &CS) -> bool {
+        if let Some(ref r) = self.changes.bottom {
+            let path = match h {
+                CS::Change(h) => r.filename(h),
+                CS::State(s) => r.tag_filename(s),
+            };
+            std::fs::metadata(&path).is_ok()
+        } else {
+            false
+        }
+    }
+
+    /// Link the changes of `hashes` that are only in the referenced
+    /// repository into this repository's changes directory, for the
+    /// operations that read change files directly, such as uploads.
+    pub fn link_from_reference(&self, hashes: &[CS]) -> Result<(), anyhow::Error> {
+        let r = if let Some(ref r) = self.changes.bottom {
+            r
+        } else {
+            return Ok(());
+        };
+        for h in hashes {
+            let (from, to) = match h {
+                CS::Change(h) => (r.filename(h), self.changes.top.filename(h)),
+                CS::State(s) => (r.tag_filename(s), self.changes.top.tag_filename(s)),
+            };
+            if std::fs::metadata(&to).is_ok() || std::fs::metadata(&from).is_err() {
+                continue;
+            }
+            std::fs::create_dir_all(to.parent().unwrap())?;
+            if std::fs::hard_link(&from, &to).is_err() {
+                std::fs::copy(&from, &to)?;
+            }
+        }
+        Ok(())
+    }
+
     pub async fn remote(
         &self,
         self_path: Option<&Path>,
@@ -50,6 +209,7 @@ impl Repository {
         no_cert_check: bool,
         with_path: bool,
     ) -> Result<RemoteRepo, anyhow::Error> {
+        let ssh_options = self.config.ssh.get(name);
         if let Some(name) = self.config.remotes.get(name) {
             unknown_remote(
                 self_path,
@@ -57,10 +217,19 @@ impl Repository {
                 channel,
                 no_cert_check,
                 with_path,
+                ssh_options,
             )
             .await
         } else {
-            unknown_remote(self_path, name, channel, no_cert_check, with_path).await
+            unknown_remote(
+                self_path,
+                name,
+                channel,
+                no_cert_check,
+                with_path,
+                ssh_options,
+            )
+            .await
         }
     }
 }
@@ -71,21 +240,29 @@ pub async fn unknown_remote(
     channel: &str,
     no_cert_check: bool,
     with_path: bool,
+    ssh_options: Option<&crate::config::SshOptions>,
 ) -> Result<RemoteRepo, anyhow::Error> {
     if let Ok(url) = url::Url::parse(name) {
         let scheme = url.scheme();
         if scheme == "http" || scheme == "https" {
             debug!("unknown_remote, http = {:?}", name);
+            crate::offline::check(name)?;
+            let client = reqwest::ClientBuilder::new()
+                .danger_accept_invalid_certs(no_cert_check)
+                .build()?;
+            let static_channel = static_files::load(&client, &url, channel).await?;
             return Ok(RemoteRepo::Http(Http {
                 url,
                 channel: channel.to_string(),
-                client: reqwest::ClientBuilder::new()
-                    .danger_accept_invalid_certs(no_cert_check)
-                    .build()?,
+                client,
                 name: name.to_string(),
+                cache_dir: self_path.map(|p| p.join(DOT_DIR).join(HTTP_CACHE_DIR)),
+                capabilities: None,
+                static_channel,
             }));
         } else if scheme == "ssh" {
-            if let Some(mut ssh) = ssh_remote(name, with_path) {
+            crate::offline::check(name)?;
+            if let Some(mut ssh) = ssh_remote(name, with_path, ssh_options) {
                 debug!("unknown_remote, ssh = {:?}", ssh);
                 if let Some(c) = ssh.connect(name, channel).await? {
                     return Ok(RemoteRepo::Ssh(c));
@@ -112,10 +289,12 @@ pub async fn unknown_remote(
         match libpijul::pristine::sanakirja::Pristine::new(&dot_dir.join("db")) {
             Ok(pristine) => {
                 debug!("pristine done");
+                let config = crate::repository::load_config(&root)?;
                 return Ok(RemoteRepo::Local(Local {
                     root: Path::new(name).to_path_buf(),
                     channel: channel.to_string(),
                     changes_dir,
+                    changes: crate::repository::changes(&root, &config),
                     pristine: Arc::new(pristine),
                     name: name.to_string(),
                 }));
@@ -128,8 +307,9 @@ pub async fn unknown_remote(
             Err(e) => return Err(e.into()),
         }
     }
-    if let Some(mut ssh) = ssh_remote(name, with_path) {
+    if let Some(mut ssh) = ssh_remote(name, with_path, ssh_options) {
         debug!("unknown_remote, ssh = {:?}", ssh);
+        crate::offline::check(name)?;
         if let Some(c) = ssh.connect(name, channel).await? {
             return Ok(RemoteRepo::Ssh(c));
         }
@@ -146,18 +326,33 @@ fn get_local_inodes(
 ) -> Result<HashSet<Position<ChangeId>>, anyhow::Error> {
     let mut paths = HashSet::new();
     for path in path.iter() {
-        let (p, ambiguous) = txn.follow_oldest_path(&repo.changes, &channel, path)?;
-        if ambiguous {
-            bail!("Ambiguous path: {:?}", path)
+        for p in txn.path_inodes(&repo.changes, &channel, path)? {
+            paths.insert(p);
+            paths.extend(
+                libpijul::fs::iter_graph_descendants(txn, &channel.read(), p)?.map(|x| x.unwrap()),
+            );
         }
-        paths.insert(p);
-        paths.extend(
-            libpijul::fs::iter_graph_descendants(txn, &channel.read(), p)?.map(|x| x.unwrap()),
-        );
     }
     Ok(paths)
 }
 
+/// Whether `hunk`, a hunk of change `hash`, touches one of `inodes`.
+/// This only reads the positions of the hunk, not its contents.
+pub fn hunk_touches_inodes(
+    hash: &Hash,
+    hunk: &libpijul::change::Hunk<Option<Hash>, libpijul::change::Local>,
+    inodes: &HashSet<Position<Hash>>,
+) -> bool {
+    hunk.iter().any(|c| {
+        let inode = c.inode();
+        debug!("inode = {:?}", inode);
+        inodes.contains(&Position {
+            change: inode.change.unwrap_or(*hash),
+            pos: inode.pos,
+        })
+    })
+}
+
 /// Embellished [`RemoteDelta`] that has information specific
 /// to a push operation. We want to know what our options are
 /// for changes to upload, whether the remote has unrecorded relevant changes,
@@ -216,12 +411,13 @@ impl RemoteDelta<MutTxn<()>> {
         let mut to_upload = Vec::new();
         let inodes = get_local_inodes(txn, channel, repo, path)?;
 
-        for x in txn.reverse_log(&*channel.read(), None)? {
-            let (_, (h, _)) = x?;
-            if let Some(channel) = txn.load_channel(remote_channel)? {
-                let channel = channel.read();
+        if let Some(remote_channel) = txn.load_channel(remote_channel)? {
+            let channels = [channel.clone(), remote_channel];
+            let guards = libpijul::lock_channels(&channels);
+            for x in txn.reverse_log(&guards[0], None)? {
+                let (_, (h, _)) = x?;
                 let h_int = txn.get_internal(h)?.unwrap();
-                if txn.get_changeset(txn.changes(&channel), h_int)?.is_none() {
+                if txn.get_changeset(txn.changes(&guards[1]), h_int)?.is_none() {
                     if inodes.is_empty() {
                         to_upload.push(CS::Change(h.into()))
                     } else {
@@ -397,20 +593,15 @@ pub(crate) fn update_changelist_local_channel(
             pos: x.pos,
         }));
         if let Some(remote_channel) = txn.load_channel(remote_channel)? {
-            let remote_channel = remote_channel.read();
-            for x in txn.reverse_log(&remote_channel, None)? {
+            let channels = [current_channel.clone(), remote_channel];
+            let guards = libpijul::lock_channels(&channels);
+            for x in txn.reverse_log(&guards[1], None)? {
                 let (_, (h, m)) = x?;
-                if txn
-                    .channel_has_state(txn.states(&*current_channel.read()), &m)?
-                    .is_some()
-                {
+                if txn.channel_has_state(txn.states(&guards[0]), &m)?.is_some() {
                     break;
                 }
                 let h_int = txn.get_internal(h)?.unwrap();
-                if txn
-                    .get_changeset(txn.changes(&*current_channel.read()), h_int)?
-                    .is_none()
-                {
+                if txn.get_changeset(txn.changes(&guards[0]), h_int)?.is_none() {
                     if inodes_.is_empty()
                         || inodes_.iter().any(|&inode| {
                             txn.get_rev_touched_files(h_int, Some(&inode))
@@ -497,11 +688,13 @@ impl RemoteRepo {
         } else {
             return Ok(None);
         };
+        self.remember_capabilities(txn, &id).await?;
         let mut remote = if let Some(name) = self.name() {
             txn.open_or_create_remote(id, name)?
         } else {
             return Ok(None);
         };
+        let cache_paths = self.extend_cached_paths(txn, &mut remote, path).await?;
         let n = self.dichotomy_changelist(txn, &remote.lock()).await?;
         debug!("update changelist {:?}", n);
         let v: Vec<_> = txn
@@ -538,8 +731,120 @@ impl RemoteRepo {
         }
 
         debug!("deleted");
-        let paths = self.download_changelist(txn, &mut remote, n, path).await?;
-        Ok(Some((paths, remote)))
+        let inodes = self
+            .download_changelist(txn, &mut remote, n, &cache_paths)
+            .await?;
+        let inodes = self.path_inodes(path, &cache_paths, inodes, n).await?;
+        Ok(Some((inodes, remote)))
+    }
+
+    /// Refresh the cached changelist of this remote, keeping the paths
+    /// it is restricted to, without computing anything to push or
+    /// pull. Returns the number of entries added to the cache, or
+    /// `None` if this remote has no cache.
+    pub async fn refresh_changelist<T: MutTxnTExt + TxnTExt + 'static>(
+        &mut self,
+        txn: &mut T,
+    ) -> Result<Option<usize>, anyhow::Error> {
+        if let RemoteRepo::LocalChannel(_) = self {
+            return Ok(None);
+        }
+        let id = if let Some(id) = self.get_id(txn).await? {
+            id
+        } else {
+            return Ok(None);
+        };
+        let (last, paths) = if let Some(remote) = txn.load_remote(&id)? {
+            let last = txn.last_remote(&remote.lock().remote)?.map(|(n, _)| n);
+            (last, txn.remote_paths(&id)?)
+        } else {
+            (None, Vec::new())
+        };
+        let remote = if let Some((_, remote)) = self.update_changelist(txn, &paths).await? {
+            remote
+        } else {
+            return Ok(None);
+        };
+        let from = last.map(|n| n + 1).unwrap_or(0);
+        let added = txn.iter_remote(&remote.lock().remote, from)?.count();
+        Ok(Some(added))
+    }
+
+    /// Make sure the cached changelist of `remote` covers `paths` (all
+    /// paths if `paths` is empty), fetching the changes that touch
+    /// the paths not covered yet. Returns the paths the cache is now
+    /// restricted to, which must be used for all subsequent updates
+    /// of the cache, or an empty list if the cache is not restricted.
+    async fn extend_cached_paths<T: MutTxnTExt + TxnTExt>(
+        &mut self,
+        txn: &mut T,
+        remote: &mut RemoteRef<T>,
+        paths: &[String],
+    ) -> Result<Vec<String>, anyhow::Error> {
+        let mut paths: Vec<String> = paths.to_vec();
+        paths.sort();
+        paths.dedup();
+        let is_empty = txn.iter_remote(&remote.lock().remote, 0)?.next().is_none();
+        if is_empty {
+            txn.set_remote_paths(remote.id(), &paths)?;
+            return Ok(paths);
+        }
+        let cached = txn.remote_paths(remote.id())?;
+        if cached.is_empty() {
+            return Ok(cached);
+        }
+        let missing: Vec<String> = paths
+            .iter()
+            .filter(|p| {
+                !cached.iter().any(|c| {
+                    p.starts_with(c.as_str())
+                        && (p.len() == c.len() || p.as_bytes()[c.len()] == b'/')
+                })
+            })
+            .cloned()
+            .collect();
+        if !paths.is_empty() && missing.is_empty() {
+            return Ok(cached);
+        }
+        debug!("cached paths {:?}, fetching {:?}", cached, missing);
+        // The changes touching the new paths may be anywhere in the
+        // remote's log, start from the beginning.
+        self.download_changelist(txn, remote, 0, &missing).await?;
+        let extended = if paths.is_empty() {
+            Vec::new()
+        } else {
+            let mut extended = cached;
+            extended.extend(missing.into_iter());
+            extended.sort();
+            extended
+        };
+        txn.set_remote_paths(remote.id(), &extended)?;
+        Ok(extended)
+    }
+
+    /// The positions in the remote channel of `paths`, the paths the
+    /// user asked for, given the positions `cached` of the paths the
+    /// cache is restricted to, `cache_paths` (see
+    /// [`Self::extend_cached_paths`]). These differ if the cache covers
+    /// more paths than asked, in which case the positions of `paths`
+    /// are asked to the remote, along with its changelist from `from`.
+    async fn path_inodes(
+        &mut self,
+        paths: &[String],
+        cache_paths: &[String],
+        cached: HashSet<Position<Hash>>,
+        from: u64,
+    ) -> Result<HashSet<Position<Hash>>, anyhow::Error> {
+        let mut paths: Vec<String> = paths.to_vec();
+        paths.sort();
+        paths.dedup();
+        if paths == cache_paths {
+            Ok(cached)
+        } else if paths.is_empty() {
+            Ok(HashSet::new())
+        } else {
+            Ok(self.download_changelist_nocache(from, &paths).await?.0)
+        }
     }
 
     async fn update_changelist_pushpull_from_scratch(
@@ -612,8 +917,15 @@ impl RemoteRepo {
                 specific_changes,
             );
         }
+        let id = self.get_id(txn).await?;
+        if let Some(ref id) = id {
+            self.remember_capabilities(txn, id).await?;
+        }
+        if !path.is_empty() && !self.capabilities().await?.paths {
+            bail!("This remote does not support restricting changelists to paths")
+        }
 
-        let id = if let Some(id) = self.get_id(txn).await? {
+        let id = if let Some(id) = id {
             debug!("id = {:?}", id);
             id
         } else {
@@ -622,6 +934,7 @@ impl RemoteRepo {
                 .await;
         };
         let mut remote_ref = txn.open_or_create_remote(id, self.name().unwrap()).unwrap();
+        let cache_paths = self.extend_cached_paths(txn, &mut remote_ref, path).await?;
         let dichotomy_n = self.dichotomy_changelist(txn, &remote_ref.lock()).await?;
         let ours_ge_dichotomy: Vec<(u64, CS)> = txn
             .iter_remote(&remote_ref.lock().remote, dichotomy_n)?
@@ -639,8 +952,12 @@ impl RemoteRepo {
                 }
             })
             .collect();
-        let (inodes, theirs_ge_dichotomy) =
-            self.download_changelist_nocache(dichotomy_n, path).await?;
+        let (inodes, theirs_ge_dichotomy) = self
+            .download_changelist_nocache(dichotomy_n, &cache_paths)
+            .await?;
+        let inodes = self
+            .path_inodes(path, &cache_paths, inodes, dichotomy_n)
+            .await?;
         debug!("theirs_ge_dichotomy = {:?}", theirs_ge_dichotomy);
         let ours_ge_dichotomy_set = ours_ge_dichotomy
             .iter()
@@ -803,7 +1120,7 @@ impl RemoteRepo {
         let r = match *self {
             RemoteRepo::Local(ref mut l) => l.download_changelist(f, &mut v, from, paths)?,
             RemoteRepo::Ssh(ref mut s) => s.download_changelist(f, &mut v, from, paths).await?,
-            RemoteRepo::Http(ref h) => h.download_changelist(f, &mut v, from, paths).await?,
+            RemoteRepo::Http(ref h) => h.download_changelist(f, &mut v, from, paths, None).await?,
             RemoteRepo::LocalChannel(_) => HashSet::new(),
             RemoteRepo::None => unreachable!(),
         };
@@ -899,6 +1216,142 @@ impl RemoteRepo {
         }
     }
 
+    /// Metadata (description, creator…) of the remote channel, if the
+    /// remote has any.
+    pub async fn get_channel_metadata<T: libpijul::TxnTExt + 'static>(
+        &mut self,
+        txn: &T,
+    ) -> Result<Option<libpijul::pristine::ChannelMetadata>, anyhow::Error> {
+        match *self {
+            RemoteRepo::Local(ref l) => l.get_channel_metadata(),
+            RemoteRepo::Ssh(ref mut s) => {
+                if s.capabilities().await?.channel_metadata {
+                    s.get_channel_metadata().await
+                } else {
+                    Ok(None)
+                }
+            }
+            RemoteRepo::Http(ref h) => h.get_channel_metadata().await,
+            RemoteRepo::LocalChannel(ref channel) => Ok(txn.channel_metadata(channel)?),
+            RemoteRepo::None => unreachable!(),
+        }
+    }
+
+    /// The channels of the remote, with their activity. Returns
+    /// `None` if the remote can't list them (HTTP remotes, and SSH
+    /// remotes running older versions of Pijul).
+    pub async fn channels<T: TxnTExt>(
+        &mut self,
+        txn: &T,
+    ) -> Result<Option<Vec<RemoteChannel>>, anyhow::Error> {
+        match *self {
+            RemoteRepo::Local(ref l) => Ok(Some(l.channels()?)),
+            RemoteRepo::Ssh(ref mut s) => {
+                if s.capabilities().await?.channels {
+                    Ok(Some(s.channels().await?))
+                } else {
+                    Ok(None)
+                }
+            }
+            RemoteRepo::Http(_) => Ok(None),
+            RemoteRepo::LocalChannel(_) => Ok(Some(list_channels(txn)?)),
+            RemoteRepo::None => unreachable!(),
+        }
+    }
+
+    /// The capabilities of the remote, asked to its server the first
+    /// time, and then remembered for the rest of the session (and in
+    /// the pristine, see `remember_capabilities`).
+    pub async fn capabilities(&mut self) -> Result<Capabilities, anyhow::Error> {
+        match *self {
+            RemoteRepo::Ssh(ref mut s) => s.capabilities().await,
+            RemoteRepo::Http(ref mut h) => h.capabilities().await,
+            RemoteRepo::Local(_) | RemoteRepo::LocalChannel(_) => Ok(Capabilities::current()),
+            RemoteRepo::None => unreachable!(),
+        }
+    }
+
+    /// Make the capabilities stored in the pristine for remote `id`
+    /// the capabilities of this session if they are less than
+    /// [`CAPABILITIES_MAX_AGE`] seconds old. Else, ask them to the
+    /// server and store them, so that the next sessions don't need
+    /// to.
+    async fn remember_capabilities<T: MutTxnT>(
+        &mut self,
+        txn: &mut T,
+        id: &libpijul::pristine::RemoteId,
+    ) -> Result<(), anyhow::Error> {
+        let session = match *self {
+            RemoteRepo::Ssh(ref mut s) => &mut s.capabilities,
+            RemoteRepo::Http(ref mut h) => &mut h.capabilities,
+            _ => return Ok(()),
+        };
+        if session.is_some() {
+            return Ok(());
+        }
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+        if let Some(stored) = txn.remote_capabilities(id)? {
+            match serde_json::from_str::<StoredCapabilities>(&stored) {
+                Ok(s) if now.saturating_sub(s.time) < CAPABILITIES_MAX_AGE => {
+                    debug!("stored capabilities = {:?}", s.capabilities);
+                    *session = Some(s.capabilities);
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+        let capabilities = self.capabilities().await?;
+        let stored = serde_json::to_string(&StoredCapabilities {
+            time: now,
+            capabilities,
+        })?;
+        txn.set_remote_capabilities(id, Some(&stored))?;
+        Ok(())
+    }
+
+    /// The changes of the remote channel needed to reach its state
+    /// `to` from its state `from` (`Merkle::zero()` for the empty
+    /// channel), in the order of its log, as computed by the remote.
+    /// Returns `None` if the remote doesn't know one of these states,
+    /// or can't compute this (static HTTP remotes, and remotes running
+    /// older versions of Pijul), in which case the changelist has to
+    /// be downloaded instead.
+    pub async fn state_closure<T: libpijul::TxnTExt>(
+        &mut self,
+        txn: &T,
+        from: &Merkle,
+        to: &Merkle,
+    ) -> Result<Option<Vec<CS>>, anyhow::Error> {
+        let closure = match *self {
+            RemoteRepo::Local(ref l) => l.state_closure(from, to)?,
+            RemoteRepo::Ssh(ref mut s) => {
+                if s.capabilities().await?.closure {
+                    s.state_closure(from, to).await?
+                } else {
+                    None
+                }
+            }
+            RemoteRepo::Http(ref mut h) => {
+                if h.capabilities().await?.closure {
+                    h.state_closure(from, to).await?
+                } else {
+                    None
+                }
+            }
+            RemoteRepo::LocalChannel(ref channel) => {
+                if let Some(channel) = txn.load_channel(&channel)? {
+                    local::state_closure(txn, &channel, from, to)?
+                } else {
+                    None
+                }
+            }
+            RemoteRepo::None => unreachable!(),
+        };
+        Ok(closure.map(|c| c.into_iter().map(|(_, h, _, _)| CS::Change(h)).collect()))
+    }
+
     /// This method might return `Ok(None)` in some cases, for example
     /// if the remote wants to indicate not to store a cache. This is
     /// the case for Nest channels, for example.
@@ -930,6 +1383,9 @@ impl RemoteRepo {
         umask: u16,
         w: W,
     ) -> Result<u64, anyhow::Error> {
+        if !self.capabilities().await?.archive {
+            bail!("This remote does not support archives")
+        }
         match *self {
             RemoteRepo::Local(ref mut l) => {
                 debug!("archiving local repo");
@@ -986,47 +1442,98 @@ impl RemoteRepo {
                     .await
             }
             RemoteRepo::Http(ref h) => {
-                h.download_changelist(f, &mut (txn, remote), from, paths)
-                    .await
+                let id = *remote.id();
+                let mut validator = txn.remote_validator(&id)?;
+                let result = h
+                    .download_changelist(
+                        f,
+                        &mut (&mut *txn, &mut *remote),
+                        from,
+                        paths,
+                        Some((&id, &mut validator)),
+                    )
+                    .await?;
+                txn.set_remote_validator(&id, validator.as_deref())?;
+                Ok(result)
             }
             RemoteRepo::LocalChannel(_) => Ok(HashSet::new()),
             RemoteRepo::None => unreachable!(),
         }
     }
 
-    pub async fn upload_changes<T: MutTxnTExt + 'static>(
+    pub async fn upload_changes<T: MutTxnTExt + TxnTExt + 'static>(
         &mut self,
         txn: &mut T,
         local: PathBuf,
         to_channel: Option<&str>,
         changes: &[CS],
     ) -> Result<(), anyhow::Error> {
+        if changes.iter().any(|c| matches!(c, CS::State(_))) && !self.capabilities().await?.tags {
+            bail!("This remote does not support tags")
+        }
         let pro_n = {
             let mut pro = PROGRESS.borrow_mut().unwrap();
-            pro.push(crate::progress::Cursor::Bar {
+            let pro_n = pro.push(crate::progress::Cursor::Bar {
                 i: 0,
                 n: changes.len(),
                 pre: "Uploading changes".into(),
-            })
+            });
+            if let RemoteRepo::Ssh(_) | RemoteRepo::Http(_) = self {
+                pro.push(crate::progress::Cursor::Rate {
+                    pre: "Uploaded".into(),
+                    transfer: &crate::transfer::UPLOAD,
+                });
+            }
+            pro_n
         };
 
-        match self {
+        let verdicts = match self {
             RemoteRepo::Local(ref mut l) => l.upload_changes(pro_n, local, to_channel, changes)?,
             RemoteRepo::Ssh(ref mut s) => {
                 s.upload_changes(pro_n, local, to_channel, changes).await?
             }
-            RemoteRepo::Http(ref h) => h.upload_changes(pro_n, local, to_channel, changes).await?,
+            RemoteRepo::Http(ref h) => {
+                h.upload_changes(pro_n, local, to_channel, changes).await?;
+                Vec::new()
+            }
             RemoteRepo::LocalChannel(ref channel) => {
                 let mut channel = txn.open_or_create_channel(channel)?;
                 let store = libpijul::changestore::filesystem::FileSystem::from_changes(
                     local,
                     crate::repository::max_files(),
                 );
-                local::upload_changes(pro_n, &store, txn, &mut channel, changes)?
+                local::upload_changes(pro_n, &store, txn, &mut channel, changes)?;
+                Vec::new()
             }
             RemoteRepo::None => unreachable!(),
-        }
+        };
         PROGRESS.join();
+        let mut rejected = 0;
+        let mut stderr = std::io::stderr();
+        for v in verdicts.iter().filter(|v| !v.accepted) {
+            rejected += 1;
+            writeln!(
+                stderr,
+                "Change {} was rejected by the remote: {}",
+                v.hash,
+                v.reason.as_deref().unwrap_or("no reason given")
+            )?;
+        }
+        if rejected > 0 {
+            bail!(
+                "{} of {} change(s) rejected by the remote",
+                rejected,
+                verdicts.len()
+            )
+        }
+        // Only now that the remote has accepted everything, bring its
+        // cached changelist up to date with the push.
+        if let RemoteRepo::Local(_) | RemoteRepo::Ssh(_) | RemoteRepo::Http(_) = self {
+            if let Some(id) = self.get_id(txn).await? {
+                let paths = txn.remote_paths(&id)?;
+                self.update_changelist(txn, &paths).await?;
+            }
+        }
         Ok(())
     }
 
@@ -1054,24 +1561,35 @@ impl RemoteRepo {
         Ok(true)
     }
 
+    /// Download the identities of the signers of `changes`, which
+    /// must already be in the change store, and save those whose
+    /// public key is correctly self-signed in `.pijul/identities`.
     pub async fn update_identities<T: MutTxnTExt + TxnTExt + GraphIter>(
         &mut self,
         repo: &mut Repository,
         remote: &RemoteRef<T>,
+        changes: &[CS],
     ) -> Result<(), anyhow::Error> {
-        debug!("Downloading identities");
+        let keys = signers(repo, changes);
+        debug!("Downloading identities {:?}", keys);
+        let capabilities = self.capabilities().await?;
+        if keys.is_empty() || !capabilities.identities {
+            return Ok(());
+        }
         let mut id_path = repo.path.clone();
         id_path.push(DOT_DIR);
         id_path.push("identities");
-        let rev = None;
         let r = match *self {
-            RemoteRepo::Local(ref mut l) => l.update_identities(rev, id_path).await?,
-            RemoteRepo::Ssh(ref mut s) => s.update_identities(rev, id_path).await?,
-            RemoteRepo::Http(ref mut h) => h.update_identities(rev, id_path).await?,
+            RemoteRepo::Local(ref mut l) => l.update_identities(&keys, &id_path).await?,
+            RemoteRepo::Ssh(ref mut s) => {
+                s.update_identities(&keys, capabilities.identity_keys, &id_path)
+                    .await?
+            }
+            RemoteRepo::Http(ref mut h) => h.update_identities(&keys, &id_path).await?,
             RemoteRepo::LocalChannel(_) => 0,
             RemoteRepo::None => unreachable!(),
         };
-        remote.set_id_revision(r);
+        remote.set_id_revision(remote.id_revision().max(r));
         Ok(())
     }
 
@@ -1090,6 +1608,12 @@ impl RemoteRepo {
             n: to_apply.len(),
             pre: "Downloading changes".into(),
         });
+        if let RemoteRepo::Ssh(_) | RemoteRepo::Http(_) = self {
+            pro.push(crate::progress::Cursor::Rate {
+                pre: "Downloaded".into(),
+                transfer: &crate::transfer::DOWNLOAD,
+            });
+        }
         let pro_b = if do_apply {
             Some(pro.push(crate::progress::Cursor::Bar {
                 i: 0,
@@ -1126,7 +1650,7 @@ impl RemoteRepo {
                     libpijul::changestore::filesystem::push_tag_filename(&mut change_path_, h);
                 }
             }
-            if std::fs::metadata(&change_path_).is_err() {
+            if std::fs::metadata(&change_path_).is_err() && !repo.in_reference(h) {
                 hash_send.send(*h)?;
                 to_download.insert(*h);
             }
@@ -1152,21 +1676,14 @@ impl RemoteRepo {
                     debug!("inodes = {:?}", inodes);
                     use libpijul::changestore::ChangeStore;
                     if let CS::Change(h) = h {
-                        let changes = repo.changes.get_changes(h)?;
-                        changes.iter().any(|c| {
-                            c.iter().any(|c| {
-                                let inode = c.inode();
-                                debug!("inode = {:?}", inode);
-                                if let Some(h) = inode.change {
-                                    inodes.contains(&Position {
-                                        change: h,
-                                        pos: inode.pos,
-                                    })
-                                } else {
-                                    false
-                                }
-                            })
-                        })
+                        let mut touches = false;
+                        for c in repo.changes.iter_hunks(h)? {
+                            touches = hunk_touches_inodes(h, &c?, inodes);
+                            if touches {
+                                break;
+                            }
+                        }
+                        touches
                     } else {
                         false
                     }
@@ -1285,6 +1802,18 @@ impl RemoteRepo {
         } else {
             return Ok(());
         };
+        self.remember_capabilities(txn, &id).await?;
+        // Smart remotes compute the changes needed to reach `state`
+        // themselves, without sending their whole changelist.
+        if let Some(to_pull) = self.state_closure(txn, &Merkle::zero(), &state).await? {
+            self.pull(repo, txn, channel, &to_pull, &HashSet::new(), true)
+                .await?;
+            let remote = txn.open_or_create_remote(id, self.name().unwrap()).unwrap();
+            self.update_identities(repo, &remote, &to_pull).await?;
+            self.complete_changes(repo, txn, channel, &to_pull, false)
+                .await?;
+            return Ok(());
+        }
         self.update_changelist(txn, &[]).await?;
         let remote = txn.open_or_create_remote(id, self.name().unwrap()).unwrap();
         let mut to_pull = Vec::new();
@@ -1303,7 +1832,7 @@ impl RemoteRepo {
         }
         self.pull(repo, txn, channel, &to_pull, &HashSet::new(), true)
             .await?;
-        self.update_identities(repo, &remote).await?;
+        self.update_identities(repo, &remote, &to_pull).await?;
 
         self.complete_changes(repo, txn, channel, &to_pull, false)
             .await?;
@@ -1412,14 +1941,210 @@ impl RemoteRepo {
         }
         self.pull(repo, txn, local_channel, &pullable, &inodes, true)
             .await?;
-        self.update_identities(repo, &remote_changes).await?;
+        self.update_identities(repo, &remote_changes, &pullable)
+            .await?;
 
         self.complete_changes(repo, txn, local_channel, &pullable, false)
             .await?;
+        self.clone_tags(repo, txn, local_channel, &remote_changes)
+            .await?;
+        Ok(())
+    }
+
+    /// Tag `local_channel` at the states tagged on the remote. The
+    /// missing tag files are downloaded in a single batch, and then
+    /// checked against the states of the channel in parallel. Tag
+    /// files that don't match their state are deleted, with a
+    /// warning.
+    pub async fn clone_tags<T: MutTxnTExt + TxnTExt + GraphIter + 'static>(
+        &mut self,
+        repo: &Repository,
+        txn: &mut T,
+        local_channel: &mut ChannelRef<T>,
+        remote: &RemoteRef<T>,
+    ) -> Result<(), anyhow::Error> {
+        if !self.capabilities().await?.tags {
+            return Ok(());
+        }
+        let mut tags = Vec::new();
+        {
+            let rem = remote.lock();
+            let channel = local_channel.read();
+            for x in txn.iter_remote(&rem.remote, 0)? {
+                let (n, p) = x?;
+                if !txn.is_tagged(&rem.tags, (*n).into())? {
+                    continue;
+                }
+                if let Some(local_n) = txn.channel_has_state(txn.states(&*channel), &p.b)? {
+                    let m: Merkle = (&p.b).into();
+                    tags.push((u64::from(local_n), m))
+                }
+            }
+        }
+        if tags.is_empty() {
+            return Ok(());
+        }
+
+        let mut to_download = Vec::new();
+        for (_, m) in tags.iter() {
+            if std::fs::metadata(&repo.changes.tag_filename(m)).is_err() {
+                to_download.push(CS::State(*m))
+            }
+        }
+        if !to_download.is_empty() {
+            let (mut send_signal, mut recv_signal) = tokio::sync::mpsc::channel(100);
+            let (send_hash, mut recv_hash) = tokio::sync::mpsc::unbounded_channel();
+            let mut tags_dir = repo.changes_dir.clone();
+            let mut self_ = std::mem::replace(self, RemoteRepo::None);
+            let pro_n = PROGRESS
+                .borrow_mut()
+                .unwrap()
+                .push(crate::progress::Cursor::Bar {
+                    i: 0,
+                    n: to_download.len(),
+                    pre: "Downloading tags".into(),
+                });
+            let t = tokio::spawn(async move {
+                self_
+                    .download_changes(
+                        pro_n,
+                        &mut recv_hash,
+                        &mut send_signal,
+                        &mut tags_dir,
+                        false,
+                    )
+                    .await?;
+                Ok::<_, anyhow::Error>(self_)
+            });
+            for m in to_download {
+                send_hash.send(m)?;
+            }
+            std::mem::drop(send_hash);
+            while recv_signal.recv().await.is_some() {}
+            *self = t.await??;
+            PROGRESS.join();
+        }
+
+        let valid = libpijul::tag::check_files(&repo.changes, tags, crate::jobs::other())?;
+        let mut channel = local_channel.write();
+        for (n, m) in valid {
+            txn.put_tags(&mut channel.tags, n, &m)?;
+        }
         Ok(())
     }
 }
 
+/// A change or tag whose contents don't match its hash, caught
+/// while uploading it, either by the client before sending it, or by
+/// the server before storing it, which reports staged changes on an
+/// `error:` line, and fails the command otherwise.
+#[derive(Debug, thiserror::Error)]
+pub enum UploadError {
+    #[error("Change {hash} is corrupt: {error}")]
+    Change {
+        hash: String,
+        error: libpijul::change::ChangeError,
+    },
+    #[error("Tag {state} is corrupt: {error}")]
+    Tag {
+        state: String,
+        error: libpijul::tag::TagError,
+    },
+    #[error("The remote refused the upload: {}", .0.join("; "))]
+    Remote(Vec<String>),
+}
+
+/// Check that `buf` is the change `hash`, both its hashed section
+/// and its contents.
+pub fn verify_change(hash: &Hash, buf: &[u8]) -> Result<(), UploadError> {
+    libpijul::change::Change::check_from_buffer(buf, hash).map_err(|error| UploadError::Change {
+        hash: hash.to_base32(),
+        error,
+    })
+}
+
+/// The short version of the tag file of `state` at `path` (its
+/// header, without the channel), as uploaded to remotes, after
+/// checking that both the file and its short version are the tag of
+/// `state`.
+pub fn verify_tag(path: &Path, state: &Merkle) -> Result<Vec<u8>, UploadError> {
+    let err = |error| UploadError::Tag {
+        state: state.to_base32(),
+        error,
+    };
+    let mut tag_file = libpijul::tag::OpenTagFile::open(path, state).map_err(err)?;
+    let mut v = Vec::new();
+    tag_file.short(&mut v).map_err(err)?;
+    libpijul::tag::read_short(std::io::Cursor::new(&v[..]), state).map_err(err)?;
+    Ok(v)
+}
+
+/// The public keys of the authors of `changes`, which are the only
+/// identities downloaded when pulling these changes.
+fn signers(repo: &Repository, changes: &[CS]) -> BTreeSet<String> {
+    use libpijul::changestore::ChangeStore;
+    let mut keys = BTreeSet::new();
+    for c in changes {
+        if let CS::Change(h) = c {
+            match repo.changes.get_header(h) {
+                Ok(header) => {
+                    for author in header.authors {
+                        if let Some(k) = author.0.get("key") {
+                            keys.insert(k.clone());
+                        }
+                    }
+                }
+                Err(e) => debug!("no header for {:?}: {}", h, e),
+            }
+        }
+    }
+    keys
+}
+
+/// The `last_modified` field of the identity of `key` saved in
+/// `id_path`, if there is one.
+fn identity_last_modified(id_path: &Path, key: &str) -> Option<u64> {
+    let f = std::fs::File::open(id_path.join(key)).ok()?;
+    let id: crate::Identity = serde_json::from_reader(std::io::BufReader::new(f)).ok()?;
+    Some(id.last_modified)
+}
+
+/// Save `id`, received from a remote, in `id_path`. Identities that
+/// weren't asked for (older servers send all their identities), whose
+/// public key doesn't carry a valid self-signature, or that aren't
+/// more recent than the saved ones, are ignored. Returns whether `id`
+/// was saved.
+fn save_identity(
+    id_path: &Path,
+    keys: &BTreeSet<String>,
+    id: &crate::Identity,
+) -> Result<bool, anyhow::Error> {
+    let key = &id.public_key.key;
+    if !keys.contains(key) {
+        debug!("identity {:?} wasn't asked for", key);
+        return Ok(false);
+    }
+    // This also checks that `key` is valid base 58, and hence a valid
+    // file name.
+    if let Err(e) = id.public_key.load() {
+        log::warn!("Invalid identity for key {}: {}", key, e);
+        return Ok(false);
+    }
+    if let Some(last_modified) = identity_last_modified(id_path, key) {
+        if last_modified >= id.last_modified {
+            return Ok(false);
+        }
+    }
+    std::fs::create_dir_all(id_path)?;
+    let path = id_path.join(key);
+    let tmp = path.with_extension("tmp");
+    let mut f = std::fs::File::create(&tmp)?;
+    serde_json::to_writer_pretty(&mut f, id)?;
+    std::io::Write::write_all(&mut f, b"\n")?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(true)
+}
+
 use libpijul::pristine::{ChangePosition, Position};
 use regex::Regex;
 
@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashSet};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
@@ -16,6 +16,7 @@ use thrussh::client::Session;
 use tokio::sync::Mutex;
 
 use super::parse_line;
+use crate::config::{HostKeyPolicy, SshOptions};
 use crate::remote::CS;
 
 pub struct Ssh {
@@ -26,6 +27,10 @@ pub struct Ssh {
     pub path: String,
     pub is_running: bool,
     pub name: String,
+    /// Capabilities of the server, once known.
+    pub capabilities: Option<super::Capabilities>,
+    /// The task sending keepalives, if enabled.
+    keepalive: Option<tokio::task::JoinHandle<()>>,
     state: Arc<Mutex<State>>,
     has_errors: Arc<Mutex<bool>>,
 }
@@ -46,9 +51,16 @@ lazy_static! {
 pub struct Remote<'a> {
     path: &'a str,
     config: thrussh_config::Config,
+    host_key_policy: HostKeyPolicy,
 }
 
-pub fn ssh_remote(addr: &str, with_path: bool) -> Option<Remote> {
+/// Parse an SSH address, completing it with `options` (from the
+/// repository configuration) and `~/.ssh/config`.
+pub fn ssh_remote<'a>(
+    addr: &'a str,
+    with_path: bool,
+    options: Option<&SshOptions>,
+) -> Option<Remote<'a>> {
     let cap = if with_path {
         ADDRESS.captures(addr)?
     } else {
@@ -59,6 +71,17 @@ pub fn ssh_remote(addr: &str, with_path: bool) -> Option<Remote> {
 
     let mut config =
         thrussh_config::parse_home(&host).unwrap_or(thrussh_config::Config::default(host));
+    if let Some(options) = options {
+        if let Some(ref file) = options.identity_file {
+            config.identity_file = Some(file.to_string_lossy().into_owned())
+        }
+        if let Some(port) = options.port {
+            config.port = port
+        }
+        if let Some(ref user) = options.user {
+            config.user = user.clone()
+        }
+    }
     if let Some(port) = cap.name("port").map(|x| x.as_str().parse().unwrap()) {
         config.port = port
     }
@@ -66,6 +89,9 @@ pub fn ssh_remote(addr: &str, with_path: bool) -> Option<Remote> {
         config.user.clear();
         config.user.push_str(u.as_str());
     }
+    if let Some(jump) = options.and_then(|o| o.proxy_jump.as_ref()) {
+        config.proxy_command = Some(proxy_jump_command(jump, &config.host_name, config.port))
+    }
     let path = if with_path {
         let p = cap
             .name("path0")
@@ -82,7 +108,25 @@ pub fn ssh_remote(addr: &str, with_path: bool) -> Option<Remote> {
     } else {
         ""
     };
-    Some(Remote { path, config })
+    Some(Remote {
+        path,
+        config,
+        host_key_policy: options
+            .map(|o| o.strict_host_key_checking)
+            .unwrap_or_default(),
+    })
+}
+
+/// The command connecting to `host:port` through `jump`, given as
+/// `[user@]host[:port]`. `ssh` doesn't take a port in its
+/// destination, hence the `-p`.
+fn proxy_jump_command(jump: &str, host: &str, port: u16) -> String {
+    match jump.rsplit_once(':') {
+        Some((jump_host, jump_port)) if jump_port.parse::<u16>().is_ok() => {
+            format!("ssh -W {}:{} -p {} {}", host, port, jump_port, jump_host)
+        }
+        _ => format!("ssh -W {}:{} {}", host, port, jump),
+    }
 }
 
 impl<'a> Remote<'a> {
@@ -100,6 +144,7 @@ impl<'a> Remote<'a> {
             addr: self.config.host_name.clone(),
             port: self.config.port,
             known_hosts: home,
+            host_key_policy: self.host_key_policy,
             last_window_adjustment: SystemTime::now(),
             state: state.clone(),
             has_errors: has_errors.clone(),
@@ -130,6 +175,21 @@ impl<'a> Remote<'a> {
         }
 
         let c = h.channel_open_session().await?;
+        let keepalive = if let Some(interval) = crate::transfer::keepalive() {
+            // Thrussh doesn't send keepalives, and data can't be sent
+            // on `c` in the middle of a command: requests without a
+            // reply are sent on another session instead, where
+            // nothing is run.
+            match h.channel_open_session().await {
+                Ok(k) => Some(tokio::spawn(keepalive(k, interval))),
+                Err(e) => {
+                    info!("could not open the keepalive session: {:?}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
         let remote_cmd = if let Ok(cmd) = std::env::var("REMOTE_PIJUL") {
             cmd
         } else {
@@ -143,6 +203,8 @@ impl<'a> Remote<'a> {
             path: self.path.to_string(),
             is_running: false,
             name: name.to_string(),
+            capabilities: None,
+            keepalive,
             state,
             has_errors,
         }))
@@ -288,6 +350,7 @@ pub struct SshClient {
     addr: String,
     port: u16,
     known_hosts: PathBuf,
+    host_key_policy: HostKeyPolicy,
     last_window_adjustment: SystemTime,
     state: Arc<Mutex<State>>,
     has_errors: Arc<Mutex<bool>>,
@@ -301,6 +364,23 @@ enum State {
     Id {
         sender: Option<tokio::sync::oneshot::Sender<Option<libpijul::pristine::RemoteId>>>,
     },
+    ChannelMeta {
+        sender: Option<tokio::sync::oneshot::Sender<Option<libpijul::pristine::ChannelMetadata>>>,
+        buf: Vec<u8>,
+    },
+    Capabilities {
+        sender: Option<tokio::sync::oneshot::Sender<Option<super::Capabilities>>>,
+        buf: Vec<u8>,
+    },
+    Channels {
+        sender: Option<tokio::sync::oneshot::Sender<Vec<super::RemoteChannel>>>,
+        buf: Vec<u8>,
+    },
+    Validate {
+        sender: Option<tokio::sync::oneshot::Sender<Validated>>,
+        buf: Vec<u8>,
+        errors: Vec<String>,
+    },
     Changes {
         sender: Option<tokio::sync::mpsc::Sender<CS>>,
         remaining_len: usize,
@@ -332,6 +412,10 @@ enum State {
     },
 }
 
+/// The verdicts of the server on the staged changes, if they could be
+/// read, and the errors it wrote about the uploads before them.
+type Validated = (Option<Vec<crate::staging::Verdict>>, Vec<String>);
+
 type BoxFuture<T> = Pin<Box<dyn futures::future::Future<Output = T> + Send>>;
 
 impl thrussh::client::Handler for SshClient {
@@ -360,7 +444,28 @@ impl thrussh::client::Handler for SshClient {
                 if e {
                     futures::future::ready(Ok((self, true)))
                 } else {
-                    match learn(&self.addr, self.port, server_public_key) {
+                    let accept = match self.host_key_policy {
+                        HostKeyPolicy::Ask => learn(&self.addr, self.port, server_public_key),
+                        HostKeyPolicy::AcceptNew => thrussh_keys::learn_known_hosts(
+                            &self.addr,
+                            self.port,
+                            server_public_key,
+                        )
+                        .map(|_| true)
+                        .map_err(|e| e.into()),
+                        HostKeyPolicy::Yes => {
+                            writeln!(
+                                std::io::stderr(),
+                                "Unknown key for {:?}, fingerprint {:?}",
+                                self.addr,
+                                server_public_key.fingerprint()
+                            )
+                            .unwrap_or(());
+                            Ok(false)
+                        }
+                        HostKeyPolicy::No => Ok(true),
+                    };
+                    match accept {
                         Ok(x) => futures::future::ready(Ok((self, x))),
                         Err(e) => futures::future::ready(Err(e)),
                     }
@@ -445,6 +550,7 @@ impl thrussh::client::Handler for SshClient {
         trace!("data {:?} {:?}", channel, data.len());
         let data = data.to_vec();
         Box::pin(async move {
+            crate::transfer::DOWNLOAD.transferred(data.len()).await;
             match *self.state.lock().await {
                 State::State { ref mut sender } => {
                     debug!("state: State");
@@ -482,6 +588,72 @@ impl thrussh::client::Handler for SshClient {
                         }
                     }
                 }
+                State::ChannelMeta {
+                    ref mut sender,
+                    ref mut buf,
+                } => {
+                    debug!("state: ChannelMeta {:?}", std::str::from_utf8(&data));
+                    buf.extend(&data);
+                    if buf.last() == Some(&10) {
+                        if let Some(sender) = sender.take() {
+                            sender
+                                .send(serde_json::from_slice(&buf[..buf.len() - 1]).ok())
+                                .unwrap_or(());
+                        }
+                    }
+                }
+                State::Capabilities {
+                    ref mut sender,
+                    ref mut buf,
+                } => {
+                    debug!("state: Capabilities {:?}", std::str::from_utf8(&data));
+                    buf.extend(&data);
+                    if buf.last() == Some(&10) {
+                        if let Some(sender) = sender.take() {
+                            sender
+                                .send(serde_json::from_slice(&buf[..buf.len() - 1]).ok())
+                                .unwrap_or(());
+                        }
+                    }
+                }
+                State::Channels {
+                    ref mut sender,
+                    ref mut buf,
+                } => {
+                    debug!("state: Channels {:?}", std::str::from_utf8(&data));
+                    buf.extend(&data);
+                    // One line per channel, followed by an empty line.
+                    if &buf[..] == b"\n" || buf.ends_with(b"\n\n") {
+                        if let Some(sender) = sender.take() {
+                            let channels = std::str::from_utf8(buf)?
+                                .lines()
+                                .filter_map(super::RemoteChannel::parse)
+                                .collect();
+                            sender.send(channels).unwrap_or(());
+                        }
+                    }
+                }
+                State::Validate {
+                    ref mut sender,
+                    ref mut buf,
+                    ref mut errors,
+                } => {
+                    debug!("state: Validate {:?}", std::str::from_utf8(&data));
+                    buf.extend(&data);
+                    // `error:` lines about the uploads, then one line
+                    // of JSON.
+                    while let Some(i) = buf.iter().position(|&c| c == b'\n') {
+                        let line: Vec<u8> = buf.drain(..=i).collect();
+                        let line = &line[..line.len() - 1];
+                        if let Some(e) = line.strip_prefix(b"error:") {
+                            errors.push(String::from_utf8_lossy(e).into_owned())
+                        } else if let Some(sender) = sender.take() {
+                            sender
+                                .send((serde_json::from_slice(line).ok(), std::mem::take(errors)))
+                                .unwrap_or(());
+                        }
+                    }
+                }
                 State::Changes {
                     ref mut sender,
                     ref mut remaining_len,
@@ -712,8 +884,24 @@ fn learn(addr: &str, port: u16, pk: &thrussh_keys::key::PublicKey) -> Result<boo
     }
 }
 
+/// Send an environment variable on session `c` every `interval`,
+/// until the connection is closed. Nothing is run on that session, so
+/// servers accepting the variable ignore it too.
+async fn keepalive(mut c: thrussh::client::Channel, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Err(e) = c.set_env(false, "PIJUL_KEEPALIVE", "1").await {
+            debug!("keepalive: {:?}", e);
+            break;
+        }
+    }
+}
+
 impl Ssh {
     pub async fn finish(&mut self) -> Result<(), anyhow::Error> {
+        if let Some(k) = self.keepalive.take() {
+            k.abort()
+        }
         self.c.eof().await?;
         while let Some(msg) = self.c.wait().await {
             debug!("msg = {:?}", msg);
@@ -765,6 +953,113 @@ impl Ssh {
         Ok(receiver.await?)
     }
 
+    /// The capabilities of the server, asked only once per session.
+    /// Servers that predate this command don't answer it, and are
+    /// assumed to support what they supported then after
+    /// `network.reply_timeout` seconds.
+    pub async fn capabilities(&mut self) -> Result<super::Capabilities, anyhow::Error> {
+        if let Some(ref c) = self.capabilities {
+            return Ok(c.clone());
+        }
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        *self.state.lock().await = State::Capabilities {
+            sender: Some(sender),
+            buf: Vec::new(),
+        };
+        self.run_protocol().await?;
+        self.c.data(&b"capabilities\n"[..]).await?;
+        let c = if let Some(t) = crate::transfer::reply_timeout() {
+            match tokio::time::timeout(t, receiver).await {
+                Ok(c) => c?,
+                Err(_) => {
+                    *self.state.lock().await = State::None;
+                    None
+                }
+            }
+        } else {
+            receiver.await?
+        };
+        let c = c.unwrap_or_else(super::Capabilities::legacy);
+        debug!("capabilities = {:?}", c);
+        self.capabilities = Some(c.clone());
+        Ok(c)
+    }
+
+    /// The channels of the remote, with their activity.
+    pub async fn channels(&mut self) -> Result<Vec<super::RemoteChannel>, anyhow::Error> {
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        *self.state.lock().await = State::Channels {
+            sender: Some(sender),
+            buf: Vec::new(),
+        };
+        self.run_protocol().await?;
+        self.c.data(&b"channels\n"[..]).await?;
+        Ok(receiver.await?)
+    }
+
+    pub async fn get_channel_metadata(
+        &mut self,
+    ) -> Result<Option<libpijul::pristine::ChannelMetadata>, anyhow::Error> {
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        *self.state.lock().await = State::ChannelMeta {
+            sender: Some(sender),
+            buf: Vec::new(),
+        };
+        self.run_protocol().await?;
+        self.c
+            .data(format!("channelmeta {}\n", self.channel).as_bytes())
+            .await?;
+        Ok(receiver.await?)
+    }
+
+    /// Ask the server for the changes needed to reach state `to` of
+    /// the channel from its state `from`. Returns `None` if the server
+    /// doesn't know one of these states. Servers that predate this
+    /// command don't answer it at all, see
+    /// [`Capabilities::closure`](super::Capabilities::closure).
+    pub async fn state_closure(
+        &mut self,
+        from: &Merkle,
+        to: &Merkle,
+    ) -> Result<Option<Vec<(u64, Hash, Merkle, bool)>>, anyhow::Error> {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(10);
+        *self.state.lock().await = State::Changelist {
+            sender,
+            pending: Vec::new(),
+        };
+        self.run_protocol().await?;
+        self.c
+            .data(
+                format!(
+                    "closure {} {} {}\n",
+                    self.channel,
+                    from.to_base32(),
+                    to.to_base32()
+                )
+                .as_bytes(),
+            )
+            .await?;
+        let mut line = receiver.recv().await;
+        let mut result = Vec::new();
+        let mut unknown = false;
+        while let Some(Some(l)) = line {
+            match l {
+                super::ListLine::Change { n, h, m, tag } => result.push((n, h, m, tag)),
+                super::ListLine::Error(e) => {
+                    debug!("closure: {:?}", e);
+                    unknown = true
+                }
+                super::ListLine::Position(_) => {}
+            }
+            line = receiver.recv().await
+        }
+        if unknown {
+            Ok(None)
+        } else {
+            Ok(Some(result))
+        }
+    }
+
     pub async fn prove(&mut self, key: libpijul::key::SKey) -> Result<(), anyhow::Error> {
         debug!("get_state");
         let (sender, receiver) = tokio::sync::oneshot::channel();
@@ -908,16 +1203,33 @@ impl Ssh {
         mut local: PathBuf,
         to_channel: Option<&str>,
         changes: &[CS],
-    ) -> Result<(), anyhow::Error> {
+    ) -> Result<Vec<crate::staging::Verdict>, anyhow::Error> {
+        // Changes are staged and validated by the server before being
+        // applied, and the tags are sent after the validation.
+        let two_phase = self.capabilities().await?.validate;
         self.run_protocol().await?;
         debug!("upload_changes");
-        for c in changes {
+        let to_channel = if let Some(t) = to_channel {
+            t.to_string()
+        } else {
+            self.channel.clone()
+        };
+        let command = if two_phase { "stage" } else { "apply" };
+        let mut ordered: Vec<&CS> = changes.iter().collect();
+        if two_phase {
+            // Stable, so the tags stay in order.
+            ordered.sort_by_key(|c| matches!(c, CS::State(_)));
+        }
+        let mut verdicts = Vec::new();
+        let mut validated = !two_phase;
+        let mut errors = Vec::new();
+        let mut receiver = if two_phase {
+            Some(self.collect_errors().await)
+        } else {
+            None
+        };
+        for c in ordered {
             debug!("{:?}", c);
-            let to_channel = if let Some(t) = to_channel {
-                t
-            } else {
-                self.channel.as_str()
-            };
             match c {
                 CS::Change(c) => {
                     libpijul::changestore::filesystem::push_filename(&mut local, &c);
@@ -926,20 +1238,34 @@ impl Ssh {
                     let mut change = thrussh::CryptoVec::new_zeroed(change_len as usize);
                     use std::io::Read;
                     change_file.read_exact(&mut change[..])?;
+                    // Don't upload a change corrupted on our side.
+                    super::verify_change(c, &change[..])?;
                     self.c
                         .data(
-                            format!("apply {} {} {}\n", to_channel, c.to_base32(), change_len)
-                                .as_bytes(),
+                            format!(
+                                "{} {} {} {}\n",
+                                command,
+                                to_channel,
+                                c.to_base32(),
+                                change_len
+                            )
+                            .as_bytes(),
                         )
                         .await?;
                     self.c.data(&change[..]).await?;
+                    crate::transfer::UPLOAD.transferred(change.len()).await;
                     libpijul::changestore::filesystem::pop_filename(&mut local);
                 }
                 CS::State(c) => {
+                    if !validated {
+                        let r = receiver.take().unwrap();
+                        let (v, e) = self.validate(&to_channel, r).await?;
+                        verdicts = v;
+                        errors.extend(e);
+                        validated = true
+                    }
                     libpijul::changestore::filesystem::push_tag_filename(&mut local, &c);
-                    let mut tag_file = libpijul::tag::OpenTagFile::open(&local, &c)?;
-                    let mut v = Vec::new();
-                    tag_file.short(&mut v)?;
+                    let v = super::verify_tag(&local, c)?;
                     self.c
                         .data(
                             format!("tagup {} {} {}\n", c.to_base32(), to_channel, v.len())
@@ -947,12 +1273,53 @@ impl Ssh {
                         )
                         .await?;
                     self.c.data(&v[..]).await?;
+                    crate::transfer::UPLOAD.transferred(v.len()).await;
                     libpijul::changestore::filesystem::pop_filename(&mut local);
                 }
             }
             super::PROGRESS.borrow_mut().unwrap()[pro_n].incr();
         }
-        Ok(())
+        if let Some(r) = receiver {
+            let (v, e) = self.validate(&to_channel, r).await?;
+            verdicts = v;
+            errors.extend(e);
+        }
+        if !errors.is_empty() {
+            return Err(super::UploadError::Remote(errors).into());
+        }
+        Ok(verdicts)
+    }
+
+    /// Collect the `error:` lines the server writes about the changes
+    /// staged from now on, until the answer to the next `validate`
+    /// command.
+    async fn collect_errors(&mut self) -> tokio::sync::oneshot::Receiver<Validated> {
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        *self.state.lock().await = State::Validate {
+            sender: Some(sender),
+            buf: Vec::new(),
+            errors: Vec::new(),
+        };
+        receiver
+    }
+
+    /// Ask the server to validate and apply the changes staged to
+    /// `channel`, and wait for its verdicts, along with the errors
+    /// collected by `receiver` (see [`Ssh::collect_errors`]).
+    async fn validate(
+        &mut self,
+        channel: &str,
+        receiver: tokio::sync::oneshot::Receiver<Validated>,
+    ) -> Result<(Vec<crate::staging::Verdict>, Vec<String>), anyhow::Error> {
+        self.c
+            .data(format!("validate {}\n", channel).as_bytes())
+            .await?;
+        // The validation hooks can take a long time, hence no timeout.
+        if let (Some(verdicts), errors) = receiver.await? {
+            Ok((verdicts, errors))
+        } else {
+            bail!("Could not read the verdicts of the remote")
+        }
     }
 
     pub async fn download_changes(
@@ -1033,10 +1400,13 @@ impl Ssh {
         Ok(())
     }
 
+    /// Download the identities of `keys`, sending the keys to the
+    /// server if it supports it (`with_keys`).
     pub async fn update_identities(
         &mut self,
-        rev: Option<u64>,
-        mut path: PathBuf,
+        keys: &BTreeSet<String>,
+        with_keys: bool,
+        path: &Path,
     ) -> Result<u64, anyhow::Error> {
         let (sender_, mut recv) = tokio::sync::mpsc::channel(100);
         *self.state.lock().await = State::Identities {
@@ -1044,21 +1414,21 @@ impl Ssh {
             buf: Vec::new(),
         };
         self.run_protocol().await?;
-        if let Some(rev) = rev {
-            self.c
-                .data(format!("identities {}\n", rev).as_bytes())
-                .await?;
-        } else {
-            self.c.data("identities\n".as_bytes()).await?;
+        // Other servers send all their identities, and only those of
+        // `keys` are saved.
+        let mut cmd = "identities 0".to_string();
+        if with_keys {
+            for key in keys {
+                cmd.push(' ');
+                cmd.push_str(key);
+            }
         }
+        cmd.push('\n');
+        self.c.data(cmd.as_bytes()).await?;
         let mut revision = 0;
-        std::fs::create_dir_all(&path)?;
         while let Some(id) = recv.recv().await {
-            path.push(&id.public_key.key);
-            debug!("recv identity: {:?} {:?}", id, path);
-            let mut id_file = std::fs::File::create(&path)?;
-            serde_json::to_writer_pretty(&mut id_file, &id)?;
-            path.pop();
+            debug!("recv identity: {:?}", id);
+            super::save_identity(path, keys, &id)?;
             revision = revision.max(id.last_modified);
         }
         debug!("done receiving");
@@ -0,0 +1,174 @@
+//! Repositories published as static files by `pijul publish
+//! --static`, which any HTTP file server can serve without running
+//! Pijul. The files are in a `.pijul` directory, like in a
+//! repository:
+//!
+//! - `static.json` is the manifest, with the id and state of each
+//!   channel. It is written last, so that clients never see a state
+//!   whose files aren't there yet.
+//! - `changelists/<channel>` is the log of each channel, in the
+//!   format of the answers to the `changelist` command of the
+//!   protocol.
+//! - `changes` contains the change and tag files, laid out as in a
+//!   repository.
+
+use std::collections::BTreeMap;
+
+use anyhow::bail;
+use libpijul::pristine::{Base32, ChannelMetadata, RemoteId};
+use libpijul::{Hash, Merkle, DOT_DIR};
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+
+use super::{ListLine, CS};
+use crate::repository::CHANGES_DIR;
+
+pub const MANIFEST: &str = "static.json";
+pub const CHANGELISTS_DIR: &str = "changelists";
+/// Version of the layout, increased when older clients can't read it
+/// anymore.
+pub const STATIC_VERSION: u64 = 1;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u64,
+    pub channels: BTreeMap<String, PublishedChannel>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PublishedChannel {
+    pub id: String,
+    /// The state of the channel, in base32.
+    pub state: String,
+    /// The number of entries in the log of the channel.
+    pub len: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<ChannelMetadata>,
+}
+
+/// A channel of a static repository, downloaded by the client.
+pub struct StaticChannel {
+    pub id: Option<RemoteId>,
+    pub metadata: Option<ChannelMetadata>,
+    /// The log of the channel: position, hash, state, and whether
+    /// that state is tagged.
+    pub log: Vec<(u64, Hash, Merkle, bool)>,
+}
+
+/// The URL of file `path` in the `.pijul` directory of `url`.
+pub fn file_url(url: &url::Url, path: &[&str]) -> Result<url::Url, anyhow::Error> {
+    let mut u = url.clone();
+    if let Ok(mut p) = u.path_segments_mut() {
+        p.pop_if_empty().push(DOT_DIR).extend(path);
+    } else {
+        bail!("Invalid URL: {}", url)
+    }
+    Ok(u)
+}
+
+/// The URL of the change or tag file of `c`.
+pub fn change_url(url: &url::Url, c: &CS) -> Result<url::Url, anyhow::Error> {
+    let (h32, ext) = match c {
+        CS::Change(h) => (h.to_base32(), "change"),
+        CS::State(m) => (m.to_base32(), "tag"),
+    };
+    let (a, b) = h32.split_at(2);
+    file_url(url, &[CHANGES_DIR, a, &format!("{}.{}", b, ext)])
+}
+
+async fn get(client: &reqwest::Client, url: url::Url) -> Result<Vec<u8>, anyhow::Error> {
+    let res = client
+        .get(url.clone())
+        .header(reqwest::header::USER_AGENT, super::http::USER_AGENT)
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        bail!("HTTP error {:?} for {}", res.status(), url)
+    }
+    Ok(res.bytes().await?.to_vec())
+}
+
+/// Load `channel` from the repository at `url`, if that repository
+/// was published as static files. Returns `None` if there is no
+/// manifest, which is the case for the repositories served by Pijul.
+pub async fn load(
+    client: &reqwest::Client,
+    url: &url::Url,
+    channel: &str,
+) -> Result<Option<StaticChannel>, anyhow::Error> {
+    let manifest = match get(client, file_url(url, &[MANIFEST])?).await {
+        Ok(m) => m,
+        Err(e) => {
+            debug!("no static manifest: {:?}", e);
+            return Ok(None);
+        }
+    };
+    let manifest: Manifest = if let Ok(m) = serde_json::from_slice(&manifest) {
+        m
+    } else {
+        debug!("invalid static manifest");
+        return Ok(None);
+    };
+    if manifest.version > STATIC_VERSION {
+        bail!(
+            "Repository {} was published with a newer version of Pijul (static layout version {})",
+            url,
+            manifest.version
+        )
+    }
+    let published = if let Some(c) = manifest.channels.get(channel) {
+        c
+    } else {
+        bail!("Channel {:?} not found in {}", channel, url)
+    };
+    let changelist = get(client, file_url(url, &[CHANGELISTS_DIR, channel])?).await?;
+    let mut log = Vec::new();
+    for l in std::str::from_utf8(&changelist)?.lines() {
+        if l.is_empty() {
+            break;
+        }
+        if let ListLine::Change { n, h, m, tag } = super::parse_line(l)? {
+            log.push((n, h, m, tag))
+        }
+    }
+    // The changelist is written before the manifest, and may already
+    // be longer if the repository is being published again.
+    log.truncate(published.len as usize);
+    let state = log.last().map(|x| x.2).unwrap_or_else(Merkle::zero);
+    if log.len() as u64 != published.len || state.to_base32() != published.state {
+        bail!(
+            "Repository {} is being published, please try again later",
+            url
+        )
+    }
+    Ok(Some(StaticChannel {
+        // Pijul servers send the text of the id, and clients keep
+        // its first bytes: do the same, so that the same repository
+        // has the same id either way.
+        id: RemoteId::from_bytes(published.id.as_bytes()),
+        metadata: published.metadata.clone(),
+        log,
+    }))
+}
+
+impl StaticChannel {
+    /// Same as `local::get_state`: the last position of the log at
+    /// or before `mid`, with its state and the last tagged state.
+    pub fn get_state(&self, mid: Option<u64>) -> Option<(u64, Merkle, Merkle)> {
+        let i = if let Some(mid) = mid {
+            self.log.iter().rposition(|x| x.0 <= mid)?
+        } else if self.log.is_empty() {
+            return None;
+        } else {
+            self.log.len() - 1
+        };
+        let (n, _, m, _) = self.log[i];
+        let tag = self.log[..=i]
+            .iter()
+            .rev()
+            .find(|x| x.3)
+            .map(|x| x.2)
+            .unwrap_or_else(Merkle::zero);
+        Some((n, m, tag))
+    }
+}
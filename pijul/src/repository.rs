@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use crate::{config, current_dir};
 use anyhow::bail;
@@ -7,15 +7,37 @@ use log::debug;
 
 pub struct Repository {
     pub pristine: libpijul::pristine::sanakirja::Pristine,
-    pub changes: libpijul::changestore::filesystem::FileSystem,
+    pub changes: Changes,
     pub working_copy: libpijul::working_copy::filesystem::FileSystem,
     pub config: config::Config,
     pub path: PathBuf,
     pub changes_dir: PathBuf,
 }
 
+/// The change store of a repository, reading the changes it doesn't
+/// have from the repository it references, if any.
+pub type Changes = libpijul::changestore::layered::Layered<
+    libpijul::changestore::filesystem::FileSystem,
+    libpijul::changestore::filesystem::FileSystem,
+>;
+
+/// The change store of the repository at `root`, referencing the
+/// changes of `reference`.
+pub fn changes(root: &Path, config: &config::Config) -> Changes {
+    let mut top = libpijul::changestore::filesystem::FileSystem::from_root(root, max_files());
+    top.set_index_contents(config.index_change_contents);
+    libpijul::changestore::layered::Layered::new(
+        top,
+        config
+            .reference
+            .as_ref()
+            .map(|r| libpijul::changestore::filesystem::FileSystem::from_root(r, max_files())),
+    )
+}
+
 pub const PRISTINE_DIR: &str = "pristine";
 pub const CHANGES_DIR: &str = "changes";
+pub const HTTP_CACHE_DIR: &str = "http_cache";
 pub const CONFIG_FILE: &str = "config";
 const DEFAULT_IGNORE: [&[u8]; 2] = [b".git", b".DS_Store"];
 // Static KV map of names for project kinds |-> elements
@@ -29,7 +51,7 @@ const IGNORE_KINDS: &[(&[&str], &[&[u8]])] = &[
 #[cfg(unix)]
 pub fn max_files() -> usize {
     let n = if let Ok((n, _)) = rlimit::getrlimit(rlimit::Resource::NOFILE) {
-        (n as usize / (2 * num_cpus::get())).max(1)
+        (n as usize / (2 * crate::jobs::other())).max(1)
     } else {
         256
     };
@@ -82,34 +104,118 @@ impl Repository {
         let mut working_copy_dir = cur.clone();
         working_copy_dir.pop();
         let config_path = cur.join(CONFIG_FILE);
-        let config = if let Ok(config) = std::fs::read(&config_path) {
-            if let Ok(toml) = toml::from_slice(&config) {
-                toml
-            } else {
-                bail!("Could not read configuration file at {:?}", config_path)
+        let config = if let Ok(config) = std::fs::read_to_string(&config_path) {
+            match config::from_str_warn(&config, &config_path) {
+                Ok(toml) => toml,
+                Err(e) => bail!(
+                    "Could not read configuration file at {:?}: {}",
+                    config_path,
+                    e
+                ),
             }
         } else {
             config::Config::default()
         };
+        let mut working_copy =
+            libpijul::working_copy::filesystem::FileSystem::from_root(&working_copy_dir);
+        working_copy.set_traversal_options(config.working_copy.clone());
+        working_copy.set_write_options(config.output.clone());
         Ok(Repository {
             pristine: libpijul::pristine::sanakirja::Pristine::new(&pristine_dir.join("db"))?,
-            working_copy: libpijul::working_copy::filesystem::FileSystem::from_root(
-                &working_copy_dir,
-            ),
-            changes: libpijul::changestore::filesystem::FileSystem::from_root(
-                &working_copy_dir,
-                crate::repository::max_files(),
-            ),
+            working_copy,
+            changes: changes(&working_copy_dir, &config),
             config,
             path: working_copy_dir,
             changes_dir,
         })
     }
 
+    /// The path of `path` relative to the root of the repository,
+    /// with `/` as a separator. Relative paths are relative to the
+    /// current directory, and the path doesn't need to exist.
+    pub fn relative_path(&self, path: &Path) -> Result<String, anyhow::Error> {
+        let root = std::fs::canonicalize(&self.path)?;
+        let full = std::fs::canonicalize(current_dir()?)?.join(path);
+        let (full, relative) = libpijul::working_copy::filesystem::get_prefix(Some(&root), &full)?;
+        if full.strip_prefix(&root).is_err() {
+            bail!("Path {:?} is outside the repository", path)
+        }
+        Ok(relative)
+    }
+
+    /// The current directory relative to the root of the repository,
+    /// or the empty string if the current directory is the root, or
+    /// isn't in the repository.
+    pub fn current_prefix(&self) -> String {
+        self.relative_path(Path::new("")).unwrap_or_default()
+    }
+
+    /// The prefixes a command should look at, given the `prefixes`
+    /// from the command line: these are made relative to the root of
+    /// the repository, and default to the current directory. An empty
+    /// result means the whole repository.
+    pub fn scoped_prefixes(&self, prefixes: &[PathBuf]) -> Result<Vec<PathBuf>, anyhow::Error> {
+        if prefixes.is_empty() {
+            let cur = self.current_prefix();
+            return Ok(if cur.is_empty() {
+                Vec::new()
+            } else {
+                vec![PathBuf::from(cur)]
+            });
+        }
+        let mut scoped = Vec::with_capacity(prefixes.len());
+        for p in prefixes {
+            let p = self.relative_path(p)?;
+            if p.is_empty() {
+                // One of the prefixes is the root.
+                return Ok(Vec::new());
+            }
+            scoped.push(PathBuf::from(p))
+        }
+        Ok(scoped)
+    }
+
     pub fn init(
         path: Option<std::path::PathBuf>,
         kind: Option<&str>,
         remote: Option<&str>,
+    ) -> Result<Self, anyhow::Error> {
+        Self::init_(path, kind, remote, false, None)
+    }
+
+    /// Initialise a repository without a working copy: only the
+    /// pristine and the changes are created, and the configuration
+    /// marks the repository as bare.
+    pub fn init_bare(
+        path: Option<std::path::PathBuf>,
+        remote: Option<&str>,
+    ) -> Result<Self, anyhow::Error> {
+        Self::init_(path, None, remote, true, None)
+    }
+
+    /// Initialise a repository reading the changes of the repository
+    /// at `reference` instead of storing its own copy. Only the
+    /// changes that are not in `reference` are stored in the new
+    /// repository.
+    pub fn init_with_reference(
+        path: Option<std::path::PathBuf>,
+        remote: Option<&str>,
+        bare: bool,
+        reference: &Path,
+    ) -> Result<Self, anyhow::Error> {
+        let reference = std::fs::canonicalize(reference)?;
+        if std::fs::metadata(reference.join(DOT_DIR).join(CHANGES_DIR)).is_err() {
+            bail!("No repository found at {:?}", reference)
+        }
+        Self::init_(path, None, remote, bare, Some(&reference))
+    }
+
+    fn init_(
+        path: Option<std::path::PathBuf>,
+        kind: Option<&str>,
+        remote: Option<&str>,
+        bare: bool,
+        reference: Option<&Path>,
     ) -> Result<Self, anyhow::Error> {
         let cur = if let Some(path) = path {
             path
@@ -124,22 +230,26 @@ impl Repository {
         };
         if std::fs::metadata(&pristine_dir).is_err() {
             std::fs::create_dir_all(&pristine_dir)?;
-            init_dot_ignore(cur.clone(), kind)?;
-            init_default_config(&cur, remote)?;
+            if !bare {
+                init_dot_ignore(cur.clone(), kind)?;
+            }
+            init_default_config(&cur, remote, bare, reference)?;
             let changes_dir = {
                 let mut base = cur.clone();
                 base.push(DOT_DIR);
                 base.push(CHANGES_DIR);
                 base
             };
+            let config = config::Config {
+                bare,
+                reference: reference.map(|r| r.to_path_buf()),
+                ..config::Config::default()
+            };
             Ok(Repository {
                 pristine: libpijul::pristine::sanakirja::Pristine::new(&pristine_dir.join("db"))?,
                 working_copy: libpijul::working_copy::filesystem::FileSystem::from_root(&cur),
-                changes: libpijul::changestore::filesystem::FileSystem::from_root(
-                    &cur,
-                    max_files(),
-                ),
-                config: config::Config::default(),
+                changes: changes(&cur, &config),
+                config,
                 path: cur,
                 changes_dir,
             })
@@ -147,9 +257,56 @@ impl Repository {
             bail!("Already in a repository")
         }
     }
+
+    /// Fail if this repository is bare, for commands that need a
+    /// working copy.
+    pub fn require_working_copy(&self) -> Result<(), anyhow::Error> {
+        if self.config.bare {
+            bail!(
+                "The repository at {:?} is bare, it has no working copy",
+                self.path
+            )
+        }
+        Ok(())
+    }
+
+    /// Lock the working copy against other pijul processes, until
+    /// the returned lock is dropped.
+    pub fn lock_working_copy(&self) -> Result<crate::lock::WorkingCopyLock, anyhow::Error> {
+        crate::lock::WorkingCopyLock::acquire(self.changes_dir.parent().unwrap())
+    }
 }
 
-fn init_default_config(path: &std::path::Path, remote: Option<&str>) -> Result<(), anyhow::Error> {
+/// Whether the repository at `path` is bare, according to its
+/// configuration file.
+pub fn is_bare(path: &Path) -> bool {
+    load_config(path).map(|c| c.bare).unwrap_or(false)
+}
+
+/// The configuration of the repository at `path`, or the default
+/// configuration if it has no configuration file.
+pub fn load_config(path: &Path) -> Result<config::Config, anyhow::Error> {
+    let config_path = path.join(DOT_DIR).join(CONFIG_FILE);
+    match std::fs::read_to_string(&config_path) {
+        Ok(config) => match config::from_str_warn(&config, &config_path) {
+            Ok(config) => Ok(config),
+            Err(e) => bail!(
+                "Could not read configuration file at {:?}: {}",
+                config_path,
+                e
+            ),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(config::Config::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn init_default_config(
+    path: &std::path::Path,
+    remote: Option<&str>,
+    bare: bool,
+    reference: Option<&Path>,
+) -> Result<(), anyhow::Error> {
     use std::io::Write;
     let mut path = path.join(DOT_DIR);
     path.push("config");
@@ -158,6 +315,13 @@ fn init_default_config(path: &std::path::Path, remote: Option<&str>) -> Result<(
         if let Some(rem) = remote {
             writeln!(f, "default_remote = {:?}", rem)?;
         }
+        if bare {
+            writeln!(f, "bare = true")?;
+        }
+        if let Some(reference) = reference {
+            let reference = toml::Value::String(reference.to_string_lossy().into_owned());
+            writeln!(f, "reference = {}", reference)?;
+        }
         writeln!(f, "[hooks]\nrecord = []")?;
     }
     Ok(())
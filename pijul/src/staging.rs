@@ -0,0 +1,179 @@
+//! Two-phase pushes. The changes pushed to a repository are first
+//! written to a staging area in `.pijul/staging`, where they are
+//! invisible to the channels. They are then validated one by one, in
+//! the order they were pushed, by the `validate` hooks of the
+//! repository:
+//!
+//! ```toml
+//! [hooks]
+//! validate = [ "./ci/check-change" ]
+//! ```
+//!
+//! Each hook gets a JSON object on its standard input, with the name
+//! of the channel, the path of the staged change file and a
+//! [`ChangeSummary`] of the change, and rejects the change by exiting
+//! with a non-zero code (its standard error is the reason of the
+//! rejection). The accepted changes are moved to the change store
+//! and applied to the channel, and the verdicts are sent back to the
+//! pusher. Changes depending on a rejected change are rejected too.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use libpijul::changestore::ChangeStore;
+use libpijul::{
+    ApplyError, ApplyWorkspace, Base32, ChannelRef, Hash, LocalApplyError, MutTxnTExt, TxnTExt,
+};
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config::HookEntry;
+use crate::notify::ChangeSummary;
+
+pub const STAGING_DIR: &str = "staging";
+
+/// The verdict on a pushed change, sent back to the pusher.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Verdict {
+    pub hash: String,
+    pub accepted: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ValidationInput<'a> {
+    channel: &'a str,
+    path: &'a Path,
+    change: ChangeSummary,
+}
+
+/// A staging area, deleted along with the changes left in it when
+/// dropped.
+pub struct Staging {
+    dir: PathBuf,
+    changes: Vec<Hash>,
+}
+
+impl Staging {
+    /// Create a new staging area in the `.pijul` directory `dot_dir`.
+    pub fn new(dot_dir: &Path) -> Result<Self, anyhow::Error> {
+        use rand::Rng;
+        let name: String = rand::thread_rng()
+            .sample_iter(&rand::distributions::Alphanumeric)
+            .take(20)
+            .map(|x| x as char)
+            .collect();
+        let dir = dot_dir.join(STAGING_DIR).join(name);
+        std::fs::create_dir_all(&dir)?;
+        Ok(Staging {
+            dir,
+            changes: Vec::new(),
+        })
+    }
+
+    /// The path where the file of change `hash` must be written
+    /// before calling [`Staging::push`].
+    pub fn path(&self, hash: &Hash) -> PathBuf {
+        self.dir.join(hash.to_base32()).with_extension("change")
+    }
+
+    pub fn push(&mut self, hash: Hash) {
+        self.changes.push(hash)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Validate the staged changes with `hooks`, in order, and apply
+    /// the accepted ones to `channel`, after moving them to
+    /// `changes_dir`, the directory of `store`. Changes failing to
+    /// apply for another reason than a missing dependency are errors
+    /// rather than rejections, after which `txn` must be dropped
+    /// without being committed.
+    pub fn validate<T: MutTxnTExt + TxnTExt + 'static, C: ChangeStore>(
+        &mut self,
+        hooks: &[HookEntry],
+        changes_dir: &Path,
+        store: &C,
+        txn: &mut T,
+        channel: &ChannelRef<T>,
+        ws: &mut ApplyWorkspace,
+    ) -> Result<Vec<Verdict>, anyhow::Error> {
+        let channel_name = txn.name(&*channel.read()).to_string();
+        let mut rejected = HashSet::new();
+        let mut verdicts = Vec::with_capacity(self.changes.len());
+        for hash in std::mem::take(&mut self.changes) {
+            let path = self.path(&hash);
+            let mut result = check(hooks, &channel_name, &path, &hash, &rejected);
+            if result.is_ok() {
+                let mut dest = changes_dir.to_path_buf();
+                libpijul::changestore::filesystem::push_filename(&mut dest, &hash);
+                std::fs::create_dir_all(dest.parent().unwrap())?;
+                if std::fs::rename(&path, &dest).is_err() {
+                    std::fs::copy(&path, &dest)?;
+                }
+                // A missing dependency is detected before anything is
+                // written to `txn`. Any other error may leave the
+                // change half-applied, and `txn` must not be
+                // committed.
+                match txn.apply_change_ws(store, &mut *channel.write(), &hash, ws) {
+                    Ok(_) => {}
+                    Err(e @ ApplyError::LocalChange(LocalApplyError::DependencyMissing { .. })) => {
+                        result = Err(e.into())
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            debug!("validate {:?}: {:?}", hash, result);
+            let reason = result.err().map(|e| e.to_string());
+            if reason.is_some() {
+                rejected.insert(hash);
+            }
+            verdicts.push(Verdict {
+                hash: hash.to_base32(),
+                accepted: reason.is_none(),
+                reason,
+            })
+        }
+        Ok(verdicts)
+    }
+}
+
+/// Check the staged change `hash` at `path`, and run the hooks on it.
+fn check(
+    hooks: &[HookEntry],
+    channel: &str,
+    path: &Path,
+    hash: &Hash,
+    rejected: &HashSet<Hash>,
+) -> Result<(), anyhow::Error> {
+    let change = libpijul::change::Change::deserialize(&path.to_string_lossy(), Some(hash))?;
+    if let Some(dep) = change
+        .hashed
+        .dependencies
+        .iter()
+        .find(|d| rejected.contains(d))
+    {
+        anyhow::bail!("Depends on rejected change {}", dep.to_base32())
+    }
+    if hooks.is_empty() {
+        return Ok(());
+    }
+    let input = serde_json::to_vec(&ValidationInput {
+        channel,
+        path,
+        change: ChangeSummary::from_change(hash, &change),
+    })?;
+    for hook in hooks {
+        hook.run_with_input(&input)?
+    }
+    Ok(())
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.dir).unwrap_or(())
+    }
+}
@@ -0,0 +1,161 @@
+//! Settings of the connections to SSH and HTTP remotes, from the
+//! `[network]` section of the global configuration: the keepalive
+//! interval and reply timeout of SSH connections, and the maximal
+//! rates at which changes are downloaded and uploaded. The rates are
+//! shared by all the concurrent transfers, and shown in the progress
+//! bars.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+use crate::config::NetworkConfig;
+
+lazy_static! {
+    static ref CONFIG: NetworkConfig = match crate::config::Global::load() {
+        Ok((global, _)) => global.network,
+        Err(_) => NetworkConfig::default(),
+    };
+    pub static ref DOWNLOAD: Transfer = Transfer::new(limit(&CONFIG.download_limit));
+    pub static ref UPLOAD: Transfer = Transfer::new(limit(&CONFIG.upload_limit));
+}
+
+fn limit(l: &Option<String>) -> Option<u64> {
+    let l = l.as_ref()?;
+    match crate::commands::parse_size(l) {
+        Ok(0) => None,
+        Ok(l) => Some(l as u64),
+        Err(e) => {
+            log::warn!("Ignoring invalid rate limit {:?}: {}", l, e);
+            None
+        }
+    }
+}
+
+/// The interval at which SSH connections are probed, to keep them
+/// open when they are idle.
+pub fn keepalive() -> Option<Duration> {
+    match CONFIG.keepalive {
+        Some(0) | None => None,
+        Some(s) => Some(Duration::from_secs(s)),
+    }
+}
+
+/// How long to wait for SSH servers to answer requests that older
+/// servers don't know, and ignore. `None` means forever.
+pub fn reply_timeout() -> Option<Duration> {
+    match CONFIG.reply_timeout {
+        Some(0) => None,
+        Some(s) => Some(Duration::from_secs(s)),
+        None => Some(Duration::from_secs(10)),
+    }
+}
+
+/// The period over which the rates shown in the progress bars are
+/// computed.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// The transfers in one direction.
+pub struct Transfer {
+    /// Maximal rate, in bytes per second.
+    limit: Option<u64>,
+    bytes: AtomicU64,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// The number of bytes that can be transferred without waiting,
+    /// refilled at `limit` bytes per second up to one second's worth,
+    /// so that a pause doesn't allow a burst above the limit
+    /// afterwards. This is negative when transfers are waiting.
+    tokens: f64,
+    /// When `tokens` was last refilled.
+    refilled: Option<Instant>,
+    /// When the first bytes were transferred.
+    start: Option<Instant>,
+    /// The transfers of the last `RATE_WINDOW`, with their sizes.
+    recent: VecDeque<(Instant, u64)>,
+}
+
+impl Transfer {
+    fn new(limit: Option<u64>) -> Self {
+        Transfer {
+            limit,
+            bytes: AtomicU64::new(0),
+            state: Mutex::new(State {
+                tokens: 0.,
+                refilled: None,
+                start: None,
+                recent: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Count `n` more bytes, and wait until they fit in the limit.
+    pub async fn transferred(&self, n: usize) {
+        self.bytes.fetch_add(n as u64, Ordering::Relaxed);
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            state.start.get_or_insert(now);
+            state.recent.push_back((now, n as u64));
+            state.forget(now);
+            if let Some(limit) = self.limit {
+                let limit = limit as f64;
+                let elapsed = if let Some(r) = state.refilled {
+                    now.saturating_duration_since(r).as_secs_f64()
+                } else {
+                    1.
+                };
+                state.refilled = Some(now);
+                state.tokens = (state.tokens + elapsed * limit).min(limit) - n as f64;
+                if state.tokens < 0. {
+                    Some(Duration::from_secs_f64(-state.tokens / limit))
+                } else {
+                    None
+                }
+            } else {
+                None
+            }
+        };
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await
+        }
+    }
+
+    /// The number of bytes transferred, and the rate over the last
+    /// few seconds in bytes per second.
+    pub fn rate(&self) -> (u64, f64) {
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        state.forget(now);
+        let window = if let Some(start) = state.start {
+            now.saturating_duration_since(start).min(RATE_WINDOW)
+        } else {
+            return (bytes, 0.);
+        };
+        let recent: u64 = state.recent.iter().map(|(_, n)| n).sum();
+        if window > Duration::from_millis(0) {
+            (bytes, recent as f64 / window.as_secs_f64())
+        } else {
+            (bytes, 0.)
+        }
+    }
+}
+
+impl State {
+    /// Forget the transfers older than `RATE_WINDOW`.
+    fn forget(&mut self, now: Instant) {
+        while let Some(&(t, _)) = self.recent.front() {
+            if now.saturating_duration_since(t) > RATE_WINDOW {
+                self.recent.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+}
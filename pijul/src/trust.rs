@@ -0,0 +1,237 @@
+//! The trust store: how much the public keys of other people are
+//! trusted, set with `pijul key trust` and `pijul key untrust`, and
+//! stored in `trust.json` in the global configuration directory.
+//!
+//! A key is trusted if it was trusted explicitly, or if one of its
+//! signatures in the store was made by a fully trusted key (which
+//! only gives it marginal trust). Trusting a key with `pijul key
+//! trust` signs it with our own key, so that other people can import
+//! that signature with `pijul key trust --signature`.
+//!
+//! When pulling and applying changes, the `[trust]` section of the
+//! global configuration decides what happens to the changes that are
+//! not signed by a trusted key: they are either accepted silently
+//! (the default), accepted with a warning, or rejected.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use libpijul::changestore::ChangeStore;
+use libpijul::{Base32, Hash};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config::{TrustConfig, TrustPolicy};
+
+const TRUST_FILE: &str = "trust.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrustLevel {
+    /// Never trust this key, even if trusted keys signed it.
+    Never,
+    Marginal,
+    Full,
+}
+
+impl std::str::FromStr for TrustLevel {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(TrustLevel::Never),
+            "marginal" => Ok(TrustLevel::Marginal),
+            "full" => Ok(TrustLevel::Full),
+            _ => bail!(
+                "Unknown trust level {:?} (expected never, marginal or full)",
+                s
+            ),
+        }
+    }
+}
+
+/// A signature of a key by another key, vouching for it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrustSignature {
+    /// The signing key.
+    pub by: String,
+    pub signature: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TrustEntry {
+    /// The trust set explicitly for this key, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<TrustLevel>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub signatures: Vec<TrustSignature>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TrustStore {
+    /// Trust entries, indexed by the base 58 encoding of the keys.
+    #[serde(default)]
+    pub keys: BTreeMap<String, TrustEntry>,
+    /// Our own public key, always fully trusted.
+    #[serde(skip)]
+    own_key: Option<String>,
+}
+
+fn trust_file() -> Option<PathBuf> {
+    crate::config::global_config_dir().map(|d| d.join(TRUST_FILE))
+}
+
+impl TrustStore {
+    /// Load the trust store, which is empty if it doesn't exist yet.
+    pub fn load() -> Result<Self, anyhow::Error> {
+        let mut store = if let Some(path) = trust_file() {
+            match std::fs::read(&path) {
+                Ok(s) => serde_json::from_slice(&s)?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => TrustStore::default(),
+                Err(e) => return Err(e.into()),
+            }
+        } else {
+            TrustStore::default()
+        };
+        if let Some(mut dir) = crate::config::global_config_dir() {
+            dir.push("publickey.json");
+            if let Ok(key) = std::fs::File::open(&dir) {
+                let k: libpijul::key::PublicKey = serde_json::from_reader(key)?;
+                store.own_key = Some(k.key)
+            }
+        }
+        Ok(store)
+    }
+
+    pub fn save(&self) -> Result<(), anyhow::Error> {
+        let path = if let Some(path) = trust_file() {
+            path
+        } else {
+            bail!("Global configuration directory not found")
+        };
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let mut f = std::fs::File::create(&path)?;
+        serde_json::to_writer_pretty(&mut f, self)?;
+        f.write_all(b"\n")?;
+        Ok(())
+    }
+
+    /// Set the trust level of `key`.
+    pub fn set_level(&mut self, key: &str, level: TrustLevel) {
+        self.keys.entry(key.to_string()).or_default().level = Some(level)
+    }
+
+    /// Add a signature of `key` by `by`, after checking it.
+    pub fn add_signature(
+        &mut self,
+        key: &str,
+        by: &str,
+        signature: &str,
+    ) -> Result<(), anyhow::Error> {
+        if libpijul::key::verify_raw(by, key.as_bytes(), signature).is_err() {
+            bail!("Invalid signature of {} by {}", key, by)
+        }
+        let sig = TrustSignature {
+            by: by.to_string(),
+            signature: signature.to_string(),
+        };
+        let entry = self.keys.entry(key.to_string()).or_default();
+        if !entry.signatures.contains(&sig) {
+            entry.signatures.push(sig)
+        }
+        Ok(())
+    }
+
+    /// The trust level of `key`, or `None` if it is unknown.
+    pub fn level(&self, key: &str) -> Option<TrustLevel> {
+        if self.own_key.as_deref() == Some(key) {
+            return Some(TrustLevel::Full);
+        }
+        let entry = self.keys.get(key)?;
+        if entry.level.is_some() {
+            return entry.level;
+        }
+        for sig in entry.signatures.iter() {
+            let by_full = self.own_key.as_deref() == Some(sig.by.as_str())
+                || self.keys.get(&sig.by).and_then(|e| e.level) == Some(TrustLevel::Full);
+            if by_full && libpijul::key::verify_raw(&sig.by, key.as_bytes(), &sig.signature).is_ok()
+            {
+                return Some(TrustLevel::Marginal);
+            }
+        }
+        None
+    }
+}
+
+/// The key that signed the change with hash `hash`, if the
+/// signature of that change is valid and made by one of its
+/// authors.
+fn signer<C: ChangeStore>(changes: &C, hash: &Hash) -> Result<Option<String>, anyhow::Error>
+where
+    C::Error: Send + Sync + 'static,
+{
+    let change = changes.get_change(hash)?;
+    let signature = if let Some(s) = change
+        .unhashed
+        .as_ref()
+        .and_then(|u| u.get("signature"))
+        .and_then(|s| s.as_str())
+    {
+        s
+    } else {
+        return Ok(None);
+    };
+    for author in change.header.authors.iter() {
+        if let Some(key) = author.0.get("key") {
+            if libpijul::key::verify_raw(key, &hash.to_bytes(), signature).is_ok() {
+                return Ok(Some(key.clone()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Apply the trust policy of the global configuration to `hashes`,
+/// before applying them: warn about the changes that are not signed
+/// by a trusted key, or fail if the policy is to reject them.
+pub fn check_changes<C: ChangeStore>(changes: &C, hashes: &[Hash]) -> Result<(), anyhow::Error>
+where
+    C::Error: Send + Sync + 'static,
+{
+    let config = match crate::config::Global::load() {
+        Ok((global, _)) => global.trust,
+        Err(_) => TrustConfig::default(),
+    };
+    if let TrustPolicy::Ignore = config.policy {
+        return Ok(());
+    }
+    let store = TrustStore::load()?;
+    let mut untrusted = Vec::new();
+    for hash in hashes {
+        let reason = match signer(changes, hash)? {
+            None => "not signed by any of its authors".to_string(),
+            Some(key) => match store.level(&key) {
+                Some(TrustLevel::Marginal) | Some(TrustLevel::Full) => continue,
+                Some(TrustLevel::Never) => format!("signed by distrusted key {}", key),
+                None => format!("signed by unknown key {}", key),
+            },
+        };
+        untrusted.push(format!("Change {} is {}", hash.to_base32(), reason))
+    }
+    if untrusted.is_empty() {
+        return Ok(());
+    }
+    match config.policy {
+        TrustPolicy::Reject => bail!(
+            "{}\nRejecting untrusted changes (see the [trust] section of the global configuration)",
+            untrusted.join("\n")
+        ),
+        _ => {
+            let mut stderr = std::io::stderr();
+            for u in untrusted {
+                writeln!(stderr, "Warning: {}", u)?;
+            }
+            Ok(())
+        }
+    }
+}